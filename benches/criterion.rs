@@ -1,7 +1,3 @@
-#![allow(dead_code, unused_macros, unused_imports)]
-#[macro_use] extern crate serde;
-#[macro_use] extern crate log;
-
 use criterion::{criterion_group, criterion_main, Criterion, Throughput};
 
 use smallvec::smallvec;
@@ -10,17 +6,14 @@ use ring::aead;
 use std::str::FromStr;
 use std::net::{SocketAddr, Ipv4Addr, SocketAddrV4, UdpSocket};
 
-include!(".code.rs");
-
-pub use error::Error;
-use util::{MockTimeSource, MsgBuffer};
-use types::{Address, Range};
-use table::{ClaimTable};
-use device::Type;
-use config::Config;
-use payload::{Packet, Frame, Protocol};
-use crypto::core::{create_dummy_pair, EXTRA_LEN};
-use tests::common::{TunSimulator, TapSimulator};
+use vpncloud_core::util::{BufferPool, MockTimeSource, MsgBuffer};
+use vpncloud_core::types::{Address, Range};
+use vpncloud_core::table::ClaimTable;
+use vpncloud_core::device::Type;
+use vpncloud_core::config::Config;
+use vpncloud_core::payload::{Packet, Frame, Protocol};
+use vpncloud_core::crypto::{create_dummy_pair, EXTRA_LEN};
+use vpncloud_core::sim::{Nat, TunSimulator, TapSimulator};
 
 fn udp_send(c: &mut Criterion) {
     let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
@@ -89,6 +82,25 @@ fn lookup_warm(c: &mut Criterion) {
     g.finish();
 }
 
+fn lookup_many(c: &mut Criterion) {
+    // More destinations than recent lookups are kept, so every lookup hits the cache
+    let mut table = ClaimTable::<MockTimeSource>::new(60, 60);
+    let addrs: Vec<_> = (1..=16).map(|i| Address::from_str(&format!("1.2.3.{}", i)).unwrap()).collect();
+    for addr in &addrs {
+        table.cache(*addr, SocketAddr::from_str("1.2.3.4:3210").unwrap());
+    }
+    let mut i = 0;
+    let mut g = c.benchmark_group("table");
+    g.throughput(Throughput::Bytes(1400));
+    g.bench_function("lookup_many", |b| {
+        b.iter(|| {
+            i = (i + 1) % addrs.len();
+            table.lookup(addrs[i])
+        });
+    });
+    g.finish();
+}
+
 fn lookup_cold(c: &mut Criterion) {
     let mut table = ClaimTable::<MockTimeSource>::new(60, 60);
     let addr = Address::from_str("1.2.3.4").unwrap();
@@ -104,6 +116,33 @@ fn lookup_cold(c: &mut Criterion) {
     g.finish();
 }
 
+fn buffer_new(c: &mut Criterion) {
+    let mut g = c.benchmark_group("buffer");
+    g.throughput(Throughput::Bytes(1400));
+    g.bench_function("new", |b| {
+        b.iter(|| {
+            let mut buffer = Box::new(MsgBuffer::new(100));
+            buffer.set_length(1400);
+            buffer
+        });
+    });
+    g.finish();
+}
+
+fn buffer_pool(c: &mut Criterion) {
+    let mut pool = BufferPool::new(100, 4);
+    let mut g = c.benchmark_group("buffer");
+    g.throughput(Throughput::Bytes(1400));
+    g.bench_function("pool", |b| {
+        b.iter(|| {
+            let mut buffer = pool.get();
+            buffer.set_length(1400);
+            pool.put(buffer);
+        });
+    });
+    g.finish();
+}
+
 fn crypto_bench(c: &mut Criterion, algo: &'static aead::Algorithm) {
     let mut buffer = MsgBuffer::new(EXTRA_LEN);
     buffer.set_length(1400);
@@ -145,12 +184,12 @@ fn full_communication_tun_router(c: &mut Criterion) {
         claims: vec!["2.2.2.2/32".to_string()],
         ..Config::default()
     };
-    let mut sim = TunSimulator::new();
-    let node1 = sim.add_node(false, &config1);
-    let node2 = sim.add_node(false, &config2);
+    let mut sim = TunSimulator::new(0);
+    let node1 = sim.add_node(&config1, Nat::None);
+    let node2 = sim.add_node(&config2, Nat::None);
 
     sim.connect(node1, node2);
-    sim.simulate_all_messages();
+    sim.run_until_idle();
     assert!(sim.is_connected(node1, node2));
     assert!(sim.is_connected(node2, node1));
 
@@ -161,7 +200,7 @@ fn full_communication_tun_router(c: &mut Criterion) {
     g.bench_function("tun_router", |b| {
         b.iter(|| {
             sim.put_payload(node1, payload.clone());
-            sim.simulate_all_messages();
+            sim.run_until_idle();
             assert_eq!(Some(&payload), sim.pop_payload(node2).as_ref());
         });
     });
@@ -171,12 +210,12 @@ fn full_communication_tun_router(c: &mut Criterion) {
 fn full_communication_tap_switch(c: &mut Criterion) {
     log::set_max_level(log::LevelFilter::Error);    
     let config = Config { device_type: Type::Tap, ..Config::default() };
-    let mut sim = TapSimulator::new();
-    let node1 = sim.add_node(false, &config);
-    let node2 = sim.add_node(false, &config);
+    let mut sim = TapSimulator::new(0);
+    let node1 = sim.add_node(&config, Nat::None);
+    let node2 = sim.add_node(&config, Nat::None);
 
     sim.connect(node1, node2);
-    sim.simulate_all_messages();
+    sim.run_until_idle();
     assert!(sim.is_connected(node1, node2));
     assert!(sim.is_connected(node2, node1));
 
@@ -187,7 +226,7 @@ fn full_communication_tap_switch(c: &mut Criterion) {
     g.bench_function("tap_switch", |b| {
         b.iter(|| {
             sim.put_payload(node1, payload.clone());
-            sim.simulate_all_messages();
+            sim.run_until_idle();
             assert_eq!(Some(&payload), sim.pop_payload(node2).as_ref());
         });
     });
@@ -197,7 +236,8 @@ fn full_communication_tap_switch(c: &mut Criterion) {
 criterion_group!(benches, 
     udp_send, 
     decode_ipv4, decode_ipv6, decode_ethernet, decode_ethernet_with_vlan, 
-    lookup_cold, lookup_warm, 
+    lookup_cold, lookup_warm, lookup_many,
+    buffer_new, buffer_pool,
     crypto_chacha20, crypto_aes128, crypto_aes256,
     full_communication_tun_router, full_communication_tap_switch
 );
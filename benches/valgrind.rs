@@ -1,8 +1,4 @@
-#![allow(dead_code, unused_macros, unused_imports)]
-#[macro_use] extern crate serde;
-#[macro_use] extern crate log;
-
-use iai::{black_box, main};
+use iai::black_box;
 
 use smallvec::smallvec;
 use ring::aead;
@@ -10,17 +6,14 @@ use ring::aead;
 use std::str::FromStr;
 use std::net::{SocketAddr, Ipv4Addr, SocketAddrV4, UdpSocket};
 
-include!(".code.rs");
-
-pub use error::Error;
-use util::{MockTimeSource, MsgBuffer};
-use config::Config;
-use types::{Address, Range};
-use device::Type;
-use table::{ClaimTable};
-use payload::{Packet, Frame, Protocol};
-use crypto::core::{create_dummy_pair, EXTRA_LEN};
-use tests::common::{TunSimulator, TapSimulator};
+use vpncloud_core::util::{MockTimeSource, MsgBuffer};
+use vpncloud_core::config::Config;
+use vpncloud_core::types::{Address, Range};
+use vpncloud_core::device::Type;
+use vpncloud_core::table::ClaimTable;
+use vpncloud_core::payload::{Packet, Frame, Protocol};
+use vpncloud_core::crypto::{create_dummy_pair, EXTRA_LEN};
+use vpncloud_core::sim::{Nat, TunSimulator, TapSimulator};
 
 fn udp_send() {
     let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
@@ -107,12 +100,12 @@ fn full_communication_tun_router() {
         claims: vec!["2.2.2.2/32".to_string()],
         ..Config::default()
     };
-    let mut sim = TunSimulator::new();
-    let node1 = sim.add_node(false, &config1);
-    let node2 = sim.add_node(false, &config2);
+    let mut sim = TunSimulator::new(0);
+    let node1 = sim.add_node(&config1, Nat::None);
+    let node2 = sim.add_node(&config2, Nat::None);
 
     sim.connect(node1, node2);
-    sim.simulate_all_messages();
+    sim.run_until_idle();
     assert!(sim.is_connected(node1, node2));
     assert!(sim.is_connected(node2, node1));
 
@@ -120,7 +113,7 @@ fn full_communication_tun_router() {
     payload.append(&mut vec![0; 1400]);
     for _ in 0..1000 {
         sim.put_payload(node1, payload.clone());
-        sim.simulate_all_messages();
+        sim.run_until_idle();
         assert_eq!(Some(&payload), black_box(sim.pop_payload(node2).as_ref()));
     }
 }
@@ -128,12 +121,12 @@ fn full_communication_tun_router() {
 fn full_communication_tap_switch() {
     log::set_max_level(log::LevelFilter::Error);
     let config = Config { device_type: Type::Tap, ..Config::default() };
-    let mut sim = TapSimulator::new();
-    let node1 = sim.add_node(false, &config);
-    let node2 = sim.add_node(false, &config);
+    let mut sim = TapSimulator::new(0);
+    let node1 = sim.add_node(&config, Nat::None);
+    let node2 = sim.add_node(&config, Nat::None);
 
     sim.connect(node1, node2);
-    sim.simulate_all_messages();
+    sim.run_until_idle();
     assert!(sim.is_connected(node1, node2));
     assert!(sim.is_connected(node2, node1));
 
@@ -141,7 +134,7 @@ fn full_communication_tap_switch() {
     payload.append(&mut vec![0; 1400]);
     for _ in 0..1000 {
         sim.put_payload(node1, payload.clone());
-        sim.simulate_all_messages();
+        sim.run_until_idle();
         assert_eq!(Some(&payload), black_box(sim.pop_payload(node2).as_ref()));
     }
 }
@@ -0,0 +1,71 @@
+/*
+ * VpnCloud - Peer-to-Peer VPN
+ * Copyright (C) 2015-2021  Dennis Schwerdel
+ * This software is licensed under GPL-3 or newer (see LICENSE.md)
+ *
+ * C bindings for embedding the VpnCloud engine.
+ *
+ * An instance is created from a config in the format of the config files (YAML) and runs the node
+ * in a background thread once it is started. Packets are exchanged either via an open tun/tap file
+ * descriptor or via a callback for outgoing packets and vpncloud_feed() for incoming packets.
+ *
+ * Functions returning int return 0 on success and -1 on failure unless noted otherwise.
+ */
+
+#ifndef VPNCLOUD_H
+#define VPNCLOUD_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct vpncloud_instance vpncloud_instance;
+
+/* Called from the thread of the instance for every packet (TUN) or frame (TAP) to deliver */
+typedef void (*vpncloud_packet_cb)(void *ctx, const uint8_t *data, size_t len);
+
+/* Called with the socket before the instance is started, returns 0 to abort the start */
+typedef int (*vpncloud_protect_cb)(void *ctx, int fd);
+
+/* Creates an instance from a YAML config, returns NULL if the config is invalid */
+vpncloud_instance *vpncloud_create(const char *config);
+
+/*
+ * Sets a callback to exclude the socket from the VPN before it is used, e.g. via
+ * VpnService.protect() on Android. Has to be called before starting the instance.
+ */
+void vpncloud_set_protect(vpncloud_instance *instance, vpncloud_protect_cb callback, void *ctx);
+
+/*
+ * Starts the instance on an open tun/tap file descriptor, the instance takes ownership of fd.
+ * The device is used as is without any ioctl calls, so this also works with file descriptors
+ * provided by Android's VpnService.
+ */
+int vpncloud_start_fd(vpncloud_instance *instance, int fd);
+
+/* Starts the instance with a callback for outgoing packets, ctx is passed to the callback */
+int vpncloud_start_callback(vpncloud_instance *instance, vpncloud_packet_cb callback, void *ctx);
+
+/* Passes an incoming packet to an instance started with vpncloud_start_callback() */
+int vpncloud_feed(vpncloud_instance *instance, const uint8_t *data, size_t len);
+
+/*
+ * Writes the status as JSON (e.g. {"running":true,"peers":2}) into buffer. Returns the length of
+ * the full status without the terminating NUL like snprintf(), the output is truncated to len.
+ */
+int vpncloud_status(const vpncloud_instance *instance, char *buffer, size_t len);
+
+/* Stops a running instance and waits for it to shut down */
+int vpncloud_stop(vpncloud_instance *instance);
+
+/* Stops the instance if it is running and frees it */
+void vpncloud_destroy(vpncloud_instance *instance);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* VPNCLOUD_H */
@@ -0,0 +1,88 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! JNI entry points for Android
+//!
+//! These functions implement the native methods of the Java class `de.ddswd.vpncloud.VpnCloud`:
+//!
+//! ```java
+//! static native long create(String config);
+//! static native int start(long instance, int fd, VpnService service);
+//! static native String status(long instance);
+//! static native int stop(long instance);
+//! static native void destroy(long instance);
+//! ```
+//!
+//! The file descriptor is the one returned by `VpnService.Builder.establish()` and has to be
+//! detached from its `ParcelFileDescriptor` as the instance takes ownership of it. The device is
+//! used without any ioctl calls, so no root permissions are needed. Addresses, routes and the MTU
+//! are configured via the `VpnService.Builder` and the address should be claimed explicitly via
+//! `claims` in the config.
+
+use std::{fs::File, os::unix::io::FromRawFd, ptr};
+
+use jni::{
+    objects::{JClass, JObject, JString, JValue},
+    sys::{jint, jlong, jstring},
+    JNIEnv,
+};
+
+use vpncloud_core::TunTapDevice;
+
+use super::{start, Instance};
+
+unsafe fn instance<'a>(instance: jlong) -> Option<&'a mut Instance> {
+    (instance as *mut Instance).as_mut()
+}
+
+#[no_mangle]
+pub extern "system" fn Java_de_ddswd_vpncloud_VpnCloud_create(env: JNIEnv, _class: JClass, config: JString) -> jlong {
+    let config: String = match env.get_string(config) {
+        Ok(config) => config.into(),
+        Err(_) => return 0,
+    };
+    match Instance::new(&config) {
+        Some(instance) => Box::into_raw(Box::new(instance)) as jlong,
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "system" fn Java_de_ddswd_vpncloud_VpnCloud_start(
+    env: JNIEnv, _class: JClass, instance: jlong, fd: jint, service: JObject,
+) -> jint {
+    let instance = match unsafe { self::instance(instance) } {
+        Some(instance) => instance,
+        None => return -1,
+    };
+    let name = instance.config.device_name.clone();
+    let device = TunTapDevice::from_file(unsafe { File::from_raw_fd(fd) }, &name, instance.config.device_type);
+    start(instance, device, |socket| {
+        env.call_method(service, "protect", "(I)Z", &[JValue::Int(socket)]).and_then(|res| res.z()).unwrap_or(false)
+    })
+}
+
+#[no_mangle]
+pub extern "system" fn Java_de_ddswd_vpncloud_VpnCloud_status(env: JNIEnv, _class: JClass, instance: jlong) -> jstring {
+    match unsafe { self::instance(instance) } {
+        Some(instance) => env.new_string(instance.status()).map(|s| s.into_inner()).unwrap_or(ptr::null_mut()),
+        None => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "system" fn Java_de_ddswd_vpncloud_VpnCloud_stop(_env: JNIEnv, _class: JClass, instance: jlong) -> jint {
+    match unsafe { self::instance(instance) } {
+        Some(instance) => instance.stop(),
+        None => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "system" fn Java_de_ddswd_vpncloud_VpnCloud_destroy(_env: JNIEnv, _class: JClass, instance: jlong) {
+    if let Some(instance) = unsafe { self::instance(instance) } {
+        instance.stop();
+        drop(unsafe { Box::from_raw(instance as *mut Instance) });
+    }
+}
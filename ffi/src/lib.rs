@@ -0,0 +1,335 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! C bindings for the VpnCloud engine
+//!
+//! The API is declared in `include/vpncloud.h`. An instance is created from a config in the format
+//! of the config files and runs the node in a background thread. Packets are either exchanged via
+//! a tun/tap file descriptor or via a callback for outgoing packets and `vpncloud_feed` for
+//! incoming packets.
+//!
+//! On Android, the entry points in the `android` module are used with the file descriptor of a
+//! `VpnService`. The socket is protected via the service so that it bypasses the VPN.
+
+#[cfg(target_os = "android")]
+mod android;
+
+use std::{
+    ffi::CStr,
+    fs::File,
+    net::{Ipv4Addr, UdpSocket},
+    os::{
+        raw::{c_char, c_int, c_void},
+        unix::{
+            io::{AsRawFd, FromRawFd, RawFd},
+            net::UnixDatagram,
+        },
+    },
+    ptr, slice,
+    sync::mpsc,
+    thread::{self, JoinHandle},
+};
+
+use vpncloud_core::{
+    config::{ConfigFile, DEFAULT_PORT},
+    payload::{Frame, Packet},
+    util::MsgBuffer,
+    CloudHandle, Config, Device, Error, GenericCloud, Protocol, Socket, SystemTimeSource, TunTapDevice, Type,
+};
+
+pub type PacketCallback = extern "C" fn(ctx: *mut c_void, data: *const u8, len: usize);
+pub type ProtectCallback = extern "C" fn(ctx: *mut c_void, fd: c_int) -> c_int;
+
+/// Opaque pointer of the embedding program that is passed to the callback
+struct Context(*mut c_void);
+
+// The embedding program is responsible for making the context usable from the node thread
+unsafe impl Send for Context {}
+
+/// A device that passes outgoing packets to a callback and reads incoming packets from a socket
+struct CallbackDevice {
+    type_: Type,
+    socket: UnixDatagram,
+    callback: PacketCallback,
+    ctx: Context,
+}
+
+impl Device for CallbackDevice {
+    fn get_type(&self) -> Type {
+        self.type_
+    }
+
+    fn ifname(&self) -> &str {
+        "callback"
+    }
+
+    fn read(&mut self, buffer: &mut MsgBuffer) -> Result<(), Error> {
+        buffer.clear();
+        let read = self.socket.recv(buffer.buffer()).map_err(|e| Error::DeviceIo("Read error", e))?;
+        buffer.set_length(read);
+        Ok(())
+    }
+
+    fn write(&mut self, buffer: &mut MsgBuffer) -> Result<(), Error> {
+        let data = buffer.message();
+        (self.callback)(self.ctx.0, data.as_ptr(), data.len());
+        Ok(())
+    }
+
+    fn get_ip(&self) -> Result<Ipv4Addr, Error> {
+        Err(Error::Device("Callback devices have no address"))
+    }
+}
+
+impl AsRawFd for CallbackDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+pub struct Instance {
+    config: Config,
+    protect: Option<(ProtectCallback, Context)>,
+    handle: Option<CloudHandle>,
+    thread: Option<JoinHandle<Result<(), Error>>>,
+    feed: Option<UnixDatagram>,
+}
+
+impl Instance {
+    fn new(config: &str) -> Option<Self> {
+        let config_file = serde_yaml::from_str::<ConfigFile>(config).ok()?;
+        let mut config = Config::default();
+        config.merge_file(config_file);
+        if config.crypto.password.is_none() && config.crypto.private_key.is_none() {
+            return None;
+        }
+        Some(Instance { config, protect: None, handle: None, thread: None, feed: None })
+    }
+
+    fn status(&self) -> String {
+        format!(
+            "{{\"running\":{},\"peers\":{}}}",
+            self.thread.is_some(),
+            self.handle.as_ref().map(|h| h.peer_count()).unwrap_or(0)
+        )
+    }
+
+    fn stop(&mut self) -> c_int {
+        if let Some(handle) = self.handle.take() {
+            handle.stop();
+        }
+        self.feed = None;
+        match self.thread.take().map(|thread| thread.join()) {
+            Some(Ok(Ok(()))) => 0,
+            _ => -1,
+        }
+    }
+}
+
+fn run<D: Device, P: Protocol>(
+    config: Config, socket: UdpSocket, device: D, started: mpsc::Sender<CloudHandle>,
+) -> Result<(), Error> {
+    let port_forwarding = if config.port_forwarding {
+        socket.create_port_forwarding(&config.port_forwarding_description())
+    } else {
+        None
+    };
+    let mut cloud =
+        GenericCloud::<D, P, UdpSocket, SystemTimeSource>::new(&config, socket, device, port_forwarding, None)?;
+    let primary = config.peers.iter().map(|p| p.priority).min().unwrap_or(0);
+    for peer in &config.peers {
+        let mut peer = peer.clone();
+        if peer.address.rfind(':').unwrap_or(0) <= peer.address.rfind(']').unwrap_or(0) {
+            // : not present or only in IPv6 address
+            peer.address = format!("{}:{}", peer.address, DEFAULT_PORT)
+        }
+        if peer.priority == primary {
+            cloud.connect(&peer.address as &str)?;
+        }
+        cloud.add_peer_config(peer);
+    }
+    started.send(cloud.handle()).ok();
+    cloud.run()
+}
+
+/// Opens the socket and starts the node in a new thread
+///
+/// The socket is opened in the calling thread and passed to `protect` before any packets are sent.
+fn start<D: Device + Send + 'static, F: FnOnce(RawFd) -> bool>(
+    instance: &mut Instance, device: D, protect: F,
+) -> c_int {
+    if instance.thread.is_some() {
+        return -1;
+    }
+    let socket = match UdpSocket::listen(&instance.config.listen) {
+        Ok(socket) => socket,
+        Err(_) => return -1,
+    };
+    if !protect(socket.as_raw_fd()) {
+        return -1;
+    }
+    let config = instance.config.clone();
+    let (started, handle) = mpsc::channel();
+    let thread = thread::spawn(move || match config.device_type {
+        Type::Tap => run::<D, Frame>(config, socket, device, started),
+        Type::Tun => run::<D, Packet>(config, socket, device, started),
+    });
+    match handle.recv() {
+        Ok(handle) => {
+            instance.handle = Some(handle);
+            instance.thread = Some(thread);
+            0
+        }
+        Err(_) => {
+            // The thread failed before the node was running
+            thread.join().ok();
+            -1
+        }
+    }
+}
+
+/// Creates an instance from a config in YAML format, returns NULL if the config is invalid
+///
+/// # Safety
+/// `config` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn vpncloud_create(config: *const c_char) -> *mut Instance {
+    if config.is_null() {
+        return ptr::null_mut();
+    }
+    match CStr::from_ptr(config).to_str().ok().and_then(Instance::new) {
+        Some(instance) => Box::into_raw(Box::new(instance)),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Sets a callback that is called with the socket before the instance is started
+///
+/// The instance is not started if the callback returns 0. This can be used to exclude the socket
+/// from the VPN, e.g. via `VpnService.protect()` on Android.
+///
+/// # Safety
+/// `instance` must have been created by `vpncloud_create`.
+#[no_mangle]
+pub unsafe extern "C" fn vpncloud_set_protect(instance: *mut Instance, callback: ProtectCallback, ctx: *mut c_void) {
+    if let Some(instance) = instance.as_mut() {
+        instance.protect = Some((callback, Context(ctx)));
+    }
+}
+
+fn protect_callback(instance: &Instance) -> impl FnOnce(RawFd) -> bool {
+    let protect = instance.protect.as_ref().map(|(callback, ctx)| (*callback, ctx.0));
+    move |fd| protect.map(|(callback, ctx)| callback(ctx, fd) != 0).unwrap_or(true)
+}
+
+/// Starts the instance on an open tun/tap file descriptor, the instance takes ownership of `fd`
+///
+/// # Safety
+/// `instance` must have been created by `vpncloud_create` and `fd` must be an open file descriptor.
+#[no_mangle]
+pub unsafe extern "C" fn vpncloud_start_fd(instance: *mut Instance, fd: c_int) -> c_int {
+    let instance = match instance.as_mut() {
+        Some(instance) => instance,
+        None => return -1,
+    };
+    let name = instance.config.device_name.clone();
+    let device = TunTapDevice::from_file(File::from_raw_fd(fd), &name, instance.config.device_type);
+    let protect = protect_callback(instance);
+    start(instance, device, protect)
+}
+
+/// Starts the instance with a callback for outgoing packets
+///
+/// The callback is called from the thread of the instance. Incoming packets have to be passed to
+/// `vpncloud_feed`.
+///
+/// # Safety
+/// `instance` must have been created by `vpncloud_create` and `ctx` must be usable from other
+/// threads.
+#[no_mangle]
+pub unsafe extern "C" fn vpncloud_start_callback(
+    instance: *mut Instance, callback: PacketCallback, ctx: *mut c_void,
+) -> c_int {
+    let instance = match instance.as_mut() {
+        Some(instance) => instance,
+        None => return -1,
+    };
+    let (feed, socket) = match UnixDatagram::pair() {
+        Ok(pair) => pair,
+        Err(_) => return -1,
+    };
+    let device = CallbackDevice { type_: instance.config.device_type, socket, callback, ctx: Context(ctx) };
+    let protect = protect_callback(instance);
+    let res = start(instance, device, protect);
+    if res == 0 {
+        instance.feed = Some(feed);
+    }
+    res
+}
+
+/// Passes a packet (TUN) or frame (TAP) to the instance as if it was read from the device
+///
+/// # Safety
+/// `instance` must have been started by `vpncloud_start_callback` and `data` must point to `len`
+/// readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn vpncloud_feed(instance: *mut Instance, data: *const u8, len: usize) -> c_int {
+    match instance.as_ref().and_then(|instance| instance.feed.as_ref()) {
+        Some(feed) if !data.is_null() => match feed.send(slice::from_raw_parts(data, len)) {
+            Ok(_) => 0,
+            Err(_) => -1,
+        },
+        _ => -1,
+    }
+}
+
+/// Writes the status of the instance as JSON into `buffer`
+///
+/// Returns the length of the status without the terminating NUL like `snprintf`. The status is
+/// truncated if the buffer is too small.
+///
+/// # Safety
+/// `instance` must have been created by `vpncloud_create` and `buffer` must point to `len`
+/// writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn vpncloud_status(instance: *const Instance, buffer: *mut c_char, len: usize) -> c_int {
+    let instance = match instance.as_ref() {
+        Some(instance) => instance,
+        None => return -1,
+    };
+    let status = instance.status();
+    if !buffer.is_null() && len > 0 {
+        let copy = status.len().min(len - 1);
+        ptr::copy_nonoverlapping(status.as_ptr(), buffer as *mut u8, copy);
+        *buffer.add(copy) = 0;
+    }
+    status.len() as c_int
+}
+
+/// Stops a running instance and waits for it to shut down
+///
+/// Returns 0 if the instance shut down cleanly and -1 if it was not running or failed.
+///
+/// # Safety
+/// `instance` must have been created by `vpncloud_create`.
+#[no_mangle]
+pub unsafe extern "C" fn vpncloud_stop(instance: *mut Instance) -> c_int {
+    let instance = match instance.as_mut() {
+        Some(instance) => instance,
+        None => return -1,
+    };
+    instance.stop()
+}
+
+/// Stops the instance if it is running and frees it
+///
+/// # Safety
+/// `instance` must have been created by `vpncloud_create` and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn vpncloud_destroy(instance: *mut Instance) {
+    if !instance.is_null() {
+        vpncloud_stop(instance);
+        drop(Box::from_raw(instance));
+    }
+}
@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use vpncloud_core::{beacon::BeaconSerializer, util::SystemTimeSource};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(data) = std::str::from_utf8(data) {
+        let ser = BeaconSerializer::<SystemTimeSource>::new(b"fuzzing");
+        ser.decode(data, Some(24));
+        ser.decode_hints(data);
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use vpncloud_core::config::{Config, ConfigFile};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(data) = std::str::from_utf8(data) {
+        if let Ok(file) = ConfigFile::parse(data) {
+            let mut config = Config::default();
+            config.merge_file(file);
+        }
+    }
+});
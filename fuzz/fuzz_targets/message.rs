@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use vpncloud_core::{messages::NodeInfo, util::MsgBuffer};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(info) = NodeInfo::decode(data) {
+        let mut buffer = MsgBuffer::new(0);
+        info.encode(&mut buffer);
+    }
+});
@@ -0,0 +1,121 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Selection of the own addresses that are advertised to the peers and in beacons
+//!
+//! Without a policy, all own addresses are advertised, i.e. the configured ones, the addresses of the socket, the ones
+//! from port forwarding and the ones that peers report. On hosts with container or VM bridges, some of them are only
+//! reachable internally and peers waste time on them when connecting. The policy can leave out private addresses,
+//! restrict the addresses to those of one interface or replace them with a static list.
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::{
+    error::Error,
+    messages::AddrList,
+    net::{interface_ips, is_local_addr, mapped_addr, parse_listen},
+};
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Do not advertise private, unique local and link-local addresses
+    #[serde(default)]
+    pub exclude_private: bool,
+    /// Only advertise addresses of this network interface
+    #[serde(default)]
+    pub interface: Option<String>,
+    /// Advertise exactly these addresses instead of the detected ones
+    #[serde(default)]
+    pub only: Vec<String>,
+}
+
+pub struct AdvertisePolicy {
+    exclude_private: bool,
+    interface: Option<String>,
+    /// Addresses of the interface as of the last refresh, mapped to IPv6
+    interface_ips: Vec<IpAddr>,
+    only: AddrList,
+}
+
+impl AdvertisePolicy {
+    pub fn new(config: &Config, port: u16) -> Result<Self, Error> {
+        let only = config
+            .only
+            .iter()
+            .map(|addr| {
+                parse_listen(addr, port)
+                    .map(mapped_addr)
+                    .map_err(|_| Error::InvalidConfigValue("Invalid advertised address", addr.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        let mut policy = Self {
+            exclude_private: config.exclude_private,
+            interface: config.interface.clone(),
+            interface_ips: vec![],
+            only,
+        };
+        policy.refresh();
+        Ok(policy)
+    }
+
+    /// Looks up the addresses of the interface again as they can change at runtime
+    pub fn refresh(&mut self) {
+        let name = match self.interface {
+            Some(ref name) => name,
+            None => return,
+        };
+        self.interface_ips = match interface_ips(name) {
+            Ok(ips) => ips.into_iter().map(|ip| mapped_addr(SocketAddr::new(ip, 0)).ip()).collect(),
+            Err(err) => {
+                warn!("Failed to get the addresses of interface {}: {}", name, err);
+                vec![]
+            }
+        };
+        if self.interface_ips.is_empty() {
+            warn!("Interface {} has no addresses, no addresses are advertised", name);
+        }
+    }
+
+    /// Whether the own address may be advertised
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        let addr = mapped_addr(*addr);
+        if self.exclude_private && is_local_addr(&addr) {
+            return false;
+        }
+        self.interface.is_none() || self.interface_ips.contains(&addr.ip())
+    }
+
+    /// Selects the addresses to advertise from the own addresses
+    pub fn select(&self, own: &[SocketAddr]) -> AddrList {
+        if !self.only.is_empty() {
+            return self.only.clone();
+        }
+        own.iter().copied().filter(|addr| self.allows(addr)).collect()
+    }
+}
+
+#[test]
+fn advertise_policy() {
+    let own: Vec<SocketAddr> = ["1.2.3.4:3210", "192.168.1.2:3210", "172.17.0.1:3210", "[fe80::1]:3210"]
+        .iter()
+        .map(|addr| mapped_addr(addr.parse().unwrap()))
+        .collect();
+    let policy = AdvertisePolicy::new(&Config::default(), 3210).unwrap();
+    assert_eq!(policy.select(&own).len(), 4);
+    let policy = AdvertisePolicy::new(&Config { exclude_private: true, ..Config::default() }, 3210).unwrap();
+    assert_eq!(&policy.select(&own)[..], &own[..1]);
+    let config = Config { only: vec!["5.6.7.8".to_string(), "[2001:db8::1]:4000".to_string()], ..Config::default() };
+    let policy = AdvertisePolicy::new(&config, 3210).unwrap();
+    assert_eq!(
+        &policy.select(&own)[..],
+        &[mapped_addr("5.6.7.8:3210".parse().unwrap()), "[2001:db8::1]:4000".parse().unwrap()]
+    );
+    // The loopback interface never has the addresses of the other interfaces
+    let policy =
+        AdvertisePolicy::new(&Config { interface: Some("lo".to_string()), ..Config::default() }, 3210).unwrap();
+    assert!(policy.select(&own).is_empty());
+    assert!(policy.allows(&"127.0.0.1:3210".parse().unwrap()) || policy.interface_ips.is_empty());
+    assert!(AdvertisePolicy::new(&Config { only: vec!["no address".to_string()], ..Config::default() }, 3210).is_err());
+}
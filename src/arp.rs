@@ -0,0 +1,181 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+use fnv::FnvHasher;
+use std::{collections::HashMap, hash::BuildHasherDefault, marker::PhantomData, net::Ipv4Addr};
+
+use crate::{
+    payload::ETHERTYPE_ARP,
+    util::{Duration, Time, TimeSource},
+};
+
+type Hash = BuildHasherDefault<FnvHasher>;
+
+pub type MacAddr = [u8; 6];
+
+const ARP_FRAME_LEN: usize = 42;
+const ARP_HTYPE_ETHERNET: u16 = 1;
+const ARP_PTYPE_IPV4: u16 = 0x0800;
+const ARP_OP_REQUEST: u16 = 1;
+const ARP_OP_REPLY: u16 = 2;
+
+struct ArpPacket {
+    op: u16,
+    sender_mac: MacAddr,
+    sender_ip: Ipv4Addr,
+    target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    fn parse(data: &[u8]) -> Option<Self> {
+        // HOT PATH
+        if data.len() < ARP_FRAME_LEN || u16::from_be_bytes([data[12], data[13]]) != ETHERTYPE_ARP {
+            return None;
+        }
+        // COLD PATH
+        let arp = &data[14..ARP_FRAME_LEN];
+        if u16::from_be_bytes([arp[0], arp[1]]) != ARP_HTYPE_ETHERNET
+            || u16::from_be_bytes([arp[2], arp[3]]) != ARP_PTYPE_IPV4
+            || arp[4] != 6
+            || arp[5] != 4
+        {
+            return None;
+        }
+        let mut sender_mac = [0; 6];
+        sender_mac.copy_from_slice(&arp[8..14]);
+        Some(Self {
+            op: u16::from_be_bytes([arp[6], arp[7]]),
+            sender_mac,
+            sender_ip: Ipv4Addr::new(arp[14], arp[15], arp[16], arp[17]),
+            target_ip: Ipv4Addr::new(arp[24], arp[25], arp[26], arp[27]),
+        })
+    }
+}
+
+struct ArpEntry {
+    mac: MacAddr,
+    timeout: Time,
+}
+
+/// A table of IP-to-MAC bindings of hosts behind remote peers
+///
+/// The bindings are learned from ARP frames received from peers and used to answer ARP requests
+/// from the local network without broadcasting them to all peers.
+pub struct ArpTable<TS: TimeSource> {
+    entries: HashMap<Ipv4Addr, ArpEntry, Hash>,
+    timeout: Duration,
+    _dummy: PhantomData<TS>,
+}
+
+impl<TS: TimeSource> ArpTable<TS> {
+    pub fn new(timeout: Duration) -> Self {
+        Self { entries: HashMap::default(), timeout, _dummy: PhantomData }
+    }
+
+    /// Learns the binding of the sender if the frame is an ARP frame
+    pub fn learn(&mut self, data: &[u8]) {
+        // HOT PATH
+        if let Some(packet) = ArpPacket::parse(data) {
+            // COLD PATH
+            if packet.sender_ip.is_unspecified() {
+                return;
+            }
+            self.entries.insert(
+                packet.sender_ip,
+                ArpEntry { mac: packet.sender_mac, timeout: TS::now() + self.timeout as Time },
+            );
+        }
+    }
+
+    pub fn lookup(&self, ip: Ipv4Addr) -> Option<MacAddr> {
+        self.entries.get(&ip).map(|e| e.mac)
+    }
+
+    /// Rewrites the frame into an ARP reply if it is an ARP request for a known address
+    ///
+    /// Returns whether the frame has been rewritten.
+    pub fn answer(&self, data: &mut [u8]) -> bool {
+        // HOT PATH
+        let packet = match ArpPacket::parse(data) {
+            Some(packet) if packet.op == ARP_OP_REQUEST => packet,
+            _ => return false,
+        };
+        // COLD PATH
+        let mac = match self.lookup(packet.target_ip) {
+            Some(mac) if mac != packet.sender_mac => mac,
+            _ => return false,
+        };
+        data[0..6].copy_from_slice(&packet.sender_mac);
+        data[6..12].copy_from_slice(&mac);
+        let arp = &mut data[14..ARP_FRAME_LEN];
+        arp[6..8].copy_from_slice(&ARP_OP_REPLY.to_be_bytes());
+        arp[8..14].copy_from_slice(&mac);
+        arp[14..18].copy_from_slice(&packet.target_ip.octets());
+        arp[18..24].copy_from_slice(&packet.sender_mac);
+        arp[24..28].copy_from_slice(&packet.sender_ip.octets());
+        true
+    }
+
+    pub fn housekeep(&mut self) {
+        let now = TS::now();
+        self.entries.retain(|_, e| e.timeout >= now);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+use crate::util::MockTimeSource;
+
+#[cfg(test)]
+fn arp_frame(op: u16, sender_mac: MacAddr, sender_ip: [u8; 4], target_ip: [u8; 4]) -> Vec<u8> {
+    let mut data = vec![0xff; 6];
+    data.extend_from_slice(&sender_mac);
+    data.extend_from_slice(&[0x08, 0x06, 0, 1, 0x08, 0, 6, 4]);
+    data.extend_from_slice(&op.to_be_bytes());
+    data.extend_from_slice(&sender_mac);
+    data.extend_from_slice(&sender_ip);
+    data.extend_from_slice(&[0; 6]);
+    data.extend_from_slice(&target_ip);
+    data
+}
+
+#[test]
+fn arp_learn_and_answer() {
+    MockTimeSource::set_time(0);
+    let mut table = ArpTable::<MockTimeSource>::new(300);
+    let remote_mac = [2, 2, 2, 2, 2, 2];
+    let local_mac = [1, 1, 1, 1, 1, 1];
+    table.learn(&arp_frame(ARP_OP_REQUEST, remote_mac, [10, 0, 0, 2], [10, 0, 0, 3]));
+    assert_eq!(Some(remote_mac), table.lookup(Ipv4Addr::new(10, 0, 0, 2)));
+    let mut request = arp_frame(ARP_OP_REQUEST, local_mac, [10, 0, 0, 1], [10, 0, 0, 2]);
+    assert!(table.answer(&mut request));
+    let mut expected = arp_frame(ARP_OP_REPLY, remote_mac, [10, 0, 0, 2], [10, 0, 0, 1]);
+    expected[0..6].copy_from_slice(&local_mac);
+    expected[32..38].copy_from_slice(&local_mac);
+    assert_eq!(expected, request);
+    // Unknown targets and replies are not answered
+    let mut request = arp_frame(ARP_OP_REQUEST, local_mac, [10, 0, 0, 1], [10, 0, 0, 4]);
+    assert!(!table.answer(&mut request));
+    let mut reply = arp_frame(ARP_OP_REPLY, local_mac, [10, 0, 0, 1], [10, 0, 0, 2]);
+    assert!(!table.answer(&mut reply));
+}
+
+#[test]
+fn arp_expire() {
+    MockTimeSource::set_time(0);
+    let mut table = ArpTable::<MockTimeSource>::new(300);
+    table.learn(&arp_frame(ARP_OP_REPLY, [2; 6], [10, 0, 0, 2], [10, 0, 0, 1]));
+    table.learn(&[0x08, 0x06, 1, 2, 3]);
+    assert_eq!(1, table.len());
+    MockTimeSource::set_time(301);
+    table.housekeep();
+    assert!(table.is_empty());
+}
@@ -0,0 +1,280 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Admission of peers by an external authority
+//!
+//! After the key of a new peer has been verified, the auth hook decides whether the peer is admitted. The hook is
+//! either a shell command that admits the peer by exiting successfully or a plain HTTP URL that admits the peer by
+//! answering a POST request with a 2xx status. The hook runs in a background thread so that it does not block the
+//! node, the peer waits until the decision arrives. Decisions are cached per key for a while so that reconnecting
+//! peers do not trigger a request every time.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Read, Write},
+    marker::PhantomData,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    process::{Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cloud::Hash,
+    error::Error,
+    util::{addr_nice, Time, TimeSource},
+};
+
+/// Time for which a decision of the hook is reused
+pub const CACHE_TIME: Time = 60;
+/// Time after which a hook that did not answer counts as rejection
+const HOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Hook {
+    Command(String),
+    Http { server: String, path: String },
+}
+
+fn parse_hook(hook: &str) -> Result<Hook, Error> {
+    if hook.starts_with("https://") {
+        return Err(Error::InvalidConfigValue("Only plain HTTP is supported for auth hooks", hook.to_string()));
+    }
+    if let Some(rest) = hook.strip_prefix("http://") {
+        let (server, path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, "/"),
+        };
+        if server.is_empty() {
+            return Err(Error::InvalidConfigValue("Invalid auth hook URL", hook.to_string()));
+        }
+        let server = if server.rfind(':') > server.rfind(']') { server.to_string() } else { format!("{}:80", server) };
+        return Ok(Hook::Http { server, path: path.to_string() });
+    }
+    Ok(Hook::Command(hook.to_string()))
+}
+
+/// Information about the peer that is passed to the hook
+#[derive(Serialize, Debug)]
+pub struct PeerAuth<'a> {
+    pub addr: SocketAddr,
+    pub key: &'a str,
+    pub name: Option<&'a str>,
+}
+
+pub struct AuthHook<TS> {
+    hook: Hook,
+    cache: HashMap<String, (bool, Time), Hash>,
+    running: HashSet<String, Hash>,
+    sender: mpsc::Sender<(String, bool)>,
+    results: mpsc::Receiver<(String, bool)>,
+    _dummy_ts: PhantomData<TS>,
+}
+
+impl<TS: TimeSource> AuthHook<TS> {
+    pub fn new(hook: &str) -> Result<Self, Error> {
+        let (sender, results) = mpsc::channel();
+        Ok(Self {
+            hook: parse_hook(hook)?,
+            cache: HashMap::default(),
+            running: HashSet::default(),
+            sender,
+            results,
+            _dummy_ts: PhantomData,
+        })
+    }
+
+    /// Returns whether the peer is admitted or `None` if the hook has been started to decide it
+    ///
+    /// The decision is available via `decision` once `poll` returned `true`.
+    pub fn check(&mut self, peer: &PeerAuth) -> Option<bool> {
+        if let Some(admitted) = self.decision(peer.key) {
+            return Some(admitted);
+        }
+        if !self.running.insert(peer.key.to_string()) {
+            return None;
+        }
+        let hook = self.hook.clone();
+        let sender = self.sender.clone();
+        let (addr, key, name) = (peer.addr, peer.key.to_string(), peer.name.map(String::from));
+        thread::spawn(move || {
+            let peer = PeerAuth { addr, key: &key, name: name.as_deref() };
+            let admitted = match run(&hook, &peer) {
+                Ok(admitted) => admitted,
+                Err(err) => {
+                    error!("Failed to run auth hook for {}: {}", addr_nice(addr), err);
+                    false
+                }
+            };
+            sender.send((key, admitted)).ok();
+        });
+        None
+    }
+
+    /// Returns the cached decision for the key if there is one
+    pub fn decision(&self, key: &str) -> Option<bool> {
+        match self.cache.get(key) {
+            Some((admitted, timeout)) if *timeout > TS::now() => Some(*admitted),
+            _ => None,
+        }
+    }
+
+    /// Collects the decisions of finished hooks and returns whether there were any
+    pub fn poll(&mut self) -> bool {
+        let mut changed = false;
+        while let Ok((key, admitted)) = self.results.try_recv() {
+            self.running.remove(&key);
+            self.cache.insert(key, (admitted, TS::now() + CACHE_TIME));
+            changed = true;
+        }
+        changed
+    }
+
+    /// Removes expired decisions
+    pub fn housekeep(&mut self) {
+        let now = TS::now();
+        self.cache.retain(|_, (_, timeout)| *timeout > now)
+    }
+}
+
+fn run(hook: &Hook, peer: &PeerAuth) -> Result<bool, io::Error> {
+    match hook {
+        Hook::Command(ref cmd) => run_command(cmd, peer),
+        Hook::Http { ref server, ref path } => run_http(server, path, peer),
+    }
+}
+
+fn run_command(script: &str, peer: &PeerAuth) -> Result<bool, io::Error> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(script)
+        .env("EVENT", "auth")
+        .env("PEER", addr_nice(peer.addr).to_string())
+        .env("PEER_KEY", peer.key)
+        .env("PEER_NAME", peer.name.unwrap_or_default())
+        .stdin(Stdio::null());
+    debug!("Running auth hook: {:?}", cmd);
+    let mut child = cmd.spawn()?;
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status.success());
+        }
+        if start.elapsed() > HOOK_TIMEOUT {
+            warn!("Auth hook did not finish in time, killing it");
+            child.kill().ok();
+            child.wait().ok();
+            return Ok(false);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn run_http(server: &str, path: &str, peer: &PeerAuth) -> Result<bool, io::Error> {
+    let addr = match server.to_socket_addrs()?.next() {
+        Some(addr) => addr,
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, "Auth hook server can not be resolved")),
+    };
+    let mut stream = TcpStream::connect_timeout(&addr, HOOK_TIMEOUT)?;
+    stream.set_read_timeout(Some(HOOK_TIMEOUT))?;
+    stream.set_write_timeout(Some(HOOK_TIMEOUT))?;
+    let body = serde_json::to_string(peer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    debug!("Asking auth hook at {}{}", server, path);
+    let request = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        server,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes())?;
+    let mut response = Vec::new();
+    stream.take(4096).read_to_end(&mut response)?;
+    let status = String::from_utf8_lossy(&response).split_whitespace().nth(1).and_then(|s| s.parse::<u16>().ok());
+    match status {
+        Some(code) => Ok((200..300).contains(&code)),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid HTTP response from auth hook")),
+    }
+}
+
+#[test]
+fn auth_hook_parse() {
+    assert_eq!(parse_hook("exit 0").unwrap(), Hook::Command("exit 0".to_string()));
+    assert_eq!(
+        parse_hook("http://auth.example.com/check").unwrap(),
+        Hook::Http { server: "auth.example.com:80".to_string(), path: "/check".to_string() }
+    );
+    assert_eq!(
+        parse_hook("http://[::1]:8080").unwrap(),
+        Hook::Http { server: "[::1]:8080".to_string(), path: "/".to_string() }
+    );
+    assert!(parse_hook("https://auth.example.com/check").is_err());
+    assert!(parse_hook("http:///check").is_err());
+}
+
+#[cfg(test)]
+fn wait_for_decisions<TS: TimeSource>(hook: &mut AuthHook<TS>, count: usize) {
+    let start = Instant::now();
+    while hook.cache.len() < count {
+        assert!(start.elapsed() < HOOK_TIMEOUT * 2, "Auth hook did not decide in time");
+        hook.poll();
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(hook.running.is_empty());
+}
+
+#[test]
+fn auth_hook_command() {
+    use crate::util::MockTimeSource;
+    MockTimeSource::set_time(0);
+    let mut hook = AuthHook::<MockTimeSource>::new("test \"$PEER_NAME\" = node1 && test \"$PEER_KEY\" = key1").unwrap();
+    let addr = "1.2.3.4:3210".parse().unwrap();
+    assert_eq!(hook.check(&PeerAuth { addr, key: "key1", name: Some("node1") }), None);
+    assert_eq!(hook.check(&PeerAuth { addr, key: "key2", name: Some("node1") }), None);
+    wait_for_decisions(&mut hook, 2);
+    assert_eq!(hook.check(&PeerAuth { addr, key: "key1", name: Some("node1") }), Some(true));
+    // Decisions only depend on the key
+    assert_eq!(hook.check(&PeerAuth { addr: "1.2.3.5:3210".parse().unwrap(), key: "key2", name: None }), Some(false));
+    MockTimeSource::set_time(CACHE_TIME);
+    hook.housekeep();
+    assert!(hook.cache.is_empty());
+}
+
+#[test]
+fn auth_hook_http() {
+    use crate::util::MockTimeSource;
+    use std::net::TcpListener;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/check", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let mut requests = vec![];
+        for status in &["200 OK", "403 Forbidden"] {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            let mut buffer = [0; 4096];
+            while request.last() != Some(&b'}') {
+                let size = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..size]);
+            }
+            requests.push(String::from_utf8(request).unwrap());
+            write!(stream, "HTTP/1.0 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+        }
+        requests
+    });
+    MockTimeSource::set_time(0);
+    let mut hook = AuthHook::<MockTimeSource>::new(&url).unwrap();
+    let addr = "1.2.3.4:3210".parse().unwrap();
+    assert_eq!(hook.check(&PeerAuth { addr, key: "key1", name: Some("node1") }), None);
+    wait_for_decisions(&mut hook, 1);
+    assert_eq!(hook.check(&PeerAuth { addr, key: "key2", name: None }), None);
+    wait_for_decisions(&mut hook, 2);
+    // Cached decisions do not cause requests
+    assert_eq!(hook.check(&PeerAuth { addr, key: "key1", name: Some("node1") }), Some(true));
+    assert_eq!(hook.check(&PeerAuth { addr, key: "key2", name: None }), Some(false));
+    let requests = server.join().unwrap();
+    assert!(requests[0].starts_with("POST /check HTTP/1.0\r\n"));
+    assert!(requests[0].ends_with("{\"addr\":\"1.2.3.4:3210\",\"key\":\"key1\",\"name\":\"node1\"}"));
+    assert!(requests[1].ends_with("{\"addr\":\"1.2.3.4:3210\",\"key\":\"key2\",\"name\":null}"));
+}
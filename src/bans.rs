@@ -0,0 +1,233 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Temporary bans of addresses that repeatedly fail to authenticate, see the `bans` config section
+//!
+//! Handshakes that fail after the node sent its reply to the first handshake message as well as peers that are not
+//! admitted count as failures of the source IP address. Earlier failures are not counted as the source address of a
+//! single message can be spoofed. Addresses of established or configured peers are never banned. After
+//! `max-failures` failures within `find-time`, all packets from the address are dropped right after they are received
+//! for `ban-time`. Each further ban of the same address lasts `backoff` times longer, up to `max-ban-time`. Bans can be
+//! listed and lifted via the control socket.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    marker::PhantomData,
+    net::{IpAddr, SocketAddr},
+};
+
+use crate::{
+    cloud::Hash,
+    util::{addr_nice, Duration, Time, TimeSource},
+};
+
+/// Maximal number of tracked addresses, failures of further addresses are ignored
+const MAX_ENTRIES: usize = 65536;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Config {
+    /// Failures that lead to a ban
+    pub max_failures: u32,
+    /// Time window in seconds in which the failures are counted
+    pub find_time: Duration,
+    /// Duration of the first ban in seconds
+    pub ban_time: Duration,
+    /// Factor by which each further ban of the same address is longer
+    pub backoff: u32,
+    /// Maximal duration of a ban in seconds
+    pub max_ban_time: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { max_failures: 5, find_time: 600, ban_time: 600, backoff: 2, max_ban_time: 86400 }
+    }
+}
+
+#[derive(Default)]
+struct Entry {
+    failures: u32,
+    first_failure: Time,
+    bans: u32,
+    banned_until: Option<Time>,
+    last_activity: Time,
+}
+
+pub struct BanList<TS: TimeSource> {
+    config: Config,
+    entries: HashMap<IpAddr, Entry, Hash>,
+    banned: usize,
+    dropped: usize,
+    bans: usize,
+    _dummy: PhantomData<TS>,
+}
+
+fn ip_nice(ip: IpAddr) -> IpAddr {
+    addr_nice(SocketAddr::new(ip, 0)).0.ip()
+}
+
+impl<TS: TimeSource> BanList<TS> {
+    pub fn new(config: Config) -> Self {
+        Self { config, entries: HashMap::default(), banned: 0, dropped: 0, bans: 0, _dummy: PhantomData }
+    }
+
+    /// Checks whether packets from the address must be dropped
+    #[inline]
+    pub fn is_banned(&mut self, ip: IpAddr) -> bool {
+        // HOT PATH
+        if self.banned == 0 {
+            return false;
+        }
+        // COLD PATH
+        match self.entries.get(&ip).and_then(|entry| entry.banned_until) {
+            Some(until) if until > TS::now() => {
+                self.dropped += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Counts an authentication failure of the address and bans it if there were too many
+    pub fn failed(&mut self, ip: IpAddr) {
+        if self.entries.len() >= MAX_ENTRIES && !self.entries.contains_key(&ip) {
+            return;
+        }
+        let now = TS::now();
+        let entry = self.entries.entry(ip).or_default();
+        entry.last_activity = now;
+        if entry.banned_until.map(|until| until > now).unwrap_or(false) {
+            return;
+        }
+        if entry.failures == 0 || now - entry.first_failure > Time::from(self.config.find_time) {
+            entry.failures = 0;
+            entry.first_failure = now;
+        }
+        entry.failures += 1;
+        debug!("Authentication failure {} of {}", entry.failures, ip_nice(ip));
+        if entry.failures < self.config.max_failures {
+            return;
+        }
+        let factor = Time::from(self.config.backoff.max(1)).saturating_pow(entry.bans);
+        let max_duration = Time::from(self.config.max_ban_time);
+        let duration = Time::from(self.config.ban_time).saturating_mul(factor).min(max_duration);
+        warn!("Banning {} for {} seconds after {} authentication failures", ip_nice(ip), duration, entry.failures);
+        if entry.banned_until.is_none() {
+            self.banned += 1;
+        }
+        entry.failures = 0;
+        entry.bans += 1;
+        entry.banned_until = Some(now + duration);
+        self.bans += 1;
+    }
+
+    /// Lifts the ban of the address or of all addresses and returns the number of lifted bans
+    pub fn clear(&mut self, ip: Option<IpAddr>) -> usize {
+        let mut count = 0;
+        for (addr, entry) in &mut self.entries {
+            if ip.map(|ip| ip == *addr).unwrap_or(true) {
+                if entry.banned_until.take().is_some() {
+                    info!("Lifted ban of {}", ip_nice(*addr));
+                    count += 1;
+                }
+                entry.failures = 0;
+                entry.bans = 0;
+            }
+        }
+        self.banned -= count;
+        count
+    }
+
+    /// Lifts expired bans and forgets addresses whose last failure is older than the maximal ban time
+    pub fn housekeep(&mut self) {
+        let now = TS::now();
+        let mut expired = 0;
+        for (addr, entry) in &mut self.entries {
+            if entry.banned_until.map(|until| until <= now).unwrap_or(false) {
+                info!("Ban of {} expired", ip_nice(*addr));
+                entry.banned_until = None;
+                expired += 1;
+            }
+        }
+        self.banned -= expired;
+        let forget_after = Time::from(self.config.max_ban_time.max(self.config.find_time));
+        self.entries.retain(|_, entry| entry.banned_until.is_some() || entry.last_activity + forget_after > now);
+    }
+
+    /// Writes the banned addresses with their remaining seconds and the number of their bans, one per line
+    pub fn write_list<W: Write>(&self, out: &mut W) -> Result<(), io::Error> {
+        let now = TS::now();
+        let mut banned: Vec<_> = self
+            .entries
+            .iter()
+            .filter_map(|(addr, entry)| entry.banned_until.map(|until| (*addr, until - now, entry.bans)))
+            .filter(|(_, remaining, _)| *remaining > 0)
+            .collect();
+        banned.sort_unstable();
+        for (addr, remaining, bans) in banned {
+            writeln!(out, "{} {} {}", ip_nice(addr), remaining, bans)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_out<W: Write>(&self, out: &mut W) -> Result<(), io::Error> {
+        writeln!(out, "bans:")?;
+        writeln!(out, "  banned: {}", self.banned)?;
+        writeln!(out, "  total: {}", self.bans)?;
+        writeln!(out, "  dropped: {}", self.dropped)?;
+        Ok(())
+    }
+
+    pub fn banned_count(&self) -> usize {
+        self.banned
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    pub fn bans(&self) -> usize {
+        self.bans
+    }
+}
+
+#[test]
+fn ban_with_backoff() {
+    use crate::util::MockTimeSource;
+    MockTimeSource::set_time(1000);
+    let config = Config { max_failures: 3, find_time: 60, ban_time: 100, backoff: 2, max_ban_time: 300 };
+    let mut bans = BanList::<MockTimeSource>::new(config);
+    let ip: IpAddr = "1.2.3.4".parse().unwrap();
+    bans.failed(ip);
+    bans.failed(ip);
+    assert!(!bans.is_banned(ip));
+    // Failures outside of the time window do not count
+    MockTimeSource::set_time(1100);
+    bans.failed(ip);
+    bans.failed(ip);
+    assert!(!bans.is_banned(ip));
+    bans.failed(ip);
+    assert!(bans.is_banned(ip));
+    assert!(!bans.is_banned("1.2.3.5".parse().unwrap()));
+    let mut list = vec![];
+    bans.write_list(&mut list).unwrap();
+    assert_eq!(String::from_utf8(list).unwrap(), "1.2.3.4 100 1\n");
+    // The next ban takes twice as long
+    MockTimeSource::set_time(1200);
+    bans.housekeep();
+    assert!(!bans.is_banned(ip));
+    assert_eq!(bans.banned_count(), 0);
+    for _ in 0..3 {
+        bans.failed(ip);
+    }
+    MockTimeSource::set_time(1399);
+    assert!(bans.is_banned(ip));
+    assert_eq!(bans.bans(), 2);
+    assert_eq!(bans.clear(Some(ip)), 1);
+    assert!(!bans.is_banned(ip));
+    assert_eq!(bans.clear(None), 0);
+    assert_eq!(bans.dropped(), 2);
+}
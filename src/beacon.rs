@@ -28,6 +28,11 @@ const TYPE_BEGIN: u8 = 0;
 const TYPE_END: u8 = 1;
 const TYPE_DATA: u8 = 2;
 const TYPE_SEED: u8 = 3;
+const TYPE_HINTS_BEGIN: u8 = 4;
+const TYPE_HINTS: u8 = 5;
+
+const HINT_VERSION: u8 = 1;
+const HINT_UDP_PORT: u8 = 2;
 
 fn base_62_sanitize(data: &str) -> String {
     data.chars().filter(|c| c.is_ascii_alphanumeric()).collect()
@@ -37,6 +42,15 @@ fn sha512(data: &[u8]) -> SmallVec<[u8; 64]> {
     digest::digest(&digest::SHA512, data).as_ref().into()
 }
 
+/// Transport hints that can optionally be published along with the peer list
+///
+/// The hints are stored in a separate block of the beacon so that older versions just ignore them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BeaconHints {
+    pub version: Option<u8>,
+    pub udp_port: Option<u16>,
+}
+
 struct FutureResult<T> {
     has_result: AtomicBool,
     result: Mutex<T>,
@@ -45,7 +59,8 @@ struct FutureResult<T> {
 #[derive(Clone)]
 pub struct BeaconSerializer<TS> {
     shared_key: Vec<u8>,
-    future_peers: Arc<FutureResult<Vec<SocketAddr>>>,
+    hints: Option<BeaconHints>,
+    future_peers: Arc<FutureResult<(Vec<SocketAddr>, Option<BeaconHints>)>>,
     _dummy_ts: PhantomData<TS>,
 }
 
@@ -53,11 +68,19 @@ impl<TS: TimeSource> BeaconSerializer<TS> {
     pub fn new(shared_key: &[u8]) -> Self {
         Self {
             shared_key: shared_key.to_owned(),
-            future_peers: Arc::new(FutureResult { has_result: AtomicBool::new(false), result: Mutex::new(Vec::new()) }),
+            hints: None,
+            future_peers: Arc::new(FutureResult {
+                has_result: AtomicBool::new(false),
+                result: Mutex::new((Vec::new(), None)),
+            }),
             _dummy_ts: PhantomData,
         }
     }
 
+    pub fn set_hints(&mut self, hints: Option<BeaconHints>) {
+        self.hints = hints
+    }
+
     fn now_hour_16() -> u16 {
         ((TS::now() / 3600) & 0xffff) as u16
     }
@@ -93,21 +116,25 @@ impl<TS: TimeSource> BeaconSerializer<TS> {
         to_base62(&self.get_keystream(TYPE_END, 0, 0))[0..5].to_string()
     }
 
-    fn encrypt_data(&self, data: &mut Vec<u8>) {
+    fn hints_begin(&self) -> String {
+        to_base62(&self.get_keystream(TYPE_HINTS_BEGIN, 0, 0))[0..5].to_string()
+    }
+
+    fn encrypt_data(&self, data: &mut Vec<u8>, type_: u8) {
         // Note: the 1 byte seed is only meant to protect from random changes,
         // not malicious ones. For full protection, at least 8 bytes (~12
         // characters) would be needed.
         let seed = sha512(data as &[u8])[0];
-        self.mask_with_keystream(data as &mut [u8], TYPE_DATA, seed);
+        self.mask_with_keystream(data as &mut [u8], type_, seed);
         data.push(seed ^ self.get_keystream(TYPE_SEED, 0, 0)[0]);
     }
 
-    fn decrypt_data(&self, data: &mut Vec<u8>) -> bool {
+    fn decrypt_data(&self, data: &mut Vec<u8>, type_: u8) -> bool {
         if data.is_empty() {
             return false;
         }
         let seed = data.pop().unwrap() ^ self.get_keystream(TYPE_SEED, 0, 0)[0];
-        self.mask_with_keystream(data as &mut [u8], TYPE_DATA, seed);
+        self.mask_with_keystream(data as &mut [u8], type_, seed);
         seed == sha512(data as &[u8])[0]
     }
 
@@ -148,18 +175,63 @@ impl<TS: TimeSource> BeaconSerializer<TS> {
             Encoder::write_u16(addr.port(), &mut dat[16..]);
             data.extend_from_slice(&dat);
         }
-        self.encrypt_data(&mut data);
+        self.encrypt_data(&mut data, TYPE_DATA);
+        to_base62(&data)
+    }
+
+    fn hints_encode(&self, hints: &BeaconHints) -> String {
+        let mut data = Vec::new();
+        if let Some(version) = hints.version {
+            data.extend_from_slice(&[HINT_VERSION, 1, version]);
+        }
+        if let Some(port) = hints.udp_port {
+            data.extend_from_slice(&[HINT_UDP_PORT, 2]);
+            data.extend_from_slice(&port.to_be_bytes());
+        }
+        self.encrypt_data(&mut data, TYPE_HINTS);
         to_base62(&data)
     }
 
+    fn hints_decode(&self, data: &str) -> Option<BeaconHints> {
+        let mut data = from_base62(data).ok()?;
+        if !self.decrypt_data(&mut data, TYPE_HINTS) {
+            return None;
+        }
+        let mut hints = BeaconHints::default();
+        let mut pos = 0;
+        while pos + 2 <= data.len() {
+            let type_ = data[pos];
+            let len = data[pos + 1] as usize;
+            pos += 2;
+            if pos + len > data.len() {
+                return None;
+            }
+            let value = &data[pos..pos + len];
+            pos += len;
+            match (type_, len) {
+                (HINT_VERSION, 1) => hints.version = Some(value[0]),
+                (HINT_UDP_PORT, 2) => hints.udp_port = Some(Encoder::read_u16(value)),
+                // Ignore unknown hints so they can be added later
+                _ => continue,
+            }
+        }
+        if pos != data.len() {
+            return None;
+        }
+        Some(hints)
+    }
+
     fn peerlist_decode(&self, data: &str, ttl_hours: Option<u16>) -> Vec<SocketAddr> {
-        let mut data = from_base62(data).expect("Invalid input");
         let mut peers = Vec::new();
+        let mut data = match from_base62(data) {
+            Ok(data) => data,
+            Err(_) => return peers,
+        };
         let mut pos = 0;
         if data.len() < 4 {
             return peers;
         }
-        if !self.decrypt_data(&mut data) {
+        if !self.decrypt_data(&mut data, TYPE_DATA) {
             return peers;
         }
         let then = Wrapping(Encoder::read_u16(&data[pos..=pos + 1]));
@@ -205,7 +277,11 @@ impl<TS: TimeSource> BeaconSerializer<TS> {
     }
 
     pub fn encode(&self, peers: &[SocketAddr]) -> String {
-        format!("{}{}{}", self.begin(), self.peerlist_encode(peers), self.end())
+        let mut beacon = format!("{}{}{}", self.begin(), self.peerlist_encode(peers), self.end());
+        if let Some(ref hints) = self.hints {
+            beacon.push_str(&format!("{}{}{}", self.hints_begin(), self.hints_encode(hints), self.end()));
+        }
+        beacon
     }
 
     pub fn write_to_file<P: AsRef<Path>>(&self, peers: &[SocketAddr], path: P) -> Result<(), io::Error> {
@@ -225,7 +301,7 @@ impl<TS: TimeSource> BeaconSerializer<TS> {
         let begin = self.begin();
         let data = self.peerlist_encode(peers);
         let end = self.end();
-        let beacon = format!("{}{}{}", begin, data, end);
+        let beacon = self.encode(peers);
         debug!("Calling beacon command: {}", cmd);
         let process = Command::new("sh")
             .args(&["-c", cmd])
@@ -256,8 +332,8 @@ impl<TS: TimeSource> BeaconSerializer<TS> {
         while let Some(found) = data[pos..].find(&begin) {
             pos += found;
             let start_pos = pos + begin.len();
-            if let Some(found) = data[pos..].find(&end) {
-                let end_pos = pos + found;
+            if let Some(found) = data[start_pos..].find(&end) {
+                let end_pos = start_pos + found;
                 peers.append(&mut self.peerlist_decode(&data[start_pos..end_pos], ttl_hours));
                 pos = start_pos
             } else {
@@ -267,13 +343,38 @@ impl<TS: TimeSource> BeaconSerializer<TS> {
         peers
     }
 
+    pub fn decode_hints(&self, data: &str) -> Option<BeaconHints> {
+        let data = base_62_sanitize(data);
+        let begin = self.hints_begin();
+        let end = self.end();
+        let mut pos = 0;
+        while let Some(found) = data[pos..].find(&begin) {
+            pos += found;
+            let start_pos = pos + begin.len();
+            if let Some(found) = data[start_pos..].find(&end) {
+                if let Some(hints) = self.hints_decode(&data[start_pos..start_pos + found]) {
+                    return Some(hints);
+                }
+                pos = start_pos
+            } else {
+                break;
+            }
+        }
+        None
+    }
+
+    /// Decodes the peers and the transport hints of a beacon
+    fn decode_with_hints(&self, data: &str, ttl_hours: Option<u16>) -> (Vec<SocketAddr>, Option<BeaconHints>) {
+        (self.decode(data, ttl_hours), self.decode_hints(data))
+    }
+
     pub fn read_from_file<P: AsRef<Path>>(
         &self, path: P, ttl_hours: Option<u16>,
-    ) -> Result<Vec<SocketAddr>, io::Error> {
+    ) -> Result<(Vec<SocketAddr>, Option<BeaconHints>), io::Error> {
         let mut f = File::open(&path)?;
         let mut contents = String::new();
         f.read_to_string(&mut contents)?;
-        Ok(self.decode(&contents, ttl_hours))
+        Ok(self.decode_with_hints(&contents, ttl_hours))
     }
 
     pub fn read_from_cmd(&self, cmd: &str, ttl_hours: Option<u16>) -> Result<(), io::Error> {
@@ -292,9 +393,9 @@ impl<TS: TimeSource> BeaconSerializer<TS> {
             let output = process.wait_with_output().expect("Failed to wait on child");
             if output.status.success() {
                 let data = String::from_utf8_lossy(&output.stdout);
-                let mut peers = this.decode(&data, ttl_hours);
-                debug!("Beacon command succeeded with {} peers", peers.len());
-                mem::swap(&mut peers, &mut this.future_peers.result.lock().expect("Lock poisoned"));
+                let mut result = this.decode_with_hints(&data, ttl_hours);
+                debug!("Beacon command succeeded with {} peers", result.0.len());
+                mem::swap(&mut result, &mut this.future_peers.result.lock().expect("Lock poisoned"));
                 this.future_peers.has_result.store(true, Ordering::Relaxed);
             } else {
                 error!("Beacon command failed: {}", String::from_utf8_lossy(&output.stderr));
@@ -303,12 +404,12 @@ impl<TS: TimeSource> BeaconSerializer<TS> {
         Ok(())
     }
 
-    pub fn get_cmd_results(&self) -> Option<Vec<SocketAddr>> {
+    pub fn get_cmd_results(&self) -> Option<(Vec<SocketAddr>, Option<BeaconHints>)> {
         if self.future_peers.has_result.load(Ordering::Relaxed) {
-            let mut peers = Vec::new();
-            mem::swap(&mut peers, &mut self.future_peers.result.lock().expect("Lock poisoned"));
+            let mut result = (Vec::new(), None);
+            mem::swap(&mut result, &mut self.future_peers.result.lock().expect("Lock poisoned"));
             self.future_peers.has_result.store(false, Ordering::Relaxed);
-            Some(peers)
+            Some(result)
         } else {
             None
         }
@@ -421,6 +522,18 @@ fn decode_invalid() {
     assert_eq!(2, ser.decode("WsHI3WsHI31EWDMBYxvITiILIrm2k9gEik22Eik22E", None).len());
 }
 
+#[test]
+fn decode_garbage() {
+    MockTimeSource::set_time(2000 * 3600);
+    let ser = BeaconSerializer::<MockTimeSource>::new(b"mysecretkey");
+    for i in 0..=255u8 {
+        let junk = to_base62(&sha512(&[i]));
+        let junk = &junk[..i as usize % junk.len()];
+        ser.decode(&format!("{}{}{}", ser.begin(), junk, ser.end()), Some(24));
+        ser.decode_hints(&format!("{}{}{}", ser.hints_begin(), junk, ser.end()));
+    }
+}
+
 #[test]
 fn encode_decode() {
     MockTimeSource::set_time(2000 * 3600);
@@ -431,16 +544,36 @@ fn encode_decode() {
     assert_eq!(format!("{:?}", peers), format!("{:?}", peers2));
 }
 
+#[test]
+fn encode_decode_hints() {
+    MockTimeSource::set_time(2000 * 3600);
+    let mut ser = BeaconSerializer::<MockTimeSource>::new(b"mysecretkey");
+    let peers = vec![SocketAddr::from_str("1.2.3.4:5678").unwrap(), SocketAddr::from_str("6.6.6.6:53").unwrap()];
+    assert_eq!(None, ser.decode_hints(&ser.encode(&peers)));
+    let hints = BeaconHints { version: Some(1), udp_port: Some(3210) };
+    ser.set_hints(Some(hints.clone()));
+    let data = ser.encode(&peers);
+    assert!(data.starts_with("WsHI31EWDMBYxvITiILIrm2k9gEik22E"));
+    assert_eq!(Some(hints), ser.decode_hints(&data));
+    assert_eq!(format!("{:?}", peers), format!("{:?}", ser.decode(&data, None)));
+    let ser2 = BeaconSerializer::<MockTimeSource>::new(b"otherkey");
+    assert_eq!(None, ser2.decode_hints(&data));
+}
+
 #[test]
 fn encode_decode_file() {
     MockTimeSource::set_time(2000 * 3600);
-    let ser = BeaconSerializer::<MockTimeSource>::new(b"mysecretkey");
+    let mut ser = BeaconSerializer::<MockTimeSource>::new(b"mysecretkey");
+    let hints = BeaconHints { version: Some(1), udp_port: Some(3210) };
+    ser.set_hints(Some(hints.clone()));
     let peers = vec![SocketAddr::from_str("1.2.3.4:5678").unwrap(), SocketAddr::from_str("6.6.6.6:53").unwrap()];
     let file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
     assert!(ser.write_to_file(&peers, file.path()).is_ok());
     let peers2 = ser.read_from_file(file.path(), None);
     assert!(peers2.is_ok());
-    assert_eq!(format!("{:?}", peers), format!("{:?}", peers2.unwrap()));
+    let (peers2, hints2) = peers2.unwrap();
+    assert_eq!(format!("{:?}", peers), format!("{:?}", peers2));
+    assert_eq!(Some(hints), hints2);
 }
 
 #[test]
@@ -456,5 +589,5 @@ fn encode_decode_cmd() {
     thread::sleep(Duration::from_millis(100));
     let peers2 = ser.get_cmd_results();
     assert!(peers2.is_some());
-    assert_eq!(format!("{:?}", peers), format!("{:?}", peers2.unwrap()));
+    assert_eq!(format!("{:?}", peers), format!("{:?}", peers2.unwrap().0));
 }
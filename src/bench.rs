@@ -0,0 +1,173 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Measurements of the performance of the current machine, see `vpncloud bench`
+
+use ring::aead;
+use smallvec::smallvec;
+use std::{
+    fmt,
+    net::{SocketAddr, UdpSocket},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cloud::GenericCloud,
+    config::Config,
+    crypto::{test_speed, CpuFeatures},
+    device::{MockDevice, Type},
+    error::Error,
+    payload::Packet,
+    table::ClaimTable,
+    types::{Address, Range},
+    util::SystemTimeSource,
+};
+
+const PAYLOAD_SIZE: usize = 1400;
+const CLAIMS: u8 = 100;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+type BenchNode = GenericCloud<MockDevice, Packet, UdpSocket, SystemTimeSource>;
+
+pub struct Report {
+    /// CPU features used by the crypto algorithms
+    pub cpu: CpuFeatures,
+    /// Throughput of encryption and decryption in MiB/s per algorithm
+    pub crypto: Vec<(&'static str, f64)>,
+    /// Table lookups per second without cache
+    pub lookup_cold: f64,
+    /// Table lookups per second with cache
+    pub lookup_warm: f64,
+    /// Forwarded packets per second between two nodes on the loopback interface
+    pub forwarding: f64,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        writeln!(formatter, "Crypto implementations: {}", self.cpu.describe())?;
+        writeln!(formatter, "Crypto throughput (encrypt + decrypt):")?;
+        for (name, speed) in &self.crypto {
+            writeln!(formatter, "  {:<10} {:>10.1} MiB/s", name, speed)?;
+        }
+        writeln!(formatter, "Table lookups ({} claims):", CLAIMS)?;
+        writeln!(formatter, "  {:<10} {:>10.1} M/s", "cold", self.lookup_cold / 1_000_000.0)?;
+        writeln!(formatter, "  {:<10} {:>10.1} M/s", "warm", self.lookup_warm / 1_000_000.0)?;
+        writeln!(formatter, "Loopback forwarding ({} byte packets):", PAYLOAD_SIZE)?;
+        writeln!(
+            formatter,
+            "  {:<10} {:>10.1} k/s  {:.1} MiB/s",
+            "packets",
+            self.forwarding / 1000.0,
+            self.forwarding * PAYLOAD_SIZE as f64 / 1_048_576.0
+        )
+    }
+}
+
+/// Measures the throughput of all supported crypto algorithms in MiB/s
+pub fn crypto(duration: Duration) -> Vec<(&'static str, f64)> {
+    vec![
+        ("AES128", test_speed(&aead::AES_128_GCM, &duration)),
+        ("AES256", test_speed(&aead::AES_256_GCM, &duration)),
+        ("CHACHA20", test_speed(&aead::CHACHA20_POLY1305, &duration)),
+    ]
+}
+
+fn lookups_per_second<F: FnMut()>(duration: Duration, mut f: F) -> f64 {
+    let mut count = 0;
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        for _ in 0..1000 {
+            f()
+        }
+        count += 1000;
+    }
+    count as f64 / start.elapsed().as_secs_f64()
+}
+
+/// Measures the table lookups per second without and with cache
+pub fn table_lookups(duration: Duration) -> (f64, f64) {
+    let mut table = ClaimTable::<SystemTimeSource>::new(60, 60);
+    for i in 0..CLAIMS {
+        let peer = SocketAddr::from_str(&format!("192.168.0.{}:3210", i)).unwrap();
+        table.set_claims(peer, smallvec![Range::from_str(&format!("10.{}.0.0/16", i)).unwrap()]);
+    }
+    let addr = Address::from_str(&format!("10.{}.1.2", CLAIMS - 1)).unwrap();
+    let cold = lookups_per_second(duration, || {
+        table.clear_cache();
+        table.lookup(addr);
+    });
+    let warm = lookups_per_second(duration, || {
+        table.lookup(addr);
+    });
+    (cold, warm)
+}
+
+/// Nodes are boxed as two of them do not fit on the stack of a thread
+fn create_node(claim: &str) -> Result<(Box<BenchNode>, SocketAddr), Error> {
+    // Fall back to IPv4 on machines without IPv6
+    let socket = UdpSocket::bind("[::1]:0")
+        .or_else(|_| UdpSocket::bind("127.0.0.1:0"))
+        .map_err(|e| Error::SocketIo("Failed to open socket", e))?;
+    socket.set_read_timeout(Some(READ_TIMEOUT)).map_err(|e| Error::SocketIo("Failed to set timeout", e))?;
+    let addr = socket.local_addr().map_err(|e| Error::SocketIo("Failed to get socket address", e))?;
+    let mut config =
+        Config { device_type: Type::Tun, auto_claim: false, claims: vec![claim.to_string()], ..Config::default() };
+    config.crypto.password = Some("benchmark".to_string());
+    Ok((Box::new(BenchNode::new(&config, socket, MockDevice::new(), None, None)?), addr))
+}
+
+/// Processes messages on both nodes until no more messages arrive
+fn drain(node1: &mut BenchNode, node2: &mut BenchNode) {
+    while node1.process_socket_event().is_ok() || node2.process_socket_event().is_ok() {}
+}
+
+/// Measures the packets per second that are forwarded from one node to another via loopback
+pub fn forwarding(duration: Duration) -> Result<f64, Error> {
+    let (mut node1, addr1) = create_node("1.1.1.1/32")?;
+    let (mut node2, addr2) = create_node("2.2.2.2/32")?;
+    node1.connect(addr2)?;
+    let start = Instant::now();
+    while !(node1.has_peer(&addr2) && node2.has_peer(&addr1)) {
+        if start.elapsed() > CONNECT_TIMEOUT {
+            return Err(Error::Socket("Benchmark nodes failed to connect"));
+        }
+        drain(&mut node1, &mut node2);
+    }
+    drain(&mut node1, &mut node2);
+    let mut packet = vec![0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2];
+    packet.resize(PAYLOAD_SIZE, 0);
+    let mut count = 0;
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        node1.mock_device().put_inbound(packet.clone());
+        node1.process_device_event()?;
+        node2.process_socket_event()?;
+        if node2.mock_device().pop_outbound().is_some() {
+            count += 1;
+        }
+    }
+    Ok(f64::from(count) / start.elapsed().as_secs_f64())
+}
+
+/// Runs all measurements, each of them for the given duration
+pub fn run(duration: Duration) -> Result<Report, Error> {
+    let crypto = crypto(duration);
+    let (lookup_cold, lookup_warm) = table_lookups(duration);
+    let forwarding = forwarding(duration)?;
+    Ok(Report { cpu: CpuFeatures::detect(), crypto, lookup_cold, lookup_warm, forwarding })
+}
+
+#[test]
+fn bench_table_lookups() {
+    let (cold, warm) = table_lookups(Duration::from_millis(10));
+    assert!(cold > 0.0);
+    assert!(warm > 0.0);
+}
+
+#[test]
+fn bench_forwarding() {
+    assert!(forwarding(Duration::from_millis(50)).unwrap() > 0.0);
+}
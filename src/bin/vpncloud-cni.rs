@@ -0,0 +1,284 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! CNI plugin that attaches pods to the overlay of a node-local VpnCloud instance
+//!
+//! The VpnCloud instance has to run in switch mode with a TAP device. Every pod gets a veth pair
+//! whose host side is part of a bridge together with the TAP device, so pod addresses are learned
+//! by the switch like any other host on the overlay and no claims have to be configured.
+//!
+//! Addresses are allocated from `subnet` (optionally limited to `rangeStart`-`rangeEnd` so that
+//! nodes sharing one overlay do not collide) and are stored as files in `dataDir`.
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Read, Write},
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+};
+
+const CNI_VERSION: &str = "0.4.0";
+const SUPPORTED_VERSIONS: [&str; 3] = ["0.3.0", "0.3.1", "0.4.0"];
+const DEFAULT_DATA_DIR: &str = "/var/lib/cni/vpncloud";
+
+// Error codes as defined by the CNI specification
+const ERR_INCOMPATIBLE_VERSION: u32 = 1;
+const ERR_UNKNOWN_CONTAINER: u32 = 3;
+const ERR_INVALID_ENV: u32 = 4;
+const ERR_IO: u32 = 5;
+const ERR_DECODE: u32 = 6;
+const ERR_INVALID_CONFIG: u32 = 7;
+const ERR_TRY_AGAIN: u32 = 11;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NetConf {
+    cni_version: String,
+    name: String,
+    /// Name of the VpnCloud TAP device
+    #[serde(default)]
+    device: Option<String>,
+    #[serde(default)]
+    bridge: Option<String>,
+    subnet: String,
+    #[serde(default)]
+    range_start: Option<Ipv4Addr>,
+    #[serde(default)]
+    range_end: Option<Ipv4Addr>,
+    #[serde(default)]
+    mtu: Option<usize>,
+    #[serde(default)]
+    data_dir: Option<String>,
+}
+
+struct CniError {
+    code: u32,
+    msg: String,
+}
+
+impl CniError {
+    fn new<T: ToString>(code: u32, msg: T) -> Self {
+        Self { code, msg: msg.to_string() }
+    }
+}
+
+impl From<io::Error> for CniError {
+    fn from(err: io::Error) -> Self {
+        Self::new(ERR_IO, err)
+    }
+}
+
+struct Args {
+    container_id: String,
+    netns: String,
+    ifname: String,
+}
+
+fn env_var(name: &str) -> Result<String, CniError> {
+    env::var(name).map_err(|_| CniError::new(ERR_INVALID_ENV, format!("{} is not set", name)))
+}
+
+/// Runs `ip`, optionally inside the network namespace `netns`
+fn ip(netns: Option<&str>, args: &[&str]) -> Result<(), CniError> {
+    let mut cmd = match netns {
+        Some(netns) => {
+            let mut cmd = process::Command::new("nsenter");
+            cmd.arg(format!("--net={}", netns)).arg("ip");
+            cmd
+        }
+        None => process::Command::new("ip"),
+    };
+    let output = cmd.args(args).output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(CniError::new(
+            ERR_IO,
+            format!("ip {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim()),
+        ))
+    }
+}
+
+fn link_exists(name: &str) -> bool {
+    Path::new("/sys/class/net").join(name).exists()
+}
+
+fn host_ifname(container_id: &str) -> String {
+    // Interface names are limited to 15 characters
+    format!("vch{}", container_id.chars().take(8).collect::<String>())
+}
+
+struct Pool {
+    dir: PathBuf,
+    prefix_len: u8,
+    first: u32,
+    last: u32,
+}
+
+impl Pool {
+    fn new(conf: &NetConf) -> Result<Self, CniError> {
+        let invalid = || CniError::new(ERR_INVALID_CONFIG, format!("Invalid subnet: {}", conf.subnet));
+        let mut parts = conf.subnet.splitn(2, '/');
+        let network = parts.next().and_then(|s| Ipv4Addr::from_str(s).ok()).ok_or_else(invalid)?;
+        let prefix_len = parts.next().and_then(|s| u8::from_str(s).ok()).filter(|l| *l <= 30).ok_or_else(invalid)?;
+        let mask = u32::max_value().checked_shl(32 - u32::from(prefix_len)).unwrap_or(0);
+        let network = u32::from(network) & mask;
+        let broadcast = network | !mask;
+        let first = conf.range_start.map(u32::from).unwrap_or(network + 1).max(network + 1);
+        let last = conf.range_end.map(u32::from).unwrap_or(broadcast - 1).min(broadcast - 1);
+        if first > last {
+            return Err(CniError::new(ERR_INVALID_CONFIG, "The address range is empty"));
+        }
+        let dir = Path::new(conf.data_dir.as_deref().unwrap_or(DEFAULT_DATA_DIR)).join(&conf.name);
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, prefix_len, first, last })
+    }
+
+    fn find(&self, container_id: &str, ifname: &str) -> Result<Option<Ipv4Addr>, CniError> {
+        let owner = format!("{}\n{}", container_id, ifname);
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if let Ok(addr) = Ipv4Addr::from_str(&entry.file_name().to_string_lossy()) {
+                if fs::read_to_string(entry.path()).map(|s| s.trim() == owner).unwrap_or(false) {
+                    return Ok(Some(addr));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn allocate(&self, container_id: &str, ifname: &str) -> Result<Ipv4Addr, CniError> {
+        if let Some(addr) = self.find(container_id, ifname)? {
+            return Ok(addr);
+        }
+        for addr in self.first..=self.last {
+            let addr = Ipv4Addr::from(addr);
+            // Creating the file fails if the address is taken, even with concurrent invocations
+            match OpenOptions::new().write(true).create_new(true).open(self.dir.join(addr.to_string())) {
+                Ok(mut file) => {
+                    writeln!(file, "{}\n{}", container_id, ifname)?;
+                    return Ok(addr);
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Err(CniError::new(ERR_TRY_AGAIN, "No free address in the range"))
+    }
+
+    fn release(&self, container_id: &str, ifname: &str) -> Result<(), CniError> {
+        if let Some(addr) = self.find(container_id, ifname)? {
+            fs::remove_file(self.dir.join(addr.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+fn setup_bridge(device: &str, bridge: &str) -> Result<(), CniError> {
+    if !link_exists(device) {
+        return Err(CniError::new(
+            ERR_INVALID_CONFIG,
+            format!("Device {} does not exist, is vpncloud running?", device),
+        ));
+    }
+    if !link_exists(bridge) {
+        ip(None, &["link", "add", "name", bridge, "type", "bridge"])?;
+    }
+    ip(None, &["link", "set", device, "master", bridge])?;
+    ip(None, &["link", "set", bridge, "up"])
+}
+
+fn cmd_add(conf: &NetConf, args: &Args) -> Result<Value, CniError> {
+    let device = conf.device.as_deref().unwrap_or("vpncloud0");
+    let bridge = conf.bridge.as_deref().unwrap_or("vpncloud-br");
+    setup_bridge(device, bridge)?;
+    let pool = Pool::new(conf)?;
+    let addr = pool.allocate(&args.container_id, &args.ifname)?;
+    let host = host_ifname(&args.container_id);
+    let mtu = match conf.mtu {
+        Some(mtu) => mtu.to_string(),
+        None => fs::read_to_string(Path::new("/sys/class/net").join(device).join("mtu"))?.trim().to_string(),
+    };
+    let address = format!("{}/{}", addr, pool.prefix_len);
+    let res = ip(
+        Some(&args.netns),
+        &["link", "add", &args.ifname, "mtu", &mtu, "type", "veth", "peer", "name", &host, "mtu", &mtu, "netns", "1"],
+    )
+    .and_then(|_| ip(Some(&args.netns), &["addr", "add", &address, "dev", &args.ifname]))
+    .and_then(|_| ip(Some(&args.netns), &["link", "set", &args.ifname, "up"]))
+    .and_then(|_| ip(None, &["link", "set", &host, "master", bridge]))
+    .and_then(|_| ip(None, &["link", "set", &host, "up"]));
+    if let Err(err) = res {
+        pool.release(&args.container_id, &args.ifname).ok();
+        if link_exists(&host) {
+            ip(None, &["link", "del", &host]).ok();
+        }
+        return Err(err);
+    }
+    Ok(json!({
+        "cniVersion": conf.cni_version,
+        "interfaces": [{"name": args.ifname, "sandbox": args.netns}],
+        "ips": [{"version": "4", "address": address, "interface": 0}],
+    }))
+}
+
+fn cmd_del(conf: &NetConf, args: &Args) -> Result<Value, CniError> {
+    // Deleting has to succeed even if the pod has been partially removed already
+    let host = host_ifname(&args.container_id);
+    if link_exists(&host) {
+        ip(None, &["link", "del", &host])?;
+    }
+    Pool::new(conf)?.release(&args.container_id, &args.ifname)?;
+    Ok(Value::Null)
+}
+
+fn cmd_check(conf: &NetConf, args: &Args) -> Result<Value, CniError> {
+    if Pool::new(conf)?.find(&args.container_id, &args.ifname)?.is_none() {
+        return Err(CniError::new(ERR_UNKNOWN_CONTAINER, "No address allocated for container"));
+    }
+    if !link_exists(&host_ifname(&args.container_id)) {
+        return Err(CniError::new(ERR_UNKNOWN_CONTAINER, "Interface of container is missing"));
+    }
+    Ok(Value::Null)
+}
+
+fn run(command: &str) -> Result<Value, CniError> {
+    if command == "VERSION" {
+        return Ok(json!({"cniVersion": CNI_VERSION, "supportedVersions": SUPPORTED_VERSIONS}));
+    }
+    let mut input = String::new();
+    io::stdin().read_to_string(&mut input)?;
+    let conf: NetConf = serde_json::from_str(&input).map_err(|e| CniError::new(ERR_DECODE, e))?;
+    if !SUPPORTED_VERSIONS.contains(&conf.cni_version.as_str()) {
+        return Err(CniError::new(ERR_INCOMPATIBLE_VERSION, format!("Unsupported version {}", conf.cni_version)));
+    }
+    let args = Args {
+        container_id: env_var("CNI_CONTAINERID")?,
+        netns: if command == "DEL" { env::var("CNI_NETNS").unwrap_or_default() } else { env_var("CNI_NETNS")? },
+        ifname: env_var("CNI_IFNAME")?,
+    };
+    match command {
+        "ADD" => cmd_add(&conf, &args),
+        "DEL" => cmd_del(&conf, &args),
+        "CHECK" => cmd_check(&conf, &args),
+        _ => Err(CniError::new(ERR_INVALID_ENV, format!("Unknown command: {}", command))),
+    }
+}
+
+fn main() {
+    let command = env::var("CNI_COMMAND").unwrap_or_default();
+    match run(&command) {
+        Ok(Value::Null) => (),
+        Ok(result) => println!("{}", result),
+        Err(err) => {
+            println!("{}", json!({"cniVersion": CNI_VERSION, "code": err.code, "msg": err.msg}));
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,288 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Transmission budgets for metered connections
+//!
+//! All traffic with peers is accounted per period (day or month), in total and for configured peers. Once a
+//! budget is exhausted, payload to the affected peers is throttled or dropped until the next period begins.
+//! Control messages are never limited so that the connections to the peers stay alive.
+
+use chrono::{Datelike, Local};
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    net::SocketAddr,
+};
+
+use crate::{cloud::Hash, util::Bytes};
+
+pub const DEFAULT_THROTTLE_RATE: u64 = 4096;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    #[serde(rename = "daily")]
+    Daily,
+    #[serde(rename = "monthly")]
+    Monthly,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Payload is limited to `throttle-rate` bytes per second
+    #[serde(rename = "throttle")]
+    Throttle,
+    /// Payload is dropped
+    #[serde(rename = "drop")]
+    Drop,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Config {
+    pub period: Period,
+    /// Bytes that can be exchanged with all peers in one period
+    pub limit: Option<u64>,
+    /// Bytes that can be exchanged with single peers in one period, by node name
+    pub peers: HashMap<String, u64>,
+    pub action: Action,
+    /// Bytes per second that can be sent when throttled
+    pub throttle_rate: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            period: Period::Monthly,
+            limit: None,
+            peers: HashMap::new(),
+            action: Action::Throttle,
+            throttle_rate: DEFAULT_THROTTLE_RATE,
+        }
+    }
+}
+
+/// Identifies the current day or month in local time
+fn current_period(period: Period) -> u32 {
+    let now = Local::now();
+    match period {
+        Period::Daily => now.num_days_from_ce() as u32,
+        Period::Monthly => now.year() as u32 * 12 + now.month0(),
+    }
+}
+
+pub struct Budget {
+    config: Config,
+    period: u32,
+    total: u64,
+    used: HashMap<String, u64>,
+    names: HashMap<SocketAddr, String, Hash>,
+    tokens: u64,
+}
+
+impl Budget {
+    pub fn new(config: Config) -> Self {
+        let used = config.peers.keys().map(|name| (name.clone(), 0)).collect();
+        Self {
+            period: current_period(config.period),
+            total: 0,
+            used,
+            names: HashMap::default(),
+            tokens: config.throttle_rate,
+            config,
+        }
+    }
+
+    /// Assigns the node name of the peer, only peers with a budget are tracked
+    pub fn set_peer(&mut self, addr: SocketAddr, name: Option<&str>) {
+        match name {
+            Some(name) if self.used.contains_key(name) => {
+                self.names.insert(addr, name.to_string());
+            }
+            _ => {
+                self.names.remove(&addr);
+            }
+        }
+    }
+
+    pub fn remove_peer(&mut self, addr: &SocketAddr) {
+        self.names.remove(addr);
+    }
+
+    /// Accounts the bytes sent to or received from the peer
+    #[inline]
+    pub fn count(&mut self, addr: &SocketAddr, bytes: usize) {
+        // HOT PATH
+        let bytes = bytes as u64;
+        if let Some(limit) = self.config.limit {
+            if self.total < limit && self.total + bytes >= limit {
+                warn!("Transmission budget of {} exhausted", Bytes(limit));
+            }
+        }
+        self.total += bytes;
+        if let Some(name) = self.names.get(addr) {
+            // COLD PATH
+            if let Some(used) = self.used.get_mut(name) {
+                let limit = self.config.peers[name];
+                if *used < limit && *used + bytes >= limit {
+                    warn!("Transmission budget of {} for peer {} exhausted", Bytes(limit), name);
+                }
+                *used += bytes;
+            }
+        }
+    }
+
+    /// Whether the budget for the peer is exhausted
+    pub fn is_exhausted(&self, addr: &SocketAddr) -> bool {
+        if self.config.limit.map(|limit| self.total >= limit).unwrap_or(false) {
+            return true;
+        }
+        match self.names.get(addr) {
+            Some(name) => self.used.get(name).map(|used| *used >= self.config.peers[name]).unwrap_or(false),
+            None => false,
+        }
+    }
+
+    /// Checks whether payload of the given size can be sent to the peer
+    #[inline]
+    pub fn allows(&mut self, addr: &SocketAddr, bytes: usize) -> bool {
+        // HOT PATH
+        if !self.is_exhausted(addr) {
+            return true;
+        }
+        // COLD PATH
+        match self.config.action {
+            Action::Drop => false,
+            Action::Throttle => {
+                if self.tokens < bytes as u64 {
+                    return false;
+                }
+                self.tokens -= bytes as u64;
+                true
+            }
+        }
+    }
+
+    fn set_period(&mut self, period: u32) {
+        if period != self.period {
+            info!("Transmission budget period ended, resetting counters");
+            self.period = period;
+            self.total = 0;
+            for used in self.used.values_mut() {
+                *used = 0;
+            }
+        }
+    }
+
+    /// Refills the throttling allowance, must be called every second
+    pub fn housekeep(&mut self) {
+        self.tokens = self.config.throttle_rate;
+        self.set_period(current_period(self.config.period));
+    }
+
+    /// Serializes the counters so that they can be restored after a restart
+    pub fn save(&self) -> String {
+        let mut data = format!("period {}\ntotal {}\n", self.period, self.total);
+        let mut used: Vec<_> = self.used.iter().collect();
+        used.sort();
+        for (name, bytes) in used {
+            data.push_str(&format!("peer {} {}\n", name, bytes));
+        }
+        data
+    }
+
+    /// Restores the counters if they belong to the current period, ignoring invalid lines
+    pub fn load(&mut self, data: &str) {
+        let mut period = None;
+        let mut total = 0;
+        let mut used = vec![];
+        for line in data.lines() {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts[..] {
+                ["period", value] => period = value.parse().ok(),
+                ["total", value] => total = value.parse().unwrap_or(0),
+                ["peer", name, value] => used.push((name, value.parse().unwrap_or(0))),
+                _ => (),
+            }
+        }
+        if period != Some(self.period) {
+            return;
+        }
+        self.total = total;
+        for (name, bytes) in used {
+            if let Some(entry) = self.used.get_mut(name) {
+                *entry = bytes;
+            }
+        }
+    }
+
+    pub fn write_out<W: Write>(&self, out: &mut W) -> Result<(), io::Error> {
+        writeln!(out, "budget:")?;
+        writeln!(out, "  used: {}", self.total)?;
+        if let Some(limit) = self.config.limit {
+            writeln!(out, "  limit: {}", limit)?;
+        }
+        writeln!(out, "  peers:")?;
+        let mut used: Vec<_> = self.used.iter().collect();
+        used.sort();
+        for (name, bytes) in used {
+            writeln!(out, "    - name: \"{}\"\n      used: {}\n      limit: {}", name, bytes, self.config.peers[name])?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn budget_limits() {
+    let mut peers = HashMap::new();
+    peers.insert("node2".to_string(), 1000);
+    let config = Config { limit: Some(5000), peers, action: Action::Drop, ..Config::default() };
+    let mut budget = Budget::new(config);
+    let node2 = "1.2.3.4:3210".parse().unwrap();
+    let node3 = "1.2.3.5:3210".parse().unwrap();
+    budget.set_peer(node2, Some("node2"));
+    budget.set_peer(node3, Some("node3"));
+    budget.count(&node2, 999);
+    assert!(budget.allows(&node2, 100));
+    budget.count(&node2, 1);
+    assert!(!budget.allows(&node2, 100));
+    assert!(budget.allows(&node3, 100));
+    budget.count(&node3, 4000);
+    assert!(!budget.allows(&node3, 100));
+    // A new period resets the counters
+    budget.set_period(budget.period + 1);
+    assert!(budget.allows(&node2, 100));
+    assert!(budget.allows(&node3, 100));
+}
+
+#[test]
+fn budget_throttle() {
+    let config = Config { limit: Some(1000), throttle_rate: 1500, ..Config::default() };
+    let mut budget = Budget::new(config);
+    let addr = "1.2.3.4:3210".parse().unwrap();
+    budget.count(&addr, 1000);
+    assert!(budget.allows(&addr, 1000));
+    assert!(!budget.allows(&addr, 1000));
+    assert!(budget.allows(&addr, 500));
+    budget.housekeep();
+    assert!(budget.allows(&addr, 1000));
+}
+
+#[test]
+fn budget_save_load() {
+    let mut peers = HashMap::new();
+    peers.insert("node2".to_string(), 1000);
+    let config = Config { limit: Some(5000), peers, ..Config::default() };
+    let mut budget = Budget::new(config.clone());
+    let addr = "1.2.3.4:3210".parse().unwrap();
+    budget.set_peer(addr, Some("node2"));
+    budget.count(&addr, 1234);
+    let data = budget.save();
+    let mut loaded = Budget::new(config.clone());
+    loaded.load(&data);
+    assert_eq!(data, loaded.save());
+    // Counters of another period are ignored
+    let mut loaded = Budget::new(config);
+    loaded.load(&data.replace(&format!("period {}", budget.period), "period 0"));
+    assert_eq!(0, loaded.total);
+}
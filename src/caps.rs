@@ -0,0 +1,95 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Linux capabilities of the process
+//!
+//! Instead of running as root, VpnCloud can be started as a normal user that has the
+//! `CAP_NET_ADMIN` capability, either as a file capability of the binary or as an ambient
+//! capability (e.g. via systemd). The capability is only needed to set up the device, so it is
+//! dropped afterwards.
+
+use std::fs;
+#[cfg(target_os = "linux")]
+use std::io;
+
+use crate::error::Error;
+
+pub const CAP_NET_BIND_SERVICE: u32 = 10;
+pub const CAP_NET_ADMIN: u32 = 12;
+
+#[cfg(target_os = "linux")]
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+#[cfg(target_os = "linux")]
+const PR_CAP_AMBIENT: libc::c_int = 47;
+#[cfg(target_os = "linux")]
+const PR_CAP_AMBIENT_CLEAR_ALL: libc::c_ulong = 4;
+
+fn parse_effective(status: &str) -> Option<u64> {
+    status.lines().find_map(|line| line.strip_prefix("CapEff:")).and_then(|v| u64::from_str_radix(v.trim(), 16).ok())
+}
+
+/// Whether the process runs as root
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Returns whether the process has the capability or `None` if this can not be determined
+pub fn has(cap: u32) -> Option<bool> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    parse_effective(&status).map(|caps| caps & (1 << cap) != 0)
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Drops all capabilities, including the ambient ones that would be passed to child processes
+#[cfg(target_os = "linux")]
+pub fn drop_all() -> Result<(), Error> {
+    let res = unsafe {
+        libc::prctl(
+            PR_CAP_AMBIENT,
+            PR_CAP_AMBIENT_CLEAR_ALL,
+            0 as libc::c_ulong,
+            0 as libc::c_ulong,
+            0 as libc::c_ulong,
+        )
+    };
+    // Kernels before 4.3 have no ambient capabilities
+    if res != 0 && io::Error::last_os_error().raw_os_error() != Some(libc::EINVAL) {
+        return Err(Error::SandboxIo("Failed to clear ambient capabilities", io::Error::last_os_error()));
+    }
+    let header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    let data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capset, &header as *const CapHeader, data.as_ptr()) } != 0 {
+        return Err(Error::SandboxIo("Failed to drop capabilities", io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_all() -> Result<(), Error> {
+    Ok(())
+}
+
+#[test]
+fn parse_capabilities() {
+    let status = "Name:\tvpncloud\nCapInh:\t0000000000000000\nCapPrm:\t0000000000001000\nCapEff:\t0000000000001000\n";
+    let caps = parse_effective(status).unwrap();
+    assert!(caps & (1 << CAP_NET_ADMIN) != 0);
+    assert!(caps & (1 << CAP_NET_BIND_SERVICE) == 0);
+    assert_eq!(parse_effective("Name:\tvpncloud\n"), None);
+}
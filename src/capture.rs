@@ -0,0 +1,306 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Capture of the encrypted packets exchanged with a single peer, see `capture` in the config file
+//!
+//! The packets are recorded as they are sent and received on the socket, so the capture shows what middleboxes on the
+//! path see and nothing of the traffic of other peers. With a file, the packets are written in pcap format wrapped in
+//! IP and UDP headers that tools like Wireshark understand, otherwise the beginning of each packet is logged. The local
+//! address in the headers is unspecified as the node does not know which of its addresses the peer sees.
+
+use byteorder::{ByteOrder, LittleEndian, NetworkEndian, WriteBytesExt};
+use std::{
+    cmp::min,
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::Error,
+    net::mapped_addr,
+    util::{addr_nice, bytes_to_hex, resolve},
+};
+
+pub const DEFAULT_MAX_PACKETS: usize = 1000;
+/// Bytes per packet that are written to a capture file by default, i.e. the whole packet
+const DEFAULT_FILE_SNAPLEN: usize = 65535;
+/// Bytes per packet that are logged by default
+const DEFAULT_LOG_SNAPLEN: usize = 32;
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+/// Link type for packets that start with an IPv4 or IPv6 header
+const LINKTYPE_RAW: u32 = 101;
+const IP_PROTOCOL_UDP: u8 = 17;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Address or name of the peer whose packets are captured
+    pub peer: String,
+    /// Pcap file to write the packets to, they are logged if this is not set
+    #[serde(default)]
+    pub file: Option<String>,
+    /// Number of packets after which the capture stops
+    #[serde(default)]
+    pub max_packets: Option<usize>,
+    /// Number of bytes that are recorded per packet
+    #[serde(default)]
+    pub snaplen: Option<usize>,
+}
+
+/// Internet checksum as used in the IPv4 header
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data.chunks(2).map(|c| u32::from(c[0]) << 8 | u32::from(*c.get(1).unwrap_or(&0))).sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Builds the IP and UDP headers of a packet with the given payload length
+fn ip_udp_header(src: SocketAddr, dst: SocketAddr, len: usize) -> Vec<u8> {
+    let udp_len = 8 + len;
+    let mut header = Vec::with_capacity(48);
+    let ipv4 = |ip: IpAddr| match ip {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(ip) if ip.is_unspecified() => Some(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(ip) => match ip.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => ip.to_ipv4(),
+            _ => None,
+        },
+    };
+    match (ipv4(src.ip()), ipv4(dst.ip())) {
+        (Some(src_ip), Some(dst_ip)) => {
+            header.extend_from_slice(&[0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, IP_PROTOCOL_UDP, 0, 0]);
+            NetworkEndian::write_u16(&mut header[2..4], (20 + udp_len) as u16);
+            header.extend_from_slice(&src_ip.octets());
+            header.extend_from_slice(&dst_ip.octets());
+            let sum = checksum(&header);
+            NetworkEndian::write_u16(&mut header[10..12], sum);
+        }
+        _ => {
+            let ipv6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            header.extend_from_slice(&[0x60, 0, 0, 0]);
+            header.write_u16::<NetworkEndian>(udp_len as u16).unwrap();
+            header.extend_from_slice(&[IP_PROTOCOL_UDP, 64]);
+            header.extend_from_slice(&ipv6(src.ip()).octets());
+            header.extend_from_slice(&ipv6(dst.ip()).octets());
+        }
+    }
+    header.write_u16::<NetworkEndian>(src.port()).unwrap();
+    header.write_u16::<NetworkEndian>(dst.port()).unwrap();
+    header.write_u16::<NetworkEndian>(udp_len as u16).unwrap();
+    header.write_u16::<NetworkEndian>(0).unwrap();
+    header
+}
+
+pub struct Capture {
+    peer: String,
+    /// Name of the peer if it was not given as an address
+    name: Option<String>,
+    /// Current address of the peer
+    target: Option<SocketAddr>,
+    port: u16,
+    file: Option<String>,
+    writer: Option<BufWriter<File>>,
+    max_packets: usize,
+    snaplen: usize,
+    packets: usize,
+    finished: bool,
+}
+
+impl Capture {
+    /// Starts a capture of the packets of the peer on the socket with the given port
+    pub fn new(config: &Config, port: u16) -> Result<Self, Error> {
+        let (name, target) = match resolve(&config.peer as &str) {
+            Ok(addrs) if !addrs.is_empty() => (None, Some(mapped_addr(addrs[0]))),
+            _ => (Some(config.peer.clone()), None),
+        };
+        let snaplen = if config.file.is_some() { DEFAULT_FILE_SNAPLEN } else { DEFAULT_LOG_SNAPLEN };
+        let snaplen = config.snaplen.unwrap_or(snaplen);
+        let writer = match config.file {
+            Some(ref path) => {
+                let mut writer =
+                    BufWriter::new(File::create(path).map_err(|e| Error::FileIo("Failed to create capture file", e))?);
+                Self::write_header(&mut writer, snaplen)
+                    .map_err(|e| Error::FileIo("Failed to write capture file", e))?;
+                Some(writer)
+            }
+            None => None,
+        };
+        warn!("Capturing the encrypted packets of peer {}, do not leave this enabled", config.peer);
+        Ok(Self {
+            peer: config.peer.clone(),
+            name,
+            target,
+            port,
+            file: config.file.clone(),
+            writer,
+            max_packets: config.max_packets.unwrap_or(DEFAULT_MAX_PACKETS),
+            snaplen,
+            packets: 0,
+            finished: false,
+        })
+    }
+
+    fn write_header<W: Write>(out: &mut W, snaplen: usize) -> Result<(), io::Error> {
+        out.write_u32::<LittleEndian>(PCAP_MAGIC)?;
+        out.write_u16::<LittleEndian>(2)?;
+        out.write_u16::<LittleEndian>(4)?;
+        out.write_i32::<LittleEndian>(0)?;
+        out.write_u32::<LittleEndian>(0)?;
+        out.write_u32::<LittleEndian>(snaplen as u32)?;
+        out.write_u32::<LittleEndian>(LINKTYPE_RAW)
+    }
+
+    /// Name of the peer that is captured, if its address has to be looked up by name
+    pub fn peer_name(&self) -> Option<&str> {
+        if self.finished {
+            None
+        } else {
+            self.name.as_deref()
+        }
+    }
+
+    /// Sets the address of the peer that was given by name
+    pub fn set_target(&mut self, addr: Option<SocketAddr>) {
+        if addr != self.target {
+            if let (Some(addr), Some(name)) = (addr, &self.name) {
+                info!("Capturing packets of peer {} at {}", name, addr_nice(addr));
+            }
+            self.target = addr;
+        }
+    }
+
+    #[inline]
+    pub fn incoming(&mut self, addr: SocketAddr, data: &[u8]) {
+        if self.target == Some(addr) {
+            self.record(addr, false, data)
+        }
+    }
+
+    #[inline]
+    pub fn outgoing(&mut self, addr: SocketAddr, data: &[u8]) {
+        if self.target == Some(addr) {
+            self.record(addr, true, data)
+        }
+    }
+
+    fn record(&mut self, addr: SocketAddr, outgoing: bool, data: &[u8]) {
+        self.packets += 1;
+        let res = match self.writer {
+            Some(ref mut writer) => {
+                let local = match addr {
+                    SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), self.port),
+                    SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), self.port),
+                };
+                let (src, dst) = if outgoing { (local, addr) } else { (addr, local) };
+                Self::write_packet(writer, src, dst, data, self.snaplen)
+            }
+            None => {
+                info!(
+                    "Captured {} bytes {} {}: {}",
+                    data.len(),
+                    if outgoing { "to" } else { "from" },
+                    addr_nice(addr),
+                    bytes_to_hex(&data[..min(data.len(), self.snaplen)])
+                );
+                Ok(())
+            }
+        };
+        if let Err(err) = res {
+            error!("Failed to write capture file: {}", err);
+            self.finish();
+        } else if self.packets >= self.max_packets {
+            self.finish();
+        }
+    }
+
+    fn write_packet<W: Write>(
+        out: &mut W, src: SocketAddr, dst: SocketAddr, data: &[u8], snaplen: usize,
+    ) -> Result<(), io::Error> {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let header = ip_udp_header(src, dst, data.len());
+        let len = header.len() + data.len();
+        let captured = min(len, snaplen);
+        out.write_u32::<LittleEndian>(time.as_secs() as u32)?;
+        out.write_u32::<LittleEndian>(time.subsec_micros())?;
+        out.write_u32::<LittleEndian>(captured as u32)?;
+        out.write_u32::<LittleEndian>(len as u32)?;
+        out.write_all(&header[..min(header.len(), captured)])?;
+        out.write_all(&data[..captured.saturating_sub(header.len())])
+    }
+
+    fn finish(&mut self) {
+        if let Some(mut writer) = self.writer.take() {
+            if let Err(err) = writer.flush() {
+                error!("Failed to write capture file: {}", err);
+            }
+        }
+        info!("Capture finished after {} packets", self.packets);
+        self.target = None;
+        self.finished = true;
+    }
+
+    /// Writes the buffered packets to the file
+    pub fn housekeep(&mut self) {
+        if let Some(ref mut writer) = self.writer {
+            if let Err(err) = writer.flush() {
+                error!("Failed to write capture file: {}", err);
+                self.finish();
+            }
+        }
+    }
+
+    pub fn write_out<W: Write>(&self, out: &mut W) -> Result<(), io::Error> {
+        writeln!(out, "capture:")?;
+        writeln!(out, "  peer: {:?}", self.peer)?;
+        writeln!(out, "  file: {:?}", self.file)?;
+        writeln!(out, "  packets: {}", self.packets)?;
+        writeln!(out, "  finished: {}", self.finished)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn capture_to_pcap_file() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let path = file.path().to_str().unwrap().to_string();
+    let config =
+        Config { peer: "1.2.3.4:3210".to_string(), file: Some(path.clone()), max_packets: Some(2), snaplen: None };
+    let mut capture = Capture::new(&config, 3210).unwrap();
+    let peer = mapped_addr("1.2.3.4:3210".parse().unwrap());
+    let other = mapped_addr("5.6.7.8:3210".parse().unwrap());
+    capture.outgoing(other, &[9; 10]);
+    capture.outgoing(peer, &[1; 10]);
+    capture.incoming(peer, &[2; 20]);
+    capture.incoming(peer, &[3; 30]);
+    assert!(capture.finished);
+    let data = std::fs::read(&path).unwrap();
+    // Global header, then two packets with IPv4 and UDP headers
+    assert_eq!(data.len(), 24 + (16 + 28 + 10) + (16 + 28 + 20));
+    assert_eq!(&data[0..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+    assert_eq!(&data[24 + 8..24 + 12], &[38, 0, 0, 0]);
+    let ip = &data[24 + 16..24 + 16 + 20];
+    assert_eq!(&ip[16..20], &[1, 2, 3, 4]);
+    assert_eq!(checksum(ip), 0);
+    assert_eq!(&data[data.len() - 20..], &[2; 20][..]);
+}
+
+#[test]
+fn capture_by_name() {
+    let config = Config { peer: "node2".to_string(), file: None, max_packets: None, snaplen: None };
+    let mut capture = Capture::new(&config, 3210).unwrap();
+    let peer = mapped_addr("1.2.3.4:3210".parse().unwrap());
+    assert_eq!(capture.peer_name(), Some("node2"));
+    capture.incoming(peer, &[1; 10]);
+    assert_eq!(capture.packets, 0);
+    capture.set_target(Some(peer));
+    capture.incoming(peer, &[1; 10]);
+    assert_eq!(capture.packets, 1);
+}
@@ -0,0 +1,154 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Chaos test mode, see `--chaos`
+//!
+//! In this mode, faults are injected at runtime to harden the daemon against flaky networks: peers are
+//! dropped, packets are lost or delayed and key rotations are forced. Additionally, some invariants of the
+//! internal state are checked regularly and a watchdog reports a stalled event loop.
+
+use rand::{thread_rng, Rng};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Probability that an outgoing packet is dropped
+const LOSS_PROBABILITY: f64 = 0.02;
+/// Probability that an outgoing packet is delayed until the next housekeeping
+const DELAY_PROBABILITY: f64 = 0.02;
+/// Probability per second that a peer is dropped
+const DROP_PEER_PROBABILITY: f64 = 0.005;
+/// Probability per second that a key rotation is forced for a peer
+const REKEY_PROBABILITY: f64 = 0.01;
+/// Time without housekeeping after which the event loop is considered stalled
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Chaos {
+    delayed: Vec<(SocketAddr, Vec<u8>)>,
+    heartbeat: Arc<AtomicUsize>,
+    stopped: Arc<AtomicBool>,
+    faults: usize,
+    violations: usize,
+}
+
+impl Chaos {
+    pub fn new() -> Self {
+        warn!("Chaos mode is enabled, faults will be injected. Do not use this in production!");
+        Self {
+            delayed: vec![],
+            heartbeat: Arc::new(AtomicUsize::new(0)),
+            stopped: Arc::new(AtomicBool::new(false)),
+            faults: 0,
+            violations: 0,
+        }
+    }
+
+    /// Decides whether an outgoing packet is sent right away
+    ///
+    /// Packets that are not sent are either dropped or delayed until `take_delayed` is called.
+    #[inline]
+    pub fn outgoing(&mut self, addr: SocketAddr, data: &[u8]) -> bool {
+        let val = thread_rng().gen::<f64>();
+        if val < LOSS_PROBABILITY {
+            self.faults += 1;
+            false
+        } else if val < LOSS_PROBABILITY + DELAY_PROBABILITY {
+            self.faults += 1;
+            self.delayed.push((addr, data.to_vec()));
+            false
+        } else {
+            true
+        }
+    }
+
+    pub fn take_delayed(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        std::mem::take(&mut self.delayed)
+    }
+
+    /// Decides whether a peer should be dropped now (called every second per peer)
+    pub fn drop_peer(&mut self, addr: SocketAddr) -> bool {
+        let drop = thread_rng().gen_bool(DROP_PEER_PROBABILITY);
+        if drop {
+            warn!("Chaos: dropping peer {}", addr);
+            self.faults += 1;
+        }
+        drop
+    }
+
+    /// Decides whether a key rotation should be forced now (called every second per peer)
+    pub fn rekey(&mut self, addr: SocketAddr) -> bool {
+        let rekey = thread_rng().gen_bool(REKEY_PROBABILITY);
+        if rekey {
+            info!("Chaos: forcing key rotation with {}", addr);
+            self.faults += 1;
+        }
+        rekey
+    }
+
+    pub fn violation(&mut self, msg: &str) {
+        error!("Chaos: invariant violated: {}", msg);
+        self.violations += 1;
+    }
+
+    /// Signals the watchdog that the event loop is alive
+    pub fn heartbeat(&self) {
+        self.heartbeat.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Starts a thread that reports when the event loop does not send heartbeats anymore
+    pub fn start_watchdog(&self) {
+        let heartbeat = self.heartbeat.clone();
+        let stopped = self.stopped.clone();
+        thread::spawn(move || {
+            let mut last = heartbeat.load(Ordering::Relaxed);
+            while !stopped.load(Ordering::Relaxed) {
+                thread::sleep(STALL_TIMEOUT);
+                let current = heartbeat.load(Ordering::Relaxed);
+                if current == last && !stopped.load(Ordering::Relaxed) {
+                    error!("Chaos: event loop stalled for more than {} seconds", STALL_TIMEOUT.as_secs());
+                }
+                last = current;
+            }
+        });
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        info!("Chaos: injected {} faults, {} invariant violations", self.faults, self.violations);
+    }
+
+    pub fn faults(&self) -> usize {
+        self.faults
+    }
+
+    pub fn violations(&self) -> usize {
+        self.violations
+    }
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn chaos_outgoing() {
+    let mut chaos = Chaos::new();
+    let addr = "1.2.3.4:3210".parse().unwrap();
+    let sent = (0..10_000).filter(|_| chaos.outgoing(addr, &[1, 2, 3])).count();
+    assert!(sent > 9000 && sent < 10_000);
+    assert_eq!(chaos.faults(), 10_000 - sent);
+    let delayed = chaos.take_delayed();
+    assert!(!delayed.is_empty() && delayed.len() < chaos.faults());
+    assert_eq!(delayed[0], (addr, vec![1, 2, 3]));
+    assert!(chaos.take_delayed().is_empty());
+    assert_eq!(chaos.violations(), 0);
+}
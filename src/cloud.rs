@@ -4,15 +4,21 @@
 
 use std::{
     cmp::{max, min},
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fmt,
     fs::{self, File},
     hash::BuildHasherDefault,
-    io::{self, Cursor, Seek, SeekFrom, Write},
+    io::{self, Cursor, Write},
     marker::PhantomData,
-    net::{SocketAddr, ToSocketAddrs},
+    mem,
+    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
     path::Path,
     str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::{self, Instant},
 };
 
 use fnv::FnvHasher;
@@ -20,41 +26,185 @@ use rand::{random, seq::SliceRandom, thread_rng};
 use smallvec::{smallvec, SmallVec};
 
 use crate::{
-    beacon::BeaconSerializer,
-    config::{Config, DEFAULT_PEER_TIMEOUT, DEFAULT_PORT},
-    crypto::{is_init_message, Crypto, MessageResult, PeerCrypto},
+    advertise::AdvertisePolicy,
+    arp::ArpTable,
+    auth::{AuthHook, PeerAuth},
+    bans::BanList,
+    beacon::{BeaconHints, BeaconSerializer},
+    budget::Budget,
+    capture::Capture,
+    chaos::Chaos,
+    config::{Config, PeerConfig, DEFAULT_PEER_TIMEOUT, DEFAULT_PORT},
+    control::{ControlCommand, ControlServer},
+    crypto::{is_init_message, Crypto, CryptoStats, MessageResult, PeerCrypto, EXTRA_LEN, TAG_LEN},
     device::{Device, Type},
-    error::Error,
+    dhcp::DhcpServer,
+    dial::DialQueue,
+    dns::{self, DnsRecords},
+    duplicate::{DuplicateFilter, SEQ_LEN},
+    error::{Error, Phase},
+    fec::{FecDecoder, FecEncoder},
+    firewall::{Direction, Firewall},
+    ipam::{self, IpamClient, IpamPool, Lease, IPAM_SERVICE},
     messages::{
-        AddrList, NodeInfo, PeerInfo, MESSAGE_TYPE_CLOSE, MESSAGE_TYPE_DATA, MESSAGE_TYPE_KEEPALIVE,
-        MESSAGE_TYPE_NODE_INFO,
+        decode_keepalive, encode_keepalive, pad_control_msg, AddrList, GossipInfo, NodeInfo, PeerInfo, ProtocolInfo,
+        CAPABILITY_DATA_KEEPALIVE, CAPABILITY_DUPLICATES, CAPABILITY_FEC, CAPABILITY_PADDING, CAPABILITY_PEER_GOSSIP,
+        CAPABILITY_PROBES, CLOSE_REASON_INCOMPATIBLE_VERSION, CLOSE_REASON_UNAUTHORIZED, KEEPALIVE_PROBE,
+        MESSAGE_TYPE_CLOSE, MESSAGE_TYPE_DATA, MESSAGE_TYPE_DUPLICATE, MESSAGE_TYPE_FEC_DATA, MESSAGE_TYPE_FEC_PARITY,
+        MESSAGE_TYPE_KEEPALIVE, MESSAGE_TYPE_LEASE, MESSAGE_TYPE_LEASE_REQUEST, MESSAGE_TYPE_NODE_INFO,
+        MESSAGE_TYPE_SEQ_DATA, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
     },
-    net::{mapped_addr, parse_listen, Socket},
-    payload::Protocol,
+    names::PeerNames,
+    nat::Nat,
+    net::{is_ipv4_addr, is_local_addr, mapped_addr, parse_listen, Socket},
+    payload::{parse_ethertype, Protocol},
+    peerstate::{PeerState, PeerStateTable},
+    policy::{ClaimFilters, ClaimPolicy},
     poll::{WaitImpl, WaitResult},
     port_forwarding::PortForwarding,
+    quality::QualityTable,
+    radius::{Accounting, TerminateCause},
+    ratelimit::ControlLimiter,
+    remote,
+    reorder::ReorderBuffer,
+    state::{
+        StateDir, BEACON_FILE, BUDGET_FILE, IPAM_LEASES_FILE, IPAM_LEASE_FILE, MAC_TABLE_FILE, NAMES_FILE, TRAFFIC_FILE,
+    },
+    stats::{create_sinks, ControlSink, MetricKind, Metric, Metrics, Snapshot, StatsSink},
     table::ClaimTable,
+    timestamp::Timestamps,
     traffic::TrafficStats,
-    types::{Address, Mode, NodeId, Range, RangeList},
-    util::{addr_nice, bytes_to_hex, resolve, CtrlC, Duration, MsgBuffer, StatsdMsg, Time, TimeSource},
+    types::{Address, DuplicateIdentityPolicy, Mode, NodeId, Range, RangeList, Topology},
+    util::{
+        addr_nice, bytes_to_hex, resolve, resolve_scoped, to_base62, BufferPool, Duration, MsgBuffer, SignalEvent,
+        Signals, Time, TimeSource,
+    },
 };
 
+#[cfg(feature = "async-runtime")]
+use crate::poll::AsyncWait;
+
 pub type Hash = BuildHasherDefault<FnvHasher>;
 
 const MAX_RECONNECT_INTERVAL: u16 = 3600;
+/// Number of failed attempts after which peers with the next priority are dialed
+const FAILOVER_ATTEMPTS: u16 = 3;
 const RESOLVE_INTERVAL: Time = 300;
 pub const STATS_INTERVAL: Time = 60;
 const OWN_ADDRESS_RESET_INTERVAL: Time = 300;
+/// Minimum time between two probes of the same local address of a peer
+const LOCAL_PROBE_INTERVAL: Time = 300;
+/// Housekeeping gap after which the system is assumed to have been suspended
+const RESUME_DETECTION_GAP: Time = 30;
+/// Time without an answer after which a peer is probed in fast failover mode
+const FAST_FAILOVER_PROBE_DELAY: Time = 1;
+/// Time without an answer after which a peer is declared down in fast failover mode
+const FAST_FAILOVER_TIMEOUT: Time = 3;
+/// Number of probes sent per second to a peer that does not answer
+const FAST_FAILOVER_PROBES: usize = 3;
+/// Clock difference to a peer above which time-limited keys are likely to fail
+const MAX_CLOCK_SKEW: Time = 60;
 const SPACE_BEFORE: usize = 100;
+const BUFFER_POOL_SIZE: usize = 4;
+/// Maximum number of packets read from the device at once when latency-sensitive packets are sent first
+const LATENCY_BYPASS_BATCH: usize = 16;
+/// Maximum number of packets that are kept to be attached to handshakes
+const MAX_EARLY_PACKETS: usize = 8;
+/// Time after which packets waiting for a handshake are dropped
+const EARLY_DATA_TIMEOUT: Time = 3;
+/// Interval of the warnings while the claims are held back because the interface is not ready
+const DEFERRED_CLAIMS_WARN_INTERVAL: Time = 60;
+/// Minimal time between two address changes of the same peer
+const MIGRATION_COOLDOWN: Time = 10;
+/// Time that changes to the node info are held back to send them together when keepalives are suppressed
+const NODE_INFO_COALESCE_DELAY: Time = 3;
+/// Maximum number of peers that are announced to a peer in one node info
+const MAX_PEER_ANNOUNCEMENTS: usize = 20;
+/// Number of new peers that are remembered to announce them incrementally
+const PEER_LOG_SIZE: usize = 64;
+/// Interval in which peers with incremental peer lists get the complete list again
+const FULL_PEER_LIST_INTERVAL: Time = 600;
+/// Maximal number of messages from unknown addresses that are checked against the peer sessions per second
+const MAX_MIGRATION_CHECKS: usize = 10;
+/// Time that an evicted peer is not readmitted, so that peers can not keep evicting each other
+const EVICTION_BACKOFF: Time = 300;
 
-struct PeerData {
+struct PeerData<TS: TimeSource> {
     addrs: AddrList,
     #[allow(dead_code)] //TODO: export in status
     last_seen: Time,
     timeout: Time,
     peer_timeout: u16,
     node_id: NodeId,
-    crypto: PeerCrypto<NodeInfo>,
+    max_payload: Option<usize>,
+    /// MTU of the virtual device of the peer, derived from its maximum payload
+    mtu: Option<u16>,
+    services: Vec<String>,
+    crypto: PeerCrypto<NodeInfo, TS>,
+    /// Protocol version negotiated with the peer during the handshake
+    version: u8,
+    /// Capabilities that both nodes support
+    capabilities: u32,
+    /// Whether the peer answers keepalive probes
+    probes: bool,
+    /// Time of the first packet sent to the peer since the last message from it
+    unanswered_since: Option<Time>,
+    /// Difference between the clock of the peer and the local clock in seconds
+    clock_skew: Option<Time>,
+    /// Name of the trusted key of the peer or the node name it announces
+    name: Option<String>,
+    /// Time before which address changes of the peer are not accepted
+    next_migration: Time,
+    /// Number of packets from the peer with source addresses outside of its claims
+    source_violations: usize,
+    /// Whether the peer accepts data as sign of life so that keepalives can be skipped
+    data_keepalive: bool,
+    /// Whether messages were sent to the peer since the last housekeeping
+    sent_data: bool,
+    /// Whether messages were received from the peer since the last housekeeping
+    received_data: bool,
+    /// Time of the last message sent to the peer
+    last_sent: Time,
+    /// Time of the last node info sent to the peer
+    last_info: Time,
+    /// Whether the peer acknowledges peer list versions, so it can get only the changes
+    gossip: bool,
+    /// Version of the peer list of the peer that has been seen completely
+    gossip_ack: u32,
+    /// Version of the own peer list that the peer has seen completely
+    gossip_acked: u32,
+    /// Time when the peer gets the complete peer list again
+    next_full_peers: Time,
+    /// Whether the peer drops the second copy of duplicated packets
+    duplicates: bool,
+    /// Sequence number of the next duplicated packet sent to the peer
+    duplicate_seq: u32,
+    /// Sequence numbers of the duplicated packets received from the peer
+    duplicate_filter: DuplicateFilter,
+    /// Whether the peer recovers packets from FEC groups and reports its packet loss
+    fec: bool,
+    /// Whether payload to the peer is protected by FEC since it reports a high packet loss
+    fec_active: bool,
+    fec_encoder: FecEncoder,
+    fec_decoder: FecDecoder,
+    /// Whether the peer has a reorder window, so the payload sent to it is numbered
+    reorder: bool,
+    /// Sequence number of the next numbered packet sent to the peer
+    reorder_seq: u32,
+    /// Numbered packets from the peer that wait for missing packets
+    reorder_buffer: ReorderBuffer,
+    /// Whether the peer ignores padding after control messages
+    padding: bool,
+}
+
+/// A peer that completed the handshake and waits for the decision of the auth hook
+struct PendingAdmission {
+    info: NodeInfo,
+    fingerprint: String,
+    /// The last handshake message, it is only sent once the peer is admitted
+    reply: Option<Box<MsgBuffer>>,
+    /// Whether the peer considers the connection established and needs to be told about a rejection
+    notify: bool,
 }
 
 #[derive(Clone)]
@@ -65,6 +215,29 @@ pub struct ReconnectEntry {
     timeout: u16,
     next: Time,
     final_timeout: Option<Time>,
+    priority: u8,
+    attempts: u16,
+    keepalive: Option<Duration>,
+    next_keepalive: Time,
+}
+
+/// A handle to observe and stop a running node from other threads
+#[derive(Clone, Default)]
+pub struct CloudHandle {
+    stopped: Arc<AtomicBool>,
+    peers: Arc<AtomicUsize>,
+}
+
+impl CloudHandle {
+    /// Makes `GenericCloud::run` return within about a second
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst)
+    }
+
+    /// Returns the number of connected peers
+    pub fn peer_count(&self) -> usize {
+        self.peers.load(Ordering::SeqCst)
+    }
 }
 
 pub struct GenericCloud<D: Device, P: Protocol, S: Socket, TS: TimeSource> {
@@ -72,37 +245,133 @@ pub struct GenericCloud<D: Device, P: Protocol, S: Socket, TS: TimeSource> {
     config: Config,
     learning: bool,
     broadcast: bool,
-    peers: HashMap<SocketAddr, PeerData, Hash>,
+    peers: HashMap<SocketAddr, PeerData<TS>, Hash>,
     reconnect_peers: SmallVec<[ReconnectEntry; 3]>,
     own_addresses: AddrList,
-    pending_inits: HashMap<SocketAddr, PeerCrypto<NodeInfo>, Hash>,
+    advertise_policy: Option<AdvertisePolicy>,
+    /// Public addresses that have last been reported to the external_address_changed hook
+    external_addresses: AddrList,
+    local_probes: HashMap<SocketAddr, Time, Hash>,
+    /// New addresses of peers that are being connected, with the old address and the time until the migration expires
+    migrations: HashMap<SocketAddr, (SocketAddr, Time), Hash>,
+    migration_checks: usize,
+    migrations_accepted: usize,
+    migrations_rejected: usize,
+    duplicates_sent: usize,
+    duplicates_dropped: usize,
+    fec_parities_sent: usize,
+    fec_recovered: usize,
+    packets_reordered: usize,
+    /// Earliest time at which a reorder buffer skips a gap
+    reorder_deadline: Option<Instant>,
+    pending_inits: HashMap<SocketAddr, PeerCrypto<NodeInfo, TS>, Hash>,
+    /// Handshakes that succeeded and wait for the auth hook, no messages of those peers are processed meanwhile
+    pending_admissions: HashMap<SocketAddr, PendingAdmission, Hash>,
+    /// Time of the last message of each pending handshake, the least recently active ones are evicted first
+    handshake_activity: HashMap<SocketAddr, Time, Hash>,
+    handshakes_evicted: usize,
+    peers_evicted: usize,
+    /// Evicted peers with the time until they can be admitted again
+    evicted: HashMap<SocketAddr, Time, Hash>,
+    /// Connection attempts that wait for other handshakes to finish
+    dials: DialQueue<TS>,
     table: ClaimTable<TS>,
+    quality: QualityTable<TS>,
+    /// Time when the kernel received the message that is currently handled, if the socket provides it
+    receive_time: Option<Instant>,
+    peer_states: PeerStateTable<TS>,
+    arp_table: Option<ArpTable<TS>>,
+    firewall: Firewall<TS>,
+    nat: Option<Nat>,
+    claim_filters: Option<ClaimFilters>,
+    claim_policy: Option<ClaimPolicy>,
+    budget: Option<Budget>,
+    control_limit: Option<ControlLimiter<TS>>,
+    bans: Option<BanList<TS>>,
+    crypto_stats: CryptoStats,
+    peer_names: PeerNames,
+    radius: Option<Accounting<TS>>,
+    auth_hook: Option<AuthHook<TS>>,
+    dns_records: Option<Arc<RwLock<DnsRecords>>>,
+    dhcp: Option<DhcpServer<TS>>,
+    ipam_pool: Option<IpamPool<TS>>,
+    ipam_client: Option<IpamClient<TS>>,
+    max_payload: Option<usize>,
+    /// MTU of the own virtual device, compared to the MTUs of the peers
+    device_mtu: Option<usize>,
+    /// Number of peers that were found to have a different MTU
+    mtu_mismatches: usize,
+    /// Number of nodes that connected with the key of another connected node
+    duplicate_identities: usize,
     socket: S,
     device: D,
     claims: RangeList,
+    /// Claims that are held back until the interface is ready and the time of the next warning about it
+    deferred_claims: Option<(RangeList, Time)>,
+    ethertypes: SmallVec<[u16; 4]>,
     crypto: Crypto,
     next_peers: Time,
+    /// Whether the node info changed since it was last sent to all peers
+    node_info_changed: bool,
+    /// Version of the own peer list, increased with every new peer
+    peer_seq: u32,
+    /// Recently added peers with the version of the peer list they were added in
+    peer_log: VecDeque<(u32, SocketAddr)>,
+    /// Oldest version of the peer list that the changes can be computed from
+    peer_log_start: u32,
     peer_timeout_publish: u16,
     update_freq: u16,
-    stats_file: Option<File>,
-    statsd_server: Option<String>,
+    state: Option<StateDir>,
+    control: Option<ControlServer>,
+    stats_sinks: Vec<Box<dyn StatsSink>>,
+    timestamps: Timestamps,
     next_housekeep: Time,
+    last_housekeep: Time,
     next_stats_out: Time,
     next_beacon: Time,
     next_own_address_reset: Time,
+    /// Whether the node stopped all traffic to the peers due to the idle timeout, see `go_idle`
+    idle: bool,
+    /// Peers that are contacted again when the node wakes up
+    idle_peers: AddrList,
+    /// Whether payload was exchanged since the last housekeeping
+    payload_seen: bool,
+    last_payload: Time,
+    /// Sorted peers of the last loaded beacon, a change wakes up an idle node
+    last_beacon: Vec<SocketAddr>,
     port_forwarding: Option<PortForwarding>,
     traffic: TrafficStats,
+    buffers: BufferPool,
+    bulk_queue: Vec<Box<MsgBuffer>>,
+    /// Packets without destination that wait for a pending handshake, see `early_data`
+    early_data: Vec<(Time, Box<MsgBuffer>)>,
     beacon_serializer: BeaconSerializer<TS>,
+    handle: CloudHandle,
+    chaos: Option<Chaos>,
+    capture: Option<Capture>,
+    signals: Signals,
+    error_counts: HashMap<u16, usize, Hash>,
     _dummy_p: PhantomData<P>,
     _dummy_ts: PhantomData<TS>,
 }
 
 impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS> {
+    /// Creates a new node on the given socket and device
+    ///
+    /// The device has to be set up already. If `port_forwarding` is given, the forwarding is
+    /// extended periodically. If `stats_file` is given, statistics are written into it periodically.
+    ///
+    /// # Errors
+    /// Returns `Error::InvalidConfig` or `Error::InvalidConfigValue` if the config is invalid and
+    /// other errors if the DNS server or DHCP server can not be started.
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: &Config, socket: S, device: D, port_forwarding: Option<PortForwarding>, stats_file: Option<File>,
-    ) -> Self {
+    ) -> Result<Self, Error> {
+        // Trap signals before the DNS and control threads are started so that they are blocked there as well
+        let signals = Signals::new();
         let (learning, broadcast) = match config.mode {
+            _ if config.observer => (false, false),
             Mode::Normal => match config.device_type {
                 Type::Tap => (true, true),
                 Type::Tun => (false, false),
@@ -113,9 +382,22 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
         };
         let mut claims = SmallVec::with_capacity(config.claims.len());
         for s in &config.claims {
-            claims.push(try_fail!(Range::from_str(s), "Invalid subnet format: {} ({})", s));
+            claims.push(Range::from_str(s).map_err(|_| Error::InvalidConfigValue("Invalid subnet format", s.clone()))?);
+        }
+        for s in &config.services {
+            if s.is_empty() || s.len() > 255 {
+                return Err(Error::InvalidConfigValue("Invalid service name", s.clone()));
+            }
+        }
+        if config.source_validation && learning {
+            warn!("Source validation is only supported in router mode, ignoring it");
         }
-        if device.get_type() == Type::Tun && config.auto_claim {
+        let mut ethertypes = SmallVec::with_capacity(config.ethertypes.len());
+        for s in &config.ethertypes {
+            ethertypes.push(parse_ethertype(s).map_err(|_| Error::InvalidConfigValue("Invalid ethertype", s.clone()))?);
+        }
+        // With deferred claims, the device address is claimed once it is verified
+        if device.get_type() == Type::Tun && config.auto_claim && !config.defer_claims {
             match device.get_ip() {
                 Ok(ip) => {
                     let range = Range { base: Address::from_ipv4(ip), prefix_len: 32 };
@@ -125,45 +407,309 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
                 Err(Error::DeviceIo(_, e)) if e.kind() == io::ErrorKind::AddrNotAvailable => {
                     info!("No address set on interface.")
                 }
-                Err(e) => error!("{}", e),
+                Err(e) => error!("[E{}] {}", e.code(), e),
+            }
+        }
+        if config.observer {
+            // Observers must not attract any traffic
+            if !claims.is_empty() {
+                warn!("Ignoring claims in observer mode");
+            }
+            claims.clear();
+        }
+        let deferred_claims = if config.defer_claims && !config.observer {
+            info!("Deferring claims until the interface has its address");
+            Some((mem::take(&mut claims), TS::now() + DEFERRED_CLAIMS_WARN_INTERVAL))
+        } else {
+            None
+        };
+        let arp_table = if config.arp_proxy && learning && device.get_type() == Type::Tap {
+            Some(ArpTable::new(config.switch_timeout as Duration))
+        } else {
+            None
+        };
+        let firewall = Firewall::new(&config.firewall, device.get_type())?;
+        let nat = if config.nat.is_empty() {
+            None
+        } else if device.get_type() == Type::Tun {
+            Some(Nat::new(&config.nat)?)
+        } else {
+            warn!("NAT is only supported on TUN devices, disabling it");
+            None
+        };
+        let claim_filters =
+            if config.claim_filters.is_empty() { None } else { Some(ClaimFilters::new(&config.claim_filters)?) };
+        let claim_policy =
+            if config.accept_claims.is_empty() { None } else { Some(ClaimPolicy::new(&config.accept_claims)?) };
+        if let Some(ref name) = config.node_name {
+            if !dns::is_valid_name(name) {
+                return Err(Error::InvalidConfigValue("Invalid node name", name.clone()));
             }
         }
+        let dns_records = match config.dns_listen {
+            Some(ref listen) => {
+                let mut records = DnsRecords::default();
+                if let Some(ref name) = config.node_name {
+                    records.set_own(name, &claims);
+                }
+                let records = Arc::new(RwLock::new(records));
+                let domain = config.dns_domain.as_ref().map(|d| d as &str).unwrap_or(dns::DEFAULT_DNS_DOMAIN);
+                dns::start_server(listen, domain, records.clone())?;
+                info!("Serving DNS for domain {} on {}", domain, listen);
+                Some(records)
+            }
+            None => None,
+        };
+        let max_payload = config.mtu.map(|mtu| mtu + device.get_type().frame_overhead());
+        let device_mtu = device.mtu().or(config.mtu);
+        let advertise_policy = match config.advertise_policy {
+            Some(ref policy) => {
+                Some(AdvertisePolicy::new(policy, socket.address().map(|addr| addr.port()).unwrap_or(0))?)
+            }
+            None => None,
+        };
+        let capture = match config.capture {
+            Some(ref capture) => Some(Capture::new(capture, socket.address().map(|addr| addr.port()).unwrap_or(0))?),
+            None => None,
+        };
         let now = TS::now();
         let update_freq = config.get_keepalive() as u16;
-        let node_id = random();
-        let crypto = Crypto::new(node_id, &config.crypto).unwrap();
+        let node_id: NodeId = random();
+        let dhcp = match config.dhcp {
+            Some(ref dhcp) if device.get_type() == Type::Tap => {
+                // Locally administered MAC address derived from the node id
+                let mut mac = [0x02; 6];
+                mac[1..].copy_from_slice(&node_id[..5]);
+                Some(DhcpServer::new(dhcp, mac)?)
+            }
+            Some(_) => {
+                warn!("DHCP server is only supported on TAP devices, disabling it");
+                None
+            }
+            None => None,
+        };
+        let crypto = Crypto::new(node_id, &config.crypto)?;
+        let state = match config.state_dir {
+            Some(ref dir) => Some(StateDir::open(dir)?),
+            None => None,
+        };
+        let mut control = match config.control_socket {
+            Some(ref path) => {
+                let control = ControlServer::start(path)?;
+                info!("Accepting control commands on {}", path);
+                Some(control)
+            }
+            None => None,
+        };
+        if let Some(ref remote_config) = config.remote_control {
+            let control = control.get_or_insert_with(ControlServer::new);
+            let overlay_ip = device.get_ip().ok().map(IpAddr::V4);
+            let addr = remote::listen(remote_config, overlay_ip, control.handler())?;
+            info!("Accepting remote control commands on {}", addr_nice(addr));
+        }
+        let mut stats_sinks = create_sinks(config, stats_file, state.as_ref())?;
+        if let Some(ref control) = control {
+            stats_sinks.push(Box::new(ControlSink::new(control.latest_stats())));
+        }
+        let mut budget = config.budget.clone().map(Budget::new);
+        if let (Some(budget), Some(state)) = (&mut budget, &state) {
+            match state.read(BUDGET_FILE) {
+                Ok(Some(data)) => budget.load(&String::from_utf8_lossy(&data)),
+                Ok(None) => (),
+                Err(err) => warn!("Failed to load budget counters: {}", err),
+            }
+        }
+        let mut peer_names = PeerNames::new();
+        if let Some(ref state) = state {
+            match state.read(NAMES_FILE) {
+                Ok(Some(data)) => peer_names.load(&String::from_utf8_lossy(&data)),
+                Ok(None) => (),
+                Err(err) => warn!("Failed to load peer names: {}", err),
+            }
+        }
+        let auth_hook = match config.auth_hook {
+            Some(ref hook) => Some(AuthHook::new(hook)?),
+            None => None,
+        };
+        let radius = match config.radius {
+            Some(ref radius) => Some(Accounting::new(radius.clone())?),
+            None => None,
+        };
+        let mut traffic = TrafficStats::default();
+        if let Some(ref state) = state {
+            match state.read(TRAFFIC_FILE) {
+                Ok(Some(data)) => traffic.load(&String::from_utf8_lossy(&data)),
+                Ok(None) => (),
+                Err(err) => warn!("Failed to load traffic counters: {}", err),
+            }
+        }
+        let mut table = ClaimTable::new(config.switch_timeout as Duration, config.peer_timeout as Duration);
+        if learning {
+            for (mac, peer) in &config.static_macs {
+                let addr =
+                    Address::from_str(mac).map_err(|_| Error::InvalidConfigValue("Invalid MAC address", mac.clone()))?;
+                table.set_static(addr, mapped_addr(resolve(peer as &str)?[0]));
+            }
+            if let Some(ref state) = state {
+                match state.read(MAC_TABLE_FILE) {
+                    Ok(Some(data)) => table.load(&String::from_utf8_lossy(&data)),
+                    Ok(None) => (),
+                    Err(err) => warn!("Failed to load MAC table: {}", err),
+                }
+            }
+        } else if !config.static_macs.is_empty() {
+            warn!("Static MAC entries are only supported in switch mode, ignoring them");
+        }
+        let (ipam_pool, ipam_client) = match config.ipam {
+            Some(ref ipam) if ipam.pool.is_some() => {
+                if ipam.request {
+                    return Err(Error::InvalidConfig("The IPAM coordinator can not request a lease itself"));
+                }
+                // Never lease the own addresses of the coordinator
+                let mut reserved: Vec<Range> = claims.iter().copied().collect();
+                if let Some((ref deferred, _)) = deferred_claims {
+                    reserved.extend(deferred.iter().copied());
+                }
+                if let Ok(ip) = device.get_ip() {
+                    reserved.push(Range { base: Address::from_ipv4(ip), prefix_len: 32 });
+                }
+                let mut pool = IpamPool::new(ipam, reserved)?;
+                if let Some(ref state) = state {
+                    match state.read(IPAM_LEASES_FILE) {
+                        Ok(Some(data)) => pool.load(&String::from_utf8_lossy(&data)),
+                        Ok(None) => (),
+                        Err(err) => warn!("Failed to load leases: {}", err),
+                    }
+                }
+                (Some(pool), None)
+            }
+            Some(ref ipam) if ipam.request => {
+                let mut client = IpamClient::new();
+                if let Some(ref state) = state {
+                    match state.read(IPAM_LEASE_FILE) {
+                        Ok(Some(data)) => client.load(&String::from_utf8_lossy(&data)),
+                        Ok(None) => (),
+                        Err(err) => warn!("Failed to load lease: {}", err),
+                    }
+                }
+                (None, Some(client))
+            }
+            Some(_) => {
+                warn!("IPAM needs either a pool or request, ignoring it");
+                (None, None)
+            }
+            None => (None, None),
+        };
         let beacon_key = config.beacon_password.as_ref().map(|s| s.as_bytes()).unwrap_or(&[]);
+        let mut beacon_serializer = BeaconSerializer::new(beacon_key);
+        beacon_serializer.set_hints(Some(BeaconHints {
+            version: Some(PROTOCOL_VERSION),
+            udp_port: socket.address().ok().map(|addr| addr.port()),
+        }));
         let mut res = GenericCloud {
             node_id,
             peers: HashMap::default(),
             claims,
+            deferred_claims,
+            ethertypes,
             learning,
             broadcast,
             pending_inits: HashMap::default(),
+            pending_admissions: HashMap::default(),
+            handshake_activity: HashMap::default(),
+            handshakes_evicted: 0,
+            peers_evicted: 0,
+            evicted: HashMap::default(),
+            dials: DialQueue::new(config.max_dials, config.dial_rate),
             reconnect_peers: SmallVec::new(),
             own_addresses: SmallVec::new(),
+            advertise_policy,
+            external_addresses: SmallVec::new(),
+            local_probes: HashMap::default(),
+            migrations: HashMap::default(),
+            migration_checks: 0,
+            migrations_accepted: 0,
+            migrations_rejected: 0,
+            duplicates_sent: 0,
+            duplicates_dropped: 0,
+            fec_parities_sent: 0,
+            fec_recovered: 0,
+            packets_reordered: 0,
+            reorder_deadline: None,
             peer_timeout_publish: config.peer_timeout as u16,
-            table: ClaimTable::new(config.switch_timeout as Duration, config.peer_timeout as Duration),
+            table,
+            quality: QualityTable::new(),
+            receive_time: None,
+            peer_states: PeerStateTable::new(),
+            arp_table,
+            firewall,
+            nat,
+            claim_filters,
+            claim_policy,
+            budget,
+            control_limit: config.control_limit.clone().map(ControlLimiter::new),
+            bans: config.bans.clone().map(BanList::new),
+            crypto_stats: CryptoStats::new(crypto.algorithm_speeds()),
+            peer_names,
+            radius,
+            auth_hook,
+            dns_records,
+            dhcp,
+            ipam_pool,
+            ipam_client,
+            max_payload,
+            device_mtu,
+            mtu_mismatches: 0,
+            duplicate_identities: 0,
             socket,
             device,
             next_peers: now,
+            node_info_changed: false,
+            peer_seq: 1,
+            peer_log: VecDeque::with_capacity(PEER_LOG_SIZE),
+            peer_log_start: 1,
             update_freq,
-            stats_file,
-            statsd_server: config.statsd_server.clone(),
+            stats_sinks,
+            timestamps: Timestamps::new(config.time_format.as_deref(), config.utc)?,
             next_housekeep: now,
+            last_housekeep: now,
             next_stats_out: now + STATS_INTERVAL,
             next_beacon: now,
             next_own_address_reset: now + OWN_ADDRESS_RESET_INTERVAL,
+            idle: false,
+            idle_peers: SmallVec::new(),
+            payload_seen: false,
+            last_payload: now,
+            last_beacon: Vec::new(),
             port_forwarding,
-            traffic: TrafficStats::default(),
-            beacon_serializer: BeaconSerializer::new(beacon_key),
+            traffic,
+            buffers: BufferPool::new(
+                SPACE_BEFORE,
+                if config.latency_bypass { BUFFER_POOL_SIZE + LATENCY_BYPASS_BATCH } else { BUFFER_POOL_SIZE },
+            ),
+            bulk_queue: Vec::new(),
+            early_data: Vec::new(),
+            beacon_serializer,
+            handle: CloudHandle::default(),
+            error_counts: HashMap::default(),
+            state,
+            control,
+            chaos: if config.chaos { Some(Chaos::new()) } else { None },
+            capture,
+            signals,
             crypto,
             config: config.clone(),
             _dummy_p: PhantomData,
             _dummy_ts: PhantomData,
         };
+        if res.ipam_pool.is_some() {
+            // Nodes find the coordinator by its service
+            res.config.services.push(IPAM_SERVICE.to_string());
+        }
         res.initialize();
-        res
+        res.connect_to_saved_peers();
+        res.connect_to_manual_peers();
+        Ok(res)
     }
 
     #[inline]
@@ -171,6 +717,11 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
         self.device.ifname()
     }
 
+    /// Returns a handle that can be used to stop the node from another thread
+    pub fn handle(&self) -> CloudHandle {
+        self.handle.clone()
+    }
+
     /// Sends the message to all peers
     ///
     /// # Errors
@@ -180,19 +731,50 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
     #[inline]
     fn broadcast_msg(&mut self, type_: u8, msg: &mut MsgBuffer) -> Result<(), Error> {
         debug!("Broadcasting message type {}, {:?} bytes to {} peers", type_, msg.len(), self.peers.len());
-        let mut msg_data = MsgBuffer::new(100);
+        let mut msg_data = self.buffers.get();
         for (addr, peer) in &mut self.peers {
+            if type_ == MESSAGE_TYPE_DATA && peer.max_payload.map(|max| msg.len() > max).unwrap_or(false) {
+                // COLD PATH
+                self.traffic.count_dropped_payload(msg.len());
+                continue;
+            }
+            if type_ == MESSAGE_TYPE_DATA && !self.budget.as_mut().map(|b| b.allows(addr, msg.len())).unwrap_or(true) {
+                // COLD PATH
+                self.traffic.count_dropped_payload(msg.len());
+                continue;
+            }
             msg_data.set_start(msg.get_start());
             msg_data.set_length(msg.len());
             msg_data.message_mut().clone_from_slice(msg.message());
+            if peer.padding && self.config.crypto.padding {
+                pad_control_msg(type_, &mut msg_data);
+            }
             peer.crypto.send_message(type_, &mut msg_data)?;
+            if let Some(ref mut chaos) = self.chaos {
+                // COLD PATH
+                if !chaos.outgoing(*addr, msg_data.message()) {
+                    continue;
+                }
+            }
+            if let Some(ref mut capture) = self.capture {
+                // COLD PATH
+                capture.outgoing(*addr, msg_data.message());
+            }
             self.traffic.count_out_traffic(*addr, msg_data.len());
+            if let Some(ref mut budget) = self.budget {
+                budget.count(addr, msg_data.len());
+            }
+            if let Some(ref mut radius) = self.radius {
+                radius.count_out(addr, msg_data.len());
+            }
+            peer.sent_data = true;
             match self.socket.send(msg_data.message(), *addr) {
                 Ok(written) if written == msg_data.len() => Ok(()),
                 Ok(_) => Err(Error::Socket("Sent out truncated packet")),
                 Err(e) => Err(Error::SocketIo("IOError when sending", e)),
             }?
         }
+        self.buffers.put(msg_data);
         Ok(())
     }
 
@@ -200,7 +782,23 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
     fn send_to(&mut self, addr: SocketAddr, msg: &mut MsgBuffer) -> Result<(), Error> {
         // HOT PATH
         debug!("Sending msg with {} bytes to {}", msg.len(), addr);
+        if let Some(ref mut chaos) = self.chaos {
+            // COLD PATH
+            if !chaos.outgoing(addr, msg.message()) {
+                return Ok(());
+            }
+        }
+        if let Some(ref mut capture) = self.capture {
+            // COLD PATH
+            capture.outgoing(addr, msg.message());
+        }
         self.traffic.count_out_traffic(addr, msg.len());
+        if let Some(ref mut budget) = self.budget {
+            budget.count(&addr, msg.len());
+        }
+        if let Some(ref mut radius) = self.radius {
+            radius.count_out(&addr, msg.len());
+        }
         match self.socket.send(msg.message(), addr) {
             Ok(written) if written == msg.len() => Ok(()),
             Ok(_) => Err(Error::Socket("Sent out truncated packet")),
@@ -216,6 +814,10 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
             Some(peer) => peer,
             None => return Err(Error::Message("Sending to node that is not a peer")),
         };
+        peer.sent_data = true;
+        if peer.padding && self.config.crypto.padding {
+            pad_control_msg(type_, msg);
+        }
         peer.crypto.send_message(type_, msg)?;
         self.send_to(addr, msg)
     }
@@ -225,20 +827,60 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
         let socket_addr = self.socket.address().map(mapped_addr)?;
         // 1) Specified advertise addresses
         for addr in &self.config.advertise_addresses {
-            self.own_addresses.push(parse_listen(addr, socket_addr.port()));
+            self.own_addresses.push(parse_listen(addr, socket_addr.port())?);
+        }
+        // 2) Addresses of UDP socket, of both address families if the node has them
+        for addr in self.socket.addresses()? {
+            let addr = mapped_addr(addr);
+            if !self.own_addresses.contains(&addr) {
+                self.own_addresses.push(addr);
+            }
         }
-        // 2) Address of UDP socket
-        self.own_addresses.push(socket_addr);
         // 3) Addresses from port forwarding
         if let Some(ref pfw) = self.port_forwarding {
             self.own_addresses.push(pfw.get_internal_ip().into());
             self.own_addresses.push(pfw.get_external_ip().into());
         }
         debug!("Own addresses: {:?}", self.own_addresses);
-        // TODO: detect address changes and call event
+        if let Some(ref mut policy) = self.advertise_policy {
+            policy.refresh();
+        }
+        self.check_external_addresses();
         Ok(())
     }
 
+    /// The own addresses that the advertise policy allows to send to peers and to put into beacons
+    fn advertised_addresses(&self) -> AddrList {
+        match self.advertise_policy {
+            Some(ref policy) => policy.select(&self.own_addresses),
+            None => self.own_addresses.clone(),
+        }
+    }
+
+    /// Calls the external_address_changed hook when a new public address has been found
+    ///
+    /// Addresses learned from peers are dropped when the own addresses are reset, so vanished addresses do not
+    /// trigger the hook, only new ones do.
+    fn check_external_addresses(&mut self) {
+        let current: AddrList = self
+            .own_addresses
+            .iter()
+            .map(|addr| mapped_addr(*addr))
+            .filter(|addr| !addr.ip().is_unspecified() && !is_local_addr(addr))
+            .collect();
+        if current.iter().all(|addr| self.external_addresses.contains(addr)) {
+            return;
+        }
+        let addrs = current.iter().map(|addr| addr_nice(*addr).to_string()).collect::<Vec<_>>().join(" ");
+        info!("External addresses changed: {}", addrs);
+        self.config.call_hook(
+            "external_address_changed",
+            vec![("IFNAME", self.device.ifname().to_owned()), ("ADDRESSES", addrs)],
+            true,
+        );
+        self.external_addresses = current;
+    }
+
     /// Returns the number of peers
     #[allow(dead_code)]
     pub fn peer_count(&self) -> usize {
@@ -250,21 +892,34 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
     /// This method adds a peer to the list of nodes to reconnect to. A periodic task will try to
     /// connect to the peer if it is not already connected.
     pub fn add_reconnect_peer(&mut self, add: String) {
+        self.add_peer_config(PeerConfig::new(add))
+    }
+
+    /// Adds a configured peer to the reconnect list
+    ///
+    /// Like `add_reconnect_peer` but the peer is only connected to when all peers with a lower
+    /// priority value failed. If a keepalive is given, keepalive messages are sent to the peer in
+    /// this interval.
+    pub fn add_peer_config(&mut self, peer: PeerConfig) {
         let now = TS::now();
-        let resolved = match resolve(&add as &str) {
+        let resolved = match resolve_scoped(&peer.address) {
             Ok(addrs) => addrs,
             Err(err) => {
-                warn!("Failed to resolve {}: {:?}", add, err);
+                warn!("Failed to resolve {}: {:?}", peer.address, err);
                 smallvec![]
             }
         };
         self.reconnect_peers.push(ReconnectEntry {
-            address: Some((add, now)),
+            address: Some((peer.address, now)),
             tries: 0,
             timeout: 1,
             resolved,
             next: now,
             final_timeout: None,
+            priority: peer.priority,
+            attempts: 0,
+            keepalive: peer.keepalive,
+            next_keepalive: now,
         })
     }
 
@@ -273,6 +928,7 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
     /// This method connects to node by sending a `Message::Init` to it. If `addr` is a name that
     /// resolves to multiple addresses, one message is sent to each of them.
     /// If the node is already a connected peer or the address is blacklisted, no message is sent.
+    /// The address is dialed even without peer exchange, only spokes refuse to connect to other nodes than their hubs.
     ///
     /// # Errors
     /// This method returns `Error::NameError` if the address is a name that fails to resolve.
@@ -286,41 +942,193 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
                 return Ok(());
             }
         }
-        if !addrs.is_empty() {
-            self.config.call_hook(
-                "peer_connecting",
-                vec![("PEER", format!("{:?}", addr_nice(addrs[0]))), ("IFNAME", self.device.ifname().to_owned())],
-                true,
-            );
+        if self.config.topology == Topology::Spoke && !addrs.iter().any(|a| self.is_configured_peer(a)) {
+            warn!("Not connecting to {:?} as spokes only connect to their hubs", addr);
+            return Ok(());
+        }
+        // Skip addresses of a family that the node has no address of, e.g. IPv6 addresses on IPv4-only nodes
+        let addrs: SmallVec<[SocketAddr; 3]> = if addrs.iter().any(|a| self.has_family_of(a)) {
+            addrs.into_iter().filter(|a| self.has_family_of(a)).collect()
+        } else {
+            addrs
+        };
+        // Avoid demoted paths if there are better ones
+        let addrs: AddrList = if addrs.iter().all(|a| self.quality.is_demoted(a)) {
+            addrs.into_iter().collect()
+        } else {
+            addrs.into_iter().filter(|a| !self.quality.is_demoted(a)).collect()
+        };
+        if addrs.is_empty() {
+            return Ok(());
+        }
+        let pending_inits = &self.pending_inits;
+        if !self.dials.try_start(&addrs, |a| pending_inits.contains_key(a)) {
+            debug!("Too many connection attempts, queueing {:?}", addr_nice(addrs[0]));
+            self.dials.push(addrs);
+            return Ok(());
+        }
+        self.dial(&addrs);
+        Ok(())
+    }
+
+    /// Connects to addresses that were learned from other nodes, if the node may dial them
+    fn connect_learned(&mut self, addrs: &[SocketAddr]) -> Result<(), Error> {
+        let addrs: SmallVec<[SocketAddr; 3]> =
+            addrs.iter().copied().filter(|a| self.may_dial(&mapped_addr(*a))).collect();
+        if addrs.is_empty() {
+            return Ok(());
+        }
+        self.connect(&addrs as &[SocketAddr])
+    }
+
+    /// Whether the node can connect to an address that it learned, without peer exchange and on spokes only configured
+    /// peers can be dialed
+    fn may_dial(&self, addr: &SocketAddr) -> bool {
+        (self.config.peer_exchange && self.config.topology != Topology::Spoke) || self.is_configured_peer(addr)
+    }
+
+    /// Whether the node has an own address of the same family as the address, so that it can probably reach it
+    ///
+    /// If the node only knows unspecified or loopback addresses of its own, it assumes to have both families.
+    fn has_family_of(&self, addr: &SocketAddr) -> bool {
+        let mut own = self
+            .own_addresses
+            .iter()
+            .filter(|a| {
+                let ip = addr_nice(**a).0.ip();
+                !ip.is_unspecified() && !ip.is_loopback()
+            })
+            .peekable();
+        if own.peek().is_none() {
+            return true;
         }
-        // Send a message to each resolved address
+        own.any(|a| is_ipv4_addr(a) == is_ipv4_addr(addr))
+    }
+
+    /// Sends a handshake to each of the addresses of a node
+    fn dial(&mut self, addrs: &[SocketAddr]) {
+        self.config.call_hook(
+            "peer_connecting",
+            vec![("PEER", format!("{:?}", addr_nice(addrs[0]))), ("IFNAME", self.device.ifname().to_owned())],
+            true,
+        );
         for a in addrs {
             // Ignore error this time
-            self.connect_sock(a).ok();
+            self.start_handshake(*a).ok();
+        }
+    }
+
+    /// Starts the queued connection attempts once other handshakes have finished
+    fn dial_queued(&mut self) {
+        let pending_inits = &self.pending_inits;
+        for addrs in self.dials.take_due(|a| pending_inits.contains_key(a)) {
+            if addrs.iter().any(|a| self.peers.contains_key(a) || self.pending_inits.contains_key(a)) {
+                continue;
+            }
+            self.dial(&addrs);
         }
-        Ok(())
     }
 
-    fn create_node_info(&self) -> NodeInfo {
+    /// Creates the node info, applying the claim filters if it is meant for a single peer and summarizing the claims
+    /// if configured
+    ///
+    /// Peers that acknowledge peer list versions only get the peers that were added since the version they have
+    /// seen, everybody else gets a random selection of all peers.
+    fn create_node_info(&self, addr: Option<SocketAddr>) -> NodeInfo {
+        let (claims, export_peers) = match (&self.claim_filters, addr) {
+            (Some(filters), Some(addr)) => (filters.export_claims(&addr, &self.claims), filters.export_peers(&addr)),
+            _ => (self.claims.clone(), true),
+        };
+        let export_peers = export_peers && self.config.peer_exchange && self.config.topology == Topology::Mesh;
+        let claims = if self.config.summarize_claims { Range::summarize(&claims) } else { claims };
+        let receiver = addr.and_then(|addr| self.peers.get(&addr));
+        let mut gossip = GossipInfo { seq: self.peer_seq, base: 0, ack: receiver.map(|p| p.gossip_ack).unwrap_or(0) };
+        // Peers decide whether to apply FEC by the loss of their messages
+        let loss = match (receiver, addr) {
+            (Some(receiver), Some(addr)) if receiver.fec => Some((self.quality.loss(&addr) * 1000.0).round() as u16),
+            _ => None,
+        };
         let mut peers = smallvec![];
-        for peer in self.peers.values() {
-            peers.push(PeerInfo { node_id: Some(peer.node_id), addrs: peer.addrs.clone() })
+        match receiver {
+            Some(receiver) if export_peers && self.can_send_peer_changes(receiver) => {
+                gossip.base = receiver.gossip_acked;
+                for &(seq, addr) in self.peer_log.iter().filter(|(seq, _)| *seq > receiver.gossip_acked) {
+                    if peers.len() >= MAX_PEER_ANNOUNCEMENTS {
+                        // The rest follows with the next node info
+                        gossip.seq = seq - 1;
+                        break;
+                    }
+                    if let Some(peer) = self.peers.get(&addr) {
+                        peers.push(PeerInfo { node_id: Some(peer.node_id), addrs: peer.addrs.clone() })
+                    }
+                }
+            }
+            _ if export_peers => {
+                for peer in self.peers.values() {
+                    peers.push(PeerInfo { node_id: Some(peer.node_id), addrs: peer.addrs.clone() })
+                }
+            }
+            _ => (),
         }
-        if peers.len() > 20 {
+        if peers.len() > MAX_PEER_ANNOUNCEMENTS {
             let mut rng = rand::thread_rng();
-            peers.partial_shuffle(&mut rng, 20);
-            peers.truncate(20);
+            peers.partial_shuffle(&mut rng, MAX_PEER_ANNOUNCEMENTS);
+            peers.truncate(MAX_PEER_ANNOUNCEMENTS);
         }
         NodeInfo {
             node_id: self.node_id,
             peers,
-            claims: self.claims.clone(),
+            claims,
             peer_timeout: Some(self.peer_timeout_publish),
-            addrs: self.own_addresses.clone(),
+            addrs: self.advertised_addresses(),
+            protocol: Some(ProtocolInfo::own()),
+            max_payload: self.max_payload.map(|max| max as u16),
+            services: self.config.services.clone(),
+            name: self.config.node_name.clone(),
+            time: Some(TS::wall_clock()),
+            gossip: Some(gossip),
+            loss,
+            reorder_window: self.config.reorder_window,
+        }
+    }
+
+    /// Checks whether the peer can get only the peers that were added since the version it has seen
+    fn can_send_peer_changes(&self, peer: &PeerData<TS>) -> bool {
+        peer.gossip
+            && peer.gossip_acked >= self.peer_log_start
+            && peer.gossip_acked <= self.peer_seq
+            && peer.next_full_peers > TS::now()
+    }
+
+    /// Records a new peer in the peer list changes
+    fn log_new_peer(&mut self, addr: SocketAddr) {
+        self.peer_seq = self.peer_seq.wrapping_add(1);
+        if self.peer_log.len() >= PEER_LOG_SIZE {
+            if let Some((seq, _)) = self.peer_log.pop_front() {
+                self.peer_log_start = seq;
+            }
         }
+        self.peer_log.push_back((self.peer_seq, addr));
     }
 
+    /// Dials an address that the node found by itself, see `may_dial`
     fn connect_sock(&mut self, addr: SocketAddr) -> Result<(), Error> {
+        let addr = mapped_addr(addr);
+        if self.peers.contains_key(&addr)
+            || self.own_addresses.contains(&addr)
+            || self.pending_inits.contains_key(&addr)
+        {
+            return Ok(());
+        }
+        if !self.may_dial(&addr) {
+            debug!("Not connecting to {} as it is no configured peer", addr_nice(addr));
+            return Ok(());
+        }
+        self.start_handshake(addr)
+    }
+
+    /// Sends a handshake to the address, also if it would not be dialed automatically
+    fn start_handshake(&mut self, addr: SocketAddr) -> Result<(), Error> {
         let addr = mapped_addr(addr);
         if self.peers.contains_key(&addr)
             || self.own_addresses.contains(&addr)
@@ -329,21 +1137,32 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
             return Ok(());
         }
         debug!("Connecting to {:?}", addr);
-        let payload = self.create_node_info();
+        self.peer_states.change(addr, PeerState::Init);
+        let payload = self.create_node_info(Some(addr));
         let mut peer_crypto = self.crypto.peer_instance(payload);
-        let mut msg = MsgBuffer::new(SPACE_BEFORE);
+        let mut msg = self.buffers.get();
         peer_crypto.initialize(&mut msg)?;
+        self.evict_handshakes();
         self.pending_inits.insert(addr, peer_crypto);
-        self.send_to(addr, &mut msg)
+        self.handshake_activity.insert(addr, TS::now());
+        self.quality.handshake_started(addr);
+        self.peer_states.change(addr, PeerState::HandshakePending);
+        self.send_to(addr, &mut msg)?;
+        self.buffers.put(msg);
+        Ok(())
     }
 
     fn crypto_housekeep(&mut self) -> Result<(), Error> {
-        let mut msg = MsgBuffer::new(SPACE_BEFORE);
+        let mut msg = self.buffers.get();
         let mut del: SmallVec<[SocketAddr; 4]> = smallvec![];
         for addr in self.pending_inits.keys().copied().collect::<SmallVec<[SocketAddr; 4]>>() {
             msg.clear();
             match self.pending_inits.get_mut(&addr).unwrap().every_second(&mut msg) {
-                Err(_) => del.push(addr),
+                Err(_) => {
+                    self.quality.handshake_failed(addr);
+                    self.crypto_stats.handshake_failed();
+                    del.push(addr)
+                }
                 Ok(MessageResult::None) => (),
                 Ok(MessageResult::Reply) => self.send_to(addr, &mut msg)?,
                 Ok(_) => unreachable!(),
@@ -351,6 +1170,10 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
         }
         for addr in self.peers.keys().copied().collect::<SmallVec<[SocketAddr; 16]>>() {
             msg.clear();
+            let (received, lost) = self.peers.get_mut(&addr).unwrap().crypto.take_packet_stats();
+            self.quality.count_packets(addr, received, lost);
+            let crypto = &mut self.peers.get_mut(&addr).unwrap().crypto;
+            self.crypto_stats.add(addr, crypto.algorithm_name(), &crypto.take_stats());
             match self.peers.get_mut(&addr).unwrap().crypto.every_second(&mut msg) {
                 Err(_) => del.push(addr),
                 Ok(MessageResult::None) => (),
@@ -358,9 +1181,19 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
                 Ok(_) => unreachable!(),
             }
         }
+        self.buffers.put(msg);
+        let pending_inits = &self.pending_inits;
+        self.handshake_activity.retain(|addr, _| pending_inits.contains_key(addr));
+        self.pending_admissions.retain(|addr, _| pending_inits.contains_key(addr));
+        let peers = &self.peers;
+        self.crypto_stats.retain_peers(|addr| peers.contains_key(addr));
         for addr in del {
             self.pending_inits.remove(&addr);
+            self.peer_states.close(addr);
             if self.peers.remove(&addr).is_some() {
+                if let Some(ref mut radius) = self.radius {
+                    radius.stop(&addr, TerminateCause::LostService);
+                }
                 self.connect_sock(addr)?;
             }
         }
@@ -369,9 +1202,18 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
 
     fn reconnect_to_peers(&mut self) -> Result<(), Error> {
         let now = TS::now();
+        // Peers with higher priority values are backups that are only dialed when all better peers failed
+        let peers = &self.peers;
+        let active_priority = self
+            .reconnect_peers
+            .iter()
+            .filter(|e| e.attempts < FAILOVER_ATTEMPTS || e.resolved.iter().any(|a| peers.contains_key(a)))
+            .map(|e| e.priority)
+            .min()
+            .unwrap_or(u8::MAX);
         // Connect to those reconnect_peers that are due
         for entry in self.reconnect_peers.clone() {
-            if entry.next > now {
+            if entry.next > now || entry.priority > active_priority {
                 continue;
             }
             self.connect(&entry.resolved as &[SocketAddr])?;
@@ -381,6 +1223,7 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
             for addr in &entry.resolved {
                 if self.peers.contains_key(&addr) {
                     entry.tries = 0;
+                    entry.attempts = 0;
                     entry.timeout = 1;
                     entry.next = now + 1;
                     continue;
@@ -389,7 +1232,7 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
             // Resolve entries anew
             if let Some((ref address, ref mut next_resolve)) = entry.address {
                 if *next_resolve <= now {
-                    match resolve(address as &str) {
+                    match resolve_scoped(address) {
                         Ok(addrs) => entry.resolved = addrs,
                         Err(_) => match resolve(&format!("{}:{}", address, DEFAULT_PORT)) {
                             Ok(addrs) => entry.resolved = addrs,
@@ -399,12 +1242,13 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
                     *next_resolve = now + RESOLVE_INTERVAL;
                 }
             }
-            // Ignore if next attempt is already in the future
-            if entry.next > now {
+            // Ignore if next attempt is already in the future or the peer is a backup that was not dialed
+            if entry.next > now || entry.priority > active_priority {
                 continue;
             }
             // Exponential back-off: every 10 tries, the interval doubles
             entry.tries += 1;
+            entry.attempts = entry.attempts.saturating_add(1);
             if entry.tries > 10 {
                 entry.tries = 0;
                 entry.timeout *= 2;
@@ -420,219 +1264,1350 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
         Ok(())
     }
 
-    fn housekeep(&mut self) -> Result<(), Error> {
-        let now = TS::now();
-        let mut buffer = MsgBuffer::new(SPACE_BEFORE);
-        let mut del: SmallVec<[SocketAddr; 3]> = SmallVec::new();
-        for (&addr, ref data) in &self.peers {
-            if data.timeout < now {
-                del.push(addr);
-            }
-        }
-        for addr in del {
-            info!("Forgot peer {} due to timeout", addr_nice(addr));
-            self.peers.remove(&addr);
-            self.table.remove_claims(addr);
-            self.connect_sock(addr)?; // Try to reconnect
-        }
-        self.table.housekeep();
-        self.crypto_housekeep()?;
-        // Periodically extend the port-forwarding
-        if let Some(ref mut pfw) = self.port_forwarding {
-            pfw.check_extend();
-        }
-        let now = TS::now();
-        // Periodically send peer list to peers
-        if self.next_peers <= now {
-            debug!("Send peer list to all peers");
-            let info = self.create_node_info();
-            info.encode(&mut buffer);
-            self.broadcast_msg(MESSAGE_TYPE_NODE_INFO, &mut buffer)?;
-            // Reschedule for next update
-            let min_peer_timeout = self.peers.iter().map(|p| p.1.peer_timeout).min().unwrap_or(DEFAULT_PEER_TIMEOUT);
-            let interval = min(self.update_freq as u16, max(min_peer_timeout / 2 - 60, 1));
-            self.next_peers = now + Time::from(interval);
+    /// Closes the connections to peers whose trusted key is outside of its access window
+    fn close_outside_access_windows(&mut self) {
+        if !self.crypto.has_schedules() {
+            return;
         }
-        self.reconnect_to_peers()?;
-        if self.next_stats_out < now {
-            // Write out the statistics
-            self.write_out_stats().map_err(|err| Error::FileIo("Failed to write stats file", err))?;
-            self.send_stats_to_statsd()?;
-            self.next_stats_out = now + STATS_INTERVAL;
-            self.traffic.period(Some(5));
+        let now = TS::wall_clock();
+        let crypto = &self.crypto;
+        let closed: SmallVec<[SocketAddr; 4]> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.crypto.peer_key().map(|key| !crypto.allows_access(key, now)).unwrap_or(false))
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in closed {
+            warn!("Closing connection to {} as it is outside of its access window", self.peer_nice(addr));
+            let mut msg = self.buffers.get();
+            (*msg).clone_from(&[CLOSE_REASON_UNAUTHORIZED]);
+            self.send_msg(addr, MESSAGE_TYPE_CLOSE, &mut msg).ok();
+            self.buffers.put(msg);
+            if let Some(ref mut radius) = self.radius {
+                radius.stop(&addr, TerminateCause::AdminReset);
+            }
+            self.remove_peer(addr);
         }
-        if let Some(peers) = self.beacon_serializer.get_cmd_results() {
-            debug!("Loaded beacon with peers: {:?}", peers);
-            for peer in peers {
-                self.connect_sock(peer)?;
+    }
+
+    /// Starts advertising the deferred claims once the interface has its address
+    fn activate_deferred_claims(&mut self) {
+        let next_warning = match self.deferred_claims {
+            Some((_, ref mut next_warning)) => next_warning,
+            None => return,
+        };
+        let expected = self.config.ip.as_ref().and_then(|ip| Ipv4Addr::from_str(ip.split('/').next()?).ok());
+        let ip = match self.device.get_ip() {
+            Ok(ip) if expected.is_none() || expected == Some(ip) => ip,
+            _ => {
+                if *next_warning <= TS::now() {
+                    warn!("Interface still has no matching address, claims are not advertised yet");
+                    *next_warning = TS::now() + DEFERRED_CLAIMS_WARN_INTERVAL;
+                }
+                return;
             }
+        };
+        let (mut claims, _) = self.deferred_claims.take().unwrap();
+        if self.device.get_type() == Type::Tun && self.config.auto_claim {
+            let range = Range { base: Address::from_ipv4(ip), prefix_len: 32 };
+            info!("Auto-claiming {} due to interface address", range);
+            claims.push(range);
         }
-        if self.next_beacon < now {
-            self.store_beacon()?;
-            self.load_beacon()?;
-            self.next_beacon = now + Time::from(self.config.beacon_interval);
+        info!("Interface has address {}, advertising claims", ip);
+        // Leased subnets might already be claimed
+        self.claims.extend(claims);
+        // Tell the peers right away
+        self.schedule_node_info();
+    }
+
+    /// Requests or renews the lease from the coordinator and drops the claim of an expired lease
+    fn ipam_housekeep(&mut self) -> Result<(), Error> {
+        if let Some(ref mut pool) = self.ipam_pool {
+            pool.housekeep();
         }
-        // Periodically reset own peers
-        if self.next_own_address_reset <= now {
-            self.reset_own_addresses().map_err(|err| Error::SocketIo("Failed to get own addresses", err))?;
-            self.next_own_address_reset = now + OWN_ADDRESS_RESET_INTERVAL;
+        if let Some(lease) = self.ipam_client.as_mut().and_then(|client| client.housekeep()) {
+            warn!("Lease of {} expired without renewal, not claiming it anymore", lease.range());
+            self.claims.retain(|range| *range != lease.range());
+            self.schedule_node_info();
         }
-        Ok(())
+        let coordinator =
+            self.peers.iter().find(|(_, peer)| peer.services.iter().any(|s| s == IPAM_SERVICE)).map(|(addr, _)| *addr);
+        let (client, coordinator) = match (&mut self.ipam_client, coordinator) {
+            (Some(client), Some(coordinator)) if client.is_due() => (client, coordinator),
+            _ => return Ok(()),
+        };
+        debug!("Requesting lease from {}", addr_nice(coordinator));
+        let mut msg = self.buffers.get();
+        client.request(&mut msg);
+        let res = self.send_msg(coordinator, MESSAGE_TYPE_LEASE_REQUEST, &mut msg);
+        self.buffers.put(msg);
+        res
     }
 
-    /// Stores the beacon
-    fn store_beacon(&mut self) -> Result<(), Error> {
-        if let Some(ref path) = self.config.beacon_store {
-            let peers: SmallVec<[SocketAddr; 3]> =
-                self.own_addresses.choose_multiple(&mut thread_rng(), 3).cloned().collect();
-            if let Some(path) = path.strip_prefix('|') {
-                self.beacon_serializer
-                    .write_to_cmd(&peers, path)
-                    .map_err(|e| Error::BeaconIo("Failed to call beacon command", e))?;
-            } else {
-                self.beacon_serializer
-                    .write_to_file(&peers, &path)
-                    .map_err(|e| Error::BeaconIo("Failed to write beacon to file", e))?;
-            }
+    /// Answers the lease request of a peer with a block of the pool
+    ///
+    /// Leases are bound to the name of the peer, peers without a name get a new lease after every restart.
+    fn handle_lease_request(&mut self, src: SocketAddr, data: &mut MsgBuffer) -> Result<(), Error> {
+        let pool = match self.ipam_pool {
+            Some(ref mut pool) => pool,
+            None => return Err(Error::Message("Lease request but this node is no IPAM coordinator")),
+        };
+        let requested = ipam::decode_request(data.message())?;
+        let client = match self.peers.get(&src) {
+            Some(peer) => peer.name.clone().unwrap_or_else(|| bytes_to_hex(&peer.node_id)),
+            None => return Ok(()),
+        };
+        let lease = pool.allocate(&client, requested);
+        match lease {
+            // The other peers learn about the client right away, so they can reach it under its new address
+            Some(_) => self.schedule_node_info(),
+            None => warn!("IPAM pool is exhausted, no lease for {}", client),
         }
-        Ok(())
+        ipam::encode_lease(lease.as_ref(), data);
+        self.send_msg(src, MESSAGE_TYPE_LEASE, data)
     }
 
-    /// Loads the beacon
-    fn load_beacon(&mut self) -> Result<(), Error> {
-        let peers;
-        if let Some(ref path) = self.config.beacon_load {
-            if let Some(path) = path.strip_prefix('|') {
-                self.beacon_serializer
-                    .read_from_cmd(path, Some(50))
-                    .map_err(|e| Error::BeaconIo("Failed to call beacon command", e))?;
+    /// Takes the lease from the coordinator and applies it if the block has changed
+    fn handle_lease(&mut self, src: SocketAddr, data: &mut MsgBuffer) -> Result<(), Error> {
+        let coordinator = self.peers.get(&src).map(|peer| peer.services.iter().any(|s| s == IPAM_SERVICE));
+        let client = match self.ipam_client {
+            Some(ref mut client) if coordinator == Some(true) => client,
+            _ => return Err(Error::Message("Lease from a node that is no IPAM coordinator")),
+        };
+        let lease = match ipam::decode_lease(data.message())? {
+            Some(lease) => lease,
+            None => {
+                warn!("IPAM coordinator {} has no free addresses", addr_nice(src));
                 return Ok(());
-            } else {
-                peers = self
-                    .beacon_serializer
-                    .read_from_file(&path, Some(50))
-                    .map_err(|e| Error::BeaconIo("Failed to read beacon from file", e))?;
             }
-        } else {
-            return Ok(());
-        }
-        debug!("Loaded beacon with peers: {:?}", peers);
-        for peer in peers {
-            self.connect_sock(peer)?;
+        };
+        if let (true, old) = client.set_lease(lease) {
+            self.apply_lease(lease, old)
         }
         Ok(())
     }
 
-    /// Writes out the statistics to a file
-    fn write_out_stats(&mut self) -> Result<(), io::Error> {
-        if let Some(ref mut f) = self.stats_file {
-            debug!("Writing out stats");
-            f.seek(SeekFrom::Start(0))?;
-            f.set_len(0)?;
-            writeln!(f, "peers:")?;
-            let now = TS::now();
-            for (addr, data) in &self.peers {
-                writeln!(
-                    f,
-                    "  - \"{}\": {{ ttl_secs: {}, crypto: {} }}",
-                    addr_nice(*addr),
-                    data.timeout - now,
-                    data.crypto.algorithm_name()
-                )?;
-            }
-            writeln!(f)?;
-            self.table.write_out(f)?;
-            writeln!(f)?;
-            self.traffic.write_out(f)?;
-            writeln!(f)?;
+    /// Sets the leased address on the interface and claims the leased block instead of the old one
+    fn apply_lease(&mut self, lease: Lease, old: Option<Lease>) {
+        info!("Leased {}, using address {}", lease.range(), lease.address());
+        if let Err(err) = self.device.set_address(lease.address(), lease.netmask()) {
+            warn!("Failed to set the leased address on the interface: {}", err);
+        }
+        if let Some(old) = old {
+            self.claims.retain(|range| *range != old.range());
+        }
+        if self.device.get_type() == Type::Tun {
+            self.claims.push(lease.range());
         }
+        self.schedule_node_info();
+        self.config.call_hook(
+            "address_leased",
+            vec![
+                ("IFNAME", self.device.ifname().to_owned()),
+                ("ADDRESS", lease.address().to_string()),
+                ("NETMASK", lease.netmask().to_string()),
+                ("SUBNET", lease.range().to_string()),
+            ],
+            true,
+        );
+    }
+
+    /// Sends keepalive messages to connected peers that have their own keepalive interval
+    ///
+    /// When keepalives are suppressed, peers that got any other message within the interval are skipped.
+    fn send_peer_keepalives(&mut self) -> Result<(), Error> {
+        let now = TS::now();
+        let mut due: SmallVec<[SocketAddr; 3]> = smallvec![];
+        let peers = &self.peers;
+        let suppress = self.config.suppress_keepalives;
+        for entry in &mut self.reconnect_peers {
+            let keepalive = match entry.keepalive {
+                Some(keepalive) if entry.next_keepalive <= now => keepalive as Time,
+                _ => continue,
+            };
+            entry.next_keepalive = now + keepalive;
+            if let Some(addr) = entry.resolved.iter().find(|a| peers.contains_key(a)) {
+                let peer = &peers[addr];
+                if suppress && peer.data_keepalive && peer.last_sent + keepalive > now {
+                    debug!("Skipping keepalive to {}, it received other messages", addr_nice(*addr));
+                    entry.next_keepalive = peer.last_sent + keepalive;
+                    continue;
+                }
+                due.push(*addr);
+            }
+        }
+        let mut msg = self.buffers.get();
+        for addr in due {
+            debug!("Sending keepalive to {}", addr_nice(addr));
+            encode_keepalive(0, TS::wall_clock(), &mut msg);
+            self.send_msg(addr, MESSAGE_TYPE_KEEPALIVE, &mut msg)?;
+            if let Some(peer) = self.peers.get_mut(&addr) {
+                peer.last_sent = now;
+            }
+        }
+        self.buffers.put(msg);
         Ok(())
     }
 
-    /// Sends the statistics to a statsd endpoint
-    fn send_stats_to_statsd(&mut self) -> Result<(), Error> {
-        if let Some(ref endpoint) = self.statsd_server {
-            let peer_traffic = self.traffic.total_peer_traffic();
-            let payload_traffic = self.traffic.total_payload_traffic();
-            let dropped = &self.traffic.dropped;
-            let prefix = self.config.statsd_prefix.as_ref().map(|s| s as &str).unwrap_or("vpncloud");
-            let msg = StatsdMsg::new()
-                .with_ns(prefix, |msg| {
-                    msg.add("peer_count", self.peers.len(), "g");
-                    msg.add("table_cache_entries", self.table.cache_len(), "g");
-                    msg.add("table_claims", self.table.claim_len(), "g");
-                    msg.with_ns("traffic", |msg| {
-                        msg.with_ns("protocol", |msg| {
-                            msg.with_ns("inbound", |msg| {
-                                msg.add("bytes", peer_traffic.in_bytes, "c");
-                                msg.add("packets", peer_traffic.in_packets, "c");
-                            });
-                            msg.with_ns("outbound", |msg| {
-                                msg.add("bytes", peer_traffic.out_bytes, "c");
-                                msg.add("packets", peer_traffic.out_packets, "c");
-                            });
-                        });
-                        msg.with_ns("payload", |msg| {
-                            msg.with_ns("inbound", |msg| {
-                                msg.add("bytes", payload_traffic.in_bytes, "c");
-                                msg.add("packets", payload_traffic.in_packets, "c");
-                            });
-                            msg.with_ns("outbound", |msg| {
-                                msg.add("bytes", payload_traffic.out_bytes, "c");
-                                msg.add("packets", payload_traffic.out_packets, "c");
-                            });
-                        });
-                    });
-                    msg.with_ns("invalid_protocol_traffic", |msg| {
-                        msg.add("bytes", dropped.in_bytes, "c");
-                        msg.add("packets", dropped.in_packets, "c");
-                    });
-                    msg.with_ns("dropped_payload", |msg| {
-                        msg.add("bytes", dropped.out_bytes, "c");
-                        msg.add("packets", dropped.out_packets, "c");
-                    });
-                })
-                .build();
-            let msg_data = msg.as_bytes();
-            let addrs = resolve(endpoint)?;
-            if let Some(addr) = addrs.first() {
-                match self.socket.send(msg_data, *addr) {
-                    Ok(written) if written == msg_data.len() => Ok(()),
-                    Ok(_) => Err(Error::Socket("Sent out truncated packet")),
-                    Err(e) => Err(Error::SocketIo("IOError when sending", e)),
-                }?
+    /// Sends the node info to all peers soon
+    ///
+    /// When keepalives are suppressed, the node info is held back for a moment so that multiple changes are sent
+    /// in one message.
+    fn schedule_node_info(&mut self) {
+        let delay = if self.config.suppress_keepalives { NODE_INFO_COALESCE_DELAY } else { 0 };
+        self.node_info_changed = true;
+        self.next_peers = min(self.next_peers, TS::now() + delay);
+    }
+
+    /// Sends the node info to the peers that are due
+    ///
+    /// Normally, all peers get the node info in every update interval. When keepalives are suppressed, peers that
+    /// accept data as sign of life are skipped as long as they got other messages within the interval, unless the
+    /// node info changed or their claim timeout requires a refresh.
+    fn send_node_infos(&mut self) -> Result<(), Error> {
+        let now = TS::now();
+        let round = self.next_peers <= now;
+        let suppress = self.config.suppress_keepalives && !self.node_info_changed;
+        if !round && !suppress {
+            return Ok(());
+        }
+        let min_peer_timeout = self.peers.iter().map(|p| p.1.peer_timeout).min().unwrap_or(DEFAULT_PEER_TIMEOUT);
+        let interval = Time::from(min(self.update_freq, max(min_peer_timeout / 2 - 60, 1)));
+        let due: SmallVec<[SocketAddr; 16]> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| {
+                if !suppress || !peer.data_keepalive {
+                    return round;
+                }
+                let refresh = Time::from(max((peer.peer_timeout / 2).saturating_sub(60), 1));
+                peer.last_sent + interval <= now || peer.last_info + refresh <= now
+            })
+            .map(|(addr, _)| *addr)
+            .collect();
+        if !due.is_empty() {
+            debug!("Send peer list to {} of {} peers", due.len(), self.peers.len());
+            let mut buffer = self.buffers.get();
+            if self.claim_filters.as_ref().map(|f| f.has_exports()).unwrap_or(false)
+                || self.peers.values().any(|p| p.gossip)
+            {
+                // Peers get individually filtered node infos and incremental peer lists
+                for addr in &due {
+                    buffer.clear();
+                    let info = self.create_node_info(Some(*addr));
+                    info.encode(&mut buffer);
+                    self.send_msg(*addr, MESSAGE_TYPE_NODE_INFO, &mut buffer)?;
+                    if info.gossip.map(|g| g.base == 0).unwrap_or(false) {
+                        if let Some(peer) = self.peers.get_mut(addr) {
+                            peer.next_full_peers = now + FULL_PEER_LIST_INTERVAL;
+                        }
+                    }
+                }
+            } else if due.len() == self.peers.len() {
+                self.create_node_info(None).encode(&mut buffer);
+                self.broadcast_msg(MESSAGE_TYPE_NODE_INFO, &mut buffer)?;
             } else {
-                error!("Failed to resolve statsd server {}", endpoint);
+                let info = self.create_node_info(None);
+                for addr in &due {
+                    buffer.clear();
+                    info.encode(&mut buffer);
+                    self.send_msg(*addr, MESSAGE_TYPE_NODE_INFO, &mut buffer)?;
+                }
+            }
+            self.buffers.put(buffer);
+            for addr in &due {
+                if let Some(peer) = self.peers.get_mut(addr) {
+                    peer.last_sent = now;
+                    peer.last_info = now;
+                }
             }
         }
+        if round {
+            // Reschedule for next update
+            self.next_peers = now + interval;
+            self.node_info_changed = false;
+        }
         Ok(())
     }
 
+    fn housekeep(&mut self) -> Result<(), Error> {
+        let now = TS::now();
+        // The time source includes suspended time, so a resume shows up as a gap between housekeepings
+        if now - self.last_housekeep > RESUME_DETECTION_GAP && !self.idle {
+            self.handle_resume(now - self.last_housekeep)?;
+        }
+        self.last_housekeep = now;
+        if let Some(idle_timeout) = self.config.idle_timeout {
+            if mem::take(&mut self.payload_seen) {
+                self.last_payload = now;
+            }
+            if !self.idle && self.last_payload + Time::from(idle_timeout) <= now {
+                self.go_idle();
+            }
+        }
+        let mut del: SmallVec<[SocketAddr; 3]> = SmallVec::new();
+        for (&addr, data) in &mut self.peers {
+            // Any message from the peer is a sign of life, any message to it keeps the path open
+            if data.received_data {
+                data.received_data = false;
+                data.last_seen = now;
+                data.timeout = max(data.timeout, now + self.config.peer_timeout as Time);
+            }
+            if data.sent_data {
+                data.sent_data = false;
+                data.last_sent = now;
+            }
+            if data.timeout < now {
+                del.push(addr);
+            }
+        }
+        for addr in del {
+            info!("Forgot peer {} due to timeout", self.peer_nice(addr));
+            self.peers.remove(&addr);
+            self.peer_states.close(addr);
+            if let Some(ref mut radius) = self.radius {
+                radius.stop(&addr, TerminateCause::IdleTimeout);
+            }
+            self.table.remove_claims(addr);
+            self.connect_sock(addr)?; // Try to reconnect
+        }
+        // Peers that are gone are not announced anymore
+        let peers = &self.peers;
+        self.peer_log.retain(|(_, addr)| peers.contains_key(addr));
+        self.table.housekeep();
+        self.quality.housekeep();
+        self.peer_states.housekeep();
+        self.local_probes.retain(|_, next| *next > now);
+        self.migrations.retain(|_, (_, timeout)| *timeout > now);
+        self.evicted.retain(|_, until| *until > now);
+        self.migration_checks = 0;
+        let early_count = self.early_data.len();
+        self.early_data.retain(|(time, _)| *time + EARLY_DATA_TIMEOUT > now);
+        if self.early_data.len() < early_count {
+            debug!("Dropped {} packets that waited for a handshake", early_count - self.early_data.len());
+        }
+        self.firewall.housekeep();
+        if let Some(ref mut budget) = self.budget {
+            budget.housekeep();
+        }
+        if let Some(ref mut control_limit) = self.control_limit {
+            control_limit.housekeep();
+        }
+        if let Some(ref mut bans) = self.bans {
+            bans.housekeep();
+        }
+        if let Some(ref mut radius) = self.radius {
+            radius.housekeep();
+        }
+        if let Some(ref mut auth_hook) = self.auth_hook {
+            auth_hook.housekeep();
+        }
+        if let Some(ref mut arp_table) = self.arp_table {
+            arp_table.housekeep();
+        }
+        if let Some(ref mut dhcp) = self.dhcp {
+            dhcp.housekeep();
+        }
+        self.ipam_housekeep()?;
+        self.handle.peers.store(self.peers.len(), Ordering::SeqCst);
+        self.crypto_housekeep()?;
+        self.close_outside_access_windows();
+        self.handle_auth_decisions()?;
+        self.handle_control_requests();
+        // Periodically extend the port-forwarding
+        if let Some(ref mut pfw) = self.port_forwarding {
+            pfw.check_extend();
+        }
+        self.activate_deferred_claims();
+        let now = TS::now();
+        if !self.idle {
+            // Periodically send peer list to peers, this also serves as keepalive
+            self.send_node_infos()?;
+            self.dial_queued();
+            self.reconnect_to_peers()?;
+            self.send_peer_keepalives()?;
+            self.flush_fec_groups()?;
+            if self.config.fast_failover {
+                self.probe_unanswered_peers()?;
+            }
+            self.avoid_demoted_paths()?;
+        }
+        if self.next_stats_out < now {
+            // Write out the statistics
+            self.write_out_stats();
+            self.save_state().map_err(|err| Error::FileIo("Failed to save state", err))?;
+            self.next_stats_out = now + STATS_INTERVAL;
+            self.traffic.period(Some(5));
+        }
+        if let Some((peers, hints)) = self.beacon_serializer.get_cmd_results() {
+            self.beacon_loaded(peers, hints)?;
+        }
+        if self.next_beacon <= now {
+            if !self.idle {
+                self.store_beacon()?;
+            }
+            self.load_beacon()?;
+            self.next_beacon = now + Time::from(self.config.beacon_interval);
+        }
+        // Periodically reset own peers
+        if self.next_own_address_reset <= now {
+            self.reset_own_addresses().map_err(|err| Error::SocketIo("Failed to get own addresses", err))?;
+            self.next_own_address_reset = now + OWN_ADDRESS_RESET_INTERVAL;
+        }
+        self.chaos_housekeep()?;
+        self.capture_housekeep();
+        Ok(())
+    }
+
+    /// Follows a captured peer that was given by name and writes out the captured packets
+    fn capture_housekeep(&mut self) {
+        let capture = match self.capture {
+            Some(ref mut capture) => capture,
+            None => return,
+        };
+        if let Some(name) = capture.peer_name() {
+            let addr = self.peers.iter().find(|(_, peer)| peer.name.as_deref() == Some(name)).map(|(addr, _)| *addr);
+            capture.set_target(addr);
+        }
+        capture.housekeep();
+    }
+
+    /// Probes peers that did not answer recently and declares them down if the probes stay unanswered
+    ///
+    /// This detects dead peers within a few seconds instead of waiting for the peer timeout. Only peers that
+    /// answer probes are checked, as others would be declared down even if they are alive.
+    fn probe_unanswered_peers(&mut self) -> Result<(), Error> {
+        let now = TS::now();
+        let mut probe: SmallVec<[SocketAddr; 3]> = SmallVec::new();
+        let mut down: SmallVec<[(SocketAddr, AddrList); 3]> = SmallVec::new();
+        for (&addr, peer) in &self.peers {
+            match peer.unanswered_since {
+                Some(since) if peer.probes && since + FAST_FAILOVER_TIMEOUT <= now => {
+                    down.push((addr, peer.addrs.clone()))
+                }
+                Some(since) if peer.probes && since + FAST_FAILOVER_PROBE_DELAY <= now => probe.push(addr),
+                None => self.peer_states.change_from(addr, PeerState::Degraded, PeerState::Established),
+                _ => (),
+            }
+        }
+        let mut msg = self.buffers.get();
+        for addr in probe {
+            debug!("Probing unresponsive peer {}", addr_nice(addr));
+            self.peer_states.change(addr, PeerState::Degraded);
+            for _ in 0..FAST_FAILOVER_PROBES {
+                encode_keepalive(KEEPALIVE_PROBE, TS::wall_clock(), &mut msg);
+                self.send_msg(addr, MESSAGE_TYPE_KEEPALIVE, &mut msg)?;
+            }
+        }
+        self.buffers.put(msg);
+        for (addr, addrs) in down {
+            warn!("Peer {} did not answer probes, failing over", self.peer_nice(addr));
+            self.remove_peer(addr);
+            self.connect_learned(&addrs)?;
+        }
+        Ok(())
+    }
+
+    /// Recovers quickly after the system resumed from suspend
+    ///
+    /// The peers have likely forgotten this node and NAT mappings have expired, so instead of waiting for all
+    /// timeouts, all peers are contacted anew, the port forwarding is set up again and the own addresses are
+    /// refreshed.
+    fn handle_resume(&mut self, gap: Time) -> Result<(), Error> {
+        info!("Detected a gap of {} seconds, assuming the system resumed from suspend", gap);
+        for addr in self.pending_inits.keys() {
+            self.peer_states.close(*addr);
+        }
+        self.pending_inits.clear();
+        let peers: SmallVec<[SocketAddr; 16]> = self.peers.keys().copied().collect();
+        for addr in &peers {
+            self.remove_peer(*addr);
+        }
+        self.rejoin(&peers)
+    }
+
+    /// Contacts the given peers and all configured peers anew with fresh port forwarding and own addresses
+    fn rejoin(&mut self, peers: &[SocketAddr]) -> Result<(), Error> {
+        let now = TS::now();
+        if let Some(pfw) = self.port_forwarding.take() {
+            self.port_forwarding = pfw.renew();
+        }
+        if let Err(err) = self.reset_own_addresses() {
+            error!("Failed to obtain local addresses: {}", err)
+        }
+        self.next_own_address_reset = now + OWN_ADDRESS_RESET_INTERVAL;
+        for entry in &mut self.reconnect_peers {
+            entry.tries = 0;
+            entry.attempts = 0;
+            entry.timeout = 1;
+            entry.next = now;
+        }
+        for addr in peers {
+            self.connect_sock(*addr)?;
+        }
+        self.schedule_node_info();
+        self.next_beacon = now;
+        Ok(())
+    }
+
+    /// Stops all traffic to the peers when no payload was exchanged within the idle timeout
+    ///
+    /// The peers are told that the connection is closed, so they do not probe the node, and the NAT mappings are
+    /// left to lapse. The node stays idle until payload from the device, a changed beacon, the `wake` control
+    /// command or SIGUSR2 wakes it up.
+    fn go_idle(&mut self) {
+        info!("No payload for {} seconds, going idle", TS::now() - self.last_payload);
+        let peers: AddrList = self.peers.keys().copied().collect();
+        let mut msg = self.buffers.get();
+        for addr in &peers {
+            msg.clear();
+            self.send_msg(*addr, MESSAGE_TYPE_CLOSE, &mut msg).ok();
+            self.remove_peer(*addr);
+        }
+        self.buffers.put(msg);
+        for addr in self.pending_inits.keys() {
+            self.peer_states.close(*addr);
+        }
+        self.pending_inits.clear();
+        self.idle_peers = peers;
+        self.idle = true;
+    }
+
+    /// Rejoins the network if the node is idle, see `go_idle`
+    pub fn wake(&mut self) -> Result<(), Error> {
+        if !self.idle {
+            return Ok(());
+        }
+        info!("Waking up, contacting the peers again");
+        self.idle = false;
+        self.last_payload = TS::now();
+        let peers = mem::take(&mut self.idle_peers);
+        self.rejoin(&peers)
+    }
+
+    /// Tries to reach peers on newly demoted paths via their other addresses
+    ///
+    /// Once one of those connections is established, `prefer_better_path` closes the demoted one.
+    fn avoid_demoted_paths(&mut self) -> Result<(), Error> {
+        for addr in self.quality.take_demoted() {
+            let alternatives: SmallVec<[SocketAddr; 4]> = match self.peers.get(&addr) {
+                Some(peer) => {
+                    peer.addrs.iter().copied().filter(|a| *a != addr && !self.quality.is_demoted(a)).collect()
+                }
+                None => continue,
+            };
+            if !alternatives.is_empty() {
+                info!("Trying {} other paths to peer {}", alternatives.len(), addr_nice(addr));
+            }
+            for alt in alternatives {
+                self.connect_sock(alt)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Closes the worse connection if the node of the given peer is connected twice
+    fn prefer_better_path(&mut self, addr: SocketAddr) {
+        let node_id = match self.peers.get(&addr) {
+            Some(peer) => peer.node_id,
+            None => return,
+        };
+        let other = match self.peers.iter().find(|(a, p)| **a != addr && p.node_id == node_id) {
+            Some((other, _)) => *other,
+            None => return,
+        };
+        if let Some((old, _)) = self.migrations.remove(&addr) {
+            if old == other {
+                info!("Peer moved from {} to {}", addr_nice(other), addr_nice(addr));
+                self.migrations_accepted += 1;
+                if let Some(peer) = self.peers.get_mut(&addr) {
+                    peer.next_migration = TS::now() + MIGRATION_COOLDOWN;
+                }
+                self.remove_peer(other);
+                return;
+            }
+        }
+        // Direct paths on the LAN are preferred over paths via the WAN
+        let better_local = is_local_addr(&addr) && !is_local_addr(&other);
+        let worse_local = is_local_addr(&other) && !is_local_addr(&addr);
+        if self.quality.is_demoted(&addr) || (worse_local && !self.quality.is_demoted(&other)) {
+            self.remove_peer(addr);
+        } else if better_local
+            || self.quality.is_demoted(&other)
+            || self.quality.score(&other) < self.quality.score(&addr)
+        {
+            info!("Switching path to node from {} to {}", addr_nice(other), addr_nice(addr));
+            self.remove_peer(other);
+        }
+    }
+
+    /// Probes the LAN addresses of a peer that is connected via the WAN
+    ///
+    /// If both nodes are on the same LAN, the probe succeeds and `prefer_better_path` switches to the local path.
+    fn probe_local_paths(&mut self, addr: SocketAddr) -> Result<(), Error> {
+        if is_local_addr(&addr) || !self.own_addresses.iter().any(is_local_addr) {
+            return Ok(());
+        }
+        let now = TS::now();
+        let own_addresses = &self.own_addresses;
+        let candidates: SmallVec<[SocketAddr; 4]> = match self.peers.get(&addr) {
+            Some(peer) => {
+                peer.addrs.iter().copied().filter(|a| is_local_addr(a) && !own_addresses.contains(a)).collect()
+            }
+            None => return Ok(()),
+        };
+        for candidate in candidates {
+            if self.local_probes.get(&candidate).map(|next| *next > now).unwrap_or(false) {
+                continue;
+            }
+            debug!("Probing local path {} to peer {}", addr_nice(candidate), addr_nice(addr));
+            self.local_probes.insert(candidate, now + LOCAL_PROBE_INTERVAL);
+            self.connect_sock(candidate)?;
+        }
+        Ok(())
+    }
+
+    /// Injects faults and checks invariants in chaos mode
+    fn chaos_housekeep(&mut self) -> Result<(), Error> {
+        let chaos = match self.chaos {
+            Some(ref mut chaos) => chaos,
+            None => return Ok(()),
+        };
+        chaos.heartbeat();
+        // Check invariants before injecting new faults
+        for addr in self.table.peers() {
+            if !self.peers.contains_key(&addr) {
+                chaos.violation(&format!("table references unknown peer {}", addr_nice(addr)));
+            }
+        }
+        for addr in &self.own_addresses {
+            if self.peers.contains_key(addr) {
+                chaos.violation(&format!("connected to own address {}", addr_nice(*addr)));
+            }
+        }
+        let mut drop: SmallVec<[SocketAddr; 4]> = smallvec![];
+        for (addr, peer) in &mut self.peers {
+            if chaos.drop_peer(*addr) {
+                drop.push(*addr);
+            } else if chaos.rekey(*addr) {
+                peer.crypto.force_rotation();
+            }
+        }
+        for (addr, data) in chaos.take_delayed() {
+            self.traffic.count_out_traffic(addr, data.len());
+            if let Some(ref mut capture) = self.capture {
+                capture.outgoing(addr, &data);
+            }
+            self.socket.send(&data, addr).map_err(|e| Error::SocketIo("IOError when sending", e))?;
+        }
+        for addr in drop {
+            self.remove_peer(addr);
+            self.connect_sock(addr)?;
+        }
+        Ok(())
+    }
+
+    /// Contacts the peers that were connected when the state was saved last
+    fn connect_to_saved_peers(&mut self) {
+        let peers = match self.state.as_ref().map(|s| s.load_peers()) {
+            Some(Ok(peers)) => peers,
+            Some(Err(err)) => {
+                warn!("Failed to load saved peers: {}", err);
+                return;
+            }
+            None => return,
+        };
+        info!("Contacting {} saved peers", peers.len());
+        for peer in peers {
+            // Saved peers were reachable recently, so they are dialed before other peers
+            self.dials.answered(peer);
+            if let Err(err) = self.connect_learned(&[peer]) {
+                debug!("Failed to contact saved peer {}: {}", addr_nice(peer), err);
+            }
+        }
+    }
+
+    /// Adds the peers that have been persisted via the control socket
+    fn connect_to_manual_peers(&mut self) {
+        let peers = match self.state.as_ref().map(|s| s.load_manual_peers()) {
+            Some(Ok(peers)) => peers,
+            Some(Err(err)) => {
+                warn!("Failed to load manual peers: {}", err);
+                return;
+            }
+            None => return,
+        };
+        for peer in peers {
+            self.add_peer_config(PeerConfig::new(peer));
+        }
+    }
+
+    fn handle_control_requests(&mut self) {
+        while let Some(request) = self.control.as_ref().and_then(|c| c.next_request()) {
+            let result = self.handle_control_command(&request.command);
+            if let Err(ref err) = result {
+                warn!("Control command '{}' failed: {}", request.command, err);
+            }
+            request.reply(result);
+        }
+    }
+
+    /// Executes a command received via the control socket
+    ///
+    /// Returns the output of commands that query the instance.
+    pub fn handle_control_command(&mut self, command: &ControlCommand) -> Result<String, Error> {
+        match command {
+            ControlCommand::Connect { address, persist } => {
+                if *persist {
+                    self.update_manual_peers(address, true)?;
+                }
+                if !self.reconnect_peers.iter().any(|e| e.address.as_ref().map(|(a, _)| a == address).unwrap_or(false))
+                {
+                    self.add_peer_config(PeerConfig::new(address.clone()));
+                }
+                self.connect(address as &str)?;
+                Ok(String::new())
+            }
+            ControlCommand::Disconnect { address, persist } => {
+                if *persist {
+                    self.update_manual_peers(address, false)?;
+                }
+                let addrs: AddrList = resolve(address as &str)?.into_iter().map(mapped_addr).collect();
+                self.dials.remove(&addrs);
+                // Stop reconnecting to the peer
+                self.reconnect_peers.retain(|e| {
+                    e.address.as_ref().map(|(a, _)| a != address).unwrap_or(true)
+                        && !e.resolved.iter().any(|a| addrs.contains(a))
+                });
+                for addr in &addrs {
+                    if self.pending_inits.remove(addr).is_some() {
+                        self.peer_states.close(*addr);
+                    }
+                }
+                let peers: SmallVec<[SocketAddr; 3]> = self
+                    .peers
+                    .iter()
+                    .filter(|(addr, peer)| addrs.contains(addr) || peer.addrs.iter().any(|a| addrs.contains(a)))
+                    .map(|(addr, _)| *addr)
+                    .collect();
+                let mut msg = self.buffers.get();
+                for addr in peers {
+                    msg.clear();
+                    self.send_msg(addr, MESSAGE_TYPE_CLOSE, &mut msg).ok();
+                    self.remove_peer(addr);
+                }
+                self.buffers.put(msg);
+                Ok(String::new())
+            }
+            ControlCommand::Peers => {
+                let mut output = vec![];
+                self.peer_states.write_out(&mut output).map_err(|e| Error::SocketIo("Failed to list peers", e))?;
+                Ok(String::from_utf8_lossy(&output).into_owned())
+            }
+            ControlCommand::Stats => Ok(self.stats_snapshot().report),
+            ControlCommand::Wake => {
+                self.wake()?;
+                Ok(String::new())
+            }
+            ControlCommand::Bans => {
+                let bans = self.bans.as_ref().ok_or(Error::InvalidConfig("Bans are not enabled"))?;
+                let mut output = vec![];
+                bans.write_list(&mut output).map_err(|e| Error::SocketIo("Failed to list bans", e))?;
+                Ok(String::from_utf8_lossy(&output).into_owned())
+            }
+            ControlCommand::Unban { address } => {
+                let bans = self.bans.as_mut().ok_or(Error::InvalidConfig("Bans are not enabled"))?;
+                let ip = if address == "all" {
+                    None
+                } else {
+                    let ip = address.parse::<IpAddr>().map_err(|_| Error::Parse("Invalid IP address"))?;
+                    Some(mapped_addr(SocketAddr::new(ip, 0)).ip())
+                };
+                if bans.clear(ip) == 0 && ip.is_some() {
+                    return Err(Error::Message("Address is not banned"));
+                }
+                Ok(String::new())
+            }
+        }
+    }
+
+    /// Adds or removes a peer from the peers that are contacted on startup
+    fn update_manual_peers(&mut self, address: &str, add: bool) -> Result<(), Error> {
+        let state = self.state.as_ref().ok_or(Error::InvalidConfig("Persisting peers needs a state directory"))?;
+        let mut peers = state.load_manual_peers().map_err(|e| Error::FileIo("Failed to load manual peers", e))?;
+        peers.retain(|p| p != address);
+        if add {
+            peers.push(address.to_string());
+        }
+        state.save_manual_peers(&peers).map_err(|e| Error::FileIo("Failed to save manual peers", e))
+    }
+
+    /// Persists the peers, the beacon and the counters in the state directory
+    fn save_state(&mut self) -> Result<(), io::Error> {
+        if let Some(ref state) = self.state {
+            debug!("Saving state to {}", state.path().display());
+            let peers: Vec<SocketAddr> = self.peers.keys().copied().collect();
+            state.save_peers(&peers)?;
+            let addrs: SmallVec<[SocketAddr; 3]> =
+                self.advertised_addresses().choose_multiple(&mut thread_rng(), 3).cloned().collect();
+            state.write(BEACON_FILE, self.beacon_serializer.encode(&addrs).as_bytes())?;
+            if let Some(ref budget) = self.budget {
+                state.write(BUDGET_FILE, budget.save().as_bytes())?;
+            }
+            state.write(TRAFFIC_FILE, self.traffic.save().as_bytes())?;
+            if self.peer_names.changed() {
+                state.write(NAMES_FILE, self.peer_names.save().as_bytes())?;
+            }
+            if self.learning {
+                state.write(MAC_TABLE_FILE, self.table.save().as_bytes())?;
+            }
+            if let Some(ref pool) = self.ipam_pool {
+                state.write(IPAM_LEASES_FILE, pool.save().as_bytes())?;
+            }
+            if let Some(ref client) = self.ipam_client {
+                state.write(IPAM_LEASE_FILE, client.save().as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores the beacon
+    fn store_beacon(&mut self) -> Result<(), Error> {
+        if let Some(ref path) = self.config.beacon_store {
+            let peers: SmallVec<[SocketAddr; 3]> =
+                self.advertised_addresses().choose_multiple(&mut thread_rng(), 3).cloned().collect();
+            if let Some(path) = path.strip_prefix('|') {
+                self.beacon_serializer
+                    .write_to_cmd(&peers, path)
+                    .map_err(|e| Error::BeaconIo("Failed to call beacon command", e))?;
+            } else {
+                self.beacon_serializer
+                    .write_to_file(&peers, &path)
+                    .map_err(|e| Error::BeaconIo("Failed to write beacon to file", e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the beacon
+    fn load_beacon(&mut self) -> Result<(), Error> {
+        let loaded;
+        if let Some(ref path) = self.config.beacon_load {
+            if let Some(path) = path.strip_prefix('|') {
+                self.beacon_serializer
+                    .read_from_cmd(path, Some(50))
+                    .map_err(|e| Error::BeaconIo("Failed to call beacon command", e))?;
+                return Ok(());
+            } else {
+                loaded = self
+                    .beacon_serializer
+                    .read_from_file(&path, Some(50))
+                    .map_err(|e| Error::BeaconIo("Failed to read beacon from file", e))?;
+            }
+        } else {
+            return Ok(());
+        }
+        self.beacon_loaded(loaded.0, loaded.1)
+    }
+
+    /// Connects to the peers of a loaded beacon, an idle node only wakes up if the beacon changed
+    ///
+    /// Beacons whose hints announce a protocol version this node can not speak are ignored instead of probing the
+    /// peers with handshakes that would be rejected anyway.
+    fn beacon_loaded(&mut self, mut peers: Vec<SocketAddr>, hints: Option<BeaconHints>) -> Result<(), Error> {
+        debug!("Loaded beacon with peers: {:?}, hints: {:?}", peers, hints);
+        if let Some(version) = hints.and_then(|hints| hints.version) {
+            if version < MIN_PROTOCOL_VERSION {
+                warn!("Ignoring beacon of incompatible protocol version {}", version);
+                return Ok(());
+            }
+        }
+        peers.sort_unstable();
+        let changed = peers != self.last_beacon;
+        self.last_beacon = peers.clone();
+        if self.idle {
+            if !changed {
+                return Ok(());
+            }
+            info!("Beacon changed, waking up");
+            self.wake()?;
+        }
+        for peer in peers {
+            self.connect_sock(peer)?;
+        }
+        Ok(())
+    }
+
+    /// Hands the statistics to all configured sinks
+    fn write_out_stats(&mut self) {
+        if self.stats_sinks.is_empty() {
+            return;
+        }
+        debug!("Writing out stats");
+        let snapshot = self.stats_snapshot();
+        let mut errors = vec![];
+        for sink in &mut self.stats_sinks {
+            if let Err(err) = sink.write(&snapshot) {
+                errors.push(err)
+            }
+        }
+        for err in errors {
+            self.report_error(&err)
+        }
+    }
+
+    fn stats_snapshot(&self) -> Snapshot {
+        let mut report = vec![];
+        // Writing into memory does not fail
+        self.write_stats(&mut report).ok();
+        let report = String::from_utf8_lossy(&report).into_owned();
+        Snapshot { time: TS::wall_clock(), report, metrics: self.stats_metrics() }
+    }
+
+    fn write_stats<W: Write>(&self, f: &mut W) -> Result<(), io::Error> {
+        writeln!(f, "time: {:?}", self.timestamps.format(TS::wall_clock()))?;
+        writeln!(f, "peers:")?;
+        let now = TS::now();
+        for (addr, data) in &self.peers {
+            writeln!(
+                f,
+                "  - \"{}\": {{ name: {:?}, state: {}, ttl_secs: {}, crypto: {}, score: {:.2}, services: {:?}, \
+                 clock_skew: {}, source_violations: {}, mtu: {}, version: {}, capabilities: {:#x} }}",
+                addr_nice(*addr),
+                data.name.as_deref().unwrap_or(""),
+                self.peer_states.state(addr).unwrap_or(PeerState::Established),
+                data.timeout - now,
+                data.crypto.algorithm_name(),
+                self.quality.score(addr),
+                data.services,
+                data.clock_skew.map(|skew| skew.to_string()).unwrap_or_else(|| "null".to_string()),
+                data.source_violations,
+                data.mtu.map(|mtu| mtu.to_string()).unwrap_or_else(|| "null".to_string()),
+                data.version,
+                data.capabilities
+            )?;
+        }
+        writeln!(f)?;
+        self.table.write_out(f)?;
+        writeln!(f)?;
+        self.traffic.write_out(f)?;
+        writeln!(f)?;
+        self.quality.write_out(f)?;
+        writeln!(f)?;
+        if let Some(ref budget) = self.budget {
+            budget.write_out(f)?;
+            writeln!(f)?;
+        }
+        if let Some(ref control_limit) = self.control_limit {
+            control_limit.write_out(f)?;
+            writeln!(f)?;
+        }
+        if let Some(ref bans) = self.bans {
+            bans.write_out(f)?;
+            writeln!(f)?;
+        }
+        self.crypto_stats.write_out(f)?;
+        writeln!(f)?;
+        if let Some(ref capture) = self.capture {
+            capture.write_out(f)?;
+            writeln!(f)?;
+        }
+        if let Some(ref pool) = self.ipam_pool {
+            pool.write_out(f)?;
+            writeln!(f)?;
+        }
+        if let Some(ref client) = self.ipam_client {
+            client.write_out(f)?;
+            writeln!(f)?;
+        }
+        writeln!(f, "sessions:")?;
+        writeln!(f, "  handshakes: {}", self.pending_inits.len())?;
+        writeln!(f, "  max_handshakes: {}", self.config.max_handshakes)?;
+        writeln!(f, "  peers: {}", self.peers.len())?;
+        writeln!(f, "  degraded: {}", self.peer_states.count(PeerState::Degraded))?;
+        writeln!(f, "  queued_dials: {}", self.dials.len())?;
+        writeln!(
+            f,
+            "  max_peers: {}",
+            self.config.max_peers.map(|max| max.to_string()).unwrap_or_else(|| "null".to_string())
+        )?;
+        writeln!(f, "  handshakes_evicted: {}", self.handshakes_evicted)?;
+        writeln!(f, "  peers_evicted: {}", self.peers_evicted)?;
+        writeln!(f, "  duplicate_identities: {}", self.duplicate_identities)?;
+        writeln!(f)?;
+        writeln!(f, "migrations:")?;
+        writeln!(f, "  accepted: {}", self.migrations_accepted)?;
+        writeln!(f, "  rejected: {}", self.migrations_rejected)?;
+        writeln!(f)?;
+        if self.config.duplication.is_some() {
+            writeln!(f, "duplicates:")?;
+            writeln!(f, "  sent: {}", self.duplicates_sent)?;
+            writeln!(f, "  dropped: {}", self.duplicates_dropped)?;
+            writeln!(f)?;
+        }
+        writeln!(f, "fec:")?;
+        writeln!(f, "  active_peers: {}", self.peers.values().filter(|p| p.fec_active).count())?;
+        writeln!(f, "  parities_sent: {}", self.fec_parities_sent)?;
+        writeln!(f, "  recovered: {}", self.fec_recovered)?;
+        writeln!(f)?;
+        writeln!(f, "mtu:")?;
+        writeln!(f, "  local: {}", self.device_mtu.map(|mtu| mtu.to_string()).unwrap_or_else(|| "null".to_string()))?;
+        writeln!(f, "  mismatches: {}", self.mtu_mismatches)?;
+        writeln!(f)?;
+        if self.config.reorder_window.is_some() {
+            writeln!(f, "reorder:")?;
+            writeln!(f, "  reordered: {}", self.packets_reordered)?;
+            let waiting = self.peers.values().filter(|p| p.reorder_buffer.deadline().is_some()).count();
+            writeln!(f, "  waiting_peers: {}", waiting)?;
+            writeln!(f)?;
+        }
+        writeln!(f, "errors:")?;
+        let mut errors: Vec<_> = self.error_counts.iter().collect();
+        errors.sort();
+        for (code, count) in errors {
+            writeln!(f, "  - E{}: {}", code, count)?;
+        }
+        writeln!(f)?;
+        Ok(())
+    }
+
+    /// Writes out the handshakes and sessions with the peers and the configured peers
+    fn write_sessions<W: Write>(&self, f: &mut W) -> Result<(), io::Error> {
+        let now = TS::now();
+        writeln!(f, "crypto_sessions:")?;
+        for (addr, data) in &self.peers {
+            writeln!(
+                f,
+                "  - \"{}\": {{ algorithm: {}, peer_key: {:?}, rotating: {} }}",
+                addr_nice(*addr),
+                data.crypto.algorithm_name(),
+                data.crypto.peer_key().map(|key| to_base62(key)).unwrap_or_default(),
+                data.crypto.has_init()
+            )?;
+        }
+        writeln!(f, "pending_handshakes:")?;
+        for addr in self.pending_inits.keys() {
+            writeln!(f, "  - \"{}\"", addr_nice(*addr))?;
+        }
+        writeln!(f, "reconnect_peers:")?;
+        for entry in &self.reconnect_peers {
+            writeln!(
+                f,
+                "  - {{ address: {:?}, resolved: {:?}, priority: {}, attempts: {}, next_attempt_secs: {} }}",
+                entry.address.as_ref().map(|(address, _)| address as &str).unwrap_or(""),
+                entry.resolved.iter().map(|addr| addr_nice(*addr).to_string()).collect::<Vec<_>>(),
+                entry.priority,
+                entry.attempts,
+                (entry.next - now).max(0)
+            )?;
+        }
+        writeln!(f)?;
+        Ok(())
+    }
+
+    /// Logs the full state of the node and writes out the statistics, triggered by SIGUSR1
+    fn dump_state(&mut self) {
+        let mut data = vec![];
+        if let Err(err) = self.write_stats(&mut data).and_then(|_| self.write_sessions(&mut data)) {
+            error!("Failed to dump state: {}", err);
+            return;
+        }
+        info!("Dumping state");
+        for line in String::from_utf8_lossy(&data).lines().filter(|line| !line.is_empty()) {
+            info!("{}", line)
+        }
+        self.write_out_stats();
+    }
+
+    /// Retries all configured peers that are not connected right away, triggered by SIGUSR2
+    fn reconnect_now(&mut self) -> Result<(), Error> {
+        if self.idle {
+            return self.wake();
+        }
+        info!("Reconnecting to all configured peers");
+        let now = TS::now();
+        for entry in &mut self.reconnect_peers {
+            entry.tries = 0;
+            entry.timeout = 1;
+            entry.next = now;
+            if let Some((_, ref mut next_resolve)) = entry.address {
+                *next_resolve = now;
+            }
+        }
+        self.reconnect_to_peers()
+    }
+
+    /// Handles the pending signals and returns whether the node should shut down
+    fn handle_signals(&mut self) -> bool {
+        let mut shutdown = false;
+        while let Some(event) = self.signals.next_event() {
+            match event {
+                SignalEvent::Shutdown => shutdown = true,
+                SignalEvent::DumpState => self.dump_state(),
+                SignalEvent::Reconnect => {
+                    if let Err(e) = self.reconnect_now() {
+                        self.report_error(&e)
+                    }
+                }
+            }
+        }
+        shutdown
+    }
+
+    /// Collects the metrics for the statistics sinks
+    fn stats_metrics(&self) -> Vec<Metric> {
+        let peer_traffic = self.traffic.total_peer_traffic();
+        let payload_traffic = self.traffic.total_payload_traffic();
+        let dropped = &self.traffic.dropped;
+        let filtered = &self.traffic.filtered;
+        let mut msg = Metrics::new();
+        msg.add("peer_count", self.peers.len(), MetricKind::Gauge);
+        msg.add("table_cache_entries", self.table.cache_len(), MetricKind::Gauge);
+        msg.add("table_claims", self.table.claim_len(), MetricKind::Gauge);
+        msg.add("demoted_paths", self.quality.demoted_count(), MetricKind::Gauge);
+        let source_violations = self.peers.values().map(|p| p.source_violations).sum::<usize>();
+        msg.add("source_violations", source_violations, MetricKind::Gauge);
+        msg.add("mtu_mismatches", self.mtu_mismatches, MetricKind::Gauge);
+        msg.add("duplicate_identities", self.duplicate_identities, MetricKind::Gauge);
+        msg.with_ns("sessions", |msg| {
+            msg.add("handshakes", self.pending_inits.len(), MetricKind::Gauge);
+            msg.add("degraded", self.peer_states.count(PeerState::Degraded), MetricKind::Gauge);
+            msg.add("queued_dials", self.dials.len(), MetricKind::Gauge);
+            msg.add("handshakes_evicted", self.handshakes_evicted, MetricKind::Gauge);
+            msg.add("peers_evicted", self.peers_evicted, MetricKind::Gauge);
+        });
+        msg.with_ns("migrations", |msg| {
+            msg.add("accepted", self.migrations_accepted, MetricKind::Gauge);
+            msg.add("rejected", self.migrations_rejected, MetricKind::Gauge);
+        });
+        msg.with_ns("duplicates", |msg| {
+            msg.add("sent", self.duplicates_sent, MetricKind::Gauge);
+            msg.add("dropped", self.duplicates_dropped, MetricKind::Gauge);
+        });
+        msg.with_ns("fec", |msg| {
+            msg.add("parities_sent", self.fec_parities_sent, MetricKind::Gauge);
+            msg.add("recovered", self.fec_recovered, MetricKind::Gauge);
+        });
+        msg.with_ns("reorder", |msg| {
+            msg.add("reordered", self.packets_reordered, MetricKind::Gauge);
+        });
+        if let Some(ref control_limit) = self.control_limit {
+            msg.with_ns("control_limit", |msg| {
+                msg.add("banned", control_limit.banned_count(), MetricKind::Gauge);
+                msg.add("dropped", control_limit.dropped(), MetricKind::Gauge);
+                msg.add("bans", control_limit.bans(), MetricKind::Gauge);
+            });
+        }
+        if let Some(ref bans) = self.bans {
+            msg.with_ns("bans", |msg| {
+                msg.add("banned", bans.banned_count(), MetricKind::Gauge);
+                msg.add("dropped", bans.dropped(), MetricKind::Counter);
+                msg.add("total", bans.bans(), MetricKind::Counter);
+            });
+        }
+        msg.with_ns("crypto", |msg| {
+            msg.add("handshake_failures", self.crypto_stats.handshake_failures(), MetricKind::Gauge);
+            for (algo, stats) in self.crypto_stats.algorithms() {
+                msg.with_ns(&algo.to_lowercase(), |msg| {
+                    if let Some(speed) = self.crypto_stats.speed(algo) {
+                        msg.add("speed", format!("{:.1}", speed), MetricKind::Gauge);
+                    }
+                    msg.add("handshakes", stats.handshakes, MetricKind::Gauge);
+                    msg.add("handshake_ns", stats.init.avg_nanos(), MetricKind::Gauge);
+                    msg.add("encryptions", stats.encrypt.count, MetricKind::Gauge);
+                    msg.add("encrypt_ns", stats.encrypt.avg_nanos(), MetricKind::Gauge);
+                    msg.add("decryptions", stats.decrypt.count, MetricKind::Gauge);
+                    msg.add("decrypt_failures", stats.decrypt.failures, MetricKind::Gauge);
+                    msg.add("decrypt_ns", stats.decrypt.avg_nanos(), MetricKind::Gauge);
+                });
+            }
+        });
+        if let Some(ref pool) = self.ipam_pool {
+            msg.add("ipam_leases", pool.len(), MetricKind::Gauge);
+        }
+        msg.with_ns("traffic", |msg| {
+            msg.with_ns("protocol", |msg| {
+                msg.with_ns("inbound", |msg| {
+                    msg.add("bytes", peer_traffic.in_bytes, MetricKind::Counter);
+                    msg.add("packets", peer_traffic.in_packets, MetricKind::Counter);
+                });
+                msg.with_ns("outbound", |msg| {
+                    msg.add("bytes", peer_traffic.out_bytes, MetricKind::Counter);
+                    msg.add("packets", peer_traffic.out_packets, MetricKind::Counter);
+                });
+            });
+            msg.with_ns("payload", |msg| {
+                msg.with_ns("inbound", |msg| {
+                    msg.add("bytes", payload_traffic.in_bytes, MetricKind::Counter);
+                    msg.add("packets", payload_traffic.in_packets, MetricKind::Counter);
+                });
+                msg.with_ns("outbound", |msg| {
+                    msg.add("bytes", payload_traffic.out_bytes, MetricKind::Counter);
+                    msg.add("packets", payload_traffic.out_packets, MetricKind::Counter);
+                });
+            });
+        });
+        msg.with_ns("invalid_protocol_traffic", |msg| {
+            msg.add("bytes", dropped.in_bytes, MetricKind::Counter);
+            msg.add("packets", dropped.in_packets, MetricKind::Counter);
+        });
+        msg.with_ns("dropped_payload", |msg| {
+            msg.add("bytes", dropped.out_bytes, MetricKind::Counter);
+            msg.add("packets", dropped.out_packets, MetricKind::Counter);
+        });
+        msg.with_ns("filtered_payload", |msg| {
+            msg.add("bytes", filtered.out_bytes, MetricKind::Counter);
+            msg.add("packets", filtered.out_packets, MetricKind::Counter);
+        });
+        msg.build()
+    }
+
     pub fn handle_interface_data(&mut self, data: &mut MsgBuffer) -> Result<(), Error> {
         // HOT PATH
+        if self.config.observer {
+            // COLD PATH
+            self.traffic.count_dropped_payload(data.len());
+            return Ok(());
+        }
         let (src, dst) = P::parse(data.message())?;
+        if let Some(ethertype) = P::ethertype(data.message()) {
+            if !self.ethertypes.is_empty() && !self.ethertypes.contains(&ethertype) {
+                // COLD PATH
+                debug!("Filtered frame with ethertype {:#06x} from interface", ethertype);
+                self.traffic.count_filtered_payload(data.len());
+                return Ok(());
+            }
+        }
+        if !self.firewall.allows(Direction::Out, data.message()) {
+            // COLD PATH
+            debug!("Firewall blocked packet from interface: src: {}, dst: {}", src, dst);
+            self.traffic.count_filtered_payload(data.len());
+            return Ok(());
+        }
+        if let Some(ref arp_table) = self.arp_table {
+            if arp_table.answer(data.message_mut()) {
+                // COLD PATH
+                debug!("Answered ARP request from {} locally", src);
+                return self.device.write(data);
+            }
+        }
+        if let Some(ref mut dhcp) = self.dhcp {
+            if let Some(reply) = dhcp.handle(data.message()) {
+                // COLD PATH
+                debug!("Answered DHCP request from {} locally", src);
+                data.clone_from(&reply);
+                return self.device.write(data);
+            }
+        }
         debug!("Read data from interface: src: {}, dst: {}, {} bytes", src, dst, data.len());
         self.traffic.count_out_payload(dst, src, data.len());
+        self.payload_seen = true;
         match self.table.lookup(dst) {
             Some(addr) => {
                 // HOT PATH
                 // Peer found for destination
                 debug!("Found destination for {} => {}", dst, addr);
-                self.send_msg(addr, MESSAGE_TYPE_DATA, data)?;
-                if !self.peers.contains_key(&addr) {
-                    // COLD PATH
-                    // If the peer is not actually connected, remove the entry in the table and try
-                    // to reconnect.
-                    warn!("Destination for {} not found in peers: {}", dst, addr_nice(addr));
-                    self.table.remove_claims(addr);
-                    self.connect_sock(addr)?;
+                // The peer is looked up only once for the limit check and the encryption
+                let peer = match self.peers.get_mut(&addr) {
+                    Some(peer) => peer,
+                    None => {
+                        // COLD PATH
+                        // If the peer is not actually connected, remove the entry in the table and try
+                        // to reconnect.
+                        warn!("Destination for {} not found in peers: {}", dst, addr_nice(addr));
+                        self.table.remove_claims(addr);
+                        return self.connect_sock(addr);
+                    }
+                };
+                if let Some(max) = peer.max_payload {
+                    if data.len() > max {
+                        // COLD PATH
+                        debug!("Payload of {} bytes exceeds limit of {} bytes of {}, dropping", data.len(), max, addr);
+                        self.traffic.count_dropped_payload(data.len());
+                        return Ok(());
+                    }
+                }
+                if let Some(ref mut budget) = self.budget {
+                    if !budget.allows(&addr, data.len()) {
+                        // COLD PATH
+                        debug!("Transmission budget for {} exhausted, dropping payload", addr_nice(addr));
+                        self.traffic.count_dropped_payload(data.len());
+                        return Ok(());
+                    }
+                }
+                if self.config.fast_failover && peer.unanswered_since.is_none() {
+                    peer.unanswered_since = Some(TS::now());
+                }
+                if let Some(ref nat) = self.nat {
+                    nat.translate_out(&addr, data.message_mut());
+                }
+                peer.sent_data = true;
+                let duplicate = match self.config.duplication {
+                    Some(ref duplication) if peer.duplicates => {
+                        duplication.selects(P::dscp(data.message()), data.len())
+                    }
+                    _ => false,
+                };
+                if duplicate {
+                    // The copy is sent via another address of the peer if it has one
+                    let quality = &self.quality;
+                    let path =
+                        peer.addrs.iter().copied().find(|a| *a != addr && !quality.is_demoted(a)).unwrap_or(addr);
+                    let seq = peer.duplicate_seq;
+                    peer.duplicate_seq = seq.wrapping_add(1);
+                    data.set_start(data.get_start() - SEQ_LEN);
+                    data.message_mut()[..SEQ_LEN].copy_from_slice(&seq.to_be_bytes());
+                    let mut copy = self.buffers.get();
+                    (*copy).clone_from(data.message());
+                    peer.crypto
+                        .send_message(MESSAGE_TYPE_DUPLICATE, data)
+                        .map_err(|e| e.with_peer(addr, Phase::Sending))?;
+                    peer.crypto
+                        .send_message(MESSAGE_TYPE_DUPLICATE, &mut copy)
+                        .map_err(|e| e.with_peer(addr, Phase::Sending))?;
+                    self.duplicates_sent += 1;
+                    self.send_to(addr, data).map_err(|e| e.with_peer(addr, Phase::Sending))?;
+                    let res = self.send_to(path, &mut copy);
+                    self.buffers.put(copy);
+                    return res.map_err(|e| e.with_peer(path, Phase::Sending));
+                }
+                if peer.fec_active {
+                    let group_size = self.config.fec.as_ref().map(|fec| fec.group_size).unwrap_or_default();
+                    let complete = peer.fec_encoder.add(data, group_size);
+                    peer.crypto
+                        .send_message(MESSAGE_TYPE_FEC_DATA, data)
+                        .map_err(|e| e.with_peer(addr, Phase::Sending))?;
+                    self.send_to(addr, data).map_err(|e| e.with_peer(addr, Phase::Sending))?;
+                    if complete {
+                        self.send_fec_parity(addr)?;
+                    }
+                    return Ok(());
                 }
+                let type_ = if peer.reorder {
+                    let seq = peer.reorder_seq;
+                    peer.reorder_seq = seq.wrapping_add(1);
+                    data.set_start(data.get_start() - SEQ_LEN);
+                    data.message_mut()[..SEQ_LEN].copy_from_slice(&seq.to_be_bytes());
+                    MESSAGE_TYPE_SEQ_DATA
+                } else {
+                    MESSAGE_TYPE_DATA
+                };
+                peer.crypto.send_message(type_, data).map_err(|e| e.with_peer(addr, Phase::Sending))?;
+                self.send_to(addr, data).map_err(|e| e.with_peer(addr, Phase::Sending))?;
             }
             None => {
                 // COLD PATH
                 if self.broadcast {
                     debug!("No destination for {} found, broadcasting", dst);
                     self.broadcast_msg(MESSAGE_TYPE_DATA, data)?;
+                } else if self.config.early_data
+                    && !self.pending_inits.is_empty()
+                    && self.early_data.len() < MAX_EARLY_PACKETS
+                {
+                    debug!("No destination for {} found, keeping it for pending handshakes", dst);
+                    let mut buffer = self.buffers.get();
+                    (*buffer).clone_from(data.message());
+                    self.early_data.push((TS::now(), buffer));
                 } else {
                     debug!("No destination for {} found, dropping", dst);
                     self.traffic.count_dropped_payload(data.len());
@@ -642,12 +2617,244 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
         Ok(())
     }
 
+    /// Formats the peer for log messages, with its name if known
+    fn peer_nice(&self, addr: SocketAddr) -> String {
+        match self.peers.get(&addr).and_then(|peer| peer.name.as_ref()) {
+            Some(name) => format!("{} ({})", name, addr_nice(addr)),
+            None => addr_nice(addr).to_string(),
+        }
+    }
+
+    /// Decides about a peer that completed the handshake, `reply` is the last handshake message if there is one
+    ///
+    /// Peers with incompatible protocol versions are dropped before any other check, the others are admitted, rejected
+    /// or kept until the auth hook decided about them.
+    fn handle_handshake_result(
+        &mut self, addr: SocketAddr, info: NodeInfo, reply: Option<&mut MsgBuffer>, notify: bool,
+    ) -> Result<(), Error> {
+        let protocol = info.protocol.unwrap_or_else(ProtocolInfo::legacy);
+        if !protocol.is_compatible() {
+            error!(
+                "Rejecting peer {}: protocol versions {}-{} are incompatible with {}",
+                addr_nice(addr),
+                protocol.min_version,
+                protocol.version,
+                PROTOCOL_VERSION
+            );
+            self.close_handshake(addr, CLOSE_REASON_INCOMPATIBLE_VERSION, notify);
+            return Ok(());
+        }
+        match self.authorize_peer(addr, &info) {
+            Some(true) => self.admit_peer(addr, info, reply, notify)?,
+            Some(false) => self.reject_peer(addr, notify),
+            None => self.defer_admission(addr, info, reply.as_deref(), notify),
+        }
+        Ok(())
+    }
+
+    /// Asks the auth hook whether a peer that completed the handshake is admitted
+    ///
+    /// Spokes only admit their configured peers, the hubs. Returns `None` if the auth hook has to be asked first, the
+    /// admission is then completed by `handle_auth_decisions`.
+    fn authorize_peer(&mut self, addr: SocketAddr, info: &NodeInfo) -> Option<bool> {
+        if self.config.topology == Topology::Spoke && !self.is_configured_peer(&addr) {
+            warn!("Refusing peer {} as spokes only connect to their hubs", addr_nice(addr));
+            return Some(false);
+        }
+        let crypto = &self.crypto;
+        let auth_hook = match self.auth_hook {
+            Some(ref mut auth_hook) => auth_hook,
+            None => return Some(true),
+        };
+        let key = self.pending_inits.get(&addr).and_then(|init| init.peer_key());
+        let fingerprint = key.map(|key| to_base62(key)).unwrap_or_default();
+        let name = key.and_then(|key| crypto.key_name(key)).or(info.name.as_deref());
+        let admitted = auth_hook.check(&PeerAuth { addr, key: &fingerprint, name });
+        if admitted == Some(false) {
+            warn!("Peer {} was not admitted by the auth hook", addr_nice(addr));
+        }
+        admitted
+    }
+
+    /// Keeps a peer that completed the handshake until the auth hook decided about it
+    fn defer_admission(&mut self, addr: SocketAddr, info: NodeInfo, reply: Option<&MsgBuffer>, notify: bool) {
+        debug!("Waiting for the auth hook to admit peer {}", addr_nice(addr));
+        let key = self.pending_inits.get(&addr).and_then(|init| init.peer_key());
+        let fingerprint = key.map(|key| to_base62(key)).unwrap_or_default();
+        let reply = reply.map(|data| {
+            let mut buffer = self.buffers.get();
+            (*buffer).clone_from(data.message());
+            buffer
+        });
+        self.pending_admissions.insert(addr, PendingAdmission { info, fingerprint, reply, notify });
+    }
+
+    /// Completes the admission of the peers that the auth hook decided about
+    fn handle_auth_decisions(&mut self) -> Result<(), Error> {
+        let auth_hook = match self.auth_hook {
+            Some(ref mut auth_hook) => auth_hook,
+            None => return Ok(()),
+        };
+        if !auth_hook.poll() {
+            return Ok(());
+        }
+        let decided: SmallVec<[(SocketAddr, bool); 4]> = self
+            .pending_admissions
+            .iter()
+            .filter_map(|(addr, admission)| {
+                auth_hook.decision(&admission.fingerprint).map(|admitted| (*addr, admitted))
+            })
+            .collect();
+        for (addr, admitted) in decided {
+            let admission = self.pending_admissions.remove(&addr).unwrap();
+            if !self.pending_inits.contains_key(&addr) {
+                // The handshake has been dropped meanwhile
+                continue;
+            }
+            if !admitted {
+                warn!("Peer {} was not admitted by the auth hook", addr_nice(addr));
+                self.reject_peer(addr, admission.notify);
+                continue;
+            }
+            match admission.reply {
+                Some(mut reply) => {
+                    self.admit_peer(addr, admission.info, Some(&mut reply), admission.notify)?;
+                    self.buffers.put(reply)
+                }
+                None => self.admit_peer(addr, admission.info, None, admission.notify)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a peer that passed all admission checks and sends the last handshake message if there is one
+    ///
+    /// The duplicate identity policy is applied last as it can close the connection of another node.
+    fn admit_peer(
+        &mut self, addr: SocketAddr, info: NodeInfo, reply: Option<&mut MsgBuffer>, notify: bool,
+    ) -> Result<(), Error> {
+        if !self.check_identity(addr, &info) {
+            self.reject_peer(addr, notify);
+            return Ok(());
+        }
+        self.add_new_peer(addr, info)?;
+        if let Some(reply) = reply {
+            self.attach_early_data(addr, reply);
+            self.send_to(addr, reply)?;
+            self.handle_early_data(addr)?
+        }
+        Ok(())
+    }
+
+    /// Applies the duplicate identity policy if the key of the new peer is already used by another node
+    ///
+    /// Connections of the same node via different paths have the same node id and are handled by
+    /// `prefer_better_path`. The own key is not checked as all nodes share it in networks without trusted keys.
+    fn check_identity(&mut self, addr: SocketAddr, info: &NodeInfo) -> bool {
+        let key = match self.pending_inits.get(&addr).and_then(|init| init.peer_key()) {
+            Some(key) if !self.crypto.is_own_key(key) => *key,
+            _ => return true,
+        };
+        let other = self
+            .peers
+            .iter()
+            .find(|(a, p)| **a != addr && p.node_id != info.node_id && p.crypto.peer_key() == Some(&key));
+        let (other, other_id) = match other {
+            Some((other, peer)) => (*other, bytes_to_hex(&peer.node_id)),
+            None => return true,
+        };
+        self.duplicate_identities += 1;
+        let key_name = self.crypto.key_name(&key).map(String::from).unwrap_or_else(|| to_base62(&key));
+        let node_id = bytes_to_hex(&info.node_id);
+        match self.config.duplicate_identity {
+            DuplicateIdentityPolicy::RejectNewer => {
+                warn!(
+                    "Refusing node {} at {}: key {} is already used by node {} at {}",
+                    node_id,
+                    addr_nice(addr),
+                    key_name,
+                    other_id,
+                    addr_nice(other)
+                );
+                false
+            }
+            DuplicateIdentityPolicy::RejectOlder => {
+                warn!(
+                    "Closing connection to node {} at {}: key {} is now used by node {} at {}",
+                    other_id,
+                    addr_nice(other),
+                    key_name,
+                    node_id,
+                    addr_nice(addr)
+                );
+                let mut msg = self.buffers.get();
+                msg.clear();
+                self.send_msg(other, MESSAGE_TYPE_CLOSE, &mut msg).ok();
+                self.buffers.put(msg);
+                self.remove_peer(other);
+                true
+            }
+            DuplicateIdentityPolicy::Allow => {
+                warn!(
+                    "Key {} is used by two nodes: {} at {} and {} at {}",
+                    key_name,
+                    other_id,
+                    addr_nice(other),
+                    node_id,
+                    addr_nice(addr)
+                );
+                // Peers are kept per address and each connection has its own session from its handshake, so both
+                // nodes can be connected without further distinction
+                true
+            }
+        }
+    }
+
+    /// Drops a peer that was not admitted, telling it why if it already considers the connection established
+    fn reject_peer(&mut self, addr: SocketAddr, notify: bool) {
+        self.record_auth_failure(addr);
+        self.close_handshake(addr, CLOSE_REASON_UNAUTHORIZED, notify)
+    }
+
+    /// Drops a completed handshake instead of adding the peer, sending the close reason if `notify` is set
+    fn close_handshake(&mut self, addr: SocketAddr, reason: u8, notify: bool) {
+        if let Some(mut init) = self.pending_inits.remove(&addr) {
+            self.quality.handshake_failed(addr);
+            self.crypto_stats.handshake_failed();
+            self.peer_states.close(addr);
+            if notify {
+                let mut msg = self.buffers.get();
+                (*msg).clone_from(&[reason]);
+                if init.send_message(MESSAGE_TYPE_CLOSE, &mut msg).is_ok() {
+                    self.send_to(addr, &mut msg).ok();
+                }
+                self.buffers.put(msg);
+            }
+        }
+    }
+
     fn add_new_peer(&mut self, addr: SocketAddr, info: NodeInfo) -> Result<(), Error> {
-        info!("Added peer {}", addr_nice(addr));
+        // A peer that connects to an idle node wakes it up
+        self.wake()?;
+        let key = self.pending_inits.get(&addr).and_then(|init| init.peer_key());
+        let key_name = key.and_then(|key| self.crypto.key_name(key)).map(|name| name.to_string());
+        let fingerprint = key.map(|key| to_base62(key));
+        if let (Some(fingerprint), Some(name)) = (&fingerprint, &info.name) {
+            self.peer_names.learn(fingerprint, name);
+        }
+        // Names of trusted keys take precedence over the announced name and that over a remembered one
+        let name = key_name
+            .or_else(|| info.name.clone())
+            .or_else(|| fingerprint.and_then(|f| self.peer_names.get(&f).map(String::from)));
+        match name {
+            Some(ref name) => info!("Added peer {} ({})", name, addr_nice(addr)),
+            None => info!("Added peer {}", addr_nice(addr)),
+        }
         self.config.call_hook(
             "peer_connected",
             vec![
                 ("PEER", format!("{:?}", addr_nice(addr))),
+                ("PEER_NAME", name.clone().unwrap_or_default()),
                 ("IFNAME", self.device.ifname().to_owned()),
                 ("CLAIMS", info.claims.iter().map(|r| format!("{:?}", r)).collect::<Vec<String>>().join(" ")),
                 ("NODE_ID", bytes_to_hex(&info.node_id)),
@@ -655,6 +2862,14 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
             true,
         );
         if let Some(init) = self.pending_inits.remove(&addr) {
+            self.quality.handshake_succeeded(addr, self.receive_time);
+            self.dials.answered(addr);
+            let protocol = info.protocol.unwrap_or_else(ProtocolInfo::legacy);
+            let capabilities = protocol.common_capabilities();
+            if !self.peers.contains_key(&addr) {
+                self.evict_peers();
+            }
+            self.traffic.set_peer(addr, name.as_deref());
             self.peers.insert(
                 addr,
                 PeerData {
@@ -662,25 +2877,173 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
                     crypto: init,
                     node_id: info.node_id,
                     peer_timeout: info.peer_timeout.unwrap_or(DEFAULT_PEER_TIMEOUT),
+                    max_payload: info.max_payload.map(|max| max as usize),
+                    mtu: None,
+                    services: info.services.clone(),
+                    version: protocol.common_version(),
+                    capabilities,
                     last_seen: TS::now(),
                     timeout: TS::now() + self.config.peer_timeout as Time,
+                    probes: capabilities & CAPABILITY_PROBES != 0,
+                    unanswered_since: None,
+                    clock_skew: None,
+                    next_migration: TS::now(),
+                    source_violations: 0,
+                    data_keepalive: capabilities & CAPABILITY_DATA_KEEPALIVE != 0,
+                    sent_data: false,
+                    received_data: false,
+                    last_sent: TS::now(),
+                    last_info: TS::now(),
+                    gossip: capabilities & CAPABILITY_PEER_GOSSIP != 0,
+                    gossip_ack: 0,
+                    gossip_acked: 0,
+                    next_full_peers: TS::now() + FULL_PEER_LIST_INTERVAL,
+                    duplicates: capabilities & CAPABILITY_DUPLICATES != 0,
+                    duplicate_seq: 1,
+                    duplicate_filter: DuplicateFilter::default(),
+                    fec: capabilities & CAPABILITY_FEC != 0,
+                    fec_active: false,
+                    fec_encoder: FecEncoder::new(),
+                    fec_decoder: FecDecoder::default(),
+                    reorder: false,
+                    reorder_seq: 1,
+                    reorder_buffer: ReorderBuffer::new(time::Duration::from_millis(
+                        self.config.reorder_window.unwrap_or_default().into(),
+                    )),
+                    name,
+                    padding: capabilities & CAPABILITY_PADDING != 0,
                 },
             );
+            self.peer_states.change(addr, PeerState::Established);
+            debug!(
+                "Peer {} uses protocol version {} with capabilities {:#x}",
+                addr_nice(addr),
+                protocol.common_version(),
+                capabilities
+            );
+            if let Some(ref mut radius) = self.radius {
+                radius.start(addr, self.peers[&addr].name.as_deref());
+            }
+            self.log_new_peer(addr);
+            self.crypto_stats.check_algorithm(addr, self.peers[&addr].crypto.algorithm_name());
             self.update_peer_info(addr, Some(info))?;
+            if self.learning {
+                // Saved addresses are added after the claims as those invalidate the cache entries of the peer
+                let addrs = self.peers[&addr].addrs.clone();
+                let seeded = self.table.seed(addr, &addrs);
+                if seeded > 0 {
+                    debug!("Restored {} saved addresses of peer {}", seeded, addr_nice(addr));
+                }
+            }
+            self.prefer_better_path(addr);
         } else {
             error!("No init for new peer {}", addr_nice(addr));
         }
-        Ok(())
+        Ok(())
+    }
+
+    /// Evicts the least recently active pending handshakes until there is room for a new one
+    fn evict_handshakes(&mut self) {
+        while self.pending_inits.len() >= self.config.max_handshakes.max(1) {
+            // COLD PATH
+            let activity = &self.handshake_activity;
+            let oldest = self.pending_inits.keys().copied().min_by_key(|addr| activity.get(addr).copied().unwrap_or(0));
+            if let Some(addr) = oldest {
+                debug!("Too many pending handshakes, evicting the one with {}", addr_nice(addr));
+                self.pending_inits.remove(&addr);
+                self.handshake_activity.remove(&addr);
+                self.peer_states.close(addr);
+                self.handshakes_evicted += 1;
+            }
+        }
+    }
+
+    /// Whether the number of peers reached `max_peers`
+    fn is_full(&self) -> bool {
+        match self.config.max_peers {
+            Some(max_peers) => self.peers.len() >= max_peers.max(1),
+            None => false,
+        }
+    }
+
+    /// Whether the address belongs to a configured peer
+    fn is_configured_peer(&self, addr: &SocketAddr) -> bool {
+        self.reconnect_peers.iter().any(|e| e.resolved.iter().any(|a| mapped_addr(*a) == *addr))
+    }
+
+    /// Returns the least recently seen peer that can be evicted
+    ///
+    /// Configured peers are not evicted as they would be dialed again right away.
+    fn eviction_candidate(&self) -> Option<SocketAddr> {
+        self.peers
+            .iter()
+            .filter(|(addr, _)| !self.is_configured_peer(addr))
+            .min_by_key(|(_, peer)| peer.last_seen)
+            .map(|(addr, _)| *addr)
+    }
+
+    /// Whether a new peer on the address can be admitted without exceeding `max_peers`
+    fn may_admit(&self, addr: &SocketAddr) -> bool {
+        if self.evicted.get(addr).map(|until| *until > TS::now()).unwrap_or(false) {
+            return false;
+        }
+        !self.is_full() || self.peers.contains_key(addr) || self.eviction_candidate().is_some()
+    }
+
+    /// Evicts the least recently seen peers until there is room for a new one
+    fn evict_peers(&mut self) {
+        while self.is_full() {
+            // COLD PATH
+            let oldest = self
+                .eviction_candidate()
+                .or_else(|| self.peers.iter().min_by_key(|(_, peer)| peer.last_seen).map(|(addr, _)| *addr));
+            if let Some(addr) = oldest {
+                warn!("Too many peers, evicting {}", self.peer_nice(addr));
+                let mut msg = self.buffers.get();
+                msg.clear();
+                self.send_msg(addr, MESSAGE_TYPE_CLOSE, &mut msg).ok();
+                self.buffers.put(msg);
+                if let Some(ref mut radius) = self.radius {
+                    radius.stop(&addr, TerminateCause::AdminReset);
+                }
+                self.remove_peer(addr);
+                self.evicted.insert(addr, TS::now() + EVICTION_BACKOFF);
+                self.peers_evicted += 1;
+            }
+        }
     }
 
     fn remove_peer(&mut self, addr: SocketAddr) {
         if let Some(peer) = self.peers.remove(&addr) {
-            info!("Closing connection to {}", addr_nice(addr));
+            self.peer_states.close(addr);
+            match peer.name {
+                Some(ref name) => info!("Closing connection to {} ({})", name, addr_nice(addr)),
+                None => info!("Closing connection to {}", addr_nice(addr)),
+            }
             self.table.remove_claims(addr);
+            if let Some(ref mut nat) = self.nat {
+                nat.remove_peer(&addr);
+            }
+            if let Some(ref mut filters) = self.claim_filters {
+                filters.remove_peer(&addr);
+            }
+            if let Some(ref mut policy) = self.claim_policy {
+                policy.remove_peer(&addr);
+            }
+            if let Some(ref mut budget) = self.budget {
+                budget.remove_peer(&addr);
+            }
+            if let Some(ref mut radius) = self.radius {
+                radius.stop(&addr, TerminateCause::AdminReset);
+            }
+            if let Some(ref dns_records) = self.dns_records {
+                dns_records.write().expect("Lock poisoned").remove_peer(&addr);
+            }
             self.config.call_hook(
                 "peer_disconnected",
                 vec![
                     ("PEER", format!("{:?}", addr)),
+                    ("PEER_NAME", peer.name.clone().unwrap_or_default()),
                     ("IFNAME", self.device.ifname().to_owned()),
                     ("NODE_ID", bytes_to_hex(&peer.node_id)),
                 ],
@@ -690,6 +3053,10 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
     }
 
     fn connect_to_peers(&mut self, peers: &[PeerInfo]) -> Result<(), Error> {
+        if self.is_full() {
+            // Learned peers are not worth evicting a connected one
+            return Ok(());
+        }
         'outer: for peer in peers {
             for addr in &peer.addrs {
                 if self.peers.contains_key(addr) {
@@ -704,6 +3071,7 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
                             self.own_addresses.push(*addr)
                         }
                     }
+                    self.check_external_addresses();
                     continue 'outer;
                 }
                 for p in self.peers.values() {
@@ -712,16 +3080,45 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
                     }
                 }
             }
-            self.connect(&peer.addrs as &[SocketAddr])?;
+            self.connect_learned(&peer.addrs)?;
         }
         Ok(())
     }
 
     fn update_peer_info(&mut self, addr: SocketAddr, info: Option<NodeInfo>) -> Result<(), Error> {
+        let crypto = &self.crypto;
         if let Some(peer) = self.peers.get_mut(&addr) {
             peer.last_seen = TS::now();
             peer.timeout = TS::now() + self.config.peer_timeout as Time;
             if let Some(info) = &info {
+                peer.max_payload = info.max_payload.map(|max| max as usize);
+                peer.services = info.services.clone();
+                // Names of trusted keys take precedence over the announced name
+                let key = peer.crypto.peer_key();
+                if let (Some(key), Some(name)) = (key, &info.name) {
+                    self.peer_names.learn(&to_base62(key), name);
+                }
+                if let Some(name) = key.and_then(|key| crypto.key_name(key)).or(info.name.as_deref()) {
+                    peer.name = Some(name.to_string());
+                }
+                if let (Some(fec), Some(loss)) = (&self.config.fec, info.loss) {
+                    let loss = f64::from(loss) / 1000.0;
+                    let active = peer.fec && fec.is_needed(loss, peer.fec_active);
+                    if active && !peer.fec_active {
+                        info!("Protecting payload to {} with FEC due to {:.1}% loss", addr_nice(addr), loss * 100.0);
+                    } else if !active && peer.fec_active {
+                        info!("Loss to {} dropped to {:.1}%, disabling FEC", addr_nice(addr), loss * 100.0);
+                    }
+                    peer.fec_active = active;
+                }
+                peer.reorder = info.reorder_window.is_some();
+                if let Some(gossip) = info.gossip {
+                    // The peer list is complete when it is relative to a version that has been seen
+                    if gossip.base <= peer.gossip_ack {
+                        peer.gossip_ack = max(peer.gossip_ack, gossip.seq);
+                    }
+                    peer.gossip_acked = gossip.ack;
+                }
                 // Update peer addresses, always add seen address
                 peer.addrs.clear();
                 peer.addrs.push(addr);
@@ -735,21 +3132,139 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
             error!("Received peer update from non peer {}", addr_nice(addr));
             return Ok(());
         }
-        if let Some(info) = info {
+        if let Some(mut info) = info {
+            if let Some(ref mut policy) = self.claim_policy {
+                let crypto = &self.crypto;
+                let key_name = self.peers.get(&addr).and_then(|p| p.crypto.peer_key()).and_then(|k| crypto.key_name(k));
+                info.claims = policy.accept_claims(addr, key_name, &info.claims);
+            }
+            if let Some(ref mut nat) = self.nat {
+                nat.set_peer(addr, info.name.as_deref());
+                info.claims = nat.translate_claims(&addr, &info.claims);
+            }
+            if let Some(ref mut filters) = self.claim_filters {
+                filters.set_peer(addr, info.name.as_deref());
+                info.claims = filters.import_claims(&addr, &info.claims);
+            }
+            if let Some(ref mut budget) = self.budget {
+                budget.set_peer(addr, info.name.as_deref());
+            }
+            if let Some(ref dns_records) = self.dns_records {
+                let mut records = dns_records.write().expect("Lock poisoned");
+                match info.name {
+                    Some(ref name) if dns::is_valid_name(name) => records.set_peer(addr, name, &info.claims),
+                    _ => records.remove_peer(&addr),
+                }
+            }
+            self.update_clock_skew(addr, info.time);
+            self.update_mtu(addr, info.max_payload);
             debug!("Adding claims of peer {}: {:?}", addr_nice(addr), info.claims);
             self.table.set_claims(addr, info.claims);
             debug!("Received {} peers from {}: {:?}", info.peers.len(), addr_nice(addr), info.peers);
-            self.connect_to_peers(&info.peers)?;
+            if self.config.peer_exchange && self.config.topology != Topology::Spoke {
+                self.connect_to_peers(&info.peers)?;
+            }
+            self.probe_local_paths(addr)?;
         }
         Ok(())
     }
 
+    /// Records the clock difference to the peer and warns when it becomes too large
+    fn update_clock_skew(&mut self, addr: SocketAddr, time: Option<Time>) {
+        let (peer, time) = match (self.peers.get_mut(&addr), time) {
+            (Some(peer), Some(time)) => (peer, time),
+            _ => return,
+        };
+        let skew = time - TS::wall_clock();
+        let name = peer.name.as_ref().map(|name| format!("{} ({})", name, addr_nice(addr)));
+        let name = name.unwrap_or_else(|| addr_nice(addr).to_string());
+        let was_skewed = peer.clock_skew.map(|skew| skew.abs() > MAX_CLOCK_SKEW).unwrap_or(false);
+        peer.clock_skew = Some(skew);
+        if skew.abs() > MAX_CLOCK_SKEW && !was_skewed {
+            warn!(
+                "Clock of peer {} is {} seconds {} the local clock, time-limited keys may be rejected. Please \
+                 synchronize the clocks, e.g. with NTP.",
+                name,
+                skew.abs(),
+                if skew > 0 { "ahead of" } else { "behind" }
+            );
+        } else if skew.abs() <= MAX_CLOCK_SKEW && was_skewed {
+            info!("Clock of peer {} is synchronized again", name);
+        }
+    }
+
+    /// Records the MTU that follows from the maximum payload of the peer and warns when it differs from the own one
+    fn update_mtu(&mut self, addr: SocketAddr, max_payload: Option<u16>) {
+        let overhead = self.device.get_type().frame_overhead() as u16;
+        let mtu = max_payload.map(|max| max.saturating_sub(overhead));
+        let peer = match self.peers.get_mut(&addr) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let own = match self.device_mtu {
+            Some(own) => own,
+            None => {
+                peer.mtu = mtu;
+                return;
+            }
+        };
+        let was_mismatched = peer.mtu.map(|mtu| usize::from(mtu) != own).unwrap_or(false);
+        peer.mtu = mtu;
+        let mismatched = mtu.map(|mtu| usize::from(mtu) != own).unwrap_or(false);
+        let name = peer.name.as_ref().map(|name| format!("{} ({})", name, addr_nice(addr)));
+        let name = name.unwrap_or_else(|| addr_nice(addr).to_string());
+        if mismatched && !was_mismatched {
+            self.mtu_mismatches += 1;
+            warn!(
+                "Peer {} uses an MTU of {} but the local device has an MTU of {}. Larger packets will be dropped, \
+                 which often shows as hanging TCP connections while ping works. Please use the same MTU on all nodes.",
+                name,
+                mtu.unwrap_or_default(),
+                own
+            );
+        } else if !mismatched && was_mismatched {
+            info!("MTU of peer {} matches again", name);
+        }
+    }
+
     fn handle_payload_from(&mut self, peer: SocketAddr, data: &mut MsgBuffer) -> Result<(), Error> {
         // HOT PATH
+        if self.config.observer {
+            // COLD PATH
+            debug!("Dropping data from {} in observer mode", addr_nice(peer));
+            self.traffic.count_dropped_payload(data.len());
+            return Ok(());
+        }
+        if let Some(ref nat) = self.nat {
+            nat.translate_in(&peer, data.message_mut());
+        }
         let (src, dst) = P::parse(data.message())?;
         let len = data.len();
+        if self.config.source_validation && !self.learning && !self.table.is_claimed_by(src, peer) {
+            // COLD PATH
+            if let Some(data) = self.peers.get_mut(&peer) {
+                data.source_violations += 1;
+                if data.source_violations == 1 {
+                    warn!("Peer {} sent packets with source {} outside of its claims", addr_nice(peer), src);
+                }
+            }
+            debug!("Dropping packet from {} with unclaimed source {}", addr_nice(peer), src);
+            self.traffic.count_filtered_payload(len);
+            return Ok(());
+        }
+        if !self.firewall.allows(Direction::In, data.message()) {
+            // COLD PATH
+            debug!("Firewall blocked packet from {}: src: {}, dst: {}", addr_nice(peer), src, dst);
+            self.traffic.count_filtered_payload(len);
+            return Ok(());
+        }
+        let dhcp_reply = match self.dhcp {
+            Some(ref mut dhcp) => dhcp.handle(data.message()),
+            None => None,
+        };
         debug!("Writing data to device: {} bytes", len);
         self.traffic.count_in_payload(src, dst, len);
+        self.payload_seen = true;
         if let Err(e) = self.device.write(data) {
             error!("Failed to send via device: {}", e);
             return Err(e);
@@ -758,6 +3273,145 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
             // Learn single address
             self.table.cache(src, peer);
         }
+        if let Some(ref mut arp_table) = self.arp_table {
+            arp_table.learn(data.message());
+        }
+        if let Some(reply) = dhcp_reply {
+            // COLD PATH
+            debug!("Answering DHCP request from {}", addr_nice(peer));
+            let mut msg = self.buffers.get();
+            (*msg).clone_from(&reply);
+            self.send_msg(peer, MESSAGE_TYPE_DATA, &mut msg)?;
+            self.buffers.put(msg);
+        }
+        Ok(())
+    }
+
+    /// Writes the packet that was recovered from an FEC group of the peer to the device
+    fn handle_fec_recovered(&mut self, addr: SocketAddr) -> Result<(), Error> {
+        let packet = match self.peers.get_mut(&addr).and_then(|peer| peer.fec_decoder.take_recovered()) {
+            Some(packet) => packet,
+            None => return Ok(()),
+        };
+        // COLD PATH
+        debug!("Recovered lost packet from {} via FEC", addr_nice(addr));
+        self.fec_recovered += 1;
+        let mut msg = self.buffers.get();
+        (*msg).clone_from(&packet);
+        let res = self.handle_payload_from(addr, &mut msg);
+        self.buffers.put(msg);
+        res
+    }
+
+    /// Sends the parity packets of the FEC groups that are not complete, so that low traffic is protected as well
+    fn flush_fec_groups(&mut self) -> Result<(), Error> {
+        let pending: SmallVec<[SocketAddr; 4]> =
+            self.peers.iter().filter(|(_, p)| p.fec_encoder.is_pending()).map(|(a, _)| *a).collect();
+        for addr in pending {
+            self.send_fec_parity(addr)?;
+        }
+        Ok(())
+    }
+
+    /// Sends the parity packet of the current FEC group to the peer
+    fn send_fec_parity(&mut self, addr: SocketAddr) -> Result<(), Error> {
+        let mut msg = self.buffers.get();
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.fec_encoder.take_parity(&mut msg);
+        }
+        self.fec_parities_sent += 1;
+        let res = self.send_msg(addr, MESSAGE_TYPE_FEC_PARITY, &mut msg);
+        self.buffers.put(msg);
+        res
+    }
+
+    /// Writes the packets from the peer that are in order now to the device
+    fn handle_reordered(&mut self, addr: SocketAddr) -> Result<(), Error> {
+        while let Some(packet) = self.peers.get_mut(&addr).and_then(|peer| peer.reorder_buffer.pop_ready()) {
+            let mut msg = self.buffers.get();
+            (*msg).clone_from(&packet);
+            let res = self.handle_payload_from(addr, &mut msg);
+            self.buffers.put(msg);
+            res?
+        }
+        Ok(())
+    }
+
+    /// Skips the gaps in the reorder buffers that have been waited for longer than the reorder window
+    fn flush_reorder_buffers(&mut self) -> Result<(), Error> {
+        let now = Instant::now();
+        let expired: SmallVec<[SocketAddr; 4]> = self
+            .peers
+            .iter_mut()
+            .filter(|(_, p)| p.reorder_buffer.deadline().map(|d| d <= now).unwrap_or(false))
+            .map(|(a, p)| {
+                p.reorder_buffer.flush(now);
+                *a
+            })
+            .collect();
+        self.reorder_deadline = self.peers.values().filter_map(|p| p.reorder_buffer.deadline()).min();
+        for addr in expired {
+            debug!("Skipping missing packets from {}", addr_nice(addr));
+            self.handle_reordered(addr)?;
+        }
+        Ok(())
+    }
+
+    /// Attaches the waiting packets that can now be routed to the new peer to the final handshake message
+    fn attach_early_data(&mut self, addr: SocketAddr, msg: &mut MsgBuffer) {
+        if self.early_data.is_empty() {
+            return;
+        }
+        let mut packets = vec![];
+        let mut i = 0;
+        while i < self.early_data.len() {
+            let routed = match P::parse(self.early_data[i].1.message()) {
+                Ok((_, dst)) => self.table.lookup(dst) == Some(addr),
+                Err(_) => false,
+            };
+            if routed {
+                packets.push(self.early_data.remove(i).1);
+            } else {
+                i += 1;
+            }
+        }
+        if packets.is_empty() {
+            return;
+        }
+        if let Some(ref nat) = self.nat {
+            for packet in &mut packets {
+                nat.translate_out(&addr, packet.message_mut());
+            }
+        }
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            let count = peer.crypto.attach_early_data(packets.iter().map(|p| p.message()), msg);
+            debug!("Attached {} of {} waiting packets to the handshake with {}", count, packets.len(), addr_nice(addr));
+            for packet in &packets[count..] {
+                self.traffic.count_dropped_payload(packet.len());
+            }
+        }
+        for packet in packets {
+            self.buffers.put(packet);
+        }
+    }
+
+    /// Handles the packets that the peer attached to its final handshake message
+    fn handle_early_data(&mut self, addr: SocketAddr) -> Result<(), Error> {
+        let packets = match self.peers.get_mut(&addr) {
+            Some(peer) => peer.crypto.take_early_data(),
+            None => return Ok(()),
+        };
+        if packets.is_empty() {
+            return Ok(());
+        }
+        debug!("Received {} packets with the handshake from {}", packets.len(), addr_nice(addr));
+        let mut buffer = self.buffers.get();
+        for packet in packets {
+            buffer.clear();
+            (*buffer).clone_from(&packet);
+            self.handle_payload_from(addr, &mut buffer)?;
+        }
+        self.buffers.put(buffer);
         Ok(())
     }
 
@@ -773,6 +3427,86 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
                         // HOT PATH
                         self.handle_payload_from(src, data)?
                     }
+                    MESSAGE_TYPE_DUPLICATE => {
+                        // HOT PATH
+                        if data.len() < SEQ_LEN {
+                            self.traffic.count_invalid_protocol(data.len());
+                            return Err(Error::Message("Duplicated message is too short"));
+                        }
+                        let mut seq = [0; SEQ_LEN];
+                        seq.copy_from_slice(&data.message()[..SEQ_LEN]);
+                        data.set_start(data.get_start() + SEQ_LEN);
+                        let seq = u32::from_be_bytes(seq);
+                        let first = match self.peers.get_mut(&src) {
+                            Some(peer) => peer.duplicate_filter.check(seq),
+                            None => false,
+                        };
+                        if first {
+                            self.handle_payload_from(src, data)?
+                        } else {
+                            // COLD PATH
+                            self.duplicates_dropped += 1;
+                        }
+                    }
+                    MESSAGE_TYPE_SEQ_DATA => {
+                        // HOT PATH
+                        if data.len() < SEQ_LEN {
+                            self.traffic.count_invalid_protocol(data.len());
+                            return Err(Error::Message("Numbered message is too short"));
+                        }
+                        let mut seq = [0; SEQ_LEN];
+                        seq.copy_from_slice(&data.message()[..SEQ_LEN]);
+                        data.set_start(data.get_start() + SEQ_LEN);
+                        let seq = u32::from_be_bytes(seq);
+                        let in_order = match self.peers.get_mut(&src) {
+                            Some(peer) if self.config.reorder_window.is_some() => {
+                                let in_order = peer.reorder_buffer.receive(seq, data.message(), Instant::now());
+                                if let Some(deadline) = peer.reorder_buffer.deadline() {
+                                    self.reorder_deadline =
+                                        Some(self.reorder_deadline.map_or(deadline, |d| d.min(deadline)));
+                                }
+                                in_order
+                            }
+                            _ => true,
+                        };
+                        if in_order {
+                            self.handle_payload_from(src, data)?
+                        } else {
+                            // COLD PATH
+                            self.packets_reordered += 1;
+                        }
+                        self.handle_reordered(src)?
+                    }
+                    MESSAGE_TYPE_FEC_DATA => {
+                        // HOT PATH
+                        let first = match self.peers.get_mut(&src) {
+                            Some(peer) => peer.fec_decoder.receive_data(data),
+                            None => Ok(false),
+                        };
+                        match first {
+                            Ok(true) => self.handle_payload_from(src, data)?,
+                            Ok(false) => (),
+                            Err(err) => {
+                                self.traffic.count_invalid_protocol(data.len());
+                                return Err(err);
+                            }
+                        }
+                        self.handle_fec_recovered(src)?
+                    }
+                    MESSAGE_TYPE_FEC_PARITY => {
+                        // COLD PATH
+                        if let Some(peer) = self.peers.get_mut(&src) {
+                            if let Err(err) = peer.fec_decoder.receive_parity(data.message()) {
+                                self.traffic.count_invalid_protocol(data.len());
+                                return Err(err);
+                            }
+                        }
+                        self.handle_fec_recovered(src)?
+                    }
+                    _ if !self.allows_control(src) => {
+                        // COLD PATH
+                        // Control message above the rate limit, counted by the limiter
+                    }
                     MESSAGE_TYPE_NODE_INFO => {
                         // COLD PATH
                         let info = match NodeInfo::decode(Cursor::new(data.message())) {
@@ -786,10 +3520,37 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
                     }
                     MESSAGE_TYPE_KEEPALIVE => {
                         // COLD PATH
-                        self.update_peer_info(src, None)?
+                        self.update_peer_info(src, None)?;
+                        let (flags, time) = decode_keepalive(data.message());
+                        self.update_clock_skew(src, time);
+                        if flags == KEEPALIVE_PROBE {
+                            debug!("Answering probe from {}", addr_nice(src));
+                            encode_keepalive(0, TS::wall_clock(), data);
+                            self.send_msg(src, MESSAGE_TYPE_KEEPALIVE, data)?
+                        }
+                    }
+                    MESSAGE_TYPE_LEASE_REQUEST => {
+                        // COLD PATH
+                        self.handle_lease_request(src, data)?
+                    }
+                    MESSAGE_TYPE_LEASE => {
+                        // COLD PATH
+                        self.handle_lease(src, data)?
                     }
                     MESSAGE_TYPE_CLOSE => {
                         // COLD PATH
+                        match data.message().first() {
+                            Some(&CLOSE_REASON_INCOMPATIBLE_VERSION) => {
+                                error!("Peer {} rejected us due to incompatible protocol versions", addr_nice(src))
+                            }
+                            Some(&CLOSE_REASON_UNAUTHORIZED) => {
+                                error!("Peer {} rejected us because we were not admitted", addr_nice(src))
+                            }
+                            _ => (),
+                        }
+                        if let Some(ref mut radius) = self.radius {
+                            radius.stop(&src, TerminateCause::UserRequest);
+                        }
                         self.remove_peer(src)
                     }
                     _ => {
@@ -801,12 +3562,13 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
             }
             MessageResult::Initialized(info) => {
                 // COLD PATH
-                self.add_new_peer(src, info)?
+                self.handle_handshake_result(src, info, None, true)?
             }
             MessageResult::InitializedWithReply(info) => {
                 // COLD PATH
-                self.add_new_peer(src, info)?;
-                self.send_to(src, data)?
+                // Only the responder has finished its handshake, so that the peer can read the close message
+                let responder = self.pending_inits.get(&src).map(|init| !init.has_init()).unwrap_or(false);
+                self.handle_handshake_result(src, info, Some(data), responder)?
             }
             MessageResult::Reply => {
                 // COLD PATH
@@ -823,8 +3585,25 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
         // HOT PATH
         let src = mapped_addr(src);
         debug!("Received {} bytes from {}", data.len(), src);
+        if let Some(ref mut capture) = self.capture {
+            // COLD PATH
+            capture.incoming(src, data.message());
+        }
+        if self.control_limit.is_some()
+            && (is_init_message(data.message()) || !self.peers.contains_key(&src))
+            && !self.allows_control(src)
+        {
+            // COLD PATH
+            // Handshakes and messages from unknown addresses are limited before any processing
+            return Ok(());
+        }
         let msg_result = if let Some(init) = self.pending_inits.get_mut(&src) {
             // COLD PATH
+            if self.pending_admissions.contains_key(&src) {
+                // Peers that wait for the auth hook are not admitted yet
+                return Ok(());
+            }
+            self.handshake_activity.insert(src, TS::now());
             init.handle_message(data)
         } else if is_init_message(data.message()) {
             // COLD PATH
@@ -837,7 +3616,16 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
             if let Some(result) = result {
                 result
             } else {
-                let mut init = self.crypto.peer_instance(self.create_node_info());
+                if self.config.topology == Topology::Spoke && !self.is_configured_peer(&src) {
+                    // Not answering at all keeps the other node from considering the handshake successful
+                    debug!("Ignoring handshake from {} as spokes only connect to their hubs", addr_nice(src));
+                    return Ok(());
+                }
+                if !self.may_admit(&src) {
+                    debug!("Ignoring handshake from {} as the peer limit is reached", addr_nice(src));
+                    return Ok(());
+                }
+                let mut init = self.crypto.peer_instance(self.create_node_info(Some(src)));
                 let msg_result = init.handle_message(data);
                 match msg_result {
                     Ok(res) => {
@@ -849,7 +3637,11 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
                             ],
                             true,
                         );
+                        self.evict_handshakes();
                         self.pending_inits.insert(src, init);
+                        self.handshake_activity.insert(src, TS::now());
+                        self.quality.handshake_started(src);
+                        self.peer_states.change(src, PeerState::HandshakePending);
                         Ok(res)
                     }
                     Err(err) => {
@@ -860,11 +3652,18 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
             }
         } else if let Some(peer) = self.peers.get_mut(&src) {
             // HOT PATH
-            peer.crypto.handle_message(data)
+            let result = peer.crypto.handle_message(data);
+            if result.is_ok() {
+                peer.unanswered_since = None;
+                peer.received_data = true;
+            }
+            result
         } else {
             // COLD PATH
-            info!("Ignoring non-init message from unknown peer {}", addr_nice(src));
-            self.traffic.count_invalid_protocol(data.len());
+            if !self.check_migration(src, data)? {
+                info!("Ignoring non-init message from unknown peer {}", addr_nice(src));
+                self.traffic.count_invalid_protocol(data.len());
+            }
             return Ok(());
         };
         // HOT PATH
@@ -881,47 +3680,181 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
         }
     }
 
+    /// Whether a control message from the address may be processed, see `ratelimit`
+    fn allows_control(&mut self, src: SocketAddr) -> bool {
+        match self.control_limit {
+            Some(ref mut control_limit) => control_limit.allows(src.ip()),
+            None => true,
+        }
+    }
+
+    /// Counts a failed authentication of the address, see `bans`
+    ///
+    /// Addresses of established and configured peers are never banned, so that a misbehaving node behind the same
+    /// address can not cut off a working connection.
+    fn record_auth_failure(&mut self, src: SocketAddr) {
+        let ip = mapped_addr(src).ip();
+        if self.bans.is_none()
+            || self.peers.keys().any(|addr| mapped_addr(*addr).ip() == ip)
+            || self.reconnect_peers.iter().any(|entry| entry.resolved.iter().any(|addr| mapped_addr(*addr).ip() == ip))
+        {
+            return;
+        }
+        if let Some(ref mut bans) = self.bans {
+            bans.failed(ip);
+        }
+    }
+
+    /// Checks whether a message from an unknown address comes from a peer that changed its address
+    ///
+    /// Only messages that pass the authentication of a peer session are accepted, so that spoofed source addresses
+    /// can not redirect sessions. The sessions are only tried without changing their state, so that the messages can
+    /// not disturb the replay protection or the key rotation of the peer. The peer is then connected on the new address
+    /// and `prefer_better_path` closes the old connection once the handshake succeeded. Each peer can only change its
+    /// address once per `MIGRATION_COOLDOWN` after a successful migration, only one migration per peer is pending at a
+    /// time and the number of checks is limited since each one tries all peer sessions.
+    fn check_migration(&mut self, src: SocketAddr, data: &MsgBuffer) -> Result<bool, Error> {
+        if self.migrations.contains_key(&src) {
+            return Ok(true);
+        }
+        if self.peers.is_empty() || data.len() <= EXTRA_LEN + TAG_LEN {
+            return Ok(false);
+        }
+        if self.migration_checks >= MAX_MIGRATION_CHECKS {
+            self.migrations_rejected += 1;
+            return Ok(false);
+        }
+        self.migration_checks += 1;
+        let old = match self.peers.iter().find(|(_, peer)| peer.crypto.verify_message(data.message())) {
+            Some((addr, _)) => *addr,
+            None => {
+                self.migrations_rejected += 1;
+                return Ok(false);
+            }
+        };
+        let now = TS::now();
+        if self.peers[&old].next_migration > now || self.migrations.values().any(|(other, _)| *other == old) {
+            self.migrations_rejected += 1;
+            debug!("Ignoring address change of peer {} to {} during cooldown", addr_nice(old), addr_nice(src));
+            return Ok(true);
+        }
+        info!("Peer {} seems to have moved to {}, connecting to the new address", self.peer_nice(old), addr_nice(src));
+        self.migrations.insert(src, (old, now + MIGRATION_COOLDOWN));
+        self.connect_sock(src)?;
+        Ok(true)
+    }
+
+    /// Logs the error with its code and counts it for the statistics
+    fn report_error(&mut self, err: &Error) {
+        error!("[E{}] {}", err.code(), err);
+        *self.error_counts.entry(err.code()).or_insert(0) += 1;
+    }
+
     fn initialize(&mut self) {
         if let Err(err) = self.reset_own_addresses() {
             error!("Failed to obtain local addresses: {}", err)
         }
     }
 
-    fn handle_socket_event(&mut self, buffer: &mut MsgBuffer) {
+    fn handle_socket_event(&mut self, buffer: &mut MsgBuffer) -> Result<(), Error> {
         // HOT PATH
-        let src = try_fail!(self.socket.receive(buffer), "Failed to read from network socket: {}");
+        let (src, receive_time) = self
+            .socket
+            .receive_timestamped(buffer)
+            .map_err(|e| Error::SocketIo("Failed to read from network socket", e))?;
+        if let Some(ref mut bans) = self.bans {
+            if bans.is_banned(mapped_addr(src).ip()) {
+                return Ok(());
+            }
+        }
+        self.receive_time = receive_time;
         self.traffic.count_in_traffic(src, buffer.len());
-        match self.handle_net_message(src, buffer) {
-            Err(e @ Error::CryptoInitFatal(_)) => {
+        if let Some(ref mut budget) = self.budget {
+            budget.count(&mapped_addr(src), buffer.len());
+        }
+        if let Some(ref mut radius) = self.radius {
+            radius.count_in(&mapped_addr(src), buffer.len());
+        }
+        match self.handle_net_message(src, buffer).map_err(|e| e.with_peer(src, Phase::Message)) {
+            Err(e) if matches!(e.root(), Error::CryptoInitFatal(_)) => {
                 // COLD PATH
                 debug!("Fatal crypto init error from {}: {}", src, e);
                 info!("Closing pending connection to {} due to error in crypto init", addr_nice(src));
-                self.pending_inits.remove(&src);
+                // Only a peer that received the reply to its first message has proven that it owns the address
+                let replied = self.pending_inits.remove(&src).map(|init| init.has_replied()).unwrap_or(false);
+                self.quality.handshake_failed(src);
+                self.crypto_stats.handshake_failed();
+                self.peer_states.close(src);
+                if replied {
+                    self.record_auth_failure(src);
+                }
                 self.config.call_hook(
                     "peer_disconnected",
                     vec![("PEER", format!("{:?}", addr_nice(src))), ("IFNAME", self.device.ifname().to_owned())],
                     true,
                 );
             }
-            Err(e @ Error::CryptoInit(_)) => {
+            Err(e) if matches!(e.root(), Error::CryptoInit(_)) => {
                 // COLD PATH
                 debug!("Recoverable init error from {}: {}", src, e);
                 info!("Ignoring invalid init message from peer {}", addr_nice(src));
             }
             Err(e) => {
                 // COLD PATH
-                error!("{}", e);
+                self.report_error(&e);
             }
             Ok(_) => {} // HOT PATH
         }
+        self.receive_time = None;
+        Ok(())
     }
 
-    fn handle_device_event(&mut self, buffer: &mut MsgBuffer) {
+    fn handle_device_event(&mut self, buffer: &mut MsgBuffer) -> Result<(), Error> {
         // HOT PATH
-        try_fail!(self.device.read(buffer), "Failed to read from device: {}");
+        if self.idle {
+            // COLD PATH
+            self.wake()?;
+        }
+        self.device.read(buffer)?;
+        if self.config.latency_bypass {
+            return self.handle_device_batch(buffer);
+        }
         if let Err(e) = self.handle_interface_data(buffer) {
-            error!("{}", e);
+            self.report_error(&e);
+        }
+        Ok(())
+    }
+
+    /// Reads all waiting packets from the device and sends the latency-sensitive ones before the bulk data
+    fn handle_device_batch(&mut self, buffer: &mut MsgBuffer) -> Result<(), Error> {
+        let mut bulk = mem::take(&mut self.bulk_queue);
+        loop {
+            if P::is_latency_sensitive(buffer.message()) {
+                if let Err(e) = self.handle_interface_data(buffer) {
+                    self.report_error(&e);
+                }
+            } else {
+                // Keep the packet and continue with an empty buffer
+                let mut queued = self.buffers.get();
+                mem::swap(buffer, &mut queued);
+                bulk.push(queued);
+            }
+            if bulk.len() >= LATENCY_BYPASS_BATCH || !self.device.has_pending() {
+                break;
+            }
+            if let Err(e) = self.device.read(buffer) {
+                self.report_error(&e);
+                break;
+            }
+        }
+        for mut data in bulk.drain(..) {
+            if let Err(e) = self.handle_interface_data(&mut data) {
+                self.report_error(&e);
+            }
+            self.buffers.put(data);
         }
+        self.bulk_queue = bulk;
+        Ok(())
     }
 
     /// The main method of the node
@@ -931,46 +3864,124 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
     /// `handle_net_message` method. It will also read from the device and call
     /// `handle_interface_data` for each packet read.
     /// Also, this method will call `housekeep` every second.
-    pub fn run(&mut self) {
-        let ctrlc = CtrlC::new();
-        let waiter = try_fail!(
-            WaitImpl::new(self.socket.as_raw_fd(), self.device.as_raw_fd(), 1000),
-            "Failed to setup poll: {}"
-        );
-        let mut buffer = MsgBuffer::new(SPACE_BEFORE);
+    ///
+    /// The method returns when Ctrl-C has been pressed or SIGTERM or SIGQUIT has been received. SIGUSR1 dumps the
+    /// state to the log and SIGUSR2 reconnects to all configured peers.
+    ///
+    /// # Errors
+    /// Returns an error if reading from the socket or the device fails or if polling fails
+    /// repeatedly. In this case, no shutdown messages are sent.
+    pub fn run(&mut self) -> Result<(), Error> {
+        let mut waiter = WaitImpl::new(self.socket.as_raw_fd(), self.device.as_raw_fd(), self.wait_timeout())
+            .map_err(|e| Error::SocketIo("Failed to setup poll", e))?;
+        if let Some(busy_poll) = self.config.busy_poll {
+            waiter.set_busy_poll(busy_poll);
+        }
+        // This buffer is shared by device reads, crypto and socket sends for all packets
+        let mut buffer = self.buffers.get();
         let mut poll_error = false;
-        self.config.call_hook("vpn_started", vec![("IFNAME", self.device.ifname())], true);
+        self.run_started();
         for evt in waiter {
-            // HOT PATH
-            match evt {
-                WaitResult::Error(err) => {
-                    // COLD PATH
-                    if poll_error {
-                        fail!("Poll wait failed again: {}", err);
-                    }
-                    debug!("Poll wait failed: {}, retrying...", err);
-                    poll_error = true;
-                }
-                WaitResult::Timeout => {}
-                WaitResult::Socket => self.handle_socket_event(&mut buffer),
-                WaitResult::Device => self.handle_device_event(&mut buffer),
+            if !self.handle_wait_result(evt, &mut buffer, &mut poll_error)? {
+                break;
+            }
+        }
+        self.run_stopped(&mut buffer);
+        Ok(())
+    }
+
+    /// The main method of the node in an async runtime
+    ///
+    /// This method behaves like `run` but waits for the socket, the device and the timer in the tokio runtime it is
+    /// called in instead of using epoll directly. The messages are handled by the same code.
+    #[cfg(feature = "async-runtime")]
+    pub async fn run_async(&mut self) -> Result<(), Error> {
+        let mut waiter = AsyncWait::new(self.socket.as_raw_fd(), self.device.as_raw_fd(), self.wait_timeout())
+            .map_err(|e| Error::SocketIo("Failed to setup async runtime", e))?;
+        let mut buffer = self.buffers.get();
+        let mut poll_error = false;
+        self.run_started();
+        loop {
+            let evt = waiter.wait().await;
+            if !self.handle_wait_result(evt, &mut buffer, &mut poll_error)? {
+                break;
             }
-            if self.next_housekeep < TS::now() {
+        }
+        self.run_stopped(&mut buffer);
+        Ok(())
+    }
+
+    /// Time in milliseconds after which waiting for events is interrupted
+    fn wait_timeout(&self) -> u32 {
+        // Reorder buffers need to be flushed within their window
+        self.config.reorder_window.map(|window| u32::from(window).clamp(1, 1000)).unwrap_or(1000)
+    }
+
+    fn run_started(&mut self) {
+        self.config.call_hook("vpn_started", vec![("IFNAME", self.device.ifname())], true);
+        if let Some(ref chaos) = self.chaos {
+            chaos.start_watchdog();
+        }
+    }
+
+    /// Handles one event of the main loop and runs the periodic tasks when they are due
+    ///
+    /// Returns `false` when the node should shut down.
+    fn handle_wait_result(
+        &mut self, evt: WaitResult, buffer: &mut MsgBuffer, poll_error: &mut bool,
+    ) -> Result<bool, Error> {
+        // HOT PATH
+        match evt {
+            WaitResult::Error(err) => {
                 // COLD PATH
-                poll_error = false;
-                if ctrlc.was_pressed() {
-                    break;
+                if *poll_error {
+                    return Err(Error::SocketIo("Poll wait failed again", err));
                 }
-                if let Err(e) = self.housekeep() {
-                    error!("{}", e)
+                debug!("Poll wait failed: {}, retrying...", err);
+                *poll_error = true;
+            }
+            WaitResult::Timeout => {}
+            WaitResult::Socket => self.handle_socket_event(buffer)?,
+            WaitResult::Device => self.handle_device_event(buffer)?,
+        }
+        if let Some(deadline) = self.reorder_deadline {
+            if deadline <= Instant::now() {
+                if let Err(e) = self.flush_reorder_buffers() {
+                    self.report_error(&e)
                 }
-                self.next_housekeep = TS::now() + 1
             }
         }
+        if self.next_housekeep < TS::now() {
+            // COLD PATH
+            *poll_error = false;
+            if self.handle_signals() || self.handle.stopped.load(Ordering::SeqCst) {
+                return Ok(false);
+            }
+            if let Err(e) = self.housekeep() {
+                self.report_error(&e)
+            }
+            self.next_housekeep = TS::now() + 1
+        }
+        Ok(true)
+    }
+
+    fn run_stopped(&mut self, buffer: &mut MsgBuffer) {
         info!("Shutting down...");
+        self.write_out_stats();
+        if let Err(err) = self.save_state() {
+            error!("Failed to save state: {}", err)
+        }
+        if let Some(ref mut radius) = self.radius {
+            radius.stop_all(TerminateCause::NasReboot);
+        }
+        if let Some(ref chaos) = self.chaos {
+            chaos.stop();
+        }
         self.config.call_hook("vpn_shutdown", vec![("IFNAME", self.device.ifname())], true);
         buffer.clear();
-        self.broadcast_msg(MESSAGE_TYPE_CLOSE, &mut buffer).ok();
+        self.broadcast_msg(MESSAGE_TYPE_CLOSE, buffer).ok();
+        // The mapping is removed from the router now as the process might exit without dropping the node
+        self.port_forwarding = None;
         if let Some(ref path) = self.config.beacon_store {
             let path = Path::new(path);
             if path.exists() {
@@ -983,14 +3994,38 @@ impl<D: Device, P: Protocol, S: Socket, TS: TimeSource> GenericCloud<D, P, S, TS
     }
 }
 
-#[cfg(test)]
 use super::device::MockDevice;
-#[cfg(test)]
+#[cfg(any(test, feature = "testing"))]
 use super::net::MockSocket;
-#[cfg(test)]
+#[cfg(any(test, feature = "testing"))]
 use super::util::MockTimeSource;
 
-#[cfg(test)]
+/// Access for the built-in benchmark, see `bench`
+impl<P: Protocol, S: Socket, TS: TimeSource> GenericCloud<MockDevice, P, S, TS> {
+    pub(crate) fn mock_device(&mut self) -> &mut MockDevice {
+        &mut self.device
+    }
+
+    pub(crate) fn process_socket_event(&mut self) -> Result<(), Error> {
+        let mut buffer = self.buffers.get();
+        self.handle_socket_event(&mut buffer)?;
+        self.buffers.put(buffer);
+        Ok(())
+    }
+
+    pub(crate) fn process_device_event(&mut self) -> Result<(), Error> {
+        let mut buffer = self.buffers.get();
+        self.handle_device_event(&mut buffer)?;
+        self.buffers.put(buffer);
+        Ok(())
+    }
+
+    pub(crate) fn has_peer(&self, addr: &SocketAddr) -> bool {
+        self.peers.contains_key(addr)
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
 impl<P: Protocol> GenericCloud<MockDevice, P, MockSocket, MockTimeSource> {
     pub fn socket(&mut self) -> &mut MockSocket {
         &mut self.socket
@@ -1001,13 +4036,21 @@ impl<P: Protocol> GenericCloud<MockDevice, P, MockSocket, MockTimeSource> {
     }
 
     pub fn trigger_socket_event(&mut self) {
-        let mut buffer = MsgBuffer::new(SPACE_BEFORE);
-        self.handle_socket_event(&mut buffer);
+        let mut buffer = self.buffers.get();
+        assert!(self.handle_socket_event(&mut buffer).is_ok());
+        self.buffers.put(buffer);
     }
 
     pub fn trigger_device_event(&mut self) {
-        let mut buffer = MsgBuffer::new(SPACE_BEFORE);
-        self.handle_device_event(&mut buffer);
+        let mut buffer = self.buffers.get();
+        // Like the event loop, keep reading until no packets are waiting on the device
+        loop {
+            assert!(self.handle_device_event(&mut buffer).is_ok());
+            if !self.device.has_pending() {
+                break;
+            }
+        }
+        self.buffers.put(buffer);
     }
 
     pub fn trigger_housekeep(&mut self) {
@@ -1018,6 +4061,26 @@ impl<P: Protocol> GenericCloud<MockDevice, P, MockSocket, MockTimeSource> {
         self.peers.contains_key(addr)
     }
 
+    pub fn clock_skew(&self, addr: &SocketAddr) -> Option<Time> {
+        self.peers.get(addr).and_then(|peer| peer.clock_skew)
+    }
+
+    pub fn peer_mtu(&self, addr: &SocketAddr) -> Option<u16> {
+        self.peers.get(addr).and_then(|peer| peer.mtu)
+    }
+
+    pub fn peer_protocol(&self, addr: &SocketAddr) -> Option<(u8, u32)> {
+        self.peers.get(addr).map(|peer| (peer.version, peer.capabilities))
+    }
+
+    pub fn mtu_mismatches(&self) -> usize {
+        self.mtu_mismatches
+    }
+
+    pub fn duplicate_identities(&self) -> usize {
+        self.duplicate_identities
+    }
+
     pub fn own_addresses(&self) -> &[SocketAddr] {
         &self.own_addresses
     }
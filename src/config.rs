@@ -2,14 +2,45 @@
 // Copyright (C) 2015-2021  Dennis Schwerdel
 // This software is licensed under GPL-3 or newer (see LICENSE.md)
 
-use super::{device::Type, types::Mode, util::run_cmd, util::Duration};
+use super::{
+    device::Type,
+    error::Error,
+    oldconfig::OldConfigFile,
+    types::{DuplicateIdentityPolicy, Mode, Topology},
+    util::run_cmd,
+    util::Duration,
+};
+pub use crate::advertise::Config as AdvertisePolicyConfig;
+pub use crate::bans::Config as BanConfig;
+pub use crate::budget::Config as BudgetConfig;
+pub use crate::capture::Config as CaptureConfig;
+use crate::control::DEFAULT_CONTROL_SOCKET;
 pub use crate::crypto::Config as CryptoConfig;
+pub use crate::dhcp::Config as DhcpConfig;
+pub use crate::docker::Config as DockerConfig;
+pub use crate::duplicate::Config as DuplicationConfig;
+pub use crate::fec::Config as FecConfig;
+pub use crate::firewall::Config as FirewallConfig;
+pub use crate::ipam::Config as IpamConfig;
+pub use crate::nat::RuleConfig as NatRuleConfig;
+pub use crate::netmanager::Config as NetManagerConfig;
+pub use crate::policy::{AcceptConfig as AcceptClaimsConfig, FilterConfig as ClaimFilterConfig};
+pub use crate::radius::Config as RadiusConfig;
+pub use crate::ratelimit::Config as ControlLimitConfig;
+pub use crate::remote::Config as RemoteControlConfig;
+pub use crate::sandbox::Config as HardeningConfig;
+pub use crate::vxlan::Config as VxlanConfig;
 
 use std::{cmp::max, collections::HashMap, ffi::OsStr, process, thread};
 use structopt::{clap::Shell, StructOpt};
 
 pub const DEFAULT_PEER_TIMEOUT: u16 = 300;
 pub const DEFAULT_PORT: u16 = 3210;
+pub const DEFAULT_MAX_HANDSHAKES: usize = 256;
+pub const DEFAULT_MAX_DIALS: usize = 16;
+
+/// Maximal length of the description of the port mapping
+const MAX_PORT_FORWARDING_DESCRIPTION_LEN: usize = 64;
 
 #[derive(Deserialize, Debug, PartialEq, Clone)]
 pub struct Config {
@@ -17,36 +48,99 @@ pub struct Config {
     pub device_name: String,
     pub device_path: Option<String>,
     pub fix_rp_filter: bool,
+    pub ip_forwarding: bool,
+    pub mtu: Option<usize>,
+    pub derive_mac: bool,
 
     pub ip: Option<String>,
+    pub derive_ip: Option<String>,
     pub advertise_addresses: Vec<String>,
+    pub advertise_policy: Option<AdvertisePolicyConfig>,
     pub ifup: Option<String>,
     pub ifdown: Option<String>,
+    pub network_manager: Option<NetManagerConfig>,
 
     pub crypto: CryptoConfig,
 
     pub listen: String,
-    pub peers: Vec<String>,
+    pub network_id: Option<String>,
+    pub networks: Vec<String>,
+    pub peers: Vec<PeerConfig>,
     pub peer_timeout: Duration,
+    pub peer_exchange: bool,
     pub keepalive: Option<Duration>,
+    pub fast_failover: bool,
+    pub suppress_keepalives: bool,
+    pub idle_timeout: Option<Duration>,
+    pub wait_for_network: Option<Duration>,
     pub beacon_store: Option<String>,
     pub beacon_load: Option<String>,
     pub beacon_interval: Duration,
     pub beacon_password: Option<String>,
     pub mode: Mode,
+    pub observer: bool,
+    pub topology: Topology,
+    pub duplicate_identity: DuplicateIdentityPolicy,
     pub switch_timeout: Duration,
+    pub static_macs: HashMap<String, String>,
     pub claims: Vec<String>,
     pub auto_claim: bool,
+    pub defer_claims: bool,
+    pub summarize_claims: bool,
+    pub source_validation: bool,
+    pub ethertypes: Vec<String>,
+    pub arp_proxy: bool,
+    pub firewall: FirewallConfig,
+    pub nat: Vec<NatRuleConfig>,
+    pub claim_filters: Vec<ClaimFilterConfig>,
+    pub accept_claims: Vec<AcceptClaimsConfig>,
+    pub budget: Option<BudgetConfig>,
+    pub control_limit: Option<ControlLimitConfig>,
+    pub bans: Option<BanConfig>,
+    pub duplication: Option<DuplicationConfig>,
+    pub fec: Option<FecConfig>,
+    pub radius: Option<RadiusConfig>,
+    pub services: Vec<String>,
+    pub node_name: Option<String>,
+    pub dns_listen: Option<String>,
+    pub dns_domain: Option<String>,
+    pub dhcp: Option<DhcpConfig>,
+    pub ipam: Option<IpamConfig>,
+    pub capture: Option<CaptureConfig>,
+    pub docker: Option<DockerConfig>,
+    pub vxlan: Option<VxlanConfig>,
+    pub hardening: HardeningConfig,
     pub port_forwarding: bool,
     pub daemonize: bool,
+    pub ephemeral: bool,
     pub pid_file: Option<String>,
     pub stats_file: Option<String>,
+    pub time_format: Option<String>,
+    pub utc: bool,
+    pub state_dir: Option<String>,
+    pub control_socket: Option<String>,
+    pub remote_control: Option<RemoteControlConfig>,
     pub statsd_server: Option<String>,
     pub statsd_prefix: Option<String>,
+    pub prometheus_file: Option<String>,
+    pub influxdb_server: Option<String>,
+    pub influxdb_measurement: Option<String>,
+    pub cpu_affinity: Vec<usize>,
+    pub priority: Option<u8>,
+    pub busy_poll: Option<u32>,
+    pub latency_bypass: bool,
+    pub early_data: bool,
+    pub reorder_window: Option<u16>,
+    pub max_handshakes: usize,
+    pub max_peers: Option<usize>,
+    pub max_dials: usize,
+    pub dial_rate: Option<usize>,
     pub user: Option<String>,
     pub group: Option<String>,
     pub hook: Option<String>,
     pub hooks: HashMap<String, String>,
+    pub auth_hook: Option<String>,
+    pub chaos: bool,
 }
 
 impl Default for Config {
@@ -56,33 +150,96 @@ impl Default for Config {
             device_name: "vpncloud%d".to_string(),
             device_path: None,
             fix_rp_filter: false,
+            ip_forwarding: false,
+            mtu: None,
+            derive_mac: false,
             ip: None,
+            derive_ip: None,
             advertise_addresses: vec![],
+            advertise_policy: None,
             ifup: None,
             ifdown: None,
+            network_manager: None,
             crypto: CryptoConfig::default(),
             listen: "3210".to_string(),
+            network_id: None,
+            networks: vec![],
             peers: vec![],
             peer_timeout: DEFAULT_PEER_TIMEOUT as Duration,
+            peer_exchange: true,
             keepalive: None,
+            fast_failover: false,
+            suppress_keepalives: false,
+            idle_timeout: None,
+            wait_for_network: None,
             beacon_store: None,
             beacon_load: None,
             beacon_interval: 3600,
             beacon_password: None,
             mode: Mode::Normal,
+            observer: false,
+            topology: Topology::Mesh,
+            duplicate_identity: DuplicateIdentityPolicy::Allow,
             switch_timeout: 300,
+            static_macs: HashMap::new(),
             claims: vec![],
             auto_claim: true,
+            defer_claims: false,
+            summarize_claims: false,
+            source_validation: false,
+            ethertypes: vec![],
+            arp_proxy: false,
+            firewall: FirewallConfig::default(),
+            nat: vec![],
+            claim_filters: vec![],
+            accept_claims: vec![],
+            budget: None,
+            control_limit: None,
+            bans: None,
+            duplication: None,
+            fec: None,
+            radius: None,
+            services: vec![],
+            node_name: None,
+            dns_listen: None,
+            dns_domain: None,
+            dhcp: None,
+            ipam: None,
+            capture: None,
+            docker: None,
+            vxlan: None,
+            hardening: HardeningConfig::default(),
             port_forwarding: true,
             daemonize: false,
+            ephemeral: false,
             pid_file: None,
             stats_file: None,
+            time_format: None,
+            utc: false,
+            state_dir: None,
+            control_socket: None,
+            remote_control: None,
             statsd_server: None,
             statsd_prefix: None,
+            prometheus_file: None,
+            influxdb_server: None,
+            influxdb_measurement: None,
+            cpu_affinity: vec![],
+            priority: None,
+            busy_poll: None,
+            latency_bypass: false,
+            early_data: false,
+            reorder_window: None,
+            max_handshakes: DEFAULT_MAX_HANDSHAKES,
+            max_peers: None,
+            max_dials: DEFAULT_MAX_DIALS,
+            dial_rate: None,
             user: None,
             group: None,
             hook: None,
             hooks: HashMap::new(),
+            auth_hook: None,
+            chaos: false,
         }
     }
 }
@@ -103,31 +260,70 @@ impl Config {
             if let Some(val) = device.fix_rp_filter {
                 self.fix_rp_filter = val;
             }
+            if let Some(val) = device.ip_forwarding {
+                self.ip_forwarding = val;
+            }
+            if let Some(val) = device.mtu {
+                self.mtu = Some(val);
+            }
+            if let Some(val) = device.derive_mac {
+                self.derive_mac = val;
+            }
         }
         if let Some(val) = file.ip {
             self.ip = Some(val);
         }
+        if let Some(val) = file.derive_ip {
+            self.derive_ip = Some(val);
+        }
         if let Some(mut val) = file.advertise_addresses {
             self.advertise_addresses.append(&mut val);
         }
+        if let Some(val) = file.advertise_policy {
+            self.advertise_policy = Some(val);
+        }
         if let Some(val) = file.ifup {
             self.ifup = Some(val);
         }
         if let Some(val) = file.ifdown {
             self.ifdown = Some(val);
         }
+        if let Some(val) = file.network_manager {
+            self.network_manager = Some(val);
+        }
         if let Some(val) = file.listen {
             self.listen = val;
         }
-        if let Some(mut val) = file.peers {
-            self.peers.append(&mut val);
+        if let Some(val) = file.network_id {
+            self.network_id = Some(val);
+        }
+        if let Some(val) = file.networks {
+            self.networks = val;
+        }
+        if let Some(val) = file.peers {
+            self.peers.extend(val.into_iter().map(PeerConfig::from));
         }
         if let Some(val) = file.peer_timeout {
             self.peer_timeout = val;
         }
+        if let Some(val) = file.peer_exchange {
+            self.peer_exchange = val;
+        }
         if let Some(val) = file.keepalive {
             self.keepalive = Some(val);
         }
+        if let Some(val) = file.fast_failover {
+            self.fast_failover = val;
+        }
+        if let Some(val) = file.suppress_keepalives {
+            self.suppress_keepalives = val;
+        }
+        if let Some(val) = file.idle_timeout {
+            self.idle_timeout = Some(val);
+        }
+        if let Some(val) = file.wait_for_network {
+            self.wait_for_network = Some(val);
+        }
         if let Some(beacon) = file.beacon {
             if let Some(val) = beacon.store {
                 self.beacon_store = Some(val);
@@ -145,15 +341,104 @@ impl Config {
         if let Some(val) = file.mode {
             self.mode = val;
         }
+        if let Some(val) = file.observer {
+            self.observer = val;
+        }
+        if let Some(val) = file.topology {
+            self.topology = val;
+        }
+        if let Some(val) = file.duplicate_identity {
+            self.duplicate_identity = val;
+        }
         if let Some(val) = file.switch_timeout {
             self.switch_timeout = val;
         }
+        for (k, v) in file.static_macs {
+            self.static_macs.insert(k, v);
+        }
         if let Some(mut val) = file.claims {
             self.claims.append(&mut val);
         }
         if let Some(val) = file.auto_claim {
             self.auto_claim = val;
         }
+        if let Some(val) = file.defer_claims {
+            self.defer_claims = val;
+        }
+        if let Some(val) = file.summarize_claims {
+            self.summarize_claims = val;
+        }
+        if let Some(val) = file.source_validation {
+            self.source_validation = val;
+        }
+        if let Some(val) = file.ethertypes {
+            self.ethertypes = val;
+        }
+        if let Some(val) = file.arp_proxy {
+            self.arp_proxy = val;
+        }
+        if let Some(val) = file.firewall {
+            self.firewall = val;
+        }
+        if let Some(mut val) = file.nat {
+            self.nat.append(&mut val);
+        }
+        if let Some(mut val) = file.claim_filters {
+            self.claim_filters.append(&mut val);
+        }
+        if let Some(mut val) = file.accept_claims {
+            self.accept_claims.append(&mut val);
+        }
+        if let Some(val) = file.budget {
+            self.budget = Some(val);
+        }
+        if let Some(val) = file.control_limit {
+            self.control_limit = Some(val);
+        }
+        if let Some(val) = file.bans {
+            self.bans = Some(val);
+        }
+        if let Some(val) = file.duplication {
+            self.duplication = Some(val);
+        }
+        if let Some(val) = file.fec {
+            self.fec = Some(val);
+        }
+        if let Some(val) = file.radius {
+            self.radius = Some(val);
+        }
+        if let Some(mut val) = file.services {
+            self.services.append(&mut val);
+        }
+        if let Some(val) = file.node_name {
+            self.node_name = Some(val);
+        }
+        if let Some(dns) = file.dns {
+            if let Some(val) = dns.listen {
+                self.dns_listen = Some(val);
+            }
+            if let Some(val) = dns.domain {
+                self.dns_domain = Some(val);
+            }
+        }
+        if let Some(val) = file.dhcp {
+            self.dhcp = Some(val);
+        }
+        if let Some(val) = file.ipam {
+            self.ipam = Some(val);
+        }
+        if let Some(val) = file.capture {
+            self.capture = Some(val);
+        }
+        if let Some(val) = file.docker {
+            self.docker = Some(val);
+        }
+        if let Some(val) = file.vxlan {
+            self.vxlan = Some(val);
+        }
+        if let Some(val) = file.hardening {
+            self.hardening = val;
+        }
         if let Some(val) = file.port_forwarding {
             self.port_forwarding = val;
         }
@@ -163,6 +448,21 @@ impl Config {
         if let Some(val) = file.stats_file {
             self.stats_file = Some(val);
         }
+        if let Some(val) = file.time_format {
+            self.time_format = Some(val);
+        }
+        if let Some(val) = file.utc {
+            self.utc = val;
+        }
+        if let Some(val) = file.state_dir {
+            self.state_dir = Some(val);
+        }
+        if let Some(val) = file.control_socket {
+            self.control_socket = Some(val);
+        }
+        if let Some(val) = file.remote_control {
+            self.remote_control = Some(val);
+        }
         if let Some(statsd) = file.statsd {
             if let Some(val) = statsd.server {
                 self.statsd_server = Some(val);
@@ -171,6 +471,51 @@ impl Config {
                 self.statsd_prefix = Some(val);
             }
         }
+        if let Some(val) = file.prometheus_file {
+            self.prometheus_file = Some(val);
+        }
+        if let Some(influxdb) = file.influxdb {
+            if let Some(val) = influxdb.server {
+                self.influxdb_server = Some(val);
+            }
+            if let Some(val) = influxdb.measurement {
+                self.influxdb_measurement = Some(val);
+            }
+        }
+        if let Some(performance) = file.performance {
+            if let Some(val) = performance.cpu_affinity {
+                self.cpu_affinity = val;
+            }
+            if let Some(val) = performance.priority {
+                self.priority = Some(val);
+            }
+            if let Some(val) = performance.busy_poll {
+                self.busy_poll = Some(val);
+            }
+            if let Some(val) = performance.latency_bypass {
+                self.latency_bypass = val;
+            }
+            if let Some(val) = performance.early_data {
+                self.early_data = val;
+            }
+            if let Some(val) = performance.reorder_window {
+                self.reorder_window = Some(val);
+            }
+        }
+        if let Some(limits) = file.limits {
+            if let Some(val) = limits.handshakes {
+                self.max_handshakes = val;
+            }
+            if let Some(val) = limits.peers {
+                self.max_peers = Some(val);
+            }
+            if let Some(val) = limits.dials {
+                self.max_dials = val;
+            }
+            if let Some(val) = limits.dial_rate {
+                self.dial_rate = Some(val);
+            }
+        }
         if let Some(val) = file.user {
             self.user = Some(val);
         }
@@ -186,16 +531,34 @@ impl Config {
         if let Some(val) = file.crypto.private_key {
             self.crypto.private_key = Some(val)
         }
+        if let Some(val) = file.crypto.secondary_key {
+            self.crypto.secondary_key = Some(val)
+        }
+        if let Some(val) = file.crypto.network_secret {
+            self.crypto.network_secret = Some(val)
+        }
         self.crypto.trusted_keys.append(&mut file.crypto.trusted_keys);
         if !file.crypto.algorithms.is_empty() {
             self.crypto.algorithms = file.crypto.algorithms.clone();
         }
+        for (k, v) in file.crypto.peer_algorithms {
+            self.crypto.peer_algorithms.insert(k, v);
+        }
+        for (k, v) in file.crypto.peer_schedules {
+            self.crypto.peer_schedules.insert(k, v);
+        }
+        if file.crypto.padding {
+            self.crypto.padding = true
+        }
         if let Some(val) = file.hook {
             self.hook = Some(val)
         }
         for (k, v) in file.hooks {
             self.hooks.insert(k, v);
         }
+        if let Some(val) = file.auth_hook {
+            self.auth_hook = Some(val)
+        }
     }
 
     pub fn merge_args(&mut self, mut args: Args) {
@@ -211,9 +574,21 @@ impl Config {
         if args.fix_rp_filter {
             self.fix_rp_filter = true;
         }
+        if args.ip_forwarding {
+            self.ip_forwarding = true;
+        }
+        if let Some(val) = args.mtu {
+            self.mtu = Some(val);
+        }
+        if args.derive_mac {
+            self.derive_mac = true;
+        }
         if let Some(val) = args.ip {
             self.ip = Some(val);
         }
+        if let Some(val) = args.derive_ip {
+            self.derive_ip = Some(val);
+        }
         if let Some(val) = args.ifup {
             self.ifup = Some(val);
         }
@@ -224,13 +599,34 @@ impl Config {
         if let Some(val) = args.listen {
             self.listen = val;
         }
-        self.peers.append(&mut args.peers);
+        if let Some(val) = args.network_id {
+            self.network_id = Some(val);
+        }
+        if !args.networks.is_empty() {
+            self.networks = args.networks;
+        }
+        self.peers.extend(args.peers.into_iter().map(PeerConfig::new));
         if let Some(val) = args.peer_timeout {
             self.peer_timeout = val;
         }
+        if args.no_peer_exchange {
+            self.peer_exchange = false;
+        }
         if let Some(val) = args.keepalive {
             self.keepalive = Some(val);
         }
+        if args.fast_failover {
+            self.fast_failover = true;
+        }
+        if args.suppress_keepalives {
+            self.suppress_keepalives = true;
+        }
+        if let Some(val) = args.idle_timeout {
+            self.idle_timeout = Some(val);
+        }
+        if let Some(val) = args.wait_for_network {
+            self.wait_for_network = Some(val);
+        }
         if let Some(val) = args.beacon_store {
             self.beacon_store = Some(val);
         }
@@ -246,6 +642,15 @@ impl Config {
         if let Some(val) = args.mode {
             self.mode = val;
         }
+        if args.observer {
+            self.observer = true;
+        }
+        if let Some(val) = args.topology {
+            self.topology = val;
+        }
+        if let Some(val) = args.duplicate_identity {
+            self.duplicate_identity = val;
+        }
         if let Some(val) = args.switch_timeout {
             self.switch_timeout = val;
         }
@@ -253,24 +658,76 @@ impl Config {
         if args.no_auto_claim {
             self.auto_claim = false;
         }
+        if args.defer_claims {
+            self.defer_claims = true;
+        }
+        if args.summarize_claims {
+            self.summarize_claims = true;
+        }
+        if args.source_validation {
+            self.source_validation = true;
+        }
+        if !args.ethertypes.is_empty() {
+            self.ethertypes = args.ethertypes;
+        }
+        if args.arp_proxy {
+            self.arp_proxy = true;
+        }
+        self.services.append(&mut args.services);
+        if let Some(val) = args.node_name {
+            self.node_name = Some(val);
+        }
+        if let Some(val) = args.dns_listen {
+            self.dns_listen = Some(val);
+        }
+        if let Some(val) = args.dns_domain {
+            self.dns_domain = Some(val);
+        }
         if args.no_port_forwarding {
             self.port_forwarding = false;
         }
         if args.daemon {
             self.daemonize = true;
         }
+        if args.ephemeral {
+            self.ephemeral = true;
+        }
+        if args.chaos {
+            self.chaos = true;
+        }
         if let Some(val) = args.pid_file {
             self.pid_file = Some(val);
         }
         if let Some(val) = args.stats_file {
             self.stats_file = Some(val);
         }
+        if let Some(val) = args.time_format {
+            self.time_format = Some(val);
+        }
+        if args.utc {
+            self.utc = true;
+        }
+        if let Some(val) = args.state_dir {
+            self.state_dir = Some(val);
+        }
+        if let Some(val) = args.control_socket {
+            self.control_socket = Some(val);
+        }
         if let Some(val) = args.statsd_server {
             self.statsd_server = Some(val);
         }
         if let Some(val) = args.statsd_prefix {
             self.statsd_prefix = Some(val);
         }
+        if let Some(val) = args.prometheus_file {
+            self.prometheus_file = Some(val);
+        }
+        if let Some(val) = args.influxdb_server {
+            self.influxdb_server = Some(val);
+        }
+        if let Some(val) = args.influxdb_measurement {
+            self.influxdb_measurement = Some(val);
+        }
         if let Some(val) = args.user {
             self.user = Some(val);
         }
@@ -286,10 +743,28 @@ impl Config {
         if let Some(val) = args.private_key {
             self.crypto.private_key = Some(val)
         }
+        if let Some(val) = args.secondary_key {
+            self.crypto.secondary_key = Some(val)
+        }
+        if let Some(val) = args.network_secret {
+            self.crypto.network_secret = Some(val)
+        }
         self.crypto.trusted_keys.append(&mut args.trusted_keys);
         if !args.algorithms.is_empty() {
             self.crypto.algorithms = args.algorithms.clone();
         }
+        for s in args.peer_algorithms {
+            // Key names can contain colons but algorithms can not
+            if let Some(pos) = s.rfind(':') {
+                let algos = s[pos + 1..].split(',').map(|a| a.trim().to_string()).collect();
+                self.crypto.peer_algorithms.insert(s[..pos].to_string(), algos);
+            } else {
+                warn!("Ignoring invalid peer algorithms: {}", s);
+            }
+        }
+        if args.padding {
+            self.crypto.padding = true
+        }
         for s in args.hook {
             if s.contains(':') {
                 let pos = s.find(':').unwrap();
@@ -300,12 +775,39 @@ impl Config {
                 self.hook = Some(s);
             }
         }
+        if let Some(val) = args.auth_hook {
+            self.auth_hook = Some(val)
+        }
     }
 
     pub fn into_config_file(self) -> ConfigFile {
         ConfigFile {
             auto_claim: Some(self.auto_claim),
+            defer_claims: Some(self.defer_claims),
+            summarize_claims: Some(self.summarize_claims),
+            source_validation: Some(self.source_validation),
             claims: Some(self.claims),
+            ethertypes: Some(self.ethertypes),
+            arp_proxy: Some(self.arp_proxy),
+            firewall: Some(self.firewall),
+            nat: Some(self.nat),
+            claim_filters: Some(self.claim_filters),
+            accept_claims: Some(self.accept_claims),
+            budget: self.budget,
+            control_limit: self.control_limit,
+            bans: self.bans,
+            duplication: self.duplication,
+            fec: self.fec,
+            radius: self.radius,
+            services: Some(self.services),
+            node_name: self.node_name,
+            dns: Some(ConfigFileDns { listen: self.dns_listen, domain: self.dns_domain }),
+            dhcp: self.dhcp,
+            ipam: self.ipam,
+            capture: self.capture,
+            docker: self.docker,
+            vxlan: self.vxlan,
+            hardening: Some(self.hardening),
             beacon: Some(ConfigFileBeacon {
                 store: self.beacon_store,
                 load: self.beacon_load,
@@ -317,29 +819,139 @@ impl Config {
                 path: self.device_path,
                 type_: Some(self.device_type),
                 fix_rp_filter: Some(self.fix_rp_filter),
+                ip_forwarding: Some(self.ip_forwarding),
+                mtu: self.mtu,
+                derive_mac: Some(self.derive_mac),
             }),
             crypto: self.crypto,
             group: self.group,
             user: self.user,
             ifup: self.ifup,
             ifdown: self.ifdown,
+            network_manager: self.network_manager,
             ip: self.ip,
+            derive_ip: self.derive_ip,
             advertise_addresses: Some(self.advertise_addresses),
+            advertise_policy: self.advertise_policy,
             keepalive: self.keepalive,
+            fast_failover: Some(self.fast_failover),
+            suppress_keepalives: Some(self.suppress_keepalives),
+            idle_timeout: self.idle_timeout,
+            wait_for_network: self.wait_for_network,
             listen: Some(self.listen),
+            network_id: self.network_id,
+            networks: Some(self.networks),
             mode: Some(self.mode),
+            observer: Some(self.observer),
+            topology: Some(self.topology),
+            duplicate_identity: Some(self.duplicate_identity),
             peer_timeout: Some(self.peer_timeout),
-            peers: Some(self.peers),
+            peer_exchange: Some(self.peer_exchange),
+            peers: Some(self.peers.into_iter().map(ConfigFilePeer::from).collect()),
             pid_file: self.pid_file,
             port_forwarding: Some(self.port_forwarding),
             stats_file: self.stats_file,
+            time_format: self.time_format,
+            utc: Some(self.utc),
+            state_dir: self.state_dir,
+            control_socket: self.control_socket,
+            remote_control: self.remote_control,
             statsd: Some(ConfigFileStatsd { server: self.statsd_server, prefix: self.statsd_prefix }),
+            prometheus_file: self.prometheus_file,
+            influxdb: Some(ConfigFileInfluxdb { server: self.influxdb_server, measurement: self.influxdb_measurement }),
+            performance: Some(ConfigFilePerformance {
+                cpu_affinity: Some(self.cpu_affinity),
+                priority: self.priority,
+                busy_poll: self.busy_poll,
+                latency_bypass: Some(self.latency_bypass),
+                early_data: Some(self.early_data),
+                reorder_window: self.reorder_window,
+            }),
+            limits: Some(ConfigFileLimits {
+                handshakes: Some(self.max_handshakes),
+                peers: self.max_peers,
+                dials: Some(self.max_dials),
+                dial_rate: self.dial_rate,
+            }),
             switch_timeout: Some(self.switch_timeout),
+            static_macs: self.static_macs,
             hook: self.hook,
             hooks: self.hooks,
+            auth_hook: self.auth_hook,
         }
     }
 
+    /// Checks that no option writes to disk, as required by the ephemeral mode
+    pub fn check_ephemeral(&self) -> Result<(), Error> {
+        let violation = if self.pid_file.is_some() {
+            "pid-file"
+        } else if self.stats_file.is_some() {
+            "stats-file"
+        } else if self.state_dir.is_some() {
+            "state-dir"
+        } else if self.beacon_store.is_some() {
+            "beacon store"
+        } else if self.control_socket.is_some() {
+            "control-socket"
+        } else if self.dhcp.as_ref().map(|dhcp| dhcp.lease_file.is_some()).unwrap_or(false) {
+            "dhcp lease-file"
+        } else if self.network_manager.is_some() {
+            "network-manager"
+        } else if self.docker.is_some() {
+            "docker"
+        } else {
+            return Ok(());
+        };
+        Err(Error::InvalidConfigValue("Option not allowed in ephemeral mode", violation.to_string()))
+    }
+
+    /// Checks that no options are set that need a TUN/TAP device or look into the payload as ethernet frames
+    pub fn check_vxlan(&self) -> Result<(), Error> {
+        if self.vxlan.is_none() {
+            return Ok(());
+        }
+        let violation = if self.device_type != Type::Tap {
+            "type tun"
+        } else if self.ip.is_some() {
+            "ip"
+        } else if self.mtu.is_some() {
+            "mtu"
+        } else if self.ifup.is_some() || self.ifdown.is_some() {
+            "ifup/ifdown"
+        } else if self.network_manager.is_some() {
+            "network-manager"
+        } else if self.docker.is_some() {
+            "docker"
+        } else if self.firewall != FirewallConfig::default() {
+            "firewall"
+        } else if self.arp_proxy {
+            "arp-proxy"
+        } else if self.dhcp.is_some() {
+            "dhcp"
+        } else {
+            return Ok(());
+        };
+        Err(Error::InvalidConfigValue("Option not allowed with VXLAN", violation.to_string()))
+    }
+
+    /// Description of the port mapping on the router, naming the network and the node
+    ///
+    /// Only printable ASCII characters are used as routers show the description in their web interface.
+    pub fn port_forwarding_description(&self) -> String {
+        let mut description = "VpnCloud".to_string();
+        if let Some(ref network_id) = self.network_id {
+            description.push_str(&format!(" {}", network_id));
+        }
+        if let Some(ref node_name) = self.node_name {
+            description.push_str(&format!(" ({})", node_name));
+        }
+        description
+            .chars()
+            .map(|c| if c.is_ascii_graphic() || c == ' ' { c } else { '_' })
+            .take(MAX_PORT_FORWARDING_DESCRIPTION_LEN)
+            .collect()
+    }
+
     pub fn get_keepalive(&self) -> Duration {
         match self.keepalive {
             Some(dur) => dur,
@@ -390,10 +1002,34 @@ pub struct Args {
     #[structopt(long)]
     pub fix_rp_filter: bool,
 
+    /// Enable IP forwarding on the host while running
+    #[structopt(long)]
+    pub ip_forwarding: bool,
+
+    /// Set the MTU of the virtual device (supports jumbo frames)
+    #[structopt(long)]
+    pub mtu: Option<usize>,
+
+    /// Derive the MAC address of a TAP device from the public key
+    #[structopt(long)]
+    pub derive_mac: bool,
+
     /// The mode of the VPN
     #[structopt(short, long, possible_values=&["normal", "router", "switch", "hub"])]
     pub mode: Option<Mode>,
 
+    /// Only take part in the control plane, never forward or originate data traffic
+    #[structopt(long)]
+    pub observer: bool,
+
+    /// The role of the node in the topology
+    #[structopt(long, possible_values=&["mesh", "hub", "spoke"])]
+    pub topology: Option<Topology>,
+
+    /// What to do when the key of a peer connects from a second node
+    #[structopt(long, possible_values=&["reject-newer", "reject-older", "allow"])]
+    pub duplicate_identity: Option<DuplicateIdentityPolicy>,
+
     /// The shared password to encrypt all traffic
     #[structopt(short, long, env)]
     pub password: Option<String>,
@@ -406,6 +1042,14 @@ pub struct Args {
     #[structopt(long)]
     pub public_key: Option<String>,
 
+    /// A second private key to trust during key migration
+    #[structopt(long, env)]
+    pub secondary_key: Option<String>,
+
+    /// A secret that peers must prove in their first packet, others get no response
+    #[structopt(long, env)]
+    pub network_secret: Option<String>,
+
     /// Other public keys to trust
     #[structopt(long = "trusted-key", alias = "trust", use_delimiter = true)]
     pub trusted_keys: Vec<String>,
@@ -414,6 +1058,14 @@ pub struct Args {
     #[structopt(long = "algorithm", alias = "algo", use_delimiter=true, case_insensitive = true, possible_values=&["plain", "aes128", "aes256", "chacha20"])]
     pub algorithms: Vec<String>,
 
+    /// Algorithms to use for a trusted key or key name, e.g. gateway:chacha20 or gateway:-aes128,-aes256
+    #[structopt(long = "peer-algorithm", allow_hyphen_values = true)]
+    pub peer_algorithms: Vec<String>,
+
+    /// Pad handshake and control messages to uniform sizes
+    #[structopt(long)]
+    pub padding: bool,
+
     /// The local subnets to claim (IP or IP/prefix)
     #[structopt(long = "claim", use_delimiter = true)]
     pub claims: Vec<String>,
@@ -422,6 +1074,42 @@ pub struct Args {
     #[structopt(long)]
     pub no_auto_claim: bool,
 
+    /// Only advertise the claims once the interface has its address
+    #[structopt(long)]
+    pub defer_claims: bool,
+
+    /// Aggregate adjacent claimed prefixes before advertising them
+    #[structopt(long)]
+    pub summarize_claims: bool,
+
+    /// Drop packets from peers with source addresses outside of their claims
+    #[structopt(long)]
+    pub source_validation: bool,
+
+    /// Ethertypes to forward on tap devices (empty list allows all)
+    #[structopt(long = "ethertype", use_delimiter = true)]
+    pub ethertypes: Vec<String>,
+
+    /// Answer ARP requests for known remote hosts locally (tap devices only)
+    #[structopt(long)]
+    pub arp_proxy: bool,
+
+    /// Services to advertise to the peers (e.g. ssh or http:8080)
+    #[structopt(long = "service", use_delimiter = true)]
+    pub services: Vec<String>,
+
+    /// The name of this node announced to the peers
+    #[structopt(long)]
+    pub node_name: Option<String>,
+
+    /// Serve DNS for the node names on this address (ip:port)
+    #[structopt(long)]
+    pub dns_listen: Option<String>,
+
+    /// The domain of the node names [default: vpn]
+    #[structopt(long, requires = "dns-listen")]
+    pub dns_domain: Option<String>,
+
     /// Name of the virtual device
     #[structopt(short, long)]
     pub device: Option<String>,
@@ -430,6 +1118,14 @@ pub struct Args {
     #[structopt(short, long)]
     pub listen: Option<String>,
 
+    /// Name of the logical network, sent in front of each packet to share the port with other networks
+    #[structopt(long)]
+    pub network_id: Option<String>,
+
+    /// Config file of an additional network that shares the port
+    #[structopt(long = "network")]
+    pub networks: Vec<String>,
+
     /// Address of a peer to connect to
     #[structopt(short = "c", long = "peer", alias = "connect")]
     pub peers: Vec<String>,
@@ -438,10 +1134,30 @@ pub struct Args {
     #[structopt(long)]
     pub peer_timeout: Option<Duration>,
 
+    /// Only connect to the configured peers and do not exchange peer lists
+    #[structopt(long)]
+    pub no_peer_exchange: bool,
+
     /// Periodically send message to keep connections alive
     #[structopt(long)]
     pub keepalive: Option<Duration>,
 
+    /// Probe peers that do not answer and fail over within seconds
+    #[structopt(long)]
+    pub fast_failover: bool,
+
+    /// Skip keepalives to peers that recently received data and coalesce control messages
+    #[structopt(long)]
+    pub suppress_keepalives: bool,
+
+    /// Stop all traffic to the peers after this many seconds without payload until the node is woken up
+    #[structopt(long)]
+    pub idle_timeout: Option<Duration>,
+
+    /// Retry binding the socket and resolving the peers for up to this many seconds when the network is not ready
+    #[structopt(long)]
+    pub wait_for_network: Option<Duration>,
+
     /// Switch table entry timeout in seconds
     #[structopt(long)]
     pub switch_timeout: Option<Duration>,
@@ -474,6 +1190,10 @@ pub struct Args {
     #[structopt(long)]
     pub ip: Option<String>,
 
+    /// Derive the IP address of the interface from the public key within this subnet
+    #[structopt(long, conflicts_with = "ip")]
+    pub derive_ip: Option<String>,
+
     /// A list of IP Addresses to advertise as our external address(s)
     #[structopt(long = "advertise_addresses", use_delimiter = true)]
     pub advertise_addresses: Vec<String>,
@@ -490,6 +1210,10 @@ pub struct Args {
     #[structopt(long)]
     pub version: bool,
 
+    /// Check crypto, device permissions and listen port, print a report and exit
+    #[structopt(long)]
+    pub self_test: bool,
+
     /// Disable automatic port forwarding
     #[structopt(long)]
     pub no_port_forwarding: bool,
@@ -498,6 +1222,14 @@ pub struct Args {
     #[structopt(long)]
     pub daemon: bool,
 
+    /// Never write to disk and do not accept keys from the config file
+    #[structopt(long, conflicts_with = "log-file")]
+    pub ephemeral: bool,
+
+    /// Read the private key from this file descriptor
+    #[structopt(long, conflicts_with_all = &["password", "private-key"])]
+    pub key_fd: Option<i32>,
+
     /// Store the process id in this file when daemonizing
     #[structopt(long)]
     pub pid_file: Option<String>,
@@ -506,14 +1238,42 @@ pub struct Args {
     #[structopt(long)]
     pub stats_file: Option<String>,
 
+    /// Format of the timestamps in the log file and the stats file
+    #[structopt(long)]
+    pub time_format: Option<String>,
+
+    /// Use UTC instead of the local time for timestamps
+    #[structopt(long)]
+    pub utc: bool,
+
+    /// Persist peers and statistics in this directory
+    #[structopt(long)]
+    pub state_dir: Option<String>,
+
+    /// Accept commands to connect and disconnect peers on this unix socket
+    #[structopt(long)]
+    pub control_socket: Option<String>,
+
     /// Send statistics to this statsd server
     #[structopt(long)]
     pub statsd_server: Option<String>,
 
-    /// Use the given prefix for statsd records
-    #[structopt(long, requires = "statsd-server")]
+    /// Use the given prefix for statsd records and Prometheus metrics
+    #[structopt(long)]
     pub statsd_prefix: Option<String>,
 
+    /// Write statistics in the Prometheus text format to this file
+    #[structopt(long)]
+    pub prometheus_file: Option<String>,
+
+    /// Send statistics to this InfluxDB server via UDP
+    #[structopt(long)]
+    pub influxdb_server: Option<String>,
+
+    /// Use the given measurement name for InfluxDB records
+    #[structopt(long, requires = "influxdb-server")]
+    pub influxdb_measurement: Option<String>,
+
     /// Run as other user
     #[structopt(long)]
     pub user: Option<String>,
@@ -530,10 +1290,34 @@ pub struct Args {
     #[structopt(long)]
     pub hook: Vec<String>,
 
+    /// Ask this command or HTTP endpoint whether to admit new peers
+    #[structopt(long)]
+    pub auth_hook: Option<String>,
+
+    /// Inject faults at runtime and check invariants (for testing only)
+    #[structopt(long, hidden = true)]
+    pub chaos: bool,
+
     #[structopt(subcommand)]
     pub cmd: Option<Command>,
 }
 
+/// The instance that receives a control command
+#[derive(StructOpt, Debug)]
+pub struct ControlTarget {
+    /// Control socket of the instance
+    #[structopt(long, default_value = DEFAULT_CONTROL_SOCKET)]
+    pub socket: String,
+
+    /// Send the command to the remote control port of this node instead
+    #[structopt(long, requires = "admin-key")]
+    pub remote: Option<String>,
+
+    /// Private admin key to sign the command for the remote node
+    #[structopt(long)]
+    pub admin_key: Option<String>,
+}
+
 #[derive(StructOpt, Debug)]
 pub enum Command {
     /// Generate and print a key-pair and exit
@@ -561,6 +1345,87 @@ pub enum Command {
         config_file: String,
     },
 
+    /// Measure the performance of this machine
+    Bench {
+        /// Duration of each measurement in seconds
+        #[structopt(long, default_value = "1")]
+        duration: f32,
+    },
+
+    /// Connect a running instance to a peer
+    Connect {
+        /// Address of the peer
+        address: String,
+
+        /// Also connect to the peer after restarts
+        #[structopt(long)]
+        persist: bool,
+
+        #[structopt(flatten)]
+        target: ControlTarget,
+    },
+
+    /// Disconnect a running instance from a peer
+    Disconnect {
+        /// Address of the peer
+        address: String,
+
+        /// Also remove the peer from the peers that are connected after restarts
+        #[structopt(long)]
+        persist: bool,
+
+        #[structopt(flatten)]
+        target: ControlTarget,
+    },
+
+    /// Show the connection states of the peers of a running instance
+    Peers {
+        #[structopt(flatten)]
+        target: ControlTarget,
+    },
+
+    /// Show the latest statistics of a running instance
+    Stats {
+        #[structopt(flatten)]
+        target: ControlTarget,
+    },
+
+    /// List the addresses that a running instance banned after failed authentications
+    Bans {
+        #[structopt(flatten)]
+        target: ControlTarget,
+    },
+
+    /// Make an idle instance rejoin the network
+    Wake {
+        #[structopt(flatten)]
+        target: ControlTarget,
+    },
+
+    /// Lift a ban of a running instance
+    Unban {
+        /// Banned IP address or `all` to lift all bans
+        address: String,
+
+        #[structopt(flatten)]
+        target: ControlTarget,
+    },
+
+    /// Diagnose NAT and connectivity problems
+    Diagnose {
+        /// Config file with listen port, keys and peers
+        #[structopt(long, short)]
+        config: Option<String>,
+
+        /// STUN server to ask for the public address (can be repeated)
+        #[structopt(long = "stun-server")]
+        stun_servers: Vec<String>,
+
+        /// Running node that checks whether the listen port is reachable (defaults to the first peer)
+        #[structopt(long)]
+        helper: Option<String>,
+    },
+
     /// Generate shell completions
     Completion {
         /// Shell to create completions for
@@ -585,6 +1450,58 @@ pub enum Command {
     },
 }
 
+/// Transport used to reach a peer
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Udp,
+}
+
+/// A peer to connect to and to reconnect to when the connection is lost
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PeerConfig {
+    pub address: String,
+    /// Peers with a higher value are only dialed when all peers with lower values failed
+    #[serde(default)]
+    pub priority: u8,
+    pub keepalive: Option<Duration>,
+    pub transport: Option<Transport>,
+}
+
+impl PeerConfig {
+    pub fn new(address: String) -> Self {
+        Self { address, priority: 0, keepalive: None, transport: None }
+    }
+}
+
+/// A peer in the config file, either just the address or the structured form
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum ConfigFilePeer {
+    Address(String),
+    Peer(PeerConfig),
+}
+
+impl From<ConfigFilePeer> for PeerConfig {
+    fn from(peer: ConfigFilePeer) -> Self {
+        match peer {
+            ConfigFilePeer::Address(address) => PeerConfig::new(address),
+            ConfigFilePeer::Peer(peer) => peer,
+        }
+    }
+}
+
+impl From<PeerConfig> for ConfigFilePeer {
+    fn from(peer: PeerConfig) -> Self {
+        if peer == PeerConfig::new(peer.address.clone()) {
+            ConfigFilePeer::Address(peer.address)
+        } else {
+            ConfigFilePeer::Peer(peer)
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
 pub struct ConfigFileDevice {
@@ -593,6 +1510,9 @@ pub struct ConfigFileDevice {
     pub name: Option<String>,
     pub path: Option<String>,
     pub fix_rp_filter: Option<bool>,
+    pub ip_forwarding: Option<bool>,
+    pub mtu: Option<usize>,
+    pub derive_mac: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
@@ -611,72 +1531,313 @@ pub struct ConfigFileStatsd {
     pub prefix: Option<String>,
 }
 
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct ConfigFileInfluxdb {
+    pub server: Option<String>,
+    pub measurement: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct ConfigFilePerformance {
+    pub cpu_affinity: Option<Vec<usize>>,
+    pub priority: Option<u8>,
+    pub busy_poll: Option<u32>,
+    pub latency_bypass: Option<bool>,
+    pub early_data: Option<bool>,
+    pub reorder_window: Option<u16>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct ConfigFileLimits {
+    pub handshakes: Option<usize>,
+    pub peers: Option<usize>,
+    pub dials: Option<usize>,
+    pub dial_rate: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct ConfigFileDns {
+    pub listen: Option<String>,
+    pub domain: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
 pub struct ConfigFile {
     pub device: Option<ConfigFileDevice>,
 
     pub ip: Option<String>,
+    pub derive_ip: Option<String>,
     pub advertise_addresses: Option<Vec<String>>,
+    pub advertise_policy: Option<AdvertisePolicyConfig>,
     pub ifup: Option<String>,
     pub ifdown: Option<String>,
+    pub network_manager: Option<NetManagerConfig>,
 
     pub crypto: CryptoConfig,
     pub listen: Option<String>,
-    pub peers: Option<Vec<String>>,
+    pub network_id: Option<String>,
+    pub networks: Option<Vec<String>>,
+    pub peers: Option<Vec<ConfigFilePeer>>,
     pub peer_timeout: Option<Duration>,
+    pub peer_exchange: Option<bool>,
     pub keepalive: Option<Duration>,
+    pub fast_failover: Option<bool>,
+    pub suppress_keepalives: Option<bool>,
+    pub idle_timeout: Option<Duration>,
+    pub wait_for_network: Option<Duration>,
 
     pub beacon: Option<ConfigFileBeacon>,
     pub mode: Option<Mode>,
+    pub observer: Option<bool>,
+    pub topology: Option<Topology>,
+    pub duplicate_identity: Option<DuplicateIdentityPolicy>,
     pub switch_timeout: Option<Duration>,
+    pub static_macs: HashMap<String, String>,
     pub claims: Option<Vec<String>>,
     pub auto_claim: Option<bool>,
+    pub defer_claims: Option<bool>,
+    pub summarize_claims: Option<bool>,
+    pub source_validation: Option<bool>,
+    pub ethertypes: Option<Vec<String>>,
+    pub arp_proxy: Option<bool>,
+    pub firewall: Option<FirewallConfig>,
+    pub nat: Option<Vec<NatRuleConfig>>,
+    pub claim_filters: Option<Vec<ClaimFilterConfig>>,
+    pub accept_claims: Option<Vec<AcceptClaimsConfig>>,
+    pub budget: Option<BudgetConfig>,
+    pub control_limit: Option<ControlLimitConfig>,
+    pub bans: Option<BanConfig>,
+    pub duplication: Option<DuplicationConfig>,
+    pub fec: Option<FecConfig>,
+    pub radius: Option<RadiusConfig>,
+    pub services: Option<Vec<String>>,
+    pub node_name: Option<String>,
+    pub dns: Option<ConfigFileDns>,
+    pub dhcp: Option<DhcpConfig>,
+    pub ipam: Option<IpamConfig>,
+    pub capture: Option<CaptureConfig>,
+    pub docker: Option<DockerConfig>,
+    pub vxlan: Option<VxlanConfig>,
+    pub hardening: Option<HardeningConfig>,
     pub port_forwarding: Option<bool>,
     pub pid_file: Option<String>,
     pub stats_file: Option<String>,
+    pub time_format: Option<String>,
+    pub utc: Option<bool>,
+    pub state_dir: Option<String>,
+    pub control_socket: Option<String>,
+    pub remote_control: Option<RemoteControlConfig>,
     pub statsd: Option<ConfigFileStatsd>,
+    pub prometheus_file: Option<String>,
+    pub influxdb: Option<ConfigFileInfluxdb>,
+    pub performance: Option<ConfigFilePerformance>,
+    pub limits: Option<ConfigFileLimits>,
     pub user: Option<String>,
     pub group: Option<String>,
     pub hook: Option<String>,
     pub hooks: HashMap<String, String>,
+    pub auth_hook: Option<String>,
+}
+
+impl ConfigFile {
+    /// Parses a config file, falling back to the config format of version 1
+    pub fn parse(data: &str) -> Result<Self, Error> {
+        match serde_yaml::from_str(data) {
+            Ok(config) => Ok(config),
+            Err(err) => {
+                error!("Failed to read config file: {}", err);
+                info!("Trying to convert from old config format");
+                let config_old: OldConfigFile = serde_yaml::from_str(data).map_err(|e| {
+                    Error::InvalidConfigValue("Config file is neither version 2 nor version 1", e.to_string())
+                })?;
+                info!("Successfully converted from old format, please migrate your config using migrate-config");
+                Ok(config_old.convert())
+            }
+        }
+    }
 }
 
 #[test]
 fn config_file() {
+    use crate::{
+        budget::{Action as BudgetAction, Period},
+        firewall::{Action, Direction, RuleConfig},
+        netmanager::Manager,
+    };
     let config_file = "
 device:
   type: tun
   name: vpncloud%d
   path: /dev/net/tun
+  ip-forwarding: true
+  mtu: 9000
+  derive-mac: true
 ip: 10.0.1.1/16
+derive-ip: 10.0.0.0/16
 advertise-addresses:
   - 192.168.0.1
   - 192.168.1.1
+advertise-policy:
+  exclude-private: true
+  interface: eth0
 ifup: ifconfig $IFNAME 10.0.1.1/16 mtu 1400 up
 ifdown: 'true'
+network-manager:
+  manager: networkd
+  routes:
+    - 10.2.0.0/16
+  dns:
+    - 10.0.1.1
 peers:
   - remote.machine.foo:3210
   - remote.machine.bar:3210
+  - address: backup.machine.foo:3210
+    priority: 1
+    keepalive: 60
+    transport: udp
 peer-timeout: 600
+peer-exchange: false
 keepalive: 840
+fast-failover: true
+idle-timeout: 1800
+wait-for-network: 60
 switch-timeout: 300
+static-macs:
+  '52:54:00:12:34:56': node2.example.com:3210
 beacon:
   store: /run/vpncloud.beacon.out
   load: /run/vpncloud.beacon.in
   interval: 3600
   password: test123
 mode: normal
+observer: false
+topology: spoke
+duplicate-identity: reject-older
 claims:
   - 10.0.1.0/24
+summarize-claims: true
+ethertypes:
+  - ipv4
+  - 0x0806
+arp-proxy: true
+firewall:
+  default: deny
+  stateful: true
+  conntrack-size: 1000
+  conntrack-timeout: 600
+  rules:
+    - action: allow
+      direction: in
+      src: 10.0.1.0/24
+      protocol: tcp
+      port: 22
+nat:
+  - peer: node2
+    remote: 10.0.1.0/24
+    local: 10.2.1.0/24
+claim-filters:
+  - peer: node2
+    import:
+      - 10.2.0.0/16
+    export:
+      - 10.0.0.0/8
+    export-peers: false
+accept-claims:
+  - key: node2
+    claims:
+      - 10.2.0.0/16
+budget:
+  period: daily
+  limit: 1000000000
+  peers:
+    node2: 100000000
+  action: drop
+control-limit:
+  rate: 20
+  ban-threshold: 1000
+bans:
+  max-failures: 3
+  backoff: 4
+duplication:
+  dscp:
+    - 46
+  max-size: 128
+fec:
+  loss-threshold: 0.05
+  group-size: 8
+radius:
+  server: radius.example.com
+  secret: secret
+  interim-interval: 600
+services:
+  - ssh
+  - http:8080
+node-name: node1
+dns:
+  listen: 10.0.1.1:53
+  domain: mesh
+dhcp:
+  server: 10.0.1.1/24
+  range: 10.0.1.100-10.0.1.200
+  dns:
+    - 10.0.1.1
+  lease-file: /var/lib/vpncloud/leases
+ipam:
+  pool: 10.1.0.0/16
+  prefix-len: 24
+capture:
+  peer: node2
+  file: /tmp/node2.pcap
+  max-packets: 100
+docker:
+  bridge: vpncloud-br
+vxlan:
+  listen: 127.0.0.1:4790
+  remote: 127.0.0.1:4789
+hardening:
+  seccomp: true
+  landlock: true
+  paths:
+    - /tmp
 port-forwarding: true
 user: nobody
 group: nogroup
 pid-file: /run/vpncloud.run
 stats-file: /var/log/vpncloud.stats
+time-format: '%Y-%m-%dT%H:%M:%S'
+utc: true
+state-dir: /var/lib/vpncloud
+control-socket: /run/vpncloud.sock
+remote-control:
+  listen: 10.0.0.1:3211
+  admin-keys:
+    - rZu2FzIfnDHDdJAXiUaFdD5IjhS2YkqnEbPxbUMHYHk
 statsd:
   server: example.com:1234
   prefix: prefix
+prometheus-file: /var/lib/node_exporter/vpncloud.prom
+influxdb:
+  server: example.com:8089
+  measurement: vpn
+performance:
+  cpu-affinity:
+    - 2
+    - 3
+  priority: 50
+  busy-poll: 50
+  latency-bypass: true
+  early-data: true
+  reorder-window: 30
+limits:
+  handshakes: 64
+  peers: 32
+  dials: 8
+  dial-rate: 4
     ";
     assert_eq!(
         serde_yaml::from_str::<ConfigFile>(config_file).unwrap(),
@@ -685,17 +1846,48 @@ statsd:
                 type_: Some(Type::Tun),
                 name: Some("vpncloud%d".to_string()),
                 path: Some("/dev/net/tun".to_string()),
-                fix_rp_filter: None
+                fix_rp_filter: None,
+                ip_forwarding: Some(true),
+                mtu: Some(9000),
+                derive_mac: Some(true)
             }),
             ip: Some("10.0.1.1/16".to_string()),
+            derive_ip: Some("10.0.0.0/16".to_string()),
             advertise_addresses: Some(vec!["192.168.0.1".to_string(), "192.168.1.1".to_string()]),
+            advertise_policy: Some(AdvertisePolicyConfig {
+                exclude_private: true,
+                interface: Some("eth0".to_string()),
+                only: vec![]
+            }),
             ifup: Some("ifconfig $IFNAME 10.0.1.1/16 mtu 1400 up".to_string()),
             ifdown: Some("true".to_string()),
+            network_manager: Some(NetManagerConfig {
+                manager: Manager::Networkd,
+                routes: vec!["10.2.0.0/16".to_string()],
+                dns: vec!["10.0.1.1".to_string()],
+                domains: vec![],
+            }),
             crypto: CryptoConfig::default(),
             listen: None,
-            peers: Some(vec!["remote.machine.foo:3210".to_string(), "remote.machine.bar:3210".to_string()]),
+            network_id: None,
+            networks: None,
+            peers: Some(vec![
+                ConfigFilePeer::Address("remote.machine.foo:3210".to_string()),
+                ConfigFilePeer::Address("remote.machine.bar:3210".to_string()),
+                ConfigFilePeer::Peer(PeerConfig {
+                    address: "backup.machine.foo:3210".to_string(),
+                    priority: 1,
+                    keepalive: Some(60),
+                    transport: Some(Transport::Udp)
+                })
+            ]),
             peer_timeout: Some(600),
+            peer_exchange: Some(false),
             keepalive: Some(840),
+            fast_failover: Some(true),
+            suppress_keepalives: None,
+            idle_timeout: Some(1800),
+            wait_for_network: Some(60),
             beacon: Some(ConfigFileBeacon {
                 store: Some("/run/vpncloud.beacon.out".to_string()),
                 load: Some("/run/vpncloud.beacon.in".to_string()),
@@ -703,20 +1895,134 @@ statsd:
                 password: Some("test123".to_string())
             }),
             mode: Some(Mode::Normal),
+            observer: Some(false),
+            topology: Some(Topology::Spoke),
+            duplicate_identity: Some(DuplicateIdentityPolicy::RejectOlder),
             switch_timeout: Some(300),
+            static_macs: vec![("52:54:00:12:34:56".to_string(), "node2.example.com:3210".to_string())]
+                .into_iter()
+                .collect(),
             claims: Some(vec!["10.0.1.0/24".to_string()]),
             auto_claim: None,
+            defer_claims: None,
+            summarize_claims: Some(true),
+            source_validation: None,
+            ethertypes: Some(vec!["ipv4".to_string(), "0x0806".to_string()]),
+            arp_proxy: Some(true),
+            firewall: Some(FirewallConfig {
+                default: Action::Deny,
+                rules: vec![RuleConfig {
+                    action: Action::Allow,
+                    direction: Some(Direction::In),
+                    src: Some("10.0.1.0/24".to_string()),
+                    dst: None,
+                    protocol: Some("tcp".to_string()),
+                    port: Some("22".to_string())
+                }],
+                stateful: true,
+                conntrack_size: 1000,
+                conntrack_timeout: 600
+            }),
+            nat: Some(vec![NatRuleConfig {
+                peer: "node2".to_string(),
+                remote: "10.0.1.0/24".to_string(),
+                local: "10.2.1.0/24".to_string()
+            }]),
+            claim_filters: Some(vec![ClaimFilterConfig {
+                peer: "node2".to_string(),
+                import: Some(vec!["10.2.0.0/16".to_string()]),
+                export: Some(vec!["10.0.0.0/8".to_string()]),
+                export_peers: false
+            }]),
+            accept_claims: Some(vec![AcceptClaimsConfig {
+                key: "node2".to_string(),
+                claims: vec!["10.2.0.0/16".to_string()]
+            }]),
+            budget: Some(BudgetConfig {
+                period: Period::Daily,
+                limit: Some(1_000_000_000),
+                peers: vec![("node2".to_string(), 100_000_000)].into_iter().collect(),
+                action: BudgetAction::Drop,
+                ..BudgetConfig::default()
+            }),
+            control_limit: Some(ControlLimitConfig { rate: 20, ban_threshold: 1000, ..ControlLimitConfig::default() }),
+            bans: Some(BanConfig { max_failures: 3, backoff: 4, ..BanConfig::default() }),
+            duplication: Some(DuplicationConfig { dscp: vec![46], max_size: Some(128) }),
+            fec: Some(FecConfig { loss_threshold: 0.05, group_size: 8 }),
+            radius: Some(RadiusConfig {
+                server: "radius.example.com".to_string(),
+                secret: "secret".to_string(),
+                interim_interval: Some(600),
+                nas_identifier: None
+            }),
+            services: Some(vec!["ssh".to_string(), "http:8080".to_string()]),
+            node_name: Some("node1".to_string()),
+            dns: Some(ConfigFileDns { listen: Some("10.0.1.1:53".to_string()), domain: Some("mesh".to_string()) }),
+            dhcp: Some(DhcpConfig {
+                server: "10.0.1.1/24".to_string(),
+                range: "10.0.1.100-10.0.1.200".to_string(),
+                router: None,
+                dns: vec!["10.0.1.1".to_string()],
+                lease_time: None,
+                lease_file: Some("/var/lib/vpncloud/leases".to_string())
+            }),
+            ipam: Some(IpamConfig {
+                pool: Some("10.1.0.0/16".to_string()),
+                prefix_len: Some(24),
+                lease_time: None,
+                request: false
+            }),
+            capture: Some(CaptureConfig {
+                peer: "node2".to_string(),
+                file: Some("/tmp/node2.pcap".to_string()),
+                max_packets: Some(100),
+                snaplen: None
+            }),
+            docker: Some(DockerConfig { socket: None, bridge: Some("vpncloud-br".to_string()) }),
+            vxlan: Some(VxlanConfig {
+                listen: "127.0.0.1:4790".to_string(),
+                remote: Some("127.0.0.1:4789".to_string())
+            }),
+            hardening: Some(HardeningConfig { seccomp: true, landlock: true, paths: vec!["/tmp".to_string()] }),
             port_forwarding: Some(true),
             user: Some("nobody".to_string()),
             group: Some("nogroup".to_string()),
             pid_file: Some("/run/vpncloud.run".to_string()),
             stats_file: Some("/var/log/vpncloud.stats".to_string()),
+            time_format: Some("%Y-%m-%dT%H:%M:%S".to_string()),
+            utc: Some(true),
+            state_dir: Some("/var/lib/vpncloud".to_string()),
+            control_socket: Some("/run/vpncloud.sock".to_string()),
+            remote_control: Some(RemoteControlConfig {
+                listen: "10.0.0.1:3211".to_string(),
+                admin_keys: vec!["rZu2FzIfnDHDdJAXiUaFdD5IjhS2YkqnEbPxbUMHYHk".to_string()]
+            }),
             statsd: Some(ConfigFileStatsd {
                 server: Some("example.com:1234".to_string()),
                 prefix: Some("prefix".to_string())
             }),
+            prometheus_file: Some("/var/lib/node_exporter/vpncloud.prom".to_string()),
+            influxdb: Some(ConfigFileInfluxdb {
+                server: Some("example.com:8089".to_string()),
+                measurement: Some("vpn".to_string())
+            }),
+            performance: Some(ConfigFilePerformance {
+                cpu_affinity: Some(vec![2, 3]),
+                priority: Some(50),
+                busy_poll: Some(50),
+                latency_bypass: Some(true),
+                early_data: Some(true),
+                reorder_window: Some(30),
+            }),
+            limits: Some(ConfigFileLimits {
+                handshakes: Some(64),
+                peers: Some(32),
+                dials: Some(8),
+                dial_rate: Some(4)
+            }),
             hook: None,
-            hooks: HashMap::new()
+            hooks: HashMap::new(),
+            auth_hook: None
         }
     )
 }
@@ -726,8 +2032,18 @@ fn parse_example_config() {
     serde_yaml::from_str::<ConfigFile>(include_str!("../assets/example.net.disabled")).unwrap();
 }
 
+#[test]
+fn parse_config_versions() {
+    let old = "device-type: tun\nshared-key: test\nport: 3210\n";
+    assert_eq!(ConfigFile::parse(old).unwrap(), serde_yaml::from_str::<OldConfigFile>(old).unwrap().convert());
+    assert_eq!(ConfigFile::parse("listen: 0.0.0.0:3211\n").unwrap().listen, Some("0.0.0.0:3211".to_string()));
+    assert!(ConfigFile::parse("listen: [").is_err());
+    assert!(ConfigFile::parse("- 1\n- 2\n").is_err());
+}
+
 #[test]
 fn config_merge() {
+    use crate::{firewall::Action, netmanager::Manager};
     let mut config = Config::default();
     config.merge_file(ConfigFile {
         device: Some(ConfigFileDevice {
@@ -735,16 +2051,42 @@ fn config_merge() {
             name: Some("vpncloud%d".to_string()),
             path: None,
             fix_rp_filter: None,
+            ip_forwarding: Some(true),
+            mtu: Some(1400),
+            derive_mac: None,
         }),
         ip: None,
+        derive_ip: None,
         advertise_addresses: Some(vec![]),
+        advertise_policy: None,
         ifup: Some("ifconfig $IFNAME 10.0.1.1/16 mtu 1400 up".to_string()),
         ifdown: Some("true".to_string()),
-        crypto: CryptoConfig::default(),
+        network_manager: Some(NetManagerConfig {
+            manager: Manager::NetworkManager,
+            routes: vec![],
+            dns: vec![],
+            domains: vec!["mesh".to_string()],
+        }),
+        crypto: CryptoConfig {
+            peer_schedules: vec![("contractor".to_string(), vec!["Mon-Fri 08:00-18:00".to_string()])]
+                .into_iter()
+                .collect(),
+            ..CryptoConfig::default()
+        },
         listen: None,
-        peers: Some(vec!["remote.machine.foo:3210".to_string(), "remote.machine.bar:3210".to_string()]),
+        network_id: Some("office".to_string()),
+        networks: None,
+        peers: Some(vec![
+            ConfigFilePeer::Address("remote.machine.foo:3210".to_string()),
+            ConfigFilePeer::Address("remote.machine.bar:3210".to_string()),
+        ]),
         peer_timeout: Some(600),
+        peer_exchange: None,
         keepalive: Some(840),
+        fast_failover: None,
+        suppress_keepalives: None,
+        idle_timeout: None,
+        wait_for_network: None,
         beacon: Some(ConfigFileBeacon {
             store: Some("/run/vpncloud.beacon.out".to_string()),
             load: Some("/run/vpncloud.beacon.in".to_string()),
@@ -752,20 +2094,68 @@ fn config_merge() {
             password: Some("test123".to_string()),
         }),
         mode: Some(Mode::Normal),
+        observer: Some(false),
+        topology: None,
+        duplicate_identity: None,
         switch_timeout: Some(300),
+        static_macs: vec![("52:54:00:12:34:56".to_string(), "node2:3210".to_string())].into_iter().collect(),
         claims: Some(vec!["10.0.1.0/24".to_string()]),
         auto_claim: Some(true),
+        defer_claims: Some(true),
+        summarize_claims: Some(true),
+        source_validation: Some(true),
+        ethertypes: None,
+        arp_proxy: Some(false),
+        firewall: Some(FirewallConfig { default: Action::Deny, ..FirewallConfig::default() }),
+        nat: None,
+        claim_filters: None,
+        accept_claims: None,
+        budget: None,
+        control_limit: None,
+        bans: None,
+        duplication: None,
+        fec: None,
+        radius: None,
+        services: Some(vec!["ssh".to_string()]),
+        node_name: Some("node1".to_string()),
+        dns: Some(ConfigFileDns { listen: Some("10.0.1.1:53".to_string()), domain: None }),
+        dhcp: None,
+        ipam: None,
+        capture: None,
+        docker: None,
+        vxlan: None,
+        hardening: Some(HardeningConfig { seccomp: true, ..HardeningConfig::default() }),
         port_forwarding: Some(true),
         user: Some("nobody".to_string()),
         group: Some("nogroup".to_string()),
         pid_file: Some("/run/vpncloud.run".to_string()),
         stats_file: Some("/var/log/vpncloud.stats".to_string()),
+        time_format: Some("%Y-%m-%dT%H:%M:%S".to_string()),
+        utc: Some(true),
+        state_dir: Some("/var/lib/vpncloud".to_string()),
+        control_socket: Some("/run/vpncloud.sock".to_string()),
+        remote_control: None,
         statsd: Some(ConfigFileStatsd {
             server: Some("example.com:1234".to_string()),
             prefix: Some("prefix".to_string()),
         }),
+        prometheus_file: Some("/var/lib/node_exporter/vpncloud.prom".to_string()),
+        influxdb: Some(ConfigFileInfluxdb {
+            server: Some("example.com:8089".to_string()),
+            measurement: Some("vpn".to_string()),
+        }),
+        performance: Some(ConfigFilePerformance {
+            cpu_affinity: Some(vec![1]),
+            priority: None,
+            busy_poll: Some(20),
+            latency_bypass: Some(true),
+            early_data: Some(true),
+            reorder_window: Some(20),
+        }),
+        limits: Some(ConfigFileLimits { handshakes: Some(64), peers: Some(100), dials: Some(4), dial_rate: Some(2) }),
         hook: None,
         hooks: HashMap::new(),
+        auth_hook: Some("http://auth.example.com/check".to_string()),
     });
     assert_eq!(
         config,
@@ -773,28 +2163,74 @@ fn config_merge() {
             device_type: Type::Tun,
             device_name: "vpncloud%d".to_string(),
             device_path: None,
+            ip_forwarding: true,
+            mtu: Some(1400),
             ip: None,
             advertise_addresses: vec![],
+            advertise_policy: None,
             ifup: Some("ifconfig $IFNAME 10.0.1.1/16 mtu 1400 up".to_string()),
             ifdown: Some("true".to_string()),
+            network_manager: Some(NetManagerConfig {
+                manager: Manager::NetworkManager,
+                routes: vec![],
+                dns: vec![],
+                domains: vec!["mesh".to_string()],
+            }),
             listen: "3210".to_string(),
-            peers: vec!["remote.machine.foo:3210".to_string(), "remote.machine.bar:3210".to_string()],
+            peers: vec![
+                PeerConfig::new("remote.machine.foo:3210".to_string()),
+                PeerConfig::new("remote.machine.bar:3210".to_string())
+            ],
             peer_timeout: 600,
             keepalive: Some(840),
             switch_timeout: 300,
+            static_macs: vec![("52:54:00:12:34:56".to_string(), "node2:3210".to_string())].into_iter().collect(),
             beacon_store: Some("/run/vpncloud.beacon.out".to_string()),
             beacon_load: Some("/run/vpncloud.beacon.in".to_string()),
             beacon_interval: 7200,
             beacon_password: Some("test123".to_string()),
             mode: Mode::Normal,
+            observer: false,
             port_forwarding: true,
             claims: vec!["10.0.1.0/24".to_string()],
+            firewall: FirewallConfig { default: Action::Deny, ..FirewallConfig::default() },
+            services: vec!["ssh".to_string()],
+            node_name: Some("node1".to_string()),
+            dns_listen: Some("10.0.1.1:53".to_string()),
+            hardening: HardeningConfig { seccomp: true, ..HardeningConfig::default() },
             user: Some("nobody".to_string()),
             group: Some("nogroup".to_string()),
             pid_file: Some("/run/vpncloud.run".to_string()),
             stats_file: Some("/var/log/vpncloud.stats".to_string()),
+            time_format: Some("%Y-%m-%dT%H:%M:%S".to_string()),
+            utc: true,
+            state_dir: Some("/var/lib/vpncloud".to_string()),
+            control_socket: Some("/run/vpncloud.sock".to_string()),
             statsd_server: Some("example.com:1234".to_string()),
             statsd_prefix: Some("prefix".to_string()),
+            prometheus_file: Some("/var/lib/node_exporter/vpncloud.prom".to_string()),
+            influxdb_server: Some("example.com:8089".to_string()),
+            influxdb_measurement: Some("vpn".to_string()),
+            cpu_affinity: vec![1],
+            busy_poll: Some(20),
+            latency_bypass: true,
+            early_data: true,
+            reorder_window: Some(20),
+            max_handshakes: 64,
+            max_peers: Some(100),
+            max_dials: 4,
+            dial_rate: Some(2),
+            auth_hook: Some("http://auth.example.com/check".to_string()),
+            defer_claims: true,
+            summarize_claims: true,
+            source_validation: true,
+            network_id: Some("office".to_string()),
+            crypto: CryptoConfig {
+                peer_schedules: vec![("contractor".to_string(), vec!["Mon-Fri 08:00-18:00".to_string()])]
+                    .into_iter()
+                    .collect(),
+                ..CryptoConfig::default()
+            },
             ..Default::default()
         }
     );
@@ -802,28 +2238,54 @@ fn config_merge() {
         type_: Some(Type::Tap),
         device: Some("vpncloud0".to_string()),
         device_path: Some("/dev/null".to_string()),
+        mtu: Some(9000),
+        derive_mac: true,
         ifup: Some("ifconfig $IFNAME 10.0.1.2/16 mtu 1400 up".to_string()),
         ifdown: Some("ifconfig $IFNAME down".to_string()),
         password: Some("anothersecret".to_string()),
+        peer_algorithms: vec!["gateway:chacha20".to_string()],
+        padding: true,
         listen: Some("[::]:3211".to_string()),
         peer_timeout: Some(1801),
+        no_peer_exchange: true,
         keepalive: Some(850),
+        fast_failover: true,
+        suppress_keepalives: true,
+        idle_timeout: Some(3600),
+        wait_for_network: Some(120),
         switch_timeout: Some(301),
         beacon_store: Some("/run/vpncloud.beacon.out2".to_string()),
         beacon_load: Some("/run/vpncloud.beacon.in2".to_string()),
         beacon_interval: Some(3600),
         beacon_password: Some("test1234".to_string()),
+        ephemeral: true,
         mode: Some(Mode::Switch),
+        observer: true,
+        topology: Some(Topology::Hub),
+        duplicate_identity: Some(DuplicateIdentityPolicy::RejectNewer),
         claims: vec![],
+        ethertypes: vec!["ipv6".to_string()],
+        arp_proxy: true,
+        services: vec!["http:8080".to_string()],
+        node_name: Some("node2".to_string()),
+        dns_listen: Some("10.0.1.2:53".to_string()),
+        dns_domain: Some("mesh".to_string()),
         peers: vec!["another:3210".to_string()],
         no_port_forwarding: true,
         daemon: true,
         pid_file: Some("/run/vpncloud-mynet.run".to_string()),
         stats_file: Some("/var/log/vpncloud-mynet.stats".to_string()),
+        state_dir: Some("/var/lib/vpncloud/mynet".to_string()),
+        control_socket: Some("/run/vpncloud-mynet.sock".to_string()),
         statsd_server: Some("example.com:2345".to_string()),
         statsd_prefix: Some("prefix2".to_string()),
+        prometheus_file: Some("/var/lib/node_exporter/mynet.prom".to_string()),
+        influxdb_server: Some("example.com:8090".to_string()),
+        influxdb_measurement: Some("mynet".to_string()),
         user: Some("root".to_string()),
         group: Some("root".to_string()),
+        auth_hook: Some("/usr/local/bin/vpncloud-auth".to_string()),
+        networks: vec!["/etc/vpncloud/guest.net".to_string()],
         ..Default::default()
     });
     assert_eq!(
@@ -833,38 +2295,155 @@ fn config_merge() {
             device_name: "vpncloud0".to_string(),
             device_path: Some("/dev/null".to_string()),
             fix_rp_filter: false,
+            ip_forwarding: true,
+            mtu: Some(9000),
+            derive_mac: true,
             ip: None,
+            derive_ip: None,
             advertise_addresses: vec![],
+            advertise_policy: None,
 
             ifup: Some("ifconfig $IFNAME 10.0.1.2/16 mtu 1400 up".to_string()),
             ifdown: Some("ifconfig $IFNAME down".to_string()),
-            crypto: CryptoConfig { password: Some("anothersecret".to_string()), ..CryptoConfig::default() },
+            network_manager: Some(NetManagerConfig {
+                manager: Manager::NetworkManager,
+                routes: vec![],
+                dns: vec![],
+                domains: vec!["mesh".to_string()],
+            }),
+            crypto: CryptoConfig {
+                password: Some("anothersecret".to_string()),
+                peer_algorithms: vec![("gateway".to_string(), vec!["chacha20".to_string()])].into_iter().collect(),
+                peer_schedules: vec![("contractor".to_string(), vec!["Mon-Fri 08:00-18:00".to_string()])]
+                    .into_iter()
+                    .collect(),
+                padding: true,
+                ..CryptoConfig::default()
+            },
             listen: "[::]:3211".to_string(),
+            network_id: Some("office".to_string()),
+            networks: vec!["/etc/vpncloud/guest.net".to_string()],
             peers: vec![
-                "remote.machine.foo:3210".to_string(),
-                "remote.machine.bar:3210".to_string(),
-                "another:3210".to_string()
+                PeerConfig::new("remote.machine.foo:3210".to_string()),
+                PeerConfig::new("remote.machine.bar:3210".to_string()),
+                PeerConfig::new("another:3210".to_string())
             ],
             peer_timeout: 1801,
+            peer_exchange: false,
             keepalive: Some(850),
+            fast_failover: true,
+            suppress_keepalives: true,
+            idle_timeout: Some(3600),
+            wait_for_network: Some(120),
             switch_timeout: 301,
+            static_macs: vec![("52:54:00:12:34:56".to_string(), "node2:3210".to_string())].into_iter().collect(),
             beacon_store: Some("/run/vpncloud.beacon.out2".to_string()),
             beacon_load: Some("/run/vpncloud.beacon.in2".to_string()),
             beacon_interval: 3600,
             beacon_password: Some("test1234".to_string()),
             mode: Mode::Switch,
+            observer: true,
+            topology: Topology::Hub,
+            duplicate_identity: DuplicateIdentityPolicy::RejectNewer,
             port_forwarding: false,
             claims: vec!["10.0.1.0/24".to_string()],
             auto_claim: true,
+            defer_claims: true,
+            summarize_claims: true,
+            source_validation: true,
+            ethertypes: vec!["ipv6".to_string()],
+            arp_proxy: true,
+            firewall: FirewallConfig { default: Action::Deny, ..FirewallConfig::default() },
+            nat: vec![],
+            claim_filters: vec![],
+            accept_claims: vec![],
+            budget: None,
+            control_limit: None,
+            bans: None,
+            duplication: None,
+            fec: None,
+            radius: None,
+            services: vec!["ssh".to_string(), "http:8080".to_string()],
+            node_name: Some("node2".to_string()),
+            dns_listen: Some("10.0.1.2:53".to_string()),
+            dns_domain: Some("mesh".to_string()),
+            dhcp: None,
+            ipam: None,
+            capture: None,
+            docker: None,
+            vxlan: None,
+            hardening: HardeningConfig { seccomp: true, ..HardeningConfig::default() },
             user: Some("root".to_string()),
             group: Some("root".to_string()),
             pid_file: Some("/run/vpncloud-mynet.run".to_string()),
             stats_file: Some("/var/log/vpncloud-mynet.stats".to_string()),
+            time_format: Some("%Y-%m-%dT%H:%M:%S".to_string()),
+            utc: true,
+            state_dir: Some("/var/lib/vpncloud/mynet".to_string()),
+            control_socket: Some("/run/vpncloud-mynet.sock".to_string()),
+            remote_control: None,
             statsd_server: Some("example.com:2345".to_string()),
             statsd_prefix: Some("prefix2".to_string()),
+            prometheus_file: Some("/var/lib/node_exporter/mynet.prom".to_string()),
+            influxdb_server: Some("example.com:8090".to_string()),
+            influxdb_measurement: Some("mynet".to_string()),
+            cpu_affinity: vec![1],
+            priority: None,
+            busy_poll: Some(20),
+            latency_bypass: true,
+            early_data: true,
+            reorder_window: Some(20),
+            max_handshakes: 64,
+            max_peers: Some(100),
+            max_dials: 4,
+            dial_rate: Some(2),
             daemonize: true,
+            ephemeral: true,
             hook: None,
-            hooks: HashMap::new()
+            hooks: HashMap::new(),
+            auth_hook: Some("/usr/local/bin/vpncloud-auth".to_string()),
+            chaos: false
         }
     );
 }
+
+#[test]
+fn config_ephemeral() {
+    let config = Config { ephemeral: true, beacon_load: Some("/run/beacon".to_string()), ..Config::default() };
+    assert!(config.check_ephemeral().is_ok());
+    let config = Config { stats_file: Some("/var/log/vpncloud.stats".to_string()), ..config };
+    match config.check_ephemeral() {
+        Err(Error::InvalidConfigValue(_, option)) => assert_eq!(option, "stats-file"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    let config = Config { stats_file: None, beacon_store: Some("/run/beacon".to_string()), ..config };
+    assert!(config.check_ephemeral().is_err());
+}
+
+#[test]
+fn config_vxlan() {
+    assert!(Config::default().check_vxlan().is_ok());
+    let vxlan = VxlanConfig { listen: "127.0.0.1:4790".to_string(), remote: None };
+    let config = Config { vxlan: Some(vxlan), ..Config::default() };
+    match config.check_vxlan() {
+        Err(Error::InvalidConfigValue(_, option)) => assert_eq!(option, "type tun"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    let config = Config { device_type: Type::Tap, ..config };
+    assert!(config.check_vxlan().is_ok());
+    let config = Config { ip: Some("10.0.0.1/24".to_string()), ..config };
+    assert!(config.check_vxlan().is_err());
+}
+
+#[test]
+fn port_forwarding_description() {
+    assert_eq!(Config::default().port_forwarding_description(), "VpnCloud");
+    let config = Config {
+        network_id: Some("office".to_string()),
+        node_name: Some("büro\n1".to_string()),
+        ..Config::default()
+    };
+    assert_eq!(config.port_forwarding_description(), "VpnCloud office (b_ro_1)");
+    let config = Config { network_id: Some("x".repeat(100)), ..Config::default() };
+    assert_eq!(config.port_forwarding_description().len(), MAX_PORT_FORWARDING_DESCRIPTION_LEN);
+}
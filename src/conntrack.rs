@@ -0,0 +1,142 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+use fnv::FnvHasher;
+use std::{collections::HashMap, hash::BuildHasherDefault, marker::PhantomData};
+
+use crate::{
+    types::Address,
+    util::{Duration, Time, TimeSource},
+};
+
+type Hash = BuildHasherDefault<FnvHasher>;
+
+/// A connection identified by protocol, addresses and ports (0 for protocols without ports)
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Flow {
+    pub protocol: u8,
+    pub src: Address,
+    pub dst: Address,
+    pub src_port: u16,
+    pub dst_port: u16,
+}
+
+impl Flow {
+    /// Returns the flow of the packets in the opposite direction
+    pub fn reverse(&self) -> Self {
+        Flow { protocol: self.protocol, src: self.dst, dst: self.src, src_port: self.dst_port, dst_port: self.src_port }
+    }
+}
+
+/// A bounded table of connections that have been allowed by the firewall
+///
+/// Entries expire after they have not been used for the configured timeout. When the table is
+/// full, new connections are not tracked until old entries have expired.
+pub struct ConnTrack<TS: TimeSource> {
+    flows: HashMap<Flow, Time, Hash>,
+    max_entries: usize,
+    timeout: Duration,
+    _dummy: PhantomData<TS>,
+}
+
+impl<TS: TimeSource> ConnTrack<TS> {
+    pub fn new(max_entries: usize, timeout: Duration) -> Self {
+        Self { flows: HashMap::default(), max_entries, timeout, _dummy: PhantomData }
+    }
+
+    /// Adds the flow to the table or refreshes its timeout
+    ///
+    /// Returns false if the flow could not be tracked because the table is full.
+    pub fn track(&mut self, flow: Flow) -> bool {
+        let timeout = TS::now() + self.timeout as Time;
+        if let Some(entry) = self.flows.get_mut(&flow) {
+            *entry = timeout;
+            return true;
+        }
+        if self.flows.len() >= self.max_entries {
+            // COLD PATH
+            self.housekeep();
+            if self.flows.len() >= self.max_entries {
+                return false;
+            }
+        }
+        self.flows.insert(flow, timeout);
+        true
+    }
+
+    /// Checks whether the flow is a reply to a tracked connection and refreshes that connection
+    pub fn is_reply(&mut self, flow: &Flow) -> bool {
+        let now = TS::now();
+        match self.flows.get_mut(&flow.reverse()) {
+            Some(entry) if *entry >= now => {
+                *entry = now + self.timeout as Time;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn housekeep(&mut self) {
+        let now = TS::now();
+        self.flows.retain(|_, timeout| *timeout >= now);
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+}
+
+#[cfg(test)]
+use crate::util::MockTimeSource;
+
+#[cfg(test)]
+fn flow(src: u8, dst: u8, src_port: u16, dst_port: u16) -> Flow {
+    use std::net::Ipv4Addr;
+    Flow {
+        protocol: 6,
+        src: Address::from_ipv4(Ipv4Addr::new(10, 0, 0, src)),
+        dst: Address::from_ipv4(Ipv4Addr::new(10, 0, 0, dst)),
+        src_port,
+        dst_port,
+    }
+}
+
+#[test]
+fn conntrack_replies() {
+    MockTimeSource::set_time(0);
+    let mut conntrack = ConnTrack::<MockTimeSource>::new(10, 60);
+    assert!(conntrack.track(flow(1, 2, 40000, 22)));
+    assert!(conntrack.is_reply(&flow(2, 1, 22, 40000)));
+    assert!(!conntrack.is_reply(&flow(1, 2, 40000, 22)));
+    assert!(!conntrack.is_reply(&flow(2, 1, 23, 40000)));
+    assert!(!conntrack.is_reply(&flow(3, 1, 22, 40000)));
+    // Replies keep the connection alive
+    MockTimeSource::set_time(50);
+    assert!(conntrack.is_reply(&flow(2, 1, 22, 40000)));
+    MockTimeSource::set_time(100);
+    assert!(conntrack.is_reply(&flow(2, 1, 22, 40000)));
+    MockTimeSource::set_time(200);
+    assert!(!conntrack.is_reply(&flow(2, 1, 22, 40000)));
+    conntrack.housekeep();
+    assert!(conntrack.is_empty());
+}
+
+#[test]
+fn conntrack_bounded() {
+    MockTimeSource::set_time(0);
+    let mut conntrack = ConnTrack::<MockTimeSource>::new(2, 60);
+    assert!(conntrack.track(flow(1, 2, 40000, 22)));
+    assert!(conntrack.track(flow(1, 2, 40001, 22)));
+    assert!(!conntrack.track(flow(1, 2, 40002, 22)));
+    // Existing flows can still be refreshed
+    assert!(conntrack.track(flow(1, 2, 40000, 22)));
+    assert_eq!(2, conntrack.len());
+    MockTimeSource::set_time(61);
+    assert!(conntrack.track(flow(1, 2, 40002, 22)));
+    assert_eq!(1, conntrack.len());
+}
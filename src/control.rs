@@ -0,0 +1,355 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Control socket to connect and disconnect peers of a running instance, see `--control-socket`
+//!
+//! The protocol is line based: the client sends one command like `connect 1.2.3.4:3210 persist` and the server
+//! answers with `ok` or `error: <message>` once the command has been executed. Commands that query the instance,
+//! like `peers`, send their output lines before the final `ok`. The `stats` command is answered by the control thread
+//! itself with the latest statistics, so it also works when the instance is busy. The same commands can be sent over
+//! the network, see `remote`.
+
+use std::{
+    fmt, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::error::Error;
+
+pub const DEFAULT_CONTROL_SOCKET: &str = "/run/vpncloud.sock";
+
+/// Time to wait for the instance to execute a command
+pub(crate) const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest accepted line, commands and the auth line of the remote control are well below this
+const MAX_LINE_LEN: u64 = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlCommand {
+    /// Connect to a peer, if `persist` is set also after restarts
+    Connect { address: String, persist: bool },
+    /// Close the connection to a peer and stop reconnecting, if `persist` is set also remove it from the saved peers
+    Disconnect { address: String, persist: bool },
+    /// List the connection states of the peers
+    Peers,
+    /// Show the latest statistics
+    Stats,
+    /// List the banned addresses
+    Bans,
+    /// Lift the ban of an address, or of all addresses if the address is `all`
+    Unban { address: String },
+    /// Rejoin the network if the instance is idle
+    Wake,
+}
+
+impl ControlCommand {
+    pub fn parse(line: &str) -> Result<Self, Error> {
+        let mut parts = line.split_whitespace();
+        let command = parts.next().ok_or(Error::Parse("Empty command"))?;
+        if command == "peers" || command == "stats" || command == "bans" || command == "wake" {
+            if parts.next().is_some() {
+                return Err(Error::Parse("Too many command arguments"));
+            }
+            return Ok(match command {
+                "peers" => ControlCommand::Peers,
+                "stats" => ControlCommand::Stats,
+                "bans" => ControlCommand::Bans,
+                _ => ControlCommand::Wake,
+            });
+        }
+        let address = parts.next().ok_or(Error::Parse("Address missing"))?.to_string();
+        if command == "unban" {
+            if parts.next().is_some() {
+                return Err(Error::Parse("Too many command arguments"));
+            }
+            return Ok(ControlCommand::Unban { address });
+        }
+        let persist = match parts.next() {
+            Some("persist") => true,
+            Some(_) => return Err(Error::Parse("Invalid command option")),
+            None => false,
+        };
+        if parts.next().is_some() {
+            return Err(Error::Parse("Too many command arguments"));
+        }
+        match command {
+            "connect" => Ok(ControlCommand::Connect { address, persist }),
+            "disconnect" => Ok(ControlCommand::Disconnect { address, persist }),
+            _ => Err(Error::Parse("Unknown command")),
+        }
+    }
+}
+
+impl fmt::Display for ControlCommand {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let (command, address, persist) = match self {
+            ControlCommand::Connect { address, persist } => ("connect", address, persist),
+            ControlCommand::Disconnect { address, persist } => ("disconnect", address, persist),
+            ControlCommand::Peers => return write!(formatter, "peers"),
+            ControlCommand::Stats => return write!(formatter, "stats"),
+            ControlCommand::Bans => return write!(formatter, "bans"),
+            ControlCommand::Wake => return write!(formatter, "wake"),
+            ControlCommand::Unban { address } => return write!(formatter, "unban {}", address),
+        };
+        write!(formatter, "{} {}", command, address)?;
+        if *persist {
+            write!(formatter, " persist")?;
+        }
+        Ok(())
+    }
+}
+
+/// A command received on the control socket that is waiting for its result
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    reply: mpsc::Sender<Result<String, String>>,
+}
+
+impl ControlRequest {
+    pub fn reply(self, result: Result<String, Error>) {
+        // The client might already be gone
+        self.reply.send(result.map_err(|err| err.to_string())).ok();
+    }
+}
+
+/// Passes command lines from a listener thread to the instance
+#[derive(Clone)]
+pub struct CommandHandler {
+    requests: mpsc::Sender<ControlRequest>,
+    stats: Arc<Mutex<String>>,
+}
+
+impl CommandHandler {
+    /// Executes the command line and returns its output or the error message
+    pub fn execute(&self, line: &str) -> Result<String, String> {
+        match ControlCommand::parse(line) {
+            Ok(ControlCommand::Stats) => {
+                let stats = self.stats.lock().expect("Lock poisoned").clone();
+                if stats.is_empty() {
+                    Err("No statistics written yet".to_string())
+                } else {
+                    Ok(stats)
+                }
+            }
+            Ok(command) => {
+                info!("Control command: {}", command);
+                let (reply, result) = mpsc::channel();
+                if self.requests.send(ControlRequest { command, reply }).is_err() {
+                    return Err("Instance is shutting down".to_string());
+                }
+                result.recv_timeout(COMMAND_TIMEOUT).unwrap_or_else(|_| Err("Command timed out".to_string()))
+            }
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
+
+pub struct ControlServer {
+    handler: CommandHandler,
+    requests: mpsc::Receiver<ControlRequest>,
+}
+
+impl Default for ControlServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ControlServer {
+    /// Creates a server without any listener, see `listen` and `remote::listen`
+    pub fn new() -> Self {
+        let (sender, requests) = mpsc::channel();
+        let handler = CommandHandler { requests: sender, stats: Arc::new(Mutex::new(String::new())) };
+        Self { handler, requests }
+    }
+
+    /// Opens the socket and accepts commands in a background thread
+    pub fn start(path: &str) -> Result<Self, Error> {
+        let server = Self::new();
+        server.listen(path)?;
+        Ok(server)
+    }
+
+    /// Accepts commands on the socket in a background thread
+    pub fn listen(&self, path: &str) -> Result<(), Error> {
+        let path = Path::new(path);
+        if path.exists() {
+            fs::remove_file(path).map_err(|e| Error::FileIo("Failed to remove old control socket", e))?;
+        }
+        let listener = UnixListener::bind(path).map_err(|e| Error::FileIo("Failed to open control socket", e))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+            .map_err(|e| Error::FileIo("Failed to set permissions on control socket", e))?;
+        let handler = self.handler();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(err) = handle_connection(stream, &handler) {
+                            warn!("Failed to handle control request: {}", err)
+                        }
+                    }
+                    Err(err) => {
+                        // Errors like running out of file descriptors are temporary
+                        error!("Failed to accept control connection: {}", err);
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// The handler that listeners use to pass commands to the instance
+    pub fn handler(&self) -> CommandHandler {
+        self.handler.clone()
+    }
+
+    /// The report that is sent for the `stats` command, updated by `stats::ControlSink`
+    pub fn latest_stats(&self) -> Arc<Mutex<String>> {
+        self.handler.stats.clone()
+    }
+
+    /// Returns the next pending command, if any
+    pub fn next_request(&self) -> Option<ControlRequest> {
+        self.requests.try_recv().ok()
+    }
+}
+
+fn handle_connection(stream: UnixStream, handler: &CommandHandler) -> Result<(), io::Error> {
+    // A client that does not send its command must not block the socket for others
+    stream.set_read_timeout(Some(COMMAND_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut line = String::new();
+    read_limited_line(&mut reader, &mut line)?;
+    write_response(&mut writer, handler.execute(&line))
+}
+
+/// Reads a line of at most `MAX_LINE_LEN` bytes
+pub(crate) fn read_limited_line<R: BufRead>(reader: &mut R, line: &mut String) -> Result<(), io::Error> {
+    reader.take(MAX_LINE_LEN).read_line(line)?;
+    if !line.ends_with('\n') && line.len() as u64 >= MAX_LINE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Line too long"));
+    }
+    Ok(())
+}
+
+/// Writes the output followed by `ok` or the error message
+pub(crate) fn write_response<W: Write>(writer: &mut W, result: Result<String, String>) -> Result<(), io::Error> {
+    match result {
+        Ok(output) => writeln!(writer, "{}ok", output),
+        Err(msg) => writeln!(writer, "error: {}", msg),
+    }
+}
+
+/// Reads the output lines up to the final `ok` or error message
+pub(crate) fn read_response<R: BufRead>(reader: &mut R) -> Result<String, Error> {
+    let mut output = String::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).map_err(|e| Error::FileIo("Failed to read control response", e))? == 0 {
+            return Err(Error::Control("Connection closed".to_string()));
+        }
+        match line.trim() {
+            "ok" => return Ok(output),
+            res if res.starts_with("error: ") => {
+                return Err(Error::Control(res.trim_start_matches("error: ").to_string()))
+            }
+            _ => output.push_str(&line),
+        }
+    }
+}
+
+/// Sends a command to a running instance and waits for the result
+///
+/// Returns the output of the command, which is empty for commands that do not query anything.
+pub fn send_command(path: &str, command: &ControlCommand) -> Result<String, Error> {
+    let stream = UnixStream::connect(path).map_err(|e| Error::FileIo("Failed to connect to control socket", e))?;
+    stream
+        .set_read_timeout(Some(COMMAND_TIMEOUT * 2))
+        .map_err(|e| Error::FileIo("Failed to set timeout on control socket", e))?;
+    let mut writer = stream.try_clone().map_err(|e| Error::FileIo("Failed to use control socket", e))?;
+    writeln!(writer, "{}", command).map_err(|e| Error::FileIo("Failed to send control command", e))?;
+    read_response(&mut BufReader::new(stream))
+}
+
+#[test]
+fn control_command_parse() {
+    let command = ControlCommand::Connect { address: "node1:3210".to_string(), persist: true };
+    assert_eq!(command, ControlCommand::parse("connect node1:3210 persist\n").unwrap());
+    assert_eq!(command, ControlCommand::parse(&command.to_string()).unwrap());
+    let command = ControlCommand::Disconnect { address: "1.2.3.4:3210".to_string(), persist: false };
+    assert_eq!(command, ControlCommand::parse(&command.to_string()).unwrap());
+    assert!(ControlCommand::parse("").is_err());
+    assert!(ControlCommand::parse("connect").is_err());
+    assert!(ControlCommand::parse("reconnect node1").is_err());
+    assert!(ControlCommand::parse("connect node1 forever").is_err());
+    assert_eq!(ControlCommand::Peers, ControlCommand::parse("peers\n").unwrap());
+    assert_eq!(ControlCommand::Peers, ControlCommand::parse(&ControlCommand::Peers.to_string()).unwrap());
+    assert!(ControlCommand::parse("peers node1").is_err());
+    assert_eq!(ControlCommand::Stats, ControlCommand::parse("stats\n").unwrap());
+    assert_eq!(ControlCommand::Bans, ControlCommand::parse("bans\n").unwrap());
+    let command = ControlCommand::Unban { address: "1.2.3.4".to_string() };
+    assert_eq!(command, ControlCommand::parse(&command.to_string()).unwrap());
+    assert!(ControlCommand::parse("unban").is_err());
+    assert!(ControlCommand::parse("unban all persist").is_err());
+    assert_eq!(ControlCommand::Wake, ControlCommand::parse(&ControlCommand::Wake.to_string()).unwrap());
+}
+
+#[test]
+fn control_line_limit() {
+    let mut line = String::new();
+    read_limited_line(&mut io::Cursor::new("peers\nstats\n"), &mut line).unwrap();
+    assert_eq!(line, "peers\n");
+    let long = "a".repeat(MAX_LINE_LEN as usize * 2);
+    assert!(read_limited_line(&mut io::Cursor::new(long), &mut String::new()).is_err());
+}
+
+#[test]
+fn control_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("control.sock");
+    let path = path.to_str().unwrap();
+    let server = ControlServer::start(path).unwrap();
+    let stats = server.latest_stats();
+    let responder = thread::spawn(move || {
+        let mut handled = 0;
+        while handled < 3 {
+            if let Some(request) = server.next_request() {
+                let result = match request.command {
+                    ControlCommand::Connect { .. } => Ok(String::new()),
+                    ControlCommand::Disconnect { .. } => Err(Error::Message("Not connected")),
+                    ControlCommand::Peers => Ok("node1 established 5 1\n".to_string()),
+                    ControlCommand::Stats
+                    | ControlCommand::Bans
+                    | ControlCommand::Unban { .. }
+                    | ControlCommand::Wake => unreachable!(),
+                };
+                request.reply(result);
+                handled += 1;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    });
+    let command = ControlCommand::Connect { address: "node1".to_string(), persist: false };
+    assert_eq!(send_command(path, &command).unwrap(), "");
+    let command = ControlCommand::Disconnect { address: "node1".to_string(), persist: false };
+    match send_command(path, &command) {
+        Err(Error::Control(msg)) => assert_eq!(msg, "Message error: Not connected"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert_eq!(send_command(path, &ControlCommand::Peers).unwrap(), "node1 established 5 1\n");
+    // Statistics are answered without the instance
+    assert!(send_command(path, &ControlCommand::Stats).is_err());
+    *stats.lock().unwrap() = "peers:\n".to_string();
+    assert_eq!(send_command(path, &ControlCommand::Stats).unwrap(), "peers:\n");
+    responder.join().unwrap();
+}
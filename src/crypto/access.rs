@@ -0,0 +1,144 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Time windows in which the peers with a specific trusted key are allowed
+//!
+//! A schedule consists of windows like `Mon-Fri 08:00-18:00`, `Sat,Sun 10:00-14:00` or `22:00-06:00`, the days are
+//! optional. Times are in the local time zone of the node. Windows that end before they start extend into the next
+//! day, their days refer to the start of the window.
+
+use crate::{error::Error, timestamp::local_offset, util::Time};
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const ALL_DAYS: u8 = 0x7f;
+const DAY_SECS: Time = 24 * 3600;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Window {
+    /// Bit mask of the weekdays, Monday is the lowest bit
+    days: u8,
+    /// Minutes since midnight
+    start: u16,
+    end: u16,
+}
+
+fn parse_day(name: &str) -> Option<u32> {
+    DAYS.iter().position(|day| day.eq_ignore_ascii_case(name)).map(|day| day as u32)
+}
+
+fn parse_days(text: &str) -> Option<u8> {
+    let mut days = 0;
+    for part in text.split(',') {
+        let (first, last) = match part.find('-') {
+            Some(pos) => (parse_day(&part[..pos])?, parse_day(&part[pos + 1..])?),
+            None => (parse_day(part)?, parse_day(part)?),
+        };
+        // Ranges like Fri-Mon wrap around the weekend
+        let mut day = first;
+        loop {
+            days |= 1 << day;
+            if day == last {
+                break;
+            }
+            day = (day + 1) % 7;
+        }
+    }
+    Some(days)
+}
+
+fn parse_time(text: &str) -> Option<u16> {
+    let pos = text.find(':')?;
+    let hours = text[..pos].parse::<u16>().ok()?;
+    let minutes = text[pos + 1..].parse::<u16>().ok()?;
+    if minutes >= 60 || hours > 24 || hours == 24 && minutes > 0 {
+        return None;
+    }
+    Some(hours * 60 + minutes)
+}
+
+impl Window {
+    fn parse(text: &str) -> Option<Self> {
+        let mut parts = text.split_whitespace();
+        let (days, times) = match (parts.next()?, parts.next(), parts.next()) {
+            (times, None, None) => (ALL_DAYS, times),
+            (days, Some(times), None) => (parse_days(days)?, times),
+            _ => return None,
+        };
+        let pos = times.find('-')?;
+        let (start, end) = (parse_time(&times[..pos])?, parse_time(&times[pos + 1..])?);
+        if start == end {
+            return None;
+        }
+        Some(Self { days, start, end })
+    }
+
+    /// Whether the window contains the minute of the weekday, Monday is 0
+    fn contains(&self, weekday: u32, minute: u16) -> bool {
+        let today = self.days & (1 << weekday) != 0;
+        if self.start < self.end {
+            today && minute >= self.start && minute < self.end
+        } else {
+            let yesterday = self.days & (1 << ((weekday + 6) % 7)) != 0;
+            today && minute >= self.start || yesterday && minute < self.end
+        }
+    }
+}
+
+/// The windows in which a peer may be connected
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessSchedule {
+    windows: Vec<Window>,
+}
+
+impl AccessSchedule {
+    pub fn parse(windows: &[String]) -> Result<Self, Error> {
+        if windows.is_empty() {
+            return Err(Error::InvalidConfig("Access schedule without windows"));
+        }
+        let windows = windows
+            .iter()
+            .map(|w| Window::parse(w).ok_or_else(|| Error::InvalidConfigValue("Invalid access window", w.clone())))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { windows })
+    }
+
+    /// Whether the peer is allowed at the wall clock time given in seconds since the epoch
+    pub fn allows(&self, time: Time) -> bool {
+        self.allows_local(time + Time::from(local_offset(time).unwrap_or(0)))
+    }
+
+    fn allows_local(&self, local: Time) -> bool {
+        // The epoch was a Thursday
+        let weekday = (local.div_euclid(DAY_SECS) + 3).rem_euclid(7) as u32;
+        let minute = (local.rem_euclid(DAY_SECS) / 60) as u16;
+        self.windows.iter().any(|window| window.contains(weekday, minute))
+    }
+}
+
+#[test]
+fn access_windows() {
+    let at = |day: Time, hours: Time, minutes: Time| (4 + day) * DAY_SECS + hours * 3600 + minutes * 60;
+    // 1970-01-05 was a Monday
+    let schedule =
+        AccessSchedule::parse(&["Mon-Fri 08:00-18:00".to_string(), "sat,SUN 22:00-02:00".to_string()]).unwrap();
+    assert!(schedule.allows_local(at(0, 8, 0)));
+    assert!(schedule.allows_local(at(4, 17, 59)));
+    assert!(!schedule.allows_local(at(4, 18, 0)));
+    assert!(!schedule.allows_local(at(0, 7, 59)));
+    assert!(!schedule.allows_local(at(5, 12, 0)));
+    assert!(schedule.allows_local(at(5, 23, 0)));
+    // Windows over midnight belong to the day they start
+    assert!(schedule.allows_local(at(7, 1, 0)));
+    assert!(!schedule.allows_local(at(5, 1, 0)));
+    let daily = AccessSchedule::parse(&["00:00-24:00".to_string()]).unwrap();
+    assert!(daily.allows_local(at(2, 23, 59)));
+    let weekend = AccessSchedule::parse(&["Fri-Mon 12:00-13:00".to_string()]).unwrap();
+    assert!(weekend.allows_local(at(6, 12, 30)));
+    assert!(!weekend.allows_local(at(1, 12, 30)));
+    assert!(AccessSchedule::parse(&[]).is_err());
+    assert!(AccessSchedule::parse(&["Mon 08:00".to_string()]).is_err());
+    assert!(AccessSchedule::parse(&["Monday 08:00-09:00".to_string()]).is_err());
+    assert!(AccessSchedule::parse(&["08:00-08:00".to_string()]).is_err());
+    assert!(AccessSchedule::parse(&["08:60-09:00".to_string()]).is_err());
+}
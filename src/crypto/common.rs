@@ -1,24 +1,38 @@
 use super::{
-    core::{test_speed, CryptoCore},
+    access::AccessSchedule,
+    core::{algorithm_name, test_speed, CpuFeatures, CryptoCore, EXTRA_LEN},
     init::{self, InitResult, InitState, CLOSING},
     rotate::RotationState,
+    stats::SessionStats,
 };
 use crate::{
-    error::Error,
+    error::{Error, Phase},
     types::NodeId,
-    util::{from_base62, to_base62, MsgBuffer},
+    util::{from_base62, to_base62, MsgBuffer, Time, TimeSource},
 };
+use byteorder::{ByteOrder, NetworkEndian};
 use ring::{
     aead::{self, Algorithm, LessSafeKey, UnboundKey},
     agreement::{EphemeralPrivateKey, UnparsedPublicKey},
-    pbkdf2,
+    constant_time, hmac, pbkdf2,
     rand::{SecureRandom, SystemRandom},
     signature::{Ed25519KeyPair, KeyPair, ED25519_PUBLIC_KEY_LEN},
 };
 use smallvec::{smallvec, SmallVec};
-use std::{fmt::Debug, io::Read, num::NonZeroU32, sync::Arc, time::Duration};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt::Debug,
+    io::Read,
+    marker::PhantomData,
+    mem,
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 const SALT: &[u8; 32] = b"vpncloudVPNCLOUDvpncl0udVpnCloud";
+const NETWORK_SECRET_SALT: &[u8; 32] = b"vpncloudNETWORKsecretVpnCloudNet";
 const INIT_MESSAGE_FIRST_BYTE: u8 = 0xff;
 const MESSAGE_TYPE_ROTATION: u8 = 0x10;
 
@@ -36,6 +50,16 @@ const SPEED_TEST_TIME: f32 = 0.1;
 
 const ROTATE_INTERVAL: usize = 120;
 
+/// Length of the tag that proves the knowledge of the network secret
+const NETWORK_PROOF_TAG_LEN: usize = 16;
+/// Length of the timestamp and the tag that prove the knowledge of the network secret
+const NETWORK_PROOF_LEN: usize = 8 + NETWORK_PROOF_TAG_LEN;
+/// Maximal age of a network proof in seconds, this also limits the tolerated clock difference
+const NETWORK_PROOF_MAX_AGE: i64 = 120;
+
+/// Maximal size of the packets attached to the peng message, so it still fits into a normal MTU
+pub const MAX_EARLY_DATA_LEN: usize = 1000;
+
 pub trait Payload: Debug + PartialEq + Sized {
     fn write_to(&self, buffer: &mut MsgBuffer);
     fn read_from<R: Read>(r: R) -> Result<Self, Error>;
@@ -47,6 +71,67 @@ pub struct Algorithms {
     pub allow_unencrypted: bool,
 }
 
+/// Key derived from the network secret together with the proofs that have been accepted recently
+///
+/// Each proof is only accepted once while its timestamp is valid, so recorded init messages can not be replayed to
+/// find the node.
+pub struct NetworkSecret {
+    key: hmac::Key,
+    seen: Mutex<HashMap<[u8; NETWORK_PROOF_TAG_LEN], Time>>,
+}
+
+impl NetworkSecret {
+    fn new(key: hmac::Key) -> Self {
+        Self { key, seen: Mutex::new(HashMap::new()) }
+    }
+
+    /// Appends the timestamp and the tag over the message and the timestamp
+    fn sign(&self, buffer: &mut MsgBuffer, now: Time) {
+        let len = buffer.len();
+        buffer.set_length(len + NETWORK_PROOF_LEN);
+        let data = buffer.message_mut();
+        NetworkEndian::write_i64(&mut data[len..len + 8], now);
+        let tag = hmac::sign(&self.key, &data[..len + 8]);
+        data[len + 8..].copy_from_slice(&tag.as_ref()[..NETWORK_PROOF_TAG_LEN]);
+    }
+
+    /// Checks the proof at the end of the message and removes it
+    fn verify(&self, buffer: &mut MsgBuffer, now: Time) -> Result<(), Error> {
+        let len = match buffer.len().checked_sub(NETWORK_PROOF_LEN) {
+            Some(len) => len,
+            None => return Err(Error::CryptoInit("Missing network proof")),
+        };
+        let data = buffer.message();
+        let tag = hmac::sign(&self.key, &data[..len + 8]);
+        if constant_time::verify_slices_are_equal(&tag.as_ref()[..NETWORK_PROOF_TAG_LEN], &data[len + 8..]).is_err() {
+            return Err(Error::CryptoInit("Invalid network proof"));
+        }
+        let time = NetworkEndian::read_i64(&data[len..len + 8]);
+        if (now - time).abs() > NETWORK_PROOF_MAX_AGE {
+            return Err(Error::CryptoInit("Network proof expired, check the clocks"));
+        }
+        let mut proof = [0; NETWORK_PROOF_TAG_LEN];
+        proof.copy_from_slice(&data[len + 8..]);
+        let mut seen = self.seen.lock().expect("Lock poisoned");
+        // Proofs that are too old to be accepted anyway do not need to be remembered
+        seen.retain(|_, time| (now - *time).abs() <= NETWORK_PROOF_MAX_AGE);
+        if seen.insert(proof, time).is_some() {
+            return Err(Error::CryptoInit("Network proof replayed"));
+        }
+        buffer.set_length(len);
+        Ok(())
+    }
+}
+
+/// Settings that depend on the peer or apply to all peers, shared by all connections of a node
+#[derive(Clone, Default)]
+pub struct PeerOptions {
+    pub peer_algorithms: Arc<HashMap<Ed25519PublicKey, Algorithms>>,
+    pub peer_schedules: Arc<HashMap<Ed25519PublicKey, AccessSchedule>>,
+    pub network_secret: Option<Arc<NetworkSecret>>,
+    pub padding: bool,
+}
+
 #[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
 pub struct Config {
@@ -55,6 +140,16 @@ pub struct Config {
     pub public_key: Option<String>,
     pub trusted_keys: Vec<String>,
     pub algorithms: Vec<String>,
+    /// A second private key whose public key is trusted in addition to the own one, used for key migration
+    pub secondary_key: Option<String>,
+    /// A secret that must be proven in every init message, otherwise the message is silently ignored
+    pub network_secret: Option<String>,
+    /// Algorithms to use for specific trusted keys (given as key or key name), algorithms prefixed with `-` are forbidden
+    pub peer_algorithms: HashMap<String, Vec<String>>,
+    /// Time windows for specific trusted keys (given as key or key name), outside of them the peers are rejected
+    pub peer_schedules: HashMap<String, Vec<String>>,
+    /// Pad init messages as well as node info, keepalive and close messages to uniform sizes
+    pub padding: bool,
 }
 
 pub struct Crypto {
@@ -62,6 +157,8 @@ pub struct Crypto {
     key_pair: Arc<Ed25519KeyPair>,
     trusted_keys: Arc<[Ed25519PublicKey]>,
     algorithms: Algorithms,
+    options: PeerOptions,
+    key_names: HashMap<Ed25519PublicKey, String>,
 }
 
 impl Crypto {
@@ -86,51 +183,148 @@ impl Crypto {
         Ok((unencrypted, algos))
     }
 
-    pub fn new(node_id: NodeId, config: &Config) -> Result<Self, Error> {
-        let key_pair = if let Some(priv_key) = &config.private_key {
+    /// Restricts the own algorithms to the ones allowed for a peer, names prefixed with `-` are forbidden
+    fn restrict_algorithms(algorithms: &Algorithms, names: &[String]) -> Result<Algorithms, Error> {
+        let (forbidden, allowed): (Vec<_>, Vec<_>) = names.iter().cloned().partition(|name| name.starts_with('-'));
+        let forbidden = forbidden.into_iter().map(|name| name[1..].to_string()).collect::<Vec<_>>();
+        let (allow_unencrypted, allowed) = if allowed.is_empty() {
+            (algorithms.allow_unencrypted, algorithms.algorithm_speeds.iter().map(|(a, _)| *a).collect())
+        } else {
+            let (unencrypted, allowed) = Self::parse_algorithms(&allowed)?;
+            (algorithms.allow_unencrypted && unencrypted, allowed)
+        };
+        let (forbid_unencrypted, forbidden) =
+            if forbidden.is_empty() { (false, vec![]) } else { Self::parse_algorithms(&forbidden)? };
+        let restricted = Algorithms {
+            algorithm_speeds: algorithms
+                .algorithm_speeds
+                .iter()
+                .filter(|(a, _)| allowed.contains(a) && !forbidden.contains(a))
+                .cloned()
+                .collect(),
+            allow_unencrypted: allow_unencrypted && !forbid_unencrypted,
+        };
+        if restricted.algorithm_speeds.is_empty() && !restricted.allow_unencrypted {
+            return Err(Error::InvalidConfig("No allowed algorithm left for peer"));
+        }
+        Ok(restricted)
+    }
+
+    fn load_keypair(config: &Config) -> Result<Ed25519KeyPair, Error> {
+        if let Some(priv_key) = &config.private_key {
             if let Some(pub_key) = &config.public_key {
-                Self::parse_keypair(priv_key, pub_key)?
+                Self::parse_keypair(priv_key, pub_key)
             } else {
-                Self::parse_private_key(priv_key)?
+                Self::parse_private_key(priv_key)
             }
         } else if let Some(password) = &config.password {
-            Self::keypair_from_password(password)
+            Ok(Self::keypair_from_password(password))
         } else {
-            return Err(Error::InvalidConfig("Either private_key or password must be set"));
-        };
+            Err(Error::InvalidConfig("Either private_key or password must be set"))
+        }
+    }
+
+    /// Returns the public key of the node as configured
+    pub fn own_public_key(config: &Config) -> Result<Ed25519PublicKey, Error> {
+        let mut key = [0; ED25519_PUBLIC_KEY_LEN];
+        key.clone_from_slice(Self::load_keypair(config)?.public_key().as_ref());
+        Ok(key)
+    }
+
+    pub fn new(node_id: NodeId, config: &Config) -> Result<Self, Error> {
+        let key_pair = Self::load_keypair(config)?;
         let mut trusted_keys = vec![];
+        let mut key_names = HashMap::new();
         for tn in &config.trusted_keys {
-            trusted_keys.push(Self::parse_public_key(tn)?);
+            // Keys can be given a name in the form name:key
+            let (name, key) = match tn.rfind(':') {
+                Some(pos) => (Some(&tn[..pos]), &tn[pos + 1..]),
+                None => (None, tn as &str),
+            };
+            let key = Self::parse_public_key(key)?;
+            if let Some(name) = name {
+                key_names.insert(key, name.to_string());
+            }
+            trusted_keys.push(key);
         }
         if trusted_keys.is_empty() {
             info!("Trusted keys not set, trusting only own public key");
             let mut key = [0; ED25519_PUBLIC_KEY_LEN];
             key.clone_from_slice(key_pair.public_key().as_ref());
             trusted_keys.push(key);
+            if let Some(secondary_key) = &config.secondary_key {
+                info!("Also trusting public key of secondary key");
+                let secondary = Self::parse_private_key(secondary_key)?;
+                key.clone_from_slice(secondary.public_key().as_ref());
+                trusted_keys.push(key);
+            }
+        } else if config.secondary_key.is_some() {
+            warn!("Secondary key is ignored as trusted keys are set, add its public key to the trusted keys instead");
         }
         let (unencrypted, allowed_algos) = Self::parse_algorithms(&config.algorithms)?;
         if unencrypted {
             warn!("Crypto settings allow unencrypted connections")
         }
         let mut algos = Algorithms { algorithm_speeds: smallvec![], allow_unencrypted: unencrypted };
+        let features = CpuFeatures::detect();
+        info!("Crypto implementations: {}", features.describe());
+        if !(features.aes && features.clmul) && cfg!(any(target_arch = "arm", target_arch = "aarch64")) {
+            info!("This CPU has no ARMv8 crypto extensions, ChaCha20 is usually the fastest algorithm here");
+        }
         let duration = Duration::from_secs_f32(SPEED_TEST_TIME);
         let mut speeds = Vec::new();
         for algo in allowed_algos {
             let speed = test_speed(algo, &duration);
             algos.algorithm_speeds.push((algo, speed as f32));
-            speeds.push((format!("{:?}", algo), speed as f32));
+            speeds.push((algorithm_name(algo), speed as f32));
         }
-        if !speeds.is_empty() {
+        if let Some((fastest, _)) = speeds.iter().max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal)) {
             info!(
-                "Crypto speeds: {}",
-                speeds.into_iter().map(|(a, s)| format!("{}: {:.1} MiB/s", a, s)).collect::<Vec<_>>().join(", ")
+                "Crypto speeds: {}, fastest: {}",
+                speeds.iter().map(|(a, s)| format!("{}: {:.1} MiB/s", a, s)).collect::<Vec<_>>().join(", "),
+                fastest
             );
         }
+        let mut peer_algorithms = HashMap::new();
+        for (peer, names) in &config.peer_algorithms {
+            let key = Self::lookup_key(peer, &key_names)?;
+            if !trusted_keys.contains(&key) {
+                return Err(Error::InvalidConfig("Algorithms set for a key that is not trusted"));
+            }
+            peer_algorithms.insert(key, Self::restrict_algorithms(&algos, names)?);
+        }
+        let mut peer_schedules = HashMap::new();
+        for (peer, windows) in &config.peer_schedules {
+            let key = Self::lookup_key(peer, &key_names)?;
+            if !trusted_keys.contains(&key) {
+                return Err(Error::InvalidConfig("Schedule set for a key that is not trusted"));
+            }
+            peer_schedules.insert(key, AccessSchedule::parse(windows)?);
+        }
+        let network_secret = config.network_secret.as_ref().map(|secret| {
+            info!("Network secret set, ignoring init messages without proof");
+            let mut key = [0; 32];
+            pbkdf2::derive(
+                pbkdf2::PBKDF2_HMAC_SHA256,
+                NonZeroU32::new(4096).unwrap(),
+                NETWORK_SECRET_SALT,
+                secret.as_bytes(),
+                &mut key,
+            );
+            Arc::new(NetworkSecret::new(hmac::Key::new(hmac::HMAC_SHA256, &key)))
+        });
         Ok(Self {
             node_id,
             key_pair: Arc::new(key_pair),
             trusted_keys: trusted_keys.into_boxed_slice().into(),
             algorithms: algos,
+            options: PeerOptions {
+                peer_algorithms: Arc::new(peer_algorithms),
+                peer_schedules: Arc::new(peer_schedules),
+                network_secret,
+                padding: config.padding,
+            },
+            key_names,
         })
     }
 
@@ -171,14 +365,14 @@ impl Crypto {
         Ok(keypair)
     }
 
-    fn parse_private_key(privkey: &str) -> Result<Ed25519KeyPair, Error> {
+    pub fn parse_private_key(privkey: &str) -> Result<Ed25519KeyPair, Error> {
         let privkey = from_base62(privkey).map_err(|_| Error::InvalidConfig("Failed to parse private key"))?;
         let keypair = Ed25519KeyPair::from_seed_unchecked(&privkey)
             .map_err(|_| Error::InvalidConfig("Key rejected by crypto library"))?;
         Ok(keypair)
     }
 
-    fn parse_public_key(pubkey: &str) -> Result<Ed25519PublicKey, Error> {
+    pub fn parse_public_key(pubkey: &str) -> Result<Ed25519PublicKey, Error> {
         let pubkey = from_base62(pubkey).map_err(|_| Error::InvalidConfig("Failed to parse public key"))?;
         if pubkey.len() != ED25519_PUBLIC_KEY_LEN {
             return Err(Error::InvalidConfig("Failed to parse public key"));
@@ -193,13 +387,46 @@ impl Crypto {
         Ok(to_base62(keypair.public_key().as_ref()))
     }
 
-    pub fn peer_instance<P: Payload>(&self, payload: P) -> PeerCrypto<P> {
+    /// Finds a trusted key by its name or parses the key
+    fn lookup_key(peer: &str, key_names: &HashMap<Ed25519PublicKey, String>) -> Result<Ed25519PublicKey, Error> {
+        match key_names.iter().find(|(_, name)| *name == peer) {
+            Some((key, _)) => Ok(*key),
+            None => Self::parse_public_key(peer),
+        }
+    }
+
+    /// Whether the key is the own public key, which is shared by all nodes in networks without trusted keys
+    pub fn is_own_key(&self, key: &Ed25519PublicKey) -> bool {
+        self.key_pair.public_key().as_ref() == key
+    }
+
+    /// Returns the name that was given to the trusted key
+    pub fn key_name(&self, key: &Ed25519PublicKey) -> Option<&str> {
+        self.key_names.get(key).map(|name| name as &str)
+    }
+
+    /// Whether the peer with the key is allowed at the wall clock time, keys without schedule are always allowed
+    pub fn allows_access(&self, key: &Ed25519PublicKey, time: Time) -> bool {
+        self.options.peer_schedules.get(key).map(|schedule| schedule.allows(time)).unwrap_or(true)
+    }
+
+    pub fn has_schedules(&self) -> bool {
+        !self.options.peer_schedules.is_empty()
+    }
+
+    /// Benchmarked speeds of the allowed algorithms in MiB/s
+    pub fn algorithm_speeds(&self) -> Vec<(&'static str, f32)> {
+        self.algorithms.algorithm_speeds.iter().map(|(algo, speed)| (algorithm_name(algo), *speed)).collect()
+    }
+
+    pub fn peer_instance<P: Payload, TS: TimeSource>(&self, payload: P) -> PeerCrypto<P, TS> {
         PeerCrypto::new(
             self.node_id,
             payload,
             self.key_pair.clone(),
             self.trusted_keys.clone(),
             self.algorithms.clone(),
+            self.options.clone(),
         )
     }
 }
@@ -213,7 +440,7 @@ pub enum MessageResult<P: Payload> {
     None,
 }
 
-pub struct PeerCrypto<P: Payload> {
+pub struct PeerCrypto<P: Payload, TS: TimeSource> {
     #[allow(dead_code)]
     node_id: NodeId,
     init: Option<InitState<P>>,
@@ -221,20 +448,46 @@ pub struct PeerCrypto<P: Payload> {
     unencrypted: bool,
     core: Option<CryptoCore>,
     rotate_counter: usize,
+    network_secret: Option<Arc<NetworkSecret>>,
+    peer_key: Option<Ed25519PublicKey>,
+    early_data: Option<Box<MsgBuffer>>,
+    stats: SessionStats,
+    _dummy_ts: PhantomData<TS>,
 }
 
-impl<P: Payload> PeerCrypto<P> {
+impl<P: Payload, TS: TimeSource> PeerCrypto<P, TS> {
     pub fn new(
         node_id: NodeId, init_payload: P, key_pair: Arc<Ed25519KeyPair>, trusted_keys: Arc<[Ed25519PublicKey]>,
-        algorithms: Algorithms,
+        algorithms: Algorithms, options: PeerOptions,
     ) -> Self {
+        let network_secret = options.network_secret.clone();
         Self {
             node_id,
-            init: Some(InitState::new(node_id, init_payload, key_pair, trusted_keys, algorithms)),
+            init: Some(InitState::new(node_id, init_payload, key_pair, trusted_keys, algorithms, options)),
             rotation: None,
             unencrypted: false,
             core: None,
             rotate_counter: 0,
+            network_secret,
+            peer_key: None,
+            early_data: None,
+            stats: SessionStats::default(),
+            _dummy_ts: PhantomData,
+        }
+    }
+
+    /// Appends a timestamp and a tag proving the knowledge of the network secret to an init message
+    fn add_network_proof(&self, buffer: &mut MsgBuffer) {
+        if let Some(ref secret) = self.network_secret {
+            secret.sign(buffer, TS::wall_clock())
+        }
+    }
+
+    /// Checks and removes the network proof of an init message, each proof is only accepted once
+    fn check_network_proof(&self, buffer: &mut MsgBuffer) -> Result<(), Error> {
+        match self.network_secret {
+            Some(ref secret) => secret.verify(buffer, TS::wall_clock()),
+            None => Ok(()),
         }
     }
 
@@ -269,30 +522,32 @@ impl<P: Payload> PeerCrypto<P> {
         } else {
             init.send_ping(out);
             out.prepend_byte(INIT_MESSAGE_FIRST_BYTE);
+            self.add_network_proof(out);
             Ok(())
         }
     }
 
+    /// Returns the public key of the peer once the handshake succeeded
+    pub fn peer_key(&self) -> Option<&Ed25519PublicKey> {
+        self.peer_key.as_ref()
+    }
+
     pub fn has_init(&self) -> bool {
         self.init.is_some()
     }
 
+    /// Whether this node answered the first handshake message, so the peer can only continue after receiving the reply
+    pub fn has_replied(&self) -> bool {
+        self.init.as_ref().map(|init| init.stage() == init::STAGE_PENG).unwrap_or(false)
+    }
+
     pub fn is_ready(&self) -> bool {
         self.core.is_some()
     }
 
     pub fn algorithm_name(&self) -> &'static str {
         if let Some(ref core) = self.core {
-            let algo = core.algorithm();
-            if algo == &aead::CHACHA20_POLY1305 {
-                "CHACHA20"
-            } else if algo == &aead::AES_128_GCM {
-                "AES128"
-            } else if algo == &aead::AES_256_GCM {
-                "AES256"
-            } else {
-                unreachable!()
-            }
+            algorithm_name(core.algorithm())
         } else {
             "PLAIN"
         }
@@ -302,11 +557,15 @@ impl<P: Payload> PeerCrypto<P> {
         let result = self.get_init()?.handle_init(buffer)?;
         if !buffer.is_empty() {
             buffer.prepend_byte(INIT_MESSAGE_FIRST_BYTE);
+            self.add_network_proof(buffer);
         }
         match result {
             InitResult::Continue => Ok(MessageResult::Reply),
             InitResult::Success { peer_payload, is_initiator } => {
+                self.stats.handshakes += 1;
                 self.core = self.get_init()?.take_core();
+                self.peer_key = self.get_init()?.peer_key();
+                self.early_data = self.get_init()?.take_early_data();
                 if self.core.is_none() {
                     self.unencrypted = true;
                 }
@@ -329,6 +588,67 @@ impl<P: Payload> PeerCrypto<P> {
         }
     }
 
+    /// Attaches packets to the peng message in `out` that has just been created by `handle_message`
+    ///
+    /// Packets are added in order as long as they fit into `MAX_EARLY_DATA_LEN`, the number of attached packets is
+    /// returned. Nothing is attached if `out` is not a fresh peng message or the connection is not encrypted.
+    pub fn attach_early_data<'a, I: IntoIterator<Item = &'a [u8]>>(
+        &mut self, packets: I, out: &mut MsgBuffer,
+    ) -> usize {
+        if out.is_empty() || self.init.as_ref().map(|i| i.stage()) != Some(init::WAITING_TO_CLOSE) {
+            return 0;
+        }
+        let core = match self.core {
+            Some(ref mut core) => core,
+            None => return 0,
+        };
+        let mut data = MsgBuffer::new(EXTRA_LEN);
+        let mut count = 0;
+        for packet in packets {
+            let len = data.len();
+            if len + 2 + packet.len() > MAX_EARLY_DATA_LEN {
+                break;
+            }
+            data.set_length(len + 2 + packet.len());
+            let msg = data.message_mut();
+            NetworkEndian::write_u16(&mut msg[len..len + 2], packet.len() as u16);
+            msg[len + 2..].copy_from_slice(packet);
+            count += 1;
+        }
+        if count == 0 {
+            return 0;
+        }
+        core.encrypt(&mut data);
+        // The early data goes between the signed message and the network proof
+        if self.network_secret.is_some() {
+            out.set_length(out.len() - NETWORK_PROOF_LEN);
+        }
+        let len = out.len();
+        out.set_length(len + data.len());
+        out.message_mut()[len..].copy_from_slice(data.message());
+        self.add_network_proof(out);
+        count
+    }
+
+    /// Returns the packets that the peer attached to its peng message
+    pub fn take_early_data(&mut self) -> Vec<Vec<u8>> {
+        let data = match self.early_data.take() {
+            Some(data) => data,
+            None => return vec![],
+        };
+        let mut packets = vec![];
+        let mut msg = data.message();
+        while msg.len() >= 2 {
+            let len = NetworkEndian::read_u16(&msg[..2]) as usize;
+            if msg.len() < 2 + len {
+                break;
+            }
+            packets.push(msg[2..2 + len].to_vec());
+            msg = &msg[2 + len..];
+        }
+        packets
+    }
+
     fn handle_rotate_message(&mut self, data: &[u8]) -> Result<(), Error> {
         if self.unencrypted {
             return Ok(());
@@ -346,7 +666,13 @@ impl<P: Payload> PeerCrypto<P> {
         if self.unencrypted {
             return Ok(());
         }
-        self.get_core()?.encrypt(buffer);
+        let core = match self.core {
+            Some(ref mut core) => core,
+            None => return Err(Error::InvalidCryptoState("Crypto core not ready yet")),
+        };
+        let start = self.stats.encrypt.begin();
+        core.encrypt(buffer);
+        self.stats.encrypt.end(start, true);
         Ok(())
     }
 
@@ -355,7 +681,22 @@ impl<P: Payload> PeerCrypto<P> {
         if self.unencrypted {
             return Ok(());
         }
-        self.get_core()?.decrypt(buffer)
+        let core = match self.core {
+            Some(ref mut core) => core,
+            None => return Err(Error::InvalidCryptoState("Crypto core not ready yet")),
+        };
+        let start = self.stats.decrypt.begin();
+        let result = core.decrypt(buffer);
+        self.stats.decrypt.end(start, result.is_ok());
+        result
+    }
+
+    /// Checks whether an encrypted message belongs to this session without changing the session state
+    pub fn verify_message(&self, buffer: &[u8]) -> bool {
+        match self.core {
+            Some(ref core) if !self.unencrypted && !is_init_message(buffer) => core.verify(buffer),
+            _ => false,
+        }
     }
 
     pub fn handle_message(&mut self, buffer: &mut MsgBuffer) -> Result<MessageResult<P>, Error> {
@@ -366,17 +707,21 @@ impl<P: Payload> PeerCrypto<P> {
         if is_init_message(buffer.buffer()) {
             // COLD PATH
             debug!("Received init message");
+            self.check_network_proof(buffer).map_err(|e| e.in_phase(Phase::Init))?;
             buffer.take_prefix();
-            self.handle_init_message(buffer)
+            let start = self.stats.init.begin_timed();
+            let result = self.handle_init_message(buffer);
+            self.stats.init.end(start, result.is_ok());
+            result.map_err(|e| e.in_phase(Phase::Init))
         } else {
             // HOT PATH
             debug!("Received encrypted message");
-            self.decrypt_message(buffer)?;
+            self.decrypt_message(buffer).map_err(|e| e.in_phase(Phase::Decryption))?;
             let msg_type = buffer.take_prefix();
             if msg_type == MESSAGE_TYPE_ROTATION {
                 // COLD PATH
                 debug!("Received rotation message");
-                self.handle_rotate_message(buffer.buffer())?;
+                self.handle_rotate_message(buffer.buffer()).map_err(|e| e.in_phase(Phase::Rotation))?;
                 buffer.clear();
                 Ok(MessageResult::None)
             } else {
@@ -392,6 +737,21 @@ impl<P: Payload> PeerCrypto<P> {
         self.encrypt_message(buffer)
     }
 
+    /// Returns the number of received and lost packets since the last call
+    pub fn take_packet_stats(&mut self) -> (u64, u64) {
+        self.core.as_mut().map(|c| c.take_packet_stats()).unwrap_or((0, 0))
+    }
+
+    /// Returns the counters and timings of the crypto operations since the last call
+    pub fn take_stats(&mut self) -> SessionStats {
+        mem::take(&mut self.stats)
+    }
+
+    /// Starts a key rotation with the next call to `every_second`
+    pub fn force_rotation(&mut self) {
+        self.rotate_counter = ROTATE_INTERVAL
+    }
+
     pub fn every_second(&mut self, out: &mut MsgBuffer) -> Result<MessageResult<P>, Error> {
         out.clear();
         if let Some(ref mut core) = self.core {
@@ -405,6 +765,7 @@ impl<P: Payload> PeerCrypto<P> {
         }
         if !out.is_empty() {
             out.prepend_byte(INIT_MESSAGE_FIRST_BYTE);
+            self.add_network_proof(out);
             return Ok(MessageResult::Reply);
         }
         if let Some(ref mut rotate) = self.rotation {
@@ -437,9 +798,13 @@ pub fn is_init_message(msg: &[u8]) -> bool {
 mod tests {
     use super::*;
 
-    use crate::types::NODE_ID_BYTES;
+    use crate::{
+        timestamp::local_offset,
+        types::NODE_ID_BYTES,
+        util::{MockTimeSource, SystemTimeSource},
+    };
 
-    fn create_node(config: &Config) -> PeerCrypto<Vec<u8>> {
+    fn create_node(config: &Config) -> PeerCrypto<Vec<u8>, MockTimeSource> {
         let rng = SystemRandom::new();
         let mut node_id = [0; NODE_ID_BYTES];
         rng.fill(&mut node_id).unwrap();
@@ -504,4 +869,200 @@ mod tests {
             }
         }
     }
+
+    fn handshake(
+        node1: &mut PeerCrypto<Vec<u8>, MockTimeSource>, node2: &mut PeerCrypto<Vec<u8>, MockTimeSource>,
+    ) -> Result<(), Error> {
+        let mut msg = MsgBuffer::new(16);
+        node1.initialize(&mut msg)?;
+        assert_eq!(node2.handle_message(&mut msg)?, MessageResult::Reply);
+        assert_eq!(node1.handle_message(&mut msg)?, MessageResult::InitializedWithReply(vec![]));
+        assert_eq!(node2.handle_message(&mut msg)?, MessageResult::InitializedWithReply(vec![]));
+        Ok(())
+    }
+
+    #[test]
+    fn early_data() {
+        let config = Config {
+            password: Some("test".to_string()),
+            network_secret: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let mut node1 = create_node(&config);
+        let mut node2 = create_node(&config);
+        let mut msg = MsgBuffer::new(16);
+        node1.initialize(&mut msg).unwrap();
+        assert_eq!(node2.handle_message(&mut msg).unwrap(), MessageResult::Reply);
+        // Early data can only be attached to the peng message
+        let mut pong = msg.clone();
+        assert_eq!(0, node2.attach_early_data(vec![&[1u8, 2, 3] as &[u8]], &mut pong));
+        assert_eq!(node1.handle_message(&mut msg).unwrap(), MessageResult::InitializedWithReply(vec![]));
+        let big = [4; MAX_EARLY_DATA_LEN];
+        assert_eq!(2, node1.attach_early_data(vec![&[1u8, 2, 3] as &[u8], &[], &big], &mut msg));
+        let peng = msg.clone();
+        assert_eq!(node2.handle_message(&mut msg).unwrap(), MessageResult::InitializedWithReply(vec![]));
+        assert_eq!(vec![vec![1, 2, 3], vec![]], node2.take_early_data());
+        assert!(node2.take_early_data().is_empty());
+        // A replayed peng message does not deliver the data again
+        msg.clone_from(peng.message());
+        node2.handle_message(&mut msg).ok();
+        assert!(node2.take_early_data().is_empty());
+    }
+
+    #[test]
+    fn key_migration() {
+        let (old_key, _) = Crypto::generate_keypair(Some("old"));
+        let (new_key, _) = Crypto::generate_keypair(Some("new"));
+        let old = Config { private_key: Some(old_key.clone()), ..Default::default() };
+        let new = Config { private_key: Some(new_key.clone()), ..Default::default() };
+        assert!(handshake(&mut create_node(&old), &mut create_node(&new)).is_err());
+        // Nodes that know both keys accept each other regardless of the primary key
+        let old_first = Config { secondary_key: Some(new_key.clone()), ..old };
+        let new_first = Config { secondary_key: Some(old_key), ..new };
+        handshake(&mut create_node(&old_first), &mut create_node(&new_first)).unwrap();
+        handshake(&mut create_node(&new_first), &mut create_node(&old_first)).unwrap();
+        let new_only = Config { private_key: Some(new_key), ..Default::default() };
+        handshake(&mut create_node(&new_first), &mut create_node(&new_only)).unwrap();
+    }
+
+    #[test]
+    fn key_names() {
+        let (private_key, public_key) = Crypto::generate_keypair(None);
+        let config = Config {
+            private_key: Some(private_key),
+            trusted_keys: vec![format!("node1:{}", public_key)],
+            ..Default::default()
+        };
+        let mut node1 = create_node(&config);
+        let mut node2 = create_node(&config);
+        assert_eq!(None, node2.peer_key());
+        handshake(&mut node1, &mut node2).unwrap();
+        let crypto = Crypto::new([0; NODE_ID_BYTES], &config).unwrap();
+        assert_eq!(Some("node1"), crypto.key_name(node1.peer_key().unwrap()));
+        assert_eq!(Some("node1"), crypto.key_name(node2.peer_key().unwrap()));
+        let config = Config { trusted_keys: vec![public_key], ..config };
+        let crypto = Crypto::new([0; NODE_ID_BYTES], &config).unwrap();
+        assert_eq!(None, crypto.key_name(node1.peer_key().unwrap()));
+    }
+
+    #[test]
+    fn peer_algorithms() {
+        let (private_key1, public_key1) = Crypto::generate_keypair(None);
+        let (private_key2, public_key2) = Crypto::generate_keypair(None);
+        let trusted_keys = vec![public_key1, format!("gateway:{}", public_key2)];
+        let config1 = Config {
+            private_key: Some(private_key1),
+            trusted_keys: trusted_keys.clone(),
+            peer_algorithms: vec![("gateway".to_string(), vec!["chacha20".to_string()])].into_iter().collect(),
+            ..Default::default()
+        };
+        let config2 = Config { private_key: Some(private_key2), trusted_keys, ..Default::default() };
+        let mut node1 = create_node(&config1);
+        let mut node2 = create_node(&config2);
+        handshake(&mut node2, &mut node1).unwrap();
+        assert_eq!("CHACHA20", node1.algorithm_name());
+        assert_eq!("CHACHA20", node2.algorithm_name());
+        // The initiator can only reject the algorithm selected by the peer
+        let config1 = Config {
+            peer_algorithms: vec![("gateway".to_string(), vec!["-aes128".to_string()])].into_iter().collect(),
+            ..config1
+        };
+        let config2 = Config { algorithms: vec!["aes128".to_string()], ..config2 };
+        assert!(handshake(&mut create_node(&config1), &mut create_node(&config2)).is_err());
+        assert!(handshake(&mut create_node(&config2), &mut create_node(&config1)).is_err());
+        // Invalid overrides
+        let invalid = |peer: &str, algos: &[&str]| Config {
+            peer_algorithms: vec![(peer.to_string(), algos.iter().map(|a| a.to_string()).collect())]
+                .into_iter()
+                .collect(),
+            ..config1.clone()
+        };
+        assert!(Crypto::new([0; NODE_ID_BYTES], &invalid("unknown", &["aes128"])).is_err());
+        assert!(Crypto::new([0; NODE_ID_BYTES], &invalid("gateway", &["rot13"])).is_err());
+        assert!(Crypto::new([0; NODE_ID_BYTES], &invalid("gateway", &["-aes128", "-aes256", "-chacha20"])).is_err());
+        let (_, untrusted) = Crypto::generate_keypair(None);
+        assert!(Crypto::new([0; NODE_ID_BYTES], &invalid(&untrusted, &["aes128"])).is_err());
+    }
+
+    #[test]
+    fn peer_schedules() {
+        let (private_key1, public_key1) = Crypto::generate_keypair(None);
+        let (private_key2, public_key2) = Crypto::generate_keypair(None);
+        let trusted_keys = vec![public_key1.clone(), format!("contractor:{}", public_key2)];
+        let with_schedule = |peer: &str, windows: &[&str]| Config {
+            private_key: Some(private_key1.clone()),
+            trusted_keys: trusted_keys.clone(),
+            peer_schedules: vec![(peer.to_string(), windows.iter().map(|w| w.to_string()).collect())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let config2 =
+            Config { private_key: Some(private_key2), trusted_keys: trusted_keys.clone(), ..Default::default() };
+        let all_day = with_schedule("contractor", &["00:00-24:00"]);
+        handshake(&mut create_node(&all_day), &mut create_node(&config2)).unwrap();
+        // A window that starts in an hour never contains the current time
+        let now = SystemTimeSource::wall_clock();
+        let minute = (now + Time::from(local_offset(now).unwrap_or(0))).rem_euclid(24 * 3600) / 60;
+        let (start, end) = ((minute + 60) % 1440, (minute + 120) % 1440);
+        let later = format!("{:02}:{:02}-{:02}:{:02}", start / 60, start % 60, end / 60, end % 60);
+        let config1 = with_schedule("contractor", &[&later]);
+        assert!(handshake(&mut create_node(&config1), &mut create_node(&config2)).is_err());
+        assert!(handshake(&mut create_node(&config2), &mut create_node(&config1)).is_err());
+        let crypto = Crypto::new([0; NODE_ID_BYTES], &config1).unwrap();
+        assert!(crypto.has_schedules());
+        assert!(!crypto.allows_access(&Crypto::parse_public_key(&public_key2).unwrap(), now));
+        assert!(crypto.allows_access(&Crypto::parse_public_key(&public_key1).unwrap(), now));
+        // Schedules need a trusted key and valid windows
+        let (_, untrusted) = Crypto::generate_keypair(None);
+        assert!(Crypto::new([0; NODE_ID_BYTES], &with_schedule(&untrusted, &["08:00-18:00"])).is_err());
+        assert!(Crypto::new([0; NODE_ID_BYTES], &with_schedule("contractor", &["8-18"])).is_err());
+    }
+
+    #[test]
+    fn network_secret() {
+        let config = Config {
+            password: Some("test".to_string()),
+            network_secret: Some("secret".to_string()),
+            ..Default::default()
+        };
+        handshake(&mut create_node(&config), &mut create_node(&config)).unwrap();
+        // Init messages without a valid proof are ignored without a reply
+        let other = Config { network_secret: Some("other".to_string()), ..config.clone() };
+        let mut msg = MsgBuffer::new(16);
+        create_node(&other).initialize(&mut msg).unwrap();
+        assert!(create_node(&config).handle_message(&mut msg).is_err());
+        let plain = Config { network_secret: None, ..config.clone() };
+        let mut msg = MsgBuffer::new(16);
+        create_node(&plain).initialize(&mut msg).unwrap();
+        assert!(create_node(&config).handle_message(&mut msg).is_err());
+        let mut msg = MsgBuffer::new(16);
+        msg.clone_from(&[INIT_MESSAGE_FIRST_BYTE, 1, 2, 3]);
+        assert!(create_node(&config).handle_message(&mut msg).is_err());
+    }
+
+    #[test]
+    fn network_secret_replay() {
+        let config = Config {
+            password: Some("test".to_string()),
+            network_secret: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let crypto = Crypto::new([1; NODE_ID_BYTES], &config).unwrap();
+        MockTimeSource::set_time(1000);
+        let mut msg = MsgBuffer::new(16);
+        create_node(&config).initialize(&mut msg).unwrap();
+        let mut replayed = msg.clone();
+        let mut init = crypto.peer_instance::<Vec<u8>, MockTimeSource>(vec![]);
+        assert_eq!(init.handle_message(&mut msg).unwrap(), MessageResult::Reply);
+        // The same message is not answered again, even from another address
+        let mut init = crypto.peer_instance::<Vec<u8>, MockTimeSource>(vec![]);
+        assert!(init.handle_message(&mut replayed).is_err());
+        // Expired proofs are rejected as well
+        let mut msg = MsgBuffer::new(16);
+        create_node(&config).initialize(&mut msg).unwrap();
+        MockTimeSource::set_time(1000 + NETWORK_PROOF_MAX_AGE + 1);
+        let mut init = crypto.peer_instance::<Vec<u8>, MockTimeSource>(vec![]);
+        assert!(init.handle_message(&mut msg).is_err());
+    }
 }
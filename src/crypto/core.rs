@@ -41,7 +41,7 @@
 // one second ago plus 1 becomes the minimum nonce that is accepted for that key. That means, that reordering can
 // happen within one second but after a second, old messages will not be accepted anymore.
 
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use ring::{
     aead::{self, LessSafeKey, UnboundKey},
     rand::{SecureRandom, SystemRandom},
@@ -88,6 +88,11 @@ impl Nonce {
         &self.0
     }
 
+    /// The lower 8 bytes of the nonce as a number
+    fn low(&self) -> u64 {
+        Cursor::new(&self.0[NONCE_LEN - 8..]).read_u64::<BigEndian>().unwrap()
+    }
+
     fn increment(&mut self) {
         for i in (0..NONCE_LEN).rev() {
             let mut num = self.0[i];
@@ -106,6 +111,8 @@ struct CryptoKey {
     min_nonce: Nonce,
     next_min_nonce: Nonce,
     seen_nonce: Nonce,
+    counted_nonce: u64,
+    received: u64,
 }
 
 impl CryptoKey {
@@ -118,6 +125,8 @@ impl CryptoKey {
             min_nonce: Nonce::zero(),
             next_min_nonce: Nonce::zero(),
             seen_nonce: Nonce::zero(),
+            counted_nonce: 0,
+            received: 0,
         }
     }
 
@@ -126,6 +135,24 @@ impl CryptoKey {
         self.next_min_nonce = self.seen_nonce.clone();
         self.next_min_nonce.increment();
     }
+
+    /// Returns the number of received and lost packets since the last call
+    ///
+    /// Since the peer increments the nonce with every packet, all nonces between the highest nonce seen at the
+    /// last call and the highest nonce seen now should have been received. Packets that are reordered across
+    /// calls lead to a slight underestimation of the loss.
+    fn count_packets(&mut self) -> (u64, u64) {
+        let seen = self.seen_nonce.low();
+        let received = mem::take(&mut self.received);
+        let mut lost = 0;
+        if self.counted_nonce > 0 && seen > self.counted_nonce {
+            lost = (seen - self.counted_nonce).saturating_sub(received);
+        }
+        if seen > self.counted_nonce {
+            self.counted_nonce = seen;
+        }
+        (received, lost)
+    }
 }
 
 pub struct CryptoCore {
@@ -133,6 +160,8 @@ pub struct CryptoCore {
     keys: [CryptoKey; 4],
     current_key: usize,
     nonce_half: bool,
+    received: u64,
+    lost: u64,
 }
 
 impl CryptoCore {
@@ -151,6 +180,8 @@ impl CryptoCore {
             ],
             current_key: 0,
             nonce_half,
+            received: 0,
+            lost: 0,
             rand,
         }
     }
@@ -188,28 +219,53 @@ impl CryptoCore {
         if key.seen_nonce < nonce {
             key.seen_nonce = nonce;
         }
+        key.received += 1;
         Ok(())
     }
 
+    /// Reads the key id and the nonce of a received message
+    fn read_extra(&self, extra: &[u8]) -> Result<(usize, Nonce), Error> {
+        let mut extra = Cursor::new(extra);
+        let key_id = extra.read_u8().map_err(|_| Error::Crypto("Input data too short"))? % 4;
+        let mut nonce = Nonce::zero();
+        extra.read_exact(&mut nonce.0[5..]).map_err(|_| Error::Crypto("Input data too short"))?;
+        nonce.set_msb(if self.nonce_half { 0x00 } else { 0x80 });
+        Ok((key_id as usize, nonce))
+    }
+
     pub fn decrypt(&mut self, buffer: &mut MsgBuffer) -> Result<(), Error> {
         assert!(buffer.len() >= EXTRA_LEN + TAG_LEN);
         let (extra, data_and_tag) = buffer.message_mut().split_at_mut(EXTRA_LEN);
-        let key_id;
-        let mut nonce;
-        {
-            let mut extra = Cursor::new(extra);
-            key_id = extra.read_u8().map_err(|_| Error::Crypto("Input data too short"))? % 4;
-            nonce = Nonce::zero();
-            extra.read_exact(&mut nonce.0[5..]).map_err(|_| Error::Crypto("Input data too short"))?;
-            nonce.set_msb(if self.nonce_half { 0x00 } else { 0x80 });
-        }
-        let key = &mut self.keys[key_id as usize];
+        let (key_id, nonce) = self.read_extra(extra)?;
+        let key = &mut self.keys[key_id];
         let result = Self::decrypt_with_key(key, nonce, data_and_tag);
         buffer.set_start(buffer.get_start() + EXTRA_LEN);
         buffer.set_length(buffer.len() - TAG_LEN);
         result
     }
 
+    /// Checks whether the message has been encrypted with one of the keys without changing any state
+    ///
+    /// Unlike `decrypt`, this neither advances the nonces nor counts the message, so it can be used to find out to
+    /// which session a message belongs.
+    pub fn verify(&self, message: &[u8]) -> bool {
+        if message.len() < EXTRA_LEN + TAG_LEN {
+            return false;
+        }
+        let (extra, data_and_tag) = message.split_at(EXTRA_LEN);
+        let (key_id, nonce) = match self.read_extra(extra) {
+            Ok(res) => res,
+            Err(_) => return false,
+        };
+        let key = &self.keys[key_id];
+        if nonce < key.min_nonce {
+            return false;
+        }
+        let mut data_and_tag = data_and_tag.to_vec();
+        let crypto_nonce = aead::Nonce::assume_unique_for_key(*nonce.as_bytes());
+        key.key.open_in_place(crypto_nonce, aead::Aad::empty(), &mut data_and_tag).is_ok()
+    }
+
     pub fn rotate_key(&mut self, key: LessSafeKey, id: u64, use_for_sending: bool) {
         debug!("Rotated key {} (use for sending: {})", id, use_for_sending);
         let id = (id % 4) as usize;
@@ -227,10 +283,32 @@ impl CryptoCore {
         // Set min nonce on all keys
         for k in &mut self.keys {
             k.update_min_nonce();
+            let (received, lost) = k.count_packets();
+            self.received += received;
+            self.lost += lost;
         }
     }
+
+    /// Returns the number of received and lost packets since the last call
+    pub fn take_packet_stats(&mut self) -> (u64, u64) {
+        (mem::take(&mut self.received), mem::take(&mut self.lost))
+    }
+}
+
+/// Short name of the algorithm as used in the statistics
+pub fn algorithm_name(algo: &aead::Algorithm) -> &'static str {
+    if algo == &aead::CHACHA20_POLY1305 {
+        "CHACHA20"
+    } else if algo == &aead::AES_128_GCM {
+        "AES128"
+    } else if algo == &aead::AES_256_GCM {
+        "AES256"
+    } else {
+        unreachable!()
+    }
 }
 
+/// Creates two cores that share a random key, used by the benchmarks
 pub fn create_dummy_pair(algo: &'static aead::Algorithm) -> (CryptoCore, CryptoCore) {
     let key_data = random_data(algo.key_len());
     let sender = CryptoCore::new(LessSafeKey::new(UnboundKey::new(algo, &key_data).unwrap()), true);
@@ -256,11 +334,89 @@ pub fn test_speed(algo: &'static aead::Algorithm, max_time: &Duration) -> f64 {
     data as f64 / duration / 1_000_000.0
 }
 
+/// CPU features that the crypto library selects at runtime to accelerate the algorithms
+///
+/// The features are detected the same way the crypto library does it, so this only reports which implementation is
+/// used, e.g. on Raspberry Pi class devices where hardware AES makes a difference of an order of magnitude.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CpuFeatures {
+    /// Hardware AES instructions (AES-NI or ARMv8 crypto extensions)
+    pub aes: bool,
+    /// Carry-less multiplication for GHASH (PCLMULQDQ or PMULL)
+    pub clmul: bool,
+    /// Vector instructions for ChaCha20 and Poly1305 (SSSE3 or NEON)
+    pub simd: bool,
+}
+
+impl CpuFeatures {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn detect() -> Self {
+        Self {
+            aes: is_x86_feature_detected!("aes"),
+            clmul: is_x86_feature_detected!("pclmulqdq"),
+            simd: is_x86_feature_detected!("ssse3"),
+        }
+    }
+
+    #[cfg(all(target_arch = "aarch64", any(target_os = "linux", target_os = "android")))]
+    pub fn detect() -> Self {
+        const HWCAP_ASIMD: libc::c_ulong = 1 << 1;
+        const HWCAP_AES: libc::c_ulong = 1 << 3;
+        const HWCAP_PMULL: libc::c_ulong = 1 << 4;
+        let hwcap = unsafe { libc::getauxval(libc::AT_HWCAP) };
+        Self { aes: hwcap & HWCAP_AES != 0, clmul: hwcap & HWCAP_PMULL != 0, simd: hwcap & HWCAP_ASIMD != 0 }
+    }
+
+    #[cfg(all(target_arch = "arm", any(target_os = "linux", target_os = "android")))]
+    pub fn detect() -> Self {
+        const HWCAP_NEON: libc::c_ulong = 1 << 12;
+        const HWCAP2_AES: libc::c_ulong = 1 << 0;
+        const HWCAP2_PMULL: libc::c_ulong = 1 << 1;
+        let hwcap = unsafe { libc::getauxval(libc::AT_HWCAP) };
+        let hwcap2 = unsafe { libc::getauxval(libc::AT_HWCAP2) };
+        Self { aes: hwcap2 & HWCAP2_AES != 0, clmul: hwcap2 & HWCAP2_PMULL != 0, simd: hwcap & HWCAP_NEON != 0 }
+    }
+
+    #[cfg(not(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        all(any(target_arch = "arm", target_arch = "aarch64"), any(target_os = "linux", target_os = "android"))
+    )))]
+    pub fn detect() -> Self {
+        Self::default()
+    }
+
+    /// Describes the implementations that are used for AES-GCM and ChaCha20-Poly1305 with these features
+    pub fn describe(&self) -> String {
+        let arm = cfg!(any(target_arch = "arm", target_arch = "aarch64"));
+        let aes = match (self.aes && self.clmul, arm) {
+            (true, true) => "hardware (ARMv8 crypto extensions)",
+            (true, false) => "hardware (AES-NI)",
+            (false, _) => "software",
+        };
+        let chacha = match (self.simd, arm) {
+            (true, true) => "NEON",
+            (true, false) => "SSSE3",
+            (false, _) => "generic",
+        };
+        format!("AES-GCM: {}, ChaCha20-Poly1305: {}", aes, chacha)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use ring::aead::{self, LessSafeKey, UnboundKey};
 
+    #[test]
+    fn test_cpu_features() {
+        let features = CpuFeatures { aes: true, clmul: true, simd: true };
+        assert!(features.describe().starts_with("AES-GCM: hardware"));
+        let features = CpuFeatures { aes: true, clmul: false, simd: false };
+        assert_eq!(features.describe(), "AES-GCM: software, ChaCha20-Poly1305: generic");
+        CpuFeatures::detect();
+    }
+
     #[test]
     fn test_nonce() {
         let mut nonce = Nonce::zero();
@@ -298,6 +454,24 @@ mod tests {
         test_encrypt_decrypt(&aead::CHACHA20_POLY1305)
     }
 
+    #[test]
+    fn test_verify() {
+        let (mut sender, mut receiver) = create_dummy_pair(&aead::AES_128_GCM);
+        let plain = random_data(1000);
+        let mut buffer = MsgBuffer::new(EXTRA_LEN);
+        buffer.clone_from(&plain);
+        sender.encrypt(&mut buffer);
+        assert!(receiver.verify(buffer.message()));
+        // Verifying does not decrypt the message in place
+        assert_ne!(&plain[..], &buffer.message()[EXTRA_LEN..EXTRA_LEN + plain.len()]);
+        let mut d = buffer.clone();
+        d.message_mut()[EXTRA_LEN] ^= 1;
+        assert!(!receiver.verify(d.message()));
+        assert!(!receiver.verify(&buffer.message()[..EXTRA_LEN + TAG_LEN - 1]));
+        receiver.decrypt(&mut buffer).unwrap();
+        assert_eq!(&plain[..], buffer.message());
+    }
+
     fn test_tampering(algo: &'static aead::Algorithm) {
         let (mut sender, mut receiver) = create_dummy_pair(algo);
         let plain = random_data(1000);
@@ -428,9 +602,32 @@ mod tests {
         test_key_rotation(&aead::CHACHA20_POLY1305);
     }
 
+    #[test]
+    fn test_packet_stats() {
+        let (mut sender, mut receiver) = create_dummy_pair(&aead::AES_128_GCM);
+        let send = |sender: &mut CryptoCore, receiver: &mut CryptoCore, deliver: bool| {
+            let mut buffer = MsgBuffer::new(EXTRA_LEN);
+            buffer.clone_from(&[1, 2, 3]);
+            sender.encrypt(&mut buffer);
+            if deliver {
+                receiver.decrypt(&mut buffer).unwrap();
+            }
+        };
+        send(&mut sender, &mut receiver, true);
+        receiver.every_second();
+        assert_eq!(receiver.take_packet_stats(), (1, 0));
+        for i in 0..10 {
+            send(&mut sender, &mut receiver, i % 5 != 0);
+        }
+        receiver.every_second();
+        assert_eq!(receiver.take_packet_stats(), (8, 2));
+        receiver.every_second();
+        assert_eq!(receiver.take_packet_stats(), (0, 0));
+    }
+
     #[test]
     fn test_core_size() {
-        assert_eq!(2384, mem::size_of::<CryptoCore>());
+        assert_eq!(2464, mem::size_of::<CryptoCore>());
     }
 
     #[test]
@@ -40,6 +40,9 @@
 // The ping message and the pong message contain a set of supported crypto algorithms together with the estimated
 // speeds of the algorithms. When B receives a ping message, or A receives a pong message, it can combine this
 // information with its own algorithm list and select the algorithm with the best expected speed for the crypto core.
+// If the algorithms are restricted for the public key of the peer, B only uses and announces the allowed algorithms
+// when replying with the pong message. A does not know the public key of B when sending the ping message, so it
+// aborts the handshake if the algorithm that B has selected is not allowed for B.
 //
 // The pong and peng message contain the payload that the nodes want to exchange in the initialization phase apart from
 // the cryptographic initialization. This payload is encoded according to the application and encrypted using the key
@@ -51,14 +54,32 @@
 // future communication and the key rotation is started. Since the peng message can be lost, A needs to keep the
 // initialization state in order to repeat a lost peng message. After one second, A removes that state.
 //
+// A can attach payload data to the peng message, so that packets that were waiting for the connection do not need an
+// extra round trip. This early data is appended after the signature and encrypted with the negotiated crypto core, so
+// it is authenticated by the session key and protected by the replay protection of the core. B only accepts early data
+// in the peng message that completes the handshake, and A never attaches it to repeated peng messages. Early data
+// requires encryption and is ignored for unencrypted connections. Nodes that do not know about early data ignore it.
+//
+// With padding enabled, every message contains a padding field that fills it up to a multiple of a fixed block size,
+// so the handshake can not be recognized by the lengths of its messages. The padding is part of the signed data and
+// nodes that do not know about padding ignore it like any unknown field.
+//
+// Trusted keys can be limited to time windows. Messages signed with such a key are rejected outside of its windows,
+// so the handshake does not complete.
+//
 // Once every second, both nodes check whether they have already finished the initialization. If not, they repeat their
 // last message. After 5 seconds, the initialization is aborted as failed.
 
 use super::{
-    core::{CryptoCore, EXTRA_LEN},
-    Algorithms, EcdhPrivateKey, EcdhPublicKey, Ed25519PublicKey, Payload,
+    access::AccessSchedule,
+    core::{CryptoCore, EXTRA_LEN, TAG_LEN},
+    Algorithms, EcdhPrivateKey, EcdhPublicKey, Ed25519PublicKey, Payload, PeerOptions,
+};
+use crate::{
+    error::Error,
+    types::NodeId,
+    util::{padded_len, MsgBuffer, SystemTimeSource, TimeSource, PADDING_BLOCK},
 };
-use crate::{error::Error, types::NodeId, util::MsgBuffer};
 use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
 use ring::{
     aead::{Algorithm, LessSafeKey, UnboundKey, AES_128_GCM, AES_256_GCM, CHACHA20_POLY1305},
@@ -69,7 +90,9 @@ use ring::{
 };
 use smallvec::{smallvec, SmallVec};
 use std::{
-    cmp, f32,
+    cmp,
+    collections::HashMap,
+    f32,
     fmt::Debug,
     io::{self, Cursor, Read, Write},
     sync::Arc,
@@ -84,6 +107,9 @@ pub const CLOSING: u8 = 5;
 pub const MAX_FAILED_RETRIES: usize = 120;
 
 pub const SALTED_NODE_ID_HASH_LEN: usize = 20;
+
+const SIGNATURE_LEN: usize = 64;
+
 pub type SaltedNodeIdHash = [u8; SALTED_NODE_ID_HASH_LEN];
 
 #[allow(clippy::large_enum_variant)]
@@ -109,6 +135,7 @@ impl InitMsg {
     const PART_ALGORITHMS: u8 = 4;
     const PART_ECDH_PUBLIC_KEY: u8 = 3;
     const PART_END: u8 = 0;
+    const PART_PADDING: u8 = 6;
     const PART_PAYLOAD: u8 = 5;
     const PART_SALTED_NODE_ID_HASH: u8 = 2;
     const PART_STAGE: u8 = 1;
@@ -139,7 +166,9 @@ impl InitMsg {
         short_hash
     }
 
-    fn read_from(buffer: &[u8], trusted_keys: &[Ed25519PublicKey]) -> Result<(Self, Ed25519PublicKey), Error> {
+    fn read_from(
+        buffer: &[u8], trusted_keys: &[Ed25519PublicKey],
+    ) -> Result<(Self, Ed25519PublicKey, usize), Error> {
         let mut r = Cursor::new(buffer);
 
         let mut public_key_salt = [0; 4];
@@ -233,6 +262,7 @@ impl InitMsg {
         let mut signature: SmallVec<[u8; 32]> = smallvec![0; signature_len];
         r.read_exact(&mut signature).map_err(|_| Error::Parse("Init message too short"))?;
 
+        let len = r.position() as usize;
         let signed_data = &r.into_inner()[0..pos];
         let public_key = signature::UnparsedPublicKey::new(&ED25519, &public_key_data);
         if public_key.verify(&signed_data, &signature).is_err() {
@@ -285,10 +315,10 @@ impl InitMsg {
             _ => return Err(Error::CryptoInit("Invalid stage")),
         };
 
-        Ok((msg, public_key_data))
+        Ok((msg, public_key_data, len))
     }
 
-    fn write_to(&self, buffer: &mut [u8], key: &Ed25519KeyPair) -> Result<usize, io::Error> {
+    fn write_to(&self, buffer: &mut [u8], key: &Ed25519KeyPair, padding: bool) -> Result<usize, io::Error> {
         let mut w = Cursor::new(buffer);
 
         let rand = SystemRandom::new();
@@ -361,6 +391,15 @@ impl InitMsg {
             _ => (),
         }
 
+        if padding {
+            // The message is padded including the first byte, the end marker and the signature
+            let len = 1 + w.position() as usize + 3 + 1 + 1 + SIGNATURE_LEN;
+            let pad_len = padded_len(len) - len;
+            w.write_u8(Self::PART_PADDING)?;
+            w.write_u16::<NetworkEndian>(pad_len as u16)?;
+            w.write_all(&[0; PADDING_BLOCK][..pad_len])?;
+        }
+
         w.write_u8(Self::PART_END)?;
 
         let pos = w.position() as usize;
@@ -393,13 +432,19 @@ pub struct InitState<P: Payload> {
     #[allow(dead_code)] // Used in tests
     selected_algorithm: Option<&'static Algorithm>,
     failed_retries: usize,
+    peer_key: Option<Ed25519PublicKey>,
+    peer_algorithms: Arc<HashMap<Ed25519PublicKey, Algorithms>>,
+    peer_schedules: Arc<HashMap<Ed25519PublicKey, AccessSchedule>>,
+    early_data: Option<Box<MsgBuffer>>,
+    padding: bool,
 }
 
 impl<P: Payload> InitState<P> {
     pub fn new(
         node_id: NodeId, payload: P, key_pair: Arc<Ed25519KeyPair>, trusted_keys: Arc<[Ed25519PublicKey]>,
-        algorithms: Algorithms,
+        algorithms: Algorithms, options: PeerOptions,
     ) -> Self {
+        let PeerOptions { peer_algorithms, peer_schedules, padding, .. } = options;
         let mut hash = [0; SALTED_NODE_ID_HASH_LEN];
         let rng = SystemRandom::new();
         rng.fill(&mut hash[0..4]).unwrap();
@@ -420,6 +465,11 @@ impl<P: Payload> InitState<P> {
             algorithms,
             failed_retries: 0,
             close_time: 60,
+            peer_key: None,
+            peer_algorithms,
+            peer_schedules,
+            early_data: None,
+            padding,
         }
     }
 
@@ -523,7 +573,7 @@ impl<P: Payload> InitState<P> {
             _ => unreachable!(),
         };
         let mut bytes = out.buffer();
-        let len = msg.write_to(&mut bytes, &self.key_pair).expect("Buffer too small");
+        let len = msg.write_to(&mut bytes, &self.key_pair, self.padding).expect("Buffer too small");
         self.last_message = Some(bytes[0..len].to_vec());
         out.set_length(len);
     }
@@ -537,6 +587,24 @@ impl<P: Payload> InitState<P> {
         }
     }
 
+    fn check_peer_algorithm(
+        &self, peer_key: &Ed25519PublicKey, algorithm: Option<&'static Algorithm>,
+    ) -> Result<(), Error> {
+        let algos = match self.peer_algorithms.get(peer_key) {
+            Some(algos) => algos,
+            None => return Ok(()),
+        };
+        let allowed = match algorithm {
+            Some(algorithm) => algos.algorithm_speeds.iter().any(|(a, _)| *a == algorithm),
+            None => algos.allow_unencrypted,
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(Error::CryptoInitFatal("Selected algorithm is not allowed for this peer"))
+        }
+    }
+
     fn select_algorithm(&self, peer_algos: &Algorithms) -> Result<Option<(&'static Algorithm, f32)>, Error> {
         if self.algorithms.allow_unencrypted && peer_algos.allow_unencrypted {
             return Ok(None);
@@ -565,7 +633,18 @@ impl<P: Payload> InitState<P> {
     }
 
     pub fn handle_init(&mut self, out: &mut MsgBuffer) -> Result<InitResult<P>, Error> {
-        let (msg, _peer_key) = InitMsg::read_from(out.buffer(), &self.trusted_keys)?;
+        let (msg, peer_key, len) = InitMsg::read_from(out.buffer(), &self.trusted_keys)?;
+        if let Some(schedule) = self.peer_schedules.get(&peer_key) {
+            if !schedule.allows(SystemTimeSource::wall_clock()) {
+                return Err(Error::CryptoInit("Peer is outside of its access window"));
+            }
+        }
+        let mut early_data = None;
+        if matches!(msg, InitMsg::Peng { .. }) && out.len() >= len + EXTRA_LEN + TAG_LEN {
+            let mut data = MsgBuffer::new(0);
+            data.clone_from(&out.message()[len..]);
+            early_data = Some(Box::new(data));
+        }
         out.clear();
         let stage = msg.stage();
         let salted_node_id_hash = *msg.salted_node_id_hash();
@@ -597,11 +676,17 @@ impl<P: Payload> InitState<P> {
             }
         }
         self.failed_retries = 0;
+        self.peer_key = Some(peer_key);
         match msg {
             InitMsg::Ping { ecdh_public_key, algorithms, .. } => {
                 // create ecdh ephemeral key
                 let (my_ecdh_private_key, my_ecdh_public_key) = self.create_ecdh_keypair();
 
+                // only offer the algorithms allowed for this peer, also in the pong message
+                if let Some(algos) = self.peer_algorithms.get(&peer_key) {
+                    self.algorithms = algos.clone();
+                }
+
                 // do ecdh agreement and derive master key
                 let algorithm = self.select_algorithm(&algorithms)?;
                 self.selected_algorithm = algorithm.map(|a| a.0);
@@ -620,6 +705,8 @@ impl<P: Payload> InitState<P> {
                 // do ecdh agreement and derive master key
                 let ecdh_private_key = self.ecdh_private_key.take().unwrap();
                 let algorithm = self.select_algorithm(&algorithms)?;
+                // the peer selected the same algorithm, it is only known now whether it is allowed for this peer
+                self.check_peer_algorithm(&peer_key, algorithm.map(|a| a.0))?;
                 self.selected_algorithm = algorithm.map(|a| a.0);
                 if let Some((algorithm, _speed)) = algorithm {
                     let master_key = self.derive_master_key(algorithm, ecdh_private_key, &ecdh_public_key);
@@ -644,6 +731,14 @@ impl<P: Payload> InitState<P> {
                     .decrypt(&mut encrypted_payload)
                     .map_err(|_| Error::CryptoInitFatal("Failed to decrypt payload"))?;
 
+                // early data is only accepted with encryption, it is just dropped if it can not be decrypted
+                if let (Some(mut data), Some(crypto)) = (early_data, &mut self.crypto) {
+                    match crypto.decrypt(&mut data) {
+                        Ok(()) => self.early_data = Some(data),
+                        Err(_) => debug!("Ignoring early data that can not be decrypted"),
+                    }
+                }
+
                 self.next_stage = CLOSING; // force resend when receiving any message
                 Ok(InitResult::Success { peer_payload, is_initiator: false })
             }
@@ -653,6 +748,16 @@ impl<P: Payload> InitState<P> {
     pub fn take_core(&mut self) -> Option<CryptoCore> {
         self.crypto.take()
     }
+
+    /// Returns the decrypted early data that the peer attached to the peng message
+    pub fn take_early_data(&mut self) -> Option<Box<MsgBuffer>> {
+        self.early_data.take()
+    }
+
+    /// Returns the public key that the peer used in the handshake
+    pub fn peer_key(&self) -> Option<Ed25519PublicKey> {
+        self.peer_key
+    }
 }
 
 #[cfg(test)]
@@ -673,7 +778,7 @@ mod tests {
         }
     }
 
-    fn create_pair() -> (InitState<Vec<u8>>, InitState<Vec<u8>>) {
+    fn create_pair_with_padding(padding: bool) -> (InitState<Vec<u8>>, InitState<Vec<u8>>) {
         let rng = SystemRandom::new();
         let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
         let key_pair = Arc::new(Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref()).unwrap());
@@ -688,11 +793,29 @@ mod tests {
             algorithm_speeds: smallvec![(&AES_128_GCM, 600.0), (&AES_256_GCM, 500.0), (&CHACHA20_POLY1305, 400.0)],
             allow_unencrypted: false,
         };
-        let sender = InitState::new(node1, vec![1], key_pair.clone(), trusted_nodes.clone(), algorithms.clone());
-        let receiver = InitState::new(node2, vec![2], key_pair, trusted_nodes, algorithms);
+        let sender = InitState::new(
+            node1,
+            vec![1],
+            key_pair.clone(),
+            trusted_nodes.clone(),
+            algorithms.clone(),
+            PeerOptions { padding, ..Default::default() },
+        );
+        let receiver = InitState::new(
+            node2,
+            vec![2],
+            key_pair,
+            trusted_nodes,
+            algorithms,
+            PeerOptions { padding, ..Default::default() },
+        );
         (sender, receiver)
     }
 
+    fn create_pair() -> (InitState<Vec<u8>>, InitState<Vec<u8>>) {
+        create_pair_with_padding(false)
+    }
+
     #[test]
     fn normal_init() {
         let (mut sender, mut receiver) = create_pair();
@@ -715,6 +838,23 @@ mod tests {
         }
     }
 
+    #[test]
+    fn padded_init() {
+        let (mut sender, mut receiver) = create_pair_with_padding(true);
+        // Nodes without padding accept padded messages
+        receiver.padding = false;
+        let mut out = MsgBuffer::new(8);
+        sender.send_ping(&mut out);
+        assert_eq!((out.len() + 1) % PADDING_BLOCK, 0);
+        let result = receiver.handle_init(&mut out).unwrap();
+        assert_eq!(result, InitResult::Continue);
+        let result = sender.handle_init(&mut out).unwrap();
+        assert_eq!((out.len() + 1) % PADDING_BLOCK, 0);
+        assert_eq!(result, InitResult::Success { peer_payload: vec![2], is_initiator: true });
+        let result = receiver.handle_init(&mut out).unwrap();
+        assert_eq!(result, InitResult::Success { peer_payload: vec![1], is_initiator: false });
+    }
+
     #[test]
     fn lost_init_sender_recovers() {
         let (mut sender, mut receiver) = create_pair();
@@ -2,10 +2,16 @@
 // Copyright (C) 2015-2021  Dennis Schwerdel
 // This software is licensed under GPL-3 or newer (see LICENSE.md)
 
+mod access;
 mod common;
 mod core;
 mod init;
 mod rotate;
+mod stats;
 
-pub use self::core::{EXTRA_LEN, TAG_LEN};
+pub use self::{
+    access::AccessSchedule,
+    core::{create_dummy_pair, test_speed, CpuFeatures, EXTRA_LEN, TAG_LEN},
+    stats::{CryptoStats, OpStats, SessionStats},
+};
 pub use common::*;
@@ -0,0 +1,228 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Counters and timings of the crypto operations per algorithm and per peer
+//!
+//! Every handshake message is timed, but only every `SAMPLE_INTERVAL`th encryption and decryption is timed to keep the
+//! overhead on the data path low. The results of the startup benchmark are included, so peers that use a slower
+//! algorithm than the fastest one of this node stand out.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+    net::SocketAddr,
+    time::Instant,
+};
+
+use crate::{cloud::Hash, util::addr_nice};
+
+/// Only one in this many encryptions and decryptions is timed
+const SAMPLE_INTERVAL: u64 = 64;
+
+/// Number, failures and timing of one kind of operation
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct OpStats {
+    pub count: u64,
+    pub failures: u64,
+    timed: u64,
+    nanos: u64,
+}
+
+impl OpStats {
+    /// Counts an operation and returns the start time if it should be timed
+    #[inline]
+    pub fn begin(&mut self) -> Option<Instant> {
+        // HOT PATH
+        self.count += 1;
+        if self.count % SAMPLE_INTERVAL == 1 {
+            Some(Instant::now())
+        } else {
+            None
+        }
+    }
+
+    /// Counts an operation that is always timed
+    pub fn begin_timed(&mut self) -> Option<Instant> {
+        self.count += 1;
+        Some(Instant::now())
+    }
+
+    #[inline]
+    pub fn end(&mut self, start: Option<Instant>, success: bool) {
+        // HOT PATH
+        if let Some(start) = start {
+            self.timed += 1;
+            self.nanos += start.elapsed().as_nanos() as u64;
+        }
+        if !success {
+            self.failures += 1;
+        }
+    }
+
+    /// Average duration of the timed operations in nanoseconds
+    pub fn avg_nanos(&self) -> u64 {
+        self.nanos.checked_div(self.timed).unwrap_or(0)
+    }
+
+    fn add(&mut self, other: &OpStats) {
+        self.count += other.count;
+        self.failures += other.failures;
+        self.timed += other.timed;
+        self.nanos += other.nanos;
+    }
+}
+
+/// Crypto operations of one session
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct SessionStats {
+    pub encrypt: OpStats,
+    pub decrypt: OpStats,
+    /// Processing of handshake messages
+    pub init: OpStats,
+    /// Completed handshakes
+    pub handshakes: u64,
+}
+
+impl SessionStats {
+    fn add(&mut self, other: &SessionStats) {
+        self.encrypt.add(&other.encrypt);
+        self.decrypt.add(&other.decrypt);
+        self.init.add(&other.init);
+        self.handshakes += other.handshakes;
+    }
+
+    fn write_fields<W: Write>(&self, out: &mut W) -> Result<(), io::Error> {
+        write!(
+            out,
+            "handshakes: {}, handshake_ns: {}, encryptions: {}, encrypt_ns: {}, decryptions: {}, \
+             decrypt_failures: {}, decrypt_ns: {}",
+            self.handshakes,
+            self.init.avg_nanos(),
+            self.encrypt.count,
+            self.encrypt.avg_nanos(),
+            self.decrypt.count,
+            self.decrypt.failures,
+            self.decrypt.avg_nanos()
+        )
+    }
+}
+
+/// Totals of the crypto operations of all sessions
+pub struct CryptoStats {
+    speeds: Vec<(&'static str, f32)>,
+    algorithms: BTreeMap<&'static str, SessionStats>,
+    peers: HashMap<SocketAddr, (&'static str, SessionStats), Hash>,
+    handshake_failures: u64,
+}
+
+impl CryptoStats {
+    /// Creates the statistics with the results of the startup benchmark in MiB/s
+    pub fn new(speeds: Vec<(&'static str, f32)>) -> Self {
+        Self { speeds, algorithms: BTreeMap::new(), peers: HashMap::default(), handshake_failures: 0 }
+    }
+
+    /// Benchmarked speed of the algorithm in MiB/s
+    pub fn speed(&self, algorithm: &str) -> Option<f32> {
+        self.speeds.iter().find(|(a, _)| *a == algorithm).map(|(_, s)| *s)
+    }
+
+    /// Logs when a new session uses a slower algorithm than the fastest one of this node
+    pub fn check_algorithm(&self, peer: SocketAddr, algorithm: &'static str) {
+        let fastest = self.speeds.iter().max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+        match (fastest, self.speed(algorithm)) {
+            (Some((best, best_speed)), Some(speed)) if *best != algorithm => info!(
+                "Peer {} uses {} ({:.1} MiB/s) instead of the faster {} ({:.1} MiB/s)",
+                addr_nice(peer),
+                algorithm,
+                speed,
+                best,
+                best_speed
+            ),
+            (Some(_), None) => warn!("Peer {} uses an unencrypted connection", addr_nice(peer)),
+            _ => (),
+        }
+    }
+
+    /// Adds the operations of the session with the peer since the last call
+    pub fn add(&mut self, peer: SocketAddr, algorithm: &'static str, stats: &SessionStats) {
+        self.algorithms.entry(algorithm).or_default().add(stats);
+        let entry = self.peers.entry(peer).or_insert_with(|| (algorithm, SessionStats::default()));
+        entry.0 = algorithm;
+        entry.1.add(stats);
+    }
+
+    pub fn handshake_failed(&mut self) {
+        self.handshake_failures += 1;
+    }
+
+    /// Forgets the peers that are not connected anymore
+    pub fn retain_peers<F: FnMut(&SocketAddr) -> bool>(&mut self, mut keep: F) {
+        self.peers.retain(|addr, _| keep(addr));
+    }
+
+    /// Totals per algorithm, sorted by name
+    pub fn algorithms(&self) -> impl Iterator<Item = (&'static str, &SessionStats)> {
+        self.algorithms.iter().map(|(algo, stats)| (*algo, stats))
+    }
+
+    pub fn handshake_failures(&self) -> u64 {
+        self.handshake_failures
+    }
+
+    pub fn write_out<W: Write>(&self, out: &mut W) -> Result<(), io::Error> {
+        writeln!(out, "crypto:")?;
+        writeln!(out, "  handshake_failures: {}", self.handshake_failures)?;
+        writeln!(out, "  benchmark:")?;
+        for (algo, speed) in &self.speeds {
+            writeln!(out, "    {}: {:.1}", algo, speed)?;
+        }
+        writeln!(out, "  algorithms:")?;
+        for (algo, stats) in &self.algorithms {
+            write!(out, "    - {}: {{ ", algo)?;
+            stats.write_fields(out)?;
+            writeln!(out, " }}")?;
+        }
+        writeln!(out, "  peers:")?;
+        let mut peers: Vec<_> = self.peers.iter().collect();
+        peers.sort_by_key(|(addr, _)| **addr);
+        for (addr, (algo, stats)) in peers {
+            write!(out, "    - \"{}\": {{ algorithm: {}, ", addr_nice(*addr), algo)?;
+            stats.write_fields(out)?;
+            writeln!(out, " }}")?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn crypto_stats_per_algorithm_and_peer() {
+    let mut stats = CryptoStats::new(vec![("AES128", 1000.0), ("CHACHA20", 500.0)]);
+    let peer1 = "1.2.3.4:3210".parse().unwrap();
+    let peer2 = "5.6.7.8:3210".parse().unwrap();
+    let mut session = SessionStats::default();
+    for i in 0..100 {
+        let start = session.decrypt.begin();
+        session.decrypt.end(start, i != 0);
+    }
+    let start = session.init.begin_timed();
+    session.init.end(start, true);
+    session.handshakes = 1;
+    // Only a sample of the operations is timed
+    assert_eq!(session.decrypt.timed, 2);
+    assert_eq!(session.init.timed, 1);
+    stats.add(peer1, "AES128", &session);
+    stats.add(peer2, "AES128", &session);
+    stats.add(peer2, "AES128", &SessionStats { handshakes: 0, ..session.clone() });
+    stats.retain_peers(|addr| *addr == peer2);
+    let algos: Vec<_> = stats.algorithms().collect();
+    assert_eq!(algos.len(), 1);
+    assert_eq!(algos[0].0, "AES128");
+    assert_eq!(algos[0].1.decrypt.count, 300);
+    assert_eq!(algos[0].1.decrypt.failures, 3);
+    assert_eq!(algos[0].1.handshakes, 2);
+    assert_eq!(stats.peers.len(), 1);
+    assert_eq!(stats.peers[&peer2].1.decrypt.count, 200);
+    assert_eq!(stats.speed("CHACHA20"), Some(500.0));
+    assert_eq!(stats.speed("PLAIN"), None);
+}
@@ -3,7 +3,6 @@
 // This software is licensed under GPL-3 or newer (see LICENSE.md)
 
 use std::{
-    cmp,
     collections::VecDeque,
     convert::TryInto,
     fmt,
@@ -19,6 +18,9 @@ use crate::{crypto, error::Error, util::MsgBuffer};
 
 static TUNSETIFF: libc::c_ulong = 1074025674;
 
+/// The largest supported MTU, leaving room for headers and crypto overhead in the message buffers
+pub const MAX_MTU: usize = 65000;
+
 #[repr(C)]
 union IfReqData {
     flags: libc::c_short,
@@ -53,6 +55,16 @@ pub enum Type {
     Tap,
 }
 
+impl Type {
+    /// Returns the size of the frame header that a payload of this device type has on top of the MTU
+    pub fn frame_overhead(self) -> usize {
+        match self {
+            Type::Tun => 0,
+            Type::Tap => 14,
+        }
+    }
+}
+
 impl fmt::Display for Type {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match *self {
@@ -106,6 +118,24 @@ pub trait Device: AsRawFd {
     fn write(&mut self, buffer: &mut MsgBuffer) -> Result<(), Error>;
 
     fn get_ip(&self) -> Result<Ipv4Addr, Error>;
+
+    /// Returns the MTU of the device if it is known
+    fn mtu(&self) -> Option<usize> {
+        None
+    }
+
+    /// Sets the address and netmask of the device while it is running
+    ///
+    /// # Errors
+    /// This method will return an error if the device does not support it or the underlying call fails.
+    fn set_address(&mut self, _addr: Ipv4Addr, _netmask: Ipv4Addr) -> Result<(), Error> {
+        Err(Error::Device("Setting the address is not supported by this device"))
+    }
+
+    /// Returns whether another packet/frame can be read without blocking
+    fn has_pending(&self) -> bool {
+        false
+    }
 }
 
 /// Represents a tun/tap device
@@ -156,6 +186,14 @@ impl TunTapDevice {
         }
     }
 
+    /// Creates a device from an already opened tun/tap file descriptor
+    ///
+    /// This is useful when the device has been created by another process or by the embedding
+    /// program. The device is used as is, the name is only used for logging and hooks.
+    pub fn from_file(fd: File, ifname: &str, type_: Type) -> Self {
+        Self { fd, ifname: ifname.to_owned(), type_ }
+    }
+
     /// Returns the default device path for a given type
     #[inline]
     pub fn default_path(type_: Type) -> &'static str {
@@ -236,26 +274,24 @@ impl TunTapDevice {
         set_device_mtu(&self.ifname, value)
     }
 
-    pub fn configure(&self, addr: Ipv4Addr, netmask: Ipv4Addr) -> io::Result<()> {
-        set_device_addr(&self.ifname, addr)?;
-        set_device_netmask(&self.ifname, netmask)?;
-        set_device_enabled(&self.ifname, true)
+    pub fn get_mtu(&self) -> io::Result<usize> {
+        get_device_mtu(&self.ifname)
     }
 
-    pub fn get_rp_filter(&self) -> io::Result<u8> {
-        Ok(cmp::max(get_rp_filter("all")?, get_rp_filter(&self.ifname)?))
+    /// Sets the MAC address of a TAP device, this must happen before the device is enabled
+    pub fn set_mac(&self, mac: [u8; 6]) -> io::Result<()> {
+        info!(
+            "Setting MAC {} on device {}",
+            mac.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":"),
+            self.ifname
+        );
+        set_device_mac(&self.ifname, mac)
     }
 
-    pub fn fix_rp_filter(&self) -> io::Result<()> {
-        if get_rp_filter("all")? > 1 {
-            info!("Setting net.ipv4.conf.all.rp_filter=1");
-            set_rp_filter("all", 1)?
-        }
-        if get_rp_filter(&self.ifname)? != 1 {
-            info!("Setting net.ipv4.conf.{}.rp_filter=1", self.ifname);
-            set_rp_filter(&self.ifname, 1)?
-        }
-        Ok(())
+    pub fn configure(&self, addr: Ipv4Addr, netmask: Ipv4Addr) -> io::Result<()> {
+        set_device_addr(&self.ifname, addr)?;
+        set_device_netmask(&self.ifname, netmask)?;
+        set_device_enabled(&self.ifname, true)
     }
 }
 
@@ -287,6 +323,19 @@ impl Device for TunTapDevice {
     fn get_ip(&self) -> Result<Ipv4Addr, Error> {
         get_device_addr(&self.ifname).map_err(|e| Error::DeviceIo("Error getting IP address", e))
     }
+
+    fn mtu(&self) -> Option<usize> {
+        self.get_mtu().ok()
+    }
+
+    fn set_address(&mut self, addr: Ipv4Addr, netmask: Ipv4Addr) -> Result<(), Error> {
+        self.configure(addr, netmask).map_err(|e| Error::DeviceIo("Error setting IP address", e))
+    }
+
+    fn has_pending(&self) -> bool {
+        let mut fds = libc::pollfd { fd: self.fd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        unsafe { libc::poll(&mut fds, 1, 0) == 1 && fds.revents & libc::POLLIN != 0 }
+    }
 }
 
 impl AsRawFd for TunTapDevice {
@@ -297,6 +346,8 @@ impl AsRawFd for TunTapDevice {
 }
 
 pub struct MockDevice {
+    type_: Type,
+    ip: Option<Ipv4Addr>,
     inbound: VecDeque<Vec<u8>>,
     outbound: VecDeque<Vec<u8>>,
 }
@@ -306,6 +357,10 @@ impl MockDevice {
         Default::default()
     }
 
+    pub fn with_type(type_: Type) -> Self {
+        Self { type_, ..Default::default() }
+    }
+
     pub fn put_inbound(&mut self, data: Vec<u8>) {
         self.inbound.push_back(data)
     }
@@ -317,11 +372,15 @@ impl MockDevice {
     pub fn has_inbound(&self) -> bool {
         !self.inbound.is_empty()
     }
+
+    pub fn set_ip(&mut self, ip: Ipv4Addr) {
+        self.ip = Some(ip)
+    }
 }
 
 impl Device for MockDevice {
     fn get_type(&self) -> Type {
-        Type::Tun
+        self.type_
     }
 
     fn ifname(&self) -> &str {
@@ -345,13 +404,27 @@ impl Device for MockDevice {
     }
 
     fn get_ip(&self) -> Result<Ipv4Addr, Error> {
-        Err(Error::Device("Dummy devices have no IP address"))
+        self.ip.ok_or(Error::Device("Dummy devices have no IP address"))
+    }
+
+    fn set_address(&mut self, addr: Ipv4Addr, _netmask: Ipv4Addr) -> Result<(), Error> {
+        self.set_ip(addr);
+        Ok(())
+    }
+
+    fn has_pending(&self) -> bool {
+        self.has_inbound()
     }
 }
 
 impl Default for MockDevice {
     fn default() -> Self {
-        Self { outbound: VecDeque::with_capacity(10), inbound: VecDeque::with_capacity(10) }
+        Self {
+            type_: Type::Tun,
+            ip: None,
+            outbound: VecDeque::with_capacity(10),
+            inbound: VecDeque::with_capacity(10)
+        }
     }
 }
 
@@ -374,6 +447,22 @@ fn set_device_mtu(ifname: &str, mtu: usize) -> io::Result<()> {
     }
 }
 
+#[allow(clippy::useless_conversion)]
+fn set_device_mac(ifname: &str, mac: [u8; 6]) -> io::Result<()> {
+    let sock = UdpSocket::bind("0.0.0.0:0")?;
+    let mut ifreq = IfReq::new(ifname);
+    // struct sockaddr with the hardware type followed by the address
+    let mut data = [0; 24];
+    data[..2].copy_from_slice(&libc::ARPHRD_ETHER.to_ne_bytes());
+    data[2..8].copy_from_slice(&mac);
+    ifreq.data._dummy = data;
+    let res = unsafe { libc::ioctl(sock.as_raw_fd(), libc::SIOCSIFHWADDR.try_into().unwrap(), &mut ifreq) };
+    match res {
+        0 => Ok(()),
+        _ => Err(IoError::last_os_error()),
+    }
+}
+
 #[allow(clippy::useless_conversion)]
 fn get_device_mtu(ifname: &str) -> io::Result<usize> {
     let sock = UdpSocket::bind("0.0.0.0:0")?;
@@ -485,15 +574,3 @@ fn get_default_device() -> io::Result<String> {
         Err(io::Error::new(io::ErrorKind::NotFound, "No default interface found".to_string()))
     }
 }
-
-fn get_rp_filter(device: &str) -> io::Result<u8> {
-    let mut fd = File::open(format!("/proc/sys/net/ipv4/conf/{}/rp_filter", device))?;
-    let mut contents = String::with_capacity(10);
-    fd.read_to_string(&mut contents)?;
-    u8::from_str(contents.trim()).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid rp_filter value"))
-}
-
-fn set_rp_filter(device: &str, val: u8) -> io::Result<()> {
-    let mut fd = File::create(format!("/proc/sys/net/ipv4/conf/{}/rp_filter", device))?;
-    writeln!(fd, "{}", val)
-}
@@ -0,0 +1,582 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+use fnv::FnvHasher;
+use std::{
+    collections::HashMap,
+    fs::File,
+    hash::BuildHasherDefault,
+    io::{self, BufRead, BufReader, Write},
+    marker::PhantomData,
+    net::Ipv4Addr,
+    str::FromStr,
+};
+
+use crate::{
+    arp::MacAddr,
+    error::Error,
+    payload::ETHERTYPE_IPV4,
+    util::{Duration, Time, TimeSource},
+};
+
+type Hash = BuildHasherDefault<FnvHasher>;
+
+pub const DEFAULT_LEASE_TIME: Duration = 3600;
+const OFFER_TIMEOUT: Duration = 60;
+
+const PROTO_UDP: u8 = 17;
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const BOOTP_REQUEST: u8 = 1;
+const BOOTP_REPLY: u8 = 2;
+const BOOTP_HEADER_LEN: usize = 240;
+const BOOTP_MIN_LEN: usize = 300;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_DECLINE: u8 = 4;
+const DHCP_ACK: u8 = 5;
+const DHCP_NAK: u8 = 6;
+const DHCP_RELEASE: u8 = 7;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Address of this node on the overlay with prefix length, e.g. 10.0.0.1/24
+    pub server: String,
+    /// First and last address of the pool, e.g. 10.0.0.100-10.0.0.200
+    pub range: String,
+    #[serde(default)]
+    pub router: Option<String>,
+    #[serde(default)]
+    pub dns: Vec<String>,
+    #[serde(default)]
+    pub lease_time: Option<Duration>,
+    #[serde(default)]
+    pub lease_file: Option<String>,
+}
+
+fn parse_ip(value: &str, msg: &'static str) -> Result<Ipv4Addr, Error> {
+    Ipv4Addr::from_str(value.trim()).map_err(|_| Error::InvalidConfig(msg))
+}
+
+struct DhcpRequest {
+    msg_type: u8,
+    xid: [u8; 4],
+    flags: [u8; 2],
+    ciaddr: Ipv4Addr,
+    giaddr: [u8; 4],
+    chaddr: MacAddr,
+    requested_ip: Option<Ipv4Addr>,
+    server_id: Option<Ipv4Addr>,
+}
+
+impl DhcpRequest {
+    fn parse(data: &[u8]) -> Option<Self> {
+        // HOT PATH
+        if data.len() < 14 + 20 + 8 + BOOTP_HEADER_LEN || u16::from_be_bytes([data[12], data[13]]) != ETHERTYPE_IPV4 {
+            return None;
+        }
+        let ip = &data[14..];
+        if ip[0] >> 4 != 4 || ip[9] != PROTO_UDP {
+            return None;
+        }
+        let header_len = (ip[0] & 0x0f) as usize * 4;
+        if header_len < 20 || ip.len() < header_len + 8 + BOOTP_HEADER_LEN {
+            return None;
+        }
+        let udp = &ip[header_len..];
+        if u16::from_be_bytes([udp[2], udp[3]]) != DHCP_SERVER_PORT {
+            return None;
+        }
+        // COLD PATH
+        let bootp = &udp[8..];
+        if bootp[0] != BOOTP_REQUEST || bootp[1] != 1 || bootp[2] != 6 || bootp[236..240] != MAGIC_COOKIE {
+            return None;
+        }
+        let mut msg_type = None;
+        let mut requested_ip = None;
+        let mut server_id = None;
+        let options = &bootp[BOOTP_HEADER_LEN..];
+        let mut pos = 0;
+        while pos < options.len() {
+            match options[pos] {
+                OPTION_PAD => {
+                    pos += 1;
+                    continue;
+                }
+                OPTION_END => break,
+                _ => (),
+            }
+            if pos + 1 >= options.len() || pos + 2 + options[pos + 1] as usize > options.len() {
+                return None;
+            }
+            let value = &options[pos + 2..pos + 2 + options[pos + 1] as usize];
+            match (options[pos], value.len()) {
+                (OPTION_MESSAGE_TYPE, 1) => msg_type = Some(value[0]),
+                (OPTION_REQUESTED_IP, 4) => requested_ip = Some(Ipv4Addr::new(value[0], value[1], value[2], value[3])),
+                (OPTION_SERVER_ID, 4) => server_id = Some(Ipv4Addr::new(value[0], value[1], value[2], value[3])),
+                _ => (),
+            }
+            pos += 2 + value.len();
+        }
+        let mut xid = [0; 4];
+        xid.copy_from_slice(&bootp[4..8]);
+        let mut giaddr = [0; 4];
+        giaddr.copy_from_slice(&bootp[24..28]);
+        let mut chaddr = [0; 6];
+        chaddr.copy_from_slice(&bootp[28..34]);
+        Some(Self {
+            msg_type: msg_type?,
+            xid,
+            flags: [bootp[10], bootp[11]],
+            ciaddr: Ipv4Addr::new(bootp[12], bootp[13], bootp[14], bootp[15]),
+            giaddr,
+            chaddr,
+            requested_ip,
+            server_id,
+        })
+    }
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in header.chunks(2) {
+        sum += u32::from(u16::from_be_bytes([chunk[0], chunk[1]]));
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn format_mac(mac: &MacAddr) -> String {
+    mac.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")
+}
+
+fn parse_mac(value: &str) -> Option<MacAddr> {
+    let mut mac = [0; 6];
+    let mut parts = value.split(':');
+    for byte in &mut mac {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(mac)
+}
+
+struct Lease {
+    ip: Ipv4Addr,
+    timeout: Time,
+    /// Whether the lease has been acknowledged or is only offered
+    bound: bool,
+}
+
+/// A DHCP server that hands out addresses from a pool to hosts on the overlay
+///
+/// Bound leases are written to the lease file so that hosts keep their addresses across
+/// restarts. As the file does not store expiry times, loaded leases are valid for a full lease
+/// time after startup.
+pub struct DhcpServer<TS: TimeSource> {
+    mac: MacAddr,
+    server_ip: Ipv4Addr,
+    netmask: Ipv4Addr,
+    pool: (u32, u32),
+    router: Option<Ipv4Addr>,
+    dns: Vec<Ipv4Addr>,
+    lease_time: Duration,
+    lease_file: Option<String>,
+    leases: HashMap<MacAddr, Lease, Hash>,
+    declined: HashMap<Ipv4Addr, Time, Hash>,
+    _dummy: PhantomData<TS>,
+}
+
+impl<TS: TimeSource> DhcpServer<TS> {
+    pub fn new(config: &Config, mac: MacAddr) -> Result<Self, Error> {
+        let (server_ip, prefix_len) = match config.server.find('/') {
+            Some(pos) => (
+                parse_ip(&config.server[..pos], "Invalid DHCP server address")?,
+                config.server[pos + 1..]
+                    .parse::<u8>()
+                    .map_err(|_| Error::InvalidConfig("Invalid DHCP prefix length"))?,
+            ),
+            None => return Err(Error::InvalidConfig("DHCP server address must have the form address/prefix")),
+        };
+        if prefix_len == 0 || prefix_len > 30 {
+            return Err(Error::InvalidConfig("Invalid DHCP prefix length"));
+        }
+        let mask = !0u32 << (32 - prefix_len);
+        let pool = match config.range.find('-') {
+            Some(pos) => (
+                u32::from(parse_ip(&config.range[..pos], "Invalid DHCP range")?),
+                u32::from(parse_ip(&config.range[pos + 1..], "Invalid DHCP range")?),
+            ),
+            None => return Err(Error::InvalidConfig("DHCP range must have the form first-last")),
+        };
+        let subnet = u32::from(server_ip) & mask;
+        if pool.0 > pool.1 || pool.0 & mask != subnet || pool.1 & mask != subnet {
+            return Err(Error::InvalidConfig("DHCP range must be within the subnet of the server address"));
+        }
+        if pool.0 & !mask == 0 || pool.1 & !mask == !mask {
+            return Err(Error::InvalidConfig("DHCP range must not contain the network or broadcast address"));
+        }
+        let router = match config.router {
+            Some(ref router) => Some(parse_ip(router, "Invalid DHCP router address")?),
+            None => None,
+        };
+        let dns =
+            config.dns.iter().map(|dns| parse_ip(dns, "Invalid DHCP DNS server address")).collect::<Result<_, _>>()?;
+        let mut server = Self {
+            mac,
+            server_ip,
+            netmask: Ipv4Addr::from(mask),
+            pool,
+            router,
+            dns,
+            lease_time: config.lease_time.unwrap_or(DEFAULT_LEASE_TIME),
+            lease_file: config.lease_file.clone(),
+            leases: HashMap::default(),
+            declined: HashMap::default(),
+            _dummy: PhantomData,
+        };
+        server.load_leases().map_err(|e| Error::FileIo("Failed to read DHCP lease file", e))?;
+        Ok(server)
+    }
+
+    fn load_leases(&mut self) -> Result<(), io::Error> {
+        let path = match self.lease_file {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let timeout = TS::now() + self.lease_time as Time;
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let mut parts = line.split_whitespace();
+            let lease = match (parts.next().and_then(parse_mac), parts.next().and_then(|ip| ip.parse().ok())) {
+                (Some(mac), Some(ip)) if self.in_pool(ip) => (mac, ip),
+                _ => {
+                    warn!("Ignoring invalid DHCP lease: {}", line);
+                    continue;
+                }
+            };
+            self.leases.insert(lease.0, Lease { ip: lease.1, timeout, bound: true });
+        }
+        info!("Loaded {} DHCP leases", self.leases.len());
+        Ok(())
+    }
+
+    fn store_leases(&self) {
+        let path = match self.lease_file {
+            Some(ref path) => path,
+            None => return,
+        };
+        let res = File::create(path).and_then(|mut f| {
+            for (mac, lease) in self.leases.iter().filter(|(_, lease)| lease.bound) {
+                writeln!(&mut f, "{} {}", format_mac(mac), lease.ip)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = res {
+            error!("Failed to write DHCP lease file {}: {}", path, e);
+        }
+    }
+
+    fn in_pool(&self, ip: Ipv4Addr) -> bool {
+        let ip = u32::from(ip);
+        ip >= self.pool.0 && ip <= self.pool.1
+    }
+
+    fn is_available(&self, ip: Ipv4Addr, mac: &MacAddr) -> bool {
+        let now = TS::now();
+        self.in_pool(ip)
+            && self.declined.get(&ip).map(|timeout| *timeout < now).unwrap_or(true)
+            && !self.leases.iter().any(|(m, lease)| m != mac && lease.ip == ip && lease.timeout >= now)
+    }
+
+    fn select_address(&self, request: &DhcpRequest) -> Option<Ipv4Addr> {
+        if let Some(lease) = self.leases.get(&request.chaddr) {
+            if self.is_available(lease.ip, &request.chaddr) {
+                return Some(lease.ip);
+            }
+        }
+        if let Some(ip) = request.requested_ip {
+            if self.is_available(ip, &request.chaddr) {
+                return Some(ip);
+            }
+        }
+        (self.pool.0..=self.pool.1).map(Ipv4Addr::from).find(|ip| self.is_available(*ip, &request.chaddr))
+    }
+
+    fn reply(&self, request: &DhcpRequest, msg_type: u8, address: Ipv4Addr) -> Vec<u8> {
+        let mut bootp = vec![0; BOOTP_HEADER_LEN];
+        bootp[0] = BOOTP_REPLY;
+        bootp[1] = 1;
+        bootp[2] = 6;
+        bootp[4..8].copy_from_slice(&request.xid);
+        bootp[10..12].copy_from_slice(&request.flags);
+        bootp[16..20].copy_from_slice(&address.octets());
+        bootp[24..28].copy_from_slice(&request.giaddr);
+        bootp[28..34].copy_from_slice(&request.chaddr);
+        bootp[236..240].copy_from_slice(&MAGIC_COOKIE);
+        bootp.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, msg_type]);
+        bootp.extend_from_slice(&[OPTION_SERVER_ID, 4]);
+        bootp.extend_from_slice(&self.server_ip.octets());
+        if msg_type != DHCP_NAK {
+            bootp.extend_from_slice(&[OPTION_LEASE_TIME, 4]);
+            bootp.extend_from_slice(&self.lease_time.to_be_bytes());
+            bootp.extend_from_slice(&[OPTION_SUBNET_MASK, 4]);
+            bootp.extend_from_slice(&self.netmask.octets());
+            if let Some(router) = self.router {
+                bootp.extend_from_slice(&[OPTION_ROUTER, 4]);
+                bootp.extend_from_slice(&router.octets());
+            }
+            if !self.dns.is_empty() {
+                bootp.extend_from_slice(&[OPTION_DNS, 4 * self.dns.len() as u8]);
+                for dns in &self.dns {
+                    bootp.extend_from_slice(&dns.octets());
+                }
+            }
+        }
+        bootp.push(OPTION_END);
+        if bootp.len() < BOOTP_MIN_LEN {
+            bootp.resize(BOOTP_MIN_LEN, OPTION_PAD);
+        }
+        // Replies are broadcast as the client has no address yet
+        let mut frame = vec![0xff; 6];
+        frame.extend_from_slice(&self.mac);
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        let ip_len = 20 + 8 + bootp.len();
+        let mut ip = [0; 20];
+        ip[0] = 0x45;
+        ip[2..4].copy_from_slice(&(ip_len as u16).to_be_bytes());
+        ip[8] = 64;
+        ip[9] = PROTO_UDP;
+        ip[12..16].copy_from_slice(&self.server_ip.octets());
+        ip[16..20].copy_from_slice(&Ipv4Addr::BROADCAST.octets());
+        let checksum = ipv4_checksum(&ip);
+        ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+        frame.extend_from_slice(&ip);
+        frame.extend_from_slice(&DHCP_SERVER_PORT.to_be_bytes());
+        frame.extend_from_slice(&DHCP_CLIENT_PORT.to_be_bytes());
+        frame.extend_from_slice(&((8 + bootp.len()) as u16).to_be_bytes());
+        // The UDP checksum is optional for IPv4
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(&bootp);
+        frame
+    }
+
+    /// Handles the frame if it is a DHCP request and returns the reply frame if there is one
+    pub fn handle(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        // HOT PATH
+        let request = DhcpRequest::parse(data)?;
+        // COLD PATH
+        let now = TS::now();
+        let mac = request.chaddr;
+        match request.msg_type {
+            DHCP_DISCOVER => {
+                let ip = match self.select_address(&request) {
+                    Some(ip) => ip,
+                    None => {
+                        warn!("DHCP pool exhausted, not answering request from {}", format_mac(&mac));
+                        return None;
+                    }
+                };
+                debug!("Offering {} to {} via DHCP", ip, format_mac(&mac));
+                let lease = self.leases.entry(mac).or_insert(Lease { ip, timeout: 0, bound: false });
+                if lease.ip != ip || !lease.bound {
+                    *lease = Lease { ip, timeout: now + OFFER_TIMEOUT as Time, bound: false };
+                }
+                Some(self.reply(&request, DHCP_OFFER, ip))
+            }
+            DHCP_REQUEST => {
+                if let Some(server_id) = request.server_id {
+                    if server_id != self.server_ip {
+                        // The client has chosen a different server
+                        if self.leases.get(&mac).map(|lease| !lease.bound).unwrap_or(false) {
+                            self.leases.remove(&mac);
+                        }
+                        return None;
+                    }
+                }
+                let ip = request.requested_ip.or_else(|| Some(request.ciaddr).filter(|ip| !ip.is_unspecified()))?;
+                if !self.is_available(ip, &mac) {
+                    info!("Rejecting DHCP request for {} from {}", ip, format_mac(&mac));
+                    return Some(self.reply(&request, DHCP_NAK, Ipv4Addr::UNSPECIFIED));
+                }
+                info!("Leasing {} to {} via DHCP", ip, format_mac(&mac));
+                self.leases.insert(mac, Lease { ip, timeout: now + self.lease_time as Time, bound: true });
+                self.store_leases();
+                Some(self.reply(&request, DHCP_ACK, ip))
+            }
+            DHCP_DECLINE => {
+                if let Some(ip) = request.requested_ip {
+                    warn!("Address {} is already in use, declined by {}", ip, format_mac(&mac));
+                    self.declined.insert(ip, now + self.lease_time as Time);
+                }
+                if self.leases.remove(&mac).map(|lease| lease.bound).unwrap_or(false) {
+                    self.store_leases();
+                }
+                None
+            }
+            DHCP_RELEASE => {
+                if self.leases.get(&mac).map(|lease| lease.ip == request.ciaddr).unwrap_or(false) {
+                    info!("Released DHCP lease of {} by {}", request.ciaddr, format_mac(&mac));
+                    self.leases.remove(&mac);
+                    self.store_leases();
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    pub fn housekeep(&mut self) {
+        let now = TS::now();
+        let bound = self.leases.values().filter(|lease| lease.bound).count();
+        self.leases.retain(|_, lease| lease.timeout >= now);
+        self.declined.retain(|_, timeout| *timeout >= now);
+        if self.leases.values().filter(|lease| lease.bound).count() != bound {
+            self.store_leases();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.leases.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leases.is_empty()
+    }
+}
+
+#[cfg(test)]
+use crate::util::MockTimeSource;
+
+#[cfg(test)]
+fn test_config() -> Config {
+    Config {
+        server: "10.0.0.1/24".to_string(),
+        range: "10.0.0.100-10.0.0.101".to_string(),
+        router: Some("10.0.0.254".to_string()),
+        dns: vec!["10.0.0.1".to_string()],
+        lease_time: None,
+        lease_file: None,
+    }
+}
+
+#[cfg(test)]
+pub fn dhcp_frame(msg_type: u8, mac: MacAddr, requested_ip: Option<[u8; 4]>, server_id: Option<[u8; 4]>) -> Vec<u8> {
+    let mut bootp = vec![0; BOOTP_HEADER_LEN];
+    bootp[0] = BOOTP_REQUEST;
+    bootp[1] = 1;
+    bootp[2] = 6;
+    bootp[4..8].copy_from_slice(&[1, 2, 3, 4]);
+    bootp[28..34].copy_from_slice(&mac);
+    bootp[236..240].copy_from_slice(&MAGIC_COOKIE);
+    bootp.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, msg_type]);
+    if let Some(ip) = requested_ip {
+        bootp.extend_from_slice(&[OPTION_REQUESTED_IP, 4]);
+        bootp.extend_from_slice(&ip);
+    }
+    if let Some(ip) = server_id {
+        bootp.extend_from_slice(&[OPTION_SERVER_ID, 4]);
+        bootp.extend_from_slice(&ip);
+    }
+    bootp.push(OPTION_END);
+    let mut frame = vec![0xff; 6];
+    frame.extend_from_slice(&mac);
+    frame.extend_from_slice(&[0x08, 0x00, 0x45, 0, 0, 0, 0, 0, 0, 0, 64, PROTO_UDP, 0, 0, 0, 0, 0, 0]);
+    frame.extend_from_slice(&[255, 255, 255, 255, 0, 68, 0, 67, 0, 0, 0, 0]);
+    frame.extend_from_slice(&bootp);
+    frame
+}
+
+#[cfg(test)]
+fn parse_reply(frame: &[u8]) -> (u8, Ipv4Addr) {
+    let bootp = &frame[42..];
+    assert_eq!(BOOTP_REPLY, bootp[0]);
+    assert_eq!(&[OPTION_MESSAGE_TYPE, 1], &bootp[240..242]);
+    (bootp[242], Ipv4Addr::new(bootp[16], bootp[17], bootp[18], bootp[19]))
+}
+
+#[test]
+fn dhcp_lease() {
+    MockTimeSource::set_time(0);
+    let mut server = DhcpServer::<MockTimeSource>::new(&test_config(), [2; 6]).unwrap();
+    let mac = [1, 2, 3, 4, 5, 6];
+    let offer = server.handle(&dhcp_frame(DHCP_DISCOVER, mac, None, None)).unwrap();
+    assert_eq!(0, ipv4_checksum(&offer[14..34]));
+    assert_eq!((DHCP_OFFER, Ipv4Addr::new(10, 0, 0, 100)), parse_reply(&offer));
+    let ack = server.handle(&dhcp_frame(DHCP_REQUEST, mac, Some([10, 0, 0, 100]), Some([10, 0, 0, 1]))).unwrap();
+    assert_eq!((DHCP_ACK, Ipv4Addr::new(10, 0, 0, 100)), parse_reply(&ack));
+    // Another client can not get the same address
+    let other = [1, 2, 3, 4, 5, 7];
+    let nak = server.handle(&dhcp_frame(DHCP_REQUEST, other, Some([10, 0, 0, 100]), None)).unwrap();
+    assert_eq!(DHCP_NAK, parse_reply(&nak).0);
+    let offer = server.handle(&dhcp_frame(DHCP_DISCOVER, other, Some([10, 0, 0, 100]), None)).unwrap();
+    assert_eq!((DHCP_OFFER, Ipv4Addr::new(10, 0, 0, 101)), parse_reply(&offer));
+    // Requests for other servers are ignored and the pool is exhausted
+    assert!(server.handle(&dhcp_frame(DHCP_REQUEST, other, Some([10, 0, 0, 101]), Some([10, 0, 0, 2]))).is_none());
+    server.handle(&dhcp_frame(DHCP_DISCOVER, other, None, None)).unwrap();
+    assert!(server.handle(&dhcp_frame(DHCP_DISCOVER, [9; 6], None, None)).is_none());
+    // Offers expire quickly, leases after the lease time
+    MockTimeSource::set_time(OFFER_TIMEOUT as Time + 1);
+    server.housekeep();
+    assert_eq!(1, server.len());
+    MockTimeSource::set_time(DEFAULT_LEASE_TIME as Time + 1);
+    server.housekeep();
+    assert!(server.is_empty());
+}
+
+#[test]
+fn dhcp_ignore_other_frames() {
+    MockTimeSource::set_time(0);
+    let mut server = DhcpServer::<MockTimeSource>::new(&test_config(), [2; 6]).unwrap();
+    let mut frame = dhcp_frame(DHCP_DISCOVER, [1; 6], None, None);
+    assert!(server.handle(&frame[..100]).is_none());
+    frame[37] = 68;
+    assert!(server.handle(&frame).is_none());
+}
+
+#[test]
+fn dhcp_lease_file() {
+    MockTimeSource::set_time(0);
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config { lease_file: Some(dir.path().join("leases").to_str().unwrap().to_string()), ..test_config() };
+    let mac = [1, 2, 3, 4, 5, 6];
+    let mut server = DhcpServer::<MockTimeSource>::new(&config, [2; 6]).unwrap();
+    server.handle(&dhcp_frame(DHCP_REQUEST, mac, Some([10, 0, 0, 101]), None)).unwrap();
+    let mut server = DhcpServer::<MockTimeSource>::new(&config, [2; 6]).unwrap();
+    assert_eq!(1, server.len());
+    let offer = server.handle(&dhcp_frame(DHCP_DISCOVER, mac, None, None)).unwrap();
+    assert_eq!((DHCP_OFFER, Ipv4Addr::new(10, 0, 0, 101)), parse_reply(&offer));
+}
+
+#[test]
+fn dhcp_invalid_config() {
+    let config = Config { range: "10.0.1.100-10.0.1.200".to_string(), ..test_config() };
+    assert!(DhcpServer::<MockTimeSource>::new(&config, [2; 6]).is_err());
+    let config = Config { range: "10.0.0.200-10.0.0.100".to_string(), ..test_config() };
+    assert!(DhcpServer::<MockTimeSource>::new(&config, [2; 6]).is_err());
+    let config = Config { server: "10.0.0.1".to_string(), ..test_config() };
+    assert!(DhcpServer::<MockTimeSource>::new(&config, [2; 6]).is_err());
+}
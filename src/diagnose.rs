@@ -0,0 +1,469 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Diagnosis of NAT and connectivity problems, see `vpncloud diagnose`
+//!
+//! The NAT type is determined by asking several STUN servers for the public address of the listen port. A helper
+//! peer is told about that address in a handshake from another port, so it tries to connect back to the listen port
+//! which shows whether other nodes can reach this node.
+
+use std::{
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use smallvec::smallvec;
+
+use crate::{
+    config::{Config, DEFAULT_PORT},
+    crypto::{is_init_message, Crypto, MessageResult},
+    error::Error,
+    messages::{NodeInfo, PeerInfo, ProtocolInfo, MESSAGE_TYPE_CLOSE},
+    net::{mapped_addr, parse_listen},
+    port_forwarding::PortForwarding,
+    util::{addr_nice, resolve_scoped, MsgBuffer, SystemTimeSource, TimeSource},
+};
+
+pub const DEFAULT_STUN_SERVERS: [&str; 2] = ["stun.l.google.com:19302", "stun1.l.google.com:19302"];
+
+const STUN_MAGIC_COOKIE: u32 = 0x2112_a442;
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_RESPONSE: u16 = 0x0101;
+const STUN_ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const STUN_ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const STUN_ATTEMPTS: usize = 2;
+
+const NATPMP_PORT: u16 = 5351;
+
+/// Time to wait for an answer of a STUN server or a router
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// Time to wait for the handshake with the helper and for its connection attempt
+const HELPER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NatType {
+    /// No STUN server answered
+    Blocked,
+    /// The listen port is directly reachable under its own address
+    Open,
+    /// The public address is the same for all destinations, NAT traversal works
+    EndpointIndependent,
+    /// The public address differs per destination, NAT traversal only works with reachable peers
+    Symmetric,
+}
+
+impl NatType {
+    pub fn classify(local: Option<SocketAddr>, mapped: &[SocketAddr]) -> Self {
+        let first = match mapped.first() {
+            Some(first) => mapped_addr(*first),
+            None => return NatType::Blocked,
+        };
+        if mapped.iter().any(|addr| mapped_addr(*addr) != first) {
+            NatType::Symmetric
+        } else if local.map(mapped_addr) == Some(first) {
+            NatType::Open
+        } else {
+            NatType::EndpointIndependent
+        }
+    }
+}
+
+impl fmt::Display for NatType {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            NatType::Blocked => write!(formatter, "unknown (no STUN server answered)"),
+            NatType::Open => write!(formatter, "none (public address)"),
+            NatType::EndpointIndependent => write!(formatter, "endpoint-independent mapping"),
+            NatType::Symmetric => write!(formatter, "symmetric (address depends on the destination)"),
+        }
+    }
+}
+
+pub struct Diagnosis {
+    pub local: Option<SocketAddr>,
+    pub stun: Vec<(String, Result<SocketAddr, String>)>,
+    pub upnp: Result<SocketAddr, String>,
+    pub natpmp: Result<Ipv4Addr, String>,
+    pub helper: Option<(String, Result<bool, String>)>,
+}
+
+impl Diagnosis {
+    pub fn nat_type(&self) -> NatType {
+        let mapped: Vec<_> = self.stun.iter().filter_map(|(_, res)| res.as_ref().ok()).copied().collect();
+        NatType::classify(self.local, &mapped)
+    }
+
+    fn port_forwarding_works(&self) -> bool {
+        self.upnp.is_ok() || self.natpmp.is_ok()
+    }
+
+    pub fn advice(&self) -> Vec<&'static str> {
+        let mut advice = vec![];
+        match self.nat_type() {
+            NatType::Blocked => advice.push(
+                "Outgoing UDP seems to be blocked or no STUN server is reachable. If UDP is blocked, use a websocket \
+                 proxy (see `vpncloud ws-proxy`).",
+            ),
+            NatType::Open => advice.push("This node has a public address, other nodes can connect to it directly."),
+            NatType::EndpointIndependent => advice.push(
+                "The NAT keeps the public port for all destinations, so connections to other nodes behind NAT \
+                 usually work via hole punching.",
+            ),
+            NatType::Symmetric => advice.push(
+                "The symmetric NAT changes the public port for every destination, so this node can only connect to \
+                 nodes that are reachable. Forward the listen port on the router or make sure some peers have a \
+                 public address.",
+            ),
+        }
+        match self.helper {
+            Some((_, Ok(true))) => advice.push("The listen port is reachable, other nodes can connect to this node."),
+            Some((_, Ok(false))) if self.port_forwarding_works() => advice.push(
+                "The listen port is not reachable even though the router supports port forwarding, check the \
+                 firewall of this machine.",
+            ),
+            Some((_, Ok(false))) => advice.push(
+                "The listen port is not reachable. Forward it on the router (UDP) or enable UPnP or NAT-PMP, \
+                 otherwise this node can only connect to reachable nodes.",
+            ),
+            Some((_, Err(_))) => advice.push("The helper could not be used, make sure it is running and trusts this node."),
+            None => advice.push("Use --helper with a running node to check whether the listen port is reachable."),
+        }
+        if self.upnp.is_err() && self.natpmp.is_ok() {
+            advice.push(
+                "The router supports NAT-PMP but not UPnP, VpnCloud only uses UPnP for automatic port forwarding, \
+                 so forward the port manually.",
+            );
+        }
+        advice
+    }
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        writeln!(formatter, "VpnCloud v{} diagnosis:", env!("CARGO_PKG_VERSION"))?;
+        match self.local {
+            Some(addr) => writeln!(formatter, "  {:<24} {}", "Local address", addr_nice(addr))?,
+            None => writeln!(formatter, "  {:<24} unknown (no route to the internet)", "Local address")?,
+        }
+        for (server, result) in &self.stun {
+            match result {
+                Ok(addr) => writeln!(formatter, "  {:<24} {}", format!("STUN {}", server), addr_nice(*addr))?,
+                Err(err) => writeln!(formatter, "  {:<24} failed: {}", format!("STUN {}", server), err)?,
+            }
+        }
+        writeln!(formatter, "  {:<24} {}", "NAT type", self.nat_type())?;
+        match self.upnp {
+            Ok(addr) => writeln!(formatter, "  {:<24} forwarded {}", "UPnP", addr_nice(addr))?,
+            Err(ref err) => writeln!(formatter, "  {:<24} failed: {}", "UPnP", err)?,
+        }
+        match self.natpmp {
+            Ok(ip) => writeln!(formatter, "  {:<24} external address {}", "NAT-PMP", ip)?,
+            Err(ref err) => writeln!(formatter, "  {:<24} failed: {}", "NAT-PMP", err)?,
+        }
+        match self.helper {
+            Some((ref helper, Ok(true))) => writeln!(formatter, "  {:<24} reachable from {}", "Listen port", helper)?,
+            Some((ref helper, Ok(false))) => {
+                writeln!(formatter, "  {:<24} not reachable from {}", "Listen port", helper)?
+            }
+            Some((ref helper, Err(ref err))) => {
+                writeln!(formatter, "  {:<24} helper {} failed: {}", "Listen port", helper, err)?
+            }
+            None => writeln!(formatter, "  {:<24} not checked (no helper)", "Listen port")?,
+        }
+        writeln!(formatter, "Advice:")?;
+        for advice in self.advice() {
+            writeln!(formatter, "  - {}", advice)?;
+        }
+        Ok(())
+    }
+}
+
+fn encode_stun_request(transaction: &[u8; 12]) -> [u8; 20] {
+    let mut data = [0; 20];
+    data[0..2].copy_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+    data[4..8].copy_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    data[8..20].copy_from_slice(transaction);
+    data
+}
+
+/// Extracts the mapped address from a STUN binding response to the given transaction
+fn parse_stun_response(data: &[u8], transaction: &[u8; 12]) -> Option<SocketAddr> {
+    if data.len() < 20
+        || u16::from_be_bytes([data[0], data[1]]) != STUN_BINDING_RESPONSE
+        || data[4..8] != STUN_MAGIC_COOKIE.to_be_bytes()
+        || &data[8..20] != transaction
+    {
+        return None;
+    }
+    let len = u16::from_be_bytes([data[2], data[3]]) as usize;
+    let mut attrs = data.get(20..20 + len)?;
+    let mut mapped = None;
+    while attrs.len() >= 4 {
+        let type_ = u16::from_be_bytes([attrs[0], attrs[1]]);
+        let attr_len = u16::from_be_bytes([attrs[2], attrs[3]]) as usize;
+        let value = attrs.get(4..4 + attr_len)?;
+        let xor = match type_ {
+            STUN_ATTR_XOR_MAPPED_ADDRESS => true,
+            STUN_ATTR_MAPPED_ADDRESS => false,
+            _ => {
+                attrs = attrs.get((4 + attr_len + 3) & !3..).unwrap_or(&[]);
+                continue;
+            }
+        };
+        if value.len() < 8 {
+            return None;
+        }
+        let mut port = u16::from_be_bytes([value[2], value[3]]);
+        let mut mask = [0; 16];
+        if xor {
+            port ^= (STUN_MAGIC_COOKIE >> 16) as u16;
+            mask[0..4].copy_from_slice(&data[4..8]);
+            mask[4..16].copy_from_slice(transaction);
+        }
+        let ip = match value[1] {
+            1 => {
+                let mut ip = [0; 4];
+                ip.iter_mut().zip(&value[4..8]).zip(&mask[0..4]).for_each(|((b, v), m)| *b = v ^ m);
+                IpAddr::V4(Ipv4Addr::from(ip))
+            }
+            2 if value.len() >= 20 => {
+                let mut ip = [0; 16];
+                ip.iter_mut().zip(&value[4..20]).zip(&mask).for_each(|((b, v), m)| *b = v ^ m);
+                IpAddr::V6(Ipv6Addr::from(ip))
+            }
+            _ => return None,
+        };
+        // The XOR variant is preferred as some routers rewrite addresses in packets
+        if xor || mapped.is_none() {
+            mapped = Some(SocketAddr::new(ip, port));
+        }
+        attrs = attrs.get((4 + attr_len + 3) & !3..).unwrap_or(&[]);
+    }
+    mapped
+}
+
+/// Asks a STUN server for the public address of the socket
+fn stun_request(socket: &UdpSocket, server: &str) -> Result<SocketAddr, String> {
+    let addrs = resolve_scoped(server).map_err(|err| err.to_string())?;
+    let server = *addrs.iter().find(|addr| addr.is_ipv4()).or_else(|| addrs.first()).ok_or("no address")?;
+    let mut buffer = [0; 1024];
+    for _ in 0..STUN_ATTEMPTS {
+        let transaction: [u8; 12] = rand::random();
+        socket.send_to(&encode_stun_request(&transaction), mapped_addr(server)).map_err(|err| err.to_string())?;
+        let deadline = Instant::now() + TIMEOUT;
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            socket.set_read_timeout(Some(timeout.max(Duration::from_millis(1)))).map_err(|err| err.to_string())?;
+            match socket.recv_from(&mut buffer) {
+                Ok((size, _)) => {
+                    if let Some(addr) = parse_stun_response(&buffer[..size], &transaction) {
+                        return Ok(addr);
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    }
+    Err("no answer".to_string())
+}
+
+/// Finds the default gateway in the contents of `/proc/net/route`
+fn parse_route_table(table: &str) -> Option<Ipv4Addr> {
+    for line in table.lines().skip(1) {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.len() > 2 && fields[1] == "00000000" {
+            let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+            // The kernel prints the address in host byte order
+            return Some(Ipv4Addr::from(u32::from_be(gateway.to_le())));
+        }
+    }
+    None
+}
+
+/// Asks the router for its external address via NAT-PMP
+fn natpmp_external_address() -> Result<Ipv4Addr, String> {
+    let table = fs::read_to_string("/proc/net/route").map_err(|err| err.to_string())?;
+    let gateway = parse_route_table(&table).ok_or("no default gateway")?;
+    let socket = UdpSocket::bind("0.0.0.0:0").map_err(|err| err.to_string())?;
+    socket.set_read_timeout(Some(TIMEOUT)).map_err(|err| err.to_string())?;
+    socket.send_to(&[0, 0], (gateway, NATPMP_PORT)).map_err(|err| err.to_string())?;
+    let mut buffer = [0; 16];
+    let (size, _) = socket.recv_from(&mut buffer).map_err(|_| format!("no answer from router {}", gateway))?;
+    if size < 12 || buffer[1] != 128 {
+        return Err("invalid answer".to_string());
+    }
+    match u16::from_be_bytes([buffer[2], buffer[3]]) {
+        0 => Ok(Ipv4Addr::new(buffer[8], buffer[9], buffer[10], buffer[11])),
+        code => Err(format!("router returned error code {}", code)),
+    }
+}
+
+/// Lets the helper try to connect to the given address and checks whether the connection arrives at the socket
+///
+/// The handshake is done from another port and announces the address as a peer, which makes the helper connect to
+/// it as it connects to all new peers of its peers.
+fn check_helper(listen: &UdpSocket, crypto: &Crypto, helper: SocketAddr, advertised: SocketAddr) -> Result<bool, Error> {
+    let socket = UdpSocket::bind("[::]:0").map_err(|e| Error::SocketIo("Failed to open socket", e))?;
+    let node_info = NodeInfo {
+        node_id: rand::random(),
+        peers: smallvec![PeerInfo { node_id: Some(rand::random()), addrs: smallvec![advertised] }],
+        claims: smallvec![],
+        peer_timeout: None,
+        addrs: smallvec![],
+        protocol: Some(ProtocolInfo::own()),
+        max_payload: None,
+        services: vec![],
+        name: None,
+        time: Some(SystemTimeSource::wall_clock()),
+        gossip: None,
+        loss: None,
+        reorder_window: None,
+    };
+    let mut peer = crypto.peer_instance::<_, SystemTimeSource>(node_info);
+    let mut msg = MsgBuffer::new(100);
+    peer.initialize(&mut msg)?;
+    let helper = mapped_addr(helper);
+    let send = |msg: &MsgBuffer| socket.send_to(msg.message(), helper).map_err(|e| Error::SocketIo("Failed to send", e));
+    send(&msg)?;
+    let deadline = Instant::now() + HELPER_TIMEOUT;
+    loop {
+        let timeout = deadline.checked_duration_since(Instant::now()).ok_or(Error::Message("Handshake timed out"))?;
+        socket.set_read_timeout(Some(timeout)).map_err(|e| Error::SocketIo("Failed to set timeout", e))?;
+        msg.clear();
+        let (size, src) = socket.recv_from(msg.buffer()).map_err(|_| Error::Message("Handshake timed out"))?;
+        msg.set_length(size);
+        if mapped_addr(src) != helper {
+            continue;
+        }
+        match peer.handle_message(&mut msg)? {
+            MessageResult::InitializedWithReply(_) => {
+                send(&msg)?;
+                break;
+            }
+            MessageResult::Initialized(_) => break,
+            MessageResult::Reply => {
+                send(&msg)?;
+            }
+            _ => (),
+        }
+    }
+    // Wait for the connection attempt of the helper on the listen port
+    let mut buffer = [0; 2048];
+    let mut reachable = false;
+    let deadline = Instant::now() + HELPER_TIMEOUT;
+    while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+        listen.set_read_timeout(Some(timeout.max(Duration::from_millis(1)))).ok();
+        match listen.recv_from(&mut buffer) {
+            Ok((size, src)) if src.ip() == helper.ip() || mapped_addr(src).ip() == helper.ip() => {
+                if is_init_message(&buffer[..size]) {
+                    reachable = true;
+                    break;
+                }
+            }
+            Ok(_) => (),
+            Err(_) => break,
+        }
+    }
+    msg.clear();
+    if peer.send_message(MESSAGE_TYPE_CLOSE, &mut msg).is_ok() {
+        send(&msg).ok();
+    }
+    Ok(reachable)
+}
+
+fn local_address(port: u16) -> Option<SocketAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("8.8.8.8:53").ok()?;
+    Some(SocketAddr::new(socket.local_addr().ok()?.ip(), port))
+}
+
+/// Runs all checks on the listen port of the config, the helper defaults to the first configured peer
+pub fn run(config: &Config, stun_servers: &[String], helper: Option<&str>) -> Result<Diagnosis, Error> {
+    let addr = parse_listen(&config.listen, DEFAULT_PORT).map_err(|e| Error::SocketIo("Invalid listen address", e))?;
+    let listen = UdpSocket::bind(addr).map_err(|e| Error::SocketIo("Failed to open listen port, is VpnCloud running?", e))?;
+    let local = local_address(addr.port());
+    let mut stun = vec![];
+    for server in stun_servers {
+        stun.push((server.clone(), stun_request(&listen, server)));
+    }
+    let upnp = match PortForwarding::new(addr.port(), &config.port_forwarding_description()) {
+        Some(pfw) => Ok(pfw.get_external_ip().into()),
+        None => Err("no router with UPnP found or port forwarding is not supported".to_string()),
+    };
+    let natpmp = natpmp_external_address();
+    let helper = helper.map(String::from).or_else(|| config.peers.first().map(|peer| peer.address.clone()));
+    let helper = helper.map(|helper| {
+        let advertised = stun.iter().find_map(|(_, res)| res.as_ref().ok().copied()).or(local);
+        let result = if config.crypto.password.is_none() && config.crypto.private_key.is_none() {
+            Err("needs a password or private key in the config".to_string())
+        } else {
+            resolve_scoped(&helper)
+                .and_then(|addrs| addrs.first().copied().ok_or(Error::Message("Helper address can not be resolved")))
+                .and_then(|helper_addr| {
+                    let crypto = Crypto::new(rand::random(), &config.crypto)?;
+                    let advertised = advertised.ok_or(Error::Message("Own address is unknown"))?;
+                    check_helper(&listen, &crypto, helper_addr, advertised)
+                })
+                .map_err(|err| err.to_string())
+        };
+        (helper, result)
+    });
+    Ok(Diagnosis { local, stun, upnp, natpmp, helper })
+}
+
+#[test]
+fn stun_response() {
+    let transaction = [1; 12];
+    let request = encode_stun_request(&transaction);
+    assert_eq!(request[0..8], [0, 1, 0, 0, 0x21, 0x12, 0xa4, 0x42]);
+    // Response with an unknown attribute, a MAPPED-ADDRESS and a XOR-MAPPED-ADDRESS for 1.2.3.4:3210
+    let mut response = vec![0x01, 0x01, 0, 32, 0x21, 0x12, 0xa4, 0x42];
+    response.extend_from_slice(&transaction);
+    response.extend_from_slice(&[0x80, 0x22, 0, 2, b'x', b'y', 0, 0]);
+    response.extend_from_slice(&[0, 0x01, 0, 8, 0, 1, 0x0c, 0x8a, 9, 9, 9, 9]);
+    response.extend_from_slice(&[0, 0x20, 0, 8, 0, 1, 0x0c ^ 0x21, 0x8a ^ 0x12, 1 ^ 0x21, 2 ^ 0x12, 3 ^ 0xa4, 4 ^ 0x42]);
+    assert_eq!(Some("1.2.3.4:3210".parse().unwrap()), parse_stun_response(&response, &transaction));
+    assert_eq!(None, parse_stun_response(&response, &[2; 12]));
+    assert_eq!(None, parse_stun_response(&response[..20], &transaction));
+    assert_eq!(None, parse_stun_response(&response[..30], &transaction));
+}
+
+#[test]
+fn nat_classification() {
+    let local = "192.168.1.2:3210".parse().ok();
+    let mapped1: SocketAddr = "1.2.3.4:3210".parse().unwrap();
+    let mapped2: SocketAddr = "1.2.3.4:4567".parse().unwrap();
+    assert_eq!(NatType::Blocked, NatType::classify(local, &[]));
+    assert_eq!(NatType::EndpointIndependent, NatType::classify(local, &[mapped1, mapped1]));
+    assert_eq!(NatType::Symmetric, NatType::classify(local, &[mapped1, mapped2]));
+    assert_eq!(NatType::Open, NatType::classify(Some(mapped1), &[mapped1, mapped1]));
+}
+
+#[test]
+fn default_gateway() {
+    let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+                 eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n\
+                 eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0\n";
+    assert_eq!(Some(Ipv4Addr::new(192, 168, 1, 1)), parse_route_table(table));
+    assert_eq!(None, parse_route_table(""));
+}
+
+#[test]
+fn diagnosis_advice() {
+    let diagnosis = Diagnosis {
+        local: "192.168.1.2:3210".parse().ok(),
+        stun: vec![
+            ("stun1".to_string(), Ok("1.2.3.4:3210".parse().unwrap())),
+            ("stun2".to_string(), Ok("1.2.3.4:4567".parse().unwrap())),
+        ],
+        upnp: Err("no router".to_string()),
+        natpmp: Err("no answer".to_string()),
+        helper: Some(("node1".to_string(), Ok(false))),
+    };
+    assert_eq!(NatType::Symmetric, diagnosis.nat_type());
+    let text = diagnosis.to_string();
+    assert!(text.contains("symmetric"));
+    assert!(text.contains("not reachable from node1"));
+    assert!(text.contains("Forward it on the router"));
+}
@@ -0,0 +1,177 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Pacing of outgoing connection attempts
+//!
+//! Connection attempts are started right away as long as less than `limits.dials` handshakes started by this node
+//! are pending and the `limits.dial-rate` for the current second is not used up. Otherwise they wait in a queue that
+//! is worked off every second, dialing the addresses that completed a handshake most recently first. This way a node
+//! with a long list of peers does not flood the network with handshakes on startup and the peers that are likely
+//! reachable are connected first.
+
+use std::{cmp::Reverse, collections::HashMap, marker::PhantomData, mem, net::SocketAddr};
+
+use crate::{
+    cloud::Hash,
+    messages::AddrList,
+    util::{Time, TimeSource},
+};
+
+/// Successful handshakes are remembered for this time to prioritize the addresses
+const ANSWER_MEMORY: Time = 86400;
+
+pub struct DialQueue<TS: TimeSource> {
+    /// Maximal number of pending dialed handshakes
+    parallel: usize,
+    /// Maximal number of dials per second
+    rate: Option<usize>,
+    queue: Vec<AddrList>,
+    /// Addresses that have been dialed and might still be pending
+    dialing: Vec<SocketAddr>,
+    /// Number of dials in the current second
+    started: usize,
+    /// Time of the last successful handshake with each address
+    answered: HashMap<SocketAddr, Time, Hash>,
+    _dummy: PhantomData<TS>,
+}
+
+impl<TS: TimeSource> DialQueue<TS> {
+    pub fn new(parallel: usize, rate: Option<usize>) -> Self {
+        Self {
+            parallel: parallel.max(1),
+            rate,
+            queue: vec![],
+            dialing: vec![],
+            started: 0,
+            answered: HashMap::default(),
+            _dummy: PhantomData,
+        }
+    }
+
+    /// Number of dials that can be started right now
+    fn room<F: Fn(&SocketAddr) -> bool>(&mut self, is_pending: F) -> usize {
+        self.dialing.retain(|a| is_pending(a));
+        let room = self.parallel.saturating_sub(self.dialing.len());
+        match self.rate {
+            Some(rate) => room.min(rate.saturating_sub(self.started)),
+            None => room,
+        }
+    }
+
+    /// Records that the addresses are dialed now
+    fn start(&mut self, addrs: &[SocketAddr]) {
+        self.dialing.extend_from_slice(addrs);
+        self.started += 1;
+    }
+
+    /// Checks whether the addresses can be dialed right away and records the dial if so
+    ///
+    /// If other dials are already waiting, the addresses have to wait as well so that the priorities are kept.
+    pub fn try_start<F: Fn(&SocketAddr) -> bool>(&mut self, addrs: &[SocketAddr], is_pending: F) -> bool {
+        if !self.queue.is_empty() || self.room(is_pending) == 0 {
+            return false;
+        }
+        self.start(addrs);
+        true
+    }
+
+    /// Puts the addresses into the queue, unless they are already waiting
+    pub fn push(&mut self, addrs: AddrList) {
+        if self.queue.iter().any(|q| q.iter().any(|a| addrs.contains(a))) {
+            return;
+        }
+        self.queue.push(addrs)
+    }
+
+    /// Removes the addresses from the queue
+    pub fn remove(&mut self, addrs: &[SocketAddr]) {
+        self.queue.retain(|q| !q.iter().any(|a| addrs.contains(a)))
+    }
+
+    /// Records a successful handshake with the address
+    pub fn answered(&mut self, addr: SocketAddr) {
+        self.answered.insert(addr, TS::now());
+    }
+
+    /// Time of the most recent successful handshake with one of the addresses
+    fn last_answer(&self, addrs: &[SocketAddr]) -> Option<Time> {
+        addrs.iter().filter_map(|a| self.answered.get(a)).max().copied()
+    }
+
+    /// Starts a new second and returns the queued dials that can be started now
+    ///
+    /// This has to be called once per second.
+    pub fn take_due<F: Fn(&SocketAddr) -> bool>(&mut self, is_pending: F) -> Vec<AddrList> {
+        self.started = 0;
+        let now = TS::now();
+        self.answered.retain(|_, time| *time + ANSWER_MEMORY > now);
+        let room = self.room(is_pending);
+        if room == 0 || self.queue.is_empty() {
+            return vec![];
+        }
+        let mut queue = mem::take(&mut self.queue);
+        // Stable sort keeps the order of addresses without answers
+        queue.sort_by_key(|addrs| Reverse(self.last_answer(addrs)));
+        let rest = queue.split_off(room.min(queue.len()));
+        self.queue = rest;
+        for addrs in &queue {
+            self.start(addrs);
+        }
+        queue
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+#[test]
+fn dial_queue_limits() {
+    use crate::util::MockTimeSource;
+    use smallvec::smallvec;
+    MockTimeSource::set_time(1000);
+    let addr = |i: u16| -> SocketAddr { format!("1.2.3.4:{}", i).parse().unwrap() };
+    let mut queue = DialQueue::<MockTimeSource>::new(2, None);
+    let pending = |_: &SocketAddr| true;
+    assert!(queue.try_start(&[addr(1)], pending));
+    assert!(queue.try_start(&[addr(2)], pending));
+    // Both dials are still pending
+    assert!(!queue.try_start(&[addr(3)], pending));
+    queue.push(smallvec![addr(3)]);
+    queue.push(smallvec![addr(4)]);
+    queue.push(smallvec![addr(3)]);
+    assert_eq!(queue.len(), 2);
+    assert!(queue.take_due(pending).is_empty());
+    // Addresses that answered before are dialed first
+    queue.answered(addr(4));
+    let due = queue.take_due(|a| *a == addr(1));
+    let expected: Vec<AddrList> = vec![smallvec![addr(4)]];
+    assert_eq!(due, expected);
+    assert_eq!(queue.len(), 1);
+    // New dials wait behind queued ones
+    assert!(!queue.try_start(&[addr(5)], |_| false));
+    queue.remove(&[addr(3)]);
+    assert!(queue.is_empty());
+    assert!(queue.try_start(&[addr(5)], |_| false));
+}
+
+#[test]
+fn dial_queue_rate() {
+    use crate::util::MockTimeSource;
+    use smallvec::smallvec;
+    let addr = |i: u16| -> SocketAddr { format!("1.2.3.4:{}", i).parse().unwrap() };
+    let mut queue = DialQueue::<MockTimeSource>::new(10, Some(1));
+    let done = |_: &SocketAddr| false;
+    assert!(queue.try_start(&[addr(1)], done));
+    assert!(!queue.try_start(&[addr(2)], done));
+    queue.push(smallvec![addr(2)]);
+    queue.push(smallvec![addr(3)]);
+    assert_eq!(queue.take_due(done).len(), 1);
+    assert_eq!(queue.take_due(done).len(), 1);
+    assert!(queue.take_due(done).is_empty());
+}
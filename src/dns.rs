@@ -0,0 +1,325 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+use fnv::FnvHasher;
+use std::{
+    collections::HashMap,
+    hash::BuildHasherDefault,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    str,
+    sync::{Arc, RwLock},
+    thread,
+};
+
+use crate::{error::Error, types::Range};
+
+type Hash = BuildHasherDefault<FnvHasher>;
+
+pub const DEFAULT_DNS_DOMAIN: &str = "vpn";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_REFUSED: u8 = 5;
+
+const TTL: u32 = 60;
+
+/// Checks whether the name can be used as a single DNS label
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 63
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+}
+
+struct DnsEntry {
+    name: String,
+    addrs: Vec<IpAddr>,
+}
+
+impl DnsEntry {
+    fn new(name: &str, claims: &[Range]) -> Self {
+        // Only single host claims are resolvable addresses
+        let addrs = claims
+            .iter()
+            .filter_map(|c| match (c.base.len, c.prefix_len) {
+                (4, 32) => {
+                    let mut ip = [0; 4];
+                    ip.copy_from_slice(&c.base.data[..4]);
+                    Some(IpAddr::V4(Ipv4Addr::from(ip)))
+                }
+                (16, 128) => Some(IpAddr::V6(Ipv6Addr::from(c.base.data))),
+                _ => None,
+            })
+            .collect();
+        Self { name: name.to_lowercase(), addrs }
+    }
+}
+
+/// The names and addresses of the own node and all peers that announced a name
+#[derive(Default)]
+pub struct DnsRecords {
+    own: Option<DnsEntry>,
+    peers: HashMap<SocketAddr, DnsEntry, Hash>,
+}
+
+impl DnsRecords {
+    pub fn set_own(&mut self, name: &str, claims: &[Range]) {
+        self.own = Some(DnsEntry::new(name, claims))
+    }
+
+    pub fn set_peer(&mut self, addr: SocketAddr, name: &str, claims: &[Range]) {
+        self.peers.insert(addr, DnsEntry::new(name, claims));
+    }
+
+    pub fn remove_peer(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &DnsEntry> {
+        self.own.iter().chain(self.peers.values())
+    }
+
+    fn resolve(&self, name: &str) -> Option<&[IpAddr]> {
+        self.entries().find(|e| e.name == name).map(|e| &e.addrs as &[IpAddr])
+    }
+
+    fn reverse(&self, ip: IpAddr) -> Option<&str> {
+        self.entries().find(|e| e.addrs.contains(&ip)).map(|e| &e.name as &str)
+    }
+}
+
+fn parse_name(data: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    loop {
+        let len = *data.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        if len > 63 {
+            // Compressed names are not expected in queries
+            return None;
+        }
+        let label = data.get(pos..pos + len)?;
+        pos += len;
+        labels.push(str::from_utf8(label).ok()?.to_lowercase());
+    }
+    Some((labels.join("."), pos))
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+fn parse_reverse_name(name: &str) -> Option<IpAddr> {
+    if let Some(rest) = name.strip_suffix(".in-addr.arpa") {
+        let mut ip = [0u8; 4];
+        let labels: Vec<&str> = rest.split('.').collect();
+        if labels.len() != 4 {
+            return None;
+        }
+        for (i, label) in labels.iter().rev().enumerate() {
+            ip[i] = label.parse().ok()?;
+        }
+        return Some(IpAddr::V4(Ipv4Addr::from(ip)));
+    }
+    if let Some(rest) = name.strip_suffix(".ip6.arpa") {
+        let mut ip = [0u8; 16];
+        let labels: Vec<&str> = rest.split('.').collect();
+        if labels.len() != 32 {
+            return None;
+        }
+        for (i, label) in labels.iter().rev().enumerate() {
+            let nibble = u8::from_str_radix(label, 16).ok()?;
+            if label.len() != 1 {
+                return None;
+            }
+            ip[i / 2] |= if i % 2 == 0 { nibble << 4 } else { nibble };
+        }
+        return Some(IpAddr::V6(Ipv6Addr::from(ip)));
+    }
+    None
+}
+
+fn write_answer(out: &mut Vec<u8>, type_: u16, data: &[u8]) {
+    // Pointer to the name in the question
+    out.extend_from_slice(&[0xc0, 12]);
+    out.extend_from_slice(&type_.to_be_bytes());
+    out.extend_from_slice(&CLASS_IN.to_be_bytes());
+    out.extend_from_slice(&TTL.to_be_bytes());
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+/// Creates a response to the given query
+///
+/// Returns `None` if the data is not a valid query that can be answered.
+pub fn handle_query(query: &[u8], domain: &str, records: &DnsRecords) -> Option<Vec<u8>> {
+    if query.len() < 12 || query[2] & 0xf8 != 0 || query[4..6] != [0, 1] {
+        // Only standard queries with a single question are supported
+        return None;
+    }
+    let (name, pos) = parse_name(query, 12)?;
+    let question = query.get(12..pos + 4)?;
+    let qtype = u16::from_be_bytes([query[pos], query[pos + 1]]);
+    let qclass = u16::from_be_bytes([query[pos + 2], query[pos + 3]]);
+    let mut answers = vec![];
+    let mut rcode = 0;
+    if qclass != CLASS_IN {
+        rcode = RCODE_REFUSED;
+    } else if let Some(ip) = parse_reverse_name(&name) {
+        match records.reverse(ip) {
+            Some(node) => {
+                if qtype == TYPE_PTR || qtype == TYPE_ANY {
+                    let mut data = vec![];
+                    write_name(&mut data, &format!("{}.{}", node, domain));
+                    answers.push((TYPE_PTR, data));
+                }
+            }
+            None => rcode = RCODE_NXDOMAIN,
+        }
+    } else if let Some(node) = name.strip_suffix(domain).and_then(|n| n.strip_suffix('.')) {
+        match records.resolve(node) {
+            Some(addrs) => {
+                for addr in addrs {
+                    match addr {
+                        IpAddr::V4(ip) if qtype == TYPE_A || qtype == TYPE_ANY => {
+                            answers.push((TYPE_A, ip.octets().to_vec()))
+                        }
+                        IpAddr::V6(ip) if qtype == TYPE_AAAA || qtype == TYPE_ANY => {
+                            answers.push((TYPE_AAAA, ip.octets().to_vec()))
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            None => rcode = RCODE_NXDOMAIN,
+        }
+    } else {
+        rcode = RCODE_REFUSED;
+    }
+    let mut out = Vec::with_capacity(512);
+    out.extend_from_slice(&query[0..2]);
+    // Response, authoritative, recursion desired copied from the query
+    out.push(0x84 | (query[2] & 0x01));
+    out.push(rcode);
+    out.extend_from_slice(&[0, 1]);
+    out.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    out.extend_from_slice(&[0, 0, 0, 0]);
+    out.extend_from_slice(question);
+    for (type_, data) in answers {
+        write_answer(&mut out, type_, &data);
+    }
+    Some(out)
+}
+
+/// Starts a DNS server on the given address in a background thread
+pub fn start_server(listen: &str, domain: &str, records: Arc<RwLock<DnsRecords>>) -> Result<(), Error> {
+    let socket = UdpSocket::bind(listen).map_err(|e| Error::SocketIo("Failed to open DNS socket", e))?;
+    let domain = domain.trim_matches('.').to_lowercase();
+    thread::spawn(move || {
+        let mut buffer = [0; 512];
+        loop {
+            let (len, addr) = match socket.recv_from(&mut buffer) {
+                Ok(res) => res,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Failed to receive DNS query: {}", e);
+                    break;
+                }
+            };
+            let response = handle_query(&buffer[..len], &domain, &records.read().expect("Lock poisoned"));
+            if let Some(response) = response {
+                if let Err(e) = socket.send_to(&response, addr) {
+                    warn!("Failed to send DNS response to {}: {}", addr, e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+fn query(name: &str, type_: u16) -> Vec<u8> {
+    let mut data = vec![0x12, 0x34, 0x01, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    write_name(&mut data, name);
+    data.extend_from_slice(&type_.to_be_bytes());
+    data.extend_from_slice(&CLASS_IN.to_be_bytes());
+    data
+}
+
+#[cfg(test)]
+use std::str::FromStr;
+
+#[cfg(test)]
+fn test_records() -> DnsRecords {
+    let mut records = DnsRecords::default();
+    records.set_own("node1", &[Range::from_str("10.0.0.1/32").unwrap()]);
+    records.set_peer(
+        "1.2.3.4:3210".parse().unwrap(),
+        "Node2",
+        &[
+            Range::from_str("10.0.0.2/32").unwrap(),
+            Range::from_str("10.0.2.0/24").unwrap(),
+            Range::from_str("fd00::2/128").unwrap(),
+        ],
+    );
+    records
+}
+
+#[test]
+fn dns_resolve() {
+    let records = test_records();
+    let response = handle_query(&query("node2.vpn", TYPE_A), "vpn", &records).unwrap();
+    assert_eq!([0x12, 0x34, 0x85, 0, 0, 1, 0, 1, 0, 0, 0, 0], response[..12]);
+    assert_eq!([10, 0, 0, 2], response[response.len() - 4..]);
+    let response = handle_query(&query("NODE2.vpn", TYPE_AAAA), "vpn", &records).unwrap();
+    assert_eq!([0, 1], response[6..8]);
+    assert_eq!(Ipv6Addr::from_str("fd00::2").unwrap().octets(), response[response.len() - 16..]);
+    let response = handle_query(&query("node1.vpn", TYPE_ANY), "vpn", &records).unwrap();
+    assert_eq!([0, 1], response[6..8]);
+    // Unknown names and foreign domains
+    let response = handle_query(&query("node3.vpn", TYPE_A), "vpn", &records).unwrap();
+    assert_eq!([0x85, RCODE_NXDOMAIN, 0, 1, 0, 0], response[2..8]);
+    let response = handle_query(&query("example.com", TYPE_A), "vpn", &records).unwrap();
+    assert_eq!([0x85, RCODE_REFUSED, 0, 1, 0, 0], response[2..8]);
+    // Invalid queries
+    assert!(handle_query(&[1, 2, 3], "vpn", &records).is_none());
+    assert!(handle_query(&query("node2.vpn", TYPE_A)[..20], "vpn", &records).is_none());
+}
+
+#[test]
+fn dns_reverse() {
+    let records = test_records();
+    let response = handle_query(&query("2.0.0.10.in-addr.arpa", TYPE_PTR), "vpn", &records).unwrap();
+    assert_eq!([0, 1], response[6..8]);
+    let mut name = vec![];
+    write_name(&mut name, "node2.vpn");
+    assert_eq!(&name as &[u8], &response[response.len() - name.len()..]);
+    let ipv6 = "2.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.d.f.ip6.arpa";
+    assert_eq!(Some(IpAddr::V6(Ipv6Addr::from_str("fd00::2").unwrap())), parse_reverse_name(ipv6));
+    let response = handle_query(&query(ipv6, TYPE_PTR), "vpn", &records).unwrap();
+    assert_eq!([0, 1], response[6..8]);
+    let response = handle_query(&query("3.0.0.10.in-addr.arpa", TYPE_PTR), "vpn", &records).unwrap();
+    assert_eq!(RCODE_NXDOMAIN, response[3]);
+}
+
+#[test]
+fn dns_names() {
+    assert!(is_valid_name("node-1"));
+    assert!(!is_valid_name(""));
+    assert!(!is_valid_name("node.1"));
+    assert!(!is_valid_name("-node"));
+}
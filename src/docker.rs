@@ -0,0 +1,341 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::Ipv4Addr,
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    process,
+    str::FromStr,
+};
+
+use crate::{device::Type, error::Error};
+
+pub const DEFAULT_SOCKET: &str = "/run/docker/plugins/vpncloud.sock";
+pub const DEFAULT_BRIDGE: &str = "vpncloud-br";
+
+const POOL_ID: &str = "vpncloud";
+const MAX_REQUEST_SIZE: usize = 1 << 16;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Path of the plugin socket, the file name is the name of the driver in Docker
+    #[serde(default)]
+    pub socket: Option<String>,
+    /// Name of the bridge that connects the containers with the tap device
+    #[serde(default)]
+    pub bridge: Option<String>,
+}
+
+fn parse_prefix(value: &str) -> Result<(u32, u8), Error> {
+    let (ip, prefix_len) = match value.find('/') {
+        Some(pos) => (&value[..pos], &value[pos + 1..]),
+        None => return Err(Error::InvalidConfigValue("Claim needs a prefix length", value.to_string())),
+    };
+    let ip = Ipv4Addr::from_str(ip).map_err(|_| Error::InvalidConfigValue("Invalid claim", value.to_string()))?;
+    let prefix_len = match u8::from_str(prefix_len) {
+        Ok(len) if len <= 30 => len,
+        _ => return Err(Error::InvalidConfigValue("Invalid prefix length", value.to_string())),
+    };
+    let mask = u32::max_value().checked_shl(32 - u32::from(prefix_len)).unwrap_or(0);
+    Ok((u32::from(ip) & mask, prefix_len))
+}
+
+fn ip(args: &[&str]) -> Result<(), String> {
+    debug!("Running ip {}", args.join(" "));
+    match process::Command::new("ip").args(args).output() {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Address management for the containers, the pool is the claimed prefix of this node
+struct Ipam {
+    network: u32,
+    prefix_len: u8,
+    allocated: HashSet<u32>,
+}
+
+impl Ipam {
+    fn new(network: u32, prefix_len: u8, reserved: &[Ipv4Addr]) -> Self {
+        let mut ipam = Self { network, prefix_len, allocated: HashSet::new() };
+        for addr in reserved {
+            if ipam.contains(u32::from(*addr)) {
+                ipam.allocated.insert(u32::from(*addr));
+            }
+        }
+        ipam
+    }
+
+    fn pool(&self) -> String {
+        format!("{}/{}", Ipv4Addr::from(self.network), self.prefix_len)
+    }
+
+    fn size(&self) -> u32 {
+        1 << (32 - self.prefix_len)
+    }
+
+    fn contains(&self, addr: u32) -> bool {
+        // Network and broadcast address are never handed out
+        addr > self.network && addr < self.network + self.size() - 1
+    }
+
+    fn format(&self, addr: u32) -> String {
+        format!("{}/{}", Ipv4Addr::from(addr), self.prefix_len)
+    }
+
+    fn request(&mut self, addr: Option<&str>) -> Result<String, String> {
+        let addr = match addr {
+            Some(addr) => {
+                let addr = addr.split('/').next().unwrap_or(addr);
+                let addr = u32::from(Ipv4Addr::from_str(addr).map_err(|_| format!("Invalid address: {}", addr))?);
+                if !self.contains(addr) || self.allocated.contains(&addr) {
+                    return Err(format!("Address {} is not available", Ipv4Addr::from(addr)));
+                }
+                addr
+            }
+            None => match (self.network + 1..self.network + self.size() - 1).find(|a| !self.allocated.contains(a)) {
+                Some(addr) => addr,
+                None => return Err(format!("No free address in {}", self.pool())),
+            },
+        };
+        self.allocated.insert(addr);
+        Ok(self.format(addr))
+    }
+
+    fn release(&mut self, addr: &str) {
+        let addr = addr.split('/').next().unwrap_or(addr);
+        if let Ok(addr) = Ipv4Addr::from_str(addr) {
+            self.allocated.remove(&u32::from(addr));
+        }
+    }
+}
+
+/// A Docker network and IPAM driver that attaches containers to the overlay
+///
+/// Every endpoint is a veth pair whose host side is part of a bridge together with the tap
+/// device, so containers are on the overlay as if they were hosts on the local network.
+pub struct Driver {
+    socket: String,
+    bridge: String,
+    ifname: String,
+    ipam: Ipam,
+    endpoints: HashMap<String, (String, String)>,
+}
+
+impl Driver {
+    pub fn new(config: &Config, ifname: &str, claims: &[String], reserved: &[Ipv4Addr]) -> Result<Self, Error> {
+        let pool = match claims.iter().find(|c| !c.contains(':')) {
+            Some(claim) => claim,
+            None => return Err(Error::InvalidConfig("The Docker driver needs a claimed IPv4 prefix")),
+        };
+        let (network, prefix_len) = parse_prefix(pool)?;
+        Ok(Self {
+            socket: config.socket.clone().unwrap_or_else(|| DEFAULT_SOCKET.to_string()),
+            bridge: config.bridge.clone().unwrap_or_else(|| DEFAULT_BRIDGE.to_string()),
+            ifname: ifname.to_string(),
+            ipam: Ipam::new(network, prefix_len, reserved),
+            endpoints: HashMap::default(),
+        })
+    }
+
+    /// Creates the bridge, adds the tap device to it and opens the plugin socket
+    pub fn setup(&self, device_type: Type) -> Result<UnixListener, Error> {
+        if device_type != Type::Tap {
+            return Err(Error::InvalidConfig("The Docker driver needs a tap device"));
+        }
+        if !Path::new("/sys/class/net").join(&self.bridge).exists() {
+            ip(&["link", "add", "name", &self.bridge, "type", "bridge"])
+                .map_err(|e| Error::DeviceIo("Failed to create bridge", io::Error::new(io::ErrorKind::Other, e)))?;
+        }
+        ip(&["link", "set", &self.ifname, "master", &self.bridge])
+            .and_then(|_| ip(&["link", "set", &self.bridge, "up"]))
+            .map_err(|e| Error::DeviceIo("Failed to setup bridge", io::Error::new(io::ErrorKind::Other, e)))?;
+        let path = Path::new(&self.socket);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| Error::FileIo("Failed to create plugin directory", e))?;
+        }
+        if path.exists() {
+            fs::remove_file(path).map_err(|e| Error::FileIo("Failed to remove old plugin socket", e))?;
+        }
+        info!("Serving Docker network driver on {}", self.socket);
+        UnixListener::bind(path).map_err(|e| Error::FileIo("Failed to open plugin socket", e))
+    }
+
+    fn mtu(&self) -> Option<String> {
+        fs::read_to_string(Path::new("/sys/class/net").join(&self.ifname).join("mtu"))
+            .ok()
+            .map(|s| s.trim().to_string())
+    }
+
+    fn create_endpoint(&mut self, id: &str) -> Result<Value, String> {
+        // Interface names are limited to 15 characters
+        let short_id = match id.get(..8) {
+            Some(short_id) => short_id,
+            None => return Err(format!("Invalid endpoint id: {}", id)),
+        };
+        let host = format!("vch{}", short_id);
+        let container = format!("vcc{}", short_id);
+        let mut args = vec!["link", "add", &host, "type", "veth", "peer", "name", &container];
+        let mtu = self.mtu();
+        if let Some(mtu) = &mtu {
+            args.extend_from_slice(&["mtu", mtu]);
+        }
+        ip(&args)?;
+        if let Some(mtu) = &mtu {
+            ip(&["link", "set", &host, "mtu", mtu])?;
+        }
+        ip(&["link", "set", &host, "master", &self.bridge]).and_then(|_| ip(&["link", "set", &host, "up"]))?;
+        info!("Created endpoint {} on {}", id, host);
+        self.endpoints.insert(id.to_string(), (host, container));
+        Ok(json!({}))
+    }
+
+    fn delete_endpoint(&mut self, id: &str) -> Result<Value, String> {
+        if let Some((host, _)) = self.endpoints.remove(id) {
+            info!("Deleting endpoint {}", id);
+            // Deleting one side also removes the peer
+            ip(&["link", "del", &host])?;
+        }
+        Ok(json!({}))
+    }
+
+    /// Handles a request to the plugin API and returns the response or an error message
+    pub fn handle(&mut self, path: &str, request: &Value) -> Result<Value, String> {
+        let field = |name: &str| request[name].as_str().filter(|s| !s.is_empty());
+        match path {
+            "/Plugin.Activate" => Ok(json!({"Implements": ["NetworkDriver", "IpamDriver"]})),
+            "/NetworkDriver.GetCapabilities" => Ok(json!({"Scope": "local", "ConnectivityScope": "global"})),
+            "/NetworkDriver.CreateEndpoint" => self.create_endpoint(field("EndpointID").unwrap_or("")),
+            "/NetworkDriver.DeleteEndpoint" => self.delete_endpoint(field("EndpointID").unwrap_or("")),
+            "/NetworkDriver.EndpointOperInfo" => Ok(json!({"Value": {}})),
+            "/NetworkDriver.Join" => match field("EndpointID").and_then(|id| self.endpoints.get(id)) {
+                Some((_, container)) => Ok(json!({"InterfaceName": {"SrcName": container, "DstPrefix": "eth"}})),
+                None => Err("Unknown endpoint".to_string()),
+            },
+            "/NetworkDriver.CreateNetwork"
+            | "/NetworkDriver.DeleteNetwork"
+            | "/NetworkDriver.Leave"
+            | "/NetworkDriver.DiscoverNew"
+            | "/NetworkDriver.DiscoverDelete"
+            | "/NetworkDriver.ProgramExternalConnectivity"
+            | "/NetworkDriver.RevokeExternalConnectivity"
+            | "/IpamDriver.ReleasePool" => Ok(json!({})),
+            "/IpamDriver.GetCapabilities" => Ok(json!({"RequiresMACAddress": false})),
+            "/IpamDriver.GetDefaultAddressSpaces" => {
+                Ok(json!({"LocalDefaultAddressSpace": POOL_ID, "GlobalDefaultAddressSpace": POOL_ID}))
+            }
+            "/IpamDriver.RequestPool" => match field("Pool") {
+                Some(pool) if pool != self.ipam.pool() => {
+                    Err(format!("Only the claimed prefix {} can be used", self.ipam.pool()))
+                }
+                _ => Ok(json!({"PoolID": POOL_ID, "Pool": self.ipam.pool(), "Data": {}})),
+            },
+            "/IpamDriver.RequestAddress" => {
+                self.ipam.request(field("Address")).map(|addr| json!({"Address": addr, "Data": {}}))
+            }
+            "/IpamDriver.ReleaseAddress" => {
+                if let Some(addr) = field("Address") {
+                    self.ipam.release(addr)
+                }
+                Ok(json!({}))
+            }
+            _ => Err(format!("Unsupported request: {}", path)),
+        }
+    }
+
+    fn handle_connection(&mut self, stream: UnixStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let path = line.split_whitespace().nth(1).unwrap_or("").to_string();
+        let mut length = 0;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            let mut parts = line.splitn(2, ':');
+            if parts.next().unwrap_or("").trim().eq_ignore_ascii_case("content-length") {
+                length = usize::from_str(parts.next().unwrap_or("").trim()).unwrap_or(0).min(MAX_REQUEST_SIZE);
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        let request = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        debug!("Docker driver request {}: {}", path, request);
+        let (status, response) = match self.handle(&path, &request) {
+            Ok(response) => ("200 OK", response),
+            Err(err) => {
+                warn!("Docker driver request {} failed: {}", path, err);
+                ("500 Internal Server Error", json!({ "Err": err }))
+            }
+        };
+        let response = response.to_string();
+        let mut stream = stream;
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: application/vnd.docker.plugins.v1+json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            response.len(),
+            response
+        )
+    }
+
+    /// Serves the plugin API until the listener fails
+    pub fn serve(mut self, listener: UnixListener) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(err) = self.handle_connection(stream) {
+                        warn!("Failed to handle Docker driver request: {}", err)
+                    }
+                }
+                Err(err) => {
+                    error!("Docker driver socket failed: {}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn ipam_allocation() {
+    let mut ipam = Ipam::new(u32::from(Ipv4Addr::new(10, 0, 1, 0)), 30, &[Ipv4Addr::new(10, 0, 1, 1)]);
+    assert_eq!(ipam.pool(), "10.0.1.0/30");
+    assert_eq!(ipam.request(None), Ok("10.0.1.2/30".to_string()));
+    assert!(ipam.request(None).is_err());
+    ipam.release("10.0.1.2/30");
+    assert!(ipam.request(Some("10.0.1.3/30")).is_err());
+    assert!(ipam.request(Some("10.0.1.1")).is_err());
+    assert_eq!(ipam.request(Some("10.0.1.2/30")), Ok("10.0.1.2/30".to_string()));
+}
+
+#[test]
+fn driver_requests() {
+    let config = Config { socket: None, bridge: None };
+    assert!(Driver::new(&config, "vpncloud0", &[], &[]).is_err());
+    let claims = vec!["fd00::/64".to_string(), "10.0.1.0/24".to_string()];
+    let mut driver = Driver::new(&config, "vpncloud0", &claims, &[Ipv4Addr::new(10, 0, 1, 1)]).unwrap();
+    assert_eq!(driver.socket, DEFAULT_SOCKET);
+    let res = driver.handle("/Plugin.Activate", &Value::Null).unwrap();
+    assert_eq!(res, json!({"Implements": ["NetworkDriver", "IpamDriver"]}));
+    let res = driver.handle("/IpamDriver.RequestPool", &json!({"Pool": ""})).unwrap();
+    assert_eq!(res["Pool"], "10.0.1.0/24");
+    assert!(driver.handle("/IpamDriver.RequestPool", &json!({"Pool": "10.0.2.0/24"})).is_err());
+    let res = driver.handle("/IpamDriver.RequestAddress", &json!({"PoolID": POOL_ID})).unwrap();
+    assert_eq!(res["Address"], "10.0.1.2/24");
+    driver.handle("/IpamDriver.ReleaseAddress", &json!({"Address": "10.0.1.2/24"})).unwrap();
+    let res = driver.handle("/IpamDriver.RequestAddress", &json!({"PoolID": POOL_ID})).unwrap();
+    assert_eq!(res["Address"], "10.0.1.2/24");
+    assert!(driver.handle("/NetworkDriver.Join", &json!({"EndpointID": "0123456789abcdef"})).is_err());
+    assert!(driver.handle("/NetworkDriver.Unknown", &Value::Null).is_err());
+}
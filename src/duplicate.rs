@@ -0,0 +1,93 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Duplication of critical packets for lossy links
+//!
+//! Selected packets are sent twice to a peer, once on the current path and once via another address of the peer, or
+//! again on the current path if the peer has no other address. Both copies carry the same sequence number, so that
+//! the peer writes only the first copy that arrives to its device. This trades bandwidth for reliability, e.g. for
+//! industrial control traffic.
+
+/// Length of the sequence number in front of duplicated packets
+pub const SEQ_LEN: usize = 4;
+
+/// Number of sequence numbers that are remembered, older packets are dropped
+const WINDOW_SIZE: u32 = 64;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Config {
+    /// DSCP values of the packets that are duplicated
+    pub dscp: Vec<u8>,
+    /// Packets up to this size are duplicated regardless of their DSCP value
+    pub max_size: Option<usize>,
+}
+
+impl Config {
+    /// Whether a packet with the given DSCP value and size is duplicated
+    #[inline]
+    pub fn selects(&self, dscp: Option<u8>, size: usize) -> bool {
+        // HOT PATH
+        self.max_size.map(|max| size <= max).unwrap_or(false)
+            || dscp.map(|dscp| self.dscp.contains(&dscp)).unwrap_or(false)
+    }
+}
+
+/// Detects the second copies of duplicated packets from one peer by their sequence numbers
+#[derive(Default)]
+pub struct DuplicateFilter {
+    /// Highest sequence number that has been received
+    last: u32,
+    /// Bit n is set if the sequence number `last - n` has been received
+    seen: u64,
+}
+
+impl DuplicateFilter {
+    /// Returns whether the packet with the given sequence number has not been received before
+    pub fn check(&mut self, seq: u32) -> bool {
+        // HOT PATH
+        let diff = seq.wrapping_sub(self.last) as i32;
+        if diff > 0 {
+            self.seen = if diff as u32 >= WINDOW_SIZE { 0 } else { self.seen << diff };
+            self.seen |= 1;
+            self.last = seq;
+            return true;
+        }
+        let age = self.last.wrapping_sub(seq);
+        if age >= WINDOW_SIZE || self.seen & (1 << age) != 0 {
+            return false;
+        }
+        self.seen |= 1 << age;
+        true
+    }
+}
+
+#[test]
+fn duplicate_filter() {
+    let mut filter = DuplicateFilter::default();
+    assert!(filter.check(1));
+    assert!(!filter.check(1));
+    // Reordered packets are accepted once
+    assert!(filter.check(3));
+    assert!(filter.check(2));
+    assert!(!filter.check(2));
+    assert!(!filter.check(3));
+    // Packets outside of the window are dropped
+    assert!(filter.check(100));
+    assert!(!filter.check(100 - WINDOW_SIZE));
+    assert!(filter.check(101 - WINDOW_SIZE));
+    // Sequence numbers wrap around
+    let mut filter = DuplicateFilter { last: u32::MAX, seen: 1 };
+    assert!(filter.check(0));
+    assert!(!filter.check(u32::MAX));
+}
+
+#[test]
+fn duplication_selection() {
+    let config = Config { dscp: vec![46], max_size: Some(128) };
+    assert!(config.selects(Some(46), 1000));
+    assert!(config.selects(None, 100));
+    assert!(!config.selects(Some(0), 1000));
+    assert!(!Config::default().selects(Some(46), 10));
+}
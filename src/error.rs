@@ -4,7 +4,35 @@
 
 use thiserror::Error;
 
-use std::io;
+use std::{fmt, io, net::SocketAddr};
+
+use crate::util::addr_nice;
+
+/// Phase of the communication with a peer in which an error happened
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Init,
+    Rotation,
+    Decryption,
+    Message,
+    Sending,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        formatter.write_str(match self {
+            Phase::Init => "init",
+            Phase::Rotation => "key rotation",
+            Phase::Decryption => "decryption",
+            Phase::Message => "message handling",
+            Phase::Sending => "sending",
+        })
+    }
+}
+
+fn describe_peer(peer: &Option<SocketAddr>) -> String {
+    peer.map(|p| format!(" with {}", addr_nice(p))).unwrap_or_default()
+}
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -26,6 +54,9 @@ pub enum Error {
     #[error("Invalid config: {0}")]
     InvalidConfig(&'static str),
 
+    #[error("Invalid config: {0}: {1}")]
+    InvalidConfigValue(&'static str, String),
+
     #[error("Socker error: {0}")]
     Socket(&'static str),
 
@@ -44,6 +75,9 @@ pub enum Error {
     #[error("Message error: {0}")]
     Message(&'static str),
 
+    #[error("Sandbox error: {0} ({1})")]
+    SandboxIo(&'static str, #[source] io::Error),
+
     #[error("Beacon error: {0} ({1})")]
     BeaconIo(&'static str, #[source] io::Error),
 
@@ -52,4 +86,96 @@ pub enum Error {
 
     #[error("Name can not be resolved: {0}")]
     NameUnresolvable(String),
+
+    /// Error reported by a running instance via the control socket
+    #[error("Control error: {0}")]
+    Control(String),
+
+    /// Error with information on the peer and the phase in which it happened
+    #[error("{source} (during {phase}{})", describe_peer(.peer))]
+    Context { peer: Option<SocketAddr>, phase: Phase, source: Box<Error> },
+}
+
+impl Error {
+    /// Stable numeric code of the error class
+    ///
+    /// The codes are part of the interface and must not change, see the error codes section of the manual.
+    pub fn code(&self) -> u16 {
+        match self {
+            Error::CryptoInit(_) => 101,
+            Error::CryptoInitFatal(_) => 102,
+            Error::Crypto(_) => 103,
+            Error::InvalidCryptoState(_) => 104,
+            Error::InvalidConfig(_) => 201,
+            Error::InvalidConfigValue(_, _) => 202,
+            Error::Socket(_) => 301,
+            Error::SocketIo(_, _) => 302,
+            Error::Device(_) => 401,
+            Error::DeviceIo(_, _) => 402,
+            Error::FileIo(_, _) => 501,
+            Error::SandboxIo(_, _) => 502,
+            Error::BeaconIo(_, _) => 503,
+            Error::Message(_) => 601,
+            Error::Parse(_) => 602,
+            Error::NameUnresolvable(_) => 701,
+            Error::Control(_) => 801,
+            Error::Context { source, .. } => source.code(),
+        }
+    }
+
+    /// The underlying error without context
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Context { source, .. } => source.root(),
+            _ => self,
+        }
+    }
+
+    pub fn peer(&self) -> Option<SocketAddr> {
+        match self {
+            Error::Context { peer, .. } => *peer,
+            _ => None,
+        }
+    }
+
+    pub fn phase(&self) -> Option<Phase> {
+        match self {
+            Error::Context { phase, .. } => Some(*phase),
+            _ => None,
+        }
+    }
+
+    /// Adds the phase to the error unless it already has one
+    pub fn in_phase(self, phase: Phase) -> Self {
+        match self {
+            Error::Context { .. } => self,
+            _ => Error::Context { peer: None, phase, source: Box::new(self) },
+        }
+    }
+
+    /// Adds the peer to the error and the phase unless it already has one
+    pub fn with_peer(self, peer: SocketAddr, phase: Phase) -> Self {
+        match self {
+            Error::Context { peer: None, phase, source } => Error::Context { peer: Some(peer), phase, source },
+            Error::Context { .. } => self,
+            _ => Error::Context { peer: Some(peer), phase, source: Box::new(self) },
+        }
+    }
+}
+
+#[test]
+fn error_context() {
+    use std::str::FromStr;
+    let peer = SocketAddr::from_str("1.2.3.4:3210").unwrap();
+    let err = Error::Crypto("invalid signature").in_phase(Phase::Init);
+    assert_eq!(err.peer(), None);
+    let err = err.with_peer(peer, Phase::Message);
+    assert_eq!(err.code(), 103);
+    assert_eq!(err.peer(), Some(peer));
+    assert_eq!(err.phase(), Some(Phase::Init));
+    assert!(matches!(err.root(), Error::Crypto(_)));
+    assert_eq!(err.to_string(), "Crypto error: invalid signature (during init with 1.2.3.4:3210)");
+    let err = Error::Message("Unknown message type").with_peer(peer, Phase::Message);
+    assert_eq!(err.to_string(), "Message error: Unknown message type (during message handling with 1.2.3.4:3210)");
+    assert_eq!(Error::Parse("test").code(), 602);
 }
@@ -0,0 +1,294 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Forward error correction for lossy paths
+//!
+//! Payload to a peer is sent in groups of `group-size` packets, followed by a parity packet that is the XOR of all
+//! packets of the group, each one prefixed with its length and padded to the longest one. If a single packet of a
+//! group gets lost, the peer recovers it from the other packets and the parity packet instead of waiting for the
+//! inner TCP to retransmit it.
+//!
+//! FEC is only applied to peers that support it and that report a packet loss above `loss-threshold` on the
+//! messages they receive from this node. It is switched off again once the loss drops below half of the threshold.
+
+use std::collections::VecDeque;
+
+use crate::{error::Error, util::MsgBuffer};
+
+/// Length of the group id and the index in front of protected packets and parity packets
+pub const FEC_HEADER_LEN: usize = 5;
+
+/// Groups can have at most this many packets so that the received packets fit into a bitmask
+pub const MAX_GROUP_SIZE: usize = 16;
+
+/// Number of recent groups that are kept for recovery
+const RECOVERY_GROUPS: usize = 4;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Config {
+    /// Packet loss between 0 and 1 reported by the peer above which FEC is applied
+    pub loss_threshold: f64,
+    /// Number of packets that are protected by one parity packet
+    pub group_size: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { loss_threshold: 0.02, group_size: 4 }
+    }
+}
+
+impl Config {
+    /// Whether FEC should be applied with the given packet loss, with a hysteresis to avoid flapping
+    pub fn is_needed(&self, loss: f64, active: bool) -> bool {
+        if active {
+            loss >= self.loss_threshold / 2.0
+        } else {
+            loss >= self.loss_threshold
+        }
+    }
+}
+
+/// XORs the length and the data of the packet into the accumulated parity
+fn xor_into(parity: &mut Vec<u8>, data: &[u8]) {
+    // HOT PATH
+    if parity.len() < data.len() + 2 {
+        parity.resize(data.len() + 2, 0);
+    }
+    let len = (data.len() as u16).to_be_bytes();
+    parity[0] ^= len[0];
+    parity[1] ^= len[1];
+    for (p, d) in parity[2..].iter_mut().zip(data) {
+        *p ^= d
+    }
+}
+
+/// Adds the protection of the packets sent to one peer
+pub struct FecEncoder {
+    group: u32,
+    count: u8,
+    parity: Vec<u8>,
+}
+
+impl FecEncoder {
+    pub fn new() -> Self {
+        Self { group: 1, count: 0, parity: vec![] }
+    }
+
+    /// Adds the packet to the current group and prepends the FEC header to it
+    ///
+    /// Returns whether the group is complete so that its parity packet should be sent.
+    pub fn add(&mut self, data: &mut MsgBuffer, group_size: usize) -> bool {
+        // HOT PATH
+        xor_into(&mut self.parity, data.message());
+        data.set_start(data.get_start() - FEC_HEADER_LEN);
+        let header = data.message_mut();
+        header[..4].copy_from_slice(&self.group.to_be_bytes());
+        header[4] = self.count;
+        self.count += 1;
+        self.count as usize >= group_size.min(MAX_GROUP_SIZE)
+    }
+
+    /// Whether the current group has packets without parity
+    pub fn is_pending(&self) -> bool {
+        self.count > 0
+    }
+
+    /// Writes the parity packet of the current group into the buffer and starts a new group
+    pub fn take_parity(&mut self, buffer: &mut MsgBuffer) {
+        buffer.clear();
+        buffer.set_length(FEC_HEADER_LEN + self.parity.len());
+        let msg = buffer.message_mut();
+        msg[..4].copy_from_slice(&self.group.to_be_bytes());
+        msg[4] = self.count;
+        msg[FEC_HEADER_LEN..].copy_from_slice(&self.parity);
+        self.parity.clear();
+        self.count = 0;
+        self.group = self.group.wrapping_add(1);
+    }
+}
+
+impl Default for FecEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Group {
+    id: u32,
+    /// Bit n is set if packet n has been received or recovered
+    received: u16,
+    /// XOR of the received packets
+    xor: Vec<u8>,
+    /// Number of packets in the group and parity, once the parity packet has been received
+    parity: Option<(u8, Vec<u8>)>,
+}
+
+/// Recovers lost packets from one peer
+#[derive(Default)]
+pub struct FecDecoder {
+    groups: VecDeque<Group>,
+    recovered: Option<Vec<u8>>,
+}
+
+impl FecDecoder {
+    fn parse_header(data: &[u8]) -> Result<(u32, u8), Error> {
+        if data.len() < FEC_HEADER_LEN {
+            return Err(Error::Message("FEC message is too short"));
+        }
+        let id = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        if data[4] as usize > MAX_GROUP_SIZE {
+            return Err(Error::Message("Invalid FEC group"));
+        }
+        Ok((id, data[4]))
+    }
+
+    /// Returns the group with the given id, unless it is too old to be tracked
+    fn group(&mut self, id: u32) -> Option<&mut Group> {
+        if let Some(pos) = self.groups.iter().position(|g| g.id == id) {
+            return self.groups.get_mut(pos);
+        }
+        if let Some(newest) = self.groups.back() {
+            if (id.wrapping_sub(newest.id) as i32) < 0 {
+                return None;
+            }
+        }
+        if self.groups.len() >= RECOVERY_GROUPS {
+            self.groups.pop_front();
+        }
+        self.groups.push_back(Group { id, received: 0, xor: vec![], parity: None });
+        self.groups.back_mut()
+    }
+
+    /// Recovers the missing packet of the group if all others and the parity have been received
+    fn try_recover(group: &mut Group, recovered: &mut Option<Vec<u8>>) {
+        let (count, parity) = match group.parity {
+            Some((count, ref parity)) => (count, parity),
+            None => return,
+        };
+        let all = if count as usize >= MAX_GROUP_SIZE { u16::MAX } else { (1 << count) - 1 };
+        let missing = all & !group.received;
+        if missing.count_ones() != 1 {
+            return;
+        }
+        let mut data = parity.clone();
+        for (d, x) in data.iter_mut().zip(&group.xor) {
+            *d ^= x
+        }
+        group.received |= missing;
+        if data.len() < 2 {
+            return;
+        }
+        let len = u16::from_be_bytes([data[0], data[1]]) as usize;
+        if len + 2 <= data.len() {
+            data.truncate(len + 2);
+            data.drain(..2);
+            *recovered = Some(data);
+        }
+    }
+
+    /// Strips the FEC header from the packet and remembers it for the recovery of its group
+    ///
+    /// Returns `false` if the packet has already been recovered and must be dropped.
+    pub fn receive_data(&mut self, data: &mut MsgBuffer) -> Result<bool, Error> {
+        // HOT PATH
+        let (id, index) = Self::parse_header(data.message())?;
+        data.set_start(data.get_start() + FEC_HEADER_LEN);
+        if index as usize >= MAX_GROUP_SIZE {
+            return Err(Error::Message("Invalid FEC group"));
+        }
+        let mut recovered = None;
+        let new = match self.group(id) {
+            Some(group) => {
+                if group.received & (1 << index) != 0 {
+                    false
+                } else {
+                    group.received |= 1 << index;
+                    xor_into(&mut group.xor, data.message());
+                    Self::try_recover(group, &mut recovered);
+                    true
+                }
+            }
+            None => true,
+        };
+        if recovered.is_some() {
+            self.recovered = recovered;
+        }
+        Ok(new)
+    }
+
+    /// Remembers the parity packet for the recovery of its group
+    pub fn receive_parity(&mut self, data: &[u8]) -> Result<(), Error> {
+        let (id, count) = Self::parse_header(data)?;
+        let mut recovered = None;
+        if let Some(group) = self.group(id) {
+            if group.parity.is_none() {
+                group.parity = Some((count, data[FEC_HEADER_LEN..].to_vec()));
+                Self::try_recover(group, &mut recovered);
+            }
+        }
+        if recovered.is_some() {
+            self.recovered = recovered;
+        }
+        Ok(())
+    }
+
+    /// Returns the packet that has been recovered by the last received message
+    pub fn take_recovered(&mut self) -> Option<Vec<u8>> {
+        self.recovered.take()
+    }
+}
+
+#[test]
+fn recover_lost_packet() {
+    let packets: Vec<Vec<u8>> = vec![vec![1, 2, 3], vec![4, 5, 6, 7, 8], vec![9], vec![10, 11]];
+    let mut encoder = FecEncoder::new();
+    let mut sent = vec![];
+    for (i, packet) in packets.iter().enumerate() {
+        let mut buffer = MsgBuffer::new(FEC_HEADER_LEN);
+        buffer.clone_from(packet);
+        assert_eq!(encoder.add(&mut buffer, packets.len()), i == packets.len() - 1);
+        sent.push(buffer.message().to_vec());
+    }
+    let mut parity = MsgBuffer::new(0);
+    encoder.take_parity(&mut parity);
+    assert!(!encoder.is_pending());
+    // Any single packet can be recovered
+    for lost in 0..packets.len() {
+        let mut decoder = FecDecoder::default();
+        for (i, msg) in sent.iter().enumerate().filter(|(i, _)| *i != lost) {
+            let mut buffer = MsgBuffer::new(0);
+            buffer.clone_from(msg);
+            assert!(decoder.receive_data(&mut buffer).unwrap());
+            assert_eq!(buffer.message(), &packets[i][..]);
+        }
+        assert_eq!(decoder.take_recovered(), None);
+        decoder.receive_parity(parity.message()).unwrap();
+        assert_eq!(decoder.take_recovered(), Some(packets[lost].clone()));
+        // The lost packet is dropped if it arrives late
+        let mut buffer = MsgBuffer::new(0);
+        buffer.clone_from(&sent[lost]);
+        assert!(!decoder.receive_data(&mut buffer).unwrap());
+    }
+    // Two lost packets can not be recovered
+    let mut decoder = FecDecoder::default();
+    decoder.receive_parity(parity.message()).unwrap();
+    for msg in &sent[2..] {
+        let mut buffer = MsgBuffer::new(0);
+        buffer.clone_from(msg);
+        decoder.receive_data(&mut buffer).unwrap();
+    }
+    assert_eq!(decoder.take_recovered(), None);
+    assert!(FecDecoder::default().receive_parity(&[0, 0, 0]).is_err());
+}
+
+#[test]
+fn fec_hysteresis() {
+    let config = Config { loss_threshold: 0.1, group_size: 4 };
+    assert!(!config.is_needed(0.05, false));
+    assert!(config.is_needed(0.1, false));
+    assert!(config.is_needed(0.05, true));
+    assert!(!config.is_needed(0.04, true));
+}
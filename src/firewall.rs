@@ -0,0 +1,396 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+use std::str::FromStr;
+
+use crate::{
+    conntrack::{ConnTrack, Flow},
+    device::Type,
+    error::Error,
+    payload::{ETHERTYPE_IPV4, ETHERTYPE_IPV6, ETHERTYPE_VLAN},
+    types::{Address, Range},
+    util::{Duration, TimeSource},
+};
+
+pub const DEFAULT_CONNTRACK_SIZE: usize = 10000;
+pub const DEFAULT_CONNTRACK_TIMEOUT: Duration = 300;
+
+const PROTO_ICMP: u8 = 1;
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+const PROTO_ICMPV6: u8 = 58;
+const PROTO_SCTP: u8 = 132;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    #[serde(rename = "allow")]
+    Allow,
+    #[serde(rename = "deny")]
+    Deny,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// Packets received from peers that are about to be delivered to the local device
+    #[serde(rename = "in")]
+    In,
+    /// Packets read from the local device that are about to be forwarded to peers
+    #[serde(rename = "out")]
+    Out,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RuleConfig {
+    pub action: Action,
+    pub direction: Option<Direction>,
+    pub src: Option<String>,
+    pub dst: Option<String>,
+    pub protocol: Option<String>,
+    pub port: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Config {
+    pub default: Action,
+    pub rules: Vec<RuleConfig>,
+    pub stateful: bool,
+    pub conntrack_size: usize,
+    pub conntrack_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            default: Action::Allow,
+            rules: vec![],
+            stateful: false,
+            conntrack_size: DEFAULT_CONNTRACK_SIZE,
+            conntrack_timeout: DEFAULT_CONNTRACK_TIMEOUT,
+        }
+    }
+}
+
+fn parse_protocol(value: &str) -> Result<u8, Error> {
+    match &value.to_lowercase() as &str {
+        "icmp" => Ok(PROTO_ICMP),
+        "tcp" => Ok(PROTO_TCP),
+        "udp" => Ok(PROTO_UDP),
+        "icmpv6" => Ok(PROTO_ICMPV6),
+        "sctp" => Ok(PROTO_SCTP),
+        val => val.parse::<u8>().map_err(|_| Error::InvalidConfig("Invalid firewall protocol")),
+    }
+}
+
+fn parse_ports(value: &str) -> Result<(u16, u16), Error> {
+    let parse = |s: &str| s.trim().parse::<u16>().map_err(|_| Error::InvalidConfig("Invalid firewall port"));
+    let (min, max) = match value.find('-') {
+        Some(pos) => (parse(&value[..pos])?, parse(&value[pos + 1..])?),
+        None => {
+            let port = parse(value)?;
+            (port, port)
+        }
+    };
+    if min > max {
+        return Err(Error::InvalidConfig("Invalid firewall port range"));
+    }
+    Ok((min, max))
+}
+
+struct PacketInfo {
+    src: Address,
+    dst: Address,
+    protocol: u8,
+    ports: Option<(u16, u16)>,
+}
+
+impl PacketInfo {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let (src, dst, protocol, payload) = match data.first().map(|b| b >> 4) {
+            Some(4) => {
+                if data.len() < 20 {
+                    return None;
+                }
+                let header_len = (data[0] & 0x0f) as usize * 4;
+                if header_len < 20 || data.len() < header_len {
+                    return None;
+                }
+                // Only the first fragment contains the transport header
+                let fragment_offset = u16::from_be_bytes([data[6], data[7]]) & 0x1fff;
+                let payload = if fragment_offset == 0 { &data[header_len..] } else { &[] as &[u8] };
+                let mut src = [0; 16];
+                let mut dst = [0; 16];
+                src[0..4].copy_from_slice(&data[12..16]);
+                dst[0..4].copy_from_slice(&data[16..20]);
+                (Address { data: src, len: 4 }, Address { data: dst, len: 4 }, data[9], payload)
+            }
+            Some(6) => {
+                if data.len() < 40 {
+                    return None;
+                }
+                let mut src = [0; 16];
+                let mut dst = [0; 16];
+                src.copy_from_slice(&data[8..24]);
+                dst.copy_from_slice(&data[24..40]);
+                (Address { data: src, len: 16 }, Address { data: dst, len: 16 }, data[6], &data[40..])
+            }
+            _ => return None,
+        };
+        let ports = match protocol {
+            PROTO_TCP | PROTO_UDP | PROTO_SCTP if payload.len() >= 4 => {
+                Some((u16::from_be_bytes([payload[0], payload[1]]), u16::from_be_bytes([payload[2], payload[3]])))
+            }
+            _ => None,
+        };
+        Some(Self { src, dst, protocol, ports })
+    }
+
+    fn flow(&self) -> Flow {
+        let (src_port, dst_port) = self.ports.unwrap_or((0, 0));
+        Flow { protocol: self.protocol, src: self.src, dst: self.dst, src_port, dst_port }
+    }
+}
+
+struct Rule {
+    action: Action,
+    direction: Option<Direction>,
+    src: Option<Range>,
+    dst: Option<Range>,
+    protocol: Option<u8>,
+    ports: Option<(u16, u16)>,
+}
+
+impl Rule {
+    fn from_config(config: &RuleConfig) -> Result<Self, Error> {
+        let range = |val: &Option<String>| -> Result<Option<Range>, Error> {
+            match val {
+                Some(val) => {
+                    Range::from_str(val).map(Some).map_err(|_| Error::InvalidConfig("Invalid firewall subnet"))
+                }
+                None => Ok(None),
+            }
+        };
+        Ok(Self {
+            action: config.action,
+            direction: config.direction,
+            src: range(&config.src)?,
+            dst: range(&config.dst)?,
+            protocol: config.protocol.as_ref().map(|p| parse_protocol(p)).transpose()?,
+            ports: config.port.as_ref().map(|p| parse_ports(p)).transpose()?,
+        })
+    }
+
+    fn matches(&self, direction: Direction, packet: &PacketInfo) -> bool {
+        if let Some(dir) = self.direction {
+            if dir != direction {
+                return false;
+            }
+        }
+        if let Some(ref src) = self.src {
+            if !src.matches(packet.src) {
+                return false;
+            }
+        }
+        if let Some(ref dst) = self.dst {
+            if !dst.matches(packet.dst) {
+                return false;
+            }
+        }
+        if let Some(protocol) = self.protocol {
+            if protocol != packet.protocol {
+                return false;
+            }
+        }
+        if let Some((min, max)) = self.ports {
+            match packet.ports {
+                Some((_, port)) if port >= min && port <= max => (),
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// A stateless firewall for tunneled packets
+///
+/// The rules are evaluated in order and the first matching rule decides about the packet. If no
+/// rule matches, the default action is applied. Frames that do not contain IP packets (e.g. ARP)
+/// are never filtered.
+///
+/// In stateful mode, allowed connections are tracked and replies to them are allowed regardless
+/// of the rules.
+pub struct Firewall<TS: TimeSource> {
+    rules: Vec<Rule>,
+    default: Action,
+    type_: Type,
+    conntrack: Option<ConnTrack<TS>>,
+}
+
+impl<TS: TimeSource> Firewall<TS> {
+    pub fn new(config: &Config, type_: Type) -> Result<Self, Error> {
+        let rules = config.rules.iter().map(Rule::from_config).collect::<Result<_, _>>()?;
+        let conntrack =
+            if config.stateful { Some(ConnTrack::new(config.conntrack_size, config.conntrack_timeout)) } else { None };
+        Ok(Self { rules, default: config.default, type_, conntrack })
+    }
+
+    fn ip_packet<'a>(&self, data: &'a [u8]) -> Option<&'a [u8]> {
+        match self.type_ {
+            Type::Tun => Some(data),
+            Type::Tap => {
+                if data.len() < 14 {
+                    return None;
+                }
+                let (ethertype, offset) = match u16::from_be_bytes([data[12], data[13]]) {
+                    ETHERTYPE_VLAN if data.len() >= 18 => (u16::from_be_bytes([data[16], data[17]]), 18),
+                    ethertype => (ethertype, 14),
+                };
+                match ethertype {
+                    ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => Some(&data[offset..]),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    /// Checks whether the given packet or frame is allowed to pass in the given direction
+    pub fn allows(&mut self, direction: Direction, data: &[u8]) -> bool {
+        // HOT PATH
+        if self.rules.is_empty() && self.default == Action::Allow && self.conntrack.is_none() {
+            return true;
+        }
+        // COLD PATH
+        let packet = match self.ip_packet(data).and_then(PacketInfo::parse) {
+            Some(packet) => packet,
+            None => return true,
+        };
+        let flow = packet.flow();
+        if let Some(ref mut conntrack) = self.conntrack {
+            if conntrack.is_reply(&flow) {
+                return true;
+            }
+        }
+        let action = self.rules.iter().find(|r| r.matches(direction, &packet)).map(|r| r.action);
+        if action.unwrap_or(self.default) == Action::Deny {
+            return false;
+        }
+        if let Some(ref mut conntrack) = self.conntrack {
+            if !conntrack.track(flow) {
+                warn!("Connection tracking table is full, not tracking {:?}", flow);
+            }
+        }
+        true
+    }
+
+    pub fn housekeep(&mut self) {
+        if let Some(ref mut conntrack) = self.conntrack {
+            conntrack.housekeep()
+        }
+    }
+
+    pub fn conntrack_len(&self) -> usize {
+        self.conntrack.as_ref().map(|c| c.len()).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+use crate::util::MockTimeSource;
+
+#[cfg(test)]
+fn ipv4_packet(protocol: u8, src: [u8; 4], dst: [u8; 4], port: u16) -> Vec<u8> {
+    let mut data = vec![0x45, 0, 0, 24, 0, 0, 0, 0, 64, protocol, 0, 0];
+    data.extend_from_slice(&src);
+    data.extend_from_slice(&dst);
+    data.extend_from_slice(&[0x30, 0x39]);
+    data.extend_from_slice(&port.to_be_bytes());
+    data
+}
+
+#[test]
+fn firewall_rules() {
+    let config: Config = serde_yaml::from_str(
+        "
+default: deny
+rules:
+  - action: deny
+    direction: in
+    protocol: tcp
+    port: 22
+  - action: allow
+    src: 10.0.1.0/24
+  - action: allow
+    dst: 10.0.2.0/24
+    protocol: udp
+    port: 1000-2000
+",
+    )
+    .unwrap();
+    let mut firewall = Firewall::<MockTimeSource>::new(&config, Type::Tun).unwrap();
+    let ssh = ipv4_packet(PROTO_TCP, [10, 0, 1, 1], [10, 0, 2, 1], 22);
+    assert!(!firewall.allows(Direction::In, &ssh));
+    assert!(firewall.allows(Direction::Out, &ssh));
+    assert!(firewall.allows(Direction::In, &ipv4_packet(PROTO_TCP, [10, 0, 1, 1], [10, 0, 2, 1], 80)));
+    assert!(firewall.allows(Direction::In, &ipv4_packet(PROTO_UDP, [10, 0, 3, 1], [10, 0, 2, 1], 1500)));
+    assert!(!firewall.allows(Direction::In, &ipv4_packet(PROTO_UDP, [10, 0, 3, 1], [10, 0, 2, 1], 2500)));
+    assert!(!firewall.allows(Direction::In, &ipv4_packet(PROTO_TCP, [10, 0, 3, 1], [10, 0, 2, 1], 1500)));
+    // Non-IP data is not filtered
+    assert!(firewall.allows(Direction::In, &[1, 2, 3]));
+    let mut firewall = Firewall::<MockTimeSource>::new(&config, Type::Tap).unwrap();
+    let mut frame = vec![2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 8, 0];
+    frame.extend_from_slice(&ssh);
+    assert!(!firewall.allows(Direction::In, &frame));
+    frame[12] = 0x88;
+    assert!(firewall.allows(Direction::In, &frame));
+}
+
+#[test]
+fn firewall_invalid_config() {
+    let rule = RuleConfig { action: Action::Deny, direction: None, src: None, dst: None, protocol: None, port: None };
+    for (protocol, port) in &[(Some("foo"), None), (None, Some("bar")), (None, Some("20-10"))] {
+        let config = Config {
+            rules: vec![RuleConfig {
+                protocol: protocol.map(|s| s.to_string()),
+                port: port.map(|s| s.to_string()),
+                ..rule.clone()
+            }],
+            ..Config::default()
+        };
+        assert!(Firewall::<MockTimeSource>::new(&config, Type::Tun).is_err());
+    }
+    let config = Config { rules: vec![RuleConfig { src: Some("10.0.0.1".to_string()), ..rule }], ..Config::default() };
+    assert!(Firewall::<MockTimeSource>::new(&config, Type::Tun).is_err());
+}
+
+#[test]
+fn firewall_stateful() {
+    let config: Config = serde_yaml::from_str(
+        "
+default: deny
+stateful: true
+conntrack-timeout: 60
+rules:
+  - action: allow
+    direction: out
+    dst: 10.0.2.0/24
+    protocol: tcp
+",
+    )
+    .unwrap();
+    MockTimeSource::set_time(0);
+    let mut firewall = Firewall::<MockTimeSource>::new(&config, Type::Tun).unwrap();
+    let request = ipv4_packet(PROTO_TCP, [10, 0, 1, 1], [10, 0, 2, 1], 22);
+    let mut reply = ipv4_packet(PROTO_TCP, [10, 0, 2, 1], [10, 0, 1, 1], 12345);
+    reply[20..22].copy_from_slice(&22u16.to_be_bytes());
+    // Servers can not initiate connections to clients
+    assert!(!firewall.allows(Direction::In, &reply));
+    assert!(firewall.allows(Direction::Out, &request));
+    assert_eq!(1, firewall.conntrack_len());
+    // Replies to connections of clients are allowed
+    assert!(firewall.allows(Direction::In, &reply));
+    MockTimeSource::set_time(100);
+    assert!(!firewall.allows(Direction::In, &reply));
+    firewall.housekeep();
+    assert_eq!(0, firewall.conntrack_len());
+}
@@ -1,4 +1,3 @@
-use crate::{error::Error, util::run_cmd};
 use std::{
     env,
     fs::{self, File},
@@ -6,6 +5,7 @@ use std::{
     os::unix::fs::PermissionsExt,
     process::Command,
 };
+use vpncloud_core::{error::Error, util::run_cmd};
 
 const MANPAGE: &[u8] = include_bytes!("../target/vpncloud.1.gz");
 const SERVICE_FILE: &[u8] = include_bytes!("../assets/vpncloud@.service");
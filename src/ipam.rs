@@ -0,0 +1,486 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Allocation of overlay subnets by a coordinator node
+//!
+//! The coordinator announces the `ipam` service and hands out non-overlapping blocks of its pool to the nodes that
+//! request them. Leases are bound to the name of the node, i.e. the name of its trusted key or the node name it
+//! announces, so a node gets the same block again after a restart. Nodes renew their lease after half of the lease
+//! time and ask for their previous block when they start.
+
+use byteorder::{ByteOrder, NetworkEndian};
+use std::{
+    collections::HashMap,
+    fmt::Write as FmtWrite,
+    io::{self, Write},
+    marker::PhantomData,
+    net::Ipv4Addr,
+    str::FromStr,
+};
+
+use crate::{
+    cloud::Hash,
+    error::Error,
+    types::{Address, Range},
+    util::{Duration, MsgBuffer, Time, TimeSource},
+};
+
+/// Name of the service that the coordinator announces
+pub const IPAM_SERVICE: &str = "ipam";
+pub const DEFAULT_LEASE_TIME: Duration = 86400;
+/// Seconds after which an unanswered request is repeated
+const RETRY_INTERVAL: Time = 10;
+/// Largest number of blocks in a pool, so that finding a free block stays cheap
+const MAX_BLOCKS: u32 = 1 << 16;
+const LEASE_LEN: usize = 10;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Network from which this node allocates blocks to the other nodes, e.g. 10.0.0.0/16
+    #[serde(default)]
+    pub pool: Option<String>,
+    /// Prefix length of the allocated blocks, 32 for single addresses
+    #[serde(default)]
+    pub prefix_len: Option<u8>,
+    #[serde(default)]
+    pub lease_time: Option<Duration>,
+    /// Whether this node requests its address from a coordinator
+    #[serde(default)]
+    pub request: bool,
+}
+
+fn mask(prefix_len: u8) -> u32 {
+    if prefix_len == 0 {
+        0
+    } else {
+        !0u32 << (32 - prefix_len)
+    }
+}
+
+fn block_range(subnet: u32, prefix_len: u8) -> Range {
+    Range { base: Address::from_ipv4(Ipv4Addr::from(subnet)), prefix_len }
+}
+
+/// A block of the pool as sent from the coordinator to a node
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lease {
+    pub subnet: Ipv4Addr,
+    pub prefix_len: u8,
+    /// Prefix length of the whole pool, used as netmask of the interface
+    pub pool_prefix_len: u8,
+    pub lease_time: Duration,
+}
+
+impl Lease {
+    /// Address of the node, the first host address of the block unless it is a single address
+    pub fn address(&self) -> Ipv4Addr {
+        if self.prefix_len >= 31 {
+            self.subnet
+        } else {
+            Ipv4Addr::from(u32::from(self.subnet) + 1)
+        }
+    }
+
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(mask(self.pool_prefix_len))
+    }
+
+    pub fn range(&self) -> Range {
+        block_range(self.subnet.into(), self.prefix_len)
+    }
+
+    fn same_block(&self, other: &Lease) -> bool {
+        self.subnet == other.subnet && self.prefix_len == other.prefix_len
+    }
+}
+
+/// Encodes a lease request with the address of the previous lease, if any
+pub fn encode_request(previous: Option<Ipv4Addr>, buffer: &mut MsgBuffer) {
+    buffer.clear();
+    buffer.buffer()[..4].copy_from_slice(&previous.unwrap_or(Ipv4Addr::UNSPECIFIED).octets());
+    buffer.set_length(4);
+}
+
+pub fn decode_request(data: &[u8]) -> Result<Option<Ipv4Addr>, Error> {
+    if data.len() < 4 {
+        return Err(Error::Message("Lease request is too short"));
+    }
+    let previous = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+    Ok(if previous.is_unspecified() { None } else { Some(previous) })
+}
+
+/// Encodes the answer to a lease request, `None` if the pool is exhausted
+pub fn encode_lease(lease: Option<&Lease>, buffer: &mut MsgBuffer) {
+    buffer.clear();
+    let data = &mut buffer.buffer()[..LEASE_LEN];
+    match lease {
+        Some(lease) => {
+            data[..4].copy_from_slice(&lease.subnet.octets());
+            data[4] = lease.prefix_len;
+            data[5] = lease.pool_prefix_len;
+            NetworkEndian::write_u32(&mut data[6..10], lease.lease_time);
+        }
+        None => data.iter_mut().for_each(|b| *b = 0),
+    }
+    buffer.set_length(LEASE_LEN);
+}
+
+pub fn decode_lease(data: &[u8]) -> Result<Option<Lease>, Error> {
+    if data.len() < LEASE_LEN {
+        return Err(Error::Message("Lease message is too short"));
+    }
+    let subnet = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+    if subnet.is_unspecified() {
+        return Ok(None);
+    }
+    let lease = Lease {
+        subnet,
+        prefix_len: data[4],
+        pool_prefix_len: data[5],
+        lease_time: NetworkEndian::read_u32(&data[6..10]),
+    };
+    if lease.prefix_len > 32 || lease.pool_prefix_len > lease.prefix_len || lease.lease_time == 0 {
+        return Err(Error::Message("Invalid lease"));
+    }
+    Ok(Some(lease))
+}
+
+struct Allocation {
+    subnet: u32,
+    expires: Time,
+}
+
+/// The pool of the coordinator and the blocks leased to the nodes
+///
+/// Leases are persisted in the state directory with their expiry as wall clock time.
+pub struct IpamPool<TS: TimeSource> {
+    network: u32,
+    pool_prefix_len: u8,
+    prefix_len: u8,
+    lease_time: Duration,
+    reserved: Vec<Range>,
+    leases: HashMap<String, Allocation, Hash>,
+    _dummy: PhantomData<TS>,
+}
+
+impl<TS: TimeSource> IpamPool<TS> {
+    /// Creates the pool, blocks overlapping with the `reserved` ranges of the coordinator are never leased
+    pub fn new(config: &Config, reserved: Vec<Range>) -> Result<Self, Error> {
+        let pool = match config.pool {
+            Some(ref pool) => pool,
+            None => return Err(Error::InvalidConfig("IPAM pool is not set")),
+        };
+        let invalid = || Error::InvalidConfigValue("Invalid IPAM pool", pool.clone());
+        let (network, pool_prefix_len) = match pool.find('/') {
+            Some(pos) => (
+                Ipv4Addr::from_str(&pool[..pos]).map_err(|_| invalid())?,
+                pool[pos + 1..].parse::<u8>().map_err(|_| invalid())?,
+            ),
+            None => return Err(invalid()),
+        };
+        let prefix_len = config.prefix_len.unwrap_or(32);
+        if pool_prefix_len > 32 || prefix_len > 32 || prefix_len < pool_prefix_len {
+            return Err(Error::InvalidConfig("IPAM prefix length must be between the pool prefix length and 32"));
+        }
+        if u32::from(prefix_len - pool_prefix_len) > MAX_BLOCKS.trailing_zeros() {
+            return Err(Error::InvalidConfig("IPAM pool has too many blocks, use a larger prefix length"));
+        }
+        Ok(Self {
+            network: u32::from(network) & mask(pool_prefix_len),
+            pool_prefix_len,
+            prefix_len,
+            lease_time: config.lease_time.unwrap_or(DEFAULT_LEASE_TIME),
+            reserved,
+            leases: HashMap::default(),
+            _dummy: PhantomData,
+        })
+    }
+
+    fn blocks(&self) -> u32 {
+        1 << (self.prefix_len - self.pool_prefix_len)
+    }
+
+    fn block_size(&self) -> u64 {
+        1 << (32 - self.prefix_len)
+    }
+
+    /// Returns the block starting at the address if it is one of the pool
+    fn block(&self, addr: Ipv4Addr) -> Option<u32> {
+        let addr = u32::from(addr);
+        if addr & mask(self.pool_prefix_len) == self.network && addr & !mask(self.prefix_len) == 0 {
+            Some(addr)
+        } else {
+            None
+        }
+    }
+
+    fn is_free(&self, subnet: u32, client: &str) -> bool {
+        let range = block_range(subnet, self.prefix_len);
+        let now = TS::now();
+        if self.prefix_len == 32 && self.pool_prefix_len < 31 {
+            // Network and broadcast address of the pool
+            if subnet == self.network || subnet == self.network | !mask(self.pool_prefix_len) {
+                return false;
+            }
+        }
+        !self.reserved.iter().any(|r| r.covers(&range) || range.covers(r))
+            && !self.leases.iter().any(|(c, lease)| c != client && lease.subnet == subnet && lease.expires >= now)
+    }
+
+    fn lease(&self, subnet: u32) -> Lease {
+        Lease {
+            subnet: subnet.into(),
+            prefix_len: self.prefix_len,
+            pool_prefix_len: self.pool_prefix_len,
+            lease_time: self.lease_time,
+        }
+    }
+
+    /// Leases a block to the client, preferring its current and then its requested block
+    ///
+    /// Returns `None` if the pool is exhausted.
+    pub fn allocate(&mut self, client: &str, requested: Option<Ipv4Addr>) -> Option<Lease> {
+        let current = self.leases.get(client).map(|lease| lease.subnet);
+        let subnet = current
+            .into_iter()
+            .chain(requested.and_then(|addr| self.block(addr)))
+            .find(|subnet| self.is_free(*subnet, client))
+            .or_else(|| {
+                (0..self.blocks())
+                    .map(|i| (u64::from(self.network) + u64::from(i) * self.block_size()) as u32)
+                    .find(|subnet| self.is_free(*subnet, client))
+            })?;
+        if current != Some(subnet) {
+            info!("Leasing {} to {}", block_range(subnet, self.prefix_len), client);
+        }
+        self.leases.insert(client.to_string(), Allocation { subnet, expires: TS::now() + self.lease_time as Time });
+        Some(self.lease(subnet))
+    }
+
+    /// Removes the expired leases
+    pub fn housekeep(&mut self) {
+        let now = TS::now();
+        let prefix_len = self.prefix_len;
+        self.leases.retain(|client, lease| {
+            if lease.expires < now {
+                info!("Lease of {} by {} expired", block_range(lease.subnet, prefix_len), client);
+            }
+            lease.expires >= now
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.leases.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leases.is_empty()
+    }
+
+    /// Serializes the leases, one "client subnet expiry" line each
+    pub fn save(&self) -> String {
+        let offset = TS::wall_clock() - TS::now();
+        let mut leases: Vec<_> = self.leases.iter().collect();
+        leases.sort_by_key(|(_, lease)| lease.subnet);
+        let mut out = String::new();
+        for (client, lease) in leases {
+            writeln!(out, "{} {} {}", client, Ipv4Addr::from(lease.subnet), lease.expires + offset).unwrap();
+        }
+        out
+    }
+
+    /// Loads the saved leases that have not expired yet and still fit into the pool
+    pub fn load(&mut self, data: &str) {
+        let offset = TS::wall_clock() - TS::now();
+        let now = TS::now();
+        for line in data.lines() {
+            let mut parts = line.split_whitespace();
+            let (client, subnet, expires) = match (
+                parts.next(),
+                parts.next().and_then(|s| s.parse().ok()).and_then(|addr| self.block(addr)),
+                parts.next().and_then(|s| s.parse::<Time>().ok()),
+            ) {
+                (Some(client), Some(subnet), Some(expires)) => (client, subnet, expires - offset),
+                _ => {
+                    warn!("Ignoring invalid lease: {}", line);
+                    continue;
+                }
+            };
+            if expires >= now && self.is_free(subnet, client) {
+                self.leases.insert(client.to_string(), Allocation { subnet, expires });
+            }
+        }
+        info!("Loaded {} leases", self.leases.len());
+    }
+
+    pub fn write_out<W: Write>(&self, out: &mut W) -> Result<(), io::Error> {
+        let now = TS::now();
+        writeln!(out, "ipam:")?;
+        writeln!(out, "  pool: {}", block_range(self.network, self.pool_prefix_len))?;
+        writeln!(out, "  leases:")?;
+        let mut leases: Vec<_> = self.leases.iter().collect();
+        leases.sort_by_key(|(_, lease)| lease.subnet);
+        for (client, lease) in leases {
+            writeln!(
+                out,
+                "    - {}: {{ client: {:?}, expires_secs: {} }}",
+                block_range(lease.subnet, self.prefix_len),
+                client,
+                lease.expires - now
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The lease of a node that gets its address from a coordinator
+pub struct IpamClient<TS: TimeSource> {
+    lease: Option<Lease>,
+    /// Block of the last lease, requested again from the coordinator
+    previous: Option<Ipv4Addr>,
+    expires: Time,
+    renew_at: Time,
+    next_request: Time,
+    _dummy: PhantomData<TS>,
+}
+
+impl<TS: TimeSource> IpamClient<TS> {
+    pub fn new() -> Self {
+        Self { lease: None, previous: None, expires: 0, renew_at: 0, next_request: 0, _dummy: PhantomData }
+    }
+
+    pub fn lease(&self) -> Option<&Lease> {
+        self.lease.as_ref()
+    }
+
+    /// Whether a lease should be requested or renewed now
+    pub fn is_due(&self) -> bool {
+        let now = TS::now();
+        self.renew_at <= now && self.next_request <= now
+    }
+
+    /// Encodes a request for the previous block and waits for the answer before requesting again
+    pub fn request(&mut self, buffer: &mut MsgBuffer) {
+        self.next_request = TS::now() + RETRY_INTERVAL;
+        encode_request(self.previous, buffer)
+    }
+
+    /// Stores the lease and returns the previous lease if the block has changed
+    pub fn set_lease(&mut self, lease: Lease) -> (bool, Option<Lease>) {
+        let now = TS::now();
+        self.expires = now + lease.lease_time as Time;
+        self.renew_at = now + (lease.lease_time / 2) as Time;
+        self.previous = Some(lease.subnet);
+        match self.lease.replace(lease) {
+            Some(old) if old.same_block(&lease) => (false, None),
+            old => (true, old),
+        }
+    }
+
+    /// Returns the lease if it has expired without being renewed
+    pub fn housekeep(&mut self) -> Option<Lease> {
+        if self.lease.is_some() && self.expires < TS::now() {
+            self.lease.take()
+        } else {
+            None
+        }
+    }
+
+    /// Serializes the block of the last lease
+    pub fn save(&self) -> String {
+        self.previous.map(|addr| format!("{}\n", addr)).unwrap_or_default()
+    }
+
+    pub fn load(&mut self, data: &str) {
+        match data.trim() {
+            "" => (),
+            addr => match addr.parse() {
+                Ok(addr) => self.previous = Some(addr),
+                Err(_) => warn!("Ignoring invalid lease: {}", addr),
+            },
+        }
+    }
+
+    pub fn write_out<W: Write>(&self, out: &mut W) -> Result<(), io::Error> {
+        writeln!(out, "ipam:")?;
+        match self.lease {
+            Some(ref lease) => writeln!(
+                out,
+                "  lease: {{ subnet: {}, address: {}, expires_secs: {} }}",
+                lease.range(),
+                lease.address(),
+                self.expires - TS::now()
+            ),
+            None => writeln!(out, "  lease: ~"),
+        }
+    }
+}
+
+impl<TS: TimeSource> Default for IpamClient<TS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+use crate::util::MockTimeSource;
+
+#[test]
+fn allocate_blocks_from_pool() {
+    MockTimeSource::set_time(1000);
+    let config = Config { pool: Some("10.0.0.0/30".to_string()), ..Default::default() };
+    let reserved = vec![Range::from_str("10.0.0.1/32").unwrap()];
+    let mut pool = IpamPool::<MockTimeSource>::new(&config, reserved).unwrap();
+    // Network, broadcast and the reserved address are not leased
+    let lease = pool.allocate("node1", Some("10.0.0.1".parse().unwrap())).unwrap();
+    assert_eq!(lease.subnet, Ipv4Addr::new(10, 0, 0, 2));
+    assert_eq!(lease.address(), Ipv4Addr::new(10, 0, 0, 2));
+    assert_eq!(lease.netmask(), Ipv4Addr::new(255, 255, 255, 252));
+    assert_eq!(pool.allocate("node2", None), None);
+    // Renewals keep the block
+    assert_eq!(pool.allocate("node1", None), Some(lease));
+    MockTimeSource::set_time(1000 + DEFAULT_LEASE_TIME as Time + 1);
+    pool.housekeep();
+    assert!(pool.is_empty());
+    assert_eq!(pool.allocate("node2", None), Some(lease));
+}
+
+#[test]
+fn persist_leases() {
+    MockTimeSource::set_time(1000);
+    let config = Config {
+        pool: Some("10.1.0.0/16".to_string()),
+        prefix_len: Some(24),
+        lease_time: Some(100),
+        ..Default::default()
+    };
+    let mut pool = IpamPool::<MockTimeSource>::new(&config, vec![]).unwrap();
+    let lease = pool.allocate("node1", Some("10.1.5.0".parse().unwrap())).unwrap();
+    assert_eq!(lease.range(), Range::from_str("10.1.5.0/24").unwrap());
+    assert_eq!(lease.address(), Ipv4Addr::new(10, 1, 5, 1));
+    pool.allocate("node2", Some("10.1.5.7".parse().unwrap())).unwrap();
+    let data = pool.save();
+    let mut pool = IpamPool::<MockTimeSource>::new(&config, vec![]).unwrap();
+    pool.load(&data);
+    assert_eq!(pool.len(), 2);
+    assert_eq!(pool.allocate("node3", Some("10.1.5.0".parse().unwrap())).unwrap().subnet, Ipv4Addr::new(10, 1, 1, 0));
+    // Requests are answered with the lease of the client
+    let mut client = IpamClient::<MockTimeSource>::new();
+    client.load("10.1.5.0\n");
+    assert!(client.is_due());
+    let mut buffer = MsgBuffer::new(0);
+    client.request(&mut buffer);
+    assert!(!client.is_due());
+    let requested = decode_request(buffer.message()).unwrap();
+    encode_lease(pool.allocate("node1", requested).as_ref(), &mut buffer);
+    let lease = decode_lease(buffer.message()).unwrap().unwrap();
+    assert_eq!(client.set_lease(lease), (true, None));
+    assert_eq!(client.set_lease(lease), (false, None));
+    MockTimeSource::set_time(1051);
+    assert!(client.is_due());
+    MockTimeSource::set_time(1101);
+    assert_eq!(client.housekeep(), Some(lease));
+    assert_eq!(client.save(), "10.1.5.0\n");
+}
@@ -0,0 +1,108 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! The VpnCloud engine as a library
+//!
+//! This crate contains everything that is needed to run a VpnCloud node inside another program.
+//! A node is represented by [`GenericCloud`] which is generic over the virtual network device
+//! ([`Device`]), the payload protocol ([`Frame`] for TAP devices, [`Packet`] for TUN devices), the
+//! socket ([`Socket`]) and the time source ([`TimeSource`]).
+//!
+//! To run a node, build a [`Config`], open a socket (e.g. `UdpSocket::listen`) and a device (e.g.
+//! [`TunTapDevice::new`]) and pass them to [`GenericCloud::new`]. Peers can be added with
+//! [`GenericCloud::connect`] and [`GenericCloud::add_reconnect_peer`] before calling
+//! [`GenericCloud::run`] which processes messages until Ctrl-C is pressed.
+//!
+//! The library never terminates the process and never installs a logger. All problems are reported
+//! as [`Error`] values and log messages are emitted via the `log` crate, so the embedding program
+//! decides where they go. Key pairs for the [`Crypto`] configuration can be created with
+//! [`Crypto::generate_keypair`].
+//!
+//! With the feature `testing`, the module `testing` provides nodes with a mock device, socket and time source, so
+//! programs that embed the library can test their logic deterministically without network access.
+
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde;
+
+#[cfg(test)]
+extern crate tempfile;
+
+#[macro_use]
+pub mod util;
+#[cfg(test)]
+#[macro_use]
+mod tests;
+pub mod advertise;
+pub mod arp;
+pub mod auth;
+pub mod bans;
+pub mod beacon;
+pub mod bench;
+pub mod budget;
+pub mod caps;
+pub mod capture;
+pub mod chaos;
+pub mod cloud;
+pub mod config;
+pub mod conntrack;
+pub mod control;
+pub mod crypto;
+pub mod device;
+pub mod dhcp;
+pub mod dial;
+pub mod diagnose;
+pub mod dns;
+pub mod docker;
+pub mod duplicate;
+pub mod error;
+pub mod fec;
+pub mod firewall;
+pub mod ipam;
+pub mod logging;
+pub mod messages;
+pub mod names;
+pub mod nat;
+pub mod net;
+pub mod netmanager;
+pub mod oldconfig;
+pub mod payload;
+pub mod peerstate;
+pub mod policy;
+pub mod poll;
+pub mod port_forwarding;
+pub mod quality;
+pub mod radius;
+pub mod ratelimit;
+pub mod remote;
+pub mod reorder;
+pub mod sandbox;
+pub mod selftest;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+pub mod state;
+pub mod stats;
+pub mod sysctl;
+pub mod table;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod timestamp;
+pub mod traffic;
+pub mod types;
+pub mod vxlan;
+#[cfg(feature = "websocket")]
+pub mod wsproxy;
+
+pub use crate::{
+    cloud::{CloudHandle, GenericCloud},
+    config::Config,
+    crypto::Crypto,
+    device::{Device, TunTapDevice, Type},
+    error::Error,
+    net::Socket,
+    payload::{Frame, Packet, Protocol, Vxlan},
+    util::{SystemTimeSource, TimeSource},
+    vxlan::VxlanDevice,
+};
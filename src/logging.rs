@@ -0,0 +1,107 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Throttling of repeated log messages
+//!
+//! A flapping peer can produce the same message many times per second. Identical messages are only logged once
+//! per interval, the number of suppressed repetitions is logged as a summary when the interval is over.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use log::Level;
+
+pub const DEFAULT_THROTTLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Maximum number of distinct messages that are tracked
+const MAX_ENTRIES: usize = 1000;
+
+struct Entry {
+    level: Level,
+    since: Instant,
+    suppressed: usize,
+}
+
+pub struct LogThrottle {
+    interval: Duration,
+    entries: HashMap<String, Entry>,
+}
+
+impl LogThrottle {
+    pub fn new(interval: Duration) -> Self {
+        Self { interval, entries: HashMap::new() }
+    }
+
+    /// Returns whether the message should be logged, otherwise it is counted for the summary
+    pub fn check(&mut self, level: Level, msg: &str, now: Instant) -> bool {
+        if let Some(entry) = self.entries.get_mut(msg) {
+            if now.duration_since(entry.since) < self.interval {
+                entry.suppressed += 1;
+                return false;
+            }
+        }
+        if self.entries.len() >= MAX_ENTRIES {
+            // Too many distinct messages, do not track any more of them
+            return true;
+        }
+        self.entries.insert(msg.to_string(), Entry { level, since: now, suppressed: 0 });
+        true
+    }
+
+    /// Returns summaries for the messages whose interval is over and forgets them
+    pub fn summaries(&mut self, now: Instant) -> Vec<(Level, String)> {
+        let interval = self.interval;
+        let mut summaries = vec![];
+        self.entries.retain(|msg, entry| {
+            if now.duration_since(entry.since) < interval {
+                return true;
+            }
+            if entry.suppressed > 0 {
+                summaries.push((entry.level, Self::summary(msg, entry.suppressed, interval)));
+            }
+            false
+        });
+        summaries
+    }
+
+    /// Returns summaries for all messages with suppressed repetitions, e.g. on shutdown
+    pub fn flush(&mut self) -> Vec<(Level, String)> {
+        let interval = self.interval;
+        let summaries = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.suppressed > 0)
+            .map(|(msg, entry)| (entry.level, Self::summary(msg, entry.suppressed, interval)))
+            .collect();
+        self.entries.clear();
+        summaries
+    }
+
+    fn summary(msg: &str, count: usize, interval: Duration) -> String {
+        format!("{} (repeated {} times in {} seconds)", msg, count, interval.as_secs())
+    }
+}
+
+#[test]
+fn log_throttle() {
+    let mut throttle = LogThrottle::new(Duration::from_secs(60));
+    let start = Instant::now();
+    assert!(throttle.check(Level::Error, "Failed to decrypt", start));
+    assert!(throttle.check(Level::Info, "Added peer", start));
+    for i in 1..10 {
+        assert!(!throttle.check(Level::Error, "Failed to decrypt", start + Duration::from_secs(i)));
+    }
+    assert!(throttle.summaries(start + Duration::from_secs(30)).is_empty());
+    assert_eq!(
+        vec![(Level::Error, "Failed to decrypt (repeated 9 times in 60 seconds)".to_string())],
+        throttle.summaries(start + Duration::from_secs(60))
+    );
+    // The interval starts again
+    assert!(throttle.check(Level::Error, "Failed to decrypt", start + Duration::from_secs(61)));
+    assert!(!throttle.check(Level::Error, "Failed to decrypt", start + Duration::from_secs(62)));
+    assert_eq!(1, throttle.flush().len());
+    assert!(throttle.flush().is_empty());
+}
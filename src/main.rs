@@ -4,82 +4,113 @@
 
 #[macro_use]
 extern crate log;
-#[macro_use]
-extern crate serde;
 
-#[cfg(test)]
-extern crate tempfile;
+macro_rules! fail {
+    ($format:expr) => ( {
+        use std::process;
+        error!($format);
+        log::logger().flush();
+        process::exit(-1);
+    } );
+    ($format:expr, $( $arg:expr ),+) => ( {
+        use std::process;
+        error!($format, $( $arg ),+ );
+        log::logger().flush();
+        process::exit(-1);
+    } );
+}
+
+macro_rules! try_fail {
+    ($val:expr, $format:expr) => ( {
+        match $val {
+            Ok(val) => val,
+            Err(err) => fail!($format, err)
+        }
+    } );
+    ($val:expr, $format:expr, $( $arg:expr ),+) => ( {
+        match $val {
+            Ok(val) => val,
+            Err(err) => fail!($format, $( $arg ),+, err)
+        }
+    } );
+}
 
-#[macro_use]
-pub mod util;
-#[cfg(test)]
-#[macro_use]
-mod tests;
-pub mod beacon;
-pub mod cloud;
-pub mod config;
-pub mod crypto;
-pub mod device;
-pub mod error;
 #[cfg(feature = "installer")]
-pub mod installer;
-pub mod messages;
-pub mod net;
-pub mod oldconfig;
-pub mod payload;
-pub mod poll;
-pub mod port_forwarding;
-pub mod table;
-pub mod traffic;
-pub mod types;
+mod installer;
 #[cfg(feature = "wizard")]
-pub mod wizard;
-#[cfg(feature = "websocket")]
-pub mod wsproxy;
+mod wizard;
 
+use backtrace::Backtrace;
 use structopt::StructOpt;
 
 use std::{
+    env, fmt,
     fs::{self, File, Permissions},
-    io::{self, Write},
-    net::{Ipv4Addr, UdpSocket},
-    os::unix::fs::PermissionsExt,
+    io::{self, Read, Write},
+    mem,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    os::unix::{fs::PermissionsExt, io::FromRawFd},
+    panic,
     path::Path,
     process,
     str::FromStr,
-    sync::Mutex,
+    sync::{Arc, Mutex},
     thread,
+    time::{Duration, Instant},
 };
 
-use crate::{
-    cloud::GenericCloud,
-    config::{Args, Command, Config, DEFAULT_PORT},
+use vpncloud_core::{
+    bench, caps,
+    cloud::{CloudHandle, GenericCloud},
+    config::{Args, Command, Config, ConfigFile, ControlTarget, DEFAULT_PORT},
+    control::{self, ControlCommand},
     crypto::Crypto,
-    device::{Device, TunTapDevice, Type},
-    net::Socket,
+    device::{Device, TunTapDevice, Type, MAX_MTU},
+    diagnose,
+    docker::Driver,
+    error::Error,
+    logging::{LogThrottle, DEFAULT_THROTTLE_INTERVAL},
+    net::{network_id, parse_listen, NetworkDispatcher, NetworkSocket, Socket},
+    netmanager,
     oldconfig::OldConfigFile,
-    payload::Protocol,
-    util::SystemTimeSource,
+    payload::{self, Protocol},
+    remote, sandbox, selftest,
+    sysctl::{self, Sysctls},
+    timestamp::Timestamps,
+    util::{derive_ip, derive_mac, resolve_scoped, SystemTimeSource},
+    vxlan::VxlanDevice,
 };
 
 #[cfg(feature = "websocket")]
-use crate::wsproxy::ProxyConnection;
+use vpncloud_core::wsproxy::{self, ProxyConnection};
 
 struct DualLogger {
     file: Option<Mutex<File>>,
+    throttle: Option<Mutex<LogThrottle>>,
+    timestamps: Timestamps,
 }
 
 impl DualLogger {
-    pub fn new<P: AsRef<Path>>(path: Option<P>) -> Result<Self, io::Error> {
+    pub fn new<P: AsRef<Path>>(path: Option<P>, throttle: bool, timestamps: Timestamps) -> Result<Self, io::Error> {
+        let throttle = if throttle { Some(Mutex::new(LogThrottle::new(DEFAULT_THROTTLE_INTERVAL))) } else { None };
         if let Some(path) = path {
             let path = path.as_ref();
             if path.exists() {
                 fs::remove_file(path)?
             }
             let file = File::create(path)?;
-            Ok(DualLogger { file: Some(Mutex::new(file)) })
+            Ok(DualLogger { file: Some(Mutex::new(file)), throttle, timestamps })
         } else {
-            Ok(DualLogger { file: None })
+            Ok(DualLogger { file: None, throttle, timestamps })
+        }
+    }
+
+    fn write(&self, level: log::Level, msg: &fmt::Arguments) {
+        println!("{} - {}", level, msg);
+        if let Some(ref file) = self.file {
+            let mut file = file.lock().expect("Lock poisoned");
+            let time = self.timestamps.now();
+            writeln!(file, "{} - {} - {}", time, level, msg).expect("Failed to write to logfile");
         }
     }
 }
@@ -93,18 +124,33 @@ impl log::Log for DualLogger {
     #[inline]
     fn log(&self, record: &log::Record) {
         if self.enabled(record.metadata()) {
-            println!("{} - {}", record.level(), record.args());
-            if let Some(ref file) = self.file {
-                let mut file = file.lock().expect("Lock poisoned");
-                let time = chrono::Local::now().format("%F %H:%M:%S");
-                writeln!(file, "{} - {} - {}", time, record.level(), record.args())
-                    .expect("Failed to write to logfile");
+            if let Some(ref throttle) = self.throttle {
+                // Repeated messages are collapsed into summaries
+                let now = Instant::now();
+                let (summaries, log) = {
+                    let mut throttle = throttle.lock().expect("Lock poisoned");
+                    let summaries = throttle.summaries(now);
+                    (summaries, throttle.check(record.level(), &record.args().to_string(), now))
+                };
+                for (level, summary) in summaries {
+                    self.write(level, &format_args!("{}", summary));
+                }
+                if !log {
+                    return;
+                }
             }
+            self.write(record.level(), record.args());
         }
     }
 
     #[inline]
     fn flush(&self) {
+        if let Some(ref throttle) = self.throttle {
+            let summaries = throttle.lock().expect("Lock poisoned").flush();
+            for (level, summary) in summaries {
+                self.write(level, &format_args!("{}", summary));
+            }
+        }
         if let Some(ref file) = self.file {
             let mut file = file.lock().expect("Lock poisoned");
             try_fail!(file.flush(), "Logging error: {}");
@@ -112,9 +158,26 @@ impl log::Log for DualLogger {
     }
 }
 
-fn run_script(script: &str, ifname: &str) {
+/// Environment of the ifup and ifdown scripts
+fn script_env(config: &Config, ifname: &str, mtu: Option<usize>) -> Vec<(&'static str, String)> {
+    let mut envs = vec![("IFNAME", ifname.to_owned())];
+    if let Some(mtu) = mtu {
+        envs.push(("MTU", mtu.to_string()));
+    }
+    if let Some(ref ip) = config.ip {
+        envs.push(("IP", ip.clone()));
+    }
+    envs.push(("CLAIMS", config.claims.join(" ")));
+    if let Ok(addr) = parse_listen(&config.listen, DEFAULT_PORT) {
+        envs.push(("LISTEN_PORT", addr.port().to_string()));
+    }
+    envs.push(("EXTERNAL_ADDRESSES", config.advertise_addresses.join(" ")));
+    envs
+}
+
+fn run_script(script: &str, envs: Vec<(&'static str, String)>) {
     let mut cmd = process::Command::new("sh");
-    cmd.arg("-c").arg(&script).env("IFNAME", ifname);
+    cmd.arg("-c").arg(&script).envs(envs);
     debug!("Running script: {:?}", cmd);
     match cmd.status() {
         Ok(status) => {
@@ -126,6 +189,23 @@ fn run_script(script: &str, ifname: &str) {
     }
 }
 
+/// Sends the command to the local control socket or, with `--remote`, to the remote control port of a node
+fn send_control_command(target: &ControlTarget, command: &ControlCommand) -> Result<String, Error> {
+    match (&target.remote, &target.admin_key) {
+        (Some(remote), Some(admin_key)) => remote::send_command(remote, admin_key, command),
+        _ => control::send_command(&target.socket, command),
+    }
+}
+
+fn with_default_port(address: String) -> String {
+    if address.rfind(':').unwrap_or(0) <= address.rfind(']').unwrap_or(0) {
+        // : not present or only in IPv6 address
+        format!("{}:{}", address, DEFAULT_PORT)
+    } else {
+        address
+    }
+}
+
 fn parse_ip_netmask(addr: &str) -> Result<(Ipv4Addr, Ipv4Addr), String> {
     let (ip_str, len_str) = match addr.find('/') {
         Some(pos) => (&addr[..pos], &addr[pos + 1..]),
@@ -140,7 +220,7 @@ fn parse_ip_netmask(addr: &str) -> Result<(Ipv4Addr, Ipv4Addr), String> {
     Ok((ip, netmask))
 }
 
-fn setup_device(config: &Config) -> TunTapDevice {
+fn setup_device(config: &Config, sysctls: &mut Sysctls) -> TunTapDevice {
     let device = try_fail!(
         TunTapDevice::new(&config.device_name, config.device_type, config.device_path.as_ref().map(|s| s as &str)),
         "Failed to open virtual {} interface {}: {}",
@@ -149,21 +229,47 @@ fn setup_device(config: &Config) -> TunTapDevice {
     );
     info!("Opened device {}", device.ifname());
     config.call_hook("device_setup", vec![("IFNAME", device.ifname())], true);
-    if let Err(err) = device.set_mtu(None) {
-        error!("Error setting optimal MTU on {}: {}", device.ifname(), err);
+    if let Some(mtu) = config.mtu {
+        if mtu > MAX_MTU {
+            fail!("MTU {} is too large, the maximum is {}", mtu, MAX_MTU);
+        }
+    }
+    if let Err(err) = device.set_mtu(config.mtu) {
+        error!("Error setting MTU on {}: {}", device.ifname(), err);
+    }
+    if config.derive_mac {
+        if config.device_type == Type::Tap {
+            let key = try_fail!(Crypto::own_public_key(&config.crypto), "Failed to load key: {}");
+            try_fail!(device.set_mac(derive_mac(&key)), "Failed to set MAC address: {}");
+        } else {
+            warn!("Deriving the MAC address is only supported on TAP devices");
+        }
     }
     if let Some(ip) = &config.ip {
         let (ip, netmask) = try_fail!(parse_ip_netmask(ip), "Invalid ip address given: {}");
-        info!("Configuring device with ip {}, netmask {}", ip, netmask);
-        try_fail!(device.configure(ip, netmask), "Failed to configure device: {}");
+        if config.network_manager.as_ref().map(|n| n.configures_addresses()).unwrap_or(false) {
+            info!("Delegating configuration of ip {}, netmask {} to network manager", ip, netmask);
+        } else {
+            info!("Configuring device with ip {}, netmask {}", ip, netmask);
+            try_fail!(device.configure(ip, netmask), "Failed to configure device: {}");
+        }
+    }
+    if let Some(network_manager) = &config.network_manager {
+        try_fail!(
+            netmanager::setup(network_manager, device.ifname(), config.ip.as_deref()),
+            "Failed to hand over device to network manager: {}"
+        );
     }
     if let Some(script) = &config.ifup {
-        run_script(script, device.ifname());
+        run_script(script, script_env(config, device.ifname(), device.get_mtu().ok()));
     }
     if config.fix_rp_filter {
-        try_fail!(device.fix_rp_filter(), "Failed to change rp_filter settings: {}");
+        try_fail!(sysctls.fix_rp_filter(device.ifname()), "Failed to change rp_filter settings: {}");
+    }
+    if config.ip_forwarding {
+        try_fail!(sysctls.enable_forwarding(), "Failed to enable IP forwarding: {}");
     }
-    if let Ok(val) = device.get_rp_filter() {
+    if let Ok(val) = sysctls.get_rp_filter(device.ifname()) {
         if val != 1 {
             warn!("Your networking configuration might be affected by a vulnerability (https://vpncloud.ddswd.de/docs/security/cve-2019-14899/), please change your rp_filter setting to 1 (currently {}).", val);
         }
@@ -172,45 +278,155 @@ fn setup_device(config: &Config) -> TunTapDevice {
     device
 }
 
-#[allow(clippy::cognitive_complexity)]
-fn run<P: Protocol, S: Socket>(config: Config, socket: S) {
-    let device = setup_device(&config);
-    let port_forwarding = if config.port_forwarding { socket.create_port_forwarding() } else { None };
-    let stats_file = match config.stats_file {
-        None => None,
-        Some(ref name) => {
-            let path = Path::new(name);
-            if path.exists() {
-                try_fail!(fs::remove_file(path), "Failed to remove file {}: {}", name);
+/// Reverts the device setup that is not undone by closing the device
+fn teardown_device(config: &Config, ifname: &str) {
+    if let Some(script) = &config.ifdown {
+        run_script(script, script_env(config, ifname, config.mtu));
+    }
+    if let Some(network_manager) = &config.network_manager {
+        if let Err(err) = netmanager::teardown(network_manager, ifname) {
+            warn!("Failed to remove network manager config: {}", err);
+        }
+    }
+}
+
+/// Keeps the changed sysctls while running, returns `None` if they can not be restored after dropping privileges
+fn monitor_sysctls(config: &Config, sysctls: Sysctls) -> Option<Arc<Mutex<Sysctls>>> {
+    if sysctls.is_empty() {
+        return None;
+    }
+    if !caps::is_root() || config.user.is_some() || config.group.is_some() {
+        warn!("The changed sysctls can not be checked and restored without root privileges");
+        return None;
+    }
+    let sysctls = Arc::new(Mutex::new(sysctls));
+    let monitored = sysctls.clone();
+    thread::spawn(move || loop {
+        thread::sleep(sysctl::CHECK_INTERVAL);
+        monitored.lock().expect("Lock poisoned").check();
+    });
+    Some(sysctls)
+}
+
+/// Logs panics with a backtrace and tries to leave the interface in a clean state
+fn install_panic_hook(config: &Config, ifname: &str) {
+    let config = config.clone();
+    let ifname = ifname.to_owned();
+    panic::set_hook(Box::new(move |info| {
+        let thread = thread::current();
+        error!("Thread {} panicked: {}\n{:?}", thread.name().unwrap_or("<unnamed>"), info, Backtrace::new());
+        if thread.name() == Some("main") {
+            // The process is going down, so try to tear down the interface
+            teardown_device(&config, &ifname);
+        }
+        log::logger().flush();
+    }));
+}
+
+/// Fails early with a clear message if the process lacks the permissions for the setup
+fn check_privileges(config: &Config) {
+    if caps::is_root() {
+        return;
+    }
+    if config.vxlan.is_none() && caps::has(caps::CAP_NET_ADMIN) == Some(false) {
+        let exe = env::current_exe().map(|p| p.display().to_string()).unwrap_or_else(|_| "vpncloud".to_string());
+        fail!(
+            "Setting up the device needs root permissions or the CAP_NET_ADMIN capability, e.g. via `setcap cap_net_admin=ep {}` or `AmbientCapabilities=CAP_NET_ADMIN` in the systemd unit",
+            exe
+        );
+    }
+    if config.user.is_some() || config.group.is_some() {
+        fail!("Changing the user or group needs root permissions, please remove these options");
+    }
+    if let Ok(addr) = parse_listen(&config.listen, DEFAULT_PORT) {
+        if addr.port() < 1024 && caps::has(caps::CAP_NET_BIND_SERVICE) == Some(false) {
+            fail!("Listening on port {} needs root permissions or the CAP_NET_BIND_SERVICE capability", addr.port());
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn setup_performance(config: &Config) {
+    if !config.cpu_affinity.is_empty() {
+        if let Some(cpu) = config.cpu_affinity.iter().find(|&&cpu| cpu >= libc::CPU_SETSIZE as usize) {
+            fail!("Invalid CPU number in cpu-affinity: {}", cpu);
+        }
+        let res = unsafe {
+            let mut set: libc::cpu_set_t = mem::zeroed();
+            for &cpu in &config.cpu_affinity {
+                libc::CPU_SET(cpu, &mut set);
             }
-            let file = try_fail!(File::create(name), "Failed to create stats file: {}");
-            try_fail!(
-                fs::set_permissions(name, Permissions::from_mode(0o644)),
-                "Failed to set permissions on stats file: {}"
-            );
-            Some(file)
+            libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set)
+        };
+        if res != 0 {
+            fail!("Failed to set CPU affinity: {}", io::Error::last_os_error());
         }
-    };
-    let mut cloud =
-        GenericCloud::<TunTapDevice, P, S, SystemTimeSource>::new(&config, socket, device, port_forwarding, stats_file);
-    for mut addr in config.peers {
-        if addr.find(':').unwrap_or(0) <= addr.find(']').unwrap_or(0) {
-            // : not present or only in IPv6 address
-            addr = format!("{}:{}", addr, DEFAULT_PORT)
+        info!("Pinned to CPUs {:?}", config.cpu_affinity);
+    }
+    if let Some(priority) = config.priority {
+        let (min, max) =
+            unsafe { (libc::sched_get_priority_min(libc::SCHED_FIFO), libc::sched_get_priority_max(libc::SCHED_FIFO)) };
+        if i32::from(priority) < min || i32::from(priority) > max {
+            fail!("Invalid priority {}, must be between {} and {}", priority, min, max);
+        }
+        let param = libc::sched_param { sched_priority: i32::from(priority) };
+        // Hook scripts and other child processes should not inherit the realtime priority
+        if unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO | libc::SCHED_RESET_ON_FORK, &param) } != 0 {
+            fail!("Failed to set realtime priority (needs root or CAP_SYS_NICE): {}", io::Error::last_os_error());
         }
-        try_fail!(cloud.connect(&addr as &str), "Failed to send message to {}: {}", &addr);
-        cloud.add_reconnect_peer(addr);
+        info!("Running with realtime priority {}", priority);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn setup_performance(config: &Config) {
+    if !config.cpu_affinity.is_empty() || config.priority.is_some() {
+        fail!("CPU affinity and priority are only supported on Linux");
+    }
+}
+
+/// Reads the private key from a file descriptor, e.g. a pipe from a password manager
+fn read_key_fd(fd: i32) -> String {
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    let mut key = String::new();
+    try_fail!(file.read_to_string(&mut key), "Failed to read key from file descriptor: {}");
+    key.trim().to_string()
+}
+
+/// Keeps the memory from being swapped to disk and disables core dumps
+#[cfg(target_os = "linux")]
+fn lock_memory(config: &Config) {
+    let limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } != 0 {
+        fail!("Failed to disable core dumps: {}", io::Error::last_os_error());
     }
+    // Without root permissions, future allocations would fail once the memlock limit is reached
+    if !caps::is_root() || config.user.is_some() || config.group.is_some() {
+        warn!("Memory can only be locked when running as root, it might be swapped to disk");
+        return;
+    }
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+        warn!("Failed to lock memory, it might be swapped to disk: {}", io::Error::last_os_error());
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lock_memory(_config: &Config) {
+    warn!("Locking memory is only supported on Linux, it might be swapped to disk");
+}
+
+#[cfg(not(target_os = "android"))]
+fn daemonize_or_drop_privileges(config: &Config) {
     if config.daemonize {
         info!("Running process as daemon");
         let mut daemonize = daemonize::Daemonize::new();
-        if let Some(user) = config.user {
-            daemonize = daemonize.user(&user as &str);
+        if let Some(user) = &config.user {
+            daemonize = daemonize.user(user as &str);
         }
-        if let Some(group) = config.group {
-            daemonize = daemonize.group(&group as &str);
+        if let Some(group) = &config.group {
+            daemonize = daemonize.group(group as &str);
         }
-        if let Some(pid_file) = config.pid_file {
+        if let Some(pid_file) = &config.pid_file {
             daemonize = daemonize.pid_file(pid_file).chown_pid_file(true);
             // Give child process some time to write PID file
             daemonize = daemonize.exit_action(|| thread::sleep(std::time::Duration::from_millis(10)));
@@ -219,17 +435,277 @@ fn run<P: Protocol, S: Socket>(config: Config, socket: S) {
     } else if config.user.is_some() || config.group.is_some() {
         info!("Dropping privileges");
         let mut pd = privdrop::PrivDrop::default();
-        if let Some(user) = config.user {
+        if let Some(user) = &config.user {
             pd = pd.user(user);
         }
-        if let Some(group) = config.group {
+        if let Some(group) = &config.group {
             pd = pd.group(group);
         }
         try_fail!(pd.apply(), "Failed to drop privileges: {}");
     }
-    cloud.run();
-    if let Some(script) = config.ifdown {
-        run_script(&script, cloud.ifname());
+}
+
+#[cfg(target_os = "android")]
+fn daemonize_or_drop_privileges(config: &Config) {
+    // Apps run unprivileged and are managed by the system
+    if config.daemonize || config.user.is_some() || config.group.is_some() {
+        fail!("Daemonizing and dropping privileges is not supported on Android");
+    }
+}
+
+fn open_stats_file(config: &Config) -> Option<File> {
+    let name = config.stats_file.as_ref()?;
+    let path = Path::new(name);
+    if path.exists() {
+        try_fail!(fs::remove_file(path), "Failed to remove file {}: {}", name);
+    }
+    let file = try_fail!(File::create(name), "Failed to create stats file: {}");
+    try_fail!(fs::set_permissions(name, Permissions::from_mode(0o644)), "Failed to set permissions on stats file: {}");
+    Some(file)
+}
+
+/// Longest pause between two attempts while waiting for the network
+const MAX_NETWORK_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Repeats a failed network operation with backoff for up to `wait` seconds, see `--wait-for-network`
+///
+/// On slow-booting devices, the network may not be ready when VpnCloud starts, so binding the socket or resolving
+/// the peers fails at first.
+fn wait_for_network<T, E: fmt::Display, F: FnMut() -> Result<T, E>>(
+    wait: Option<u32>, what: &str, mut f: F,
+) -> Result<T, E> {
+    let deadline = wait.map(|secs| Instant::now() + Duration::from_secs(u64::from(secs)));
+    let mut interval = Duration::from_secs(1);
+    loop {
+        match f() {
+            Ok(val) => return Ok(val),
+            Err(err) => match deadline {
+                Some(deadline) if Instant::now() + interval <= deadline => {
+                    warn!("Failed to {}: {}, retrying in {} seconds", what, err, interval.as_secs());
+                    thread::sleep(interval);
+                    interval = (interval * 2).min(MAX_NETWORK_RETRY_INTERVAL);
+                }
+                _ => return Err(err),
+            },
+        }
+    }
+}
+
+fn connect_peers<D: Device, P: Protocol, S: Socket>(
+    cloud: &mut GenericCloud<D, P, S, SystemTimeSource>, config: &Config,
+) {
+    // Backup peers with a higher priority value are only dialed when the primary peers fail
+    let primary = config.peers.iter().map(|p| p.priority).min().unwrap_or(0);
+    for peer in &config.peers {
+        let mut peer = peer.clone();
+        peer.address = with_default_port(peer.address);
+        if peer.priority == primary {
+            let what = format!("connect to {}", peer.address);
+            try_fail!(
+                wait_for_network(config.wait_for_network, &what, || {
+                    let addrs = resolve_scoped(&peer.address)?;
+                    cloud.connect(&addrs as &[SocketAddr])
+                }),
+                "Failed to connect to {}: {}",
+                &peer.address
+            );
+        }
+        cloud.add_peer_config(peer);
+    }
+}
+
+/// Runs the main loop of the node
+#[cfg(not(feature = "async-runtime"))]
+fn run_node<D: Device, P: Protocol, S: Socket>(
+    cloud: &mut GenericCloud<D, P, S, SystemTimeSource>,
+) -> Result<(), Error> {
+    cloud.run()
+}
+
+/// Runs the main loop of the node in a single-threaded tokio runtime
+#[cfg(feature = "async-runtime")]
+fn run_node<D: Device, P: Protocol, S: Socket>(
+    cloud: &mut GenericCloud<D, P, S, SystemTimeSource>,
+) -> Result<(), Error> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| Error::SocketIo("Failed to start async runtime", e))?;
+    runtime.block_on(cloud.run_async())
+}
+
+/// Additional networks that share the port of the main network
+#[derive(Default)]
+struct Networks {
+    dispatcher: Option<NetworkDispatcher>,
+    nodes: Vec<(CloudHandle, Box<dyn FnOnce() + Send>)>,
+    configs: Vec<Config>,
+    sysctls: Sysctls,
+}
+
+impl Networks {
+    /// Starts the dispatcher and the additional networks, each of them stops the main network when it fails
+    fn start(self, main: CloudHandle) -> Vec<(CloudHandle, thread::JoinHandle<()>)> {
+        let mut threads = vec![];
+        if let Some(dispatcher) = self.dispatcher {
+            let handle = main.clone();
+            thread::spawn(move || {
+                error!("Failed to read from socket: {}", dispatcher.run());
+                handle.stop()
+            });
+        }
+        for (handle, node) in self.nodes {
+            let main = main.clone();
+            let thread = thread::spawn(move || {
+                node();
+                main.stop()
+            });
+            threads.push((handle, thread));
+        }
+        threads
+    }
+}
+
+/// Sets up the device and the node of an additional network and returns the function that runs it
+fn setup_network<P: Protocol + Send + 'static>(
+    config: Config, socket: NetworkSocket, sysctls: &mut Sysctls,
+) -> (CloudHandle, Box<dyn FnOnce() + Send>) {
+    let device = setup_device(&config, sysctls);
+    let stats_file = open_stats_file(&config);
+    let name = config.network_id.clone().unwrap_or_default();
+    let mut cloud = try_fail!(
+        GenericCloud::<TunTapDevice, P, _, SystemTimeSource>::new(&config, socket, device, None, stats_file),
+        "Failed to start network {}: {}",
+        name
+    );
+    connect_peers(&mut cloud, &config);
+    let handle = cloud.handle();
+    let node = move || {
+        let res = run_node(&mut cloud);
+        teardown_device(&config, cloud.ifname());
+        if let Err(err) = res {
+            error!("[E{}] Fatal error in network {}: {}", err.code(), name, err);
+        }
+    };
+    (handle, Box::new(node))
+}
+
+/// Splits the socket into the main network and the additional networks given by their config files
+fn setup_networks(config: &Config, socket: UdpSocket) -> (NetworkSocket, Networks) {
+    let name = match &config.network_id {
+        Some(name) => name,
+        None => fail!("The network id must be set when multiple networks share the port"),
+    };
+    let mut dispatcher = NetworkDispatcher::new(socket);
+    let main = try_fail!(dispatcher.add_network(network_id(name)), "Failed to add network {}: {}", name);
+    let mut networks = Networks::default();
+    for file in &config.networks {
+        info!("Reading network config file '{}'", file);
+        let data = try_fail!(fs::read_to_string(file), "Failed to read network config file: {:?}");
+        let mut net_config = Config::default();
+        net_config.merge_file(try_fail!(ConfigFile::parse(&data), "{}"));
+        let name = match &net_config.network_id {
+            Some(name) => name.clone(),
+            None => fail!("The network id must be set in network config file {}", file),
+        };
+        if net_config.daemonize
+            || net_config.user.is_some()
+            || net_config.group.is_some()
+            || net_config.pid_file.is_some()
+            || net_config.docker.is_some()
+            || net_config.vxlan.is_some()
+            || !net_config.networks.is_empty()
+        {
+            fail!("Network config file {}: daemonize, user, group, pid-file, docker, vxlan and networks are only allowed in the main config", file);
+        }
+        if net_config.control_socket.is_some() && net_config.control_socket == config.control_socket {
+            fail!("Network config file {} uses the same control socket as the main network", file);
+        }
+        if net_config.crypto.password.is_none() && net_config.crypto.private_key.is_none() {
+            fail!("Either password or private key must be set in network config file {}", file);
+        }
+        let socket = try_fail!(dispatcher.add_network(network_id(&name)), "Failed to add network {}: {}", name);
+        info!("Adding network {} on the same port", name);
+        let node = match net_config.device_type {
+            Type::Tap => setup_network::<payload::Frame>(net_config.clone(), socket, &mut networks.sysctls),
+            Type::Tun => setup_network::<payload::Packet>(net_config.clone(), socket, &mut networks.sysctls),
+        };
+        networks.nodes.push(node);
+        networks.configs.push(net_config);
+    }
+    networks.dispatcher = Some(dispatcher);
+    (main, networks)
+}
+
+/// Opens the device and runs the main network with the payload protocol that matches the device
+fn run<S: Socket>(config: Config, socket: S, mut networks: Networks) {
+    let mut sysctls = mem::take(&mut networks.sysctls);
+    if let Some(vxlan) = &config.vxlan {
+        let device = try_fail!(VxlanDevice::new(vxlan), "Failed to open VXLAN socket {}: {}", vxlan.listen);
+        info!("Bridging VXLAN packets on {}", device.ifname());
+        return run_device::<_, payload::Vxlan, S>(config, socket, networks, sysctls, device);
+    }
+    let device = setup_device(&config, &mut sysctls);
+    match config.device_type {
+        Type::Tap => run_device::<_, payload::Frame, S>(config, socket, networks, sysctls, device),
+        Type::Tun => run_device::<_, payload::Packet, S>(config, socket, networks, sysctls, device),
+    }
+}
+
+#[allow(clippy::cognitive_complexity)]
+fn run_device<D: Device, P: Protocol, S: Socket>(
+    config: Config, socket: S, networks: Networks, sysctls: Sysctls, device: D,
+) {
+    install_panic_hook(&config, device.ifname());
+    let docker = config.docker.as_ref().map(|docker| {
+        if config.user.is_some() || config.group.is_some() {
+            fail!("The Docker driver needs root permissions and can not be combined with user or group");
+        }
+        let reserved: Vec<_> = config.ip.iter().filter_map(|ip| parse_ip_netmask(ip).ok()).map(|(ip, _)| ip).collect();
+        let driver =
+            try_fail!(Driver::new(docker, device.ifname(), &config.claims, &reserved), "Invalid Docker config: {}");
+        let listener = try_fail!(driver.setup(config.device_type), "Failed to setup Docker driver: {}");
+        (driver, listener)
+    });
+    setup_performance(&config);
+    if !caps::is_root() && docker.is_none() {
+        // All privileged operations are done, the capabilities are not needed anymore
+        try_fail!(caps::drop_all(), "Failed to drop capabilities: {}");
+        debug!("Dropped all capabilities");
+    }
+    let port_forwarding = if config.port_forwarding {
+        socket.create_port_forwarding(&config.port_forwarding_description())
+    } else {
+        None
+    };
+    let stats_file = open_stats_file(&config);
+    let mut cloud = try_fail!(
+        GenericCloud::<D, P, S, SystemTimeSource>::new(&config, socket, device, port_forwarding, stats_file),
+        "Failed to start: {}"
+    );
+    connect_peers(&mut cloud, &config);
+    daemonize_or_drop_privileges(&config);
+    let sysctls = monitor_sysctls(&config, sysctls);
+    try_fail!(sandbox::apply_with_networks(&config, &networks.configs), "Failed to restrict process: {}");
+    if let Some((driver, listener)) = docker {
+        thread::spawn(move || driver.serve(listener));
+    }
+    let threads = networks.start(cloud.handle());
+    let res = run_node(&mut cloud);
+    for (handle, _) in &threads {
+        handle.stop()
+    }
+    for (_, thread) in threads {
+        thread.join().ok();
+    }
+    teardown_device(&config, cloud.ifname());
+    if let Some(sysctls) = sysctls {
+        sysctls.lock().expect("Lock poisoned").restore();
+    }
+    // Dropping the node removes the port mapping, this would be skipped by the exit on errors
+    drop(cloud);
+    if let Err(err) = res {
+        fail!("[E{}] Fatal error: {}", err.code(), err);
     }
 }
 
@@ -239,8 +715,14 @@ fn main() {
         println!("VpnCloud v{}", env!("CARGO_PKG_VERSION"));
         return;
     }
-    let logger = try_fail!(DualLogger::new(args.log_file.as_ref()), "Failed to open logfile: {}");
+    let timestamps = Timestamps::new(args.time_format.as_deref(), args.utc);
+    // Debug output is never throttled
+    let logger = try_fail!(
+        DualLogger::new(args.log_file.as_ref(), !args.verbose, timestamps.as_ref().ok().cloned().unwrap_or_default()),
+        "Failed to open logfile: {}"
+    );
     log::set_boxed_logger(Box::new(logger)).unwrap();
+    try_fail!(timestamps, "{}");
     assert!(!args.verbose || !args.quiet);
     log::set_max_level(if args.verbose {
         log::LevelFilter::Debug
@@ -278,6 +760,62 @@ fn main() {
                 );
                 try_fail!(serde_yaml::to_writer(f, &new_config), "Failed to write converted config: {:?}");
             }
+            Command::Bench { duration } => {
+                if !args.verbose {
+                    log::set_max_level(log::LevelFilter::Warn);
+                }
+                println!("Running benchmarks, this takes a few seconds...");
+                let report = try_fail!(bench::run(Duration::from_secs_f32(duration)), "Benchmark failed: {}");
+                print!("{}", report);
+            }
+            Command::Connect { address, persist, target } => {
+                let address = with_default_port(address);
+                let command = ControlCommand::Connect { address, persist };
+                try_fail!(send_control_command(&target, &command), "Failed to connect: {}");
+            }
+            Command::Disconnect { address, persist, target } => {
+                let address = with_default_port(address);
+                let command = ControlCommand::Disconnect { address, persist };
+                try_fail!(send_control_command(&target, &command), "Failed to disconnect: {}");
+            }
+            Command::Peers { target } => {
+                let command = ControlCommand::Peers;
+                let output = try_fail!(send_control_command(&target, &command), "Failed to list peers: {}");
+                print!("{}", output);
+            }
+            Command::Stats { target } => {
+                let command = ControlCommand::Stats;
+                let output = try_fail!(send_control_command(&target, &command), "Failed to show statistics: {}");
+                print!("{}", output);
+            }
+            Command::Bans { target } => {
+                let command = ControlCommand::Bans;
+                let output = try_fail!(send_control_command(&target, &command), "Failed to list bans: {}");
+                print!("{}", output);
+            }
+            Command::Wake { target } => {
+                let command = ControlCommand::Wake;
+                try_fail!(send_control_command(&target, &command), "Failed to wake instance: {}");
+            }
+            Command::Unban { address, target } => {
+                let command = ControlCommand::Unban { address };
+                try_fail!(send_control_command(&target, &command), "Failed to lift ban: {}");
+            }
+            Command::Diagnose { config: config_file, stun_servers, helper } => {
+                let mut config = Config::default();
+                if let Some(file) = config_file {
+                    let data = try_fail!(fs::read_to_string(file), "Failed to read config file: {:?}");
+                    config.merge_file(try_fail!(ConfigFile::parse(&data), "{}"));
+                }
+                let stun_servers = if stun_servers.is_empty() {
+                    diagnose::DEFAULT_STUN_SERVERS.iter().map(|s| s.to_string()).collect()
+                } else {
+                    stun_servers
+                };
+                println!("Running diagnosis, this takes a few seconds...");
+                let diagnosis = try_fail!(diagnose::run(&config, &stun_servers, helper.as_deref()), "Diagnosis failed: {}");
+                print!("{}", diagnosis);
+            }
             Command::Completion { shell } => {
                 Args::clap().gen_completions_to(env!("CARGO_PKG_NAME"), shell, &mut io::stdout());
             }
@@ -301,42 +839,77 @@ fn main() {
         return;
     }
     let mut config = Config::default();
+    let mut keys_in_file = false;
     if let Some(ref file) = args.config {
         info!("Reading config file '{}'", file);
-        let f = try_fail!(File::open(file), "Failed to open config file: {:?}");
-        let config_file = match serde_yaml::from_reader(f) {
-            Ok(config) => config,
-            Err(err) => {
-                error!("Failed to read config file: {}", err);
-                info!("Trying to convert from old config format");
-                let f = try_fail!(File::open(file), "Failed to open config file: {:?}");
-                let config_file_old: OldConfigFile =
-                    try_fail!(serde_yaml::from_reader(f), "Config file is neither version 2 nor version 1: {:?}");
-                let new_config = config_file_old.convert();
-                info!("Successfully converted from old format, please migrate your config using migrate-config");
-                new_config
-            }
-        };
+        let data = try_fail!(fs::read_to_string(file), "Failed to read config file: {:?}");
+        let config_file = try_fail!(ConfigFile::parse(&data), "{}");
+        let crypto = &config_file.crypto;
+        keys_in_file = crypto.password.is_some() || crypto.private_key.is_some() || crypto.secondary_key.is_some();
         config.merge_file(config_file)
     }
+    let self_test = args.self_test;
+    let key_fd = args.key_fd;
     config.merge_args(args);
+    if let Some(fd) = key_fd {
+        config.crypto.private_key = Some(read_key_fd(fd));
+    }
+    try_fail!(config.check_vxlan(), "{}");
+    if config.ephemeral {
+        if keys_in_file {
+            fail!("Keys must not be stored in the config file in ephemeral mode, use the environment or --key-fd");
+        }
+        try_fail!(config.check_ephemeral(), "{}");
+        lock_memory(&config);
+        info!("Running in ephemeral mode, nothing is written to disk");
+    }
     debug!("Config: {:?}", config);
+    if self_test {
+        let report = selftest::run(&config);
+        print!("{}", report);
+        process::exit(if report.passed() { 0 } else { 1 });
+    }
     if config.crypto.password.is_none() && config.crypto.private_key.is_none() {
         error!("Either password or private key must be set in config or given as parameter");
         return;
     }
+    if let Some(subnet) = &config.derive_ip {
+        if config.ip.is_none() {
+            let (network, netmask) = try_fail!(parse_ip_netmask(subnet), "Invalid subnet given: {}");
+            if u32::from(netmask).count_ones() > 30 {
+                fail!("Subnet {} is too small to derive an address", subnet);
+            }
+            let key = try_fail!(Crypto::own_public_key(&config.crypto), "Failed to load key: {}");
+            let ip = derive_ip(&key, network, netmask);
+            info!("Derived ip {} from public key", ip);
+            config.ip = Some(format!("{}/{}", ip, u32::from(netmask).count_ones()));
+        } else {
+            warn!("Ignoring subnet to derive ip from as ip is set");
+        }
+    }
+    check_privileges(&config);
     #[cfg(feature = "websocket")]
     if config.listen.starts_with("ws://") {
-        let socket = try_fail!(ProxyConnection::listen(&config.listen), "Failed to open socket {}: {}", config.listen);
-        match config.device_type {
-            Type::Tap => run::<payload::Frame, _>(config, socket),
-            Type::Tun => run::<payload::Packet, _>(config, socket),
+        let socket = try_fail!(
+            wait_for_network(config.wait_for_network, "open socket", || ProxyConnection::listen(&config.listen)),
+            "Failed to open socket {}: {}",
+            config.listen
+        );
+        if config.network_id.is_some() || !config.networks.is_empty() {
+            fail!("Network ids can not be used with websocket proxies");
         }
+        run(config, socket, Networks::default());
         return;
     }
-    let socket = try_fail!(UdpSocket::listen(&config.listen), "Failed to open socket {}: {}", config.listen);
-    match config.device_type {
-        Type::Tap => run::<payload::Frame, _>(config, socket),
-        Type::Tun => run::<payload::Packet, _>(config, socket),
+    let socket = try_fail!(
+        wait_for_network(config.wait_for_network, "open socket", || UdpSocket::listen(&config.listen)),
+        "Failed to open socket {}: {}",
+        config.listen
+    );
+    if config.network_id.is_some() || !config.networks.is_empty() {
+        let (socket, networks) = setup_networks(&config, socket);
+        run(config, socket, networks);
+        return;
     }
+    run(config, socket, Networks::default());
 }
@@ -5,21 +5,81 @@
 use crate::{
     crypto::Payload,
     error::Error,
+    net::{is_ipv4_addr, mapped_addr},
     types::{NodeId, Range, RangeList, NODE_ID_BYTES},
-    util::MsgBuffer,
+    util::{padded_len, MsgBuffer},
 };
-use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt, WriteBytesExt};
 use smallvec::{smallvec, SmallVec};
 use std::{
     io::{self, Cursor, Read, Seek, SeekFrom, Take, Write},
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
 };
 
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+pub const CAPABILITIES: u32 = CAPABILITY_PROBES
+    | CAPABILITY_DATA_KEEPALIVE
+    | CAPABILITY_PEER_GOSSIP
+    | CAPABILITY_DUPLICATES
+    | CAPABILITY_FEC
+    | CAPABILITY_PADDING;
+
+/// The node answers keepalive probes immediately
+pub const CAPABILITY_PROBES: u32 = 0x01;
+/// The node treats all messages as sign of life, so data can replace keepalives
+pub const CAPABILITY_DATA_KEEPALIVE: u32 = 0x02;
+/// The node acknowledges peer list versions, so only changes need to be sent
+pub const CAPABILITY_PEER_GOSSIP: u32 = 0x04;
+/// The node accepts duplicated packets with sequence numbers and drops the second copy
+pub const CAPABILITY_DUPLICATES: u32 = 0x08;
+/// The node recovers lost packets from FEC groups and reports the loss it measures
+pub const CAPABILITY_FEC: u32 = 0x10;
+/// The node ignores padding after node info, keepalive and close messages
+pub const CAPABILITY_PADDING: u32 = 0x20;
+
 pub const MESSAGE_TYPE_DATA: u8 = 0;
 pub const MESSAGE_TYPE_NODE_INFO: u8 = 1;
 pub const MESSAGE_TYPE_KEEPALIVE: u8 = 2;
+pub const MESSAGE_TYPE_DUPLICATE: u8 = 3;
+pub const MESSAGE_TYPE_FEC_DATA: u8 = 4;
+pub const MESSAGE_TYPE_FEC_PARITY: u8 = 5;
+pub const MESSAGE_TYPE_SEQ_DATA: u8 = 6;
+pub const MESSAGE_TYPE_LEASE_REQUEST: u8 = 7;
+pub const MESSAGE_TYPE_LEASE: u8 = 8;
 pub const MESSAGE_TYPE_CLOSE: u8 = 0xff;
 
+pub const CLOSE_REASON_INCOMPATIBLE_VERSION: u8 = 1;
+pub const CLOSE_REASON_UNAUTHORIZED: u8 = 2;
+
+/// Keepalive flag that asks the peer to answer immediately
+pub const KEEPALIVE_PROBE: u8 = 1;
+
+/// Encodes a keepalive message with the given flags and the local wall clock time
+pub fn encode_keepalive(flags: u8, time: i64, buffer: &mut MsgBuffer) {
+    buffer.clear();
+    buffer.buffer()[0] = flags;
+    NetworkEndian::write_i64(&mut buffer.buffer()[1..9], time);
+    buffer.set_length(9);
+}
+
+/// Decodes the flags and the wall clock time of a keepalive message, older nodes send neither
+pub fn decode_keepalive(data: &[u8]) -> (u8, Option<i64>) {
+    let flags = data.first().copied().unwrap_or(0);
+    let time = if data.len() >= 9 { Some(NetworkEndian::read_i64(&data[1..9])) } else { None };
+    (flags, time)
+}
+
+/// Pads node info, keepalive and close messages, so that they have uniform sizes together with their type byte
+///
+/// All these messages end with a fixed length or an end marker, so the padding is ignored by nodes that announce
+/// `CAPABILITY_PADDING`. Other messages are left unchanged.
+pub fn pad_control_msg(type_: u8, buffer: &mut MsgBuffer) {
+    if type_ == MESSAGE_TYPE_NODE_INFO || type_ == MESSAGE_TYPE_KEEPALIVE || type_ == MESSAGE_TYPE_CLOSE {
+        buffer.pad_to(padded_len(buffer.len() + 1) - 1)
+    }
+}
+
 pub type AddrList = SmallVec<[SocketAddr; 4]>;
 pub type PeerList = SmallVec<[PeerInfo; 16]>;
 
@@ -29,6 +89,51 @@ pub struct PeerInfo {
     pub addrs: AddrList,
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ProtocolInfo {
+    pub version: u8,
+    pub min_version: u8,
+    pub capabilities: u32,
+}
+
+impl ProtocolInfo {
+    /// Protocol info of this node
+    pub fn own() -> Self {
+        Self { version: PROTOCOL_VERSION, min_version: MIN_PROTOCOL_VERSION, capabilities: CAPABILITIES }
+    }
+
+    /// Protocol info assumed for nodes that do not send it
+    pub fn legacy() -> Self {
+        Self { version: 1, min_version: 1, capabilities: 0 }
+    }
+
+    /// Checks whether both sides support a common protocol version
+    pub fn is_compatible(&self) -> bool {
+        self.version >= MIN_PROTOCOL_VERSION && self.min_version <= PROTOCOL_VERSION
+    }
+
+    /// Returns the version that both sides will use
+    pub fn common_version(&self) -> u8 {
+        self.version.min(PROTOCOL_VERSION)
+    }
+
+    /// Returns the capabilities that both sides support
+    pub fn common_capabilities(&self) -> u32 {
+        self.capabilities & CAPABILITIES
+    }
+}
+
+/// Versions of the peer lists for incremental exchanges
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct GossipInfo {
+    /// Version of the peer list of the sender
+    pub seq: u32,
+    /// Version of the peer list that the sent peers are relative to, 0 if they are the complete list
+    pub base: u32,
+    /// Version of the peer list of the receiver that the sender has seen
+    pub ack: u32,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct NodeInfo {
     pub node_id: NodeId,
@@ -36,6 +141,17 @@ pub struct NodeInfo {
     pub claims: RangeList,
     pub peer_timeout: Option<u16>,
     pub addrs: AddrList,
+    pub protocol: Option<ProtocolInfo>,
+    pub max_payload: Option<u16>,
+    pub services: Vec<String>,
+    pub name: Option<String>,
+    /// Wall clock time of the sender in seconds since the epoch
+    pub time: Option<i64>,
+    pub gossip: Option<GossipInfo>,
+    /// Loss in permille that the sender measured on the messages from the receiver
+    pub loss: Option<u16>,
+    /// Reorder window of the sender in milliseconds, the receiver numbers its payload if it is set
+    pub reorder_window: Option<u16>,
 }
 
 impl NodeInfo {
@@ -45,6 +161,14 @@ impl NodeInfo {
     const PART_PEERS: u8 = 1;
     const PART_PEER_TIMEOUT: u8 = 3;
     const PART_ADDRS: u8 = 5;
+    const PART_PROTOCOL: u8 = 6;
+    const PART_MAX_PAYLOAD: u8 = 7;
+    const PART_SERVICES: u8 = 8;
+    const PART_NAME: u8 = 9;
+    const PART_TIME: u8 = 10;
+    const PART_GOSSIP: u8 = 11;
+    const PART_LOSS: u8 = 12;
+    const PART_REORDER: u8 = 13;
 
     fn read_addr_list<R: Read>(r: &mut Take<R>) -> Result<AddrList, io::Error> {
         let flags = r.read_u8()?;
@@ -66,7 +190,8 @@ impl NodeInfo {
             let mut ip = [0u8; 4];
             r.read_exact(&mut ip)?;
             let port = r.read_u16::<NetworkEndian>()?;
-            let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(ip), port));
+            // Addresses are handled as IPv6 internally
+            let addr = mapped_addr(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(ip), port)));
             addrs.push(addr);
         }
         Ok(addrs)
@@ -97,12 +222,48 @@ impl NodeInfo {
         Ok(claims)
     }
 
+    fn decode_protocol_part<R: Read>(r: &mut Take<R>) -> Result<ProtocolInfo, io::Error> {
+        let version = r.read_u8()?;
+        let min_version = r.read_u8()?;
+        let capabilities = r.read_u32::<NetworkEndian>()?;
+        // Skip any extensions added in later versions
+        io::copy(r, &mut io::sink())?;
+        Ok(ProtocolInfo { version, min_version, capabilities })
+    }
+
+    fn decode_gossip_part<R: Read>(r: &mut Take<R>) -> Result<GossipInfo, io::Error> {
+        let seq = r.read_u32::<NetworkEndian>()?;
+        let base = r.read_u32::<NetworkEndian>()?;
+        let ack = r.read_u32::<NetworkEndian>()?;
+        io::copy(r, &mut io::sink())?;
+        Ok(GossipInfo { seq, base, ack })
+    }
+
+    fn decode_services_part<R: Read>(r: &mut Take<R>) -> Result<Vec<String>, Error> {
+        let mut services = vec![];
+        while r.limit() > 0 {
+            let len = r.read_u8().map_err(|_| Error::Message("Truncated message"))? as usize;
+            let mut data = vec![0; len];
+            r.read_exact(&mut data).map_err(|_| Error::Message("Truncated message"))?;
+            services.push(String::from_utf8(data).map_err(|_| Error::Message("Invalid service name"))?);
+        }
+        Ok(services)
+    }
+
     fn decode_internal<R: Read>(mut r: R) -> Result<Self, Error> {
         let mut peers = smallvec![];
         let mut claims = smallvec![];
         let mut peer_timeout = None;
         let mut node_id = None;
         let mut addrs = smallvec![];
+        let mut protocol = None;
+        let mut max_payload = None;
+        let mut services = vec![];
+        let mut name = None;
+        let mut time = None;
+        let mut gossip = None;
+        let mut loss = None;
+        let mut reorder_window = None;
         loop {
             let part = r.read_u8().map_err(|_| Error::Message("Truncated message"))?;
             if part == Self::PART_END {
@@ -127,6 +288,32 @@ impl NodeInfo {
                 Self::PART_ADDRS => {
                     addrs = Self::read_addr_list(&mut rp).map_err(|_| Error::Message("Truncated message"))?;
                 }
+                Self::PART_PROTOCOL => {
+                    protocol =
+                        Some(Self::decode_protocol_part(&mut rp).map_err(|_| Error::Message("Truncated message"))?);
+                }
+                Self::PART_SERVICES => services = Self::decode_services_part(&mut rp)?,
+                Self::PART_NAME => {
+                    let mut data = vec![0; part_len];
+                    rp.read_exact(&mut data).map_err(|_| Error::Message("Truncated message"))?;
+                    name = Some(String::from_utf8(data).map_err(|_| Error::Message("Invalid node name"))?);
+                }
+                Self::PART_MAX_PAYLOAD => {
+                    max_payload = Some(rp.read_u16::<NetworkEndian>().map_err(|_| Error::Message("Truncated message"))?)
+                }
+                Self::PART_TIME => {
+                    time = Some(rp.read_i64::<NetworkEndian>().map_err(|_| Error::Message("Truncated message"))?)
+                }
+                Self::PART_GOSSIP => {
+                    gossip = Some(Self::decode_gossip_part(&mut rp).map_err(|_| Error::Message("Truncated message"))?)
+                }
+                Self::PART_LOSS => {
+                    loss = Some(rp.read_u16::<NetworkEndian>().map_err(|_| Error::Message("Truncated message"))?)
+                }
+                Self::PART_REORDER => {
+                    reorder_window =
+                        Some(rp.read_u16::<NetworkEndian>().map_err(|_| Error::Message("Truncated message"))?)
+                }
                 _ => {
                     let mut data = vec![0; part_len];
                     rp.read_exact(&mut data).map_err(|_| Error::Message("Truncated message"))?;
@@ -138,29 +325,51 @@ impl NodeInfo {
             Some(node_id) => node_id,
             None => return Err(Error::Message("Payload without node_id")),
         };
-        Ok(Self { node_id, peers, claims, peer_timeout, addrs })
+        Ok(Self {
+            node_id,
+            peers,
+            claims,
+            peer_timeout,
+            addrs,
+            protocol,
+            max_payload,
+            services,
+            name,
+            time,
+            gossip,
+            loss,
+            reorder_window,
+        })
     }
 
     pub fn decode<R: Read>(r: R) -> Result<Self, Error> {
         Self::decode_internal(r).map_err(|_| Error::Message("Input data too short"))
     }
 
+    /// Splits the addresses by family for encoding, at most 7 of each family fit into an address list
+    ///
+    /// IPv4 addresses that are mapped into IPv6 are encoded as IPv4 addresses, so nodes with addresses of both
+    /// families announce both even if they have many IPv6 addresses.
+    fn split_addr_list(addrs: &[SocketAddr]) -> (SmallVec<[SocketAddrV4; 16]>, SmallVec<[SocketAddrV6; 16]>) {
+        let mut addr_ipv4: SmallVec<[SocketAddrV4; 16]> = smallvec![];
+        let mut addr_ipv6: SmallVec<[SocketAddrV6; 16]> = smallvec![];
+        for a in addrs {
+            match *a {
+                SocketAddr::V4(addr) => addr_ipv4.push(addr),
+                SocketAddr::V6(addr) => match addr.ip().to_ipv4() {
+                    Some(ip) if is_ipv4_addr(a) => addr_ipv4.push(SocketAddrV4::new(ip, addr.port())),
+                    _ => addr_ipv6.push(addr),
+                },
+            }
+        }
+        addr_ipv4.truncate(7);
+        addr_ipv6.truncate(7);
+        (addr_ipv4, addr_ipv6)
+    }
+
     fn encode_peer_list_part<W: Write>(&self, mut out: W) -> Result<(), io::Error> {
         for p in &self.peers {
-            let mut addr_ipv4: SmallVec<[SocketAddrV4; 16]> = smallvec![];
-            let mut addr_ipv6: SmallVec<[SocketAddrV6; 16]> = smallvec![];
-            for a in &p.addrs {
-                match a {
-                    SocketAddr::V4(addr) => addr_ipv4.push(*addr),
-                    SocketAddr::V6(addr) => addr_ipv6.push(*addr),
-                }
-            }
-            while addr_ipv4.len() >= 8 {
-                addr_ipv4.pop();
-            }
-            while addr_ipv6.len() >= 8 {
-                addr_ipv6.pop();
-            }
+            let (addr_ipv4, addr_ipv6) = Self::split_addr_list(&p.addrs);
             let mut flags = addr_ipv6.len() as u8 * 8 + addr_ipv4.len() as u8;
             if p.node_id.is_some() {
                 flags += 0x80;
@@ -182,20 +391,7 @@ impl NodeInfo {
     }
 
     fn encode_addrs_part<W: Write>(&self, mut out: W) -> Result<(), io::Error> {
-        let mut addr_ipv4: SmallVec<[SocketAddrV4; 16]> = smallvec![];
-        let mut addr_ipv6: SmallVec<[SocketAddrV6; 16]> = smallvec![];
-        for a in &self.addrs {
-            match a {
-                SocketAddr::V4(addr) => addr_ipv4.push(*addr),
-                SocketAddr::V6(addr) => addr_ipv6.push(*addr),
-            }
-        }
-        while addr_ipv4.len() >= 8 {
-            addr_ipv4.pop();
-        }
-        while addr_ipv6.len() >= 8 {
-            addr_ipv6.pop();
-        }
+        let (addr_ipv4, addr_ipv6) = Self::split_addr_list(&self.addrs);
         let flags = addr_ipv6.len() as u8 * 8 + addr_ipv4.len() as u8;
         out.write_u8(flags)?;
         for a in addr_ipv6 {
@@ -242,6 +438,46 @@ impl NodeInfo {
                 })?
             }
             Self::encode_part(&mut cursor, Self::PART_ADDRS, |cursor| self.encode_addrs_part(cursor))?;
+            if let Some(protocol) = self.protocol {
+                Self::encode_part(&mut cursor, Self::PART_PROTOCOL, |cursor| {
+                    cursor.write_u8(protocol.version)?;
+                    cursor.write_u8(protocol.min_version)?;
+                    cursor.write_u32::<NetworkEndian>(protocol.capabilities)
+                })?
+            }
+            if let Some(max_payload) = self.max_payload {
+                Self::encode_part(&mut cursor, Self::PART_MAX_PAYLOAD, |cursor| {
+                    cursor.write_u16::<NetworkEndian>(max_payload)
+                })?
+            }
+            if !self.services.is_empty() {
+                Self::encode_part(&mut cursor, Self::PART_SERVICES, |cursor| {
+                    for service in &self.services {
+                        cursor.write_u8(service.len() as u8)?;
+                        cursor.write_all(service.as_bytes())?;
+                    }
+                    Ok(())
+                })?
+            }
+            if let Some(ref name) = self.name {
+                Self::encode_part(&mut cursor, Self::PART_NAME, |cursor| cursor.write_all(name.as_bytes()))?
+            }
+            if let Some(time) = self.time {
+                Self::encode_part(&mut cursor, Self::PART_TIME, |cursor| cursor.write_i64::<NetworkEndian>(time))?
+            }
+            if let Some(gossip) = self.gossip {
+                Self::encode_part(&mut cursor, Self::PART_GOSSIP, |cursor| {
+                    cursor.write_u32::<NetworkEndian>(gossip.seq)?;
+                    cursor.write_u32::<NetworkEndian>(gossip.base)?;
+                    cursor.write_u32::<NetworkEndian>(gossip.ack)
+                })?
+            }
+            if let Some(loss) = self.loss {
+                Self::encode_part(&mut cursor, Self::PART_LOSS, |cursor| cursor.write_u16::<NetworkEndian>(loss))?
+            }
+            if let Some(window) = self.reorder_window {
+                Self::encode_part(&mut cursor, Self::PART_REORDER, |cursor| cursor.write_u16::<NetworkEndian>(window))?
+            }
             cursor.write_u8(Self::PART_END)?;
             len = cursor.position() as usize;
         }
@@ -263,3 +499,143 @@ impl Payload for NodeInfo {
         Self::decode(r)
     }
 }
+
+#[test]
+fn node_info_protocol() {
+    let mut info = NodeInfo {
+        node_id: [1; NODE_ID_BYTES],
+        peers: smallvec![],
+        claims: smallvec![],
+        peer_timeout: Some(300),
+        addrs: smallvec![],
+        protocol: None,
+        max_payload: None,
+        services: vec![],
+        name: None,
+        time: None,
+        gossip: None,
+        loss: None,
+        reorder_window: None,
+    };
+    let mut buffer = MsgBuffer::new(0);
+    info.encode(&mut buffer);
+    assert_eq!(info, NodeInfo::decode(Cursor::new(buffer.message())).unwrap());
+    info.protocol = Some(ProtocolInfo { version: 3, min_version: 2, capabilities: 0x05 });
+    let mut buffer = MsgBuffer::new(0);
+    info.encode(&mut buffer);
+    assert_eq!(info, NodeInfo::decode(Cursor::new(buffer.message())).unwrap());
+    info.max_payload = Some(9014);
+    let mut buffer = MsgBuffer::new(0);
+    info.encode(&mut buffer);
+    assert_eq!(info, NodeInfo::decode(Cursor::new(buffer.message())).unwrap());
+    info.services = vec!["ssh".to_string(), "http:8080".to_string()];
+    let mut buffer = MsgBuffer::new(0);
+    info.encode(&mut buffer);
+    assert_eq!(info, NodeInfo::decode(Cursor::new(buffer.message())).unwrap());
+    info.name = Some("node1".to_string());
+    let mut buffer = MsgBuffer::new(0);
+    info.encode(&mut buffer);
+    assert_eq!(info, NodeInfo::decode(Cursor::new(buffer.message())).unwrap());
+    info.time = Some(1_600_000_000);
+    let mut buffer = MsgBuffer::new(0);
+    info.encode(&mut buffer);
+    assert_eq!(info, NodeInfo::decode(Cursor::new(buffer.message())).unwrap());
+    info.gossip = Some(GossipInfo { seq: 12, base: 10, ack: 3 });
+    let mut buffer = MsgBuffer::new(0);
+    info.encode(&mut buffer);
+    assert_eq!(info, NodeInfo::decode(Cursor::new(buffer.message())).unwrap());
+    info.loss = Some(25);
+    let mut buffer = MsgBuffer::new(0);
+    info.encode(&mut buffer);
+    assert_eq!(info, NodeInfo::decode(Cursor::new(buffer.message())).unwrap());
+    info.reorder_window = Some(30);
+    let mut buffer = MsgBuffer::new(0);
+    info.encode(&mut buffer);
+    assert_eq!(info, NodeInfo::decode(Cursor::new(buffer.message())).unwrap());
+}
+
+#[test]
+fn node_info_dual_stack_addrs() {
+    let v4 = mapped_addr("1.2.3.4:3210".parse().unwrap());
+    let v6: Vec<SocketAddr> = (1..=8).map(|i| format!("[2001:db8::{}]:3210", i).parse().unwrap()).collect();
+    let mut addrs: AddrList = smallvec![v4];
+    addrs.extend(v6.iter().copied());
+    let info = NodeInfo {
+        node_id: [1; NODE_ID_BYTES],
+        peers: smallvec![PeerInfo { node_id: None, addrs: addrs.clone() }],
+        claims: smallvec![],
+        peer_timeout: None,
+        addrs,
+        protocol: None,
+        max_payload: None,
+        services: vec![],
+        name: None,
+        time: None,
+        gossip: None,
+        loss: None,
+        reorder_window: None,
+    };
+    let mut buffer = MsgBuffer::new(0);
+    info.encode(&mut buffer);
+    let decoded = NodeInfo::decode(Cursor::new(buffer.message())).unwrap();
+    // The IPv4 address is not crowded out by the IPv6 addresses
+    let mut expected: AddrList = v6[..7].iter().copied().collect();
+    expected.push(v4);
+    assert_eq!(decoded.addrs, expected);
+    assert_eq!(decoded.peers[0].addrs, expected);
+}
+
+#[test]
+fn keepalive_time() {
+    let mut buffer = MsgBuffer::new(0);
+    encode_keepalive(KEEPALIVE_PROBE, 1_600_000_000, &mut buffer);
+    assert_eq!((KEEPALIVE_PROBE, Some(1_600_000_000)), decode_keepalive(buffer.message()));
+    assert_eq!((0, None), decode_keepalive(&[]));
+    assert_eq!((KEEPALIVE_PROBE, None), decode_keepalive(&[KEEPALIVE_PROBE]));
+}
+
+#[test]
+fn control_msg_padding() {
+    let mut buffer = MsgBuffer::new(0);
+    encode_keepalive(KEEPALIVE_PROBE, 1_600_000_000, &mut buffer);
+    pad_control_msg(MESSAGE_TYPE_KEEPALIVE, &mut buffer);
+    assert_eq!(buffer.len() + 1, crate::util::PADDING_BLOCK);
+    assert_eq!((KEEPALIVE_PROBE, Some(1_600_000_000)), decode_keepalive(buffer.message()));
+    let info = NodeInfo {
+        node_id: [1; NODE_ID_BYTES],
+        peers: smallvec![],
+        claims: smallvec![],
+        peer_timeout: None,
+        addrs: smallvec![],
+        protocol: Some(ProtocolInfo::own()),
+        max_payload: None,
+        services: vec![],
+        name: Some("node1".to_string()),
+        time: None,
+        gossip: None,
+        loss: None,
+        reorder_window: None,
+    };
+    let mut buffer = MsgBuffer::new(0);
+    info.encode(&mut buffer);
+    pad_control_msg(MESSAGE_TYPE_NODE_INFO, &mut buffer);
+    assert_eq!((buffer.len() + 1) % crate::util::PADDING_BLOCK, 0);
+    assert_eq!(info, NodeInfo::decode(Cursor::new(buffer.message())).unwrap());
+    let mut buffer = MsgBuffer::new(0);
+    buffer.clone_from(&[1, 2, 3]);
+    pad_control_msg(MESSAGE_TYPE_DATA, &mut buffer);
+    assert_eq!(buffer.message(), &[1, 2, 3]);
+}
+
+#[test]
+fn protocol_compatibility() {
+    assert!(ProtocolInfo::own().is_compatible());
+    assert!(ProtocolInfo::legacy().is_compatible());
+    let newer = ProtocolInfo { version: PROTOCOL_VERSION + 1, min_version: PROTOCOL_VERSION, capabilities: 0xff };
+    assert!(newer.is_compatible());
+    assert_eq!(PROTOCOL_VERSION, newer.common_version());
+    assert_eq!(CAPABILITIES, newer.common_capabilities());
+    let incompatible =
+        ProtocolInfo { version: PROTOCOL_VERSION + 2, min_version: PROTOCOL_VERSION + 1, capabilities: 0 };
+    assert!(!incompatible.is_compatible());
+}
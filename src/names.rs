@@ -0,0 +1,109 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Names that peers announce, remembered by the fingerprint of their public key
+//!
+//! Nodes announce their `node-name` to their peers. The names are stored with the key fingerprint in the state
+//! directory, so a peer keeps its name across address changes and restarts, even while it does not announce a name
+//! itself. Names of trusted keys in the config always take precedence.
+
+use std::collections::HashMap;
+
+/// Maximal number of remembered names
+const MAX_NAMES: usize = 4096;
+
+/// Maximal length of a name in bytes
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Default)]
+pub struct PeerNames {
+    names: HashMap<String, String>,
+    changed: bool,
+}
+
+impl PeerNames {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Remembers the name the peer with the fingerprint announced
+    ///
+    /// Names that are too long or contain control characters are ignored as they could corrupt the log and the file.
+    pub fn learn(&mut self, fingerprint: &str, name: &str) {
+        if name.is_empty() || name.len() > MAX_NAME_LEN || name.chars().any(char::is_control) {
+            return;
+        }
+        match self.names.get(fingerprint) {
+            Some(known) if known == name => return,
+            Some(known) => info!("Peer {} renamed itself from {} to {}", fingerprint, known, name),
+            None if self.names.len() >= MAX_NAMES => return,
+            None => debug!("Learned name {} of peer {}", name, fingerprint),
+        }
+        self.names.insert(fingerprint.to_string(), name.to_string());
+        self.changed = true;
+    }
+
+    pub fn get(&self, fingerprint: &str) -> Option<&str> {
+        self.names.get(fingerprint).map(|name| name as &str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Whether names have been learned since the last save
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+
+    /// Loads the names saved by `save`, ignoring invalid lines
+    pub fn load(&mut self, data: &str) {
+        for line in data.lines() {
+            if let Some((fingerprint, name)) = line.trim().split_once(' ') {
+                self.learn(fingerprint, name);
+            }
+        }
+        self.changed = false;
+    }
+
+    /// Formats the names as one `fingerprint name` line per peer
+    pub fn save(&mut self) -> String {
+        let mut names: Vec<_> = self.names.iter().collect();
+        names.sort();
+        let mut data = String::new();
+        for (fingerprint, name) in names {
+            data.push_str(&format!("{} {}\n", fingerprint, name));
+        }
+        self.changed = false;
+        data
+    }
+}
+
+#[test]
+fn peer_names_learn_and_save() {
+    let mut names = PeerNames::new();
+    names.learn("key1", "node1");
+    names.learn("key2", "node two");
+    names.learn("key3", "evil\nkey4 node4");
+    names.learn("key3", &"x".repeat(MAX_NAME_LEN + 1));
+    assert_eq!(names.len(), 2);
+    assert!(names.changed());
+    let data = names.save();
+    assert_eq!(data, "key1 node1\nkey2 node two\n");
+    assert!(!names.changed());
+    names.learn("key1", "node1");
+    assert!(!names.changed());
+    names.learn("key1", "renamed");
+    assert!(names.changed());
+    let mut loaded = PeerNames::new();
+    loaded.load(&format!("{}invalid\n", data));
+    assert_eq!(loaded.get("key1"), Some("node1"));
+    assert_eq!(loaded.get("key2"), Some("node two"));
+    assert_eq!(loaded.len(), 2);
+    assert!(!loaded.changed());
+}
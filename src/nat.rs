@@ -0,0 +1,302 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+use fnv::FnvHasher;
+use std::{collections::HashMap, hash::BuildHasherDefault, net::SocketAddr, str::FromStr};
+
+use crate::{
+    error::Error,
+    types::{Address, Range, RangeList},
+};
+
+type Hash = BuildHasherDefault<FnvHasher>;
+
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+const PROTO_ICMPV6: u8 = 58;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RuleConfig {
+    /// The name of the peer whose addresses are translated
+    pub peer: String,
+    /// The prefix used at the site of the peer
+    pub remote: String,
+    /// The prefix under which the site of the peer is reachable locally
+    pub local: String,
+}
+
+/// A prefix mapping from one range to another range of the same size
+#[derive(Clone, Copy)]
+struct Mapping {
+    from: Range,
+    to: Range,
+}
+
+impl Mapping {
+    fn apply(&self, addr: &mut [u8]) {
+        let full = self.from.prefix_len as usize / 8;
+        addr[..full].copy_from_slice(&self.to.base.data[..full]);
+        let bits = self.from.prefix_len % 8;
+        if bits > 0 {
+            let mask = 0xff << (8 - bits);
+            addr[full] = (self.to.base.data[full] & mask) | (addr[full] & !mask);
+        }
+    }
+
+    fn apply_range(&self, range: &Range) -> Option<Range> {
+        if range.prefix_len < self.from.prefix_len || !self.from.matches(range.base) {
+            return None;
+        }
+        let mut base = range.base;
+        self.apply(&mut base.data[..base.len as usize]);
+        Some(Range { base, prefix_len: range.prefix_len })
+    }
+}
+
+/// The mappings that are active for a peer
+struct PeerMappings {
+    inbound: Vec<Mapping>,
+    outbound: Vec<Mapping>,
+}
+
+struct Rule {
+    peer: String,
+    remote: Range,
+    local: Range,
+}
+
+fn parse_prefix(value: &str) -> Result<Range, Error> {
+    let range = Range::from_str(value).map_err(|_| Error::InvalidConfig("Invalid NAT prefix"))?;
+    if (range.base.len != 4 && range.base.len != 16) || range.prefix_len > range.base.len * 8 {
+        return Err(Error::InvalidConfig("NAT prefixes must be IPv4 or IPv6 subnets"));
+    }
+    Ok(range)
+}
+
+/// Incrementally updates a checksum after the covered data changed from `old` to `new` (RFC 1624)
+fn update_checksum(checksum: &mut [u8], old: &[u8], new: &[u8]) {
+    let mut sum = u32::from(!u16::from_be_bytes([checksum[0], checksum[1]]));
+    for (o, n) in old.chunks(2).zip(new.chunks(2)) {
+        sum += u32::from(!u16::from_be_bytes([o[0], o[1]]));
+        sum += u32::from(u16::from_be_bytes([n[0], n[1]]));
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    checksum.copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+/// Rewrites the source or destination address of an IP packet and fixes all checksums
+fn rewrite(packet: &mut [u8], dst: bool, mappings: &[Mapping]) {
+    // HOT PATH
+    let (pos, len, protocol, transport) = match packet.first().map(|b| b >> 4) {
+        Some(4) if packet.len() >= 20 => {
+            let header_len = (packet[0] & 0x0f) as usize * 4;
+            // Only the first fragment contains the transport header
+            let first_fragment = packet[6] & 0x1f == 0 && packet[7] == 0;
+            (if dst { 16 } else { 12 }, 4, packet[9], if first_fragment { Some(header_len) } else { None })
+        }
+        Some(6) if packet.len() >= 40 => (if dst { 24 } else { 8 }, 16, packet[6], Some(40)),
+        _ => return,
+    };
+    let mut addr = Address { data: [0; 16], len };
+    addr.data[..len as usize].copy_from_slice(&packet[pos..pos + len as usize]);
+    let mapping = match mappings.iter().find(|m| m.from.matches(addr)) {
+        Some(mapping) => mapping,
+        None => return,
+    };
+    let old = addr.data;
+    let new = &mut packet[pos..pos + len as usize];
+    mapping.apply(new);
+    let mut new_data = [0; 16];
+    new_data[..len as usize].copy_from_slice(new);
+    let (old, new) = (&old[..len as usize], &new_data[..len as usize]);
+    if len == 4 {
+        update_checksum(&mut packet[10..12], old, new);
+    }
+    let offset = match transport {
+        Some(offset) => offset,
+        None => return,
+    };
+    let checksum_pos = match protocol {
+        PROTO_TCP => offset + 16,
+        PROTO_UDP => offset + 6,
+        PROTO_ICMPV6 if len == 16 => offset + 2,
+        _ => return,
+    };
+    if packet.len() < checksum_pos + 2 {
+        return;
+    }
+    let checksum = &mut packet[checksum_pos..checksum_pos + 2];
+    if protocol == PROTO_UDP {
+        if checksum == [0, 0] {
+            // UDP checksums are optional for IPv4
+            return;
+        }
+        update_checksum(checksum, old, new);
+        if checksum == [0, 0] {
+            checksum.copy_from_slice(&[0xff, 0xff]);
+        }
+    } else {
+        update_checksum(checksum, old, new);
+    }
+}
+
+/// 1:1 prefix translation of the addresses of peers with overlapping site prefixes
+///
+/// Each rule maps the prefix used at the site of a named peer to a local prefix of the same size.
+/// Claims of that peer are translated accordingly, source addresses of packets received from it
+/// are translated to the local prefix and destination addresses of packets sent to it are
+/// translated back.
+pub struct Nat {
+    rules: Vec<Rule>,
+    peers: HashMap<SocketAddr, PeerMappings, Hash>,
+}
+
+impl Nat {
+    pub fn new(rules: &[RuleConfig]) -> Result<Self, Error> {
+        let mut res = vec![];
+        for rule in rules {
+            let remote = parse_prefix(&rule.remote)?;
+            let local = parse_prefix(&rule.local)?;
+            if remote.base.len != local.base.len || remote.prefix_len != local.prefix_len {
+                return Err(Error::InvalidConfig("NAT prefixes of a rule must have the same type and size"));
+            }
+            res.push(Rule { peer: rule.peer.clone(), remote, local });
+        }
+        Ok(Self { rules: res, peers: HashMap::default() })
+    }
+
+    /// Activates the rules for the peer with the given name
+    pub fn set_peer(&mut self, addr: SocketAddr, name: Option<&str>) {
+        let rules: Vec<_> = self.rules.iter().filter(|rule| Some(&rule.peer as &str) == name).collect();
+        if rules.is_empty() {
+            self.peers.remove(&addr);
+            return;
+        }
+        let inbound = rules.iter().map(|rule| Mapping { from: rule.remote, to: rule.local }).collect();
+        let outbound = rules.iter().map(|rule| Mapping { from: rule.local, to: rule.remote }).collect();
+        self.peers.insert(addr, PeerMappings { inbound, outbound });
+    }
+
+    pub fn remove_peer(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+    }
+
+    /// Translates the claims of the peer into the local prefixes
+    pub fn translate_claims(&self, addr: &SocketAddr, claims: &[Range]) -> RangeList {
+        let mappings = match self.peers.get(addr) {
+            Some(mappings) => &mappings.inbound,
+            None => return claims.iter().cloned().collect(),
+        };
+        claims.iter().map(|claim| mappings.iter().find_map(|m| m.apply_range(claim)).unwrap_or(*claim)).collect()
+    }
+
+    /// Translates the source address of a packet received from the peer
+    pub fn translate_in(&self, addr: &SocketAddr, packet: &mut [u8]) {
+        // HOT PATH
+        if let Some(mappings) = self.peers.get(addr) {
+            rewrite(packet, false, &mappings.inbound)
+        }
+    }
+
+    /// Translates the destination address of a packet that is sent to the peer
+    pub fn translate_out(&self, addr: &SocketAddr, packet: &mut [u8]) {
+        // HOT PATH
+        if let Some(mappings) = self.peers.get(addr) {
+            rewrite(packet, true, &mappings.outbound)
+        }
+    }
+}
+
+#[cfg(test)]
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in data.chunks(2) {
+        sum += u32::from(u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]));
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+fn udp_packet(src: [u8; 4], dst: [u8; 4]) -> Vec<u8> {
+    let mut packet = vec![0x45, 0, 0, 32, 0, 0, 0, 0, 64, PROTO_UDP, 0, 0];
+    packet.extend_from_slice(&src);
+    packet.extend_from_slice(&dst);
+    packet.extend_from_slice(&[0x30, 0x39, 0, 53, 0, 12, 0, 0, 1, 2, 3, 4]);
+    let header = checksum(&packet[..20]);
+    packet[10..12].copy_from_slice(&header.to_be_bytes());
+    let mut pseudo = packet[12..20].to_vec();
+    pseudo.extend_from_slice(&[0, PROTO_UDP, 0, 12]);
+    pseudo.extend_from_slice(&packet[20..]);
+    packet[26..28].copy_from_slice(&checksum(&pseudo).to_be_bytes());
+    packet
+}
+
+#[cfg(test)]
+fn test_nat() -> (Nat, SocketAddr) {
+    let mut nat = Nat::new(&[RuleConfig {
+        peer: "site2".to_string(),
+        remote: "192.168.1.0/24".to_string(),
+        local: "10.2.1.0/24".to_string(),
+    }])
+    .unwrap();
+    let addr = "1.2.3.4:3210".parse().unwrap();
+    nat.set_peer(addr, Some("site2"));
+    (nat, addr)
+}
+
+#[test]
+fn nat_translate_packets() {
+    let (nat, addr) = test_nat();
+    let mut packet = udp_packet([192, 168, 1, 5], [192, 168, 1, 1]);
+    nat.translate_in(&addr, &mut packet);
+    assert_eq!(udp_packet([10, 2, 1, 5], [192, 168, 1, 1]), packet);
+    let mut packet = udp_packet([192, 168, 1, 1], [10, 2, 1, 5]);
+    nat.translate_out(&addr, &mut packet);
+    assert_eq!(udp_packet([192, 168, 1, 1], [192, 168, 1, 5]), packet);
+    // Other peers and addresses are not translated
+    let other = "1.2.3.5:3210".parse().unwrap();
+    let mut packet = udp_packet([192, 168, 1, 5], [192, 168, 1, 1]);
+    nat.translate_in(&other, &mut packet);
+    assert_eq!(udp_packet([192, 168, 1, 5], [192, 168, 1, 1]), packet);
+    let mut packet = udp_packet([192, 168, 2, 5], [192, 168, 1, 1]);
+    nat.translate_in(&addr, &mut packet);
+    assert_eq!(udp_packet([192, 168, 2, 5], [192, 168, 1, 1]), packet);
+}
+
+#[test]
+fn nat_translate_claims() {
+    let (mut nat, addr) = test_nat();
+    let claims = vec![
+        Range::from_str("192.168.1.0/24").unwrap(),
+        Range::from_str("192.168.1.128/25").unwrap(),
+        Range::from_str("192.168.0.0/16").unwrap(),
+    ];
+    let expected = vec![
+        Range::from_str("10.2.1.0/24").unwrap(),
+        Range::from_str("10.2.1.128/25").unwrap(),
+        Range::from_str("192.168.0.0/16").unwrap(),
+    ];
+    assert_eq!(&expected as &[Range], &nat.translate_claims(&addr, &claims) as &[Range]);
+    nat.remove_peer(&addr);
+    assert_eq!(&claims as &[Range], &nat.translate_claims(&addr, &claims) as &[Range]);
+}
+
+#[test]
+fn nat_invalid_rules() {
+    let rule = |remote: &str, local: &str| RuleConfig {
+        peer: "site2".to_string(),
+        remote: remote.to_string(),
+        local: local.to_string(),
+    };
+    assert!(Nat::new(&[rule("192.168.1.0/24", "10.2.0.0/16")]).is_err());
+    assert!(Nat::new(&[rule("192.168.1.0/24", "fd00::/24")]).is_err());
+    assert!(Nat::new(&[rule("192.168.1.0/33", "10.2.1.0/33")]).is_err());
+    assert!(Nat::new(&[rule("fd00:1::/64", "fd00:2::/64")]).is_ok());
+}
@@ -2,17 +2,38 @@
 // Copyright (C) 2015-2021  Dennis Schwerdel
 // This software is licensed under GPL-3 or newer (see LICENSE.md)
 
+use byteorder::{ByteOrder, NetworkEndian};
+use fnv::FnvHasher;
+use smallvec::{smallvec, SmallVec};
 use std::{
     collections::{HashMap, VecDeque},
+    ffi::CStr,
+    hash::Hasher,
     io::{self, ErrorKind},
-    net::{IpAddr, Ipv6Addr, SocketAddr, UdpSocket},
-    os::unix::io::{AsRawFd, RawFd},
-    sync::atomic::{AtomicBool, Ordering},
+    mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    os::unix::{
+        io::{AsRawFd, RawFd},
+        net::UnixDatagram,
+    },
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use super::util::{MockTimeSource, MsgBuffer, Time, TimeSource};
+use super::util::{parse_scoped_addr, MockTimeSource, MsgBuffer, Time, TimeSource, MAX_MSG_SIZE};
 use crate::{config::DEFAULT_PORT, port_forwarding::PortForwarding};
 
+/// Length of the network id in front of each packet when the port is shared by networks
+pub const NETWORK_ID_LEN: usize = 4;
+/// Length of the address in front of each packet that the dispatcher passes to a network
+const ADDR_LEN: usize = 18;
+/// Receive timestamps that are older are implausible, e.g. from a hardware clock that is not synchronized
+const MAX_TIMESTAMP_AGE: Duration = Duration::from_secs(1);
+
 pub fn mapped_addr(addr: SocketAddr) -> SocketAddr {
     // HOT PATH
     match addr {
@@ -21,38 +42,217 @@ pub fn mapped_addr(addr: SocketAddr) -> SocketAddr {
     }
 }
 
+/// Whether the address is a private or link-local address that is only reachable on the LAN
+pub fn is_local_addr(addr: &SocketAddr) -> bool {
+    match addr.ip() {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            match ip.to_ipv4() {
+                Some(ip) if segments[5] == 0xffff => ip.is_private() || ip.is_link_local(),
+                _ => (segments[0] & 0xfe00) == 0xfc00 || (segments[0] & 0xffc0) == 0xfe80,
+            }
+        }
+    }
+}
+
+/// Whether the address is an IPv4 address, also if it is mapped into IPv6
+pub fn is_ipv4_addr(addr: &SocketAddr) -> bool {
+    match addr.ip() {
+        IpAddr::V4(_) => true,
+        IpAddr::V6(ip) => ip.segments()[..6] == [0, 0, 0, 0, 0, 0xffff],
+    }
+}
+
+/// Public hosts that are used to find the addresses of the default routes, nothing is sent to them
+const ROUTE_TARGET_V4: &str = "8.8.8.8:53";
+const ROUTE_TARGET_V6: &str = "[2001:4860:4860::8888]:53";
+
+/// Returns the address that the node uses to reach the target if there is a route to it
+fn route_ip(target: &str) -> Option<IpAddr> {
+    // Connecting a UDP socket does not send anything but selects the local address
+    let s = UdpSocket::bind("[::]:0").ok()?;
+    s.connect(target).ok()?;
+    s.local_addr().ok().map(|addr| addr.ip())
+}
+
+/// Returns the address of the default route, preferring IPv4
 pub fn get_ip() -> IpAddr {
-    let s = UdpSocket::bind("[::]:0").unwrap();
-    s.connect("8.8.8.8:0").unwrap();
-    s.local_addr().unwrap().ip()
+    route_ip(ROUTE_TARGET_V4)
+        .or_else(|| route_ip(ROUTE_TARGET_V6))
+        .unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+}
+
+/// Returns the addresses of the default routes of both address families, if the node has them
+pub fn get_ips() -> SmallVec<[IpAddr; 2]> {
+    [ROUTE_TARGET_V4, ROUTE_TARGET_V6].iter().filter_map(|target| route_ip(target)).collect()
+}
+
+/// Returns the IP addresses of the network interface with the given name
+pub fn interface_ips(name: &str) -> Result<Vec<IpAddr>, io::Error> {
+    let mut ifap: *mut libc::ifaddrs = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifap) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut ips = vec![];
+    let mut ifa = ifap;
+    while !ifa.is_null() {
+        let entry = unsafe { &*ifa };
+        ifa = entry.ifa_next;
+        if entry.ifa_addr.is_null() || unsafe { CStr::from_ptr(entry.ifa_name) }.to_bytes() != name.as_bytes() {
+            continue;
+        }
+        match unsafe { (*entry.ifa_addr).sa_family } as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+                ips.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))));
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in6) };
+                ips.push(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+            }
+            _ => (),
+        }
+    }
+    unsafe { libc::freeifaddrs(ifap) };
+    Ok(ips)
 }
 
 pub trait Socket: AsRawFd + Sized {
     fn listen(addr: &str) -> Result<Self, io::Error>;
     fn receive(&mut self, buffer: &mut MsgBuffer) -> Result<SocketAddr, io::Error>;
+
+    /// Receives a message like `receive` and also returns the time when the kernel received it if known
+    fn receive_timestamped(&mut self, buffer: &mut MsgBuffer) -> Result<(SocketAddr, Option<Instant>), io::Error> {
+        Ok((self.receive(buffer)?, None))
+    }
+
     fn send(&mut self, data: &[u8], addr: SocketAddr) -> Result<usize, io::Error>;
     fn address(&self) -> Result<SocketAddr, io::Error>;
-    fn create_port_forwarding(&self) -> Option<PortForwarding>;
+
+    /// Returns the addresses of the socket, one per address family if it is reachable via both
+    fn addresses(&self) -> Result<SmallVec<[SocketAddr; 2]>, io::Error> {
+        Ok(smallvec![self.address()?])
+    }
+
+    fn create_port_forwarding(&self, description: &str) -> Option<PortForwarding>;
 }
 
-pub fn parse_listen(addr: &str, default_port: u16) -> SocketAddr {
-    if let Some(addr) = addr.strip_prefix("*:") {
-        let port = try_fail!(addr.parse::<u16>(), "Invalid port: {}");
-        SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port)
+pub fn parse_listen(addr: &str, default_port: u16) -> Result<SocketAddr, io::Error> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", msg, addr));
+    if let Some(port) = addr.strip_prefix("*:") {
+        let port = port.parse::<u16>().map_err(|_| invalid("Invalid port"))?;
+        Ok(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port))
+    } else if let Some(addr) = parse_scoped_addr(addr) {
+        Ok(addr)
     } else if addr.contains(':') {
-        try_fail!(addr.parse::<SocketAddr>(), "Invalid address: {}: {}", addr)
+        addr.parse::<SocketAddr>().map_err(|_| invalid("Invalid address"))
     } else if let Ok(port) = addr.parse::<u16>() {
-        SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port)
+        Ok(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port))
     } else {
-        let ip = try_fail!(addr.parse::<IpAddr>(), "Invalid addr: {}");
-        SocketAddr::new(ip, default_port)
+        let ip = addr.parse::<IpAddr>().map_err(|_| invalid("Invalid address"))?;
+        Ok(SocketAddr::new(ip, default_port))
+    }
+}
+
+fn set_socket_option(socket: &UdpSocket, option: libc::c_int, value: libc::c_int) -> Result<(), io::Error> {
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Asks the kernel to timestamp received packets and returns the name of the used mechanism
+///
+/// `SO_TIMESTAMPING` also delivers hardware timestamps if the network card has been configured to create them
+/// (e.g. with `hwstamp_ctl`). Older kernels only support software timestamps via `SO_TIMESTAMPNS`.
+pub fn enable_receive_timestamps(socket: &UdpSocket) -> Result<&'static str, io::Error> {
+    let flags = libc::SOF_TIMESTAMPING_RX_HARDWARE
+        | libc::SOF_TIMESTAMPING_RAW_HARDWARE
+        | libc::SOF_TIMESTAMPING_RX_SOFTWARE
+        | libc::SOF_TIMESTAMPING_SOFTWARE;
+    if set_socket_option(socket, libc::SO_TIMESTAMPING, flags as libc::c_int).is_ok() {
+        return Ok("SO_TIMESTAMPING");
+    }
+    set_socket_option(socket, libc::SO_TIMESTAMPNS, 1)?;
+    Ok("SO_TIMESTAMPNS")
+}
+
+fn sockaddr_to_addr(addr: &libc::sockaddr_storage) -> Result<SocketAddr, io::Error> {
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(addr as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(addr as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            let port = u16::from_be(addr.sin6_port);
+            Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, addr.sin6_flowinfo, addr.sin6_scope_id)))
+        }
+        _ => Err(io::Error::new(ErrorKind::InvalidData, "Unsupported address family")),
+    }
+}
+
+/// Extracts the receive timestamp from the control messages, preferring the software timestamp
+///
+/// The hardware timestamp is in the time of the clock of the network card, so it is only used if there is no software
+/// timestamp and it is plausible.
+///
+/// # Safety
+/// The control messages of the header must be valid, i.e. it must have been filled by `recvmsg`.
+unsafe fn receive_timestamp(msg: &libc::msghdr) -> Option<libc::timespec> {
+    let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+    while !cmsg.is_null() {
+        if (*cmsg).cmsg_level == libc::SOL_SOCKET {
+            let data = libc::CMSG_DATA(cmsg);
+            match (*cmsg).cmsg_type {
+                libc::SCM_TIMESTAMPING => {
+                    // Software, deprecated and hardware timestamp
+                    let stamps = ptr::read_unaligned(data as *const [libc::timespec; 3]);
+                    return stamps.iter().step_by(2).find(|ts| ts.tv_sec != 0 || ts.tv_nsec != 0).copied();
+                }
+                libc::SCM_TIMESTAMPNS => return Some(ptr::read_unaligned(data as *const libc::timespec)),
+                _ => (),
+            }
+        }
+        cmsg = libc::CMSG_NXTHDR(msg, cmsg);
     }
+    None
+}
+
+/// Converts the system time of a receive timestamp into an instant, implausible timestamps are ignored
+fn timestamp_to_instant(ts: libc::timespec) -> Option<Instant> {
+    if ts.tv_sec < 0 || !(0..1_000_000_000).contains(&ts.tv_nsec) {
+        return None;
+    }
+    let received = UNIX_EPOCH.checked_add(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))?;
+    let age = SystemTime::now().duration_since(received).ok()?;
+    if age > MAX_TIMESTAMP_AGE {
+        return None;
+    }
+    Instant::now().checked_sub(age)
 }
 
 impl Socket for UdpSocket {
     fn listen(addr: &str) -> Result<Self, io::Error> {
-        let addr = parse_listen(addr, DEFAULT_PORT);
-        UdpSocket::bind(addr)
+        let addr = parse_listen(addr, DEFAULT_PORT)?;
+        let socket = UdpSocket::bind(addr)?;
+        match enable_receive_timestamps(&socket) {
+            Ok(mechanism) => debug!("Timestamping received packets via {}", mechanism),
+            Err(err) => debug!("Failed to enable receive timestamps: {}", err),
+        }
+        Ok(socket)
     }
 
     fn receive(&mut self, buffer: &mut MsgBuffer) -> Result<SocketAddr, io::Error> {
@@ -62,6 +262,30 @@ impl Socket for UdpSocket {
         Ok(addr)
     }
 
+    fn receive_timestamped(&mut self, buffer: &mut MsgBuffer) -> Result<(SocketAddr, Option<Instant>), io::Error> {
+        // HOT PATH
+        buffer.clear();
+        let data = buffer.buffer();
+        let mut iov = libc::iovec { iov_base: data.as_mut_ptr() as *mut libc::c_void, iov_len: data.len() };
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        // Aligned space for the control message with the timestamps
+        let mut control = [0u64; 16];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+        let size = unsafe { libc::recvmsg(self.as_raw_fd(), &mut msg, 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buffer.set_length(size as usize);
+        let time = unsafe { receive_timestamp(&msg) }.and_then(timestamp_to_instant);
+        Ok((sockaddr_to_addr(&addr)?, time))
+    }
+
     fn send(&mut self, data: &[u8], addr: SocketAddr) -> Result<usize, io::Error> {
         self.send_to(data, addr)
     }
@@ -72,8 +296,160 @@ impl Socket for UdpSocket {
         Ok(addr)
     }
 
-    fn create_port_forwarding(&self) -> Option<PortForwarding> {
-        PortForwarding::new(self.address().unwrap().port())
+    fn addresses(&self) -> Result<SmallVec<[SocketAddr; 2]>, io::Error> {
+        let local = self.local_addr()?;
+        if !local.ip().is_unspecified() {
+            return Ok(smallvec![local]);
+        }
+        // Sockets bound to [::] are dual-stack, sockets bound to 0.0.0.0 can only use IPv4
+        let addrs: SmallVec<[SocketAddr; 2]> = get_ips()
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, local.port()))
+            .filter(|addr| local.is_ipv6() || is_ipv4_addr(addr))
+            .collect();
+        if addrs.is_empty() {
+            return Ok(smallvec![local]);
+        }
+        Ok(addrs)
+    }
+
+    fn create_port_forwarding(&self, description: &str) -> Option<PortForwarding> {
+        PortForwarding::new(self.address().unwrap().port(), description)
+    }
+}
+
+/// Derives the id that is sent in front of each packet from the name of the network
+pub fn network_id(name: &str) -> u32 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(name.as_bytes());
+    let hash = hasher.finish();
+    (hash ^ (hash >> 32)) as u32
+}
+
+fn write_addr(addr: SocketAddr, out: &mut [u8]) {
+    let ip = match mapped_addr(addr).ip() {
+        IpAddr::V6(ip) => ip,
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+    };
+    out[..16].copy_from_slice(&ip.octets());
+    NetworkEndian::write_u16(&mut out[16..ADDR_LEN], addr.port());
+}
+
+fn read_addr(data: &[u8]) -> SocketAddr {
+    let mut ip = [0; 16];
+    ip.copy_from_slice(&data[..16]);
+    let port = NetworkEndian::read_u16(&data[16..ADDR_LEN]);
+    SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0))
+}
+
+/// Splits one UDP socket into multiple logical networks by the network id in front of each packet
+///
+/// The dispatcher reads all packets from the socket and passes them to the network with the matching id via a
+/// socket pair, so each network can wait on its own file descriptor. Packets for unknown networks are dropped.
+pub struct NetworkDispatcher {
+    socket: Arc<UdpSocket>,
+    networks: HashMap<u32, UnixDatagram>,
+}
+
+impl NetworkDispatcher {
+    pub fn new(socket: UdpSocket) -> Self {
+        Self { socket: Arc::new(socket), networks: HashMap::new() }
+    }
+
+    /// Creates the socket of the network with the given id
+    pub fn add_network(&mut self, id: u32) -> Result<NetworkSocket, io::Error> {
+        if self.networks.contains_key(&id) {
+            return Err(io::Error::new(ErrorKind::AlreadyExists, format!("Network id {:08x} is already used", id)));
+        }
+        let (sender, receiver) = UnixDatagram::pair()?;
+        // A slow network must not hold up the others
+        sender.set_nonblocking(true)?;
+        self.networks.insert(id, sender);
+        Ok(NetworkSocket { id, socket: self.socket.clone(), receiver, buffer: Vec::with_capacity(MAX_MSG_SIZE) })
+    }
+
+    /// Passes the packets to the networks until reading from the socket fails
+    pub fn run(self) -> io::Error {
+        // The address replaces the network id in front of the packet
+        let mut buffer = vec![0; MAX_MSG_SIZE + ADDR_LEN];
+        let offset = ADDR_LEN - NETWORK_ID_LEN;
+        loop {
+            let (size, addr) = match self.socket.recv_from(&mut buffer[offset..]) {
+                Ok(res) => res,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return err,
+            };
+            if size < NETWORK_ID_LEN {
+                continue;
+            }
+            let id = NetworkEndian::read_u32(&buffer[offset..ADDR_LEN]);
+            let network = match self.networks.get(&id) {
+                Some(network) => network,
+                None => {
+                    debug!("Dropping packet from {} for unknown network {:08x}", addr, id);
+                    continue;
+                }
+            };
+            write_addr(addr, &mut buffer[..ADDR_LEN]);
+            if let Err(err) = network.send(&buffer[..offset + size]) {
+                debug!("Failed to pass packet to network {:08x}: {}", id, err);
+            }
+        }
+    }
+}
+
+/// Socket of one logical network on a port that is shared via a `NetworkDispatcher`
+pub struct NetworkSocket {
+    id: u32,
+    socket: Arc<UdpSocket>,
+    receiver: UnixDatagram,
+    buffer: Vec<u8>,
+}
+
+impl AsRawFd for NetworkSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.receiver.as_raw_fd()
+    }
+}
+
+impl Socket for NetworkSocket {
+    fn listen(_addr: &str) -> Result<Self, io::Error> {
+        Err(io::Error::new(ErrorKind::Other, "Network sockets are created by the dispatcher"))
+    }
+
+    fn receive(&mut self, buffer: &mut MsgBuffer) -> Result<SocketAddr, io::Error> {
+        buffer.clear();
+        let size = self.receiver.recv(buffer.buffer())?;
+        if size < ADDR_LEN {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Truncated packet from dispatcher"));
+        }
+        let addr = read_addr(buffer.buffer());
+        buffer.set_start(buffer.get_start() + ADDR_LEN);
+        buffer.set_length(size - ADDR_LEN);
+        Ok(addr)
+    }
+
+    fn send(&mut self, data: &[u8], addr: SocketAddr) -> Result<usize, io::Error> {
+        // HOT PATH
+        self.buffer.clear();
+        self.buffer.extend_from_slice(&self.id.to_be_bytes());
+        self.buffer.extend_from_slice(data);
+        let written = self.socket.send_to(&self.buffer, addr)?;
+        Ok(written.saturating_sub(NETWORK_ID_LEN))
+    }
+
+    fn address(&self) -> Result<SocketAddr, io::Error> {
+        let mut addr = self.socket.local_addr()?;
+        addr.set_ip(get_ip());
+        Ok(addr)
+    }
+
+    fn addresses(&self) -> Result<SmallVec<[SocketAddr; 2]>, io::Error> {
+        self.socket.addresses()
+    }
+
+    fn create_port_forwarding(&self, description: &str) -> Option<PortForwarding> {
+        PortForwarding::new(self.socket.local_addr().ok()?.port(), description)
     }
 }
 
@@ -136,7 +512,7 @@ impl AsRawFd for MockSocket {
 
 impl Socket for MockSocket {
     fn listen(addr: &str) -> Result<Self, io::Error> {
-        Ok(Self::new(parse_listen(addr, DEFAULT_PORT)))
+        Ok(Self::new(parse_listen(addr, DEFAULT_PORT)?))
     }
 
     fn receive(&mut self, buffer: &mut MsgBuffer) -> Result<SocketAddr, io::Error> {
@@ -162,7 +538,7 @@ impl Socket for MockSocket {
         Ok(self.address)
     }
 
-    fn create_port_forwarding(&self) -> Option<PortForwarding> {
+    fn create_port_forwarding(&self, _description: &str) -> Option<PortForwarding> {
         None
     }
 }
@@ -181,3 +557,79 @@ mod bench {
         b.bytes = 1400;
     }
 }
+
+#[test]
+fn local_addr() {
+    for addr in &["192.168.1.2:3210", "10.1.2.3:3210", "[::ffff:172.16.1.1]:3210", "[fd00::1]:3210", "[fe80::1]:3210"] {
+        assert!(is_local_addr(&addr.parse().unwrap()), "{}", addr);
+    }
+    for addr in &["1.2.3.4:3210", "[::ffff:8.8.8.8]:3210", "[2001:db8::1]:3210", "[::1]:3210"] {
+        assert!(!is_local_addr(&addr.parse().unwrap()), "{}", addr);
+    }
+}
+
+#[test]
+fn ipv4_addr() {
+    for addr in &["1.2.3.4:3210", "[::ffff:1.2.3.4]:3210"] {
+        assert!(is_ipv4_addr(&addr.parse().unwrap()), "{}", addr);
+    }
+    for addr in &["[2001:db8::1]:3210", "[::1]:3210", "[::]:3210"] {
+        assert!(!is_ipv4_addr(&addr.parse().unwrap()), "{}", addr);
+    }
+}
+
+#[test]
+fn socket_addresses() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    assert_eq!(socket.addresses().unwrap().as_slice(), &[socket.local_addr().unwrap()]);
+    // Sockets bound to 0.0.0.0 only have IPv4 addresses
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    assert!(socket.addresses().unwrap().iter().all(is_ipv4_addr));
+}
+
+#[test]
+fn network_dispatcher() {
+    use std::thread;
+    let mut dispatcher = NetworkDispatcher::new(UdpSocket::bind("[::]:0").unwrap());
+    let port = dispatcher.socket.local_addr().unwrap().port();
+    let mut net1 = dispatcher.add_network(network_id("net1")).unwrap();
+    let mut net2 = dispatcher.add_network(network_id("net2")).unwrap();
+    assert!(dispatcher.add_network(network_id("net1")).is_err());
+    thread::spawn(move || dispatcher.run());
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let peer_addr = mapped_addr(peer.local_addr().unwrap());
+    let local = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), port);
+    // Packets arrive only at the network with the matching id
+    let mut packet = network_id("net2").to_be_bytes().to_vec();
+    packet.extend_from_slice(&[1, 2, 3]);
+    peer.send_to(&packet, local).unwrap();
+    let mut buffer = MsgBuffer::new(16);
+    assert_eq!(net2.receive(&mut buffer).unwrap(), peer_addr);
+    assert_eq!(buffer.message(), &[1, 2, 3]);
+    net1.receiver.set_nonblocking(true).unwrap();
+    assert!(net1.receive(&mut buffer).is_err());
+    // Sent packets carry the id of the network
+    assert_eq!(net1.send(&[4, 5], peer_addr).unwrap(), 2);
+    let mut data = [0; 16];
+    let (size, _) = peer.recv_from(&mut data).unwrap();
+    assert_eq!(&data[..size], &[&network_id("net1").to_be_bytes()[..], &[4, 5]].concat()[..]);
+}
+
+#[test]
+fn receive_timestamps() {
+    let mut socket = UdpSocket::listen("127.0.0.1:0").unwrap();
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let before = Instant::now();
+    peer.send_to(&[1, 2, 3], socket.local_addr().unwrap()).unwrap();
+    let mut buffer = MsgBuffer::new(16);
+    let (addr, time) = socket.receive_timestamped(&mut buffer).unwrap();
+    assert_eq!(addr, peer.local_addr().unwrap());
+    assert_eq!(buffer.message(), &[1, 2, 3]);
+    // Not all kernels (e.g. in sandboxes) support the timestamps
+    if let Some(time) = time {
+        assert!(time <= Instant::now() && time + MAX_TIMESTAMP_AGE >= before);
+    }
+    assert!(timestamp_to_instant(libc::timespec { tv_sec: 0, tv_nsec: 0 }).is_none());
+    assert!(timestamp_to_instant(libc::timespec { tv_sec: -1, tv_nsec: 0 }).is_none());
+}
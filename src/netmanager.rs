@@ -0,0 +1,187 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Integration with the network manager of the system
+//!
+//! With systemd-networkd, the configuration of the interface (addresses, routes and DNS) is
+//! delegated to networkd via a generated `.network` file. With NetworkManager, the interface is
+//! marked as unmanaged so that NetworkManager does not remove the configuration done by VpnCloud.
+
+use std::{
+    fmt::Write as FmtWrite,
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::error::Error;
+
+pub const NETWORKD_DIR: &str = "/run/systemd/network";
+pub const NETWORKMANAGER_DIR: &str = "/run/NetworkManager/conf.d";
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Manager {
+    Networkd,
+    NetworkManager,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    pub manager: Manager,
+    /// Networks that are routed via the interface
+    #[serde(default)]
+    pub routes: Vec<String>,
+    /// DNS servers that are reachable via the interface
+    #[serde(default)]
+    pub dns: Vec<String>,
+    /// DNS search domains that are resolved via the interface
+    #[serde(default)]
+    pub domains: Vec<String>,
+}
+
+impl Config {
+    /// Whether the addresses of the interface are configured by the network manager
+    pub fn configures_addresses(&self) -> bool {
+        self.manager == Manager::Networkd
+    }
+
+    /// Directory in which the generated configuration is placed
+    pub fn dir(&self) -> &'static str {
+        match self.manager {
+            Manager::Networkd => NETWORKD_DIR,
+            Manager::NetworkManager => NETWORKMANAGER_DIR,
+        }
+    }
+
+    fn path(&self, ifname: &str) -> PathBuf {
+        match self.manager {
+            Manager::Networkd => Path::new(NETWORKD_DIR).join(format!("50-vpncloud-{}.network", ifname)),
+            Manager::NetworkManager => Path::new(NETWORKMANAGER_DIR).join(format!("90-vpncloud-{}.conf", ifname)),
+        }
+    }
+}
+
+fn networkd_unit(config: &Config, ifname: &str, address: Option<&str>) -> String {
+    let mut unit = String::new();
+    writeln!(unit, "# Generated by VpnCloud, do not edit").unwrap();
+    writeln!(unit, "[Match]\nName={}\n", ifname).unwrap();
+    writeln!(unit, "[Link]\nRequiredForOnline=no\n").unwrap();
+    writeln!(unit, "[Network]\nConfigureWithoutCarrier=yes\nLinkLocalAddressing=no\nIPv6AcceptRA=no").unwrap();
+    if let Some(address) = address {
+        if address.contains('/') {
+            writeln!(unit, "Address={}", address).unwrap();
+        } else {
+            writeln!(unit, "Address={}/24", address).unwrap();
+        }
+    }
+    for dns in &config.dns {
+        writeln!(unit, "DNS={}", dns).unwrap();
+    }
+    if !config.domains.is_empty() {
+        // Prefixing the domains with ~ routes the queries to the DNS servers of this interface
+        let domains: Vec<_> = config.domains.iter().map(|d| format!("~{}", d.trim_start_matches('~'))).collect();
+        writeln!(unit, "Domains={}", domains.join(" ")).unwrap();
+    }
+    for route in &config.routes {
+        writeln!(unit, "\n[Route]\nDestination={}", route).unwrap();
+    }
+    unit
+}
+
+fn networkmanager_conf(ifname: &str) -> String {
+    format!("# Generated by VpnCloud, do not edit\n[keyfile]\nunmanaged-devices=interface-name:{}\n", ifname)
+}
+
+fn run_command(cmd: &str, args: &[&str]) -> Result<(), Error> {
+    debug!("Running {} {}", cmd, args.join(" "));
+    let status =
+        process::Command::new(cmd).args(args).status().map_err(|e| Error::DeviceIo("Failed to run command", e))?;
+    if !status.success() {
+        error!("Command {} {} returned with error: {:?}", cmd, args.join(" "), status.code());
+        return Err(Error::Device("Failed to configure network manager"));
+    }
+    Ok(())
+}
+
+fn reload(config: &Config, ifname: Option<&str>) -> Result<(), Error> {
+    match config.manager {
+        Manager::Networkd => {
+            run_command("networkctl", &["reload"])?;
+            if let Some(ifname) = ifname {
+                run_command("networkctl", &["reconfigure", ifname])?;
+            }
+            Ok(())
+        }
+        Manager::NetworkManager => run_command("nmcli", &["general", "reload", "conf"]),
+    }
+}
+
+/// Hands the interface over to the network manager
+///
+/// With networkd, `address` is configured by networkd, otherwise it has to be configured before.
+pub fn setup(config: &Config, ifname: &str, address: Option<&str>) -> Result<(), Error> {
+    let path = config.path(ifname);
+    let content = match config.manager {
+        Manager::Networkd => networkd_unit(config, ifname, address),
+        Manager::NetworkManager => networkmanager_conf(ifname),
+    };
+    fs::create_dir_all(config.dir()).map_err(|e| Error::FileIo("Failed to create config directory", e))?;
+    fs::write(&path, content).map_err(|e| Error::FileIo("Failed to write network config", e))?;
+    info!("Wrote network config to {}", path.display());
+    reload(config, Some(ifname))?;
+    if config.manager == Manager::NetworkManager {
+        for route in &config.routes {
+            run_command("ip", &["route", "replace", route, "dev", ifname])?;
+        }
+        if !config.dns.is_empty() {
+            let mut args = vec!["dns", ifname];
+            args.extend(config.dns.iter().map(|s| s as &str));
+            run_command("resolvectl", &args)?;
+        }
+        if !config.domains.is_empty() {
+            let domains: Vec<_> = config.domains.iter().map(|d| format!("~{}", d.trim_start_matches('~'))).collect();
+            let mut args = vec!["domain", ifname];
+            args.extend(domains.iter().map(|s| s as &str));
+            run_command("resolvectl", &args)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the generated configuration again
+pub fn teardown(config: &Config, ifname: &str) -> Result<(), Error> {
+    let path = config.path(ifname);
+    if path.exists() {
+        fs::remove_file(&path).map_err(|e| Error::FileIo("Failed to remove network config", e))?;
+        reload(config, None)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn networkd_config() {
+    let config = Config {
+        manager: Manager::Networkd,
+        routes: vec!["10.1.0.0/16".to_string()],
+        dns: vec!["10.0.0.1".to_string()],
+        domains: vec!["vpn.example.com".to_string()],
+    };
+    let unit = networkd_unit(&config, "vpncloud0", Some("10.0.0.2"));
+    assert!(unit.contains("[Match]\nName=vpncloud0\n"));
+    assert!(unit.contains("Address=10.0.0.2/24\n"));
+    assert!(unit.contains("DNS=10.0.0.1\n"));
+    assert!(unit.contains("Domains=~vpn.example.com\n"));
+    assert!(unit.contains("[Route]\nDestination=10.1.0.0/16\n"));
+    assert!(!networkd_unit(&config, "vpncloud0", None).contains("Address="));
+    assert_eq!(config.path("vpncloud0"), Path::new("/run/systemd/network/50-vpncloud-vpncloud0.network"));
+}
+
+#[test]
+fn networkmanager_config() {
+    let config = Config { manager: Manager::NetworkManager, routes: vec![], dns: vec![], domains: vec![] };
+    assert!(!config.configures_addresses());
+    assert!(networkmanager_conf("vpncloud0").contains("unmanaged-devices=interface-name:vpncloud0\n"));
+}
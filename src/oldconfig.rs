@@ -3,7 +3,7 @@
 // This software is licensed under GPL-3 or newer (see LICENSE.md)
 
 use super::{device::Type, types::Mode, util::Duration};
-use crate::config::{ConfigFile, ConfigFileBeacon, ConfigFileDevice, ConfigFileStatsd, CryptoConfig};
+use crate::config::{ConfigFile, ConfigFileBeacon, ConfigFileDevice, ConfigFilePeer, ConfigFileStatsd, CryptoConfig};
 use std::collections::HashMap;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
@@ -85,6 +85,9 @@ impl OldConfigFile {
         warn!("Even with a converted config file version 2 nodes can not communicate with version 1 nodes");
         ConfigFile {
             auto_claim: None,
+            defer_claims: None,
+            summarize_claims: None,
+            source_validation: None,
             beacon: Some(ConfigFileBeacon {
                 interval: self.beacon_interval,
                 load: self.beacon_load,
@@ -98,31 +101,84 @@ impl OldConfigFile {
                 private_key: None,
                 public_key: None,
                 trusted_keys: vec![],
+                secondary_key: None,
+                network_secret: None,
+                peer_algorithms: HashMap::new(),
+                peer_schedules: HashMap::new(),
+                padding: false,
             },
+            ethertypes: None,
+            arp_proxy: None,
+            firewall: None,
+            nat: None,
+            claim_filters: None,
+            accept_claims: None,
+            budget: None,
+            control_limit: None,
+            bans: None,
+            duplication: None,
+            fec: None,
+            radius: None,
+            services: None,
+            node_name: None,
+            dns: None,
+            dhcp: None,
+            ipam: None,
+            capture: None,
+            docker: None,
+            vxlan: None,
+            hardening: None,
             device: Some(ConfigFileDevice {
                 fix_rp_filter: None,
+                ip_forwarding: None,
+                mtu: None,
+                derive_mac: None,
                 name: self.device_name,
                 path: self.device_path,
                 type_: self.device_type,
             }),
             group: self.group,
             ifdown: self.ifdown,
+            network_manager: None,
             ifup: self.ifup,
             ip: None,
+            derive_ip: None,
             advertise_addresses: None,
+            advertise_policy: None,
             keepalive: self.keepalive,
+            fast_failover: None,
+            suppress_keepalives: None,
+            idle_timeout: None,
+            wait_for_network: None,
             listen: self.listen.or(self.port.map(|p| format!("{}", p))),
+            network_id: None,
+            networks: None,
             mode: self.mode,
+            observer: None,
+            topology: None,
+            duplicate_identity: None,
             peer_timeout: self.peer_timeout,
-            peers: self.peers,
+            peer_exchange: None,
+            peers: self.peers.map(|peers| peers.into_iter().map(ConfigFilePeer::Address).collect()),
             pid_file: self.pid_file,
             port_forwarding: self.port_forwarding,
             stats_file: self.stats_file,
+            time_format: None,
+            utc: None,
+            state_dir: None,
+            control_socket: None,
+            remote_control: None,
             statsd: Some(ConfigFileStatsd { prefix: self.statsd_prefix, server: self.statsd_server }),
+            prometheus_file: None,
+            influxdb: None,
+            performance: None,
+            limits: None,
             switch_timeout: self.dst_timeout,
+            static_macs: HashMap::new(),
             user: self.user,
             hook: None,
             hooks: HashMap::new(),
+            auth_hook: None,
         }
     }
 }
@@ -5,8 +5,90 @@
 use crate::{error::Error, types::Address};
 use std::io::{Cursor, Read};
 
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+pub const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const PROTO_ICMP: u8 = 1;
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+const PROTO_ICMPV6: u8 = 58;
+
+/// Differentiated services code point "expedited forwarding" as used for VoIP
+const DSCP_EF: u8 = 46;
+
+/// Larger packets are never considered latency-sensitive
+pub const LATENCY_SENSITIVE_MAX_SIZE: usize = 256;
+
 pub trait Protocol: Sized {
     fn parse(_: &[u8]) -> Result<(Address, Address), Error>;
+
+    /// Returns the ethertype of the payload if the protocol has one
+    fn ethertype(_: &[u8]) -> Option<u16> {
+        None
+    }
+
+    /// Returns whether the payload is a small latency-sensitive packet that should be sent before bulk data
+    fn is_latency_sensitive(_: &[u8]) -> bool {
+        false
+    }
+
+    /// Returns the DSCP value of the payload if it is an IP packet
+    fn dscp(_: &[u8]) -> Option<u8> {
+        None
+    }
+}
+
+/// Extracts the DSCP value from the header of an IPv4 or IPv6 packet
+fn ip_dscp(data: &[u8]) -> Option<u8> {
+    match data.first().map(|b| b >> 4) {
+        Some(4) if data.len() >= 20 => Some(data[1] >> 2),
+        Some(6) if data.len() >= 40 => Some((((data[0] & 0x0f) << 4) | (data[1] >> 4)) >> 2),
+        _ => None,
+    }
+}
+
+/// Classifies small IP packets: ICMP, UDP (e.g. DNS and VoIP), TCP without data (e.g. ACKs) and anything marked
+/// with DSCP EF
+fn is_latency_sensitive_ip(data: &[u8]) -> bool {
+    if data.len() > LATENCY_SENSITIVE_MAX_SIZE {
+        return false;
+    }
+    let (dscp, protocol, payload) = match data.first().map(|b| b >> 4) {
+        Some(4) if data.len() >= 20 => {
+            let header_len = (data[0] & 0x0f) as usize * 4;
+            if header_len < 20 || data.len() < header_len {
+                return false;
+            }
+            (data[1] >> 2, data[9], &data[header_len..])
+        }
+        Some(6) if data.len() >= 40 => ((((data[0] & 0x0f) << 4) | (data[1] >> 4)) >> 2, data[6], &data[40..]),
+        _ => return false,
+    };
+    match protocol {
+        _ if dscp == DSCP_EF => true,
+        PROTO_ICMP | PROTO_ICMPV6 | PROTO_UDP => true,
+        PROTO_TCP if payload.len() >= 20 => payload.len() <= (payload[12] >> 4) as usize * 4,
+        _ => false,
+    }
+}
+
+/// Parses an ethertype given by name ("ipv4", "ipv6", "arp") or number (e.g. "0x0800")
+pub fn parse_ethertype(value: &str) -> Result<u16, Error> {
+    match &value.to_lowercase() as &str {
+        "ipv4" | "ip" => Ok(ETHERTYPE_IPV4),
+        "ipv6" => Ok(ETHERTYPE_IPV6),
+        "arp" => Ok(ETHERTYPE_ARP),
+        "vlan" => Ok(ETHERTYPE_VLAN),
+        val => {
+            let res = match val.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16),
+                None => val.parse::<u16>(),
+            };
+            res.map_err(|_| Error::InvalidConfig("Invalid ethertype"))
+        }
+    }
 }
 
 /// An ethernet frame dissector
@@ -50,6 +132,45 @@ impl Protocol for Frame {
             Ok((Address { data: src, len: 6 }, Address { data: dst, len: 6 }))
         }
     }
+
+    /// Returns the ethertype of the frame, skipping a VLAN tag if present
+    fn ethertype(data: &[u8]) -> Option<u16> {
+        // HOT PATH
+        if data.len() < 14 {
+            return None;
+        }
+        let ethertype = u16::from_be_bytes([data[12], data[13]]);
+        if ethertype == ETHERTYPE_VLAN {
+            if data.len() < 18 {
+                return None;
+            }
+            return Some(u16::from_be_bytes([data[16], data[17]]));
+        }
+        Some(ethertype)
+    }
+
+    fn is_latency_sensitive(data: &[u8]) -> bool {
+        let ethertype = match Self::ethertype(data) {
+            Some(ethertype) => ethertype,
+            None => return false,
+        };
+        let offset = if u16::from_be_bytes([data[12], data[13]]) == ETHERTYPE_VLAN { 18 } else { 14 };
+        match ethertype {
+            ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => is_latency_sensitive_ip(&data[offset..]),
+            // ARP is small and needed to start any communication
+            ETHERTYPE_ARP => true,
+            _ => false,
+        }
+    }
+
+    fn dscp(data: &[u8]) -> Option<u8> {
+        let offset = match Self::ethertype(data)? {
+            ETHERTYPE_IPV4 | ETHERTYPE_IPV6 if u16::from_be_bytes([data[12], data[13]]) == ETHERTYPE_VLAN => 18,
+            ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => 14,
+            _ => return None,
+        };
+        ip_dscp(&data[offset..])
+    }
 }
 
 #[test]
@@ -77,6 +198,27 @@ fn decode_invalid_frame() {
     assert!(Frame::parse(&[6, 5, 4, 3, 2, 1, 1, 2, 3, 4, 5, 6, 0x81, 0x00]).is_err());
 }
 
+#[test]
+fn frame_ethertype() {
+    let data = [6, 5, 4, 3, 2, 1, 1, 2, 3, 4, 5, 6, 0x08, 0x06, 1, 2, 3, 4];
+    assert_eq!(Some(ETHERTYPE_ARP), Frame::ethertype(&data));
+    let data = [6, 5, 4, 3, 2, 1, 1, 2, 3, 4, 5, 6, 0x81, 0, 4, 210, 0x86, 0xdd, 1, 2];
+    assert_eq!(Some(ETHERTYPE_IPV6), Frame::ethertype(&data));
+    assert_eq!(None, Frame::ethertype(&[6, 5, 4, 3, 2, 1, 1, 2, 3, 4, 5, 6, 0x81, 0, 4, 210]));
+    assert_eq!(None, Packet::ethertype(&data));
+}
+
+#[test]
+fn parse_ethertypes() {
+    assert_eq!(ETHERTYPE_IPV4, parse_ethertype("ipv4").unwrap());
+    assert_eq!(ETHERTYPE_IPV6, parse_ethertype("IPv6").unwrap());
+    assert_eq!(ETHERTYPE_ARP, parse_ethertype("arp").unwrap());
+    assert_eq!(0x88cc, parse_ethertype("0x88cc").unwrap());
+    assert_eq!(2048, parse_ethertype("2048").unwrap());
+    assert!(parse_ethertype("lldp").is_err());
+    assert!(parse_ethertype("0x12345").is_err());
+}
+
 /// An IP packet dissector
 ///
 /// This dissector is able to extract the source and destination ip addresses of ipv4 packets and
@@ -115,6 +257,14 @@ impl Protocol for Packet {
             _ => Err(Error::Parse("Invalid IP protocol version")),
         }
     }
+
+    fn is_latency_sensitive(data: &[u8]) -> bool {
+        is_latency_sensitive_ip(data)
+    }
+
+    fn dscp(data: &[u8]) -> Option<u8> {
+        ip_dscp(data)
+    }
 }
 
 #[test]
@@ -157,3 +307,128 @@ fn decode_invalid_packet() {
     ])
     .is_err());
 }
+
+#[test]
+fn latency_sensitive_packets() {
+    let ipv4 = |protocol: u8, tos: u8, payload: &[u8]| {
+        let mut data = vec![0x45, tos, 0, 0, 0, 0, 0, 0, 64, protocol, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2];
+        data.extend_from_slice(payload);
+        data
+    };
+    let mut tcp_ack = [0; 20];
+    tcp_ack[12] = 0x50;
+    assert!(Packet::is_latency_sensitive(&ipv4(PROTO_TCP, 0, &tcp_ack)));
+    assert!(Packet::is_latency_sensitive(&ipv4(PROTO_UDP, 0, &[0; 40])));
+    assert!(Packet::is_latency_sensitive(&ipv4(PROTO_ICMP, 0, &[0; 8])));
+    // TCP with data, unless marked as expedited forwarding
+    assert!(!Packet::is_latency_sensitive(&ipv4(PROTO_TCP, 0, &[tcp_ack, [0; 20]].concat())));
+    assert!(Packet::is_latency_sensitive(&ipv4(PROTO_TCP, DSCP_EF << 2, &[tcp_ack, [0; 20]].concat())));
+    // Large packets
+    assert!(!Packet::is_latency_sensitive(&ipv4(PROTO_UDP, 0, &[0; LATENCY_SENSITIVE_MAX_SIZE])));
+    let mut ipv6 = vec![0x60, 0, 0, 0, 0, 0, PROTO_UDP, 64];
+    ipv6.extend_from_slice(&[0; 40]);
+    assert!(Packet::is_latency_sensitive(&ipv6));
+    ipv6[6] = PROTO_TCP;
+    assert!(!Packet::is_latency_sensitive(&ipv6));
+    assert!(!Packet::is_latency_sensitive(&[]));
+    // Frames with and without VLAN tag
+    let mut frame = vec![6, 5, 4, 3, 2, 1, 1, 2, 3, 4, 5, 6, 0x08, 0x00];
+    frame.extend_from_slice(&ipv4(PROTO_UDP, 0, &[0; 40]));
+    assert!(Frame::is_latency_sensitive(&frame));
+    let mut frame = vec![6, 5, 4, 3, 2, 1, 1, 2, 3, 4, 5, 6, 0x81, 0x00, 0, 1, 0x08, 0x00];
+    frame.extend_from_slice(&ipv4(PROTO_TCP, 0, &[tcp_ack, [0; 20]].concat()));
+    assert!(!Frame::is_latency_sensitive(&frame));
+    assert!(Frame::is_latency_sensitive(&[6, 5, 4, 3, 2, 1, 1, 2, 3, 4, 5, 6, 0x08, 0x06, 0, 1]));
+}
+
+#[test]
+fn dscp_values() {
+    let mut ipv4 = vec![0x45, DSCP_EF << 2, 0, 0, 0, 0, 0, 0, 64, PROTO_UDP, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2];
+    assert_eq!(Some(DSCP_EF), Packet::dscp(&ipv4));
+    let mut ipv6 = vec![0x60 | (DSCP_EF >> 2), (DSCP_EF & 0x03) << 6, 0, 0, 0, 0, PROTO_UDP, 64];
+    ipv6.extend_from_slice(&[0; 32]);
+    assert_eq!(Some(DSCP_EF), Packet::dscp(&ipv6));
+    assert_eq!(None, Packet::dscp(&ipv6[..20]));
+    let mut frame = vec![6, 5, 4, 3, 2, 1, 1, 2, 3, 4, 5, 6, 0x81, 0x00, 0, 1, 0x08, 0x00];
+    frame.append(&mut ipv4);
+    assert_eq!(Some(DSCP_EF), Frame::dscp(&frame));
+    assert_eq!(None, Frame::dscp(&[6, 5, 4, 3, 2, 1, 1, 2, 3, 4, 5, 6, 0x08, 0x06, 0, 1]));
+}
+
+/// Length of the VXLAN header in front of the ethernet frame
+pub const VXLAN_HEADER_LEN: usize = 8;
+
+/// Flag that marks the VXLAN network identifier as valid
+const VXLAN_FLAG_VNI: u8 = 0x08;
+
+/// A VXLAN packet dissector
+///
+/// This dissector handles VXLAN packets (RFC 7348) including their 8-byte header, so the packets of existing VXLAN
+/// setups can be carried without decapsulating them on the way.
+///
+/// The addresses of the inner ethernet frame are prefixed with the 3-byte VXLAN network identifier, so the same MAC
+/// address can be used in several VXLAN networks.
+pub struct Vxlan;
+
+impl Protocol for Vxlan {
+    /// Parses a VXLAN packet and extracts the addresses of the inner ethernet frame
+    ///
+    /// # Errors
+    /// This method will fail when the given data is not a VXLAN packet with a valid ethernet frame.
+    fn parse(data: &[u8]) -> Result<(Address, Address), Error> {
+        // HOT PATH
+        if data.len() < VXLAN_HEADER_LEN {
+            return Err(Error::Parse("VXLAN header is too short"));
+        }
+        if data[0] & VXLAN_FLAG_VNI == 0 {
+            return Err(Error::Parse("VXLAN packet without network identifier"));
+        }
+        let (inner_src, inner_dst) = Frame::parse(&data[VXLAN_HEADER_LEN..])?;
+        let mut src = Address { data: [0; 16], len: 3 + inner_src.len };
+        let mut dst = Address { data: [0; 16], len: 3 + inner_dst.len };
+        src.data[..3].copy_from_slice(&data[4..7]);
+        dst.data[..3].copy_from_slice(&data[4..7]);
+        src.data[3..src.len as usize].copy_from_slice(&inner_src.data[..inner_src.len as usize]);
+        dst.data[3..dst.len as usize].copy_from_slice(&inner_dst.data[..inner_dst.len as usize]);
+        Ok((src, dst))
+    }
+
+    fn ethertype(data: &[u8]) -> Option<u16> {
+        Frame::ethertype(data.get(VXLAN_HEADER_LEN..)?)
+    }
+
+    fn is_latency_sensitive(data: &[u8]) -> bool {
+        data.len() > VXLAN_HEADER_LEN && Frame::is_latency_sensitive(&data[VXLAN_HEADER_LEN..])
+    }
+
+    fn dscp(data: &[u8]) -> Option<u8> {
+        Frame::dscp(data.get(VXLAN_HEADER_LEN..)?)
+    }
+}
+
+#[test]
+fn decode_vxlan_packet() {
+    let data = [0x08, 0, 0, 0, 0, 0, 42, 0, 6, 5, 4, 3, 2, 1, 1, 2, 3, 4, 5, 6, 0x08, 0x06, 1, 2, 3, 4];
+    let (src, dst) = Vxlan::parse(&data).unwrap();
+    assert_eq!(src, Address { data: [0, 0, 42, 1, 2, 3, 4, 5, 6, 0, 0, 0, 0, 0, 0, 0], len: 9 });
+    assert_eq!(dst, Address { data: [0, 0, 42, 6, 5, 4, 3, 2, 1, 0, 0, 0, 0, 0, 0, 0], len: 9 });
+    assert_eq!(Some(ETHERTYPE_ARP), Vxlan::ethertype(&data));
+    assert!(Vxlan::is_latency_sensitive(&data));
+    // Inner frame with VLAN tag
+    let data = [0x08, 0, 0, 0, 0, 0, 42, 0, 6, 5, 4, 3, 2, 1, 1, 2, 3, 4, 5, 6, 0x81, 0, 4, 210, 0x08, 0x00];
+    let (src, _) = Vxlan::parse(&data).unwrap();
+    assert_eq!(src, Address { data: [0, 0, 42, 4, 210, 1, 2, 3, 4, 5, 6, 0, 0, 0, 0, 0], len: 11 });
+    assert_eq!(Some(ETHERTYPE_IPV4), Vxlan::ethertype(&data));
+}
+
+#[test]
+fn decode_invalid_vxlan_packet() {
+    // truncated header
+    assert!(Vxlan::parse(&[0x08, 0, 0, 0, 0, 0, 42]).is_err());
+    assert_eq!(None, Vxlan::ethertype(&[0x08, 0, 0, 0, 0, 0, 42]));
+    assert!(!Vxlan::is_latency_sensitive(&[0x08, 0, 0, 0, 0, 0, 42]));
+    // network identifier not flagged as valid
+    assert!(Vxlan::parse(&[0, 0, 0, 0, 0, 0, 42, 0, 6, 5, 4, 3, 2, 1, 1, 2, 3, 4, 5, 6, 0x08, 0x06]).is_err());
+    // truncated inner frame
+    assert!(Vxlan::parse(&[0x08, 0, 0, 0, 0, 0, 42, 0, 6, 5, 4, 3, 2, 1]).is_err());
+}
@@ -0,0 +1,217 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Explicit connection states of the peers
+//!
+//! Every address that a connection is set up with moves through the states `init`, `handshake-pending`,
+//! `established`, `degraded` and `closing`. Each transition is logged and the current state of every address is
+//! kept together with the time it was entered, so that reconnect problems can be followed in the log, the stats
+//! file and via `vpncloud peers` on the control socket. Closed connections are kept for a while so that flapping
+//! peers remain visible.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    marker::PhantomData,
+    net::SocketAddr,
+};
+
+use crate::{
+    cloud::Hash,
+    util::{addr_nice, Time, TimeSource},
+};
+
+/// Closed connections are forgotten after this time
+const CLOSED_TIMEOUT: Time = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerState {
+    /// The handshake is being prepared
+    Init,
+    /// The handshake has been started but not completed yet
+    HandshakePending,
+    /// The connection is working
+    Established,
+    /// The peer did not answer recently and is being probed
+    Degraded,
+    /// The connection has been closed or the handshake failed
+    Closing,
+}
+
+impl PeerState {
+    /// Whether the state machine allows to go from this state to the other one
+    pub fn can_change_to(self, other: PeerState) -> bool {
+        use PeerState::*;
+        matches!(
+            (self, other),
+            (Init, HandshakePending)
+                | (Init, Closing)
+                | (HandshakePending, Established)
+                | (HandshakePending, Closing)
+                | (Established, Degraded)
+                | (Established, Closing)
+                | (Degraded, Established)
+                | (Degraded, Closing)
+                // The peer restarted and sent a new handshake
+                | (Established, HandshakePending)
+                | (Degraded, HandshakePending)
+                | (Closing, Init)
+                | (Closing, HandshakePending)
+        )
+    }
+}
+
+impl fmt::Display for PeerState {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let name = match self {
+            PeerState::Init => "init",
+            PeerState::HandshakePending => "handshake-pending",
+            PeerState::Established => "established",
+            PeerState::Degraded => "degraded",
+            PeerState::Closing => "closing",
+        };
+        write!(formatter, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStateEntry {
+    pub state: PeerState,
+    /// Time at which the state was entered
+    pub since: Time,
+    /// Number of times the connection has been established
+    pub established: u32,
+}
+
+pub struct PeerStateTable<TS: TimeSource> {
+    entries: HashMap<SocketAddr, PeerStateEntry, Hash>,
+    _dummy: PhantomData<TS>,
+}
+
+impl<TS: TimeSource> PeerStateTable<TS> {
+    pub fn new() -> Self {
+        Self { entries: HashMap::default(), _dummy: PhantomData }
+    }
+
+    /// Moves the connection with the address into the given state
+    ///
+    /// Unexpected transitions are applied anyway but logged as warnings since they hint at a bug.
+    pub fn change(&mut self, addr: SocketAddr, state: PeerState) {
+        let now = TS::now();
+        match self.entries.get_mut(&addr) {
+            Some(entry) if entry.state == state => (),
+            Some(entry) => {
+                if entry.state.can_change_to(state) {
+                    debug!(
+                        "Peer {} changed from {} to {} after {} seconds",
+                        addr_nice(addr),
+                        entry.state,
+                        state,
+                        now - entry.since
+                    );
+                } else {
+                    warn!("Unexpected state change of peer {} from {} to {}", addr_nice(addr), entry.state, state);
+                }
+                entry.state = state;
+                entry.since = now;
+                if state == PeerState::Established {
+                    entry.established += 1;
+                }
+            }
+            None => {
+                debug!("Peer {} starts in {}", addr_nice(addr), state);
+                let established = if state == PeerState::Established { 1 } else { 0 };
+                self.entries.insert(addr, PeerStateEntry { state, since: now, established });
+            }
+        }
+    }
+
+    /// Moves the connection into the given state if it is currently in the expected one
+    pub fn change_from(&mut self, addr: SocketAddr, expected: PeerState, state: PeerState) {
+        if self.state(&addr) == Some(expected) {
+            self.change(addr, state)
+        }
+    }
+
+    /// Closes the connection with the address if it is tracked
+    pub fn close(&mut self, addr: SocketAddr) {
+        if self.entries.contains_key(&addr) {
+            self.change(addr, PeerState::Closing)
+        }
+    }
+
+    pub fn state(&self, addr: &SocketAddr) -> Option<PeerState> {
+        self.entries.get(addr).map(|e| e.state)
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&PeerStateEntry> {
+        self.entries.get(addr)
+    }
+
+    pub fn count(&self, state: PeerState) -> usize {
+        self.entries.values().filter(|e| e.state == state).count()
+    }
+
+    /// Forgets connections that have been closed long ago
+    pub fn housekeep(&mut self) {
+        let now = TS::now();
+        self.entries.retain(|_, e| e.state != PeerState::Closing || e.since + CLOSED_TIMEOUT > now);
+    }
+
+    /// Lists the connections with their state and the number of seconds they are in it
+    pub fn write_out<W: Write>(&self, out: &mut W) -> Result<(), io::Error> {
+        let now = TS::now();
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_unstable_by_key(|(addr, _)| **addr);
+        for (addr, entry) in entries {
+            writeln!(out, "{} {} {} {}", addr_nice(*addr), entry.state, now - entry.since, entry.established)?;
+        }
+        Ok(())
+    }
+}
+
+impl<TS: TimeSource> Default for PeerStateTable<TS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn peer_state_transitions() {
+    use crate::util::MockTimeSource;
+    let mut table = PeerStateTable::<MockTimeSource>::new();
+    let addr = "1.2.3.4:3210".parse().unwrap();
+    MockTimeSource::set_time(100);
+    table.change(addr, PeerState::Init);
+    table.change(addr, PeerState::HandshakePending);
+    MockTimeSource::set_time(102);
+    table.change(addr, PeerState::Established);
+    assert_eq!(table.get(&addr), Some(&PeerStateEntry { state: PeerState::Established, since: 102, established: 1 }));
+    // Only degraded peers recover
+    table.change_from(addr, PeerState::Degraded, PeerState::Established);
+    assert_eq!(table.get(&addr).unwrap().since, 102);
+    table.change(addr, PeerState::Degraded);
+    table.change_from(addr, PeerState::Degraded, PeerState::Established);
+    assert_eq!(table.get(&addr).unwrap().established, 2);
+    assert_eq!(table.count(PeerState::Established), 1);
+    table.close(addr);
+    assert_eq!(table.state(&addr), Some(PeerState::Closing));
+    // Closing untracked addresses does not track them
+    let other = "1.2.3.5:3210".parse().unwrap();
+    table.close(other);
+    assert_eq!(table.state(&other), None);
+    let mut out = vec![];
+    MockTimeSource::set_time(110);
+    table.write_out(&mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "1.2.3.4:3210 closing 8 2\n");
+    // Closed connections are forgotten after a while
+    table.housekeep();
+    assert_eq!(table.state(&addr), Some(PeerState::Closing));
+    MockTimeSource::set_time(102 + CLOSED_TIMEOUT + 10);
+    table.housekeep();
+    assert_eq!(table.state(&addr), None);
+    assert!(PeerState::Closing.can_change_to(PeerState::HandshakePending));
+    assert!(!PeerState::Init.can_change_to(PeerState::Established));
+}
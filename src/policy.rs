@@ -0,0 +1,265 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+use fnv::FnvHasher;
+use std::{collections::HashMap, hash::BuildHasherDefault, net::SocketAddr, str::FromStr};
+
+use crate::{
+    error::Error,
+    types::{Range, RangeList},
+    util::addr_nice,
+};
+
+type Hash = BuildHasherDefault<FnvHasher>;
+
+/// Peer name of the filter that applies to all peers without their own filter
+pub const ANY_PEER: &str = "*";
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct AcceptConfig {
+    /// The name of the trusted keys the rule applies to, or `*` for all other peers
+    pub key: String,
+    /// Prefixes or MAC ranges that the peers may claim
+    pub claims: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct FilterConfig {
+    /// The name of the peer the filter applies to, or `*` for all other peers
+    pub peer: String,
+    /// Prefixes of the claims that are accepted from the peer, all if not set
+    pub import: Option<Vec<String>>,
+    /// Prefixes of the own claims that are advertised to the peer, all if not set
+    pub export: Option<Vec<String>>,
+    /// Whether other peers are advertised to the peer
+    #[serde(default = "default_export_peers")]
+    pub export_peers: bool,
+}
+
+fn default_export_peers() -> bool {
+    true
+}
+
+struct Filter {
+    peer: String,
+    import: Option<RangeList>,
+    export: Option<RangeList>,
+    export_peers: bool,
+}
+
+fn parse_prefixes(values: &Option<Vec<String>>) -> Result<Option<RangeList>, Error> {
+    match values {
+        Some(values) => {
+            let mut prefixes = RangeList::new();
+            for value in values {
+                prefixes.push(Range::from_str(value).map_err(|_| Error::InvalidConfig("Invalid claim filter prefix"))?);
+            }
+            Ok(Some(prefixes))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Whether the claim lies within one of the prefixes
+fn is_within(claim: &Range, prefixes: &[Range]) -> bool {
+    prefixes.iter().any(|p| claim.prefix_len >= p.prefix_len && p.matches(claim.base))
+}
+
+/// Keeps only the claims that lie within one of the prefixes
+fn filter_claims(claims: &[Range], prefixes: &Option<RangeList>) -> RangeList {
+    match prefixes {
+        Some(prefixes) => claims.iter().filter(|claim| is_within(claim, prefixes)).cloned().collect(),
+        None => claims.iter().cloned().collect(),
+    }
+}
+
+/// Filters the claims exchanged with peers
+///
+/// The filter of a peer is selected by the name the peer announces. Peers without a filter of their own and
+/// peers whose name is not yet known use the `*` filter if there is one.
+pub struct ClaimFilters {
+    filters: Vec<Filter>,
+    peers: HashMap<SocketAddr, usize, Hash>,
+    default: Option<usize>,
+}
+
+impl ClaimFilters {
+    pub fn new(configs: &[FilterConfig]) -> Result<Self, Error> {
+        let mut filters = vec![];
+        for config in configs {
+            filters.push(Filter {
+                peer: config.peer.clone(),
+                import: parse_prefixes(&config.import)?,
+                export: parse_prefixes(&config.export)?,
+                export_peers: config.export_peers,
+            });
+        }
+        let default = filters.iter().position(|f| f.peer == ANY_PEER);
+        Ok(Self { filters, peers: HashMap::default(), default })
+    }
+
+    pub fn set_peer(&mut self, addr: SocketAddr, name: Option<&str>) {
+        match self.filters.iter().position(|f| Some(&f.peer as &str) == name) {
+            Some(index) => {
+                self.peers.insert(addr, index);
+            }
+            None => {
+                self.peers.remove(&addr);
+            }
+        }
+    }
+
+    pub fn remove_peer(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+    }
+
+    fn get(&self, addr: &SocketAddr) -> Option<&Filter> {
+        self.peers.get(addr).copied().or(self.default).map(|index| &self.filters[index])
+    }
+
+    /// Whether the peers receive different node infos
+    pub fn has_exports(&self) -> bool {
+        self.filters.iter().any(|f| f.export.is_some() || !f.export_peers)
+    }
+
+    /// Returns the claims of the peer that are accepted
+    pub fn import_claims(&self, addr: &SocketAddr, claims: &[Range]) -> RangeList {
+        match self.get(addr) {
+            Some(filter) => filter_claims(claims, &filter.import),
+            None => claims.iter().cloned().collect(),
+        }
+    }
+
+    /// Returns the own claims that are advertised to the peer
+    pub fn export_claims(&self, addr: &SocketAddr, claims: &[Range]) -> RangeList {
+        match self.get(addr) {
+            Some(filter) => filter_claims(claims, &filter.export),
+            None => claims.iter().cloned().collect(),
+        }
+    }
+
+    /// Whether other peers are advertised to the peer
+    pub fn export_peers(&self, addr: &SocketAddr) -> bool {
+        self.get(addr).map(|f| f.export_peers).unwrap_or(true)
+    }
+}
+
+/// Restricts the claims that peers may make by the name of their trusted key
+///
+/// Unlike claim filters, the rules are selected by the verified key of the peer and not by the name it announces,
+/// so a peer can not pick a more permissive rule. All keys that share a name form a group with the same rule.
+/// Peers without a rule and without a `*` rule may claim anything.
+pub struct ClaimPolicy {
+    rules: Vec<(String, RangeList)>,
+    default: Option<usize>,
+    /// The claims that have been rejected last, to log only changes
+    rejected: HashMap<SocketAddr, RangeList, Hash>,
+}
+
+impl ClaimPolicy {
+    pub fn new(configs: &[AcceptConfig]) -> Result<Self, Error> {
+        let mut rules = vec![];
+        for config in configs {
+            let claims = parse_prefixes(&Some(config.claims.clone()))?.unwrap_or_default();
+            rules.push((config.key.clone(), claims));
+        }
+        let default = rules.iter().position(|(key, _)| key == ANY_PEER);
+        Ok(Self { rules, default, rejected: HashMap::default() })
+    }
+
+    /// Returns the claims of the peer that are allowed, rejected claims are logged
+    pub fn accept_claims(&mut self, addr: SocketAddr, key_name: Option<&str>, claims: &[Range]) -> RangeList {
+        let index = self.rules.iter().position(|(key, _)| Some(key as &str) == key_name).or(self.default);
+        let (name, allowed) = match index {
+            Some(index) => &self.rules[index],
+            None => return claims.iter().cloned().collect(),
+        };
+        let (accepted, rejected): (RangeList, RangeList) =
+            claims.iter().cloned().partition(|claim| is_within(claim, allowed));
+        if rejected.is_empty() {
+            self.rejected.remove(&addr);
+        } else if self.rejected.get(&addr) != Some(&rejected) {
+            warn!("Rejecting claims of peer {} outside of the policy for {}: {:?}", addr_nice(addr), name, rejected);
+            self.rejected.insert(addr, rejected);
+        }
+        accepted
+    }
+
+    pub fn remove_peer(&mut self, addr: &SocketAddr) {
+        self.rejected.remove(addr);
+    }
+}
+
+#[cfg(test)]
+fn ranges(values: &[&str]) -> RangeList {
+    values.iter().map(|v| Range::from_str(v).unwrap()).collect()
+}
+
+#[test]
+fn claim_filters() {
+    let filters = ClaimFilters::new(&[
+        FilterConfig {
+            peer: "spoke1".to_string(),
+            import: Some(vec!["10.1.0.0/16".to_string()]),
+            export: None,
+            export_peers: true,
+        },
+        FilterConfig {
+            peer: ANY_PEER.to_string(),
+            import: None,
+            export: Some(vec!["10.0.0.0/8".to_string()]),
+            export_peers: false,
+        },
+    ]);
+    let mut filters = filters.unwrap();
+    let spoke1 = "1.2.3.4:3210".parse().unwrap();
+    let spoke2 = "1.2.3.5:3210".parse().unwrap();
+    filters.set_peer(spoke1, Some("spoke1"));
+    filters.set_peer(spoke2, Some("spoke2"));
+    let claims = ranges(&["10.1.2.0/24", "10.2.0.0/16", "192.168.0.0/16", "10.0.0.0/7"]);
+    assert_eq!(ranges(&["10.1.2.0/24"]), filters.import_claims(&spoke1, &claims));
+    assert_eq!(claims, filters.export_claims(&spoke1, &claims));
+    assert!(filters.export_peers(&spoke1));
+    assert_eq!(claims, filters.import_claims(&spoke2, &claims));
+    assert_eq!(ranges(&["10.1.2.0/24", "10.2.0.0/16"]), filters.export_claims(&spoke2, &claims));
+    assert!(!filters.export_peers(&spoke2));
+    // Removed peers fall back to the default filter
+    filters.remove_peer(&spoke1);
+    assert_eq!(claims, filters.import_claims(&spoke1, &claims));
+    assert!(filters.has_exports());
+}
+
+#[test]
+fn claim_filters_invalid() {
+    let config = FilterConfig {
+        peer: "spoke1".to_string(),
+        import: Some(vec!["10.1.0.0".to_string()]),
+        export: None,
+        export_peers: true,
+    };
+    assert!(ClaimFilters::new(&[config]).is_err());
+}
+
+#[test]
+fn claim_policy() {
+    let mut policy = ClaimPolicy::new(&[
+        AcceptConfig { key: "office".to_string(), claims: vec!["10.1.0.0/16".to_string()] },
+        AcceptConfig { key: "switch".to_string(), claims: vec!["02:00:00:00:00:00/40".to_string()] },
+        AcceptConfig { key: ANY_PEER.to_string(), claims: vec!["10.9.0.0/16".to_string()] },
+    ])
+    .unwrap();
+    let addr = "1.2.3.4:3210".parse().unwrap();
+    let claims = ranges(&["10.1.2.0/24", "0.0.0.0/0", "02:00:00:00:00:01/48"]);
+    assert_eq!(ranges(&["10.1.2.0/24"]), policy.accept_claims(addr, Some("office"), &claims));
+    assert_eq!(ranges(&["02:00:00:00:00:01/48"]), policy.accept_claims(addr, Some("switch"), &claims));
+    // Unknown keys and peers without key names use the default rule
+    assert!(policy.accept_claims(addr, Some("other"), &claims).is_empty());
+    assert_eq!(ranges(&["10.9.1.1/32"]), policy.accept_claims(addr, None, &ranges(&["10.9.1.1/32"])));
+    policy.remove_peer(&addr);
+    let mut open = ClaimPolicy::new(&[]).unwrap();
+    assert_eq!(claims, open.accept_claims(addr, None, &claims));
+    assert!(ClaimPolicy::new(&[AcceptConfig { key: "*".to_string(), claims: vec!["10.0.0.0".to_string()] }]).is_err());
+}
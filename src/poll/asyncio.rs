@@ -0,0 +1,63 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Waiting for the socket and the device in the optional tokio runtime
+//!
+//! The runtime reports readiness edge-triggered, so the file descriptors are checked again before the readiness is
+//! cleared. This way the node can read one packet per event like with `EpollWait`.
+
+use std::{io, os::unix::io::RawFd, time::Duration};
+
+use tokio::io::unix::AsyncFd;
+
+use super::WaitResult;
+
+/// Checks whether data can be read from the file descriptor without blocking
+fn has_data(fd: RawFd) -> bool {
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    unsafe { libc::poll(&mut pollfd, 1, 0) == 1 }
+}
+
+/// Waits until data can be read from the file descriptor
+async fn readable(fd: &AsyncFd<RawFd>) -> io::Result<()> {
+    loop {
+        let mut guard = fd.readable().await?;
+        if has_data(*fd.get_ref()) {
+            // The readiness is kept, so the next call checks the descriptor again
+            return Ok(());
+        }
+        guard.clear_ready();
+    }
+}
+
+pub struct AsyncWait {
+    socket: AsyncFd<RawFd>,
+    device: AsyncFd<RawFd>,
+    timeout: Duration,
+}
+
+impl AsyncWait {
+    /// Registers the socket and the device with the tokio runtime, this must be called inside the runtime
+    pub fn new(socket: RawFd, device: RawFd, timeout: u32) -> io::Result<Self> {
+        Ok(Self {
+            socket: AsyncFd::new(socket)?,
+            device: AsyncFd::new(device)?,
+            timeout: Duration::from_millis(u64::from(timeout)),
+        })
+    }
+
+    pub async fn wait(&mut self) -> WaitResult {
+        tokio::select! {
+            res = readable(&self.socket) => match res {
+                Ok(()) => WaitResult::Socket,
+                Err(err) => WaitResult::Error(err),
+            },
+            res = readable(&self.device) => match res {
+                Ok(()) => WaitResult::Device,
+                Err(err) => WaitResult::Error(err),
+            },
+            _ = tokio::time::sleep(self.timeout) => WaitResult::Timeout,
+        }
+    }
+}
@@ -2,7 +2,11 @@
 // Copyright (C) 2015-2021  Dennis Schwerdel
 // This software is licensed under GPL-3 or newer (see LICENSE.md)
 
-use std::{io, os::unix::io::RawFd};
+use std::{
+    hint, io, mem,
+    os::unix::io::RawFd,
+    time::{Duration, Instant},
+};
 
 use super::WaitResult;
 
@@ -12,6 +16,7 @@ pub struct EpollWait {
     socket: RawFd,
     device: RawFd,
     timeout: u32,
+    busy_poll: Option<Duration>,
 }
 
 impl EpollWait {
@@ -37,21 +42,33 @@ impl EpollWait {
                 return Err(io::Error::last_os_error());
             }
         }
-        Ok(Self { poll_fd, event, socket, device, timeout })
+        Ok(Self { poll_fd, event, socket, device, timeout, busy_poll: None })
     }
-}
 
-impl Drop for EpollWait {
-    fn drop(&mut self) {
-        unsafe { libc::close(self.poll_fd) };
+    /// Spins for the given number of microseconds after each event before sleeping again
+    ///
+    /// The socket is also configured to busy poll the network card instead of waiting for an
+    /// interrupt. This trades CPU time for consistently low latency.
+    pub fn set_busy_poll(&mut self, micros: u32) {
+        let value = micros as libc::c_int;
+        let res = unsafe {
+            libc::setsockopt(
+                self.socket,
+                libc::SOL_SOCKET,
+                libc::SO_BUSY_POLL,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if res != 0 {
+            // Values above net.core.busy_read need CAP_NET_ADMIN, spinning works anyway
+            warn!("Failed to enable busy polling on socket: {}", io::Error::last_os_error());
+        }
+        self.busy_poll = Some(Duration::from_micros(u64::from(micros)));
     }
-}
-
-impl Iterator for EpollWait {
-    type Item = WaitResult;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        Some(match unsafe { libc::epoll_wait(self.poll_fd, &mut self.event, 1, self.timeout as i32) } {
+    fn wait(&mut self, timeout: i32) -> WaitResult {
+        match unsafe { libc::epoll_wait(self.poll_fd, &mut self.event, 1, timeout) } {
             -1 => WaitResult::Error(io::Error::last_os_error()),
             0 => WaitResult::Timeout,
             1 => {
@@ -64,6 +81,34 @@ impl Iterator for EpollWait {
                 }
             }
             _ => unreachable!(),
-        })
+        }
+    }
+}
+
+impl Drop for EpollWait {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.poll_fd) };
+    }
+}
+
+impl Iterator for EpollWait {
+    type Item = WaitResult;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(busy_poll) = self.busy_poll {
+            // Spin first as the next packet is likely to arrive soon, then sleep
+            let start = Instant::now();
+            loop {
+                match self.wait(0) {
+                    WaitResult::Timeout => (),
+                    res => return Some(res),
+                }
+                if start.elapsed() >= busy_poll {
+                    break;
+                }
+                hint::spin_loop();
+            }
+        }
+        Some(self.wait(self.timeout as i32))
     }
 }
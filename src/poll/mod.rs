@@ -8,6 +8,12 @@ mod epoll;
 #[cfg(any(target_os = "linux", target_os = "android"))]
 pub use self::epoll::EpollWait as WaitImpl;
 
+#[cfg(feature = "async-runtime")]
+mod asyncio;
+
+#[cfg(feature = "async-runtime")]
+pub use self::asyncio::AsyncWait;
+
 use std::io;
 
 pub enum WaitResult {
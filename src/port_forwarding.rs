@@ -13,17 +13,17 @@ mod internal {
 
     const LEASE_TIME: u32 = 1800;
 
-    const DESCRIPTION: &str = "VpnCloud";
-
     pub struct PortForwarding {
         pub internal_addr: SocketAddrV4,
         pub external_addr: SocketAddrV4,
         gateway: Gateway,
         pub next_extension: Option<Time>,
+        /// Description of the mapping that the router shows, see `Config::port_forwarding_description`
+        description: String,
     }
 
     impl PortForwarding {
-        pub fn new(port: u16) -> Option<Self> {
+        pub fn new(port: u16, description: &str) -> Option<Self> {
             // Get the gateway
             let gateway = match search_gateway(Default::default()) {
                 Ok(gateway) => gateway,
@@ -49,33 +49,48 @@ mod internal {
                     return None;
                 }
             };
-            if let Ok((port, timeout)) = Self::get_any_forwarding(&gateway, internal_addr, port) {
+            if let Ok((port, timeout)) = Self::get_any_forwarding(&gateway, internal_addr, port, description) {
                 debug!("Port-forwarding: external IP is {}", external_ip);
                 let external_addr = SocketAddrV4::new(external_ip, port);
-                debug!("Port-forwarding has timeout {}", timeout);
-                info!("Port-forwarding: successfully activated port forward on {}", external_addr);
+                if timeout > 0 {
+                    debug!("Port-forwarding has a lease time of {} seconds", timeout);
+                } else {
+                    debug!("Port-forwarding has a permanent lease");
+                }
+                info!(
+                    "Port-forwarding: successfully activated port forward on {} as \"{}\"",
+                    external_addr, description
+                );
                 let next_extension =
                     if timeout > 0 { Some(SystemTimeSource::now() + Time::from(timeout) - 60) } else { None };
-                Some(PortForwarding { internal_addr, external_addr, gateway, next_extension })
+                Some(PortForwarding {
+                    internal_addr,
+                    external_addr,
+                    gateway,
+                    next_extension,
+                    description: description.to_string(),
+                })
             } else {
                 None
             }
         }
 
-        fn get_any_forwarding(gateway: &Gateway, addr: SocketAddrV4, port: u16) -> Result<(u16, u32), ()> {
-            if let Ok(a) = Self::get_forwarding(gateway, addr, port) {
+        fn get_any_forwarding(
+            gateway: &Gateway, addr: SocketAddrV4, port: u16, description: &str,
+        ) -> Result<(u16, u32), ()> {
+            if let Ok(a) = Self::get_forwarding(gateway, addr, port, description) {
                 return Ok(a);
             }
-            if let Ok(a) = Self::get_forwarding(gateway, addr, 0) {
+            if let Ok(a) = Self::get_forwarding(gateway, addr, 0, description) {
                 return Ok(a);
             }
             for i in 1..5 {
-                if let Ok(a) = Self::get_forwarding(gateway, addr, port + i) {
+                if let Ok(a) = Self::get_forwarding(gateway, addr, port + i, description) {
                     return Ok(a);
                 }
             }
             for _ in 0..5 {
-                if let Ok(a) = Self::get_forwarding(gateway, addr, rand::random()) {
+                if let Ok(a) = Self::get_forwarding(gateway, addr, rand::random(), description) {
                     return Ok(a);
                 }
             }
@@ -83,13 +98,15 @@ mod internal {
             Err(())
         }
 
-        fn get_forwarding(gateway: &Gateway, addr: SocketAddrV4, port: u16) -> Result<(u16, u32), ()> {
+        fn get_forwarding(
+            gateway: &Gateway, addr: SocketAddrV4, port: u16, description: &str,
+        ) -> Result<(u16, u32), ()> {
             debug!("Trying external port {}", port);
             if port == 0 {
-                match gateway.add_any_port(PortMappingProtocol::UDP, addr, LEASE_TIME, DESCRIPTION) {
+                match gateway.add_any_port(PortMappingProtocol::UDP, addr, LEASE_TIME, description) {
                     Ok(port) => Ok((port, LEASE_TIME)),
                     Err(AddAnyPortError::OnlyPermanentLeasesSupported) => {
-                        match gateway.add_any_port(PortMappingProtocol::UDP, addr, 0, DESCRIPTION) {
+                        match gateway.add_any_port(PortMappingProtocol::UDP, addr, 0, description) {
                             Ok(port) => Ok((port, 0)),
                             Err(err) => {
                                 debug!("Port-forwarding: failed to activate port forwarding: {}", err);
@@ -103,10 +120,10 @@ mod internal {
                     }
                 }
             } else {
-                match gateway.add_port(PortMappingProtocol::UDP, port, addr, LEASE_TIME, DESCRIPTION) {
+                match gateway.add_port(PortMappingProtocol::UDP, port, addr, LEASE_TIME, description) {
                     Ok(()) => Ok((port, LEASE_TIME)),
                     Err(AddPortError::OnlyPermanentLeasesSupported) => {
-                        match gateway.add_port(PortMappingProtocol::UDP, port, addr, 0, DESCRIPTION) {
+                        match gateway.add_port(PortMappingProtocol::UDP, port, addr, 0, description) {
                             Ok(()) => Ok((port, 0)),
                             Err(err) => {
                                 debug!("Port-forwarding: failed to activate port forwarding: {}", err);
@@ -135,7 +152,7 @@ mod internal {
                 self.external_addr.port(),
                 self.internal_addr,
                 LEASE_TIME,
-                DESCRIPTION,
+                &self.description,
             ) {
                 Ok(()) => debug!("Port-forwarding: extended port forwarding"),
                 Err(err) => debug!("Port-forwarding: failed to extend port forwarding: {}", err),
@@ -143,6 +160,14 @@ mod internal {
             self.next_extension = Some(SystemTimeSource::now() + Time::from(LEASE_TIME) - 60);
         }
 
+        /// Deactivates the forwarding and sets it up anew, e.g. after the network changed
+        pub fn renew(self) -> Option<Self> {
+            let port = self.internal_addr.port();
+            let description = self.description.clone();
+            drop(self);
+            Self::new(port, &description)
+        }
+
         fn deactivate(&self) {
             match self.gateway.remove_port(PortMappingProtocol::UDP, self.external_addr.port()) {
                 Ok(()) => info!("Port-forwarding: successfully deactivated port forwarding"),
@@ -168,17 +193,31 @@ mod internal {
 
 #[cfg(not(feature = "nat"))]
 mod internal {
+    use std::net::SocketAddrV4;
+
     pub struct PortForwarding;
 
     impl PortForwarding {
-        pub fn new(_port: u16) -> Option<Self> {
+        pub fn new(_port: u16, _description: &str) -> Option<Self> {
             warn!("Compiled without feature 'nat', skipping port forwarding.");
             None
         }
 
+        pub fn get_internal_ip(&self) -> SocketAddrV4 {
+            unreachable!()
+        }
+
+        pub fn get_external_ip(&self) -> SocketAddrV4 {
+            unreachable!()
+        }
+
         pub fn check_extend(&mut self) {
             unreachable!()
         }
+
+        pub fn renew(self) -> Option<Self> {
+            unreachable!()
+        }
     }
 }
 
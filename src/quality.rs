@@ -0,0 +1,229 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Quality scoring of the paths (addresses) used to reach peers
+//!
+//! Every path gets a score between 0 and 1 from its packet loss, the variance of its handshake round-trip times
+//! and the number of consecutive failed handshakes. Paths whose score drops below `DEMOTE_SCORE` are demoted and
+//! avoided as long as a better path to the same node exists. To avoid flapping, a demoted path is only promoted
+//! again once its score rises above `PROMOTE_SCORE`.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    marker::PhantomData,
+    net::SocketAddr,
+    time::Instant,
+};
+
+use crate::{
+    cloud::Hash,
+    util::{addr_nice, Time, TimeSource},
+};
+
+/// Paths with a score below this value are demoted
+pub const DEMOTE_SCORE: f64 = 0.5;
+/// Demoted paths with a score above this value are promoted again
+pub const PROMOTE_SCORE: f64 = 0.8;
+/// Weight of a new sample in the moving averages
+const SMOOTHING: f64 = 0.1;
+/// Round-trip time variance in milliseconds that halves the score
+const RTT_VAR_REFERENCE: f64 = 100.0;
+/// Paths without any activity are forgotten after this time
+const FORGET_TIMEOUT: Time = 3600;
+
+#[derive(Default)]
+pub struct PathQuality {
+    loss: f64,
+    rtt: Option<f64>,
+    rtt_var: f64,
+    handshake_failures: u32,
+    handshake_start: Option<Instant>,
+    demoted: bool,
+    last_update: Time,
+}
+
+impl PathQuality {
+    /// The score of this path, 1.0 is perfect
+    pub fn score(&self) -> f64 {
+        (1.0 - self.loss) * RTT_VAR_REFERENCE / (RTT_VAR_REFERENCE + self.rtt_var)
+            * 0.5f64.powi(self.handshake_failures as i32)
+    }
+}
+
+pub struct QualityTable<TS: TimeSource> {
+    paths: HashMap<SocketAddr, PathQuality, Hash>,
+    demoted: Vec<SocketAddr>,
+    _dummy: PhantomData<TS>,
+}
+
+impl<TS: TimeSource> QualityTable<TS> {
+    pub fn new() -> Self {
+        Self { paths: HashMap::default(), demoted: vec![], _dummy: PhantomData }
+    }
+
+    fn get_mut(&mut self, addr: SocketAddr) -> &mut PathQuality {
+        let path = self.paths.entry(addr).or_default();
+        path.last_update = TS::now();
+        path
+    }
+
+    /// Re-evaluates the score of the path and demotes or promotes it
+    fn evaluate(&mut self, addr: SocketAddr) {
+        let path = self.get_mut(addr);
+        let score = path.score();
+        if !path.demoted && score < DEMOTE_SCORE {
+            info!("Demoting path to {} due to bad quality (score {:.2})", addr_nice(addr), score);
+            path.demoted = true;
+            self.demoted.push(addr);
+        } else if path.demoted && score > PROMOTE_SCORE {
+            info!("Promoting path to {} again (score {:.2})", addr_nice(addr), score);
+            path.demoted = false;
+        }
+    }
+
+    pub fn handshake_started(&mut self, addr: SocketAddr) {
+        self.get_mut(addr).handshake_start = Some(Instant::now());
+    }
+
+    /// Accounts a finished handshake, `received` is the time when the kernel received the answer if known
+    ///
+    /// With the receive time, the round-trip time does not include the time that the answer waited in the socket
+    /// buffer until the event loop got to it.
+    pub fn handshake_succeeded(&mut self, addr: SocketAddr, received: Option<Instant>) {
+        let path = self.get_mut(addr);
+        path.handshake_failures = 0;
+        if let Some(start) = path.handshake_start.take() {
+            let end = received.filter(|&time| time >= start).unwrap_or_else(Instant::now);
+            let rtt = end.duration_since(start).as_secs_f64() * 1000.0;
+            match path.rtt {
+                Some(avg) => {
+                    path.rtt_var = (1.0 - SMOOTHING) * path.rtt_var + SMOOTHING * (rtt - avg).abs();
+                    path.rtt = Some((1.0 - SMOOTHING) * avg + SMOOTHING * rtt);
+                }
+                None => path.rtt = Some(rtt),
+            }
+        }
+        self.evaluate(addr)
+    }
+
+    pub fn handshake_failed(&mut self, addr: SocketAddr) {
+        let path = self.get_mut(addr);
+        path.handshake_start = None;
+        path.handshake_failures += 1;
+        self.evaluate(addr)
+    }
+
+    /// Accounts the packets received from the path and the packets that got lost on the way
+    pub fn count_packets(&mut self, addr: SocketAddr, received: u64, lost: u64) {
+        if received + lost == 0 {
+            return;
+        }
+        let path = self.get_mut(addr);
+        let loss = lost as f64 / (received + lost) as f64;
+        path.loss = (1.0 - SMOOTHING) * path.loss + SMOOTHING * loss;
+        self.evaluate(addr)
+    }
+
+    pub fn is_demoted(&self, addr: &SocketAddr) -> bool {
+        self.paths.get(addr).map(|p| p.demoted).unwrap_or(false)
+    }
+
+    /// The smoothed fraction of packets from the path that got lost
+    pub fn loss(&self, addr: &SocketAddr) -> f64 {
+        self.paths.get(addr).map(|p| p.loss).unwrap_or(0.0)
+    }
+
+    pub fn score(&self, addr: &SocketAddr) -> f64 {
+        self.paths.get(addr).map(|p| p.score()).unwrap_or(1.0)
+    }
+
+    /// Returns the paths that have been demoted since the last call
+    pub fn take_demoted(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.demoted)
+    }
+
+    pub fn demoted_count(&self) -> usize {
+        self.paths.values().filter(|p| p.demoted).count()
+    }
+
+    pub fn housekeep(&mut self) {
+        let now = TS::now();
+        self.paths.retain(|_, p| p.last_update + FORGET_TIMEOUT > now);
+    }
+
+    pub fn write_out<W: Write>(&self, out: &mut W) -> Result<(), io::Error> {
+        writeln!(out, "path_quality:")?;
+        let mut paths: Vec<_> = self.paths.iter().collect();
+        paths.sort_unstable_by_key(|(addr, _)| **addr);
+        for (addr, path) in paths {
+            writeln!(
+                out,
+                "  - path: \"{}\"\n    score: {:.2}\n    loss: {:.3}\n    rtt_ms: {:.1}\n    rtt_var_ms: {:.1}\n    handshake_failures: {}\n    demoted: {}",
+                addr_nice(*addr),
+                path.score(),
+                path.loss,
+                path.rtt.unwrap_or(0.0),
+                path.rtt_var,
+                path.handshake_failures,
+                path.demoted
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl<TS: TimeSource> Default for QualityTable<TS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn quality_hysteresis() {
+    use crate::util::MockTimeSource;
+    let mut table = QualityTable::<MockTimeSource>::new();
+    let addr = "1.2.3.4:3210".parse().unwrap();
+    assert!((table.score(&addr) - 1.0).abs() < 1e-9);
+    table.handshake_failed(addr);
+    assert!(!table.is_demoted(&addr));
+    table.handshake_failed(addr);
+    assert!(table.is_demoted(&addr));
+    assert_eq!(table.take_demoted(), vec![addr]);
+    assert!(table.take_demoted().is_empty());
+    table.handshake_succeeded(addr, None);
+    assert!(!table.is_demoted(&addr));
+    // Heavy loss demotes the path
+    for _ in 0..10 {
+        table.count_packets(addr, 10, 90);
+    }
+    assert!(table.is_demoted(&addr));
+    assert_eq!(table.demoted_count(), 1);
+    // A score in between does not promote it again
+    for _ in 0..5 {
+        table.count_packets(addr, 100, 0);
+    }
+    assert!(table.score(&addr) > DEMOTE_SCORE && table.score(&addr) < PROMOTE_SCORE);
+    assert!(table.is_demoted(&addr));
+    for _ in 0..20 {
+        table.count_packets(addr, 100, 0);
+    }
+    assert!(!table.is_demoted(&addr));
+}
+
+#[test]
+fn quality_rtt_from_receive_time() {
+    use crate::util::MockTimeSource;
+    use std::time::Duration;
+    let mut table = QualityTable::<MockTimeSource>::new();
+    let addr = "1.2.3.4:3210".parse().unwrap();
+    table.handshake_started(addr);
+    let start = table.paths[&addr].handshake_start.unwrap();
+    table.handshake_succeeded(addr, Some(start + Duration::from_millis(5)));
+    assert!((table.paths[&addr].rtt.unwrap() - 5.0).abs() < 1e-6);
+    // A receive time before the start is not plausible
+    table.handshake_started(addr);
+    table.handshake_succeeded(addr, Some(start));
+    assert!(table.paths[&addr].rtt.unwrap() >= 4.5);
+}
@@ -0,0 +1,417 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! RADIUS accounting (RFC 2866) of peer sessions
+//!
+//! Every connection to a peer is a session that is reported to the accounting server with a Start record when it is
+//! established, periodic Interim-Update records and a Stop record when it ends. The records contain the duration as
+//! well as the bytes and packets exchanged with the peer, so that existing billing systems can consume them.
+//! Requests that are not answered by the server are repeated a few times.
+
+use std::{
+    collections::HashMap,
+    io,
+    marker::PhantomData,
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use crate::{
+    cloud::Hash,
+    error::Error,
+    util::{addr_nice, resolve, Time, TimeSource},
+};
+
+pub const DEFAULT_PORT: u16 = 1813;
+pub const DEFAULT_INTERIM_INTERVAL: Time = 300;
+pub const DEFAULT_NAS_IDENTIFIER: &str = "vpncloud";
+
+const CODE_ACCOUNTING_REQUEST: u8 = 4;
+const CODE_ACCOUNTING_RESPONSE: u8 = 5;
+const HEADER_LEN: usize = 20;
+
+const ATTR_USER_NAME: u8 = 1;
+const ATTR_CALLING_STATION_ID: u8 = 31;
+const ATTR_NAS_IDENTIFIER: u8 = 32;
+const ATTR_ACCT_STATUS_TYPE: u8 = 40;
+const ATTR_ACCT_INPUT_OCTETS: u8 = 42;
+const ATTR_ACCT_OUTPUT_OCTETS: u8 = 43;
+const ATTR_ACCT_SESSION_ID: u8 = 44;
+const ATTR_ACCT_SESSION_TIME: u8 = 46;
+const ATTR_ACCT_INPUT_PACKETS: u8 = 47;
+const ATTR_ACCT_OUTPUT_PACKETS: u8 = 48;
+const ATTR_ACCT_TERMINATE_CAUSE: u8 = 49;
+const ATTR_ACCT_INPUT_GIGAWORDS: u8 = 52;
+const ATTR_ACCT_OUTPUT_GIGAWORDS: u8 = 53;
+const ATTR_EVENT_TIMESTAMP: u8 = 55;
+
+const STATUS_START: u32 = 1;
+const STATUS_STOP: u32 = 2;
+const STATUS_INTERIM_UPDATE: u32 = 3;
+
+/// Time after which an unanswered request is repeated
+const RETRY_INTERVAL: Time = 3;
+/// Number of times a request is sent before it is given up
+const MAX_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Config {
+    /// Address of the accounting server, the port defaults to 1813
+    pub server: String,
+    /// Shared secret with the accounting server
+    pub secret: String,
+    /// Seconds between interim updates, 0 disables them
+    pub interim_interval: Option<Time>,
+    pub nas_identifier: Option<String>,
+}
+
+/// Reason why a session ended, with the values of the Acct-Terminate-Cause attribute
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TerminateCause {
+    /// The peer closed the connection
+    UserRequest = 1,
+    /// The connection failed
+    LostService = 3,
+    /// The peer did not answer any more
+    IdleTimeout = 4,
+    /// The connection was closed locally
+    AdminReset = 6,
+    /// VpnCloud is shutting down
+    NasReboot = 11,
+}
+
+struct Session {
+    id: String,
+    user: String,
+    start: Time,
+    next_interim: Time,
+    in_bytes: u64,
+    in_packets: u64,
+    out_bytes: u64,
+    out_packets: u64,
+}
+
+struct Request {
+    data: Vec<u8>,
+    attempts: usize,
+    next_retry: Time,
+}
+
+pub struct Accounting<TS: TimeSource> {
+    config: Config,
+    server: SocketAddr,
+    socket: UdpSocket,
+    sessions: HashMap<SocketAddr, Session, Hash>,
+    pending: HashMap<u8, Request, Hash>,
+    session_prefix: u32,
+    session_counter: u32,
+    next_id: u8,
+    _dummy_ts: PhantomData<TS>,
+}
+
+impl<TS: TimeSource> Accounting<TS> {
+    pub fn new(config: Config) -> Result<Self, Error> {
+        let server = if config.server.contains(':') {
+            resolve(&config.server)?
+        } else {
+            resolve((&config.server as &str, DEFAULT_PORT))?
+        };
+        let server = *server.first().ok_or_else(|| Error::NameUnresolvable(config.server.clone()))?;
+        let bind = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind).map_err(|e| Error::SocketIo("Failed to open RADIUS socket", e))?;
+        socket.set_nonblocking(true).map_err(|e| Error::SocketIo("Failed to open RADIUS socket", e))?;
+        Ok(Self {
+            config,
+            server,
+            socket,
+            sessions: HashMap::default(),
+            pending: HashMap::default(),
+            session_prefix: rand::random(),
+            session_counter: 0,
+            next_id: rand::random(),
+            _dummy_ts: PhantomData,
+        })
+    }
+
+    fn interim_interval(&self) -> Time {
+        self.config.interim_interval.unwrap_or(DEFAULT_INTERIM_INTERVAL)
+    }
+
+    /// Starts a session for the peer, the name is used as user name if known
+    pub fn start(&mut self, addr: SocketAddr, name: Option<&str>) {
+        self.session_counter += 1;
+        let now = TS::now();
+        let session = Session {
+            id: format!("{:08x}-{:08x}", self.session_prefix, self.session_counter),
+            user: name.map(String::from).unwrap_or_else(|| addr_nice(addr).to_string()),
+            start: now,
+            next_interim: now + self.interim_interval(),
+            in_bytes: 0,
+            in_packets: 0,
+            out_bytes: 0,
+            out_packets: 0,
+        };
+        debug!("RADIUS: starting session {} for {}", session.id, session.user);
+        self.send(addr, &session, STATUS_START, None);
+        self.sessions.insert(addr, session);
+    }
+
+    /// Ends the session of the peer, if there is one
+    pub fn stop(&mut self, addr: &SocketAddr, cause: TerminateCause) {
+        if let Some(session) = self.sessions.remove(addr) {
+            debug!("RADIUS: stopping session {} for {}", session.id, session.user);
+            self.send(*addr, &session, STATUS_STOP, Some(cause));
+        }
+    }
+
+    /// Ends all sessions and waits shortly for the answers
+    pub fn stop_all(&mut self, cause: TerminateCause) {
+        let addrs: Vec<_> = self.sessions.keys().copied().collect();
+        for addr in addrs {
+            self.stop(&addr, cause);
+        }
+        self.socket.set_read_timeout(Some(Duration::from_secs(1))).ok();
+        self.socket.set_nonblocking(false).ok();
+        while !self.pending.is_empty() {
+            if !matches!(self.receive(), Ok(true)) {
+                break;
+            }
+        }
+    }
+
+    #[inline]
+    pub fn count_in(&mut self, addr: &SocketAddr, bytes: usize) {
+        if let Some(session) = self.sessions.get_mut(addr) {
+            session.in_bytes += bytes as u64;
+            session.in_packets += 1;
+        }
+    }
+
+    #[inline]
+    pub fn count_out(&mut self, addr: &SocketAddr, bytes: usize) {
+        if let Some(session) = self.sessions.get_mut(addr) {
+            session.out_bytes += bytes as u64;
+            session.out_packets += 1;
+        }
+    }
+
+    /// Handles answers, repeats unanswered requests and sends interim updates
+    pub fn housekeep(&mut self) {
+        while let Ok(true) = self.receive() {}
+        let now = TS::now();
+        let mut failed = vec![];
+        for (id, request) in &mut self.pending {
+            if request.next_retry <= now {
+                if request.attempts >= MAX_ATTEMPTS {
+                    failed.push(*id);
+                    continue;
+                }
+                request.attempts += 1;
+                request.next_retry = now + RETRY_INTERVAL;
+                if let Err(err) = self.socket.send_to(&request.data, self.server) {
+                    debug!("RADIUS: failed to send request: {}", err);
+                }
+            }
+        }
+        for id in failed {
+            warn!("RADIUS: accounting server {} did not answer request {}", addr_nice(self.server), id);
+            self.pending.remove(&id);
+        }
+        let interval = self.interim_interval();
+        if interval == 0 {
+            return;
+        }
+        let due: Vec<_> =
+            self.sessions.iter().filter(|(_, session)| session.next_interim <= now).map(|(addr, _)| *addr).collect();
+        for addr in due {
+            if let Some(mut session) = self.sessions.remove(&addr) {
+                session.next_interim = now + interval;
+                self.send(addr, &session, STATUS_INTERIM_UPDATE, None);
+                self.sessions.insert(addr, session);
+            }
+        }
+    }
+
+    /// Reads one answer from the server, returns `false` if none is waiting
+    fn receive(&mut self) -> Result<bool, io::Error> {
+        let mut buffer = [0; 4096];
+        let (size, src) = match self.socket.recv_from(&mut buffer) {
+            Ok(res) => res,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        if src != self.server {
+            return Ok(true);
+        }
+        let data = &buffer[..size];
+        if size < HEADER_LEN || data[0] != CODE_ACCOUNTING_RESPONSE {
+            return Ok(true);
+        }
+        let id = data[1];
+        if let Some(request) = self.pending.get(&id) {
+            if check_response(data, &request.data, self.config.secret.as_bytes()) {
+                self.pending.remove(&id);
+            } else {
+                warn!("RADIUS: ignoring answer with invalid authenticator, check the shared secret");
+            }
+        }
+        Ok(true)
+    }
+
+    fn send(&mut self, addr: SocketAddr, session: &Session, status: u32, cause: Option<TerminateCause>) {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let mut attrs = Attributes::default();
+        attrs.add_u32(ATTR_ACCT_STATUS_TYPE, status);
+        attrs.add(ATTR_ACCT_SESSION_ID, session.id.as_bytes());
+        attrs.add(ATTR_USER_NAME, session.user.as_bytes());
+        attrs.add(ATTR_CALLING_STATION_ID, addr_nice(addr).to_string().as_bytes());
+        attrs.add(
+            ATTR_NAS_IDENTIFIER,
+            self.config.nas_identifier.as_deref().unwrap_or(DEFAULT_NAS_IDENTIFIER).as_bytes(),
+        );
+        attrs.add_u32(ATTR_EVENT_TIMESTAMP, TS::wall_clock() as u32);
+        if status != STATUS_START {
+            attrs.add_u32(ATTR_ACCT_SESSION_TIME, (TS::now() - session.start) as u32);
+            attrs.add_u32(ATTR_ACCT_INPUT_OCTETS, session.in_bytes as u32);
+            attrs.add_u32(ATTR_ACCT_INPUT_GIGAWORDS, (session.in_bytes >> 32) as u32);
+            attrs.add_u32(ATTR_ACCT_INPUT_PACKETS, session.in_packets as u32);
+            attrs.add_u32(ATTR_ACCT_OUTPUT_OCTETS, session.out_bytes as u32);
+            attrs.add_u32(ATTR_ACCT_OUTPUT_GIGAWORDS, (session.out_bytes >> 32) as u32);
+            attrs.add_u32(ATTR_ACCT_OUTPUT_PACKETS, session.out_packets as u32);
+        }
+        if let Some(cause) = cause {
+            attrs.add_u32(ATTR_ACCT_TERMINATE_CAUSE, cause as u32);
+        }
+        let data = encode_request(id, &attrs.0, self.config.secret.as_bytes());
+        if let Err(err) = self.socket.send_to(&data, self.server) {
+            debug!("RADIUS: failed to send request: {}", err);
+        }
+        self.pending.insert(id, Request { data, attempts: 1, next_retry: TS::now() + RETRY_INTERVAL });
+    }
+}
+
+#[derive(Default)]
+struct Attributes(Vec<u8>);
+
+impl Attributes {
+    fn add(&mut self, type_: u8, value: &[u8]) {
+        let value = &value[..value.len().min(253)];
+        self.0.push(type_);
+        self.0.push(value.len() as u8 + 2);
+        self.0.extend_from_slice(value);
+    }
+
+    fn add_u32(&mut self, type_: u8, value: u32) {
+        self.add(type_, &value.to_be_bytes())
+    }
+}
+
+/// Creates an Accounting-Request with the authenticator from the shared secret
+fn encode_request(id: u8, attrs: &[u8], secret: &[u8]) -> Vec<u8> {
+    let len = HEADER_LEN + attrs.len();
+    let mut data = Vec::with_capacity(len);
+    data.push(CODE_ACCOUNTING_REQUEST);
+    data.push(id);
+    data.extend_from_slice(&(len as u16).to_be_bytes());
+    data.extend_from_slice(&[0; 16]);
+    data.extend_from_slice(attrs);
+    let mut context = md5::Context::new();
+    context.consume(&data);
+    context.consume(secret);
+    let authenticator = context.compute();
+    data[4..HEADER_LEN].copy_from_slice(&authenticator.0);
+    data
+}
+
+/// Checks the authenticator of an Accounting-Response to the given request
+fn check_response(response: &[u8], request: &[u8], secret: &[u8]) -> bool {
+    if response.len() < HEADER_LEN || u16::from_be_bytes([response[2], response[3]]) as usize != response.len() {
+        return false;
+    }
+    let mut context = md5::Context::new();
+    context.consume(&response[..4]);
+    context.consume(&request[4..HEADER_LEN]);
+    context.consume(&response[HEADER_LEN..]);
+    context.consume(secret);
+    context.compute().0 == response[4..HEADER_LEN]
+}
+
+#[cfg(test)]
+fn encode_response(request: &[u8], secret: &[u8]) -> Vec<u8> {
+    let mut data = vec![CODE_ACCOUNTING_RESPONSE, request[1], 0, HEADER_LEN as u8];
+    data.extend_from_slice(&request[4..HEADER_LEN]);
+    let mut context = md5::Context::new();
+    context.consume(&data);
+    context.consume(secret);
+    let authenticator = context.compute();
+    data[4..].copy_from_slice(&authenticator.0);
+    data
+}
+
+#[test]
+fn radius_packets() {
+    let mut attrs = Attributes::default();
+    attrs.add_u32(ATTR_ACCT_STATUS_TYPE, STATUS_START);
+    attrs.add(ATTR_USER_NAME, b"node1");
+    assert_eq!(attrs.0, vec![40, 6, 0, 0, 0, 1, 1, 7, b'n', b'o', b'd', b'e', b'1']);
+    let request = encode_request(7, &attrs.0, b"secret");
+    assert_eq!(request[..4], [4, 7, 0, 33]);
+    assert_eq!(request[HEADER_LEN..], attrs.0[..]);
+    // The authenticator is the hash over the packet with a zero authenticator and the secret
+    let mut data = request.clone();
+    data[4..HEADER_LEN].copy_from_slice(&[0; 16]);
+    data.extend_from_slice(b"secret");
+    assert_eq!(md5::compute(&data).0, request[4..HEADER_LEN]);
+    let response = encode_response(&request, b"secret");
+    assert!(check_response(&response, &request, b"secret"));
+    assert!(!check_response(&response, &request, b"wrong"));
+    assert!(!check_response(&response[..HEADER_LEN - 1], &request, b"secret"));
+}
+
+#[test]
+fn radius_sessions() {
+    use crate::util::MockTimeSource;
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let config = Config {
+        server: server.local_addr().unwrap().to_string(),
+        secret: "secret".to_string(),
+        interim_interval: Some(60),
+        nas_identifier: None,
+    };
+    MockTimeSource::set_time(0);
+    let mut accounting = Accounting::<MockTimeSource>::new(config).unwrap();
+    let peer = "1.2.3.4:3210".parse().unwrap();
+    let mut buffer = [0; 4096];
+    accounting.start(peer, Some("node1"));
+    let (size, client) = server.recv_from(&mut buffer).unwrap();
+    let start = buffer[..size].to_vec();
+    assert_eq!(start[0], CODE_ACCOUNTING_REQUEST);
+    // Unanswered requests are repeated
+    MockTimeSource::set_time(RETRY_INTERVAL);
+    accounting.housekeep();
+    let (size, _) = server.recv_from(&mut buffer).unwrap();
+    assert_eq!(buffer[..size], start[..]);
+    server.send_to(&encode_response(&start, b"secret"), client).unwrap();
+    accounting.count_in(&peer, 100);
+    accounting.count_out(&peer, 50);
+    MockTimeSource::set_time(60);
+    accounting.housekeep();
+    assert_eq!(accounting.pending.len(), 1);
+    let (size, _) = server.recv_from(&mut buffer).unwrap();
+    let interim = buffer[..size].to_vec();
+    assert!(interim.windows(6).any(|w| w == [ATTR_ACCT_STATUS_TYPE, 6, 0, 0, 0, 3]));
+    assert!(interim.windows(6).any(|w| w == [ATTR_ACCT_INPUT_OCTETS, 6, 0, 0, 0, 100]));
+    assert!(interim.windows(6).any(|w| w == [ATTR_ACCT_SESSION_TIME, 6, 0, 0, 0, 60]));
+    server.send_to(&encode_response(&interim, b"secret"), client).unwrap();
+    accounting.stop(&peer, TerminateCause::UserRequest);
+    let (size, _) = server.recv_from(&mut buffer).unwrap();
+    let stop = buffer[..size].to_vec();
+    assert!(stop.windows(6).any(|w| w == [ATTR_ACCT_TERMINATE_CAUSE, 6, 0, 0, 0, 1]));
+    server.send_to(&encode_response(&stop, b"secret"), client).unwrap();
+    accounting.stop_all(TerminateCause::NasReboot);
+    assert!(accounting.pending.is_empty());
+}
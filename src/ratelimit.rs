@@ -0,0 +1,168 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Rate limiting of control messages per source address
+//!
+//! Handshake messages, messages from unknown addresses and the control messages of peers (node info, keepalive and
+//! close) are limited by a token bucket per source IP address before they are processed, so a flood of them can not
+//! starve the data path. Data messages of established peers are never limited. An address that keeps sending after
+//! its bucket is empty is banned for a while and all of its control messages are dropped.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    marker::PhantomData,
+    net::IpAddr,
+};
+
+use crate::{
+    cloud::Hash,
+    util::{Duration, Time, TimeSource},
+};
+
+/// Maximal number of tracked source addresses, messages from further addresses are dropped
+const MAX_SOURCES: usize = 65536;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Config {
+    /// Control messages per second that each address can send
+    pub rate: u32,
+    /// Control messages that each address can send at once
+    pub burst: u32,
+    /// Messages above the limit within one second that cause a ban, 0 disables bans
+    pub ban_threshold: u32,
+    /// Duration of a ban in seconds
+    pub ban_time: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { rate: 10, burst: 50, ban_threshold: 500, ban_time: 300 }
+    }
+}
+
+struct Source {
+    tokens: u32,
+    excess: u32,
+    banned_until: Option<Time>,
+}
+
+pub struct ControlLimiter<TS: TimeSource> {
+    config: Config,
+    sources: HashMap<IpAddr, Source, Hash>,
+    dropped: usize,
+    bans: usize,
+    _dummy: PhantomData<TS>,
+}
+
+impl<TS: TimeSource> ControlLimiter<TS> {
+    pub fn new(config: Config) -> Self {
+        Self { config, sources: HashMap::default(), dropped: 0, bans: 0, _dummy: PhantomData }
+    }
+
+    /// Checks whether a control message from the address can be processed and takes a token for it
+    pub fn allows(&mut self, ip: IpAddr) -> bool {
+        if self.sources.len() >= MAX_SOURCES && !self.sources.contains_key(&ip) {
+            self.dropped += 1;
+            return false;
+        }
+        let burst = self.config.burst;
+        let source = self.sources.entry(ip).or_insert(Source { tokens: burst, excess: 0, banned_until: None });
+        if let Some(until) = source.banned_until {
+            if until > TS::now() {
+                self.dropped += 1;
+                return false;
+            }
+            info!("Ban of {} expired", ip);
+            source.banned_until = None;
+        }
+        if source.tokens > 0 {
+            source.tokens -= 1;
+            return true;
+        }
+        self.dropped += 1;
+        source.excess += 1;
+        if self.config.ban_threshold > 0 && source.excess >= self.config.ban_threshold {
+            warn!("Banning {} for {} seconds due to a flood of control messages", ip, self.config.ban_time);
+            source.banned_until = Some(TS::now() + Time::from(self.config.ban_time));
+            self.bans += 1;
+        } else if source.excess == 1 {
+            debug!("Rate limiting control messages from {}", ip);
+        }
+        false
+    }
+
+    /// Number of currently banned addresses
+    pub fn banned_count(&self) -> usize {
+        let now = TS::now();
+        self.sources.values().filter(|s| s.banned_until.map(|until| until > now).unwrap_or(false)).count()
+    }
+
+    /// Refills the buckets and forgets idle addresses, must be called every second
+    pub fn housekeep(&mut self) {
+        let now = TS::now();
+        let (rate, burst) = (self.config.rate, self.config.burst);
+        self.sources.retain(|_, source| {
+            source.tokens = source.tokens.saturating_add(rate).min(burst);
+            source.excess = 0;
+            source.tokens < burst || source.banned_until.map(|until| until > now).unwrap_or(false)
+        });
+    }
+
+    pub fn write_out<W: Write>(&self, out: &mut W) -> Result<(), io::Error> {
+        writeln!(out, "control_limit:")?;
+        writeln!(out, "  sources: {}", self.sources.len())?;
+        writeln!(out, "  banned: {}", self.banned_count())?;
+        writeln!(out, "  dropped: {}", self.dropped)?;
+        writeln!(out, "  bans: {}", self.bans)?;
+        Ok(())
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    pub fn bans(&self) -> usize {
+        self.bans
+    }
+}
+
+#[test]
+fn control_limit_and_ban() {
+    use crate::util::MockTimeSource;
+    MockTimeSource::set_time(0);
+    let config = Config { rate: 2, burst: 5, ban_threshold: 10, ban_time: 60 };
+    let mut limiter = ControlLimiter::<MockTimeSource>::new(config);
+    let ip: IpAddr = "1.2.3.4".parse().unwrap();
+    let other: IpAddr = "1.2.3.5".parse().unwrap();
+    for _ in 0..5 {
+        assert!(limiter.allows(ip));
+    }
+    assert!(!limiter.allows(ip));
+    assert!(limiter.allows(other));
+    // The bucket is refilled by the rate
+    limiter.housekeep();
+    assert!(limiter.allows(ip));
+    assert!(limiter.allows(ip));
+    assert!(!limiter.allows(ip));
+    assert_eq!(limiter.dropped(), 2);
+    // Flooding leads to a ban that outlasts the refill
+    for _ in 0..10 {
+        limiter.allows(ip);
+    }
+    assert_eq!(limiter.bans(), 1);
+    assert_eq!(limiter.banned_count(), 1);
+    limiter.housekeep();
+    assert!(!limiter.allows(ip));
+    assert!(limiter.allows(other));
+    MockTimeSource::set_time(61);
+    assert!(limiter.allows(ip));
+    assert_eq!(limiter.banned_count(), 0);
+    // Idle addresses are forgotten once their bucket is full
+    for _ in 0..3 {
+        limiter.housekeep();
+    }
+    assert_eq!(limiter.sources.len(), 0);
+}
@@ -0,0 +1,249 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Remote control of an instance over the VPN, see `remote-control`
+//!
+//! The remote control port accepts the same commands as the control socket, so `vpncloud peers --remote <addr>` and
+//! the other commands can inspect any node from one machine. The port can only be bound to the address of the node
+//! inside the VPN, so commands and their output only travel through the encrypted overlay.
+//!
+//! Every command is authenticated with an admin key: the server sends `challenge <random>`, the client answers with
+//! `auth <public key> <signature>` and the command line, where the signature covers the challenge and the command. The
+//! command is only executed if the key is one of the admin keys and the signature is valid, so a recorded command can
+//! not be replayed. The responses are not authenticated, the client relies on the overlay to deliver them unchanged.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use ring::{
+    rand::{SecureRandom, SystemRandom},
+    signature::{self, KeyPair},
+};
+use smallvec::smallvec;
+
+use crate::{
+    control::{read_limited_line, read_response, write_response, CommandHandler, ControlCommand, COMMAND_TIMEOUT},
+    crypto::{Crypto, Ed25519PublicKey},
+    error::Error,
+    net::parse_listen,
+    util::{addr_nice, from_base62, resolve, to_base62},
+};
+
+pub const DEFAULT_REMOTE_CONTROL_PORT: u16 = 3211;
+const CHALLENGE_LEN: usize = 32;
+/// Connections that are handled at the same time, further connections are closed right away
+const MAX_CONNECTIONS: usize = 8;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Address to accept commands on, the address of the VPN interface
+    pub listen: String,
+    /// Public keys of the admins that may send commands
+    #[serde(default)]
+    pub admin_keys: Vec<String>,
+}
+
+/// Opens the remote control port and passes authenticated commands to the handler in a background thread
+///
+/// The port must be bound to `overlay_ip`, the address of the VPN interface.
+pub fn listen(config: &Config, overlay_ip: Option<IpAddr>, handler: CommandHandler) -> Result<SocketAddr, Error> {
+    if config.admin_keys.is_empty() {
+        return Err(Error::InvalidConfig("Remote control requires at least one admin key"));
+    }
+    let admin_keys: Arc<[Ed25519PublicKey]> =
+        config.admin_keys.iter().map(|key| Crypto::parse_public_key(key)).collect::<Result<Vec<_>, _>>()?.into();
+    let addr = parse_listen(&config.listen, DEFAULT_REMOTE_CONTROL_PORT)
+        .map_err(|_| Error::InvalidConfigValue("Invalid remote control address", config.listen.clone()))?;
+    if Some(addr.ip()) != overlay_ip {
+        return Err(Error::InvalidConfigValue(
+            "Remote control must listen on the address of the VPN interface",
+            config.listen.clone(),
+        ));
+    }
+    let listener = TcpListener::bind(addr).map_err(|e| Error::SocketIo("Failed to open remote control port", e))?;
+    let addr = listener.local_addr().map_err(|e| Error::SocketIo("Failed to open remote control port", e))?;
+    let connections = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                        warn!("Too many remote control connections, closing the new one");
+                        continue;
+                    }
+                    // A slow client must not block the port for others until its timeout
+                    let admin_keys = admin_keys.clone();
+                    let handler = handler.clone();
+                    let connections = connections.clone();
+                    thread::spawn(move || {
+                        if let Err(err) = handle_connection(stream, &admin_keys, &handler) {
+                            warn!("Failed to handle remote control request: {}", err)
+                        }
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+                Err(err) => {
+                    // Errors like running out of file descriptors are temporary
+                    error!("Failed to accept remote control connection: {}", err);
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }
+        }
+    });
+    Ok(addr)
+}
+
+fn signed_message(challenge: &str, command: &str) -> String {
+    format!("{} {}", challenge, command)
+}
+
+/// Checks that the auth line carries a valid signature of an admin key
+fn verify(admin_keys: &[Ed25519PublicKey], challenge: &str, auth: &str, command: &str) -> Result<(), &'static str> {
+    let mut parts = auth.split_whitespace();
+    let (key, sig) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some("auth"), Some(key), Some(sig), None) => (key, sig),
+        _ => return Err("Authentication missing"),
+    };
+    let key = Crypto::parse_public_key(key).map_err(|_| "Invalid admin key")?;
+    if !admin_keys.contains(&key) {
+        return Err("Not an admin key");
+    }
+    let sig = from_base62(sig).map_err(|_| "Invalid signature")?;
+    signature::UnparsedPublicKey::new(&signature::ED25519, &key)
+        .verify(signed_message(challenge, command).as_bytes(), &sig)
+        .map_err(|_| "Invalid signature")
+}
+
+fn handle_connection(
+    stream: TcpStream, admin_keys: &[Ed25519PublicKey], handler: &CommandHandler,
+) -> Result<(), io::Error> {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(COMMAND_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut challenge = [0; CHALLENGE_LEN];
+    SystemRandom::new().fill(&mut challenge).map_err(|_| io::Error::new(io::ErrorKind::Other, "No randomness"))?;
+    let challenge = to_base62(&challenge);
+    writeln!(writer, "challenge {}", challenge)?;
+    let mut auth = String::new();
+    read_limited_line(&mut reader, &mut auth)?;
+    let mut command = String::new();
+    read_limited_line(&mut reader, &mut command)?;
+    let result = match verify(admin_keys, &challenge, auth.trim(), command.trim()) {
+        Ok(()) => handler.execute(&command),
+        Err(msg) => {
+            warn!("Rejected remote control command from {}: {}", addr_nice(peer), msg);
+            Err(msg.to_string())
+        }
+    };
+    write_response(&mut writer, result)
+}
+
+/// Sends a command to the remote control port of a node and waits for the result
+///
+/// The command is signed with the given private admin key.
+pub fn send_command(addr: &str, admin_key: &str, command: &ControlCommand) -> Result<String, Error> {
+    let key_pair = Crypto::parse_private_key(admin_key)?;
+    let addrs = match parse_listen(addr, DEFAULT_REMOTE_CONTROL_PORT) {
+        Ok(addr) => smallvec![addr],
+        Err(_) if addr.contains(':') => resolve(addr)?,
+        Err(_) => resolve((addr, DEFAULT_REMOTE_CONTROL_PORT))?,
+    };
+    let stream = TcpStream::connect_timeout(&addrs[0], COMMAND_TIMEOUT)
+        .map_err(|e| Error::SocketIo("Failed to connect to remote control port", e))?;
+    stream
+        .set_read_timeout(Some(COMMAND_TIMEOUT * 2))
+        .map_err(|e| Error::SocketIo("Failed to set timeout on remote control connection", e))?;
+    let mut reader = BufReader::new(stream.try_clone().map_err(|e| Error::SocketIo("Failed to use connection", e))?);
+    let mut writer = stream;
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|e| Error::SocketIo("Failed to read challenge", e))?;
+    let challenge = match line.trim().strip_prefix("challenge ") {
+        Some(challenge) => challenge.to_string(),
+        None => return Err(Error::Control("Not a remote control port".to_string())),
+    };
+    let command = command.to_string();
+    let sig = key_pair.sign(signed_message(&challenge, &command).as_bytes());
+    write!(writer, "auth {} {}\n{}\n", to_base62(key_pair.public_key().as_ref()), to_base62(sig.as_ref()), command)
+        .map_err(|e| Error::SocketIo("Failed to send remote control command", e))?;
+    read_response(&mut reader)
+}
+
+#[test]
+fn remote_command_signature() {
+    let (privkey, pubkey) = Crypto::generate_keypair(None);
+    let key_pair = Crypto::parse_private_key(&privkey).unwrap();
+    let admin_keys = [Crypto::parse_public_key(&pubkey).unwrap()];
+    let sig = to_base62(key_pair.sign(signed_message("abc", "peers").as_bytes()).as_ref());
+    let auth = format!("auth {} {}", pubkey, sig);
+    assert_eq!(verify(&admin_keys, "abc", &auth, "peers"), Ok(()));
+    // Signatures are bound to the challenge and the command
+    assert_eq!(verify(&admin_keys, "abd", &auth, "peers"), Err("Invalid signature"));
+    assert_eq!(verify(&admin_keys, "abc", &auth, "bans"), Err("Invalid signature"));
+    let (_, other) = Crypto::generate_keypair(None);
+    assert_eq!(verify(&[Crypto::parse_public_key(&other).unwrap()], "abc", &auth, "peers"), Err("Not an admin key"));
+    assert_eq!(verify(&admin_keys, "abc", "peers", "peers"), Err("Authentication missing"));
+}
+
+#[test]
+fn remote_control_port() {
+    use crate::control::ControlServer;
+    let (privkey, pubkey) = Crypto::generate_keypair(None);
+    let server = ControlServer::new();
+    let config = Config { listen: "127.0.0.1:0".to_string(), admin_keys: vec![pubkey] };
+    let overlay_ip = Some("127.0.0.1".parse().unwrap());
+    // Only the address of the VPN interface can be used
+    let any = Config { listen: "0.0.0.0:0".to_string(), ..config.clone() };
+    assert!(listen(&any, overlay_ip, server.handler()).is_err());
+    assert!(listen(&config, None, server.handler()).is_err());
+    let addr = listen(&config, overlay_ip, server.handler()).unwrap().to_string();
+    let responder = thread::spawn(move || loop {
+        if let Some(request) = server.next_request() {
+            assert_eq!(request.command, ControlCommand::Peers);
+            request.reply(Ok("node1 established 5 1\n".to_string()));
+            break;
+        }
+        thread::sleep(std::time::Duration::from_millis(10));
+    });
+    let (other, _) = Crypto::generate_keypair(None);
+    match send_command(&addr, &other, &ControlCommand::Peers) {
+        Err(Error::Control(msg)) => assert_eq!(msg, "Not an admin key"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert_eq!(send_command(&addr, &privkey, &ControlCommand::Peers).unwrap(), "node1 established 5 1\n");
+    responder.join().unwrap();
+    let config = Config { listen: "127.0.0.1:0".to_string(), admin_keys: vec![] };
+    assert!(listen(&config, overlay_ip, ControlServer::new().handler()).is_err());
+}
+
+#[test]
+fn remote_connection_limit() {
+    use crate::control::ControlServer;
+    let (_, pubkey) = Crypto::generate_keypair(None);
+    let server = ControlServer::new();
+    let config = Config { listen: "127.0.0.1:0".to_string(), admin_keys: vec![pubkey] };
+    let addr = listen(&config, Some("127.0.0.1".parse().unwrap()), server.handler()).unwrap();
+    let mut open = vec![];
+    for _ in 0..MAX_CONNECTIONS {
+        let mut reader = BufReader::new(TcpStream::connect(addr).unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("challenge "));
+        open.push(reader);
+    }
+    // Further connections are closed without a challenge
+    let mut line = String::new();
+    BufReader::new(TcpStream::connect(addr).unwrap()).read_line(&mut line).unwrap();
+    assert_eq!(line, "");
+}
@@ -0,0 +1,177 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Reordering of payload from peers
+//!
+//! Nodes with a `reorder-window` ask their peers to number the payload they send, like duplicated packets. Packets
+//! that arrive out of order are held back until the missing packets arrive, so that the inner TCP does not see the
+//! reordering caused by multiple paths or LTE links as loss. A gap is skipped once it is older than the window or
+//! too many packets are waiting behind it.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Number of packets that can wait behind a gap before the gap is skipped
+const MAX_WAITING: usize = 64;
+
+/// Restores the order of the numbered packets from one peer
+pub struct ReorderBuffer {
+    /// Maximal time to wait for a missing packet
+    window: Duration,
+    /// Sequence number of the next packet that can be delivered
+    next: Option<u32>,
+    /// Slot n holds the packet with the sequence number `next + n` if it has arrived early
+    slots: VecDeque<Option<Vec<u8>>>,
+    /// Packets that are in order now and can be delivered
+    ready: VecDeque<Vec<u8>>,
+    /// Time at which the current gap is skipped
+    deadline: Option<Instant>,
+}
+
+impl ReorderBuffer {
+    pub fn new(window: Duration) -> Self {
+        Self { window, next: None, slots: VecDeque::new(), ready: VecDeque::new(), deadline: None }
+    }
+
+    /// Moves on to the next sequence number, releasing the packet waiting for it if there is one
+    fn advance(&mut self) {
+        self.next = self.next.map(|next| next.wrapping_add(1));
+        if let Some(Some(data)) = self.slots.pop_front() {
+            self.ready.push_back(data)
+        }
+    }
+
+    /// Releases the packets that follow the next sequence number without a gap
+    fn release(&mut self) {
+        while let Some(Some(_)) = self.slots.front() {
+            self.advance()
+        }
+        if self.slots.iter().all(Option::is_none) {
+            self.slots.clear();
+            self.deadline = None;
+        }
+    }
+
+    /// Handles a numbered packet from the peer
+    ///
+    /// Returns `true` if the packet should be delivered right away, before the packets from `pop_ready`. Otherwise
+    /// the packet has been copied into the buffer.
+    pub fn receive(&mut self, seq: u32, data: &[u8], now: Instant) -> bool {
+        // HOT PATH
+        let next = match self.next {
+            Some(next) => next,
+            None => {
+                self.next = Some(seq.wrapping_add(1));
+                return true;
+            }
+        };
+        let mut diff = seq.wrapping_sub(next) as i32;
+        if diff < 0 {
+            // COLD PATH
+            // The gap has already been skipped, so the packet is delivered as late as it is
+            return true;
+        }
+        if diff == 0 {
+            self.advance();
+            if !self.slots.is_empty() {
+                // COLD PATH
+                self.release();
+            }
+            return true;
+        }
+        // COLD PATH
+        while diff as usize >= MAX_WAITING {
+            self.advance();
+            diff -= 1;
+        }
+        let pos = diff as usize;
+        if self.slots.len() <= pos {
+            self.slots.resize(pos + 1, None);
+        }
+        self.slots[pos] = Some(data.to_vec());
+        if self.deadline.is_none() {
+            self.deadline = Some(now + self.window);
+        }
+        self.release();
+        false
+    }
+
+    /// Time at which the current gap will be skipped, if packets are waiting
+    #[inline]
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Skips the current gap if it has been waited for longer than the window
+    pub fn flush(&mut self, now: Instant) {
+        match self.deadline {
+            Some(deadline) if deadline <= now => (),
+            _ => return,
+        }
+        while let Some(None) = self.slots.front() {
+            self.advance()
+        }
+        self.release();
+        if !self.slots.is_empty() {
+            self.deadline = Some(now + self.window);
+        }
+    }
+
+    /// Returns the next packet that is in order now
+    #[inline]
+    pub fn pop_ready(&mut self) -> Option<Vec<u8>> {
+        self.ready.pop_front()
+    }
+}
+
+#[test]
+fn reorder_packets() {
+    let now = Instant::now();
+    let window = Duration::from_millis(50);
+    let mut buffer = ReorderBuffer::new(window);
+    assert!(buffer.receive(10, &[10], now));
+    assert!(buffer.receive(11, &[11], now));
+    // Packets after a gap wait until it is filled
+    assert!(!buffer.receive(13, &[13], now));
+    assert!(!buffer.receive(14, &[14], now));
+    assert_eq!(buffer.pop_ready(), None);
+    assert_eq!(buffer.deadline(), Some(now + window));
+    assert!(buffer.receive(12, &[12], now));
+    assert_eq!(buffer.pop_ready(), Some(vec![13]));
+    assert_eq!(buffer.pop_ready(), Some(vec![14]));
+    assert_eq!(buffer.pop_ready(), None);
+    assert_eq!(buffer.deadline(), None);
+    // Gaps are skipped after the window
+    assert!(!buffer.receive(17, &[17], now));
+    assert!(!buffer.receive(16, &[16], now));
+    buffer.flush(now + Duration::from_millis(10));
+    assert_eq!(buffer.pop_ready(), None);
+    buffer.flush(now + window);
+    assert_eq!(buffer.pop_ready(), Some(vec![16]));
+    assert_eq!(buffer.pop_ready(), Some(vec![17]));
+    assert_eq!(buffer.deadline(), None);
+    // Late packets are delivered right away
+    assert!(buffer.receive(15, &[15], now));
+    assert!(buffer.receive(18, &[18], now));
+}
+
+#[test]
+fn reorder_limit() {
+    let now = Instant::now();
+    let mut buffer = ReorderBuffer::new(Duration::from_secs(1));
+    assert!(buffer.receive(u32::MAX, &[0], now));
+    for seq in 1..MAX_WAITING as u32 {
+        assert!(!buffer.receive(seq, &[seq as u8], now));
+    }
+    assert_eq!(buffer.pop_ready(), None);
+    // Too many waiting packets skip the gap
+    assert!(!buffer.receive(MAX_WAITING as u32, &[MAX_WAITING as u8], now));
+    for seq in 1..=MAX_WAITING as u32 {
+        assert_eq!(buffer.pop_ready(), Some(vec![seq as u8]));
+    }
+    assert_eq!(buffer.pop_ready(), None);
+    assert!(buffer.receive(MAX_WAITING as u32 + 1, &[0], now));
+}
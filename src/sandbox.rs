@@ -0,0 +1,484 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Restricts the process after initialization
+//!
+//! Once the device and the sockets are set up and privileges have been dropped, the process only
+//! needs a small set of syscalls and file system paths. A seccomp filter denies all other syscalls
+//! and Landlock rules deny access to all other paths, so a compromised message parser can not be
+//! used to take over the host. Both restrictions apply to all threads and child processes.
+
+use std::path::{Path, PathBuf};
+
+use crate::{config::Config as MainConfig, error::Error};
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Config {
+    /// Only allow the syscalls that are needed after initialization
+    pub seccomp: bool,
+    /// Only allow access to the paths that are needed after initialization
+    pub landlock: bool,
+    /// Additional paths that can be read and written with Landlock
+    pub paths: Vec<String>,
+}
+
+/// Paths that are always readable, e.g. for name resolution and time zones
+const READ_PATHS: [&str; 5] = ["/etc", "/proc", "/sys", "/dev/urandom", "/usr/share/zoneinfo"];
+/// Paths that are readable and executable when commands are run
+const EXEC_PATHS: [&str; 6] = ["/bin", "/sbin", "/usr", "/lib", "/lib64", "/dev/null"];
+
+/// Whether the node runs external commands after initialization
+fn needs_exec(config: &MainConfig) -> bool {
+    config.hook.is_some()
+        || !config.hooks.is_empty()
+        || config.ifdown.is_some()
+        || config.docker.is_some()
+        || config.network_manager.is_some()
+        || config.beacon_store.as_ref().map(|s| s.starts_with('|')).unwrap_or(false)
+        || config.beacon_load.as_ref().map(|s| s.starts_with('|')).unwrap_or(false)
+}
+
+/// Paths that are written after initialization
+fn write_paths(config: &MainConfig) -> Vec<PathBuf> {
+    let mut paths = vec![];
+    let files = [
+        &config.beacon_store,
+        &config.beacon_load,
+        &config.dhcp.as_ref().and_then(|d| d.lease_file.clone()),
+        &config.prometheus_file,
+    ];
+    for file in files.iter().filter_map(|f| f.as_ref()) {
+        if file.starts_with('|') {
+            continue;
+        }
+        // Files are replaced, so the whole directory is needed
+        match Path::new(file).parent() {
+            Some(dir) if dir != Path::new("") => paths.push(dir.to_path_buf()),
+            _ => paths.push(PathBuf::from(".")),
+        }
+    }
+    if let Some(network_manager) = &config.network_manager {
+        paths.push(PathBuf::from(network_manager.dir()));
+    }
+    if let Some(state_dir) = &config.state_dir {
+        paths.push(PathBuf::from(state_dir));
+    }
+    if config.fix_rp_filter || config.ip_forwarding {
+        // Changed sysctls are kept while running and restored on shutdown
+        paths.push(PathBuf::from("/proc/sys/net"));
+    }
+    paths.extend(config.hardening.paths.iter().map(PathBuf::from));
+    paths
+}
+
+/// Applies the configured restrictions to the process
+pub fn apply(config: &MainConfig) -> Result<(), Error> {
+    apply_with_networks(config, &[])
+}
+
+/// Applies the restrictions of the main config, allowing what the additional networks in the process need
+pub fn apply_with_networks(config: &MainConfig, networks: &[MainConfig]) -> Result<(), Error> {
+    if !config.hardening.seccomp && !config.hardening.landlock {
+        return Ok(());
+    }
+    let exec = needs_exec(config) || networks.iter().any(needs_exec);
+    if exec {
+        info!("Commands are executed with the same restrictions as the node");
+    }
+    imp::no_new_privs()?;
+    if config.hardening.landlock {
+        let mut read: Vec<&Path> = READ_PATHS.iter().map(Path::new).collect();
+        let mut execute = vec![];
+        if exec {
+            execute.extend(EXEC_PATHS.iter().map(Path::new));
+        } else {
+            read.extend(EXEC_PATHS.iter().map(Path::new));
+        }
+        let mut write = write_paths(config);
+        write.extend(networks.iter().flat_map(write_paths));
+        if imp::landlock(&read, &execute, &write)? {
+            info!("Restricted file system access with Landlock");
+        }
+    }
+    if config.hardening.seccomp {
+        imp::seccomp(exec)?;
+        info!("Restricted syscalls with seccomp");
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use super::*;
+
+    pub fn no_new_privs() -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn landlock(_read: &[&Path], _exec: &[&Path], _write: &[PathBuf]) -> Result<bool, Error> {
+        warn!("Landlock is only supported on Linux");
+        Ok(false)
+    }
+
+    pub fn seccomp(_exec: bool) -> Result<(), Error> {
+        Err(Error::InvalidConfig("Seccomp is only supported on Linux"))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::*;
+    use libc::c_long;
+    use std::{
+        fs::{File, OpenOptions},
+        io, mem,
+        os::unix::{
+            fs::OpenOptionsExt,
+            io::{AsRawFd, FromRawFd},
+        },
+    };
+
+    // Landlock ABI version 1, the syscall numbers are the same on all architectures
+    const SYS_LANDLOCK_CREATE_RULESET: c_long = 444;
+    const SYS_LANDLOCK_ADD_RULE: c_long = 445;
+    const SYS_LANDLOCK_RESTRICT_SELF: c_long = 446;
+    const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+    const ACCESS_EXECUTE: u64 = 1 << 0;
+    const ACCESS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_READ_DIR: u64 = 1 << 3;
+    const ACCESS_FILE: u64 = ACCESS_EXECUTE | ACCESS_WRITE_FILE | ACCESS_READ_FILE;
+    const ACCESS_ALL: u64 = (1 << 13) - 1;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    pub fn no_new_privs() -> Result<(), Error> {
+        if unsafe {
+            libc::prctl(
+                libc::PR_SET_NO_NEW_PRIVS,
+                1 as libc::c_ulong,
+                0 as libc::c_ulong,
+                0 as libc::c_ulong,
+                0 as libc::c_ulong,
+            )
+        } != 0
+        {
+            return Err(Error::SandboxIo("Failed to set no_new_privs", io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    fn add_rule(ruleset: &File, path: &Path, access: u64) -> Result<(), Error> {
+        let file = match OpenOptions::new().read(true).custom_flags(libc::O_PATH | libc::O_CLOEXEC).open(path) {
+            Ok(file) => file,
+            Err(_) => {
+                debug!("Skipping missing path {}", path.display());
+                return Ok(());
+            }
+        };
+        let is_dir = file.metadata().map(|m| m.is_dir()).unwrap_or(false);
+        let attr = PathBeneathAttr {
+            allowed_access: if is_dir { access } else { access & ACCESS_FILE },
+            parent_fd: file.as_raw_fd(),
+        };
+        let res =
+            unsafe { libc::syscall(SYS_LANDLOCK_ADD_RULE, ruleset.as_raw_fd(), LANDLOCK_RULE_PATH_BENEATH, &attr, 0) };
+        if res != 0 {
+            return Err(Error::SandboxIo("Failed to add Landlock rule", io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Restricts the file system access, returns false if the kernel does not support Landlock
+    pub fn landlock(read: &[&Path], exec: &[&Path], write: &[PathBuf]) -> Result<bool, Error> {
+        let attr = RulesetAttr { handled_access_fs: ACCESS_ALL };
+        let fd = unsafe {
+            libc::syscall(SYS_LANDLOCK_CREATE_RULESET, &attr as *const RulesetAttr, mem::size_of::<RulesetAttr>(), 0)
+        };
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => {
+                    warn!("Landlock is not supported by the kernel, file system access is not restricted");
+                    Ok(false)
+                }
+                _ => Err(Error::SandboxIo("Failed to create Landlock ruleset", err)),
+            };
+        }
+        let ruleset = unsafe { File::from_raw_fd(fd as i32) };
+        for path in read {
+            add_rule(&ruleset, path, ACCESS_READ_FILE | ACCESS_READ_DIR)?;
+        }
+        for path in exec {
+            add_rule(&ruleset, path, ACCESS_READ_FILE | ACCESS_READ_DIR | ACCESS_EXECUTE)?;
+        }
+        for path in write {
+            add_rule(&ruleset, path, ACCESS_ALL & !ACCESS_EXECUTE)?;
+        }
+        if unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset.as_raw_fd(), 0) } != 0 {
+            return Err(Error::SandboxIo("Failed to apply Landlock rules", io::Error::last_os_error()));
+        }
+        Ok(true)
+    }
+
+    #[repr(C)]
+    #[derive(Debug, PartialEq)]
+    pub struct SockFilter {
+        pub code: u16,
+        pub jt: u8,
+        pub jf: u8,
+        pub k: u32,
+    }
+
+    #[repr(C)]
+    struct SockFprog {
+        len: u16,
+        filter: *const SockFilter,
+    }
+
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_JEQ_K: u16 = 0x15;
+    const BPF_RET_K: u16 = 0x06;
+    const SECCOMP_SET_MODE_FILTER: libc::c_uint = 1;
+    const SECCOMP_FILTER_FLAG_TSYNC: libc::c_uint = 1;
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+    const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+    const SYS_CLONE3: c_long = 435;
+    #[cfg(target_arch = "x86_64")]
+    const SYS_RSEQ: c_long = 334;
+    #[cfg(target_arch = "aarch64")]
+    const SYS_RSEQ: c_long = 293;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// Syscalls that are used by the node itself
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    const BASE_SYSCALLS: &[c_long] = &[
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_readv,
+        libc::SYS_writev,
+        libc::SYS_pread64,
+        libc::SYS_pwrite64,
+        libc::SYS_close,
+        libc::SYS_lseek,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_openat,
+        libc::SYS_fcntl,
+        libc::SYS_dup,
+        libc::SYS_dup3,
+        libc::SYS_ioctl,
+        libc::SYS_fsync,
+        libc::SYS_fdatasync,
+        libc::SYS_ftruncate,
+        libc::SYS_unlinkat,
+        libc::SYS_renameat,
+        libc::SYS_getdents64,
+        libc::SYS_readlinkat,
+        libc::SYS_faccessat,
+        libc::SYS_socket,
+        libc::SYS_bind,
+        libc::SYS_connect,
+        libc::SYS_getsockname,
+        libc::SYS_getpeername,
+        libc::SYS_setsockopt,
+        libc::SYS_getsockopt,
+        libc::SYS_sendto,
+        libc::SYS_recvfrom,
+        libc::SYS_sendmsg,
+        libc::SYS_recvmsg,
+        libc::SYS_sendmmsg,
+        libc::SYS_recvmmsg,
+        libc::SYS_shutdown,
+        libc::SYS_accept4,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        libc::SYS_ppoll,
+        libc::SYS_pselect6,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        libc::SYS_brk,
+        libc::SYS_futex,
+        libc::SYS_set_robust_list,
+        libc::SYS_set_tid_address,
+        SYS_RSEQ,
+        libc::SYS_sched_yield,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_nanosleep,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_getres,
+        libc::SYS_gettimeofday,
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_rt_sigtimedwait,
+        libc::SYS_sigaltstack,
+        libc::SYS_tgkill,
+        libc::SYS_getpid,
+        libc::SYS_gettid,
+        libc::SYS_getuid,
+        libc::SYS_geteuid,
+        libc::SYS_getgid,
+        libc::SYS_getegid,
+        libc::SYS_uname,
+        libc::SYS_getrandom,
+        libc::SYS_prlimit64,
+        libc::SYS_clone,
+        SYS_CLONE3,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        libc::SYS_restart_syscall,
+    ];
+
+    /// Legacy syscalls that only exist on some architectures
+    #[cfg(target_arch = "x86_64")]
+    const ARCH_SYSCALLS: &[c_long] = &[
+        libc::SYS_open,
+        libc::SYS_stat,
+        libc::SYS_lstat,
+        libc::SYS_access,
+        libc::SYS_poll,
+        libc::SYS_select,
+        libc::SYS_epoll_wait,
+        libc::SYS_pipe,
+        libc::SYS_dup2,
+        libc::SYS_rename,
+        libc::SYS_unlink,
+        libc::SYS_readlink,
+        libc::SYS_arch_prctl,
+        libc::SYS_fork,
+        libc::SYS_vfork,
+        libc::SYS_getdents,
+        libc::SYS_time,
+    ];
+    #[cfg(target_arch = "aarch64")]
+    const ARCH_SYSCALLS: &[c_long] = &[];
+
+    /// Syscalls that are used by the async runtime when it is started
+    #[cfg(all(feature = "async-runtime", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    const ASYNC_SYSCALLS: &[c_long] = &[libc::SYS_epoll_create1, libc::SYS_eventfd2];
+
+    /// Syscalls that are used when running commands
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    const EXEC_SYSCALLS: &[c_long] = &[
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        libc::SYS_wait4,
+        libc::SYS_waitid,
+        libc::SYS_pipe2,
+        libc::SYS_setpgid,
+        libc::SYS_getppid,
+        libc::SYS_getpgid,
+        libc::SYS_setsid,
+        libc::SYS_kill,
+        libc::SYS_umask,
+        libc::SYS_chdir,
+        libc::SYS_fchdir,
+        libc::SYS_getcwd,
+        libc::SYS_sysinfo,
+        libc::SYS_statfs,
+        libc::SYS_fstatfs,
+        libc::SYS_prctl,
+    ];
+
+    /// Builds a filter that allows the given syscalls and denies all others with EPERM
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn build_filter(syscalls: &[c_long]) -> Vec<SockFilter> {
+        let mut filter = vec![
+            // Syscall numbers differ between architectures, so other architectures are killed
+            SockFilter { code: BPF_LD_W_ABS, jt: 0, jf: 0, k: 4 },
+            SockFilter { code: BPF_JEQ_K, jt: 1, jf: 0, k: AUDIT_ARCH },
+            SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_KILL_PROCESS },
+            SockFilter { code: BPF_LD_W_ABS, jt: 0, jf: 0, k: 0 },
+        ];
+        for nr in syscalls {
+            filter.push(SockFilter { code: BPF_JEQ_K, jt: 0, jf: 1, k: *nr as u32 });
+            filter.push(SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_ALLOW });
+        }
+        filter.push(SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_ERRNO | libc::EPERM as u32 });
+        filter
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn seccomp(exec: bool) -> Result<(), Error> {
+        let mut syscalls = BASE_SYSCALLS.to_vec();
+        syscalls.extend_from_slice(ARCH_SYSCALLS);
+        #[cfg(feature = "async-runtime")]
+        syscalls.extend_from_slice(ASYNC_SYSCALLS);
+        if exec {
+            syscalls.extend_from_slice(EXEC_SYSCALLS);
+        }
+        let filter = build_filter(&syscalls);
+        let prog = SockFprog { len: filter.len() as u16, filter: filter.as_ptr() };
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                SECCOMP_SET_MODE_FILTER,
+                SECCOMP_FILTER_FLAG_TSYNC,
+                &prog as *const SockFprog,
+            )
+        };
+        if res != 0 {
+            return Err(Error::SandboxIo("Failed to install seccomp filter", io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub fn seccomp(_exec: bool) -> Result<(), Error> {
+        Err(Error::InvalidConfig("Seccomp is not supported on this architecture"))
+    }
+}
+
+#[test]
+fn hardening_paths() {
+    use crate::config::DhcpConfig;
+    let mut config = MainConfig { beacon_store: Some("|beacon-upload".to_string()), ..MainConfig::default() };
+    assert!(needs_exec(&config));
+    assert!(write_paths(&config).is_empty());
+    config.beacon_store = Some("/var/lib/vpncloud/beacon".to_string());
+    config.dhcp = Some(DhcpConfig {
+        server: "10.0.0.1/24".to_string(),
+        range: "10.0.0.100-10.0.0.200".to_string(),
+        router: None,
+        dns: vec![],
+        lease_time: None,
+        lease_file: Some("leases".to_string()),
+    });
+    config.hardening.paths = vec!["/tmp".to_string()];
+    assert!(!needs_exec(&config));
+    assert_eq!(
+        write_paths(&config),
+        vec![PathBuf::from("/var/lib/vpncloud"), PathBuf::from("."), PathBuf::from("/tmp")]
+    );
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[test]
+fn seccomp_filter() {
+    let filter = imp::build_filter(&[libc::SYS_read, libc::SYS_write]);
+    assert_eq!(filter.len(), 4 + 2 * 2 + 1);
+    assert_eq!(filter[4], imp::SockFilter { code: 0x15, jt: 0, jf: 1, k: libc::SYS_read as u32 });
+    assert_eq!(filter[8], imp::SockFilter { code: 0x06, jt: 0, jf: 0, k: 0x0005_0000 | libc::EPERM as u32 });
+}
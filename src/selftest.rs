@@ -0,0 +1,178 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Self-test of the environment, see `--self-test`
+//!
+//! The report lists the result of every check so it can be attached to support tickets.
+
+use std::{fmt, fs::OpenOptions, net::UdpSocket};
+
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+
+use crate::{
+    caps,
+    config::{Config, DEFAULT_PORT},
+    device::TunTapDevice,
+    net::parse_listen,
+    util::bytes_to_hex,
+};
+
+/// A known answer test of an AEAD algorithm: key, nonce, additional data, plaintext and ciphertext with tag
+struct TestVector {
+    name: &'static str,
+    algorithm: &'static aead::Algorithm,
+    key: &'static [u8],
+    nonce: [u8; 12],
+    aad: &'static [u8],
+    plaintext: &'static [u8],
+    ciphertext: &'static str,
+}
+
+const CHACHA20_KEY: [u8; 32] = [
+    0x80, 0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x8b, 0x8c, 0x8d, 0x8e, 0x8f, 0x90, 0x91, 0x92,
+    0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0x9b, 0x9c, 0x9d, 0x9e, 0x9f,
+];
+
+/// Test case 2 and 14 of the GCM specification and section 2.8.2 of RFC 8439
+const TEST_VECTORS: [TestVector; 3] = [
+    TestVector {
+        name: "AES128",
+        algorithm: &aead::AES_128_GCM,
+        key: &[0; 16],
+        nonce: [0; 12],
+        aad: &[],
+        plaintext: &[0; 16],
+        ciphertext: "0388dace60b6a392f328c2b971b2fe78ab6e47d42cec13bdf53a67b21257bddf",
+    },
+    TestVector {
+        name: "AES256",
+        algorithm: &aead::AES_256_GCM,
+        key: &[0; 32],
+        nonce: [0; 12],
+        aad: &[],
+        plaintext: &[0; 16],
+        ciphertext: "cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919",
+    },
+    TestVector {
+        name: "CHACHA20",
+        algorithm: &aead::CHACHA20_POLY1305,
+        key: &CHACHA20_KEY,
+        nonce: [0x07, 0x00, 0x00, 0x00, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47],
+        aad: &[0x50, 0x51, 0x52, 0x53, 0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7],
+        plaintext: b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, \
+                     sunscreen would be it.",
+        ciphertext: "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d63dbea45e8ca9671282fafb69da92728b\
+                     1a71de0a9e060b2905d6a5b67ecd3b3692ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc\
+                     3ff4def08e4b7a9de576d26586cec64b61161ae10b594f09e26a7e902ecbd0600691",
+    },
+];
+
+pub struct Check {
+    pub name: String,
+    pub result: Result<String, String>,
+}
+
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.result.is_ok())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        writeln!(formatter, "VpnCloud v{} self-test:", env!("CARGO_PKG_VERSION"))?;
+        for check in &self.checks {
+            match check.result {
+                Ok(ref msg) => writeln!(formatter, "  PASS  {:<20} {}", check.name, msg)?,
+                Err(ref msg) => writeln!(formatter, "  FAIL  {:<20} {}", check.name, msg)?,
+            }
+        }
+        writeln!(formatter, "Result: {}", if self.passed() { "all checks passed" } else { "some checks failed" })
+    }
+}
+
+fn check_vector(vector: &TestVector) -> Result<String, String> {
+    let key = LessSafeKey::new(UnboundKey::new(vector.algorithm, vector.key).map_err(|_| "invalid key")?);
+    let mut data = vector.plaintext.to_vec();
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(vector.nonce), Aad::from(vector.aad), &mut data)
+        .map_err(|_| "encryption failed")?;
+    if bytes_to_hex(&data) != vector.ciphertext {
+        return Err("ciphertext does not match test vector".to_string());
+    }
+    let plaintext = key
+        .open_in_place(Nonce::assume_unique_for_key(vector.nonce), Aad::from(vector.aad), &mut data)
+        .map_err(|_| "decryption failed")?;
+    if plaintext != vector.plaintext {
+        return Err("decrypted data does not match".to_string());
+    }
+    Ok("test vector matches".to_string())
+}
+
+fn check_device(config: &Config) -> Result<String, String> {
+    let path = config.device_path.as_deref().unwrap_or_else(|| TunTapDevice::default_path(config.device_type));
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(_) => Ok(format!("{} is accessible", path)),
+        Err(err) => Err(format!("failed to open {}: {}", path, err)),
+    }
+}
+
+fn check_capabilities() -> Result<String, String> {
+    if caps::is_root() {
+        return Ok("running as root".to_string());
+    }
+    match caps::has(caps::CAP_NET_ADMIN) {
+        Some(true) => Ok("CAP_NET_ADMIN is available".to_string()),
+        Some(false) => Err("needs root permissions or the CAP_NET_ADMIN capability".to_string()),
+        None => Err("failed to determine capabilities".to_string()),
+    }
+}
+
+fn check_listen(config: &Config) -> Result<String, String> {
+    if config.listen.starts_with("ws://") {
+        return Ok("websocket proxy, nothing to check".to_string());
+    }
+    let addr = parse_listen(&config.listen, DEFAULT_PORT).map_err(|err| err.to_string())?;
+    match UdpSocket::bind(addr) {
+        Ok(_) => Ok(format!("{} is bindable", addr)),
+        Err(err) => Err(format!("failed to bind {}: {}", addr, err)),
+    }
+}
+
+/// Runs all checks for the given config
+pub fn run(config: &Config) -> Report {
+    let mut checks = vec![];
+    for vector in &TEST_VECTORS {
+        checks.push(Check { name: format!("crypto {}", vector.name), result: check_vector(vector) });
+    }
+    checks.push(Check { name: "device".to_string(), result: check_device(config) });
+    checks.push(Check { name: "capabilities".to_string(), result: check_capabilities() });
+    checks.push(Check { name: "listen port".to_string(), result: check_listen(config) });
+    Report { checks }
+}
+
+#[test]
+fn self_test_vectors() {
+    for vector in &TEST_VECTORS {
+        assert!(check_vector(vector).is_ok(), "{}", vector.name);
+    }
+}
+
+#[test]
+fn self_test_report() {
+    let config = Config {
+        listen: "127.0.0.1:0".to_string(),
+        device_path: Some("/nonexisting".to_string()),
+        ..Config::default()
+    };
+    let report = run(&config);
+    assert!(!report.passed());
+    let text = report.to_string();
+    assert!(text.contains("PASS  crypto CHACHA20"));
+    assert!(text.contains("PASS  listen port"));
+    assert!(text.contains("FAIL  device"));
+}
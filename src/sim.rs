@@ -0,0 +1,323 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Simulation of multiple nodes in one process (feature `sim`)
+//!
+//! The nodes are connected by a virtual network with configurable loss, latency, jitter and NAT
+//! behavior. Time is simulated and all random decisions are derived from a seed, so scenarios
+//! like hole punching or failover can be tested deterministically.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap},
+    net::SocketAddr,
+};
+
+use crate::{
+    cloud::GenericCloud,
+    config::Config,
+    device::MockDevice,
+    net::MockSocket,
+    payload::{Frame, Packet, Protocol},
+    util::{MockTimeSource, Time, TimeSource},
+};
+
+/// Time after which NAT mappings expire in seconds
+const NAT_TIMEOUT: Time = 300;
+
+pub type SimNode<P> = GenericCloud<MockDevice, P, MockSocket, MockTimeSource>;
+pub type TapSimulator = Simulator<Frame>;
+pub type TunSimulator = Simulator<Packet>;
+
+/// Message in flight: delivery time, sequence number, source, destination and data
+type InFlight = Reverse<(u64, u64, SocketAddr, SocketAddr, Vec<u8>)>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Conditions {
+    /// Fraction of messages that are lost (0.0 - 1.0)
+    pub loss: f64,
+    /// Base latency in milliseconds
+    pub latency: u64,
+    /// Maximal additional random latency in milliseconds
+    pub jitter: u64,
+}
+
+impl Default for Conditions {
+    fn default() -> Self {
+        Self { loss: 0.0, latency: 1, jitter: 0 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Nat {
+    /// Node is reachable by everyone
+    None,
+    /// Node only receives messages from addresses it has sent a message to recently
+    Restricted,
+}
+
+struct NodeState<P: Protocol> {
+    node: SimNode<P>,
+    nat: Nat,
+    nat_mappings: HashMap<SocketAddr, Time>,
+}
+
+/// Small deterministic pseudo random generator (xorshift64*)
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+pub struct Simulator<P: Protocol> {
+    next_port: u16,
+    nodes: BTreeMap<SocketAddr, NodeState<P>>,
+    messages: BinaryHeap<InFlight>,
+    next_seq: u64,
+    now_ms: u64,
+    conditions: Conditions,
+    links: HashMap<(SocketAddr, SocketAddr), Conditions>,
+    rng: Rng,
+    dropped: usize,
+}
+
+impl<P: Protocol> Simulator<P> {
+    pub fn new(seed: u64) -> Self {
+        MockTimeSource::set_time(0);
+        MockSocket::set_nat(false);
+        Self {
+            next_port: 1,
+            nodes: BTreeMap::new(),
+            messages: BinaryHeap::new(),
+            next_seq: 0,
+            now_ms: 0,
+            conditions: Conditions::default(),
+            links: HashMap::new(),
+            // Xorshift needs a non-zero state
+            rng: Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1),
+            dropped: 0,
+        }
+    }
+
+    pub fn add_node(&mut self, config: &Config, nat: Nat) -> SocketAddr {
+        let mut config = config.clone();
+        config.listen = format!("[::]:{}", self.next_port);
+        self.next_port += 1;
+        let addr = config.listen.parse::<SocketAddr>().unwrap();
+        if config.crypto.password.is_none() && config.crypto.private_key.is_none() {
+            config.crypto.password = Some("test123".to_string())
+        }
+        let node = SimNode::new(&config, MockSocket::new(addr), MockDevice::new(), None, None).unwrap();
+        self.nodes.insert(addr, NodeState { node, nat, nat_mappings: HashMap::new() });
+        addr
+    }
+
+    pub fn get_node(&mut self, addr: SocketAddr) -> &mut SimNode<P> {
+        &mut self.nodes.get_mut(&addr).unwrap().node
+    }
+
+    /// Sets the conditions of all links that have no specific conditions
+    pub fn set_default_conditions(&mut self, conditions: Conditions) {
+        self.conditions = conditions
+    }
+
+    /// Sets the conditions of the link between the nodes in both directions
+    pub fn set_conditions(&mut self, node1: SocketAddr, node2: SocketAddr, conditions: Conditions) {
+        self.links.insert((node1, node2), conditions);
+        self.links.insert((node2, node1), conditions);
+    }
+
+    /// Cuts the link between the nodes in both directions
+    pub fn disconnect_link(&mut self, node1: SocketAddr, node2: SocketAddr) {
+        self.set_conditions(node1, node2, Conditions { loss: 1.0, ..self.conditions })
+    }
+
+    /// Time since the start of the simulation in milliseconds
+    pub fn now(&self) -> u64 {
+        self.now_ms
+    }
+
+    /// Number of messages that were lost or filtered by NAT
+    pub fn dropped_messages(&self) -> usize {
+        self.dropped
+    }
+
+    pub fn message_count(&self) -> usize {
+        self.messages.len()
+    }
+
+    fn send_outbound(&mut self, src: SocketAddr) {
+        let now = MockTimeSource::now();
+        let mut outbound = vec![];
+        let state = self.nodes.get_mut(&src).unwrap();
+        while let Some((dst, data)) = state.node.socket().pop_outbound() {
+            state.nat_mappings.insert(dst, now + NAT_TIMEOUT);
+            outbound.push((dst, data));
+        }
+        for (dst, data) in outbound {
+            let conditions = self.links.get(&(src, dst)).copied().unwrap_or(self.conditions);
+            if conditions.loss > 0.0 && self.rng.next_f64() < conditions.loss {
+                self.dropped += 1;
+                continue;
+            }
+            let jitter = if conditions.jitter > 0 { self.rng.next() % (conditions.jitter + 1) } else { 0 };
+            let deliver_at = self.now_ms + conditions.latency + jitter;
+            self.messages.push(Reverse((deliver_at, self.next_seq, src, dst, data)));
+            self.next_seq += 1;
+        }
+    }
+
+    fn deliver(&mut self, src: SocketAddr, dst: SocketAddr, data: Vec<u8>) {
+        let now = MockTimeSource::now();
+        let state = match self.nodes.get_mut(&dst) {
+            Some(state) => state,
+            None => {
+                warn!("Message to unknown node {}", dst);
+                self.dropped += 1;
+                return;
+            }
+        };
+        if state.nat == Nat::Restricted && state.nat_mappings.get(&src).map(|t| *t < now).unwrap_or(true) {
+            debug!("Sender {} is filtered out by NAT of {}", src, dst);
+            self.dropped += 1;
+            return;
+        }
+        if state.node.socket().put_inbound(src, data) {
+            state.node.trigger_socket_event();
+        }
+        self.send_outbound(dst);
+    }
+
+    pub fn connect(&mut self, src: SocketAddr, dst: SocketAddr) {
+        self.get_node(src).connect(dst).unwrap();
+        self.send_outbound(src);
+    }
+
+    pub fn is_connected(&self, src: SocketAddr, dst: SocketAddr) -> bool {
+        self.nodes[&src].node.is_connected(&dst)
+    }
+
+    pub fn put_payload(&mut self, addr: SocketAddr, data: Vec<u8>) {
+        let node = self.get_node(addr);
+        node.device().put_inbound(data);
+        node.trigger_device_event();
+        self.send_outbound(addr);
+    }
+
+    pub fn pop_payload(&mut self, addr: SocketAddr) -> Option<Vec<u8>> {
+        self.get_node(addr).device().pop_outbound()
+    }
+
+    fn housekeep(&mut self) {
+        let addrs: Vec<_> = self.nodes.keys().copied().collect();
+        for addr in addrs {
+            self.get_node(addr).trigger_housekeep();
+            self.send_outbound(addr);
+        }
+    }
+
+    /// Runs the simulation for the given number of milliseconds
+    ///
+    /// Messages are delivered when they are due and all nodes run their housekeeping every
+    /// second of simulated time.
+    pub fn run_for(&mut self, millis: u64) {
+        let end = self.now_ms + millis;
+        loop {
+            let next_second = (self.now_ms / 1000 + 1) * 1000;
+            let next_message = self.messages.peek().map(|Reverse(m)| m.0).unwrap_or(u64::MAX);
+            let next = next_second.min(next_message);
+            if next > end {
+                self.now_ms = end;
+                MockTimeSource::set_time((end / 1000) as Time);
+                return;
+            }
+            self.now_ms = next;
+            MockTimeSource::set_time((next / 1000) as Time);
+            if next == next_message {
+                let Reverse((_, _, src, dst, data)) = self.messages.pop().unwrap();
+                self.deliver(src, dst, data);
+            } else {
+                self.housekeep();
+            }
+        }
+    }
+
+    /// Runs the simulation until all messages in flight have been delivered
+    pub fn run_until_idle(&mut self) {
+        while let Some(Reverse(m)) = self.messages.peek() {
+            let due = m.0;
+            self.run_for(due - self.now_ms);
+        }
+    }
+}
+
+#[test]
+fn sim_latency() {
+    let config = Config::default();
+    let mut sim = TapSimulator::new(1);
+    sim.set_default_conditions(Conditions { loss: 0.0, latency: 100, jitter: 20 });
+    let node1 = sim.add_node(&config, Nat::None);
+    let node2 = sim.add_node(&config, Nat::None);
+    sim.connect(node1, node2);
+    sim.run_for(50);
+    assert!(!sim.is_connected(node1, node2));
+    sim.run_for(5000);
+    assert!(sim.is_connected(node1, node2));
+    assert!(sim.is_connected(node2, node1));
+}
+
+#[test]
+fn sim_nat_hole_punching() {
+    let config = Config { port_forwarding: false, ..Default::default() };
+    let mut sim = TapSimulator::new(2);
+    sim.set_default_conditions(Conditions { loss: 0.0, latency: 20, jitter: 10 });
+    let node1 = sim.add_node(&config, Nat::Restricted);
+    let node2 = sim.add_node(&config, Nat::Restricted);
+    sim.connect(node1, node2);
+    sim.run_until_idle();
+    assert!(!sim.is_connected(node2, node1));
+    assert!(sim.dropped_messages() > 0);
+    sim.connect(node2, node1);
+    sim.run_for(60_000);
+    assert!(sim.is_connected(node1, node2));
+    assert!(sim.is_connected(node2, node1));
+}
+
+#[test]
+fn sim_link_failure() {
+    let config = Config::default();
+    let mut sim = TapSimulator::new(3);
+    let node1 = sim.add_node(&config, Nat::None);
+    let node2 = sim.add_node(&config, Nat::None);
+    sim.connect(node1, node2);
+    sim.run_for(1000);
+    assert!(sim.is_connected(node1, node2));
+    sim.disconnect_link(node1, node2);
+    sim.run_for(u64::from(config.peer_timeout) * 2000);
+    assert!(!sim.is_connected(node1, node2));
+    assert!(!sim.is_connected(node2, node1));
+}
+
+#[test]
+fn sim_deterministic() {
+    let run = |seed| {
+        let mut sim = TapSimulator::new(seed);
+        sim.set_default_conditions(Conditions { loss: 0.5, latency: 10, jitter: 50 });
+        let node1 = sim.add_node(&Config::default(), Nat::None);
+        let node2 = sim.add_node(&Config::default(), Nat::None);
+        sim.connect(node1, node2);
+        sim.run_for(10_000);
+        (sim.dropped_messages(), sim.now())
+    };
+    assert_eq!(run(4), run(4));
+}
@@ -0,0 +1,128 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Directory in which state is persisted across restarts, see `--state-dir`
+//!
+//! All files are replaced atomically, so a crash never leaves a partially written file behind.
+
+use std::{
+    fs::{self, File, Permissions},
+    io::{self, Write},
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::error::Error;
+
+pub const PEERS_FILE: &str = "peers";
+pub const BEACON_FILE: &str = "beacon";
+pub const STATS_FILE: &str = "stats";
+pub const BUDGET_FILE: &str = "budget";
+pub const TRAFFIC_FILE: &str = "traffic";
+pub const MANUAL_PEERS_FILE: &str = "manual-peers";
+pub const NAMES_FILE: &str = "names";
+pub const MAC_TABLE_FILE: &str = "mac-table";
+pub const IPAM_LEASES_FILE: &str = "ipam-leases";
+pub const IPAM_LEASE_FILE: &str = "ipam-lease";
+
+#[derive(Clone)]
+pub struct StateDir {
+    path: PathBuf,
+}
+
+impl StateDir {
+    /// Opens the directory, creating it if needed
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        fs::create_dir_all(path).map_err(|e| Error::FileIo("Failed to create state directory", e))?;
+        fs::set_permissions(path, Permissions::from_mode(0o700))
+            .map_err(|e| Error::FileIo("Failed to set permissions on state directory", e))?;
+        Ok(Self { path: path.to_path_buf() })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replaces the file atomically by writing a temporary file and renaming it
+    pub fn write(&self, name: &str, data: &[u8]) -> Result<(), io::Error> {
+        let path = self.path.join(name);
+        let tmp = self.path.join(format!(".{}.tmp", name));
+        {
+            let mut f = File::create(&tmp)?;
+            f.write_all(data)?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, &path)
+    }
+
+    pub fn read(&self, name: &str) -> Result<Option<Vec<u8>>, io::Error> {
+        match fs::read(self.path.join(name)) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn save_peers(&self, peers: &[SocketAddr]) -> Result<(), io::Error> {
+        let mut data = String::new();
+        for peer in peers {
+            data.push_str(&peer.to_string());
+            data.push('\n');
+        }
+        self.write(PEERS_FILE, data.as_bytes())
+    }
+
+    /// Loads the peers, ignoring invalid lines
+    pub fn load_peers(&self) -> Result<Vec<SocketAddr>, io::Error> {
+        let data = match self.read(PEERS_FILE)? {
+            Some(data) => data,
+            None => return Ok(vec![]),
+        };
+        Ok(String::from_utf8_lossy(&data).lines().filter_map(|l| SocketAddr::from_str(l.trim()).ok()).collect())
+    }
+
+    /// Saves the peers that have been added via the control socket
+    pub fn save_manual_peers(&self, peers: &[String]) -> Result<(), io::Error> {
+        let mut data = String::new();
+        for peer in peers {
+            data.push_str(peer);
+            data.push('\n');
+        }
+        self.write(MANUAL_PEERS_FILE, data.as_bytes())
+    }
+
+    pub fn load_manual_peers(&self) -> Result<Vec<String>, io::Error> {
+        let data = match self.read(MANUAL_PEERS_FILE)? {
+            Some(data) => data,
+            None => return Ok(vec![]),
+        };
+        Ok(String::from_utf8_lossy(&data).lines().map(str::trim).filter(|l| !l.is_empty()).map(String::from).collect())
+    }
+}
+
+#[test]
+fn state_peers() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = StateDir::open(dir.path().join("state")).unwrap();
+    assert!(state.load_peers().unwrap().is_empty());
+    let peers = vec![SocketAddr::from_str("1.2.3.4:3210").unwrap(), SocketAddr::from_str("[::1]:3211").unwrap()];
+    state.save_peers(&peers).unwrap();
+    assert_eq!(state.load_peers().unwrap(), peers);
+    state.write(PEERS_FILE, b"1.2.3.4:3210\ninvalid\n").unwrap();
+    assert_eq!(state.load_peers().unwrap(), &peers[..1]);
+    assert!(!state.path().join(".peers.tmp").exists());
+}
+
+#[test]
+fn state_manual_peers() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = StateDir::open(dir.path()).unwrap();
+    assert!(state.load_manual_peers().unwrap().is_empty());
+    let peers = vec!["node1.example.com:3210".to_string(), "1.2.3.4:3210".to_string()];
+    state.save_manual_peers(&peers).unwrap();
+    assert_eq!(state.load_manual_peers().unwrap(), peers);
+}
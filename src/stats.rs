@@ -0,0 +1,351 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Outputs for the statistics of the node
+//!
+//! Every `STATS_INTERVAL` the node takes a `Snapshot` of its statistics and hands it to all configured sinks: the
+//! stats file and the state directory get the full report, statsd, Prometheus and InfluxDB get the metrics and the
+//! control socket answers `vpncloud stats` with the latest report. New outputs only need to implement `StatsSink`
+//! and be created in `create_sinks`.
+
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Seek, SeekFrom, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    config::Config,
+    error::Error,
+    state::{StateDir, STATS_FILE},
+    util::{resolve, Time},
+};
+
+pub const DEFAULT_PREFIX: &str = "vpncloud";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    /// Current value like the number of peers
+    Gauge,
+    /// Ever increasing value like the number of bytes sent
+    Counter,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metric {
+    /// Dotted name without prefix like `traffic.protocol.inbound.bytes`
+    pub name: String,
+    pub value: String,
+    pub kind: MetricKind,
+}
+
+/// Builder for a list of metrics with nested namespaces
+#[derive(Default)]
+pub struct Metrics {
+    metrics: Vec<Metric>,
+    key: Vec<String>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add<T: fmt::Display>(&mut self, key: &str, val: T, kind: MetricKind) -> &mut Self {
+        let mut name = self.key.join(".");
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.push_str(key);
+        self.metrics.push(Metric { name, value: val.to_string(), kind });
+        self
+    }
+
+    pub fn with_ns<F: FnOnce(&mut Self)>(&mut self, ns: &str, f: F) -> &mut Self {
+        self.key.push(ns.to_string());
+        f(self);
+        self.key.pop();
+        self
+    }
+
+    pub fn build(self) -> Vec<Metric> {
+        self.metrics
+    }
+}
+
+/// The statistics of the node at one point in time
+pub struct Snapshot {
+    pub time: Time,
+    /// Human readable report in YAML format
+    pub report: String,
+    pub metrics: Vec<Metric>,
+}
+
+pub trait StatsSink: Send {
+    fn write(&mut self, snapshot: &Snapshot) -> Result<(), Error>;
+}
+
+/// Writes the report into the stats file, see `--stats-file`
+pub struct FileSink {
+    file: File,
+}
+
+impl FileSink {
+    pub fn new(file: File) -> Self {
+        Self { file }
+    }
+
+    fn write_report(&mut self, report: &str) -> Result<(), io::Error> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.set_len(0)?;
+        self.file.write_all(report.as_bytes())
+    }
+}
+
+impl StatsSink for FileSink {
+    fn write(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+        self.write_report(&snapshot.report).map_err(|e| Error::FileIo("Failed to write stats file", e))
+    }
+}
+
+/// Writes the report into the state directory, see `--state-dir`
+pub struct StateSink {
+    state: StateDir,
+}
+
+impl StateSink {
+    pub fn new(state: StateDir) -> Self {
+        Self { state }
+    }
+}
+
+impl StatsSink for StateSink {
+    fn write(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+        self.state
+            .write(STATS_FILE, snapshot.report.as_bytes())
+            .map_err(|e| Error::FileIo("Failed to write stats to state directory", e))
+    }
+}
+
+/// Opens an unconnected UDP socket that can reach the given server
+fn udp_socket(server: &str) -> Result<UdpSocket, Error> {
+    let ipv6 = resolve(server).map(|addrs| addrs.first().map(SocketAddr::is_ipv6).unwrap_or(false)).unwrap_or(false);
+    let socket = if ipv6 {
+        UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))
+    } else {
+        UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+    };
+    socket.map_err(|e| Error::SocketIo("Failed to open stats socket", e))
+}
+
+/// Sends the data to the server, resolving its name every time as it might change
+fn send_udp(socket: &UdpSocket, server: &str, data: &[u8]) -> Result<(), Error> {
+    let addrs = resolve(server)?;
+    let addr = addrs.first().ok_or_else(|| Error::NameUnresolvable(server.to_string()))?;
+    match socket.send_to(data, addr) {
+        Ok(written) if written == data.len() => Ok(()),
+        Ok(_) => Err(Error::Socket("Sent out truncated packet")),
+        Err(e) => Err(Error::SocketIo("IOError when sending", e)),
+    }
+}
+
+/// Sends the metrics to a statsd server, see `--statsd-server`
+pub struct StatsdSink {
+    server: String,
+    prefix: String,
+    socket: UdpSocket,
+}
+
+impl StatsdSink {
+    pub fn new(server: &str, prefix: Option<&str>) -> Result<Self, Error> {
+        let socket = udp_socket(server)?;
+        Ok(Self { server: server.to_string(), prefix: prefix.unwrap_or(DEFAULT_PREFIX).to_string(), socket })
+    }
+
+    fn format(&self, snapshot: &Snapshot) -> String {
+        let lines: Vec<_> = snapshot
+            .metrics
+            .iter()
+            .map(|m| {
+                let kind = match m.kind {
+                    MetricKind::Gauge => "g",
+                    MetricKind::Counter => "c",
+                };
+                format!("{}.{}:{}|{}", self.prefix, m.name, m.value, kind)
+            })
+            .collect();
+        lines.join("\n")
+    }
+}
+
+impl StatsSink for StatsdSink {
+    fn write(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+        send_udp(&self.socket, &self.server, self.format(snapshot).as_bytes())
+    }
+}
+
+/// Writes the metrics in the Prometheus text format for the textfile collector of the node exporter, see
+/// `--prometheus-file`
+pub struct PrometheusSink {
+    path: PathBuf,
+    tmp: PathBuf,
+    prefix: String,
+}
+
+impl PrometheusSink {
+    pub fn new(path: &str, prefix: Option<&str>) -> Self {
+        let path = PathBuf::from(path);
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        Self { path, tmp: tmp.into(), prefix: prefix.unwrap_or(DEFAULT_PREFIX).replace('.', "_") }
+    }
+
+    fn format(&self, snapshot: &Snapshot) -> String {
+        let mut out = String::new();
+        for metric in &snapshot.metrics {
+            let name = format!("{}_{}", self.prefix, metric.name.replace('.', "_"));
+            let kind = match metric.kind {
+                MetricKind::Gauge => "gauge",
+                MetricKind::Counter => "counter",
+            };
+            out.push_str(&format!("# TYPE {} {}\n{} {}\n", name, kind, name, metric.value));
+        }
+        out
+    }
+
+    /// Replaces the file atomically so that the collector never reads a partial file
+    fn write_file(&self, data: &str) -> Result<(), io::Error> {
+        fs::write(&self.tmp, data)?;
+        fs::rename(&self.tmp, &self.path)
+    }
+}
+
+impl StatsSink for PrometheusSink {
+    fn write(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+        self.write_file(&self.format(snapshot)).map_err(|e| Error::FileIo("Failed to write Prometheus file", e))
+    }
+}
+
+/// Sends the metrics to an InfluxDB server in the line protocol via UDP, see `--influxdb-server`
+pub struct InfluxSink {
+    server: String,
+    measurement: String,
+    tags: String,
+    socket: UdpSocket,
+}
+
+/// Escapes commas, spaces and equal signs in measurement names, tag keys and tag values
+fn influx_escape(value: &str) -> String {
+    value.replace(',', "\\,").replace(' ', "\\ ").replace('=', "\\=")
+}
+
+impl InfluxSink {
+    pub fn new(server: &str, measurement: Option<&str>, network_id: Option<&str>) -> Result<Self, Error> {
+        let socket = udp_socket(server)?;
+        let tags = match network_id {
+            Some(id) => format!(",network={}", influx_escape(id)),
+            None => String::new(),
+        };
+        Ok(Self {
+            server: server.to_string(),
+            measurement: influx_escape(measurement.unwrap_or(DEFAULT_PREFIX)),
+            tags,
+            socket,
+        })
+    }
+
+    fn format(&self, snapshot: &Snapshot) -> String {
+        let fields: Vec<_> = snapshot
+            .metrics
+            .iter()
+            .map(|m| {
+                // All metrics are integers, they need a suffix to not be stored as floats
+                let suffix = if m.value.parse::<i64>().is_ok() { "i" } else { "" };
+                format!("{}={}{}", influx_escape(&m.name), m.value, suffix)
+            })
+            .collect();
+        format!("{}{} {} {}\n", self.measurement, self.tags, fields.join(","), snapshot.time * 1_000_000_000)
+    }
+}
+
+impl StatsSink for InfluxSink {
+    fn write(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+        if snapshot.metrics.is_empty() {
+            return Ok(());
+        }
+        send_udp(&self.socket, &self.server, self.format(snapshot).as_bytes())
+    }
+}
+
+/// Keeps the latest report so that the control socket can answer `vpncloud stats` without waiting for the node
+pub struct ControlSink {
+    latest: Arc<Mutex<String>>,
+}
+
+impl ControlSink {
+    pub fn new(latest: Arc<Mutex<String>>) -> Self {
+        Self { latest }
+    }
+}
+
+impl StatsSink for ControlSink {
+    fn write(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+        *self.latest.lock().expect("Lock poisoned") = snapshot.report.clone();
+        Ok(())
+    }
+}
+
+/// Creates the sinks for the configured outputs, except for the control socket
+pub fn create_sinks(
+    config: &Config, stats_file: Option<File>, state: Option<&StateDir>,
+) -> Result<Vec<Box<dyn StatsSink>>, Error> {
+    let mut sinks: Vec<Box<dyn StatsSink>> = vec![];
+    if let Some(file) = stats_file {
+        sinks.push(Box::new(FileSink::new(file)));
+    }
+    if let Some(state) = state {
+        sinks.push(Box::new(StateSink::new(state.clone())));
+    }
+    if let Some(ref server) = config.statsd_server {
+        sinks.push(Box::new(StatsdSink::new(server, config.statsd_prefix.as_deref())?));
+    }
+    if let Some(ref path) = config.prometheus_file {
+        sinks.push(Box::new(PrometheusSink::new(path, config.statsd_prefix.as_deref())));
+    }
+    if let Some(ref server) = config.influxdb_server {
+        let sink = InfluxSink::new(server, config.influxdb_measurement.as_deref(), config.network_id.as_deref())?;
+        sinks.push(Box::new(sink));
+    }
+    Ok(sinks)
+}
+
+#[test]
+fn stats_sink_formats() {
+    let mut metrics = Metrics::new();
+    metrics.add("peer_count", 2, MetricKind::Gauge).with_ns("traffic", |m| {
+        m.add("bytes", 1234, MetricKind::Counter);
+    });
+    let snapshot = Snapshot { time: 1000, report: "peers:\n".to_string(), metrics: metrics.build() };
+    let statsd = StatsdSink::new("127.0.0.1:8125", Some("node")).unwrap();
+    assert_eq!(statsd.format(&snapshot), "node.peer_count:2|g\nnode.traffic.bytes:1234|c");
+    let prometheus = PrometheusSink::new("/tmp/vpncloud.prom", None);
+    assert_eq!(
+        prometheus.format(&snapshot),
+        concat!(
+            "# TYPE vpncloud_peer_count gauge\nvpncloud_peer_count 2\n",
+            "# TYPE vpncloud_traffic_bytes counter\nvpncloud_traffic_bytes 1234\n"
+        )
+    );
+    let influx = InfluxSink::new("127.0.0.1:8089", None, Some("my net")).unwrap();
+    assert_eq!(
+        influx.format(&snapshot),
+        "vpncloud,network=my\\ net peer_count=2i,traffic.bytes=1234i 1000000000000\n"
+    );
+    let latest = Arc::new(Mutex::new(String::new()));
+    ControlSink::new(latest.clone()).write(&snapshot).unwrap();
+    assert_eq!(*latest.lock().unwrap(), "peers:\n");
+}
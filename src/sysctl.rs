@@ -0,0 +1,176 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Management of the kernel network settings (sysctls) that the VPN depends on
+//!
+//! VpnCloud can fix insecure `rp_filter` settings (`fix-rp-filter`) and enable IP forwarding for nodes that route
+//! traffic between the VPN and other networks (`ip-forwarding`). The original values are recorded and restored on
+//! shutdown. While the node runs, the values are checked periodically and set again if something else, e.g. a
+//! network manager, changed them.
+
+use std::{
+    fs,
+    io::{self, Write},
+    mem,
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+/// Interval in which the values are checked
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Setting {
+    key: String,
+    value: String,
+    original: String,
+}
+
+/// The settings that have been changed, keys are paths relative to `/proc/sys` like `net/ipv4/ip_forward`
+pub struct Sysctls {
+    root: PathBuf,
+    settings: Vec<Setting>,
+}
+
+/// Name of the setting as shown by the `sysctl` command
+fn name(key: &str) -> String {
+    key.replace('/', ".")
+}
+
+impl Sysctls {
+    pub fn new() -> Self {
+        Self::with_root("/proc/sys")
+    }
+
+    pub fn with_root<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into(), settings: vec![] }
+    }
+
+    pub fn get(&self, key: &str) -> io::Result<String> {
+        Ok(fs::read_to_string(self.root.join(key))?.trim().to_string())
+    }
+
+    fn write(&self, key: &str, value: &str) -> io::Result<()> {
+        let mut fd = fs::OpenOptions::new().write(true).open(self.root.join(key))?;
+        writeln!(fd, "{}", value)
+    }
+
+    /// Sets the value if needed and keeps it while the node runs
+    pub fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        let current = self.get(key)?;
+        if current != value {
+            info!("Setting {}={}", name(key), value);
+            self.write(key, value)?;
+        }
+        match self.settings.iter_mut().find(|s| s.key == key) {
+            Some(setting) => setting.value = value.to_string(),
+            None => self.settings.push(Setting { key: key.to_string(), value: value.to_string(), original: current }),
+        }
+        Ok(())
+    }
+
+    fn get_number(&self, key: &str) -> io::Result<u8> {
+        let value = self.get(key)?;
+        u8::from_str(&value).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid sysctl value"))
+    }
+
+    /// The effective rp_filter setting of the interface
+    pub fn get_rp_filter(&self, ifname: &str) -> io::Result<u8> {
+        let all = self.get_number("net/ipv4/conf/all/rp_filter")?;
+        Ok(all.max(self.get_number(&format!("net/ipv4/conf/{}/rp_filter", ifname))?))
+    }
+
+    /// Sets rp_filter to strict mode for the interface
+    pub fn fix_rp_filter(&mut self, ifname: &str) -> io::Result<()> {
+        // The maximum of both values is used, so loose mode on all interfaces would override strict mode
+        if self.get_number("net/ipv4/conf/all/rp_filter")? > 1 {
+            self.set("net/ipv4/conf/all/rp_filter", "1")?;
+        }
+        self.set(&format!("net/ipv4/conf/{}/rp_filter", ifname), "1")
+    }
+
+    /// Enables forwarding of IPv4 and, if available, IPv6 packets between the interfaces
+    pub fn enable_forwarding(&mut self) -> io::Result<()> {
+        self.set("net/ipv4/ip_forward", "1")?;
+        if self.root.join("net/ipv6/conf/all/forwarding").exists() {
+            self.set("net/ipv6/conf/all/forwarding", "1")?;
+        } else {
+            debug!("IPv6 is disabled, not enabling IPv6 forwarding");
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.settings.is_empty()
+    }
+
+    /// Sets values again that have been changed by something else and returns their number
+    pub fn check(&self) -> usize {
+        let mut changed = 0;
+        for setting in &self.settings {
+            let name = name(&setting.key);
+            match self.get(&setting.key) {
+                Ok(value) if value == setting.value => continue,
+                Ok(value) => warn!("{} was changed to {}, setting it to {} again", name, value, setting.value),
+                Err(err) => warn!("Failed to read {}: {}", name, err),
+            }
+            changed += 1;
+            if let Err(err) = self.write(&setting.key, &setting.value) {
+                warn!("Failed to set {}: {}", name, err);
+            }
+        }
+        changed
+    }
+
+    /// Restores the original values
+    pub fn restore(&mut self) {
+        let settings = mem::take(&mut self.settings);
+        for setting in settings.into_iter().rev() {
+            if setting.original == setting.value {
+                continue;
+            }
+            info!("Restoring {}={}", name(&setting.key), setting.original);
+            if let Err(err) = self.write(&setting.key, &setting.original) {
+                warn!("Failed to restore {}: {}", name(&setting.key), err);
+            }
+        }
+    }
+}
+
+impl Default for Sysctls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn sysctls_set_and_restore() {
+    let dir = tempfile::tempdir().unwrap();
+    for (key, value) in &[
+        ("net/ipv4/ip_forward", "0"),
+        ("net/ipv4/conf/all/rp_filter", "2"),
+        ("net/ipv4/conf/vpn0/rp_filter", "0"),
+    ] {
+        let path = dir.path().join(key);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, format!("{}\n", value)).unwrap();
+    }
+    let mut sysctls = Sysctls::with_root(dir.path());
+    assert_eq!(sysctls.get_rp_filter("vpn0").unwrap(), 2);
+    sysctls.fix_rp_filter("vpn0").unwrap();
+    assert_eq!(sysctls.get_rp_filter("vpn0").unwrap(), 1);
+    // IPv6 is missing here
+    sysctls.enable_forwarding().unwrap();
+    assert_eq!(sysctls.get("net/ipv4/ip_forward").unwrap(), "1");
+    assert_eq!(sysctls.check(), 0);
+    // Values changed by others are set again
+    fs::write(dir.path().join("net/ipv4/ip_forward"), "0\n").unwrap();
+    assert_eq!(sysctls.check(), 1);
+    assert_eq!(sysctls.get("net/ipv4/ip_forward").unwrap(), "1");
+    sysctls.restore();
+    assert!(sysctls.is_empty());
+    assert_eq!(sysctls.get("net/ipv4/ip_forward").unwrap(), "0");
+    assert_eq!(sysctls.get("net/ipv4/conf/all/rp_filter").unwrap(), "2");
+    assert_eq!(sysctls.get("net/ipv4/conf/vpn0/rp_filter").unwrap(), "0");
+}
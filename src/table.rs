@@ -5,6 +5,7 @@
 use fnv::FnvHasher;
 use std::{
     cmp::min, collections::HashMap, hash::BuildHasherDefault, io, io::Write, marker::PhantomData, net::SocketAddr,
+    str::FromStr,
 };
 
 use crate::{
@@ -14,6 +15,9 @@ use crate::{
 
 type Hash = BuildHasherDefault<FnvHasher>;
 
+/// Number of recent lookups that are kept in front of the cache
+const RECENT_SIZE: usize = 4;
+
 struct CacheValue {
     peer: SocketAddr,
     timeout: Time,
@@ -26,27 +30,103 @@ struct ClaimEntry {
 }
 
 pub struct ClaimTable<TS: TimeSource> {
+    recent: [Option<(Address, SocketAddr)>; RECENT_SIZE],
     cache: HashMap<Address, CacheValue, Hash>,
     cache_timeout: Duration,
     claims: Vec<ClaimEntry>,
     claim_timeout: Duration,
+    statics: HashMap<Address, SocketAddr, Hash>,
+    seeds: HashMap<SocketAddr, Vec<Address>, Hash>,
+    seeds_timeout: Time,
     _dummy: PhantomData<TS>,
 }
 
 impl<TS: TimeSource> ClaimTable<TS> {
     pub fn new(cache_timeout: Duration, claim_timeout: Duration) -> Self {
-        Self { cache: HashMap::default(), cache_timeout, claims: vec![], claim_timeout, _dummy: PhantomData }
+        Self {
+            recent: [None; RECENT_SIZE],
+            cache: HashMap::default(),
+            cache_timeout,
+            claims: vec![],
+            claim_timeout,
+            statics: HashMap::default(),
+            seeds: HashMap::default(),
+            seeds_timeout: 0,
+            _dummy: PhantomData,
+        }
     }
 
     pub fn cache(&mut self, addr: Address, peer: SocketAddr) {
         // HOT PATH
+        for entry in self.recent.iter_mut().flatten() {
+            if entry.0 == addr {
+                entry.1 = peer
+            }
+        }
         self.cache.insert(addr, CacheValue { peer, timeout: TS::now() + self.cache_timeout as Time });
     }
 
+    /// Sets a static entry that is used until the address is learned from traffic
+    pub fn set_static(&mut self, addr: Address, peer: SocketAddr) {
+        self.statics.insert(addr, peer);
+        self.recent = [None; RECENT_SIZE];
+    }
+
+    /// Formats the learned addresses as one `address peer` line per entry
+    pub fn save(&self) -> String {
+        let mut entries: Vec<_> = self.cache.iter().map(|(addr, entry)| (addr.to_string(), entry.peer)).collect();
+        entries.sort();
+        let mut data = String::new();
+        for (addr, peer) in entries {
+            data.push_str(&format!("{} {}\n", addr, peer));
+        }
+        data
+    }
+
+    /// Loads saved addresses, they are only used once their peer is connected again
+    ///
+    /// Entries whose peer does not connect within the cache timeout are dropped.
+    pub fn load(&mut self, data: &str) {
+        for line in data.lines() {
+            let entry = line
+                .trim()
+                .split_once(' ')
+                .and_then(|(addr, peer)| Some((Address::from_str(addr).ok()?, SocketAddr::from_str(peer).ok()?)));
+            if let Some((addr, peer)) = entry {
+                self.seeds.entry(peer).or_default().push(addr);
+            }
+        }
+        self.seeds_timeout = TS::now() + self.cache_timeout as Time;
+    }
+
+    /// Adds the loaded addresses of a newly connected peer that is known under the given addresses to the cache
+    pub fn seed(&mut self, peer: SocketAddr, addrs: &[SocketAddr]) -> usize {
+        if self.seeds.is_empty() {
+            return 0;
+        }
+        let mut count = 0;
+        let timeout = TS::now() + self.cache_timeout as Time;
+        for known in Some(&peer).into_iter().chain(addrs) {
+            for addr in self.seeds.remove(known).unwrap_or_default() {
+                self.cache.entry(addr).or_insert(CacheValue { peer, timeout });
+                count += 1;
+            }
+        }
+        count
+    }
+
     pub fn clear_cache(&mut self) {
+        self.recent = [None; RECENT_SIZE];
         self.cache.clear()
     }
 
+    /// Remembers a lookup result as the most recent one, evicting the least recent one
+    #[inline]
+    fn remember(&mut self, addr: Address, peer: SocketAddr) {
+        self.recent.rotate_right(1);
+        self.recent[0] = Some((addr, peer));
+    }
+
     pub fn set_claims(&mut self, peer: SocketAddr, mut claims: RangeList) {
         for entry in &mut self.claims {
             if entry.peer == peer {
@@ -89,10 +169,21 @@ impl<TS: TimeSource> ClaimTable<TS> {
 
     pub fn lookup(&mut self, addr: Address) -> Option<SocketAddr> {
         // HOT PATH
+        if let Some(pos) = self.recent.iter().position(|e| matches!(e, Some((a, _)) if *a == addr)) {
+            let peer = self.recent[pos].unwrap().1;
+            self.recent[..=pos].rotate_right(1);
+            return Some(peer);
+        }
         if let Some(entry) = self.cache.get(&addr) {
-            return Some(entry.peer);
+            let peer = entry.peer;
+            self.remember(addr, peer);
+            return Some(peer);
         }
         // COLD PATH
+        if let Some(peer) = self.statics.get(&addr).copied() {
+            self.remember(addr, peer);
+            return Some(peer);
+        }
         let mut found = None;
         let mut prefix_len = -1;
         for entry in &self.claims {
@@ -106,15 +197,36 @@ impl<TS: TimeSource> ClaimTable<TS> {
                 addr,
                 CacheValue { peer: entry.peer, timeout: min(TS::now() + self.cache_timeout as Time, entry.timeout) },
             );
-            return Some(entry.peer);
+            let peer = entry.peer;
+            self.remember(addr, peer);
+            return Some(peer);
         }
         None
     }
 
+    /// Checks whether the address is covered by one of the claims of the peer
+    pub fn is_claimed_by(&mut self, addr: Address, peer: SocketAddr) -> bool {
+        // HOT PATH
+        if self.lookup(addr) == Some(peer) {
+            return true;
+        }
+        // COLD PATH
+        self.claims.iter().any(|entry| entry.peer == peer && entry.claim.matches(addr))
+    }
+
     pub fn housekeep(&mut self) {
         let now = TS::now();
+        self.recent = [None; RECENT_SIZE];
         self.cache.retain(|_, v| v.timeout >= now);
         self.claims.retain(|e| e.timeout >= now);
+        if !self.seeds.is_empty() && self.seeds_timeout < now {
+            self.seeds.clear();
+        }
+    }
+
+    /// All peers that are referenced by claims or cache entries
+    pub fn peers(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.claims.iter().map(|e| e.peer).chain(self.cache.values().map(|v| v.peer))
     }
 
     pub fn cache_len(&self) -> usize {
@@ -139,6 +251,10 @@ impl<TS: TimeSource> ClaimTable<TS> {
                 entry.timeout - now
             )?;
         }
+        writeln!(out, "  static:")?;
+        for (addr, peer) in &self.statics {
+            writeln!(out, "    - \"{}\": {{ peer: \"{}\" }}", addr, addr_nice(*peer))?;
+        }
         writeln!(out, "  cache:")?;
         for (addr, entry) in &self.cache {
             writeln!(
@@ -153,4 +269,87 @@ impl<TS: TimeSource> ClaimTable<TS> {
     }
 }
 
-// TODO: test
+#[test]
+fn recent_lookups() {
+    use crate::util::MockTimeSource;
+    use smallvec::smallvec;
+    MockTimeSource::set_time(1000);
+    let mut table = ClaimTable::<MockTimeSource>::new(60, 60);
+    let peer1 = SocketAddr::from_str("1.2.3.4:3210").unwrap();
+    let peer2 = SocketAddr::from_str("5.6.7.8:3210").unwrap();
+    let addr = Address::from_str("10.0.0.1").unwrap();
+    table.set_claims(peer1, smallvec![Range::from_str("10.0.0.0/24").unwrap()]);
+    assert_eq!(table.lookup(addr), Some(peer1));
+    assert_eq!(table.recent[0], Some((addr, peer1)));
+    // Learned addresses update recent lookups
+    table.cache(addr, peer2);
+    assert_eq!(table.lookup(addr), Some(peer2));
+    // Least recent lookups are evicted
+    for i in 2..=RECENT_SIZE as u8 + 1 {
+        table.cache(Address::from_str(&format!("10.0.0.{}", i)).unwrap(), peer1);
+        table.lookup(Address::from_str(&format!("10.0.0.{}", i)).unwrap());
+    }
+    assert!(table.recent.iter().all(|e| e.map(|(a, _)| a != addr).unwrap_or(true)));
+    assert_eq!(table.lookup(addr), Some(peer2));
+    // Table changes invalidate recent lookups
+    table.remove_claims(peer2);
+    assert!(table.recent.iter().all(|e| e.is_none()));
+    assert_eq!(table.lookup(addr), Some(peer1));
+}
+
+#[test]
+fn claimed_addresses() {
+    use crate::util::MockTimeSource;
+    use smallvec::smallvec;
+    MockTimeSource::set_time(1000);
+    let mut table = ClaimTable::<MockTimeSource>::new(60, 60);
+    let peer1 = SocketAddr::from_str("1.2.3.4:3210").unwrap();
+    let peer2 = SocketAddr::from_str("5.6.7.8:3210").unwrap();
+    table.set_claims(peer1, smallvec![Range::from_str("10.0.0.0/16").unwrap()]);
+    table.set_claims(peer2, smallvec![Range::from_str("10.0.1.0/24").unwrap()]);
+    let addr = Address::from_str("10.0.1.5").unwrap();
+    assert_eq!(table.lookup(addr), Some(peer2));
+    // Less specific claims also cover the address
+    assert!(table.is_claimed_by(addr, peer1));
+    assert!(table.is_claimed_by(addr, peer2));
+    let addr = Address::from_str("10.0.2.5").unwrap();
+    assert!(table.is_claimed_by(addr, peer1));
+    assert!(!table.is_claimed_by(addr, peer2));
+    assert!(!table.is_claimed_by(Address::from_str("10.1.0.1").unwrap(), peer1));
+}
+
+#[test]
+fn saved_and_static_entries() {
+    use crate::util::MockTimeSource;
+    MockTimeSource::set_time(1000);
+    let mut table = ClaimTable::<MockTimeSource>::new(60, 60);
+    let peer1 = SocketAddr::from_str("1.2.3.4:3210").unwrap();
+    let peer2 = SocketAddr::from_str("5.6.7.8:3210").unwrap();
+    let mac1 = Address::from_str("52:54:00:00:00:01").unwrap();
+    let mac2 = Address::from_str("vlan5/52:54:00:00:00:02").unwrap();
+    let mac3 = Address::from_str("52:54:00:00:00:03").unwrap();
+    table.cache(mac1, peer1);
+    table.cache(mac2, peer2);
+    let data = table.save();
+    assert_eq!(data, "52:54:00:00:00:01 1.2.3.4:3210\nvlan5/52:54:00:00:00:02 5.6.7.8:3210\n");
+    // Loaded entries are only used once their peer is connected
+    let mut table = ClaimTable::<MockTimeSource>::new(60, 60);
+    table.load(&data);
+    table.set_static(mac3, peer1);
+    assert_eq!(table.lookup(mac1), None);
+    assert_eq!(table.lookup(mac3), Some(peer1));
+    let peer3 = SocketAddr::from_str("9.9.9.9:3210").unwrap();
+    assert_eq!(table.seed(peer3, &[peer1]), 1);
+    assert_eq!(table.lookup(mac1), Some(peer3));
+    assert_eq!(table.lookup(mac2), None);
+    // Learned addresses take precedence over static entries
+    table.cache(mac3, peer2);
+    assert_eq!(table.lookup(mac3), Some(peer2));
+    table.remove_claims(peer2);
+    assert_eq!(table.lookup(mac3), Some(peer1));
+    // Entries of peers that do not connect again are dropped
+    MockTimeSource::set_time(1061);
+    table.housekeep();
+    assert_eq!(table.seed(peer2, &[]), 0);
+    assert_eq!(table.lookup(mac2), None);
+}
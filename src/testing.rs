@@ -0,0 +1,83 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Building blocks for deterministic tests of programs that embed VpnCloud (feature `testing`)
+//!
+//! A [`MockNode`] uses a [`MockDevice`] and a [`MockSocket`] that just queue the data, and a [`MockTimeSource`] whose
+//! time only changes when the test sets it. The test moves the data between the nodes itself, e.g. with
+//! [`exchange_messages`], and calls [`GenericCloud::trigger_housekeep`] after advancing the time. The mock time is
+//! per thread, so tests can run in parallel. For whole networks with loss and latency, see the `sim` feature.
+
+use std::net::SocketAddr;
+
+use crate::{cloud::GenericCloud, config::Config, error::Error, net::Socket, payload::Protocol};
+
+pub use crate::{
+    device::MockDevice,
+    net::MockSocket,
+    util::{MockTimeSource, TimeSource},
+};
+
+pub type MockNode<P> = GenericCloud<MockDevice, P, MockSocket, MockTimeSource>;
+
+/// Creates a node that listens on the address given in the config
+///
+/// The device type is taken from the config, but the payload protocol must match it.
+pub fn mock_node<P: Protocol>(config: &Config) -> Result<MockNode<P>, Error> {
+    let addr = config.listen.parse::<SocketAddr>().map_err(|_| Error::InvalidConfig("Listen address is no address"))?;
+    MockNode::new(config, MockSocket::new(addr), MockDevice::with_type(config.device_type), None, None)
+}
+
+/// Delivers the messages that the nodes have sent to each other until no messages are left and returns their number
+///
+/// Messages to other addresses are dropped.
+pub fn exchange_messages<P: Protocol>(node1: &mut MockNode<P>, node2: &mut MockNode<P>) -> usize {
+    let addr1 = node1.socket().address().expect("Mock sockets have an address");
+    let addr2 = node2.socket().address().expect("Mock sockets have an address");
+    let mut count = 0;
+    loop {
+        let mut delivered = false;
+        while let Some((dst, data)) = node1.socket().pop_outbound() {
+            if dst == addr2 && node2.socket().put_inbound(addr1, data) {
+                node2.trigger_socket_event();
+                delivered = true;
+                count += 1;
+            }
+        }
+        while let Some((dst, data)) = node2.socket().pop_outbound() {
+            if dst == addr1 && node1.socket().put_inbound(addr2, data) {
+                node1.trigger_socket_event();
+                delivered = true;
+                count += 1;
+            }
+        }
+        if !delivered {
+            return count;
+        }
+    }
+}
+
+#[test]
+fn mock_nodes_connect() {
+    use crate::{config::CryptoConfig, device::Type, payload::Frame};
+    MockTimeSource::set_time(0);
+    MockSocket::set_nat(false);
+    let config = Config {
+        device_type: Type::Tap,
+        crypto: CryptoConfig { password: Some("test123".to_string()), ..Default::default() },
+        ..Default::default()
+    };
+    let mut node1 = mock_node::<Frame>(&Config { listen: "[::]:1".to_string(), ..config.clone() }).unwrap();
+    let mut node2 = mock_node::<Frame>(&Config { listen: "[::]:2".to_string(), ..config }).unwrap();
+    let addr2 = node2.socket().address().unwrap();
+    node1.connect(addr2).unwrap();
+    assert!(exchange_messages(&mut node1, &mut node2) >= 3);
+    assert!(node1.is_connected(&addr2));
+    assert!(node2.is_connected(&node1.socket().address().unwrap()));
+    MockTimeSource::set_time(60);
+    node1.trigger_housekeep();
+    node2.trigger_housekeep();
+    exchange_messages(&mut node1, &mut node2);
+    assert!(node1.is_connected(&addr2));
+}
@@ -10,16 +10,23 @@ use std::{
         atomic::{AtomicUsize, Ordering},
         Once,
     },
+    thread, time,
 };
 
 pub use crate::{
     cloud::GenericCloud,
-    config::{Config, CryptoConfig},
-    device::{MockDevice, Type},
+    config::{
+        AcceptClaimsConfig, ClaimFilterConfig, Config, CryptoConfig, DuplicationConfig, FecConfig, IpamConfig,
+        PeerConfig,
+    },
+    control::ControlCommand,
+    crypto::Crypto,
+    device::{Device, MockDevice, Type},
+    error::Error,
     net::MockSocket,
     payload::{Frame, Packet, Protocol},
-    types::Range,
-    util::{MockTimeSource, Time, TimeSource},
+    types::{Address, DuplicateIdentityPolicy, Range, Topology},
+    util::{addr_nice, MockTimeSource, Time, TimeSource},
 };
 
 static INIT_LOGGER: Once = Once::new();
@@ -76,6 +83,7 @@ impl<P: Protocol> Simulator<P> {
     pub fn new() -> Self {
         init_debug_logger();
         MockTimeSource::set_time(0);
+        MockTimeSource::set_clock_offset(0);
         Self { next_port: 1, nodes: HashMap::default(), messages: VecDeque::with_capacity(10) }
     }
 
@@ -89,7 +97,8 @@ impl<P: Protocol> Simulator<P> {
         }
         DebugLogger::set_node(self.next_port as usize);
         self.next_port += 1;
-        let node = TestNode::new(&config, MockSocket::new(addr), MockDevice::new(), None, None);
+        let node = TestNode::new(&config, MockSocket::new(addr), MockDevice::with_type(config.device_type), None, None)
+            .unwrap();
         DebugLogger::set_node(0);
         self.nodes.insert(addr, node);
         addr
@@ -164,6 +173,19 @@ impl<P: Protocol> Simulator<P> {
         }
     }
 
+    /// Runs the housekeeping in real time until the condition holds, for tasks that run in background threads
+    #[allow(dead_code)]
+    pub fn wait_until<F: Fn(&Self) -> bool>(&mut self, cond: F) {
+        for _ in 0..1000 {
+            if cond(self) {
+                return;
+            }
+            thread::sleep(time::Duration::from_millis(10));
+            self.trigger_housekeep();
+            self.simulate_all_messages();
+        }
+    }
+
     pub fn connect(&mut self, src: SocketAddr, dst: SocketAddr) {
         let node = self.nodes.get_mut(&src).unwrap();
         DebugLogger::set_node(node.get_num());
@@ -175,6 +197,18 @@ impl<P: Protocol> Simulator<P> {
         }
     }
 
+    pub fn control(&mut self, addr: SocketAddr, command: ControlCommand) -> Result<String, Error> {
+        let node = self.nodes.get_mut(&addr).unwrap();
+        DebugLogger::set_node(node.get_num());
+        let res = node.handle_control_command(&command);
+        DebugLogger::set_node(0);
+        let sock = node.socket();
+        while let Some((dst, data)) = sock.pop_outbound() {
+            self.messages.push_back((addr, dst, data));
+        }
+        res
+    }
+
     pub fn is_connected(&self, src: SocketAddr, dst: SocketAddr) -> bool {
         self.nodes.get(&src).unwrap().is_connected(&dst)
     }
@@ -190,8 +224,15 @@ impl<P: Protocol> Simulator<P> {
     }
 
     pub fn put_payload(&mut self, addr: SocketAddr, data: Vec<u8>) {
+        self.put_payloads(addr, vec![data])
+    }
+
+    /// Puts all packets on the device before the node handles the device event
+    pub fn put_payloads(&mut self, addr: SocketAddr, packets: Vec<Vec<u8>>) {
         let node = self.nodes.get_mut(&addr).unwrap();
-        node.device().put_inbound(data);
+        for data in packets {
+            node.device().put_inbound(data);
+        }
         DebugLogger::set_node(node.get_num());
         node.trigger_device_event();
         DebugLogger::set_node(0);
@@ -208,4 +249,20 @@ impl<P: Protocol> Simulator<P> {
     pub fn drop_message(&mut self) {
         self.messages.pop_front();
     }
+
+    /// Moves the next message behind all other messages
+    #[allow(dead_code)]
+    pub fn delay_message(&mut self) {
+        if let Some(msg) = self.messages.pop_front() {
+            self.messages.push_back(msg);
+        }
+    }
+
+    /// Changes the source address of the next message
+    #[allow(dead_code)]
+    pub fn spoof_message(&mut self, src: SocketAddr) {
+        if let Some(msg) = self.messages.front_mut() {
+            msg.0 = src;
+        }
+    }
 }
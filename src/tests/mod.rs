@@ -5,4 +5,4 @@
 mod common;
 mod nat;
 mod payload;
-mod peers;
\ No newline at end of file
+mod peers;
@@ -114,6 +114,129 @@ fn switch_honours_vlans() {
     assert_eq!(Some(payload), sim.pop_payload(node3));
 }
 
+#[test]
+fn switch_filters_ethertypes() {
+    let ethertypes = vec!["ipv4".to_string(), "ipv6".to_string(), "arp".to_string()];
+    let config = Config { device_type: Type::Tap, ethertypes, ..Config::default() };
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config);
+    let node2 = sim.add_node(false, &config);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+
+    // LLDP frames are not forwarded
+    let payload = vec![2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 0x88, 0xcc, 3, 4, 5];
+    sim.put_payload(node1, payload);
+    sim.simulate_all_messages();
+    assert_eq!(None, sim.pop_payload(node2));
+
+    // ARP frames are forwarded
+    let payload = vec![2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 8, 6, 3, 4, 5];
+    sim.put_payload(node1, payload.clone());
+    sim.simulate_all_messages();
+    assert_eq!(Some(payload), sim.pop_payload(node2));
+}
+
+#[test]
+fn switch_answers_arp_locally() {
+    let config = Config { device_type: Type::Tap, arp_proxy: true, ..Config::default() };
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config);
+    let node2 = sim.add_node(false, &config);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+
+    // ARP request from 10.0.0.2 behind node2 for 10.0.0.3
+    let request = vec![
+        255, 255, 255, 255, 255, 255, 2, 2, 2, 2, 2, 2, 8, 6, 0, 1, 8, 0, 6, 4, 0, 1, 2, 2, 2, 2, 2, 2, 10, 0, 0, 2, 0,
+        0, 0, 0, 0, 0, 10, 0, 0, 3,
+    ];
+    sim.put_payload(node2, request.clone());
+    sim.simulate_all_messages();
+    assert_eq!(Some(request), sim.pop_payload(node1));
+
+    // ARP request from 10.0.0.1 behind node1 for 10.0.0.2 is answered by node1
+    let request = vec![
+        255, 255, 255, 255, 255, 255, 1, 1, 1, 1, 1, 1, 8, 6, 0, 1, 8, 0, 6, 4, 0, 1, 1, 1, 1, 1, 1, 1, 10, 0, 0, 1, 0,
+        0, 0, 0, 0, 0, 10, 0, 0, 2,
+    ];
+    let reply = vec![
+        1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 8, 6, 0, 1, 8, 0, 6, 4, 0, 2, 2, 2, 2, 2, 2, 2, 10, 0, 0, 2, 1, 1, 1, 1, 1,
+        1, 10, 0, 0, 1,
+    ];
+    sim.put_payload(node1, request);
+    sim.simulate_all_messages();
+    assert_eq!(Some(reply), sim.pop_payload(node1));
+    assert_eq!(None, sim.pop_payload(node2));
+}
+
+#[test]
+fn switch_serves_dhcp() {
+    use crate::{config::DhcpConfig, dhcp::dhcp_frame};
+    let dhcp = DhcpConfig {
+        server: "10.0.0.1/24".to_string(),
+        range: "10.0.0.100-10.0.0.200".to_string(),
+        router: None,
+        dns: vec![],
+        lease_time: None,
+        lease_file: None,
+    };
+    let config1 = Config { device_type: Type::Tap, dhcp: Some(dhcp), ..Config::default() };
+    let config2 = Config { device_type: Type::Tap, ..Config::default() };
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config1);
+    let node2 = sim.add_node(false, &config2);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+
+    // DHCP discover from a host behind node2 is answered by node1
+    let discover = dhcp_frame(1, [2, 2, 2, 2, 2, 2], None, None);
+    sim.put_payload(node2, discover.clone());
+    sim.simulate_all_messages();
+    assert_eq!(Some(discover), sim.pop_payload(node1));
+    let offer = sim.pop_payload(node2).unwrap();
+    assert_eq!(2, offer[42 + 242]);
+    assert_eq!(&[10, 0, 0, 100], &offer[42 + 16..42 + 20]);
+
+    // DHCP discover from a host behind node1 is answered locally
+    let discover = dhcp_frame(1, [1, 1, 1, 1, 1, 1], None, None);
+    sim.put_payload(node1, discover);
+    sim.simulate_all_messages();
+    let offer = sim.pop_payload(node1).unwrap();
+    assert_eq!(&[10, 0, 0, 101], &offer[42 + 16..42 + 20]);
+    assert_eq!(None, sim.pop_payload(node2));
+}
+
+#[test]
+fn switch_respects_peer_mtu() {
+    let config1 = Config { device_type: Type::Tap, ..Config::default() };
+    let config2 = Config { device_type: Type::Tap, mtu: Some(1000), ..Config::default() };
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config1);
+    let node2 = sim.add_node(false, &config2);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+
+    let mut payload = vec![2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 8, 0];
+    payload.resize(1014, 0);
+    sim.put_payload(node1, payload.clone());
+    sim.simulate_all_messages();
+    assert_eq!(Some(payload.clone()), sim.pop_payload(node2));
+
+    payload.push(0);
+    sim.put_payload(node1, payload);
+    sim.simulate_all_messages();
+    assert_eq!(None, sim.pop_payload(node2));
+}
+
 #[test]
 #[ignore]
 fn switch_forgets() {
@@ -152,6 +275,97 @@ fn router_delivers() {
     assert_eq!(Some(payload), sim.pop_payload(node2));
 }
 
+#[test]
+fn router_applies_firewall() {
+    use crate::{
+        config::FirewallConfig,
+        firewall::{Action, Direction, RuleConfig},
+    };
+    let config1 = Config {
+        device_type: Type::Tun,
+        auto_claim: false,
+        claims: vec!["1.1.1.1/32".to_string()],
+        ..Config::default()
+    };
+    let config2 = Config {
+        device_type: Type::Tun,
+        auto_claim: false,
+        claims: vec!["2.2.2.2/32".to_string()],
+        firewall: FirewallConfig {
+            default: Action::Allow,
+            rules: vec![RuleConfig {
+                action: Action::Deny,
+                direction: Some(Direction::In),
+                src: None,
+                dst: None,
+                protocol: Some("tcp".to_string()),
+                port: Some("22".to_string()),
+            }],
+            ..FirewallConfig::default()
+        },
+        ..Config::default()
+    };
+    let mut sim = TunSimulator::new();
+    let node1 = sim.add_node(false, &config1);
+    let node2 = sim.add_node(false, &config2);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+
+    // TCP packet to port 22 is blocked
+    let payload = vec![0x45, 0, 0, 24, 0, 0, 0, 0, 64, 6, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 0x30, 0x39, 0, 22];
+    sim.put_payload(node1, payload);
+    sim.simulate_all_messages();
+    assert_eq!(None, sim.pop_payload(node2));
+
+    // UDP packet to port 22 is delivered
+    let payload = vec![0x45, 0, 0, 24, 0, 0, 0, 0, 64, 17, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 0x30, 0x39, 0, 22];
+    sim.put_payload(node1, payload.clone());
+    sim.simulate_all_messages();
+    assert_eq!(Some(payload), sim.pop_payload(node2));
+}
+
+#[test]
+fn router_translates_overlapping_prefixes() {
+    use crate::config::NatRuleConfig;
+    let nat = |peer: &str, local: &str| NatRuleConfig {
+        peer: peer.to_string(),
+        remote: "10.0.1.0/24".to_string(),
+        local: local.to_string(),
+    };
+    let config1 = Config {
+        device_type: Type::Tun,
+        auto_claim: false,
+        claims: vec!["10.0.1.0/24".to_string()],
+        node_name: Some("site1".to_string()),
+        nat: vec![nat("site2", "10.2.1.0/24")],
+        ..Config::default()
+    };
+    let config2 = Config {
+        device_type: Type::Tun,
+        auto_claim: false,
+        claims: vec!["10.0.1.0/24".to_string()],
+        node_name: Some("site2".to_string()),
+        nat: vec![nat("site1", "10.1.1.0/24")],
+        ..Config::default()
+    };
+    let mut sim = TunSimulator::new();
+    let node1 = sim.add_node(false, &config1);
+    let node2 = sim.add_node(false, &config2);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+
+    // 10.0.1.5 at site1 talks to 10.0.1.7 at site2 via 10.2.1.7 and appears as 10.1.1.5 there
+    let payload = vec![0x45, 0, 0, 24, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 1, 5, 10, 2, 1, 7, 0x30, 0x39, 0, 53];
+    sim.put_payload(node1, payload);
+    sim.simulate_all_messages();
+    let payload = sim.pop_payload(node2).unwrap();
+    assert_eq!(&[10, 1, 1, 5, 10, 0, 1, 7], &payload[12..20]);
+}
+
 #[test]
 fn router_drops_unknown_dest() {
     let config1 = Config {
@@ -182,3 +396,326 @@ fn router_drops_unknown_dest() {
 
     assert_eq!(None, sim.pop_payload(node2));
 }
+
+#[test]
+fn router_rejects_claims_outside_policy() {
+    let config1 = Config {
+        device_type: Type::Tun,
+        auto_claim: false,
+        claims: vec!["1.1.1.1/32".to_string()],
+        accept_claims: vec![AcceptClaimsConfig { key: "*".to_string(), claims: vec!["2.0.0.0/8".to_string()] }],
+        ..Config::default()
+    };
+    let config2 = Config {
+        device_type: Type::Tun,
+        auto_claim: false,
+        claims: vec!["2.2.2.2/32".to_string(), "0.0.0.0/0".to_string()],
+        ..Config::default()
+    };
+    let mut sim = TunSimulator::new();
+    let node1 = sim.add_node(false, &config1);
+    let node2 = sim.add_node(false, &config2);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+
+    let payload = vec![0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2];
+    sim.put_payload(node1, payload.clone());
+    sim.simulate_all_messages();
+    assert_eq!(Some(payload), sim.pop_payload(node2));
+
+    // The default route claimed by node2 is not accepted
+    let payload = vec![0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 3, 3, 3, 3];
+    sim.put_payload(node1, payload);
+    sim.simulate_all_messages();
+    assert_eq!(None, sim.pop_payload(node2));
+}
+
+#[test]
+fn observer_does_not_forward() {
+    let config = Config { device_type: Type::Tap, ..Config::default() };
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config);
+    let node2 = sim.add_node(false, &config);
+    let observer = sim.add_node(false, &Config { observer: true, ..config });
+
+    sim.connect(node1, node2);
+    sim.connect(observer, node1);
+    sim.simulate_all_messages();
+    sim.simulate_time(120);
+
+    // The observer learns the other peers
+    assert!(sim.is_connected(observer, node1));
+    assert!(sim.is_connected(observer, node2));
+
+    let payload = vec![2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 8, 0, 3, 4, 5];
+
+    sim.put_payload(node1, payload.clone());
+    sim.simulate_all_messages();
+
+    assert_eq!(Some(payload.clone()), sim.pop_payload(node2));
+    assert_eq!(None, sim.pop_payload(observer));
+
+    sim.put_payload(observer, payload);
+    sim.simulate_all_messages();
+
+    assert_eq!(None, sim.pop_payload(node1));
+    assert_eq!(None, sim.pop_payload(node2));
+}
+
+#[test]
+fn latency_bypass_sends_small_packets_first() {
+    let config = Config { device_type: Type::Tap, ..Config::default() };
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &Config { latency_bypass: true, ..config.clone() });
+    let node2 = sim.add_node(false, &config);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+
+    let packet = |protocol: u8, payload_len: usize| {
+        let mut data = vec![2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 8, 0];
+        data.extend_from_slice(&[0x45, 0, 0, 0, 0, 0, 0, 0, 64, protocol, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+        let mut tcp = vec![0; 20];
+        tcp[12] = 0x50;
+        data.extend_from_slice(&tcp);
+        data.resize(data.len() + payload_len, 0);
+        data
+    };
+    let bulk1 = packet(6, 1000);
+    let bulk2 = packet(6, 500);
+    let ack = packet(6, 0);
+
+    sim.put_payloads(node1, vec![bulk1.clone(), ack.clone(), bulk2.clone()]);
+    sim.simulate_all_messages();
+
+    assert_eq!(Some(ack), sim.pop_payload(node2));
+    assert_eq!(Some(bulk1), sim.pop_payload(node2));
+    assert_eq!(Some(bulk2), sim.pop_payload(node2));
+    assert_eq!(None, sim.pop_payload(node2));
+}
+
+#[test]
+fn early_data_is_sent_with_handshake() {
+    let config1 = Config {
+        device_type: Type::Tun,
+        auto_claim: false,
+        claims: vec!["1.1.1.1/32".to_string()],
+        early_data: true,
+        ..Config::default()
+    };
+    let config2 = Config {
+        device_type: Type::Tun,
+        auto_claim: false,
+        claims: vec!["2.2.2.2/32".to_string()],
+        ..Config::default()
+    };
+    let mut sim = TunSimulator::new();
+    let node1 = sim.add_node(false, &config1);
+    let node2 = sim.add_node(false, &config2);
+
+    sim.connect(node1, node2);
+    let payload = vec![0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2];
+    sim.put_payload(node1, payload.clone());
+
+    // The payload has no destination yet, it is delivered with the handshake instead of being dropped
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node2, node1));
+    assert_eq!(Some(payload), sim.pop_payload(node2));
+    assert_eq!(None, sim.pop_payload(node2));
+}
+
+#[test]
+fn deferred_claims_wait_for_interface_address() {
+    let config1 = Config {
+        device_type: Type::Tun,
+        auto_claim: false,
+        claims: vec!["1.1.1.1/32".to_string()],
+        ..Config::default()
+    };
+    let config2 = Config {
+        device_type: Type::Tun,
+        ip: Some("2.2.2.2/24".to_string()),
+        auto_claim: true,
+        defer_claims: true,
+        ..Config::default()
+    };
+    let mut sim = TunSimulator::new();
+    let node1 = sim.add_node(false, &config1);
+    let node2 = sim.add_node(false, &config2);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+
+    // The address is not claimed before the interface has it
+    let payload = vec![0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2];
+    sim.put_payload(node1, payload.clone());
+    sim.simulate_all_messages();
+    assert_eq!(None, sim.pop_payload(node2));
+
+    sim.get_node(node2).device().set_ip("2.2.2.2".parse().unwrap());
+    sim.simulate_time(1);
+    sim.put_payload(node1, payload.clone());
+    sim.simulate_all_messages();
+    assert_eq!(Some(payload), sim.pop_payload(node2));
+}
+
+#[test]
+fn source_validation_drops_unclaimed_sources() {
+    let config1 = Config {
+        device_type: Type::Tun,
+        auto_claim: false,
+        claims: vec!["1.1.1.1/32".to_string()],
+        ..Config::default()
+    };
+    let config2 = Config {
+        device_type: Type::Tun,
+        auto_claim: false,
+        claims: vec!["2.2.2.2/32".to_string()],
+        source_validation: true,
+        ..Config::default()
+    };
+    let mut sim = TunSimulator::new();
+    let node1 = sim.add_node(false, &config1);
+    let node2 = sim.add_node(false, &config2);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+    assert!(sim.is_connected(node2, node1));
+
+    let payload = vec![0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2];
+    sim.put_payload(node1, payload.clone());
+    sim.simulate_all_messages();
+    assert_eq!(Some(payload), sim.pop_payload(node2));
+
+    // Source 3.3.3.3 is not claimed by node1
+    let payload = vec![0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 3, 3, 3, 2, 2, 2, 2];
+    sim.put_payload(node1, payload);
+    sim.simulate_all_messages();
+    assert_eq!(None, sim.pop_payload(node2));
+}
+
+#[test]
+fn duplicated_packets_are_delivered_once() {
+    let config1 = Config {
+        device_type: Type::Tun,
+        auto_claim: false,
+        claims: vec!["1.1.1.1/32".to_string()],
+        duplication: Some(DuplicationConfig { dscp: vec![46], max_size: None }),
+        ..Config::default()
+    };
+    let config2 =
+        Config { device_type: Type::Tun, auto_claim: false, claims: vec!["2.2.2.2/32".to_string()], ..Config::default() };
+    let mut sim = TunSimulator::new();
+    let node1 = sim.add_node(false, &config1);
+    let node2 = sim.add_node(false, &config2);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+    assert!(sim.is_connected(node2, node1));
+
+    // Packets marked with DSCP EF are sent twice
+    let payload = vec![0x40, 46 << 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2];
+    sim.put_payload(node1, payload.clone());
+    assert_eq!(sim.message_count(), 2);
+    sim.simulate_all_messages();
+    assert_eq!(Some(payload), sim.pop_payload(node2));
+    assert_eq!(None, sim.pop_payload(node2));
+
+    // Other packets are sent once
+    let payload = vec![0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2];
+    sim.put_payload(node1, payload.clone());
+    assert_eq!(sim.message_count(), 1);
+    sim.simulate_all_messages();
+    assert_eq!(Some(payload), sim.pop_payload(node2));
+}
+
+#[test]
+fn fec_recovers_lost_packet() {
+    let fec = FecConfig { loss_threshold: 0.0, group_size: 4 };
+    let config1 = Config {
+        device_type: Type::Tun,
+        auto_claim: false,
+        claims: vec!["1.1.1.1/32".to_string()],
+        fec: Some(fec.clone()),
+        ..Config::default()
+    };
+    let config2 = Config {
+        device_type: Type::Tun,
+        auto_claim: false,
+        claims: vec!["2.2.2.2/32".to_string()],
+        fec: Some(fec),
+        ..Config::default()
+    };
+    let mut sim = TunSimulator::new();
+    let node1 = sim.add_node(false, &config1);
+    let node2 = sim.add_node(false, &config2);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+    // FEC is applied once node2 reported its loss
+    sim.simulate_time(120);
+
+    let packets: Vec<Vec<u8>> =
+        (0..4).map(|i| vec![0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, i]).collect();
+    sim.put_payloads(node1, packets.clone());
+    // Four packets and one parity packet
+    assert_eq!(sim.message_count(), 5);
+    sim.simulate_next_message();
+    sim.drop_message();
+    sim.simulate_all_messages();
+    assert_eq!(Some(packets[0].clone()), sim.pop_payload(node2));
+    assert_eq!(Some(packets[2].clone()), sim.pop_payload(node2));
+    assert_eq!(Some(packets[3].clone()), sim.pop_payload(node2));
+    assert_eq!(Some(packets[1].clone()), sim.pop_payload(node2));
+    assert_eq!(None, sim.pop_payload(node2));
+}
+
+#[test]
+fn reordered_packets_are_delivered_in_order() {
+    let config1 = Config {
+        device_type: Type::Tun,
+        auto_claim: false,
+        claims: vec!["1.1.1.1/32".to_string()],
+        ..Config::default()
+    };
+    let config2 = Config {
+        device_type: Type::Tun,
+        auto_claim: false,
+        claims: vec!["2.2.2.2/32".to_string()],
+        reorder_window: Some(50),
+        ..Config::default()
+    };
+    let mut sim = TunSimulator::new();
+    let node1 = sim.add_node(false, &config1);
+    let node2 = sim.add_node(false, &config2);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+
+    let packets: Vec<Vec<u8>> =
+        (0..4).map(|i| vec![0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, i]).collect();
+    // The first packet starts the sequence
+    sim.put_payload(node1, packets[0].clone());
+    sim.simulate_all_messages();
+    assert_eq!(Some(packets[0].clone()), sim.pop_payload(node2));
+    sim.put_payloads(node1, packets[1..].to_vec());
+    assert_eq!(sim.message_count(), 3);
+    // The second packet arrives last
+    sim.delay_message();
+    sim.simulate_next_message();
+    sim.simulate_next_message();
+    assert_eq!(None, sim.pop_payload(node2));
+    sim.simulate_all_messages();
+    for packet in &packets[1..] {
+        assert_eq!(Some(packet.clone()), sim.pop_payload(node2));
+    }
+    assert_eq!(None, sim.pop_payload(node2));
+}
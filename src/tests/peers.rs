@@ -17,6 +17,20 @@ fn direct_connect() {
     assert!(sim.is_connected(node2, node1));
 }
 
+#[test]
+fn protocol_negotiated() {
+    use crate::messages::{CAPABILITIES, PROTOCOL_VERSION};
+    let config = Config::default();
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config);
+    let node2 = sim.add_node(false, &config);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert_eq!(Some((PROTOCOL_VERSION, CAPABILITIES)), sim.get_node(node1).peer_protocol(&node2));
+    assert_eq!(Some((PROTOCOL_VERSION, CAPABILITIES)), sim.get_node(node2).peer_protocol(&node1));
+}
+
 #[test]
 fn direct_connect_unencrypted() {
     let config = Config {
@@ -33,6 +47,23 @@ fn direct_connect_unencrypted() {
     assert!(sim.is_connected(node2, node1));
 }
 
+#[test]
+fn direct_connect_padded() {
+    let padded = Config { crypto: CryptoConfig { padding: true, ..CryptoConfig::default() }, ..Config::default() };
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &padded);
+    let node2 = sim.add_node(false, &Config::default());
+
+    // Nodes with and without padding understand each other
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+    assert!(sim.is_connected(node2, node1));
+    sim.simulate_time(120);
+    assert!(sim.is_connected(node1, node2));
+    assert!(sim.is_connected(node2, node1));
+}
+
 #[test]
 fn cross_connect() {
     let config = Config::default();
@@ -55,6 +86,89 @@ fn cross_connect() {
     assert!(sim.is_connected(node3, node2));
 }
 
+#[test]
+fn no_peer_exchange() {
+    let static_config = Config { peer_exchange: false, ..Config::default() };
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &Config::default());
+    let node2 = sim.add_node(false, &static_config);
+    let node3 = sim.add_node(false, &static_config);
+
+    // Only the configured peers are dialed, even if they are announced by other peers
+    sim.get_node(node2).add_peer_config(PeerConfig::new(node1.to_string()));
+    sim.get_node(node3).add_peer_config(PeerConfig::new(node1.to_string()));
+    sim.simulate_time(120);
+
+    assert!(sim.is_connected(node1, node2));
+    assert!(sim.is_connected(node2, node1));
+    assert!(sim.is_connected(node1, node3));
+    assert!(sim.is_connected(node3, node1));
+    assert!(!sim.is_connected(node2, node3));
+    assert!(!sim.is_connected(node3, node2));
+
+    // Explicit connections are still made
+    sim.connect(node2, node3);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node2, node3));
+    assert!(sim.is_connected(node3, node2));
+}
+
+#[test]
+fn control_connect_disconnect() {
+    let config = Config::default();
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config);
+    let node2 = sim.add_node(false, &config);
+
+    sim.control(node1, ControlCommand::Connect { address: node2.to_string(), persist: false }).unwrap();
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+    assert!(sim.is_connected(node2, node1));
+
+    sim.control(node1, ControlCommand::Disconnect { address: node2.to_string(), persist: false }).unwrap();
+    sim.simulate_all_messages();
+    sim.simulate_time(120);
+    assert!(!sim.is_connected(node1, node2));
+    assert!(!sim.is_connected(node2, node1));
+
+    // Persisting needs a state directory
+    assert!(sim.control(node1, ControlCommand::Connect { address: node2.to_string(), persist: true }).is_err());
+}
+
+#[test]
+fn control_lists_peer_states() {
+    let config = Config::default();
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config);
+    let node2 = sim.add_node(false, &config);
+
+    sim.connect(node1, node2);
+    let peers = sim.control(node1, ControlCommand::Peers).unwrap();
+    assert!(peers.starts_with(&format!("{} handshake-pending ", addr_nice(node2))), "{}", peers);
+    sim.simulate_all_messages();
+    let peers = sim.control(node1, ControlCommand::Peers).unwrap();
+    assert!(peers.starts_with(&format!("{} established ", addr_nice(node2))), "{}", peers);
+
+    sim.control(node1, ControlCommand::Disconnect { address: node2.to_string(), persist: false }).unwrap();
+    let peers = sim.control(node1, ControlCommand::Peers).unwrap();
+    assert!(peers.starts_with(&format!("{} closing ", addr_nice(node2))), "{}", peers);
+}
+
+#[test]
+fn control_shows_stats() {
+    let config = Config::default();
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config);
+    let node2 = sim.add_node(false, &config);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    let stats = sim.control(node1, ControlCommand::Stats).unwrap();
+    assert!(stats.starts_with("time: "), "{}", stats);
+    assert!(stats.contains("\npeers:\n"), "{}", stats);
+    assert!(stats.contains(&addr_nice(node2).to_string()), "{}", stats);
+}
+
 #[test]
 fn connect_via_beacons() {
     let mut sim = TapSimulator::new();
@@ -73,6 +187,91 @@ fn connect_via_beacons() {
     assert!(sim.is_connected(node2, node1));
 }
 
+#[test]
+fn hub_hides_spokes() {
+    let mut sim = TapSimulator::new();
+    let filter = ClaimFilterConfig { peer: "*".to_string(), import: None, export: Some(vec![]), export_peers: false };
+    let hub = sim.add_node(false, &Config { claim_filters: vec![filter], ..Config::default() });
+    let spoke1 = sim.add_node(false, &Config::default());
+    let spoke2 = sim.add_node(false, &Config::default());
+
+    sim.connect(spoke1, hub);
+    sim.connect(spoke2, hub);
+    sim.simulate_all_messages();
+
+    sim.simulate_time(120);
+
+    assert!(sim.is_connected(hub, spoke1));
+    assert!(sim.is_connected(hub, spoke2));
+    assert!(!sim.is_connected(spoke1, spoke2));
+    assert!(!sim.is_connected(spoke2, spoke1));
+}
+
+#[test]
+fn spokes_only_connect_to_hub() {
+    let spoke_config = Config { topology: Topology::Spoke, ..Config::default() };
+    let mut sim = TapSimulator::new();
+    let hub = sim.add_node(false, &Config { topology: Topology::Hub, ..Config::default() });
+    let spoke1 = sim.add_node(false, &spoke_config);
+    let spoke2 = sim.add_node(false, &spoke_config);
+    let other = sim.add_node(false, &Config::default());
+
+    sim.get_node(spoke1).add_peer_config(PeerConfig::new(hub.to_string()));
+    sim.get_node(spoke2).add_peer_config(PeerConfig::new(hub.to_string()));
+    // Spokes neither dial other nodes nor accept their connections
+    sim.connect(spoke1, spoke2);
+    sim.connect(other, spoke1);
+    sim.simulate_time(120);
+
+    assert!(sim.is_connected(hub, spoke1));
+    assert!(sim.is_connected(spoke1, hub));
+    assert!(sim.is_connected(hub, spoke2));
+    assert!(sim.is_connected(spoke2, hub));
+    assert!(!sim.is_connected(spoke1, spoke2));
+    assert!(!sim.is_connected(spoke2, spoke1));
+    assert!(!sim.is_connected(other, spoke1));
+    assert!(!sim.is_connected(spoke1, other));
+}
+
+#[test]
+fn ipam_leases_addresses() {
+    let coordinator_config = Config {
+        device_type: Type::Tun,
+        ipam: Some(IpamConfig { pool: Some("10.0.0.0/24".to_string()), ..IpamConfig::default() }),
+        ..Config::default()
+    };
+    let client_config = |name: &str| Config {
+        device_type: Type::Tun,
+        node_name: Some(name.to_string()),
+        ipam: Some(IpamConfig { request: true, ..IpamConfig::default() }),
+        ..Config::default()
+    };
+    let mut sim = TunSimulator::new();
+    let coordinator = sim.add_node(false, &coordinator_config);
+    let node1 = sim.add_node(false, &client_config("node1"));
+    let node2 = sim.add_node(false, &client_config("node2"));
+
+    sim.connect(node1, coordinator);
+    sim.connect(node2, coordinator);
+    sim.simulate_time(30);
+
+    let ip1 = sim.get_node(node1).device().get_ip().unwrap();
+    let ip2 = sim.get_node(node2).device().get_ip().unwrap();
+    let pool: Range = "10.0.0.0/24".parse().unwrap();
+    assert_ne!(ip1, ip2);
+    assert!(pool.matches(Address::from_ipv4(ip1)));
+    assert!(pool.matches(Address::from_ipv4(ip2)));
+
+    // The leased addresses are claimed, so the nodes can reach each other
+    assert!(sim.is_connected(node1, node2));
+    let mut payload = vec![0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    payload.extend_from_slice(&ip1.octets());
+    payload.extend_from_slice(&ip2.octets());
+    sim.put_payload(node1, payload.clone());
+    sim.simulate_all_messages();
+    assert_eq!(Some(payload), sim.pop_payload(node2));
+}
+
 #[test]
 fn reconnect_after_timeout() {
     let config = Config::default();
@@ -95,6 +294,221 @@ fn reconnect_after_timeout() {
     assert!(sim.is_connected(node2, node1));
 }
 
+#[test]
+fn backup_peer_after_failure() {
+    let config = Config::default();
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config);
+    let node2 = sim.add_node(false, &config);
+    let node3 = sim.add_node(false, &config);
+
+    let node = sim.get_node(node1);
+    node.add_peer_config(PeerConfig::new("[::]:100".to_string()));
+    node.add_peer_config(PeerConfig { priority: 1, ..PeerConfig::new(node2.to_string()) });
+    node.add_peer_config(PeerConfig { priority: 2, ..PeerConfig::new(node3.to_string()) });
+    sim.simulate_time(2);
+    assert!(!sim.is_connected(node1, node2));
+
+    sim.simulate_time(10);
+    assert!(sim.is_connected(node1, node2));
+    assert!(sim.is_connected(node2, node1));
+    assert!(!sim.is_connected(node1, node3));
+}
+
+#[test]
+fn reconnect_after_resume() {
+    let config = Config::default();
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config);
+    let node2 = sim.add_node(false, &config);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+
+    // Suspended for less than the peer timeout
+    sim.set_time(100);
+    sim.trigger_node_housekeep(node1);
+    assert!(!sim.is_connected(node1, node2));
+
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+    assert!(sim.is_connected(node2, node1));
+}
+
+#[test]
+fn fast_failover_keeps_live_peer() {
+    let config = Config { device_type: Type::Tap, fast_failover: true, ..Config::default() };
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config);
+    let node2 = sim.add_node(false, &config);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    sim.put_payload(node2, vec![1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 8, 0, 1, 2, 3]);
+    sim.simulate_all_messages();
+    sim.put_payload(node1, vec![2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 8, 0, 3, 2, 1]);
+
+    // The payload is lost, but the probes are answered
+    while sim.message_count() > 0 {
+        sim.drop_message();
+    }
+    sim.set_time(1);
+    sim.trigger_node_housekeep(node1);
+    sim.simulate_all_messages();
+
+    sim.set_time(3);
+    sim.trigger_node_housekeep(node1);
+    assert!(sim.is_connected(node1, node2));
+}
+
+#[test]
+fn fast_failover_detects_dead_peer() {
+    let config = Config { device_type: Type::Tap, fast_failover: true, ..Config::default() };
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config);
+    let node2 = sim.add_node(false, &config);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    sim.put_payload(node2, vec![1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 8, 0, 1, 2, 3]);
+    sim.simulate_all_messages();
+    sim.put_payload(node1, vec![2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 8, 0, 3, 2, 1]);
+
+    // Neither the payload nor the probes reach node2
+    for time in 1..3 {
+        while sim.message_count() > 0 {
+            sim.drop_message();
+        }
+        sim.set_time(time);
+        sim.trigger_node_housekeep(node1);
+        assert!(sim.is_connected(node1, node2));
+    }
+    sim.set_time(3);
+    sim.trigger_node_housekeep(node1);
+    assert!(!sim.is_connected(node1, node2));
+}
+
+#[test]
+fn clock_skew_detected() {
+    let config = Config::default();
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config);
+    let node2 = sim.add_node(false, &config);
+
+    // The handshake of node1 carries a clock that is one hour ahead
+    MockTimeSource::set_clock_offset(3600);
+    sim.connect(node1, node2);
+    MockTimeSource::set_clock_offset(0);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node2, node1));
+    assert_eq!(Some(3600), sim.get_node(node2).clock_skew(&node1));
+    assert_eq!(Some(0), sim.get_node(node1).clock_skew(&node2));
+}
+
+#[test]
+fn mtu_mismatch_detected() {
+    let config = Config { peer_exchange: false, ..Config::default() };
+    let config1 = Config { mtu: Some(1400), ..config.clone() };
+    let config2 = Config { mtu: Some(1300), ..config.clone() };
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config1);
+    let node2 = sim.add_node(false, &config2);
+    let node3 = sim.add_node(false, &config);
+
+    // Without peer exchange, node1 and node3 only know their configured peer
+    sim.get_node(node1).add_peer_config(PeerConfig::new(node2.to_string()));
+    sim.get_node(node2).add_peer_config(PeerConfig::new(node3.to_string()));
+    sim.simulate_time(120);
+    assert!(sim.is_connected(node1, node2));
+    assert!(!sim.is_connected(node1, node3));
+    assert!(sim.is_connected(node2, node3));
+    assert_eq!(Some(1300), sim.get_node(node1).peer_mtu(&node2));
+    assert_eq!(Some(1400), sim.get_node(node2).peer_mtu(&node1));
+    assert_eq!(None, sim.get_node(node2).peer_mtu(&node3));
+    assert_eq!(1, sim.get_node(node1).mtu_mismatches());
+    assert_eq!(1, sim.get_node(node2).mtu_mismatches());
+    assert_eq!(0, sim.get_node(node3).mtu_mismatches());
+}
+
+#[test]
+fn duplicate_identity_policy() {
+    let (hub_key, hub_pub) = Crypto::generate_keypair(Some("hub"));
+    let (node_key, node_pub) = Crypto::generate_keypair(Some("node"));
+    let crypto = CryptoConfig { trusted_keys: vec![hub_pub, node_pub], ..CryptoConfig::default() };
+    // Without peer exchange, the nodes only connect to the hub and do not reconnect via each other
+    let node_config = Config {
+        peer_exchange: false,
+        crypto: CryptoConfig { private_key: Some(node_key), ..crypto.clone() },
+        ..Config::default()
+    };
+    for &(policy, keeps_older, keeps_newer) in &[
+        (DuplicateIdentityPolicy::RejectNewer, true, false),
+        (DuplicateIdentityPolicy::RejectOlder, false, true),
+        (DuplicateIdentityPolicy::Allow, true, true),
+    ] {
+        let hub_config = Config {
+            duplicate_identity: policy,
+            crypto: CryptoConfig { private_key: Some(hub_key.clone()), ..crypto.clone() },
+            ..Config::default()
+        };
+        let mut sim = TapSimulator::new();
+        let hub = sim.add_node(false, &hub_config);
+        // Both nodes use the same key like VMs cloned from one image
+        let older = sim.add_node(false, &node_config);
+        let newer = sim.add_node(false, &node_config);
+
+        sim.get_node(older).add_peer_config(PeerConfig::new(hub.to_string()));
+        sim.simulate_time(1);
+        assert!(sim.is_connected(hub, older));
+        sim.get_node(newer).add_peer_config(PeerConfig::new(hub.to_string()));
+        sim.simulate_time(2);
+        assert_eq!(keeps_older, sim.is_connected(hub, older), "{}", policy);
+        assert_eq!(keeps_newer, sim.is_connected(hub, newer), "{}", policy);
+        assert_eq!(keeps_older, sim.is_connected(older, hub), "{}", policy);
+        assert_eq!(keeps_newer, sim.is_connected(newer, hub), "{}", policy);
+        assert_eq!(1, sim.get_node(hub).duplicate_identities());
+    }
+}
+
+#[test]
+fn duplicate_identity_policy_after_auth_hook() {
+    let (hub_key, hub_pub) = Crypto::generate_keypair(Some("hub"));
+    let (node_key, node_pub) = Crypto::generate_keypair(Some("node"));
+    let crypto = CryptoConfig { trusted_keys: vec![hub_pub, node_pub], ..CryptoConfig::default() };
+    let node_config = Config {
+        peer_exchange: false,
+        crypto: CryptoConfig { private_key: Some(node_key), ..crypto.clone() },
+        ..Config::default()
+    };
+    let mut sim = TapSimulator::new();
+    let older = sim.add_node(false, &node_config);
+    let newer = sim.add_node(false, &node_config);
+    let hub_config = Config {
+        duplicate_identity: DuplicateIdentityPolicy::RejectOlder,
+        auth_hook: Some(format!("test \"$PEER\" != \"{}\"", addr_nice(newer))),
+        crypto: CryptoConfig { private_key: Some(hub_key), ..crypto },
+        ..Config::default()
+    };
+    let hub = sim.add_node(false, &hub_config);
+
+    sim.get_node(older).add_peer_config(PeerConfig::new(hub.to_string()));
+    sim.simulate_time(1);
+    sim.wait_until(|sim| sim.is_connected(hub, older));
+    // The decision for the shared key expires before the newer node connects
+    sim.simulate_time(100);
+    sim.get_node(newer).add_peer_config(PeerConfig::new(hub.to_string()));
+    sim.simulate_time(101);
+    assert!(sim.is_connected(newer, hub));
+    sim.wait_until(|sim| !sim.is_connected(newer, hub));
+    // The newer node is refused by the hook, so the policy does not close the older connection
+    assert!(!sim.is_connected(newer, hub));
+    assert!(!sim.is_connected(hub, newer));
+    assert!(sim.is_connected(hub, older));
+    assert!(sim.is_connected(older, hub));
+    assert_eq!(0, sim.get_node(hub).duplicate_identities());
+}
+
 #[test]
 fn lost_init_ping() {
     let config = Config::default();
@@ -177,3 +591,220 @@ fn automatic_peer_timeout() {
     // TODO Test
     unimplemented!()
 }
+
+#[test]
+fn spoofed_source_does_not_redirect_session() {
+    let config = Config { device_type: Type::Tap, ..Config::default() };
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config);
+    let node2 = sim.add_node(false, &config);
+    let spoofed = "[::]:9999".parse().unwrap();
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node2, node1));
+
+    // An authentic message replayed from another address does not move the session
+    sim.put_payload(node1, vec![2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 8, 0, 3, 4, 5]);
+    sim.spoof_message(spoofed);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node2, node1));
+    assert!(!sim.is_connected(node2, spoofed));
+    assert_eq!(None, sim.pop_payload(node2));
+}
+
+#[test]
+fn busy_link_suppresses_keepalives() {
+    let config = Config { device_type: Type::Tap, keepalive: Some(10), suppress_keepalives: true, ..Config::default() };
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config);
+    let node2 = sim.add_node(false, &config);
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+
+    // Data replaces the keepalives
+    for time in 1..=30 {
+        sim.set_time(time);
+        sim.put_payload(node1, vec![2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 8, 0, 3, 4, 5]);
+        sim.put_payload(node2, vec![1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 8, 0, 3, 2, 1]);
+        sim.simulate_all_messages();
+        assert!(sim.pop_payload(node1).is_some());
+        assert!(sim.pop_payload(node2).is_some());
+        sim.trigger_housekeep();
+        assert_eq!(sim.message_count(), 0);
+    }
+
+    // Idle links get keepalives again
+    let mut messages = 0;
+    for time in 31..=41 {
+        sim.set_time(time);
+        sim.trigger_housekeep();
+        messages += sim.message_count();
+        sim.simulate_all_messages();
+    }
+    assert!(messages > 0);
+    assert!(sim.is_connected(node1, node2));
+    assert!(sim.is_connected(node2, node1));
+}
+
+#[test]
+fn cross_connect_with_incremental_peer_lists() {
+    let config = Config::default();
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config);
+    let node2 = sim.add_node(false, &config);
+    let node3 = sim.add_node(false, &config);
+
+    // Both peers have acknowledged each others peer lists
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    sim.simulate_time(200);
+    assert!(!sim.is_connected(node2, node3));
+
+    // Peers that join later still form a full mesh
+    sim.connect(node1, node3);
+    sim.simulate_all_messages();
+    sim.simulate_time(400);
+
+    assert!(sim.is_connected(node2, node3));
+    assert!(sim.is_connected(node3, node2));
+}
+
+#[test]
+fn peer_limit_evicts_least_recently_seen() {
+    let config = Config::default();
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &Config { max_peers: Some(1), ..Config::default() });
+    let node2 = sim.add_node(false, &config);
+    let node3 = sim.add_node(false, &config);
+
+    sim.connect(node2, node1);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+
+    sim.connect(node3, node1);
+    sim.simulate_all_messages();
+    assert_eq!(sim.get_node(node1).peer_count(), 1);
+    assert!(sim.is_connected(node1, node3));
+    assert!(!sim.is_connected(node1, node2));
+    // The evicted peer is told to close the connection
+    assert!(!sim.is_connected(node2, node1));
+    // and it is not readmitted right away
+    sim.connect(node2, node1);
+    sim.simulate_all_messages();
+    assert!(!sim.is_connected(node1, node2));
+    assert!(sim.is_connected(node1, node3));
+}
+
+#[test]
+fn handshake_limit_evicts_oldest_handshake() {
+    let config = Config::default();
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &Config { max_handshakes: 1, ..Config::default() });
+    let node2 = sim.add_node(false, &config);
+    let node3 = sim.add_node(false, &config);
+
+    sim.connect(node2, node1);
+    sim.connect(node3, node1);
+    // The handshake of node3 replaces the one of node2
+    sim.simulate_next_message();
+    sim.simulate_next_message();
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node3));
+    assert!(!sim.is_connected(node1, node2));
+}
+
+#[test]
+fn dial_limit_queues_connections() {
+    let config = Config::default();
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &Config { max_dials: 1, ..Config::default() });
+    let node2 = sim.add_node(false, &config);
+    let node3 = sim.add_node(false, &config);
+
+    sim.connect(node1, node2);
+    sim.connect(node1, node3);
+    // Only the first handshake is started
+    assert_eq!(sim.message_count(), 1);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+    assert!(!sim.is_connected(node1, node3));
+
+    // The queued connection is started once the first handshake has finished
+    sim.trigger_node_housekeep(node1);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node3));
+}
+
+#[test]
+fn connect_prefers_own_address_family() {
+    use crate::net::mapped_addr;
+    use std::net::SocketAddr;
+    let mut sim = TapSimulator::new();
+    // The node only has an IPv4 address
+    let config = Config { advertise_addresses: vec!["192.0.2.1:1".to_string()], ..Default::default() };
+    let node1 = sim.add_node(false, &config);
+    let v4: SocketAddr = "192.0.2.2:3210".parse().unwrap();
+    let v6: SocketAddr = "[2001:db8::2]:3210".parse().unwrap();
+
+    let node = sim.get_node(node1);
+    node.connect(&[v6, v4] as &[SocketAddr]).unwrap();
+    let mut dsts = vec![];
+    while let Some((dst, _)) = node.socket().pop_outbound() {
+        dsts.push(dst);
+    }
+    assert_eq!(dsts, vec![mapped_addr(v4)]);
+
+    // Addresses of the other family are still tried if there are no others
+    node.connect(v6).unwrap();
+    assert_eq!(node.socket().pop_outbound().map(|(dst, _)| dst), Some(v6));
+}
+
+#[test]
+fn idle_mode_and_wake() {
+    let config = Config { idle_timeout: Some(300), ..Config::default() };
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config);
+    let node2 = sim.add_node(false, &Config::default());
+
+    sim.connect(node1, node2);
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+
+    // Without payload, the node closes its connections and stays quiet
+    sim.simulate_time(310);
+    assert!(!sim.is_connected(node1, node2));
+    assert!(!sim.is_connected(node2, node1));
+    sim.simulate_time(1000);
+    assert!(!sim.is_connected(node1, node2));
+
+    sim.control(node1, ControlCommand::Wake).unwrap();
+    sim.simulate_all_messages();
+    assert!(sim.is_connected(node1, node2));
+    assert!(sim.is_connected(node2, node1));
+}
+
+#[test]
+fn auth_hook_decides_in_background() {
+    let config = Config::default();
+    let admit = Config { auth_hook: Some("exit 0".to_string()), ..Config::default() };
+    let reject = Config { auth_hook: Some("exit 1".to_string()), ..Config::default() };
+    let mut sim = TapSimulator::new();
+    let node1 = sim.add_node(false, &config);
+    let node2 = sim.add_node(false, &admit);
+    let node3 = sim.add_node(false, &reject);
+
+    sim.connect(node1, node2);
+    sim.connect(node1, node3);
+    sim.simulate_all_messages();
+    // The handshakes are complete but the peers wait for the hooks
+    assert!(!sim.is_connected(node2, node1));
+    assert!(!sim.is_connected(node3, node1));
+    sim.wait_until(|sim| sim.is_connected(node2, node1) && !sim.is_connected(node1, node3));
+    assert!(sim.is_connected(node1, node2));
+    assert!(sim.is_connected(node2, node1));
+    assert!(!sim.is_connected(node1, node3));
+    assert!(!sim.is_connected(node3, node1));
+}
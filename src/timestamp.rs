@@ -0,0 +1,81 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Timestamps in the log file and the stats file, see `--time-format` and `--utc`
+//!
+//! The offset of the local time zone is looked up via the C library for every timestamp, so changes of the daylight
+//! saving time are followed. If the lookup fails, e.g. in containers without time zone data, the timestamp is given
+//! in UTC instead of aborting. Nothing is logged here since the logger itself uses this module.
+
+use std::{
+    mem,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{
+    format::{Item, StrftimeItems},
+    FixedOffset, TimeZone,
+};
+
+use crate::{error::Error, util::Time};
+
+pub const DEFAULT_TIME_FORMAT: &str = "%F %H:%M:%S";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timestamps {
+    format: String,
+    utc: bool,
+}
+
+/// Offset of the local time zone at the given time in seconds east of UTC
+pub fn local_offset(secs: Time) -> Option<i32> {
+    let time = secs as libc::time_t;
+    // Safe since localtime_r only writes into the given struct
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return None;
+    }
+    Some(tm.tm_gmtoff as i32)
+}
+
+impl Timestamps {
+    pub fn new(format: Option<&str>, utc: bool) -> Result<Self, Error> {
+        let format = format.unwrap_or(DEFAULT_TIME_FORMAT);
+        if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+            return Err(Error::InvalidConfigValue("Invalid time format", format.to_string()));
+        }
+        Ok(Self { format: format.to_string(), utc })
+    }
+
+    /// Formats the time given in seconds since the epoch
+    pub fn format(&self, secs: Time) -> String {
+        let offset = if self.utc { 0 } else { local_offset(secs).unwrap_or(0) };
+        match FixedOffset::east_opt(offset).and_then(|zone| zone.timestamp_opt(secs, 0).single()) {
+            Some(time) => time.format(&self.format).to_string(),
+            None => secs.to_string(),
+        }
+    }
+
+    pub fn now(&self) -> String {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as Time).unwrap_or(0);
+        self.format(secs)
+    }
+}
+
+impl Default for Timestamps {
+    fn default() -> Self {
+        Self { format: DEFAULT_TIME_FORMAT.to_string(), utc: false }
+    }
+}
+
+#[test]
+fn timestamps_format() {
+    let utc = Timestamps::new(None, true).unwrap();
+    assert_eq!(utc.format(1_600_000_000), "2020-09-13 12:26:40");
+    let iso = Timestamps::new(Some("%Y-%m-%dT%H:%M:%SZ"), true).unwrap();
+    assert_eq!(iso.format(0), "1970-01-01T00:00:00Z");
+    assert!(Timestamps::new(Some("%Q"), false).is_err());
+    // Local time never fails, whatever the time zone of the test system is
+    assert_eq!(Timestamps::default().format(1_600_000_000).len(), 19);
+}
@@ -71,11 +71,50 @@ impl TrafficEntry {
     }
 }
 
+/// Counters that are accumulated over the whole lifetime and persisted in the state directory
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct Counters {
+    pub in_bytes: u64,
+    pub in_packets: u64,
+    pub out_bytes: u64,
+    pub out_packets: u64,
+}
+
+impl Counters {
+    fn add(&mut self, entry: &TrafficEntry) {
+        self.in_bytes += entry.in_bytes;
+        self.in_packets += entry.in_packets as u64;
+        self.out_bytes += entry.out_bytes;
+        self.out_packets += entry.out_packets as u64;
+    }
+
+    fn parse(parts: &[&str]) -> Option<Self> {
+        match *parts {
+            [in_bytes, in_packets, out_bytes, out_packets] => Some(Counters {
+                in_bytes: in_bytes.parse().ok()?,
+                in_packets: in_packets.parse().ok()?,
+                out_bytes: out_bytes.parse().ok()?,
+                out_packets: out_packets.parse().ok()?,
+            }),
+            _ => None,
+        }
+    }
+
+    fn save(&self) -> String {
+        format!("{} {} {} {}", self.in_bytes, self.in_packets, self.out_bytes, self.out_packets)
+    }
+}
+
 #[derive(Default)]
 pub struct TrafficStats {
     peers: HashMap<SocketAddr, TrafficEntry, Hash>,
     payload: HashMap<(Address, Address), TrafficEntry, Hash>,
     pub dropped: TrafficEntry,
+    pub filtered: TrafficEntry,
+    /// Names under which the cumulative counters of the peers are kept
+    names: HashMap<SocketAddr, String, Hash>,
+    cumulative: HashMap<String, Counters>,
+    cumulative_total: Counters,
 }
 
 impl TrafficStats {
@@ -111,7 +150,40 @@ impl TrafficStats {
         self.dropped.count_out(bytes)
     }
 
+    pub fn count_filtered_payload(&mut self, bytes: usize) {
+        self.filtered.count_out(bytes)
+    }
+
+    /// Sets the name under which the cumulative counters of the peer are kept, the address is used otherwise
+    pub fn set_peer(&mut self, addr: SocketAddr, name: Option<&str>) {
+        match name {
+            Some(name) => self.names.insert(addr, name.to_string()),
+            None => self.names.remove(&addr),
+        };
+    }
+
+    fn peer_key(&self, addr: &SocketAddr) -> String {
+        match self.names.get(addr) {
+            Some(name) => name.clone(),
+            None => addr_nice(*addr).to_string(),
+        }
+    }
+
+    /// Returns the cumulative counters including the current period
+    fn current_cumulative(&self) -> (Counters, HashMap<String, Counters>) {
+        let mut total = self.cumulative_total;
+        let mut peers = self.cumulative.clone();
+        for (addr, entry) in &self.peers {
+            total.add(entry);
+            peers.entry(self.peer_key(addr)).or_insert_with(Counters::default).add(entry);
+        }
+        (total, peers)
+    }
+
     pub fn period(&mut self, cleanup_idle: Option<usize>) {
+        let (total, peers) = self.current_cumulative();
+        self.cumulative_total = total;
+        self.cumulative = peers;
         for entry in self.peers.values_mut() {
             entry.period();
         }
@@ -119,9 +191,44 @@ impl TrafficStats {
             entry.period();
         }
         self.dropped.period();
+        self.filtered.period();
         if let Some(periods) = cleanup_idle {
             self.peers.retain(|_, entry| entry.idle_periods < periods);
             self.payload.retain(|_, entry| entry.idle_periods < periods);
+            let peers = &self.peers;
+            self.names.retain(|addr, _| peers.contains_key(addr));
+        }
+    }
+
+    /// Returns the cumulative counters in the format of the state file
+    pub fn save(&self) -> String {
+        let (total, peers) = self.current_cumulative();
+        let mut data = format!("total {}\n", total.save());
+        let mut peers: Vec<_> = peers.into_iter().collect();
+        peers.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, counters) in peers {
+            data.push_str(&format!("peer {} {}\n", name, counters.save()));
+        }
+        data
+    }
+
+    /// Restores the cumulative counters, ignoring invalid lines
+    pub fn load(&mut self, data: &str) {
+        for line in data.lines() {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts[..] {
+                ["total", ..] => {
+                    if let Some(counters) = Counters::parse(&parts[1..]) {
+                        self.cumulative_total = counters
+                    }
+                }
+                ["peer", name, ..] => {
+                    if let Some(counters) = Counters::parse(&parts[2..]) {
+                        self.cumulative.insert(name.to_string(), counters);
+                    }
+                }
+                _ => (),
+            }
         }
     }
 
@@ -200,6 +307,51 @@ impl TrafficStats {
             self.dropped.out_bytes,
             self.dropped.out_packets
         )?;
+        writeln!(
+            out,
+            "filtered_payload_traffic: {{ display: \"{}/s\", bytes: {}, packets: {} }}",
+            Bytes(self.filtered.out_bytes / STATS_INTERVAL as u64),
+            self.filtered.out_bytes,
+            self.filtered.out_packets
+        )?;
+        writeln!(out)?;
+        let (total, peers) = self.current_cumulative();
+        writeln!(out, "cumulative_traffic:")?;
+        writeln!(
+            out,
+            "  total: {{ in: {{ bytes: {}, packets: {} }}, out: {{ bytes: {}, packets: {} }} }}",
+            total.in_bytes, total.in_packets, total.out_bytes, total.out_packets
+        )?;
+        writeln!(out, "  peers:")?;
+        let mut peers: Vec<_> = peers.into_iter().collect();
+        peers.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, counters) in peers {
+            writeln!(
+                out,
+                "    - peer: \"{}\"\n      in: {{ bytes: {}, packets: {} }}\n      out: {{ bytes: {}, packets: {} }}",
+                name, counters.in_bytes, counters.in_packets, counters.out_bytes, counters.out_packets
+            )?;
+        }
         Ok(())
     }
 }
+
+#[test]
+fn cumulative_traffic() {
+    let peer1 = "1.2.3.4:3210".parse().unwrap();
+    let peer2 = "1.2.3.5:3210".parse().unwrap();
+    let mut stats = TrafficStats::default();
+    stats.set_peer(peer1, Some("node1"));
+    stats.count_out_traffic(peer1, 100);
+    stats.count_in_traffic(peer1, 50);
+    stats.period(Some(5));
+    stats.count_out_traffic(peer2, 10);
+    // The current period is included before it is accounted
+    assert_eq!(stats.save(), "total 50 1 110 2\npeer 1.2.3.5:3210 0 0 10 1\npeer node1 50 1 100 1\n");
+    let mut restarted = TrafficStats::default();
+    restarted.load(&stats.save());
+    restarted.load("peer invalid 1 2\n");
+    restarted.set_peer(peer1, Some("node1"));
+    restarted.count_out_traffic(peer1, 100);
+    assert_eq!(restarted.save(), "total 50 1 210 3\npeer 1.2.3.5:3210 0 0 10 1\npeer node1 50 1 200 2\n");
+}
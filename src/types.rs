@@ -114,6 +114,17 @@ impl FromStr for Address {
             }
             return Ok(Address { data: res, len: 16 });
         }
+        if let Some((vlan, mac)) = text.strip_prefix("vlan").and_then(|t| t.split_once('/')) {
+            let vlan = u16::from_str(vlan).map_err(|_| Error::Parse("Failed to parse vlan"))?;
+            let mac = Address::from_str(mac)?;
+            if mac.len != 6 {
+                return Err(Error::Parse("Failed to parse mac"));
+            }
+            let mut bytes = [0; 16];
+            Encoder::write_u16(vlan, &mut bytes[0..]);
+            bytes[2..8].copy_from_slice(&mac.data[0..6]);
+            return Ok(Address { data: bytes, len: 8 });
+        }
         let parts: SmallVec<[&str; 10]> = text.split(':').collect();
         if parts.len() == 6 {
             let mut bytes = [0; 16];
@@ -162,6 +173,64 @@ impl Range {
         self.base.write_to(&mut w);
         w.write_u8(self.prefix_len).expect("Buffer too small")
     }
+
+    /// Returns the range with all bits of the base after the prefix cleared
+    pub fn normalized(&self) -> Range {
+        let mut base = self.base;
+        for bit in self.prefix_len as usize..base.len as usize * 8 {
+            base.data[bit / 8] &= !(0x80 >> (bit % 8));
+        }
+        Range { base, prefix_len: self.prefix_len.min(base.len * 8) }
+    }
+
+    /// Whether the range contains all addresses of the other range
+    pub fn covers(&self, other: &Range) -> bool {
+        self.prefix_len <= other.prefix_len && self.matches(other.base)
+    }
+
+    /// Returns the range of both halves if the normalized ranges are the two halves of a larger range
+    fn merge(&self, other: &Range) -> Option<Range> {
+        if self.base.len != other.base.len || self.prefix_len != other.prefix_len || self.prefix_len == 0 {
+            return None;
+        }
+        let parent = Range { base: self.base, prefix_len: self.prefix_len - 1 }.normalized();
+        let other_parent = Range { base: other.base, prefix_len: other.prefix_len - 1 }.normalized();
+        if parent == other_parent && self.normalized() != other.normalized() {
+            Some(parent)
+        } else {
+            None
+        }
+    }
+
+    /// Aggregates the ranges into as few ranges as possible that cover exactly the same addresses
+    ///
+    /// Ranges that are covered by other ranges are dropped and two ranges that form the halves of a larger range
+    /// are replaced by it, as long as possible. So the claims `10.1.0.0/24` and `10.1.1.0/24` become `10.1.0.0/23`.
+    pub fn summarize(ranges: &[Range]) -> RangeList {
+        let mut ranges: RangeList = ranges.iter().map(Range::normalized).collect();
+        loop {
+            ranges.sort_by_key(|r| (r.base.len, r.prefix_len, r.base.data));
+            ranges.dedup();
+            let all = ranges.clone();
+            ranges.retain(|r| !all.iter().any(|other| other.prefix_len < r.prefix_len && other.covers(r)));
+            let mut merged = None;
+            'outer: for (i, a) in ranges.iter().enumerate() {
+                for (j, b) in ranges.iter().enumerate().skip(i + 1) {
+                    if let Some(parent) = a.merge(b) {
+                        merged = Some((i, j, parent));
+                        break 'outer;
+                    }
+                }
+            }
+            match merged {
+                Some((i, j, parent)) => {
+                    ranges.remove(j);
+                    ranges[i] = parent;
+                }
+                None => return ranges,
+            }
+        }
+    }
 }
 
 impl FromStr for Range {
@@ -225,6 +294,76 @@ impl FromStr for Mode {
     }
 }
 
+/// Role of the node in the topology of the network
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Topology {
+    /// Nodes connect to all nodes they learn about
+    #[serde(rename = "mesh")]
+    Mesh,
+    /// The node does not announce its peers, so spokes do not learn about each other
+    #[serde(rename = "hub")]
+    Hub,
+    /// The node only connects to its configured peers, the hubs, and refuses all other peers
+    #[serde(rename = "spoke")]
+    Spoke,
+}
+impl fmt::Display for Topology {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Topology::Mesh => write!(formatter, "mesh"),
+            Topology::Hub => write!(formatter, "hub"),
+            Topology::Spoke => write!(formatter, "spoke"),
+        }
+    }
+}
+impl FromStr for Topology {
+    type Err = &'static str;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Ok(match &text.to_lowercase() as &str {
+            "mesh" => Self::Mesh,
+            "hub" => Self::Hub,
+            "spoke" => Self::Spoke,
+            _ => return Err("Unknown topology"),
+        })
+    }
+}
+
+/// What to do when a key connects from two nodes at the same time, e.g. from cloned VM images
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum DuplicateIdentityPolicy {
+    /// Keep the existing connection and refuse the new node
+    #[serde(rename = "reject-newer")]
+    RejectNewer,
+    /// Close the existing connection in favor of the new node
+    #[serde(rename = "reject-older")]
+    RejectOlder,
+    /// Keep both nodes, they are told apart by their node ids
+    #[serde(rename = "allow")]
+    Allow,
+}
+impl fmt::Display for DuplicateIdentityPolicy {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            DuplicateIdentityPolicy::RejectNewer => write!(formatter, "reject-newer"),
+            DuplicateIdentityPolicy::RejectOlder => write!(formatter, "reject-older"),
+            DuplicateIdentityPolicy::Allow => write!(formatter, "allow"),
+        }
+    }
+}
+impl FromStr for DuplicateIdentityPolicy {
+    type Err = &'static str;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Ok(match &text.to_lowercase() as &str {
+            "reject-newer" => Self::RejectNewer,
+            "reject-older" => Self::RejectOlder,
+            "allow" => Self::Allow,
+            _ => return Err("Unknown duplicate identity policy"),
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -240,6 +379,11 @@ mod tests {
             format!("{}", Address { data: [3, 56, 120, 45, 22, 5, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0], len: 8 }),
             "vlan824/78:2d:16:05:01:02"
         );
+        assert_eq!(
+            Address::from_str("vlan824/78:2d:16:05:01:02").unwrap(),
+            Address { data: [3, 56, 120, 45, 22, 5, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0], len: 8 }
+        );
+        assert!(Address::from_str("vlan824/120.45.22.5").is_err());
         assert_eq!(
             format!("{}", Address::from_str("0001:0203:0405:0607:0809:0a0b:0c0d:0e0f").unwrap()),
             "0001:0203:0405:0607:0809:0a0b:0c0d:0e0f"
@@ -301,4 +445,28 @@ mod tests {
         buf[0] = 17;
         assert!(Range::read_from(Cursor::new(&buf)).is_err());
     }
+
+    #[test]
+    fn range_summarize() {
+        let ranges = |values: &[&str]| -> RangeList { values.iter().map(|v| Range::from_str(v).unwrap()).collect() };
+        assert_eq!(
+            Range::summarize(&ranges(&["10.1.1.0/24", "10.1.0.0/24", "10.1.2.0/24", "10.1.3.0/24"])),
+            ranges(&["10.1.0.0/22"])
+        );
+        // Covered ranges are dropped and host bits are cleared
+        assert_eq!(
+            Range::summarize(&ranges(&["10.1.0.5/24", "10.1.0.7/32", "10.1.2.0/24", "10.2.0.0/16"])),
+            ranges(&["10.2.0.0/16", "10.1.0.0/24", "10.1.2.0/24"])
+        );
+        // Only the two halves of a range are merged
+        assert_eq!(
+            Range::summarize(&ranges(&["10.1.1.0/24", "10.1.2.0/24"])),
+            ranges(&["10.1.1.0/24", "10.1.2.0/24"])
+        );
+        assert_eq!(
+            Range::summarize(&ranges(&["0.0.0.0/1", "128.0.0.0/1", "02:00:00:00:00:00/48", "02:00:00:00:00:01/48"])),
+            ranges(&["0.0.0.0/0", "02:00:00:00:00:00/47"])
+        );
+        assert!(Range::summarize(&[]).is_empty());
+    }
 }
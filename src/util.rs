@@ -4,34 +4,50 @@
 
 use std::process::Command;
 use std::{
+    ffi::{CStr, CString},
     fmt,
-    net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs, UdpSocket},
+    os::raw::c_char,
     sync::atomic::{AtomicIsize, Ordering},
 };
 
 use crate::error::Error;
+use byteorder::{ByteOrder, NetworkEndian};
+use ring::digest;
 
 #[cfg(not(target_os = "linux"))]
 use time;
 
 use signal::{trap::Trap, Signal};
 use smallvec::SmallVec;
-use std::time::Instant;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 pub type Duration = u32;
 pub type Time = i64;
 
+pub const MAX_MSG_SIZE: usize = 65535;
+
+/// Padded messages have a multiple of this size, so their exact length is hidden
+pub const PADDING_BLOCK: usize = 256;
+
+/// Length of a message after padding it to a multiple of `PADDING_BLOCK`
+pub fn padded_len(len: usize) -> usize {
+    (len + PADDING_BLOCK - 1) / PADDING_BLOCK * PADDING_BLOCK
+}
+
+/// Aligned to a cache line
 #[derive(Clone)]
+#[repr(align(64))]
 pub struct MsgBuffer {
     space_before: usize,
-    buffer: [u8; 65535],
+    buffer: [u8; MAX_MSG_SIZE],
     start: usize,
     end: usize,
 }
 
 impl MsgBuffer {
     pub fn new(space_before: usize) -> Self {
-        Self { buffer: [0; 65535], space_before, start: space_before, end: space_before }
+        Self { buffer: [0; MAX_MSG_SIZE], space_before, start: space_before, end: space_before }
     }
 
     pub fn get_start(&self) -> usize {
@@ -79,6 +95,17 @@ impl MsgBuffer {
         self.end = self.start + length
     }
 
+    /// Extends the message with zeros to the given length
+    pub fn pad_to(&mut self, length: usize) {
+        let len = self.len();
+        if length > len {
+            self.set_length(length);
+            for byte in &mut self.message_mut()[len..] {
+                *byte = 0;
+            }
+        }
+    }
+
     pub fn clone_from(&mut self, other: &[u8]) {
         self.set_length(other.len());
         self.message_mut().clone_from_slice(other);
@@ -98,6 +125,45 @@ impl MsgBuffer {
     }
 }
 
+/// Pool of message buffers that are reused instead of allocating and zeroing a new one per packet
+pub struct BufferPool {
+    buffers: Vec<Box<MsgBuffer>>,
+    space_before: usize,
+    capacity: usize,
+}
+
+impl BufferPool {
+    pub fn new(space_before: usize, capacity: usize) -> Self {
+        Self { buffers: Vec::with_capacity(capacity), space_before, capacity }
+    }
+
+    /// Takes a cleared buffer from the pool, allocating a new one only if the pool is empty
+    pub fn get(&mut self) -> Box<MsgBuffer> {
+        match self.buffers.pop() {
+            Some(mut buffer) => {
+                buffer.clear();
+                buffer
+            }
+            None => Box::new(MsgBuffer::new(self.space_before)),
+        }
+    }
+
+    /// Returns a buffer to the pool, it is freed if the pool is full
+    pub fn put(&mut self, buffer: Box<MsgBuffer>) {
+        if self.buffers.len() < self.capacity {
+            self.buffers.push(buffer)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+}
+
 const HEX_CHARS: &[u8] = b"0123456789abcdef";
 
 pub fn bytes_to_hex(bytes: &[u8]) -> String {
@@ -109,13 +175,76 @@ pub fn bytes_to_hex(bytes: &[u8]) -> String {
     s
 }
 
-pub fn addr_nice(addr: SocketAddr) -> SocketAddr {
+pub fn addr_nice(addr: SocketAddr) -> NiceAddr {
     if let SocketAddr::V6(v6addr) = addr {
         if let Some(ip) = v6addr.ip().to_ipv4() {
-            return (ip, addr.port()).into();
+            return NiceAddr((ip, addr.port()).into());
+        }
+    }
+    NiceAddr(addr)
+}
+
+/// An address for display, IPv6 scope ids are shown with the interface name (e.g. `[fe80::1%eth0]:3210`)
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct NiceAddr(pub SocketAddr);
+
+impl fmt::Display for NiceAddr {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self.0 {
+            SocketAddr::V6(addr) if addr.scope_id() != 0 => {
+                write!(formatter, "[{}%{}]:{}", addr.ip(), scope_name(addr.scope_id()), addr.port())
+            }
+            addr => write!(formatter, "{}", addr),
         }
     }
-    addr
+}
+
+impl fmt::Debug for NiceAddr {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt::Display::fmt(self, formatter)
+    }
+}
+
+/// Returns the name of the interface with the given index or the index itself if there is no such interface
+fn scope_name(scope_id: u32) -> String {
+    let mut name = [0 as c_char; libc::IF_NAMESIZE];
+    if unsafe { libc::if_indextoname(scope_id, name.as_mut_ptr()) }.is_null() {
+        return scope_id.to_string();
+    }
+    unsafe { CStr::from_ptr(name.as_ptr()) }.to_string_lossy().into_owned()
+}
+
+/// Parses an IPv6 address with scope id like `[fe80::1%eth0]:3210`
+///
+/// The scope can be given as interface name or as interface index. Returns `None` if the address has no scope
+/// id or is invalid.
+pub fn parse_scoped_addr(addr: &str) -> Option<SocketAddr> {
+    let rest = addr.strip_prefix('[')?;
+    let end = rest.find(']')?;
+    let (host, port) = (&rest[..end], rest[end + 1..].strip_prefix(':')?);
+    let pos = host.find('%')?;
+    let ip = host[..pos].parse::<Ipv6Addr>().ok()?;
+    let port = port.parse::<u16>().ok()?;
+    let scope = &host[pos + 1..];
+    let scope_id = match scope.parse::<u32>() {
+        Ok(id) => id,
+        Err(_) => {
+            let name = CString::new(scope).ok()?;
+            match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+                0 => return None,
+                id => id,
+            }
+        }
+    };
+    Some(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)))
+}
+
+/// Like `resolve` but also accepts IPv6 addresses with scope id
+pub fn resolve_scoped(addr: &str) -> Result<SmallVec<[SocketAddr; 4]>, Error> {
+    match parse_scoped_addr(addr) {
+        Some(addr) => Ok(smallvec::smallvec![addr]),
+        None => resolve(addr),
+    }
 }
 
 pub struct Encoder;
@@ -170,36 +299,6 @@ impl Encoder {
     }
 }
 
-macro_rules! fail {
-    ($format:expr) => ( {
-        use std::process;
-        error!($format);
-        log::logger().flush();
-        process::exit(-1);
-    } );
-    ($format:expr, $( $arg:expr ),+) => ( {
-        use std::process;
-        error!($format, $( $arg ),+ );
-        log::logger().flush();
-        process::exit(-1);
-    } );
-}
-
-macro_rules! try_fail {
-    ($val:expr, $format:expr) => ( {
-        match $val {
-            Ok(val) => val,
-            Err(err) => fail!($format, err)
-        }
-    } );
-    ($val:expr, $format:expr, $( $arg:expr ),+) => ( {
-        match $val {
-            Ok(val) => val,
-            Err(err) => fail!($format, $( $arg ),+, err)
-        }
-    } );
-}
-
 pub fn get_internal_ip() -> Ipv4Addr {
     // Get the internal address (this trick gets the address by opening a UDP connection which
     // does not really open anything but returns the correct address)
@@ -262,31 +361,54 @@ impl fmt::Display for Bytes {
     }
 }
 
-pub struct CtrlC {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignalEvent {
+    /// SIGINT, SIGTERM or SIGQUIT
+    Shutdown,
+    /// SIGUSR1
+    DumpState,
+    /// SIGUSR2
+    Reconnect,
+}
+
+/// Traps the signals that stop the process and the ones that trigger operational actions
+///
+/// The signals are blocked in the creating thread and all threads started by it afterwards, so this has to be
+/// created before any other threads.
+pub struct Signals {
     dummy_time: Instant,
     trap: Trap,
 }
 
-impl CtrlC {
+impl Signals {
     pub fn new() -> Self {
         Default::default()
     }
 
-    pub fn was_pressed(&self) -> bool {
-        self.trap.wait(self.dummy_time).is_some()
+    /// Returns the next pending signal without waiting
+    pub fn next_event(&self) -> Option<SignalEvent> {
+        self.trap.wait(self.dummy_time).map(|signal| match signal {
+            Signal::SIGUSR1 => SignalEvent::DumpState,
+            Signal::SIGUSR2 => SignalEvent::Reconnect,
+            _ => SignalEvent::Shutdown,
+        })
     }
 }
 
-impl Default for CtrlC {
+impl Default for Signals {
     fn default() -> Self {
         let dummy_time = Instant::now();
-        let trap = Trap::trap(&[Signal::SIGINT, Signal::SIGTERM, Signal::SIGQUIT]);
+        let trap =
+            Trap::trap(&[Signal::SIGINT, Signal::SIGTERM, Signal::SIGQUIT, Signal::SIGUSR1, Signal::SIGUSR2]);
         Self { dummy_time, trap }
     }
 }
 
 pub trait TimeSource: Sync + Copy + Send + 'static {
     fn now() -> Time;
+
+    /// Wall clock time in seconds since the epoch, unlike `now` this can jump when the clock is set
+    fn wall_clock() -> Time;
 }
 
 #[derive(Clone, Copy)]
@@ -306,10 +428,15 @@ impl TimeSource for SystemTimeSource {
     fn now() -> Time {
         time::get_time().sec
     }
+
+    fn wall_clock() -> Time {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as Time).unwrap_or(0)
+    }
 }
 
 thread_local! {
     static MOCK_TIME: AtomicIsize = AtomicIsize::new(0);
+    static MOCK_CLOCK_OFFSET: AtomicIsize = AtomicIsize::new(0);
 }
 
 #[derive(Clone, Copy)]
@@ -319,12 +446,21 @@ impl MockTimeSource {
     pub fn set_time(time: Time) {
         MOCK_TIME.with(|t| t.store(time as isize, Ordering::SeqCst))
     }
+
+    /// Sets the difference between the wall clock and the monotonic time
+    pub fn set_clock_offset(offset: Time) {
+        MOCK_CLOCK_OFFSET.with(|t| t.store(offset as isize, Ordering::SeqCst))
+    }
 }
 
 impl TimeSource for MockTimeSource {
     fn now() -> Time {
         MOCK_TIME.with(|t| t.load(Ordering::SeqCst) as Time)
     }
+
+    fn wall_clock() -> Time {
+        Self::now() + MOCK_CLOCK_OFFSET.with(|t| t.load(Ordering::SeqCst) as Time)
+    }
 }
 
 /// Helper function that multiplies the base62 data in buf[0..buflen] by 16 and adds m to it
@@ -387,34 +523,6 @@ pub fn from_base62(data: &str) -> Result<Vec<u8>, char> {
     Ok(buf)
 }
 
-#[derive(Default)]
-pub struct StatsdMsg {
-    entries: Vec<String>,
-    key: Vec<String>,
-}
-
-impl StatsdMsg {
-    pub fn new() -> Self {
-        Default::default()
-    }
-
-    pub fn add<T: fmt::Display>(&mut self, key: &str, val: T, type_: &str) -> &mut Self {
-        self.entries.push(format!("{}.{}:{}|{}", self.key.join("."), key, val, type_));
-        self
-    }
-
-    pub fn with_ns<F: FnOnce(&mut Self)>(&mut self, ns: &str, f: F) -> &mut Self {
-        self.key.push(ns.to_string());
-        f(self);
-        self.key.pop();
-        self
-    }
-
-    pub fn build(&self) -> String {
-        self.entries.join("\n")
-    }
-}
-
 pub fn run_cmd(mut cmd: Command) {
     match cmd.status() {
         Ok(status) => {
@@ -426,6 +534,30 @@ pub fn run_cmd(mut cmd: Command) {
     }
 }
 
+/// Derives a stable, locally administered unicast MAC address from a public key
+pub fn derive_mac(key: &[u8]) -> [u8; 6] {
+    let hash = digest::digest(&digest::SHA256, &[b"vpncloud-mac" as &[u8], key].concat());
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&hash.as_ref()[..6]);
+    mac[0] = (mac[0] & 0xfc) | 0x02;
+    mac
+}
+
+/// Derives a stable host address within the network from a public key
+///
+/// The network and broadcast addresses are never returned, so the network must have room for at least two hosts.
+pub fn derive_ip(key: &[u8], network: Ipv4Addr, netmask: Ipv4Addr) -> Ipv4Addr {
+    let hash = digest::digest(&digest::SHA256, &[b"vpncloud-ip" as &[u8], key].concat());
+    let host_mask = !u32::from(netmask);
+    let mut host = NetworkEndian::read_u32(&hash.as_ref()[..4]) & host_mask;
+    if host == 0 {
+        host = 1;
+    } else if host == host_mask {
+        host -= 1;
+    }
+    Ipv4Addr::from((u32::from(network) & u32::from(netmask)) | host)
+}
+
 #[test]
 fn base62() {
     assert_eq!("", to_base62(&[0]));
@@ -439,3 +571,51 @@ fn base62() {
     assert_eq!(vec![1, 0], from_base62("48").unwrap());
     assert_eq!(b"Test".to_vec(), from_base62("1Xp7Ke").unwrap());
 }
+
+#[test]
+fn scoped_addr() {
+    let addr = parse_scoped_addr("[fe80::1%3999999]:3210").unwrap();
+    assert_eq!(addr, SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 3210, 0, 3999999)));
+    assert_eq!(addr_nice(addr).to_string(), "[fe80::1%3999999]:3210");
+    assert_eq!(parse_scoped_addr("[fe80::1]:3210"), None);
+    assert_eq!(parse_scoped_addr("[fe80::1%no-such-interface]:3210"), None);
+    assert_eq!(parse_scoped_addr("[fe80::1%3]"), None);
+    assert_eq!(resolve_scoped("[fe80::1%3]:3210").unwrap()[0].port(), 3210);
+    assert_eq!(addr_nice("[::ffff:1.2.3.4]:3210".parse().unwrap()).to_string(), "1.2.3.4:3210");
+}
+
+#[test]
+fn derive_identity() {
+    let key = [1; 32];
+    let mac = derive_mac(&key);
+    assert_eq!(mac, derive_mac(&key));
+    assert_ne!(mac, derive_mac(&[2; 32]));
+    assert_eq!(0x02, mac[0] & 0x03);
+    let netmask = Ipv4Addr::new(255, 255, 0, 0);
+    let ip = derive_ip(&key, Ipv4Addr::new(10, 1, 0, 0), netmask);
+    assert_eq!(ip, derive_ip(&key, Ipv4Addr::new(10, 1, 0, 0), netmask));
+    assert_eq!([10, 1], ip.octets()[..2]);
+    let ip = derive_ip(&key, Ipv4Addr::new(10, 1, 2, 0), Ipv4Addr::new(255, 255, 255, 252));
+    assert!(ip == Ipv4Addr::new(10, 1, 2, 1) || ip == Ipv4Addr::new(10, 1, 2, 2));
+}
+
+#[test]
+fn buffer_pool() {
+    let mut pool = BufferPool::new(10, 2);
+    assert!(pool.is_empty());
+    let mut buffer = pool.get();
+    (*buffer).clone_from(&[1, 2, 3]);
+    buffer.prepend_byte(0);
+    let ptr = &*buffer as *const MsgBuffer;
+    pool.put(buffer);
+    assert_eq!(pool.len(), 1);
+    let buffer = pool.get();
+    assert_eq!(&*buffer as *const MsgBuffer, ptr);
+    assert!(buffer.is_empty());
+    assert_eq!(buffer.get_start(), 10);
+    assert_eq!(ptr as usize % 64, 0);
+    pool.put(buffer);
+    pool.put(Box::new(MsgBuffer::new(10)));
+    pool.put(Box::new(MsgBuffer::new(10)));
+    assert_eq!(pool.len(), 2);
+}
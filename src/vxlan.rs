@@ -0,0 +1,141 @@
+// VpnCloud - Peer-to-Peer VPN
+// Copyright (C) 2015-2021  Dennis Schwerdel
+// This software is licensed under GPL-3 or newer (see LICENSE.md)
+
+//! Bridging of an existing VXLAN network instead of a TUN/TAP device, see the `vxlan` config section
+//!
+//! The VXLAN packets that the local VXLAN endpoint (e.g. a Linux `vxlan` interface or Open vSwitch) sends to the
+//! listen address are carried to the peers as they are, including their VXLAN header, and are sent to the VXLAN
+//! endpoint of the receiving node. So existing VXLAN networks can be connected over untrusted networks without an
+//! additional interface. The payload protocol is [`crate::payload::Vxlan`].
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    os::unix::io::{AsRawFd, RawFd},
+};
+
+use crate::{
+    device::{Device, Type},
+    error::Error,
+    net::parse_listen,
+    payload::VXLAN_HEADER_LEN,
+    util::{resolve, MsgBuffer},
+};
+
+/// Port assigned to VXLAN by IANA
+pub const DEFAULT_VXLAN_PORT: u16 = 4789;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Address to receive the VXLAN packets of the local VXLAN endpoint on
+    pub listen: String,
+    /// Address of the local VXLAN endpoint, learned from the received packets if not set
+    #[serde(default)]
+    pub remote: Option<String>,
+}
+
+/// A device that exchanges VXLAN packets with a local VXLAN endpoint over UDP
+pub struct VxlanDevice {
+    socket: UdpSocket,
+    ifname: String,
+    remote: Option<SocketAddr>,
+    learn_remote: bool,
+}
+
+impl VxlanDevice {
+    /// Opens the UDP socket for the VXLAN packets
+    ///
+    /// # Errors
+    /// This method will return an error if the addresses are invalid or the socket can not be opened.
+    pub fn new(config: &Config) -> Result<Self, Error> {
+        let listen = parse_listen(&config.listen, DEFAULT_VXLAN_PORT)
+            .map_err(|e| Error::DeviceIo("Invalid VXLAN listen address", e))?;
+        let socket = UdpSocket::bind(listen).map_err(|e| Error::DeviceIo("Failed to open VXLAN socket", e))?;
+        let remote = match config.remote {
+            Some(ref remote) => Some(resolve(remote as &str)?[0]),
+            None => None,
+        };
+        Ok(Self { socket, ifname: format!("vxlan:{}", listen), remote, learn_remote: remote.is_none() })
+    }
+
+    /// Address of the local VXLAN endpoint if known
+    pub fn remote(&self) -> Option<SocketAddr> {
+        self.remote
+    }
+}
+
+impl Device for VxlanDevice {
+    fn get_type(&self) -> Type {
+        Type::Tap
+    }
+
+    fn ifname(&self) -> &str {
+        &self.ifname
+    }
+
+    fn read(&mut self, buffer: &mut MsgBuffer) -> Result<(), Error> {
+        buffer.clear();
+        let (read, src) = self.socket.recv_from(buffer.buffer()).map_err(|e| Error::DeviceIo("Read error", e))?;
+        buffer.set_length(read);
+        if read < VXLAN_HEADER_LEN {
+            return Err(Error::Device("Received a packet that is too short for VXLAN"));
+        }
+        if self.learn_remote && self.remote != Some(src) {
+            info!("Sending VXLAN packets to {}", src);
+            self.remote = Some(src);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, buffer: &mut MsgBuffer) -> Result<(), Error> {
+        match self.remote {
+            Some(remote) => {
+                self.socket.send_to(buffer.message(), remote).map_err(|e| Error::DeviceIo("Write error", e))?;
+            }
+            None => debug!("Dropping VXLAN packet as the VXLAN endpoint is not known yet"),
+        }
+        Ok(())
+    }
+
+    fn get_ip(&self) -> Result<Ipv4Addr, Error> {
+        Err(Error::Device("VXLAN devices have no ip address"))
+    }
+
+    fn has_pending(&self) -> bool {
+        let mut fds = libc::pollfd { fd: self.socket.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        unsafe { libc::poll(&mut fds, 1, 0) == 1 && fds.revents & libc::POLLIN != 0 }
+    }
+}
+
+impl AsRawFd for VxlanDevice {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+#[test]
+fn vxlan_device_learns_remote() {
+    let mut device = VxlanDevice::new(&Config { listen: "127.0.0.1:0".to_string(), remote: None }).unwrap();
+    let addr = device.socket.local_addr().unwrap();
+    let endpoint = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let packet = [0x08, 0, 0, 0, 0, 0, 42, 0, 6, 5, 4, 3, 2, 1, 1, 2, 3, 4, 5, 6, 0x08, 0x06];
+    let mut buffer = MsgBuffer::new(64);
+    buffer.clone_from(&packet);
+    // Packets are dropped until the endpoint is known
+    device.write(&mut buffer).unwrap();
+    assert_eq!(device.remote(), None);
+    endpoint.send_to(&packet, addr).unwrap();
+    device.read(&mut buffer).unwrap();
+    assert_eq!(buffer.message(), &packet);
+    assert_eq!(device.remote(), Some(endpoint.local_addr().unwrap()));
+    device.write(&mut buffer).unwrap();
+    let mut received = [0; 64];
+    let (len, src) = endpoint.recv_from(&mut received).unwrap();
+    assert_eq!(&received[..len], &packet);
+    assert_eq!(src, addr);
+    // Too short for a VXLAN header
+    endpoint.send_to(&packet[..4], addr).unwrap();
+    assert!(device.read(&mut buffer).is_err());
+}
@@ -1,7 +1,12 @@
-use crate::{config::Config, crypto::Crypto, device, types::Mode};
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, MultiSelect, Password, Select};
 use ring::aead;
 use std::{collections::HashMap, fs, io, os::unix::fs::PermissionsExt, path::Path};
+use vpncloud_core::{
+    config::{Config, PeerConfig},
+    crypto::Crypto,
+    device,
+    types::Mode,
+};
 
 const MODE_SIMPLE: usize = 0;
 const MODE_ADVANCED: usize = 1;
@@ -28,12 +33,21 @@ fn configure_connectivity(config: &mut Config, mode: usize, theme: &ColorfulThem
         config.listen =
             Input::with_theme(theme).with_prompt("Listen address").default(config.listen.clone()).interact_text()?;
     }
-    config.peers = str_list(
+    let addresses = str_list(
         Input::with_theme(theme)
             .with_prompt("Peer addresses (comma separated)")
-            .default(config.peers.join(","))
+            .default(config.peers.iter().map(|p| &p.address as &str).collect::<Vec<_>>().join(","))
             .interact_text()?,
     );
+    // Keep the settings of peers that are still present
+    let mut peers = std::mem::take(&mut config.peers);
+    config.peers = addresses
+        .into_iter()
+        .map(|address| match peers.iter().position(|p| p.address == address) {
+            Some(pos) => peers.remove(pos),
+            None => PeerConfig::new(address),
+        })
+        .collect();
     if mode >= MODE_ADVANCED {
         config.port_forwarding = Confirm::with_theme(theme)
             .with_prompt("Enable automatic port forwarding?")
@@ -419,6 +433,7 @@ fn configure_hooks(config: &mut Config, mode: usize, theme: &ColorfulTheme) -> R
                 "device_configured",
                 "vpn_started",
                 "vpn_shutdown",
+                "external_address_changed",
             ] {
                 if let Some(cmd) = str_opt(
                     Input::with_theme(theme)
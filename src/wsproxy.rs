@@ -92,7 +92,7 @@ fn serve_proxy_connection(stream: TcpStream) -> Result<(), io::Error> {
 }
 
 pub fn run_proxy(listen: &str) -> Result<(), io::Error> {
-    let addr = parse_listen(listen, 8080);
+    let addr = parse_listen(listen, 8080)?;
     let server = TcpListener::bind(addr)?;
     info!("Listening on ws://{}", server.local_addr()?);
     for stream in server.incoming() {
@@ -160,7 +160,7 @@ impl Socket for ProxyConnection {
         Ok(self.addr)
     }
 
-    fn create_port_forwarding(&self) -> Option<PortForwarding> {
+    fn create_port_forwarding(&self, _description: &str) -> Option<PortForwarding> {
         None
     }
 }